@@ -0,0 +1,58 @@
+/*
+ * The C ABI of py-spy, for taking snapshots of the stack traces of a python process from other
+ * languages. These are implemented in src/ffi.rs, and are exported from the py-spy library when
+ * it's built as a cdylib or staticlib:
+ *
+ *     cargo rustc --lib --release --crate-type cdylib
+ *
+ * Neither function allocates memory that the caller has to free: results are written to a
+ * buffer the caller owns, and the length of the result is returned like snprintf does. The
+ * message for the last error is kept per thread, and both functions can be called from any
+ * number of threads at once.
+ */
+
+#ifndef PY_SPY_H
+#define PY_SPY_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* A buffer was null with a non zero length */
+#define PYSPY_ERROR_INVALID_ARGUMENT -1
+/* The process doesn't exist (or exited while it was being read) */
+#define PYSPY_ERROR_NO_SUCH_PROCESS -2
+/* We don't have permission to read the memory of the process */
+#define PYSPY_ERROR_PERMISSION_DENIED -3
+/* The process couldn't be read as a python program, like when it isn't one */
+#define PYSPY_ERROR_ATTACH_FAILED -4
+/* The stack traces of the process couldn't be read */
+#define PYSPY_ERROR_SAMPLE_FAILED -5
+/* py-spy panicked, which is a bug in py-spy */
+#define PYSPY_ERROR_PANIC -6
+
+/*
+ * Writes the stack traces of each thread in a python process to buf as a NUL terminated JSON
+ * array, in the same format as 'py-spy dump --json'.
+ *
+ * Returns the length of the JSON (without the NUL), or one of the negative PYSPY_ERROR codes.
+ * If the returned length is len or more, the buffer was too small and nothing was written:
+ * calling again with a bigger buffer takes a new snapshot, which can be a different length.
+ * buf can be NULL when len is 0.
+ */
+int64_t pyspy_snapshot(int32_t pid, char *buf, size_t len);
+
+/*
+ * Writes the message for the last error returned to this thread to buf, with the same return
+ * value as pyspy_snapshot. The message is empty if the last call succeeded.
+ */
+int64_t pyspy_last_error(char *buf, size_t len);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* PY_SPY_H */
@@ -0,0 +1,785 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{Context, Error};
+use remoteprocess::Pid;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::config::{Config, FileFormat, LockingStrategy, RecordDuration};
+use crate::http::{read_request, Request, Response};
+use crate::metrics::{self, render, Metrics};
+use crate::python_spy::PythonSpy;
+use crate::sampler::Sampler;
+use crate::sampler_stats::SamplerStats;
+use crate::signals;
+use crate::stack_trace::StackTrace;
+use crate::systemd;
+use crate::{file_extension, new_recorder, write_output};
+
+/*
+ * This file contains code for 'py-spy agent', which runs py-spy as a long running service that
+ * other tools can drive over HTTP, instead of running py-spy and parsing what it prints.
+ *
+ * The API is JSON over HTTP/1.1, served from a thread per connection, and every request has to
+ * have the agent's token as a bearer token:
+ *
+ *   GET    /recordings               lists the recordings
+ *   POST   /recordings               starts recording, from a JSON body like
+ *                                    {"pid": 1234, "format": "speedscope", "duration": 30}
+ *   GET    /recordings/<id>          the status of a recording
+ *   POST   /recordings/<id>/stop     stops a recording, writing out its profile
+ *   GET    /recordings/<id>/profile  downloads the profile of a finished recording
+ *   DELETE /recordings/<id>          stops a recording and deletes its profile
+ *   GET    /dump?pid=1234            the stack traces of a process, like 'dump --json'
+ *   GET    /top?pid=1234             streams a JSON line every second with the functions using
+ *                                    the most time, like 'top', until the client disconnects
+ *   GET    /metrics                  metrics about the running recordings, for Prometheus
+ *
+ * Recordings are sampled on threads of their own, and written out to files in the agent's
+ * directory when they finish, in any of the formats 'record' can write. When the agent is
+ * stopped (with SIGTERM or Control-C), the running recordings are stopped and written out
+ * before it exits. The agent can also be run as a systemd service (see systemd.rs).
+ */
+
+/// How many functions each summary streamed from /top has
+const TOP_FUNCTIONS: usize = 50;
+
+/// How often to check for new connections, and whether the agent has been asked to stop
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Runs the agent until it's killed
+pub fn run(config: &Config) -> Result<(), Error> {
+    let token = match config
+        .agent_token
+        .clone()
+        .or_else(|| std::env::var("PY_SPY_AGENT_TOKEN").ok())
+    {
+        Some(token) => token,
+        None => {
+            let token: String = rand::random::<[u8; 16]>()
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect();
+            eprintln!("Generated the token for the API: {}", token);
+            token
+        }
+    };
+    let dir = match config.agent_dir.as_ref() {
+        Some(dir) => {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create directory '{}'", dir))?;
+            PathBuf::from(dir)
+        }
+        None => tempfile::Builder::new()
+            .prefix("py-spy-agent")
+            .tempdir()?
+            .into_path(),
+    };
+
+    // with systemd socket activation, systemd has already bound the address
+    let listener = match systemd::activated_listener()? {
+        Some(listener) => listener,
+        None => TcpListener::bind(&config.agent_listen)
+            .with_context(|| format!("Failed to listen on {}", config.agent_listen))?,
+    };
+    let address = listener.local_addr()?;
+    eprintln!(
+        "Listening on http://{}, writing profiles to '{}'",
+        address,
+        dir.display()
+    );
+
+    let agent = Arc::new(Agent {
+        token,
+        dir,
+        recordings: Mutex::new(BTreeMap::new()),
+        next_id: AtomicU64::new(1),
+    });
+
+    // connections are polled for, so that the agent can stop when it's asked to
+    signals::install_terminate()?;
+    listener.set_nonblocking(true)?;
+    systemd::notify(&format!("READY=1\nSTATUS=Listening on {}", address));
+    let watchdog = systemd::watchdog_interval();
+    let mut last_watchdog = Instant::now();
+    while !signals::terminate_requested() {
+        if watchdog.is_some_and(|interval| last_watchdog.elapsed() >= interval) {
+            systemd::notify("WATCHDOG=1");
+            last_watchdog = Instant::now();
+        }
+        let stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                std::thread::sleep(ACCEPT_INTERVAL);
+                continue;
+            }
+            Err(e) => {
+                warn!("Failed to accept connection: {}", e);
+                continue;
+            }
+        };
+        stream.set_nonblocking(false)?;
+        let agent = agent.clone();
+        std::thread::spawn(move || {
+            if let Err(e) = agent.serve(stream) {
+                info!("Failed to serve request: {}", e);
+            }
+        });
+    }
+
+    systemd::notify("STOPPING=1");
+    agent.drain(watchdog)
+}
+
+struct Agent {
+    token: String,
+    dir: PathBuf,
+    recordings: Mutex<BTreeMap<u64, Arc<Recording>>>,
+    // ids aren't reused after recordings are deleted
+    next_id: AtomicU64,
+}
+
+struct Recording {
+    format: FileFormat,
+    filename: PathBuf,
+    stop: AtomicBool,
+    status: Mutex<RecordingStatus>,
+    metrics: Mutex<Metrics>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct RecordingStatus {
+    id: u64,
+    pid: Pid,
+    format: String,
+    state: RecordingState,
+    /// When the recording started, in seconds since the unix epoch
+    started: u64,
+    samples: u64,
+    errors: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum RecordingState {
+    Running,
+    Finished,
+    Failed,
+}
+
+/// The body of a request to start recording
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RecordRequest {
+    pid: Pid,
+    #[serde(default)]
+    format: Option<String>,
+    /// How long to record for in seconds, or until it's stopped if not given
+    #[serde(default)]
+    duration: Option<u64>,
+    #[serde(default)]
+    rate: Option<u64>,
+    #[serde(default)]
+    gil: bool,
+    #[serde(default)]
+    idle: bool,
+    #[serde(default)]
+    subprocesses: bool,
+    #[serde(default)]
+    native: bool,
+    #[serde(default)]
+    nonblocking: bool,
+}
+
+impl RecordRequest {
+    fn config(&self) -> Result<Config, String> {
+        let format = match self.format.as_ref() {
+            Some(format) => format.parse()?,
+            None => FileFormat::speedscope,
+        };
+        let mut config = Config::default();
+        config.command = String::from("record");
+        config.pids = vec![self.pid];
+        config.format = Some(format);
+        config.duration = self
+            .duration
+            .map_or(RecordDuration::Unlimited, RecordDuration::Seconds);
+        config.sampling_rate = self.rate.unwrap_or(100);
+        config.gil_only = self.gil;
+        config.include_idle = self.idle;
+        config.subprocesses = self.subprocesses;
+        config.native = self.native;
+        config.blocking = if self.nonblocking {
+            LockingStrategy::NonBlocking
+        } else {
+            LockingStrategy::Lock
+        };
+        config.hide_progress = true;
+        Ok(config)
+    }
+}
+
+/// The functions using the most time over a second of sampling, streamed from /top
+#[derive(Debug, Serialize)]
+struct TopSummary {
+    /// Seconds since the start of the stream
+    elapsed: f64,
+    samples: u64,
+    errors: u64,
+    functions: Vec<TopFunction>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+struct TopFunction {
+    function: String,
+    filename: String,
+    /// The samples where this function was running
+    own: u64,
+    /// The samples where this function was on the stack
+    total: u64,
+}
+
+impl Agent {
+    /// Stops the running recordings, and waits for their profiles to be written out
+    fn drain(&self, watchdog: Option<Duration>) -> Result<(), Error> {
+        let recordings: Vec<Arc<Recording>> =
+            self.recordings.lock().unwrap().values().cloned().collect();
+        let running = |recording: &&Arc<Recording>| {
+            recording.status.lock().unwrap().state == RecordingState::Running
+        };
+        let count = recordings.iter().filter(running).count();
+        if count > 0 {
+            eprintln!("Stopping {} running recording(s)", count);
+            systemd::notify(&format!("STATUS=Stopping {} running recording(s)", count));
+        }
+        for recording in &recordings {
+            recording.stop.store(true, Ordering::SeqCst);
+        }
+
+        let mut last_watchdog = Instant::now();
+        while recordings.iter().any(|recording| running(&recording)) {
+            // writing out a long recording can take a while, which isn't a hang
+            if watchdog.is_some_and(|interval| last_watchdog.elapsed() >= interval) {
+                systemd::notify("WATCHDOG=1");
+                last_watchdog = Instant::now();
+            }
+            std::thread::sleep(ACCEPT_INTERVAL);
+        }
+        for recording in &recordings {
+            let status = recording.status.lock().unwrap();
+            if status.state == RecordingState::Finished {
+                eprintln!(
+                    "Wrote recording {} to '{}'",
+                    status.id,
+                    recording.filename.display()
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn serve(&self, mut stream: TcpStream) -> Result<(), Error> {
+        stream.set_read_timeout(Some(Duration::from_secs(30)))?;
+        let request = match read_request(&mut BufReader::new(&stream)) {
+            Ok(request) => request,
+            Err(e) => {
+                Response::bad_request(&e).write(&mut stream)?;
+                return Ok(());
+            }
+        };
+        info!("{} {}", request.method, request.path);
+
+        if !self.authorized(&request) {
+            let mut response = Response::error(401, "Missing or invalid bearer token");
+            response
+                .headers
+                .push(("WWW-Authenticate", String::from("Bearer")));
+            response.write(&mut stream)?;
+            return Ok(());
+        }
+
+        let segments: Vec<&str> = request
+            .path
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .collect();
+        let response = match (request.method.as_str(), segments.as_slice()) {
+            ("GET", ["recordings"]) => self.list_recordings(),
+            ("POST", ["recordings"]) => self.start_recording(&request),
+            ("GET", ["recordings", id]) => self.recording_status(id),
+            ("POST", ["recordings", id, "stop"]) => self.stop_recording(id),
+            ("GET", ["recordings", id, "profile"]) => self.download_profile(id),
+            ("DELETE", ["recordings", id]) => self.delete_recording(id),
+            ("GET", ["dump"]) => dump(&request),
+            ("GET", ["metrics"]) => Ok(self.metrics()),
+            ("GET", ["top"]) => return top(&request, &mut stream),
+            (_, ["recordings", ..]) | (_, ["dump"]) | (_, ["top"]) | (_, ["metrics"]) => {
+                Ok(Response::error(405, "Method not allowed"))
+            }
+            _ => Ok(Response::error(404, "Not found")),
+        };
+        let response = response.unwrap_or_else(|e| Response::error(500, &format!("{:#}", e)));
+        response.write(&mut stream)?;
+        Ok(())
+    }
+
+    fn authorized(&self, request: &Request) -> bool {
+        let token = request
+            .headers
+            .get("authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .unwrap_or_default();
+        constant_time_eq(token.trim().as_bytes(), self.token.as_bytes())
+    }
+
+    fn recording(&self, id: &str) -> Option<Arc<Recording>> {
+        let id: u64 = id.parse().ok()?;
+        self.recordings.lock().unwrap().get(&id).cloned()
+    }
+
+    /// The metrics of the running recordings, for Prometheus
+    fn metrics(&self) -> Response {
+        let recordings: Vec<Arc<Recording>> = self
+            .recordings
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|recording| recording.status.lock().unwrap().state == RecordingState::Running)
+            .cloned()
+            .collect();
+        let metrics: Vec<_> = recordings
+            .iter()
+            .map(|recording| recording.metrics.lock().unwrap())
+            .collect();
+        let sources: Vec<_> = recordings
+            .iter()
+            .zip(metrics.iter())
+            .map(|(recording, metrics)| {
+                // the pid of each thread is already a label of its series
+                let id = recording.status.lock().unwrap().id;
+                (vec![("recording", id.to_string())], &**metrics)
+            })
+            .collect();
+        Response::new(200, metrics::CONTENT_TYPE, render(&sources).into_bytes())
+    }
+
+    fn list_recordings(&self) -> Result<Response, Error> {
+        let statuses: Vec<RecordingStatus> = self
+            .recordings
+            .lock()
+            .unwrap()
+            .values()
+            .map(|recording| recording.status.lock().unwrap().clone())
+            .collect();
+        Response::json(200, &statuses)
+    }
+
+    fn start_recording(&self, request: &Request) -> Result<Response, Error> {
+        let config = match serde_json::from_slice::<RecordRequest>(&request.body)
+            .map_err(|e| e.to_string())
+            .and_then(|body| body.config())
+        {
+            Ok(config) => config,
+            Err(e) => return Ok(Response::error(400, &e)),
+        };
+        let format = config.format.unwrap();
+
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let recording = Arc::new(Recording {
+            format,
+            filename: self.dir.join(format!("{}.{}", id, file_extension(&format))),
+            stop: AtomicBool::new(false),
+            metrics: Mutex::new(Metrics::new()),
+            status: Mutex::new(RecordingStatus {
+                id,
+                pid: config.pids[0],
+                format: format!("{:?}", format),
+                state: RecordingState::Running,
+                started: SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)?
+                    .as_secs(),
+                samples: 0,
+                errors: 0,
+                error: None,
+            }),
+        });
+        self.recordings
+            .lock()
+            .unwrap()
+            .insert(id, recording.clone());
+
+        let status = recording.status.lock().unwrap().clone();
+        std::thread::spawn(move || {
+            let result = record(&recording, &config);
+            let mut status = recording.status.lock().unwrap();
+            match result {
+                Ok(()) => status.state = RecordingState::Finished,
+                Err(e) => {
+                    status.state = RecordingState::Failed;
+                    status.error = Some(format!("{:#}", e));
+                }
+            }
+        });
+        Response::json(201, &status)
+    }
+
+    fn recording_status(&self, id: &str) -> Result<Response, Error> {
+        match self.recording(id) {
+            Some(recording) => Response::json(200, &*recording.status.lock().unwrap()),
+            None => Ok(Response::error(404, "No such recording")),
+        }
+    }
+
+    fn stop_recording(&self, id: &str) -> Result<Response, Error> {
+        let recording = match self.recording(id) {
+            Some(recording) => recording,
+            None => return Ok(Response::error(404, "No such recording")),
+        };
+        recording.stop.store(true, Ordering::SeqCst);
+        // wait for the profile to be written out, so that it can be downloaded straight away
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while recording.status.lock().unwrap().state == RecordingState::Running
+            && Instant::now() < deadline
+        {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let status = recording.status.lock().unwrap().clone();
+        Response::json(200, &status)
+    }
+
+    fn download_profile(&self, id: &str) -> Result<Response, Error> {
+        let recording = match self.recording(id) {
+            Some(recording) => recording,
+            None => return Ok(Response::error(404, "No such recording")),
+        };
+        let status = recording.status.lock().unwrap().clone();
+        match status.state {
+            RecordingState::Running => {
+                return Ok(Response::error(409, "The recording hasn't finished yet"))
+            }
+            RecordingState::Failed => {
+                let error = status.error.unwrap_or_default();
+                return Ok(Response::error(
+                    409,
+                    &format!("The recording failed: {}", error),
+                ));
+            }
+            RecordingState::Finished => {}
+        }
+        let ext = file_extension(&recording.format);
+        Ok(Response {
+            status: 200,
+            content_type: content_type(ext),
+            headers: vec![(
+                "Content-Disposition",
+                format!(
+                    "attachment; filename=\"{}-{}.{}\"",
+                    status.pid, status.id, ext
+                ),
+            )],
+            body: std::fs::read(&recording.filename)?,
+        })
+    }
+
+    fn delete_recording(&self, id: &str) -> Result<Response, Error> {
+        let recording = match id
+            .parse()
+            .ok()
+            .and_then(|id: u64| self.recordings.lock().unwrap().remove(&id))
+        {
+            Some(recording) => recording,
+            None => return Ok(Response::error(404, "No such recording")),
+        };
+        recording.stop.store(true, Ordering::SeqCst);
+        // the recording might still be writing out its profile, which is removed once it has
+        std::thread::spawn(move || {
+            while recording.status.lock().unwrap().state == RecordingState::Running {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            let _ = std::fs::remove_file(&recording.filename);
+        });
+        Ok(Response {
+            status: 204,
+            content_type: "application/json",
+            headers: Vec::new(),
+            body: Vec::new(),
+        })
+    }
+}
+
+/// Samples a process until the recording is stopped, its duration is up or the process exits,
+/// and then writes out its profile
+fn record(recording: &Recording, config: &Config) -> Result<(), Error> {
+    let filename = recording.filename.to_string_lossy().to_string();
+    let sampler = Sampler::new_multiple(&config.pids, config)?;
+    let mut output = new_recorder(config, &filename)?;
+    if let Some(metadata) = sampler.metadata.as_ref() {
+        output.set_metadata(metadata)?;
+    }
+
+    let start = Instant::now();
+    let mut stats = SamplerStats::new(config.sampling_rate);
+    let mut errors = 0;
+    for mut sample in sampler {
+        if recording.stop.load(Ordering::SeqCst) {
+            break;
+        }
+        if let RecordDuration::Seconds(seconds) = config.duration {
+            if start.elapsed() >= Duration::from_secs(seconds) {
+                break;
+            }
+        }
+        if sample.paused {
+            continue;
+        }
+        stats.add_sample(&sample);
+        recording.metrics.lock().unwrap().add_sample(&sample);
+
+        let timestamp = start.elapsed().as_micros() as u64;
+        let mut samples = 0;
+        for trace in sample.traces.iter_mut() {
+            if !(config.include_idle || trace.active) || (config.gil_only && !trace.owns_gil) {
+                continue;
+            }
+            // with --subprocesses, the processes are at the root of the stacks
+            if let Some(process_info) = trace.process_info.clone() {
+                trace.frames.push(process_info.to_frame());
+                let mut parent = process_info.parent.as_ref();
+                while let Some(process_info) = parent {
+                    trace.frames.push(process_info.to_frame());
+                    parent = process_info.parent.as_ref();
+                }
+            }
+            output.increment_at(trace, timestamp)?;
+            samples += 1;
+        }
+        for (pid, e) in sample.sampling_errors.iter().flatten() {
+            warn!("Failed to get stack trace from {}: {}", pid, e);
+            errors += 1;
+        }
+
+        let mut status = recording.status.lock().unwrap();
+        status.samples += samples;
+        status.errors = errors;
+    }
+
+    output.set_errors(errors as usize);
+    stats.errors = errors;
+    stats.sampling_time = start.elapsed().as_secs_f64();
+    output.set_stats(&stats)?;
+    write_output(output.as_mut(), &filename, None)
+}
+
+/// Gets the stack traces of a process, with '?locals=1' for their local variables
+fn dump(request: &Request) -> Result<Response, Error> {
+    let pid = match query_pid(request) {
+        Ok(pid) => pid,
+        Err(response) => return Ok(response),
+    };
+    let mut config = Config::default();
+    config.command = String::from("dump");
+    config.dump_locals = request
+        .query
+        .get("locals")
+        .and_then(|locals| locals.parse().ok())
+        .unwrap_or(0);
+    let mut process = PythonSpy::new(pid, &config)?;
+    let traces = process.get_stack_traces()?;
+    Response::json(200, &traces)
+}
+
+/// Streams the functions using the most time in a process every second, as JSON lines. This
+/// carries on until the process exits, the client disconnects or for '?duration=<seconds>'
+fn top(request: &Request, stream: &mut TcpStream) -> Result<(), Error> {
+    let pid = match query_pid(request) {
+        Ok(pid) => pid,
+        Err(response) => return Ok(response.write(stream)?),
+    };
+    let duration = request
+        .query
+        .get("duration")
+        .and_then(|duration| duration.parse().ok())
+        .map(Duration::from_secs);
+    let mut config = Config::default();
+    config.command = String::from("top");
+    config.pids = vec![pid];
+    let sampler = match Sampler::new_multiple(&config.pids, &config) {
+        Ok(sampler) => sampler,
+        Err(e) => return Ok(Response::error(500, &format!("{:#}", e)).write(stream)?),
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: application/x-ndjson\r\nConnection: close\r\n\r\n"
+    )?;
+    let start = Instant::now();
+    let mut last_summary = Instant::now();
+    let mut traces = Vec::new();
+    let mut errors = 0;
+    for sample in sampler {
+        traces.extend(sample.traces.into_iter().filter(|trace| trace.active));
+        errors += sample
+            .sampling_errors
+            .map_or(0, |errors| errors.len() as u64);
+        if last_summary.elapsed() >= Duration::from_secs(1) {
+            let summary = TopSummary {
+                elapsed: start.elapsed().as_secs_f64(),
+                samples: traces.len() as u64,
+                errors,
+                functions: top_functions(&traces),
+            };
+            let mut line = serde_json::to_vec(&summary)?;
+            line.push(b'\n');
+            // the client has gone away
+            if stream.write_all(&line).is_err() {
+                return Ok(());
+            }
+            traces.clear();
+            errors = 0;
+            last_summary = Instant::now();
+        }
+        if duration.is_some_and(|duration| start.elapsed() >= duration) {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Adds up how many samples each function was running in, or on the stack for, ordered by the
+/// samples it was running in
+fn top_functions(traces: &[StackTrace]) -> Vec<TopFunction> {
+    let mut functions: HashMap<(&str, &str), TopFunction> = HashMap::new();
+    for trace in traces {
+        let mut seen = std::collections::HashSet::new();
+        for (depth, frame) in trace.frames.iter().enumerate() {
+            let key = (frame.name.as_str(), frame.filename.as_str());
+            let function = functions.entry(key).or_insert_with(|| TopFunction {
+                function: frame.name.clone(),
+                filename: frame.filename.clone(),
+                own: 0,
+                total: 0,
+            });
+            if depth == 0 {
+                function.own += 1;
+            }
+            // recursive functions are only counted once per sample
+            if seen.insert(key) {
+                function.total += 1;
+            }
+        }
+    }
+    let mut functions: Vec<TopFunction> = functions.into_values().collect();
+    functions.sort_by(|a, b| {
+        (b.own, b.total, &a.function, &a.filename).cmp(&(a.own, a.total, &b.function, &b.filename))
+    });
+    functions.truncate(TOP_FUNCTIONS);
+    functions
+}
+
+fn query_pid(request: &Request) -> Result<Pid, Response> {
+    match request.query.get("pid").map(|pid| pid.parse()) {
+        Some(Ok(pid)) => Ok(pid),
+        Some(Err(_)) => Err(Response::error(400, "Invalid pid")),
+        None => Err(Response::error(400, "A pid is required, like '?pid=1234'")),
+    }
+}
+
+/// Compares the token from a request without leaking how much of it matched through timing
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+fn content_type(ext: &str) -> &'static str {
+    match ext {
+        "svg" => "image/svg+xml",
+        "json" => "application/json",
+        "html" => "text/html",
+        "txt" | "csv" => "text/plain",
+        "jsonl" => "application/x-ndjson",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{frame, trace};
+
+    #[test]
+    fn test_record_request() {
+        let raw = b"POST /recordings?pid=12&name=a%20b HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer secret\r\nContent-Length: 11\r\n\r\n{\"pid\": 12}";
+        let request = read_request(&mut &raw[..]).unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/recordings");
+        assert_eq!(request.query["pid"], "12");
+        assert_eq!(request.query["name"], "a b");
+        assert_eq!(request.headers["authorization"], "Bearer secret");
+        let body: RecordRequest = serde_json::from_slice(&request.body).unwrap();
+        assert_eq!(body.pid, 12);
+        let config = body.config().unwrap();
+        assert_eq!(config.format, Some(FileFormat::speedscope));
+        assert_eq!(config.duration, RecordDuration::Unlimited);
+
+        let body = br#"{"pid": 12, "format": "flamegraph", "duration": 10, "idle": true}"#;
+        let config = serde_json::from_slice::<RecordRequest>(body)
+            .unwrap()
+            .config()
+            .unwrap();
+        assert_eq!(config.format, Some(FileFormat::flamegraph));
+        assert_eq!(config.duration, RecordDuration::Seconds(10));
+        assert!(config.include_idle);
+        let body = br#"{"pid": 12, "format": "bmp"}"#;
+        let request: RecordRequest = serde_json::from_slice(body).unwrap();
+        assert!(request.config().is_err());
+        assert!(serde_json::from_slice::<RecordRequest>(br#"{"pid": 12, "rates": 5}"#).is_err());
+    }
+
+    #[test]
+    fn test_authorized() {
+        let agent = Agent {
+            token: String::from("secret"),
+            dir: PathBuf::new(),
+            recordings: Mutex::new(BTreeMap::new()),
+            next_id: AtomicU64::new(1),
+        };
+        let request = |authorization: &str| {
+            let raw = format!(
+                "GET /recordings HTTP/1.1\r\nAuthorization: {}\r\n\r\n",
+                authorization
+            );
+            read_request(&mut raw.as_bytes()).unwrap()
+        };
+        assert!(agent.authorized(&request("Bearer secret")));
+        assert!(!agent.authorized(&request("Bearer secre")));
+        assert!(!agent.authorized(&request("Basic secret")));
+        let raw = b"GET /recordings HTTP/1.1\r\n\r\n";
+        assert!(!agent.authorized(&read_request(&mut &raw[..]).unwrap()));
+    }
+
+    #[test]
+    fn test_top_functions() {
+        let frame = |name: &str| frame(name, "app.py", 1);
+        let trace = |names: &[&str]| trace(names.iter().map(|name| frame(name)).collect());
+        let traces = vec![
+            trace(&["work", "work", "main"]),
+            trace(&["work", "main"]),
+            trace(&["sleep", "main"]),
+        ];
+        let functions = top_functions(&traces);
+        let summary: Vec<(&str, u64, u64)> = functions
+            .iter()
+            .map(|f| (f.function.as_str(), f.own, f.total))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![("work", 2, 2), ("sleep", 1, 1), ("main", 0, 3)]
+        );
+    }
+}
@@ -0,0 +1,440 @@
+use std::collections::HashMap;
+use std::mem::size_of;
+
+use anyhow::{Context, Error, Result};
+use remoteprocess::ProcessMemory;
+
+use crate::config::LineNo;
+use crate::python_bindings::{v3_10_0, v3_11_0, v3_7_0, v3_8_0, v3_9_5};
+use crate::python_data_access::{
+    copy_long, copy_string, find_key, object_dict, set_items, DictIterator,
+};
+use crate::python_interpreters::{FrameObject, InterpreterState, Object, ThreadState, TypeObject};
+use crate::stack_trace::{get_frame, get_qualified_name, Frame};
+use crate::struct_offsets::{copy_pointer, copy_struct};
+use crate::version::Version;
+
+/*
+ * This file contains code to read the stacks of the asyncio tasks in a process that are
+ * suspended, for '--asyncio'. A suspended task isn't on the stack of any thread, so the only way
+ * of seeing where it is waiting is to follow the chain of coroutines it is awaiting.
+ *
+ * Every task is in the 'asyncio.tasks._all_tasks' WeakSet. The coroutine a task is running isn't
+ * in the same place in the task on each version of python (and the layout of tasks isn't in
+ * python's headers), so it's found as the field of the task that points to a coroutine. The name
+ * of the task is the field after it (from python 3.8 on).
+ *
+ * A suspended coroutine is always suspended at an 'await', and the object it's awaiting is on
+ * the top of the value stack of its frame. When that's another coroutine, it's followed in turn,
+ * until the innermost coroutine (which is usually awaiting a future). Coroutines that are running
+ * are skipped, since they're already on the stack of the thread running them.
+ *
+ * The layout of coroutines (and generators, which generator based coroutines are) only differs
+ * in the fields at the start, which are defined here for each version of python.
+ */
+
+// the most fields of a task to look for its coroutine in
+const MAX_TASK_FIELDS: usize = 48;
+
+// the state of a suspended frame in python 3.10 and 3.11 (the FRAME_SUSPENDED enum value)
+const FRAME_SUSPENDED: i8 = -1;
+
+/// A suspended asyncio task, and the stack of coroutines it's awaiting
+#[derive(Debug, Clone)]
+pub struct AsyncioTask {
+    /// The name of the task, like 'Task-1'
+    pub name: String,
+    /// The python thread id of the thread running the event loop the task belongs to
+    pub thread_id: u64,
+    /// Identifies the task the same way StackTrace::task_id does while the task is running
+    pub task_id: u64,
+    /// The frames of the coroutines being awaited, innermost first
+    pub frames: Vec<Frame>,
+}
+
+/// The fields at the start of a coroutine in python 3.7 to 3.9
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct Coroutine3_7 {
+    ob_refcnt: isize,
+    ob_type: usize,
+    frame: usize,
+    running: i8,
+}
+
+/// The fields at the start of a coroutine in python 3.10
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct Coroutine3_10 {
+    ob_refcnt: isize,
+    ob_type: usize,
+    frame: usize,
+}
+
+/// The fields of a coroutine in python 3.11, which is followed by its frame
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct Coroutine3_11 {
+    ob_refcnt: isize,
+    ob_type: usize,
+    code: usize,
+    weakreflist: usize,
+    name: usize,
+    qualname: usize,
+    exc_value: usize,
+    exc_previous_item: usize,
+    origin_or_finalizer: usize,
+    hooks_inited: i8,
+    closed: i8,
+    running_async: i8,
+    frame_state: i8,
+}
+
+/// Reads the frames of suspended coroutines, which are stored differently in each version
+pub trait SuspendedFrame: FrameObject + Copy {
+    /// The address and contents of the frame of the coroutine at 'addr', if it's suspended
+    fn suspended<P: ProcessMemory>(process: &P, addr: usize) -> Result<Option<(usize, Self)>>;
+
+    /// The address of the top of the value stack of this (suspended) frame at 'addr', which
+    /// holds what the coroutine is awaiting
+    fn stack_top(&self, addr: usize) -> Option<usize>;
+}
+
+macro_rules! SuspendedFrameImpl {
+    ($py: ident) => {
+        impl SuspendedFrame for $py::PyFrameObject {
+            fn suspended<P: ProcessMemory>(
+                process: &P,
+                addr: usize,
+            ) -> Result<Option<(usize, Self)>> {
+                let coroutine: Coroutine3_7 = process.copy_struct(addr)?;
+                if coroutine.running != 0 || coroutine.frame == 0 {
+                    return Ok(None);
+                }
+                Ok(Some((
+                    coroutine.frame,
+                    copy_struct(process, coroutine.frame)?,
+                )))
+            }
+
+            fn stack_top(&self, _addr: usize) -> Option<usize> {
+                // coroutines that haven't started yet have an empty stack
+                if self.f_lasti < 0 || self.f_stacktop <= self.f_valuestack {
+                    return None;
+                }
+                Some(self.f_stacktop as usize - size_of::<usize>())
+            }
+        }
+    };
+}
+SuspendedFrameImpl!(v3_7_0);
+SuspendedFrameImpl!(v3_8_0);
+SuspendedFrameImpl!(v3_9_5);
+
+impl SuspendedFrame for v3_10_0::PyFrameObject {
+    fn suspended<P: ProcessMemory>(process: &P, addr: usize) -> Result<Option<(usize, Self)>> {
+        let coroutine: Coroutine3_10 = process.copy_struct(addr)?;
+        if coroutine.frame == 0 {
+            return Ok(None);
+        }
+        let frame: Self = copy_struct(process, coroutine.frame)?;
+        if frame.f_state != FRAME_SUSPENDED {
+            return Ok(None);
+        }
+        Ok(Some((coroutine.frame, frame)))
+    }
+
+    fn stack_top(&self, _addr: usize) -> Option<usize> {
+        if self.f_stackdepth <= 0 {
+            return None;
+        }
+        Some(self.f_valuestack as usize + (self.f_stackdepth as usize - 1) * size_of::<usize>())
+    }
+}
+
+impl SuspendedFrame for v3_11_0::_PyInterpreterFrame {
+    fn suspended<P: ProcessMemory>(process: &P, addr: usize) -> Result<Option<(usize, Self)>> {
+        let coroutine: Coroutine3_11 = process.copy_struct(addr)?;
+        if coroutine.frame_state != FRAME_SUSPENDED {
+            return Ok(None);
+        }
+        let frame = addr + size_of::<Coroutine3_11>();
+        Ok(Some((frame, copy_struct(process, frame)?)))
+    }
+
+    fn stack_top(&self, addr: usize) -> Option<usize> {
+        if self.stacktop <= 0 {
+            return None;
+        }
+        let localsplus = addr + std::mem::offset_of!(v3_11_0::_PyInterpreterFrame, localsplus);
+        Some(localsplus + (self.stacktop as usize - 1) * size_of::<usize>())
+    }
+}
+
+/// Reads the asyncio tasks of an interpreter (and the coroutines of trio tasks), keeping track of
+/// the type names it has read and where the coroutine is in each type of task
+pub struct TaskReader<'a, I: InterpreterState, P: ProcessMemory> {
+    process: &'a P,
+    version: &'a Version,
+    lineno: LineNo,
+    qualified_names: bool,
+    type_names: HashMap<usize, String>,
+    coroutine_fields: &'a mut HashMap<usize, usize>,
+    _interpreter: std::marker::PhantomData<I>,
+}
+
+impl<'a, I, P> TaskReader<'a, I, P>
+where
+    I: InterpreterState,
+    P: ProcessMemory,
+    <I::ThreadState as ThreadState>::FrameObject: SuspendedFrame,
+{
+    pub fn new(
+        process: &'a P,
+        version: &'a Version,
+        lineno: LineNo,
+        qualified_names: bool,
+        coroutine_fields: &'a mut HashMap<usize, usize>,
+    ) -> TaskReader<'a, I, P> {
+        TaskReader {
+            process,
+            version,
+            lineno,
+            qualified_names,
+            type_names: HashMap::new(),
+            coroutine_fields,
+            _interpreter: std::marker::PhantomData,
+        }
+    }
+
+    /// Gets the suspended tasks of an interpreter whose event loop is running on a thread. This
+    /// is empty if asyncio hasn't been imported
+    pub fn suspended_tasks(&mut self, interp: &I) -> Result<Vec<AsyncioTask>, Error> {
+        let tasks = match self.all_tasks(interp)? {
+            Some(tasks) => tasks,
+            None => return Ok(Vec::new()),
+        };
+
+        // the thread each event loop is running on
+        let mut loop_threads: HashMap<usize, Option<u64>> = HashMap::new();
+        let mut suspended = Vec::new();
+        for task in tasks {
+            let task = match self.suspended_task(task, &mut loop_threads) {
+                Ok(Some(task)) => task,
+                Ok(None) => continue,
+                Err(e) => {
+                    // tasks can finish while they're being read
+                    debug!("Failed to read asyncio task at 0x{:x}: {}", task, e);
+                    continue;
+                }
+            };
+            suspended.push(task);
+        }
+        Ok(suspended)
+    }
+
+    /// Reads every task in 'asyncio.tasks._all_tasks', if asyncio has been imported
+    fn all_tasks(&mut self, interp: &I) -> Result<Option<Vec<usize>>, Error> {
+        let process = self.process;
+        let module = match find_key::<I::StringObject, P>(
+            DictIterator::from(process, self.version, interp.modules() as usize)?,
+            process,
+            "asyncio.tasks",
+        )? {
+            Some(module) => module,
+            None => return Ok(None),
+        };
+        let all_tasks = find_key::<I::StringObject, P>(
+            object_dict::<I, P>(process, self.version, module)?,
+            process,
+            "_all_tasks",
+        )?
+        .ok_or_else(|| format_err!("Failed to find asyncio.tasks._all_tasks"))?;
+        let data = find_key::<I::StringObject, P>(
+            object_dict::<I, P>(process, self.version, all_tasks)?,
+            process,
+            "data",
+        )?
+        .ok_or_else(|| format_err!("Failed to find the set of asyncio tasks"))?;
+
+        let mut tasks = Vec::new();
+        for weakref in set_items(process, data)? {
+            if !matches!(
+                self.type_name(weakref)?.as_str(),
+                "weakref" | "weakref.ReferenceType"
+            ) {
+                continue;
+            }
+            // the object a weakref refers to comes after the object header
+            let task: usize = process.copy_struct(weakref + 2 * size_of::<usize>())?;
+            if self.type_name(task)? != "NoneType" {
+                tasks.push(task);
+            }
+        }
+        Ok(Some(tasks))
+    }
+
+    fn suspended_task(
+        &mut self,
+        task: usize,
+        loop_threads: &mut HashMap<usize, Option<u64>>,
+    ) -> Result<Option<AsyncioTask>, Error> {
+        let fields: Vec<usize> = self.process.copy_vec(task, MAX_TASK_FIELDS)?;
+        // the event loop is the first field after the object header, in every version
+        let event_loop = fields[2];
+        let thread_id = match loop_threads.get(&event_loop) {
+            Some(thread_id) => *thread_id,
+            None => {
+                let thread_id = self.loop_thread_id(event_loop).unwrap_or(None);
+                loop_threads.insert(event_loop, thread_id);
+                thread_id
+            }
+        };
+        let thread_id = match thread_id {
+            Some(thread_id) => thread_id,
+            None => return Ok(None),
+        };
+
+        let index = match self.coroutine_field(task, &fields)? {
+            Some(index) => index,
+            None => return Ok(None),
+        };
+        let coroutine = fields[index];
+
+        let name = match fields.get(index + 1) {
+            Some(&name) if self.version.minor >= 8 && self.type_name(name)? == "str" => {
+                copy_string(name as *const I::StringObject, self.process)?
+            }
+            _ => format!("Task at 0x{:x}", task),
+        };
+
+        let (frames, task_id) = self.awaiting(coroutine)?;
+        if frames.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(AsyncioTask {
+            name,
+            thread_id,
+            task_id,
+            frames,
+        }))
+    }
+
+    /// The python thread id of the thread an event loop is running on, if it's running
+    fn loop_thread_id(&mut self, event_loop: usize) -> Result<Option<u64>, Error> {
+        let dict = object_dict::<I, P>(self.process, self.version, event_loop)?;
+        let thread_id = match find_key::<I::StringObject, P>(dict, self.process, "_thread_id")? {
+            Some(thread_id) => thread_id,
+            None => return Ok(None),
+        };
+        if self.type_name(thread_id)? != "int" {
+            return Ok(None);
+        }
+        let (thread_id, _) = copy_long(self.process, thread_id)?;
+        Ok(Some(thread_id as u64))
+    }
+
+    /// The index of the field of a task that is its coroutine
+    fn coroutine_field(&mut self, task: usize, fields: &[usize]) -> Result<Option<usize>, Error> {
+        let task_type: usize = fields[1];
+        if let Some(&index) = self.coroutine_fields.get(&task_type) {
+            return Ok(Some(index));
+        }
+        for (index, &field) in fields.iter().enumerate().skip(3) {
+            if field == 0 || field % size_of::<usize>() != 0 {
+                continue;
+            }
+            if let Ok(name) = self.type_name(field) {
+                if is_coroutine(&name) {
+                    debug!(
+                        "Found the coroutine of asyncio task 0x{:x} in field {}",
+                        task, index
+                    );
+                    self.coroutine_fields.insert(task_type, index);
+                    return Ok(Some(index));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Follows the chain of coroutines that a coroutine is awaiting, returning their frames
+    /// (innermost first) and the id of the task
+    pub fn awaiting(&mut self, coroutine: usize) -> Result<(Vec<Frame>, u64), Error> {
+        let mut frames = Vec::new();
+        let mut task_id = 0;
+        let mut awaiting = coroutine;
+        while is_coroutine(&self.type_name(awaiting)?) {
+            let (address, frame) = match <I::ThreadState as ThreadState>::FrameObject::suspended(
+                self.process,
+                awaiting,
+            )? {
+                Some(frame) => frame,
+                None => break,
+            };
+            let code =
+                copy_pointer(self.process, frame.code()).context("Failed to copy PyCodeObject")?;
+            if frames.is_empty() {
+                // the same as the task_id of the stack traces of threads running this task
+                task_id = address as u64 ^ frame.code() as u64;
+            }
+            let mut python_frame = get_frame(&code, frame.lasti(), self.process, self.lineno)?;
+            if self.qualified_names {
+                if let Ok(Some(name)) = get_qualified_name::<
+                    <I::Object as Object>::TypeObject,
+                    _,
+                    _,
+                    P,
+                >(&code, address, &frame, self.process)
+                {
+                    python_frame.name = name;
+                }
+            }
+            frames.push(python_frame);
+            if frames.len() > 4096 {
+                return Err(format_err!("Max coroutine recursion depth reached"));
+            }
+
+            awaiting = match frame.stack_top(address) {
+                Some(top) => self.process.copy_struct(top)?,
+                None => break,
+            };
+        }
+        frames.reverse();
+        Ok((frames, task_id))
+    }
+
+    /// The name of the type of the python object at an address
+    pub fn type_name(&mut self, addr: usize) -> Result<String, Error> {
+        let object: I::Object = self.process.copy_struct(addr)?;
+        let type_addr = object.ob_type() as usize;
+        if let Some(name) = self.type_names.get(&type_addr) {
+            return Ok(name.clone());
+        }
+        let object_type = self.process.copy_pointer(object.ob_type())?;
+        let max_len = 128;
+        let name = self.process.copy(object_type.name() as usize, max_len)?;
+        let length = name.iter().position(|&x| x == 0).unwrap_or(max_len);
+        let name = String::from_utf8_lossy(&name[..length]).into_owned();
+        self.type_names.insert(type_addr, name.clone());
+        Ok(name)
+    }
+}
+
+fn is_coroutine(type_name: &str) -> bool {
+    type_name == "coroutine" || type_name == "generator"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(target_pointer_width = "64")]
+    fn test_coroutine_layouts() {
+        // the frame of a coroutine comes straight after these fields in python 3.11
+        assert_eq!(size_of::<Coroutine3_11>(), 80);
+        assert_eq!(std::mem::offset_of!(Coroutine3_11, frame_state), 75);
+        assert_eq!(std::mem::offset_of!(Coroutine3_7, running), 24);
+    }
+}
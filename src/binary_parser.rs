@@ -7,10 +7,12 @@ use goblin::Object;
 use memmap::Mmap;
 
 pub struct BinaryInfo {
+    #[allow(dead_code)]
     pub filename: std::path::PathBuf,
     pub symbols: HashMap<String, u64>,
     pub bss_addr: u64,
     pub bss_size: u64,
+    #[allow(dead_code)]
     pub offset: u64,
     pub addr: u64,
     pub size: u64,
@@ -99,7 +101,7 @@ pub fn parse_binary(filename: &Path, addr: u64, size: u64) -> Result<BinaryInfo,
                 .filter(|header| {
                     strtab
                         .get_at(header.sh_name)
-                        .map_or(true, |name| name == ".bss")
+                        .is_none_or(|name| name == ".bss")
                 })
                 // if we have multiple sections here, take the largest
                 .max_by_key(|header| header.sh_size)
@@ -128,11 +130,20 @@ pub fn parse_binary(filename: &Path, addr: u64, size: u64) -> Result<BinaryInfo,
             // the map address is relatively small. In this case we can default to 0.
             let offset = offset.saturating_sub(program_header.p_vaddr);
 
+            // symbols imported from other libraries (like the python api in an extension module)
+            // don't have an address in this binary
+            let undefined = goblin::elf::section_header::SHN_UNDEF as usize;
             for sym in elf.syms.iter() {
+                if sym.st_shndx == undefined {
+                    continue;
+                }
                 let name = elf.strtab[sym.st_name].to_string();
                 symbols.insert(name, sym.st_value + offset);
             }
             for dynsym in elf.dynsyms.iter() {
+                if dynsym.st_shndx == undefined {
+                    continue;
+                }
                 let name = elf.dynstrtab[dynsym.st_name].to_string();
                 symbols.insert(name, dynsym.st_value + offset);
             }
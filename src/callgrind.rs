@@ -0,0 +1,194 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+
+use anyhow::Error;
+
+use crate::stack_trace::{Frame, StackTrace};
+
+/*
+ * This file contains code to export py-spy profiles in the callgrind format, for
+ * viewing in KCachegrind/QCachegrind.
+ *
+ * The format is documented at https://valgrind.org/docs/manual/cl-format.html
+ *
+ * Unlike the flamegraph output, callgrind files store an edge weighted call graph: each
+ * function has a 'self' cost for samples where it was the leaf frame, and an inclusive cost
+ * for each of the functions that it called. We use the number of samples as the only event
+ * type, and count each sample that an edge appeared in as a single call.
+ */
+
+#[derive(Default)]
+struct FunctionCosts {
+    // line -> number of samples with this function as the leaf
+    self_costs: BTreeMap<i32, u64>,
+    // (caller line, callee function id, callee line) -> number of samples including this edge
+    calls: BTreeMap<(i32, usize, i32), u64>,
+}
+
+pub struct Callgrind {
+    // (filename, function name) -> function id
+    function_ids: HashMap<(String, String), usize>,
+    functions: Vec<(String, String)>,
+    costs: HashMap<usize, FunctionCosts>,
+    total: u64,
+    show_linenumbers: bool,
+}
+
+impl Callgrind {
+    pub fn new(show_linenumbers: bool) -> Callgrind {
+        Callgrind {
+            function_ids: HashMap::new(),
+            functions: Vec::new(),
+            costs: HashMap::new(),
+            total: 0,
+            show_linenumbers,
+        }
+    }
+
+    pub fn increment(&mut self, trace: &StackTrace) -> std::io::Result<()> {
+        let frames: Vec<(usize, i32)> = trace
+            .frames
+            .iter()
+            .map(|frame| (self.function_id(frame), self.line(frame)))
+            .collect();
+
+        if let Some((leaf, line)) = frames.first() {
+            let costs = self.costs.entry(*leaf).or_default();
+            *costs.self_costs.entry(*line).or_insert(0) += 1;
+        }
+
+        // frames are stored leaf first, so each window is a (callee, caller) pair
+        for pair in frames.windows(2) {
+            let (callee, callee_line) = pair[0];
+            let (caller, caller_line) = pair[1];
+            let costs = self.costs.entry(caller).or_default();
+            *costs
+                .calls
+                .entry((caller_line, callee, callee_line))
+                .or_insert(0) += 1;
+        }
+
+        self.total += 1;
+        Ok(())
+    }
+
+    pub fn write(&self, w: &mut dyn Write) -> Result<(), Error> {
+        writeln!(w, "# callgrind format")?;
+        writeln!(w, "version: 1")?;
+        writeln!(w, "creator: py-spy {}", env!("CARGO_PKG_VERSION"))?;
+        writeln!(
+            w,
+            "cmd: {}",
+            std::env::args().collect::<Vec<String>>().join(" ")
+        )?;
+        writeln!(w, "positions: line")?;
+        writeln!(w, "events: Samples")?;
+        writeln!(w, "summary: {}", self.total)?;
+
+        // callgrind lets us compress repeated file and function names by only writing out
+        // the name the first time an id is used
+        let mut written_files = HashMap::new();
+        let mut written_functions = vec![false; self.functions.len()];
+        let mut file_name = |filename: &str| {
+            let next_id = written_files.len() + 1;
+            match written_files.get(filename) {
+                Some(id) => format!("({})", id),
+                None => {
+                    written_files.insert(filename.to_owned(), next_id);
+                    format!("({}) {}", next_id, filename)
+                }
+            }
+        };
+        let mut function_name = |id: usize| {
+            if written_functions[id] {
+                format!("({})", id + 1)
+            } else {
+                written_functions[id] = true;
+                format!("({}) {}", id + 1, self.functions[id].1)
+            }
+        };
+
+        let mut ids: Vec<&usize> = self.costs.keys().collect();
+        ids.sort();
+        for id in ids {
+            let costs = &self.costs[id];
+            writeln!(w)?;
+            writeln!(w, "fl={}", file_name(&self.functions[*id].0))?;
+            writeln!(w, "fn={}", function_name(*id))?;
+            for (line, cost) in costs.self_costs.iter() {
+                writeln!(w, "{} {}", line, cost)?;
+            }
+            for ((line, callee, callee_line), cost) in costs.calls.iter() {
+                writeln!(w, "cfl={}", file_name(&self.functions[*callee].0))?;
+                writeln!(w, "cfn={}", function_name(*callee))?;
+                writeln!(w, "calls={} {}", cost, callee_line)?;
+                writeln!(w, "{} {}", line, cost)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn function_id(&mut self, frame: &Frame) -> usize {
+        let filename = match &frame.short_filename {
+            Some(f) => f,
+            None => &frame.filename,
+        };
+        let functions = &mut self.functions;
+        *self
+            .function_ids
+            .entry((filename.clone(), frame.name.clone()))
+            .or_insert_with_key(|key| {
+                functions.push(key.clone());
+                functions.len() - 1
+            })
+    }
+
+    fn line(&self, frame: &Frame) -> i32 {
+        if self.show_linenumbers {
+            frame.line
+        } else {
+            0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{frame, trace};
+
+    #[test]
+    fn test_callgrind_output() {
+        let mut callgrind = Callgrind::new(true);
+        let mut trace = trace(vec![
+            frame("inner", "test.py", 2),
+            frame("outer", "test.py", 10),
+        ]);
+        callgrind.increment(&trace).unwrap();
+        callgrind.increment(&trace).unwrap();
+        trace.frames.remove(0);
+        callgrind.increment(&trace).unwrap();
+
+        let mut out = Vec::new();
+        callgrind.write(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let body: Vec<&str> = out.lines().skip_while(|l| !l.is_empty()).collect();
+        assert_eq!(
+            body,
+            vec![
+                "",
+                "fl=(1) test.py",
+                "fn=(1) inner",
+                "2 2",
+                "",
+                "fl=(1)",
+                "fn=(2) outer",
+                "10 1",
+                "cfl=(1)",
+                "cfn=(1)",
+                "calls=2 2",
+                "10 2",
+            ]
+        );
+    }
+}
@@ -1,51 +1,249 @@
 use std::cmp::min;
-use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::{Hash, Hasher};
 use std::io::Write;
 use std::time::Instant;
 
 use anyhow::Error;
-use serde_derive::Serialize;
+use remoteprocess::Pid;
+use serde::Serialize;
 
+use crate::frame_category;
+use crate::process_tree::{forked_from, ProcessTree};
+use crate::sampler_stats::SamplerStats;
 use crate::stack_trace::Frame;
-use crate::stack_trace::StackTrace;
+use crate::stack_trace::{StackTrace, ThreadStatus};
+use crate::thread_lifetimes::{ThreadEvent, ThreadEventKind};
+use crate::utils::process_usage;
+use crate::MemoryCounters;
 
-#[derive(Clone, Debug, Serialize)]
+/*
+ * This file contains code to write out samples in the Chrome trace event format, which can be
+ * loaded in chrome://tracing or Perfetto.
+ *
+ * Events are written out to the output file as they're generated, so that only the frames that
+ * are currently open on each thread need to be kept in memory. Since the trace format allows the
+ * closing ']' of the event array to be missing, the output can be loaded even if recording is
+ * stopped before the file is finished off.
+ */
+
+// how often to write out counter events, in microseconds
+const COUNTER_INTERVAL: u64 = 100_000;
+
+// how long to remember asyncio tasks that haven't been seen, in microseconds
+const TASK_TIMEOUT: u64 = 60_000_000;
+
+// added to the remapped id of a thread to get the id of its --thread-states track
+const STATUS_TRACK_OFFSET: u32 = 1 << 30;
+
+#[derive(Clone, Debug, serde_derive::Serialize)]
 struct Args {
     pub filename: String,
     pub line: Option<u32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sort_index: Option<u32>,
+    // labels shown next to the name of a process
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub labels: Option<String>,
+    // where the frame's code is from (stdlib, site-packages, application or native)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub category: Option<&'static str>,
+    // the dotted python module path of the frame
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub module: Option<String>,
+    // the shared library that a native frame is from
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub library: Option<String>,
 }
 
-#[derive(Clone, Debug, Serialize)]
+#[derive(Clone, Debug, serde_derive::Serialize)]
 struct Event {
     pub args: Args,
     pub cat: String,
+    // one of the reserved colour names from the trace viewer
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cname: Option<&'static str>,
+    pub name: String,
+    pub ph: String,
+    pub pid: u64,
+    pub tid: u32,
+    pub ts: u64,
+}
+
+// Counter events are shown as a track for each process, with a line for each of the args
+#[derive(Clone, Debug, serde_derive::Serialize)]
+struct Counter {
+    pub args: BTreeMap<&'static str, f64>,
+    pub cat: String,
+    pub name: String,
+    pub ph: String,
+    pub pid: u64,
+    pub ts: u64,
+}
+
+// Flow events are drawn as an arrow from the slice enclosing the start ('s') event to the slice
+// enclosing the finish ('f') event
+#[derive(Clone, Debug, serde_derive::Serialize)]
+struct Flow {
+    pub cat: String,
+    pub name: String,
+    pub ph: String,
+    pub id: u64,
+    pub pid: u64,
+    pub tid: u32,
+    pub ts: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bp: Option<String>,
+}
+
+// Sample events point to the leaf of their stack, in the 'stackFrames' section of the trace
+#[derive(Clone, Debug, serde_derive::Serialize)]
+struct SampleEvent {
+    pub cat: String,
+    pub name: String,
+    pub ph: String,
+    pub pid: u64,
+    pub tid: u32,
+    pub ts: u64,
+    pub sf: u64,
+}
+
+#[derive(Clone, Debug, serde_derive::Serialize)]
+struct StackFrame {
+    pub category: String,
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent: Option<u64>,
+}
+
+// Instant events are drawn as a marker on a thread at a single point in time
+#[derive(Clone, Debug, serde_derive::Serialize)]
+struct InstantEvent {
+    pub args: BTreeMap<&'static str, String>,
+    pub cat: String,
     pub name: String,
     pub ph: String,
     pub pid: u64,
     pub tid: u32,
     pub ts: u64,
+    // the scope of the event: 't' for thread, 'g' for the whole trace
+    pub s: String,
+}
+
+// What was seen for a process during the current COUNTER_INTERVAL
+#[derive(Default)]
+struct CounterWindow {
+    // the number of sampling intervals, and how many of those had a thread holding the GIL
+    rounds: u64,
+    gil_rounds: u64,
+    active_threads: u64,
+    idle_threads: u64,
+    // timestamp of the last sampling interval, and whether the GIL was held during it
+    last_round: Option<u64>,
+    gil_held: bool,
 }
 
 pub struct Chrometrace {
-    events: Vec<Event>,
+    output: Box<dyn Write>,
+    events_written: u64,
+    counter_windows: BTreeMap<Pid, CounterWindow>,
+    window_start: u64,
+    // whether to add cpu and memory counters, which are read from the running process
+    process_stats: bool,
+    // (time, cpu time in seconds) the last time cpu usage was read for each process
+    cpu_times: HashMap<Pid, (Instant, f64)>,
+    // (pid, task id) -> (thread id, timestamp) of the last sample each asyncio task was seen in
+    tasks: HashMap<(Pid, u64), (u32, u64)>,
+    flows: u64,
     start_ts: Instant,
+    // added to all timestamps, to anchor them to the unix epoch with --absolute-timestamps
+    ts_offset: u64,
+    end_ts: u64,
     prev_traces: HashMap<u64, StackTrace>,
     show_linenumbers: bool,
     // Perfetto only supports 32bit thread IDs so we remap them in the actual emitted events.
+    // (the native perfetto output format uses track descriptors instead, and doesn't need this)
     thread_ids: HashMap<u64, u32>,
+    used_thread_ids: HashSet<u32>,
+    // the last thread_name written out for each thread
+    thread_names: HashMap<u64, String>,
+    // pid -> sort index of each process
+    process_ids: HashMap<Pid, u32>,
+    // whether to write out each sample as a sample event instead of merging consecutive samples
+    // into slices (which can make recursive code look like a few long calls). The tree of stack
+    // frames they point into is written out once recording finishes, at the end of the file
+    stack_samples: bool,
+    // (parent id, name, filename, line) -> id of each stack frame, with --stack-samples
+    stack_frame_ids: HashMap<(Option<u64>, String, String, i32), u64>,
+    stack_frames: BTreeMap<u64, StackFrame>,
+    /// Slices shorter than this (in microseconds) are left out of the trace, to keep down the size
+    /// of traces recorded at high sampling rates
+    pub min_slice_duration: u64,
+    // thread id -> start events for the innermost slices on each thread, which haven't been
+    // written out yet because they're shorter than min_slice_duration so far (outermost first)
+    pending_slices: HashMap<u64, Vec<Event>>,
+    /// Whether to add a track underneath each thread showing what it was doing (running, waiting
+    /// on the GIL, blocked on I/O or sleeping)
+    pub thread_states: bool,
+    // thread id -> (pid, track id, status) of the status slice that's open on each thread
+    statuses: HashMap<u64, (Pid, u32, ThreadStatus)>,
+    // the processes sampled with --subprocesses, and the processes they were forked from
+    processes: ProcessTree,
 }
 
 impl Chrometrace {
-    pub fn new(show_linenumbers: bool) -> Chrometrace {
-        Chrometrace {
-            events: Vec::new(),
+    pub fn new(
+        mut output: Box<dyn Write>,
+        show_linenumbers: bool,
+        process_stats: bool,
+        start_time: Option<u64>,
+        stack_samples: bool,
+    ) -> std::io::Result<Chrometrace> {
+        // the stack frames have to be written after the events, which needs the object format
+        if stack_samples {
+            output.write_all(b"{\"traceEvents\": [\n")?;
+        } else {
+            output.write_all(b"[\n")?;
+        }
+        Ok(Chrometrace {
+            output,
+            events_written: 0,
+            counter_windows: BTreeMap::new(),
+            window_start: 0,
+            process_stats,
+            cpu_times: HashMap::new(),
+            tasks: HashMap::new(),
+            flows: 0,
             start_ts: Instant::now(),
+            ts_offset: start_time.unwrap_or(0),
+            end_ts: 0,
             prev_traces: HashMap::new(),
             show_linenumbers,
             thread_ids: HashMap::new(),
+            used_thread_ids: HashSet::new(),
+            thread_names: HashMap::new(),
+            process_ids: HashMap::new(),
+            stack_samples,
+            stack_frame_ids: HashMap::new(),
+            stack_frames: BTreeMap::new(),
+            min_slice_duration: 0,
+            pending_slices: HashMap::new(),
+            thread_states: false,
+            statuses: HashMap::new(),
+            processes: ProcessTree::default(),
+        })
+    }
+
+    fn write_event<T: Serialize>(&mut self, event: &T) -> std::io::Result<()> {
+        if self.events_written > 0 {
+            self.output.write_all(b",\n")?;
         }
+        serde_json::to_writer(&mut self.output, event)?;
+        self.events_written += 1;
+        Ok(())
     }
 
     // Return whether these frames are similar enough such that we should merge
@@ -59,7 +257,8 @@ impl Chrometrace {
             tid: self.get_thread_id(trace),
             pid: trace.pid as u64,
             name: frame.name.to_string(),
-            cat: "py-spy".to_owned(),
+            cat: category(frame).to_owned(),
+            cname: None,
             ph: phase.to_owned(),
             ts,
             args: Args {
@@ -70,49 +269,182 @@ impl Chrometrace {
                     None
                 },
                 name: None,
+                sort_index: None,
+                labels: None,
+                category: Some(frame_category::category(frame).as_str()),
+                module: frame_category::module_path(frame),
+                library: frame.module.clone(),
             },
         }
     }
 
-    fn get_thread_id<'a>(&self, trace: &'a StackTrace) -> u32 {
+    fn metadata(&self, trace: &StackTrace, name: &str, tid: u32, args: Args) -> Event {
+        Event {
+            args,
+            cat: "py-spy".to_owned(),
+            cname: None,
+            name: name.to_owned(),
+            ph: "M".to_owned(),
+            pid: trace.pid as u64,
+            tid,
+            ts: 0,
+        }
+    }
+
+    fn get_thread_id(&self, trace: &StackTrace) -> u32 {
         let thread_id = trace.thread_id;
         self.thread_ids[&thread_id]
     }
 
-    fn record_new_thread(&mut self, trace: &StackTrace) {
-        if !self.thread_ids.contains_key(&trace.thread_id) {
-            let thread_id = trace.thread_id;
+    fn record_new_process(&mut self, trace: &StackTrace) -> std::io::Result<()> {
+        if !self.process_ids.contains_key(&trace.pid) {
+            // processes are ordered by when they were first seen
+            let sort_index = self.process_ids.len() as u32;
+            self.process_ids.insert(trace.pid, sort_index);
+
+            let name = process_name(trace);
+            let event = self.metadata(trace, "process_name", 0, metadata_args(Some(name), None));
+            self.write_event(&event)?;
+            let event = self.metadata(
+                trace,
+                "process_sort_index",
+                0,
+                metadata_args(None, Some(sort_index)),
+            );
+            self.write_event(&event)?;
+            if let Some(forked_from) = trace.process_info.as_deref().and_then(forked_from) {
+                let args = Args {
+                    labels: Some(forked_from),
+                    ..metadata_args(None, None)
+                };
+                let event = self.metadata(trace, "process_labels", 0, args);
+                self.write_event(&event)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Remaps a thread id to be in the uint32 space. The remapped id comes from a hash of
+    /// the thread, so that the same thread gets the same id in different recordings, which
+    /// lets traces be compared. Collisions are moved on to the next free id.
+    fn remap_thread_id(&mut self, trace: &StackTrace) -> u32 {
+        let mut hasher = DefaultHasher::new();
+        trace.pid.hash(&mut hasher);
+        trace
+            .os_thread_id
+            .unwrap_or(trace.thread_id)
+            .hash(&mut hasher);
+        trace.thread_name.hash(&mut hasher);
+        // leave room for the --thread-states tracks above the remapped ids
+        let mut remapped_id = (hasher.finish() % STATUS_TRACK_OFFSET as u64) as u32;
+        while !self.used_thread_ids.insert(remapped_id) {
+            remapped_id = (remapped_id + 1) % STATUS_TRACK_OFFSET;
+        }
+        remapped_id
+    }
 
-            // remap IDs to be in the uint32 space
-            let remapped_id = self.thread_ids.len() as u32;
+    fn record_thread(&mut self, trace: &StackTrace) -> std::io::Result<()> {
+        let thread_id = trace.thread_id;
+        if !self.thread_ids.contains_key(&thread_id) {
+            // threads are sorted by when they were first seen (including threads that exited)
+            let sort_index = self.used_thread_ids.len() as u32;
+            let remapped_id = self.remap_thread_id(trace);
             self.thread_ids.insert(thread_id, remapped_id);
 
-            let name = thread_id.to_string() + ": " + trace.thread_name .as_deref().unwrap_or_default();
+            let event = self.metadata(
+                trace,
+                "thread_sort_index",
+                remapped_id,
+                metadata_args(None, Some(sort_index)),
+            );
+            self.write_event(&event)?;
+            if self.thread_states {
+                // tracks with the same sort index are sorted by name, which puts the status
+                // track straight after its thread
+                let event = self.metadata(
+                    trace,
+                    "thread_sort_index",
+                    remapped_id + STATUS_TRACK_OFFSET,
+                    metadata_args(None, Some(sort_index)),
+                );
+                self.write_event(&event)?;
+            }
+        }
 
-            self.events.push(Event { 
-                args: Args { filename: "".to_owned(), line: None, name: Some(name)}, 
-                cat: "py-spy".to_owned(), 
-                name: "thread_name".to_owned(), 
-                ph: "M".to_owned(), 
-                pid: trace.pid as u64, 
-                tid: self.get_thread_id(trace),
-                ts: 0,
-            });
+        // threads are often named after they've started (like workers in a thread pool), so
+        // the name is written out again whenever it changes. the last name written is shown.
+        // (failing to look up the name shouldn't clear out a name we already have though)
+        let name = thread_id.to_string() + ": " + trace.thread_name.as_deref().unwrap_or_default();
+        let renamed = match self.thread_names.get(&thread_id) {
+            Some(prev) => trace.thread_name.is_some() && *prev != name,
+            None => true,
+        };
+        if renamed {
+            let event = self.metadata(
+                trace,
+                "thread_name",
+                self.get_thread_id(trace),
+                metadata_args(Some(name.clone()), None),
+            );
+            self.write_event(&event)?;
+            if self.thread_states {
+                let event = self.metadata(
+                    trace,
+                    "thread_name",
+                    self.get_thread_id(trace) + STATUS_TRACK_OFFSET,
+                    metadata_args(Some(name.clone() + " state"), None),
+                );
+                self.write_event(&event)?;
+            }
+            self.thread_names.insert(thread_id, name);
         }
+        Ok(())
     }
 
     pub fn increment(&mut self, trace: &StackTrace) -> std::io::Result<()> {
         let now = self.start_ts.elapsed().as_micros() as u64;
+        self.increment_at(trace, now)
+    }
+
+    /// Records a trace sampled at a given time, in microseconds since the start of the recording
+    pub fn increment_at(&mut self, trace: &StackTrace, now: u64) -> std::io::Result<()> {
+        let now = now + self.ts_offset;
+        self.end_ts = self.end_ts.max(now);
+        self.update_counters(trace, now)?;
 
-        // Maybe add metadata for new threads.
-        self.record_new_thread(trace);
+        // Maybe add metadata for new processes and threads.
+        if let Some(process_info) = trace.process_info.as_ref() {
+            self.processes.record(process_info, now);
+        }
+        self.record_new_process(trace)?;
+        self.record_thread(trace)?;
+        if self.thread_states {
+            self.record_status(trace, now)?;
+        }
+
+        if self.stack_samples {
+            if let Some(sf) = self.stack_frame_id(trace) {
+                let event = SampleEvent {
+                    cat: "py-spy".to_owned(),
+                    name: "sample".to_owned(),
+                    ph: "P".to_owned(),
+                    pid: trace.pid as u64,
+                    tid: self.get_thread_id(trace),
+                    ts: now,
+                    sf,
+                };
+                self.write_event(&event)?;
+            }
+            self.record_instants(trace, now)?;
+            return Ok(());
+        }
 
         // Load the previous frames for this thread.
-        let prev_frames = self
-            .prev_traces
-            .remove(&trace.thread_id)
-            .map(|t| t.frames)
-            .unwrap_or_default();
+        let prev_trace = self.prev_traces.remove(&trace.thread_id);
+        let prev_task_id = prev_trace.as_ref().and_then(|t| t.task_id);
+        let prev_waiting = prev_trace.as_ref().map(waiting_for_gil).unwrap_or(false);
+        let waiting = waiting_for_gil(trace);
+        let prev_frames = prev_trace.map(|t| t.frames).unwrap_or_default();
 
         // Find the index where we first see new frames.
         let new_idx = prev_frames
@@ -122,37 +454,1261 @@ impl Chrometrace {
             .position(|(a, b)| !self.should_merge_frames(a, b))
             .unwrap_or(min(prev_frames.len(), trace.frames.len()));
 
+        let mut pending = self
+            .pending_slices
+            .remove(&trace.thread_id)
+            .unwrap_or_default();
+        self.write_pending(&mut pending, now)?;
+
+        // the GIL wait slice is the innermost slice, so it has to be ended (and then started
+        // again) whenever the frames change
+        let frames_changed = new_idx < prev_frames.len() || new_idx < trace.frames.len();
+        if prev_waiting && (!waiting || frames_changed) {
+            let event = self.gil_wait_event(trace, "E", now);
+            self.end_slice(&mut pending, event)?;
+        }
+
         // Publish end events for the previous frames that got dropped in the
         // most recent trace.
         for frame in prev_frames.iter().rev().skip(new_idx).rev() {
-            self.events.push(self.event(trace, frame, "E", now));
+            let event = self.event(trace, frame, "E", now);
+            self.end_slice(&mut pending, event)?;
         }
 
         // Publish start events for frames that got added in the most recent
         // trace.
         for frame in trace.frames.iter().rev().skip(new_idx) {
-            self.events.push(self.event(trace, frame, "B", now));
+            let event = self.event(trace, frame, "B", now);
+            self.start_slice(&mut pending, event)?;
         }
 
+        if waiting && (!prev_waiting || frames_changed) {
+            let event = self.gil_wait_event(trace, "B", now);
+            self.start_slice(&mut pending, event)?;
+        }
+        if !pending.is_empty() {
+            self.pending_slices.insert(trace.thread_id, pending);
+        }
+
+        if let Some(task_id) = trace.task_id {
+            if prev_task_id != Some(task_id) {
+                self.record_task_resumed(trace, task_id, now)?;
+            }
+            let thread_id = self.get_thread_id(trace);
+            self.tasks.insert((trace.pid, task_id), (thread_id, now));
+        }
+
+        self.record_instants(trace, now)?;
+
         // Save this stack trace for the next iteration.
         self.prev_traces.insert(trace.thread_id, trace.clone());
 
         Ok(())
     }
 
-    pub fn write(&self, w: &mut dyn Write) -> Result<(), Error> {
+    /// Writes out the start events of the pending slices that have now lasted long enough
+    fn write_pending(&mut self, pending: &mut Vec<Event>, now: u64) -> std::io::Result<()> {
+        // slices are nested, so if a slice is long enough then so is every slice around it
+        let ready = pending
+            .iter()
+            .take_while(|event| now.saturating_sub(event.ts) >= self.min_slice_duration)
+            .count();
+        for event in pending.drain(..ready) {
+            self.write_event(&event)?;
+        }
+        Ok(())
+    }
+
+    /// Starts a new innermost slice on a thread, holding back the start event with
+    /// min_slice_duration
+    fn start_slice(&mut self, pending: &mut Vec<Event>, event: Event) -> std::io::Result<()> {
+        if self.min_slice_duration > 0 {
+            pending.push(event);
+            Ok(())
+        } else {
+            self.write_event(&event)
+        }
+    }
+
+    /// Ends the innermost slice on a thread, dropping it if its start hasn't been written out
+    fn end_slice(&mut self, pending: &mut Vec<Event>, event: Event) -> std::io::Result<()> {
+        if pending.pop().is_none() {
+            self.write_event(&event)?;
+        }
+        Ok(())
+    }
+
+    /// Starts a new slice on the status track of a thread whenever its status changes
+    fn record_status(&mut self, trace: &StackTrace, now: u64) -> std::io::Result<()> {
+        let status = trace.thread_status();
+        let tid = self.get_thread_id(trace) + STATUS_TRACK_OFFSET;
+        let prev = self
+            .statuses
+            .insert(trace.thread_id, (trace.pid, tid, status));
+        if let Some((pid, tid, prev_status)) = prev {
+            if prev_status == status {
+                return Ok(());
+            }
+            self.write_event(&status_event(pid, tid, prev_status, "E", now))?;
+        }
+        self.write_event(&status_event(trace.pid, tid, status, "B", now))
+    }
+
+    /// The slice added underneath the innermost frame of a thread that's runnable but waiting on
+    /// the GIL, so that convoys of threads waiting on each other show up on the timeline
+    fn gil_wait_event(&self, trace: &StackTrace, phase: &str, ts: u64) -> Event {
+        Event {
+            args: metadata_args(None, None),
+            cat: "gil".to_owned(),
+            cname: None,
+            name: "GIL wait".to_owned(),
+            ph: phase.to_owned(),
+            pid: trace.pid as u64,
+            tid: self.get_thread_id(trace),
+            ts,
+        }
+    }
+
+    /// Returns the id of the leaf stack frame of a trace, adding any new frames to the tree
+    fn stack_frame_id(&mut self, trace: &StackTrace) -> Option<u64> {
+        let mut parent = None;
+        for frame in trace.frames.iter().rev() {
+            let line = if self.show_linenumbers { frame.line } else { 0 };
+            let key = (parent, frame.name.clone(), frame.filename.clone(), line);
+            let id = match self.stack_frame_ids.get(&key) {
+                Some(id) => *id,
+                None => {
+                    let id = self.stack_frame_ids.len() as u64 + 1;
+                    self.stack_frame_ids.insert(key, id);
+                    let filename = frame.short_filename.as_ref().unwrap_or(&frame.filename);
+                    let name = if self.show_linenumbers {
+                        format!("{} ({}:{})", frame.name, filename, frame.line)
+                    } else {
+                        format!("{} ({})", frame.name, filename)
+                    };
+                    self.stack_frames.insert(
+                        id,
+                        StackFrame {
+                            category: category(frame).to_owned(),
+                            name,
+                            parent,
+                        },
+                    );
+                    id
+                }
+            };
+            parent = Some(id);
+        }
+        parent
+    }
+
+    /// Marks the samples where a thread was unwinding an exception or running the garbage collector
+    fn record_instants(&mut self, trace: &StackTrace, now: u64) -> std::io::Result<()> {
+        if let Some(exception) = &trace.exception {
+            let event = self.instant(trace, "exception", now, &[("type", exception.clone())]);
+            self.write_event(&event)?;
+        }
+        if trace.in_gc {
+            let event = self.instant(trace, "gc", now, &[]);
+            self.write_event(&event)?;
+        }
+        Ok(())
+    }
+
+    fn update_counters(&mut self, trace: &StackTrace, now: u64) -> std::io::Result<()> {
+        if now >= self.window_start + COUNTER_INTERVAL {
+            let window_start = self.window_start;
+            for (pid, window) in std::mem::take(&mut self.counter_windows) {
+                let mut counters = counters(pid, &window, window_start);
+                if self.process_stats {
+                    counters.extend(self.process_counters(pid, window_start));
+                }
+                for counter in counters {
+                    self.write_event(&counter)?;
+                }
+            }
+            self.window_start = now - now % COUNTER_INTERVAL;
+            // flush out the events periodically, so that the file is usable while recording
+            self.output.flush()?;
+            self.tasks.retain(|_, (_, ts)| *ts + TASK_TIMEOUT >= now);
+        }
+
+        // all the traces from the same sampling interval share a timestamp
+        let window = self.counter_windows.entry(trace.pid).or_default();
+        if window.last_round != Some(now) {
+            window.last_round = Some(now);
+            window.rounds += 1;
+            window.gil_held = false;
+        }
+        if trace.owns_gil && !window.gil_held {
+            window.gil_held = true;
+            window.gil_rounds += 1;
+        }
+        if trace.active {
+            window.active_threads += 1;
+        } else {
+            window.idle_threads += 1;
+        }
+        Ok(())
+    }
+
+    fn instant(
+        &self,
+        trace: &StackTrace,
+        name: &str,
+        ts: u64,
+        args: &[(&'static str, String)],
+    ) -> InstantEvent {
+        InstantEvent {
+            args: args.iter().cloned().collect(),
+            cat: "py-spy".to_owned(),
+            name: name.to_owned(),
+            ph: "i".to_owned(),
+            pid: trace.pid as u64,
+            tid: self.get_thread_id(trace),
+            ts,
+            s: "t".to_owned(),
+        }
+    }
+
+    /// Marks the time that --adaptive-rate changed the sampling rate, across the whole trace
+    pub fn set_sampling_rate(&mut self, rate: u64, timestamp: u64) -> std::io::Result<()> {
+        let event = InstantEvent {
+            args: [("rate", rate.to_string())].into_iter().collect(),
+            cat: "py-spy".to_owned(),
+            name: "sampling rate".to_owned(),
+            ph: "i".to_owned(),
+            pid: 0,
+            tid: 0,
+            ts: timestamp + self.ts_offset,
+            s: "g".to_owned(),
+        };
+        self.write_event(&event)
+    }
+
+    /// Marks the statistics of the sampler at the end of the trace, once recording has finished
+    pub fn set_stats(&mut self, stats: &SamplerStats) -> std::io::Result<()> {
+        let event = InstantEvent {
+            args: stats.metadata().into_iter().collect(),
+            cat: "py-spy".to_owned(),
+            name: "sampler stats".to_owned(),
+            ph: "i".to_owned(),
+            pid: 0,
+            tid: 0,
+            ts: self.end_ts,
+            s: "g".to_owned(),
+        };
+        self.write_event(&event)
+    }
+
+    /// Adds counters for the memory usage of a process, read at a given time
+    pub fn add_memory_counters(
+        &mut self,
+        pid: Pid,
+        counters: &MemoryCounters,
+        timestamp: u64,
+    ) -> std::io::Result<()> {
+        let ts = timestamp + self.ts_offset;
         let mut events = Vec::new();
-        events.extend(self.events.to_vec());
+        if let Some(rss) = counters.rss {
+            events.push(counter(pid, "Memory", ts, &[("RSS MB", rss as f64 / 1e6)]));
+        }
+        if let Some(arenas) = counters.arenas {
+            events.push(counter(pid, "pymalloc", ts, &[("arenas", arenas as f64)]));
+        }
+        if let Some([gen0, gen1, gen2]) = counters.gc_counts.as_deref() {
+            events.push(counter(
+                pid,
+                "GC",
+                ts,
+                &[
+                    ("gen0", *gen0 as f64),
+                    ("gen1", *gen1 as f64),
+                    ("gen2", *gen2 as f64),
+                ],
+            ));
+        }
+        for event in events {
+            self.write_event(&event)?;
+        }
+        Ok(())
+    }
+
+    /// Marks a thread starting or exiting at a given time. The slices that were open on a thread
+    /// are ended when it exits, and a new thread that reuses its thread id gets its own track
+    pub fn add_thread_event(&mut self, event: &ThreadEvent, timestamp: u64) -> std::io::Result<()> {
+        let now = timestamp + self.ts_offset;
+        self.end_ts = self.end_ts.max(now);
+        let trace = &event.thread;
+        self.record_new_process(trace)?;
+        self.record_thread(trace)?;
+        if event.kind == ThreadEventKind::Started {
+            let event = self.instant(trace, "thread started", now, &[]);
+            return self.write_event(&event);
+        }
+
+        if let Some(prev_trace) = self.prev_traces.remove(&trace.thread_id) {
+            self.end_slices(&prev_trace, now)?;
+        }
+        if let Some((pid, tid, status)) = self.statuses.remove(&trace.thread_id) {
+            self.write_event(&status_event(pid, tid, status, "E", now))?;
+        }
+        let event = self.instant(trace, "thread exited", now, &[]);
+        self.write_event(&event)?;
+        self.thread_ids.remove(&trace.thread_id);
+        self.thread_names.remove(&trace.thread_id);
+        Ok(())
+    }
+
+    /// Ends the slices that are open on the thread of a trace
+    fn end_slices(&mut self, trace: &StackTrace, ts: u64) -> std::io::Result<()> {
+        let mut pending = self
+            .pending_slices
+            .remove(&trace.thread_id)
+            .unwrap_or_default();
+        self.write_pending(&mut pending, ts)?;
+        if waiting_for_gil(trace) {
+            let event = self.gil_wait_event(trace, "E", ts);
+            self.end_slice(&mut pending, event)?;
+        }
+        for frame in &trace.frames {
+            let event = self.event(trace, frame, "E", ts);
+            self.end_slice(&mut pending, event)?;
+        }
+        Ok(())
+    }
+
+    /// Links an asyncio task that has just been resumed to the last time it was running, with a
+    /// flow event that Perfetto draws as an arrow between the two
+    fn record_task_resumed(
+        &mut self,
+        trace: &StackTrace,
+        task_id: u64,
+        now: u64,
+    ) -> std::io::Result<()> {
+        let (prev_thread_id, prev_ts) = match self.tasks.get(&(trace.pid, task_id)) {
+            Some(prev) => *prev,
+            None => return Ok(()),
+        };
+        self.flows += 1;
+        let flow = |ph: &str, tid: u32, ts: u64, bp: Option<String>| Flow {
+            cat: "asyncio".to_owned(),
+            name: "resume".to_owned(),
+            ph: ph.to_owned(),
+            id: self.flows,
+            pid: trace.pid as u64,
+            tid,
+            ts,
+            bp,
+        };
+        let start = flow("s", prev_thread_id, prev_ts, None);
+        // binds to the slices that were just started for this task
+        let finish = flow("f", self.get_thread_id(trace), now, Some("e".to_owned()));
+        self.write_event(&start)?;
+        self.write_event(&finish)
+    }
+
+    fn process_counters(&mut self, pid: Pid, ts: u64) -> Vec<Counter> {
+        let (cpu_time, rss) = match process_usage(pid) {
+            Some(usage) => usage,
+            None => return Vec::new(),
+        };
+        let mut counters = vec![counter(pid, "Memory", ts, &[("RSS MB", rss as f64 / 1e6)])];
+
+        // cpu usage is the change in cpu time since the last time we checked
+        let now = Instant::now();
+        if let Some((prev_time, prev_cpu_time)) = self.cpu_times.insert(pid, (now, cpu_time)) {
+            let elapsed = now.duration_since(prev_time).as_secs_f64();
+            if elapsed > 0.0 {
+                let usage = 100.0 * (cpu_time - prev_cpu_time) / elapsed;
+                counters.push(counter(pid, "CPU", ts, &[("usage %", usage)]));
+            }
+        }
+        counters
+    }
 
+    /// Flushes out the events written so far, so that the trace can be loaded while recording
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.output.flush()
+    }
+
+    /// Writes out the end of the trace, once recording has finished
+    pub fn finish(&mut self) -> Result<(), Error> {
         // Add end events for any unfinished slices.
-        let now = self.start_ts.elapsed().as_micros() as u64;
-        for trace in self.prev_traces.values() {
-            for frame in &trace.frames {
-                events.push(self.event(trace, frame, "E", now));
+        for trace in std::mem::take(&mut self.prev_traces).into_values() {
+            self.end_slices(&trace, self.end_ts)?;
+        }
+
+        for (pid, tid, status) in std::mem::take(&mut self.statuses).into_values() {
+            self.write_event(&status_event(pid, tid, status, "E", self.end_ts))?;
+        }
+
+        // Add counters for the samples since they were last written out
+        for (pid, window) in std::mem::take(&mut self.counter_windows) {
+            for counter in counters(pid, &window, self.window_start) {
+                self.write_event(&counter)?;
             }
         }
 
-        writeln!(w, "{}", serde_json::to_string(&events)?)?;
+        // Add the tree of processes sampled with --subprocesses, with when each was sampled
+        let processes = self.processes.processes();
+        if !processes.is_empty() {
+            let event = InstantEvent {
+                args: [("processes", serde_json::to_string(&processes)?)]
+                    .into_iter()
+                    .collect(),
+                cat: "py-spy".to_owned(),
+                name: "process tree".to_owned(),
+                ph: "i".to_owned(),
+                pid: 0,
+                tid: 0,
+                ts: self.end_ts,
+                s: "g".to_owned(),
+            };
+            self.write_event(&event)?;
+        }
+
+        if self.stack_samples {
+            self.output.write_all(b"\n],\n\"stackFrames\": ")?;
+            serde_json::to_writer(&mut self.output, &self.stack_frames)?;
+            self.output.write_all(b"}\n")?;
+        } else {
+            self.output.write_all(b"\n]\n")?;
+        }
+        self.output.flush()?;
         Ok(())
     }
 }
+
+fn metadata_args(name: Option<String>, sort_index: Option<u32>) -> Args {
+    Args {
+        filename: "".to_owned(),
+        line: None,
+        name,
+        sort_index,
+        labels: None,
+        category: None,
+        module: None,
+        library: None,
+    }
+}
+
+fn status_event(pid: Pid, tid: u32, status: ThreadStatus, phase: &str, ts: u64) -> Event {
+    // the colours the trace viewer uses for the thread states in systrace
+    let cname = match status {
+        ThreadStatus::Running => "thread_state_running",
+        ThreadStatus::WaitingForGil => "thread_state_runnable",
+        ThreadStatus::BlockingIo => "thread_state_iowait",
+        ThreadStatus::Sleeping => "thread_state_sleeping",
+        ThreadStatus::WaitingOnLock => "thread_state_uninterruptible",
+    };
+    Event {
+        args: metadata_args(None, None),
+        cat: "thread_state".to_owned(),
+        cname: Some(cname),
+        name: status.as_str().to_owned(),
+        ph: phase.to_owned(),
+        pid: pid as u64,
+        tid,
+        ts,
+    }
+}
+
+/// Native frames (which are only included with --native) get their own category
+fn category(frame: &Frame) -> &'static str {
+    if frame.module.is_some() {
+        "native"
+    } else {
+        "py-spy"
+    }
+}
+
+#[derive(serde_derive::Serialize)]
+struct Index<'a> {
+    traces: Vec<&'a IndexEntry>,
+}
+
+#[derive(Clone, Debug, serde_derive::Serialize)]
+struct IndexEntry {
+    pub pid: u64,
+    pub command_line: String,
+    pub filename: String,
+    pub samples: u64,
+}
+
+/// Lists the trace written out for each process with --split-processes, since a single trace
+/// with every process from a --subprocesses recording can get too big to load
+#[derive(Default)]
+pub struct TraceIndex {
+    traces: BTreeMap<Pid, IndexEntry>,
+}
+
+impl TraceIndex {
+    pub fn increment(&mut self, trace: &StackTrace, filename: &str) {
+        let entry = self.traces.entry(trace.pid).or_insert_with(|| IndexEntry {
+            pid: trace.pid as u64,
+            command_line: process_name(trace),
+            filename: filename.to_owned(),
+            samples: 0,
+        });
+        entry.samples += 1;
+    }
+
+    pub fn write(&self, w: &mut dyn Write) -> Result<(), Error> {
+        let index = Index {
+            traces: self.traces.values().collect(),
+        };
+        serde_json::to_writer_pretty(&mut *w, &index)?;
+        writeln!(w)?;
+        Ok(())
+    }
+}
+
+/// Whether a thread was ready to run, but waiting on another thread to release the GIL
+fn waiting_for_gil(trace: &StackTrace) -> bool {
+    trace.active && !trace.owns_gil
+}
+
+/// The name of the process a trace is from, when it's known
+fn process_name(trace: &StackTrace) -> String {
+    match trace.process_info.as_ref() {
+        Some(process_info) => process_info.name(),
+        None => format!("Process {}", trace.pid),
+    }
+}
+
+/// Returns the GIL and thread counters for a process, averaged over a COUNTER_INTERVAL
+fn counters(pid: Pid, window: &CounterWindow, ts: u64) -> Vec<Counter> {
+    let rounds = window.rounds.max(1) as f64;
+    vec![
+        counter(
+            pid,
+            "GIL",
+            ts,
+            &[("held %", 100.0 * window.gil_rounds as f64 / rounds)],
+        ),
+        // idle threads are only included in the samples with --idle
+        counter(
+            pid,
+            "Threads",
+            ts,
+            &[
+                ("active", window.active_threads as f64 / rounds),
+                ("idle", window.idle_threads as f64 / rounds),
+            ],
+        ),
+    ]
+}
+
+fn counter(pid: Pid, name: &str, ts: u64, values: &[(&'static str, f64)]) -> Counter {
+    Counter {
+        args: values.iter().cloned().collect(),
+        cat: "py-spy".to_owned(),
+        name: name.to_owned(),
+        ph: "C".to_owned(),
+        pid: pid as u64,
+        ts,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{frame, trace};
+
+    use crate::stack_trace::ProcessInfo;
+    use std::sync::{Arc, Mutex};
+
+    // lets us inspect what was written, after handing ownership of the writer to Chrometrace
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_streaming() {
+        let buffer = SharedBuffer::default();
+        let mut chrometrace =
+            Chrometrace::new(Box::new(buffer.clone()), true, false, None, false).unwrap();
+        let mut trace = trace(vec![
+            frame("inner", "test.py", 2),
+            frame("outer", "test.py", 10),
+        ]);
+        trace.pid = 1234;
+        trace.thread_name = Some(String::from("MainThread"));
+        trace.owns_gil = true;
+        chrometrace.increment_at(&trace, 0).unwrap();
+        trace.frames.remove(0);
+        chrometrace.increment_at(&trace, 10000).unwrap();
+
+        // events are written out as they happen, and the unfinished output is still readable
+        // once the missing ']' is added
+        let partial = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let events: Vec<serde_json::Value> = serde_json::from_str(&(partial + "]")).unwrap();
+        let phases: Vec<&str> = events.iter().map(|e| e["ph"].as_str().unwrap()).collect();
+        assert_eq!(phases, vec!["M", "M", "M", "M", "B", "B", "E"]);
+        assert_eq!(events[6]["name"], "inner");
+        assert_eq!(events[6]["ts"], 10000);
+
+        chrometrace.finish().unwrap();
+        let events: Vec<serde_json::Value> =
+            serde_json::from_slice(&buffer.0.lock().unwrap()).unwrap();
+        // 'outer' is closed off at the end of the recording
+        assert_eq!(events[7]["ph"], "E");
+        assert_eq!(events[7]["name"], "outer");
+        assert_eq!(events[7]["args"]["line"], 10);
+    }
+
+    #[test]
+    fn test_absolute_timestamps() {
+        let buffer = SharedBuffer::default();
+        let start_time = 1_700_000_000_000_000;
+        let mut chrometrace = Chrometrace::new(
+            Box::new(buffer.clone()),
+            false,
+            false,
+            Some(start_time),
+            false,
+        )
+        .unwrap();
+        let mut trace = trace(vec![
+            frame("inner", "test.py", 2),
+            frame("outer", "test.py", 10),
+        ]);
+        trace.pid = 1234;
+        trace.owns_gil = true;
+        chrometrace.increment_at(&trace, 0).unwrap();
+        trace.frames.remove(0);
+        chrometrace.increment_at(&trace, 250_000).unwrap();
+        chrometrace.finish().unwrap();
+
+        let events: Vec<serde_json::Value> =
+            serde_json::from_slice(&buffer.0.lock().unwrap()).unwrap();
+        let timestamps = |ph: &str| -> Vec<u64> {
+            events
+                .iter()
+                .filter(|e| e["ph"] == ph)
+                .map(|e| e["ts"].as_u64().unwrap())
+                .collect()
+        };
+        assert_eq!(timestamps("B"), vec![start_time, start_time]);
+        assert_eq!(
+            timestamps("E"),
+            vec![start_time + 250_000, start_time + 250_000]
+        );
+        // counters are also anchored to the start time
+        assert!(timestamps("C").iter().all(|ts| *ts >= start_time));
+    }
+
+    #[test]
+    fn test_metadata() {
+        let buffer = SharedBuffer::default();
+        let mut chrometrace =
+            Chrometrace::new(Box::new(buffer.clone()), false, false, None, false).unwrap();
+        let mut trace = trace(vec![frame("main", "test.py", 1)]);
+        trace.pid = 1234;
+        trace.thread_name = Some(String::from("MainThread"));
+        trace.process_info = Some(Arc::new(ProcessInfo {
+            pid: 1234,
+            command_line: String::from("python parent.py"),
+            parent: None,
+            role: None,
+        }));
+        chrometrace.increment_at(&trace, 0).unwrap();
+        trace.thread_id = 2;
+        trace.thread_name = Some(String::from("worker"));
+        chrometrace.increment_at(&trace, 0).unwrap();
+        trace.pid = 5678;
+        trace.thread_id = 3;
+        trace.process_info = None;
+        chrometrace.increment_at(&trace, 0).unwrap();
+        chrometrace.finish().unwrap();
+
+        let events: Vec<serde_json::Value> =
+            serde_json::from_slice(&buffer.0.lock().unwrap()).unwrap();
+        let metadata = |name: &str| -> Vec<(u64, u64, serde_json::Value)> {
+            events
+                .iter()
+                .filter(|e| e["ph"] == "M" && e["name"] == name)
+                .map(|e| {
+                    let value = match name {
+                        "process_name" | "thread_name" => e["args"]["name"].clone(),
+                        _ => e["args"]["sort_index"].clone(),
+                    };
+                    (
+                        e["pid"].as_u64().unwrap(),
+                        e["tid"].as_u64().unwrap(),
+                        value,
+                    )
+                })
+                .collect()
+        };
+        assert_eq!(
+            metadata("process_name"),
+            vec![
+                (1234, 0, "python parent.py".into()),
+                (5678, 0, "Process 5678".into())
+            ]
+        );
+        assert_eq!(
+            metadata("process_sort_index"),
+            vec![(1234, 0, 0.into()), (5678, 0, 1.into())]
+        );
+        // threads are sorted in the order they were first seen
+        let sort_indices: Vec<(u64, serde_json::Value)> = metadata("thread_sort_index")
+            .into_iter()
+            .map(|(pid, _, sort_index)| (pid, sort_index))
+            .collect();
+        assert_eq!(
+            sort_indices,
+            vec![(1234, 0.into()), (1234, 1.into()), (5678, 2.into())]
+        );
+    }
+
+    #[test]
+    fn test_stable_thread_ids() {
+        let thread_ids = |order: &[u64]| -> HashMap<u64, u32> {
+            let mut chrometrace =
+                Chrometrace::new(Box::new(std::io::sink()), false, false, None, false).unwrap();
+            for thread_id in order {
+                let mut trace = trace(vec![frame("main", "test.py", 1)]);
+                trace.pid = 1234;
+                trace.thread_id = *thread_id;
+                trace.thread_name = Some(format!("Thread-{}", thread_id));
+                trace.os_thread_id = Some(*thread_id + 100);
+                chrometrace.increment_at(&trace, 0).unwrap();
+            }
+            chrometrace.thread_ids
+        };
+        // threads get the same ids, whatever order they're seen in
+        let ids = thread_ids(&[1, 2, 3]);
+        assert_eq!(ids, thread_ids(&[3, 1, 2]));
+        assert!(ids.values().all(|id| *id < STATUS_TRACK_OFFSET));
+
+        // colliding threads are moved on to the next free id
+        let mut chrometrace =
+            Chrometrace::new(Box::new(std::io::sink()), false, false, None, false).unwrap();
+        let mut trace = trace(vec![frame("main", "test.py", 1)]);
+        trace.pid = 1234;
+        trace.thread_name = Some(String::from("Thread-1"));
+        trace.os_thread_id = Some(101);
+        chrometrace.used_thread_ids.insert(ids[&1]);
+        chrometrace.increment_at(&trace, 0).unwrap();
+        assert_eq!(chrometrace.thread_ids[&1], ids[&1] + 1);
+    }
+
+    #[test]
+    fn test_thread_renamed() {
+        let buffer = SharedBuffer::default();
+        let mut chrometrace =
+            Chrometrace::new(Box::new(buffer.clone()), false, false, None, false).unwrap();
+        let mut trace = trace(vec![frame("worker", "test.py", 1)]);
+        trace.pid = 1234;
+        trace.thread_id = 7;
+        trace.thread_name = Some(String::from("Thread-1"));
+        chrometrace.increment_at(&trace, 0).unwrap();
+        chrometrace.increment_at(&trace, 10000).unwrap();
+        trace.thread_name = Some(String::from("pool-worker-0"));
+        chrometrace.increment_at(&trace, 20000).unwrap();
+        // a failed name lookup shouldn't undo the rename
+        trace.thread_name = None;
+        chrometrace.increment_at(&trace, 30000).unwrap();
+        chrometrace.finish().unwrap();
+
+        let events: Vec<serde_json::Value> =
+            serde_json::from_slice(&buffer.0.lock().unwrap()).unwrap();
+        let names: Vec<&str> = events
+            .iter()
+            .filter(|e| e["name"] == "thread_name")
+            .map(|e| e["args"]["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, vec!["7: Thread-1", "7: pool-worker-0"]);
+    }
+
+    #[test]
+    fn test_stack_samples() {
+        let buffer = SharedBuffer::default();
+        let mut chrometrace =
+            Chrometrace::new(Box::new(buffer.clone()), true, false, None, true).unwrap();
+        let mut trace = trace(vec![
+            frame("fib", "test.py", 3),
+            frame("fib", "test.py", 3),
+            frame("main", "test.py", 10),
+        ]);
+        trace.pid = 1234;
+        chrometrace.increment_at(&trace, 0).unwrap();
+        trace.frames.remove(0);
+        chrometrace.increment_at(&trace, 10000).unwrap();
+        chrometrace.finish().unwrap();
+
+        let trace: serde_json::Value = serde_json::from_slice(&buffer.0.lock().unwrap()).unwrap();
+        let events = trace["traceEvents"].as_array().unwrap();
+        // samples aren't merged into slices
+        assert!(events.iter().all(|e| e["ph"] != "B" && e["ph"] != "E"));
+        let samples: Vec<&serde_json::Value> = events.iter().filter(|e| e["ph"] == "P").collect();
+        assert_eq!(samples.len(), 2);
+
+        // walk the stack frames from each sample back up to the root
+        let stack = |sample: &serde_json::Value| -> Vec<String> {
+            let mut names = Vec::new();
+            let mut sf = sample["sf"].to_string();
+            while let Some(frame) = trace["stackFrames"].get(&sf) {
+                names.push(frame["name"].as_str().unwrap().to_owned());
+                sf = frame["parent"].to_string();
+            }
+            names
+        };
+        assert_eq!(
+            stack(samples[0]),
+            vec!["fib (test.py:3)", "fib (test.py:3)", "main (test.py:10)"]
+        );
+        assert_eq!(
+            stack(samples[1]),
+            vec!["fib (test.py:3)", "main (test.py:10)"]
+        );
+    }
+
+    #[test]
+    fn test_native_frames() {
+        let buffer = SharedBuffer::default();
+        let mut chrometrace =
+            Chrometrace::new(Box::new(buffer.clone()), false, false, None, false).unwrap();
+        let mut native = frame("compute", "test.py", 0);
+        native.module = Some(String::from("_extension.so"));
+        let mut trace = trace(vec![native, frame("main", "test.py", 10)]);
+        trace.pid = 1234;
+        chrometrace.increment_at(&trace, 0).unwrap();
+        chrometrace.finish().unwrap();
+
+        let events: Vec<serde_json::Value> =
+            serde_json::from_slice(&buffer.0.lock().unwrap()).unwrap();
+        let slices: Vec<&serde_json::Value> = events.iter().filter(|e| e["ph"] == "B").collect();
+        // the native frame is nested beneath the python frame that called it
+        assert_eq!(slices[0]["name"], "main");
+        assert_eq!(slices[0]["cat"], "py-spy");
+        assert_eq!(slices[1]["name"], "compute");
+        assert_eq!(slices[1]["cat"], "native");
+        assert_eq!(slices[1]["args"]["library"], "_extension.so");
+        assert_eq!(slices[1]["args"]["category"], "native");
+    }
+
+    #[test]
+    fn test_min_slice_duration() {
+        let buffer = SharedBuffer::default();
+        let mut chrometrace =
+            Chrometrace::new(Box::new(buffer.clone()), false, false, None, false).unwrap();
+        chrometrace.min_slice_duration = 15000;
+        let mut trace = trace(vec![
+            frame("short", "test.py", 2),
+            frame("main", "test.py", 10),
+        ]);
+        trace.pid = 1234;
+        trace.owns_gil = true;
+        chrometrace.increment_at(&trace, 0).unwrap();
+        trace.frames = vec![frame("main", "test.py", 10)];
+        chrometrace.increment_at(&trace, 10000).unwrap();
+        trace.frames = vec![frame("long", "test.py", 5), frame("main", "test.py", 10)];
+        chrometrace.increment_at(&trace, 20000).unwrap();
+        trace.frames = vec![frame("main", "test.py", 10)];
+        chrometrace.increment_at(&trace, 40000).unwrap();
+        chrometrace.finish().unwrap();
+
+        let events: Vec<serde_json::Value> =
+            serde_json::from_slice(&buffer.0.lock().unwrap()).unwrap();
+        let slices: Vec<(&str, &str, u64)> = events
+            .iter()
+            .filter(|e| e["ph"] == "B" || e["ph"] == "E")
+            .map(|e| {
+                (
+                    e["ph"].as_str().unwrap(),
+                    e["name"].as_str().unwrap(),
+                    e["ts"].as_u64().unwrap(),
+                )
+            })
+            .collect();
+        // 'short' only lasted 10ms, so is left out
+        assert_eq!(
+            slices,
+            vec![
+                ("B", "main", 0),
+                ("B", "long", 20000),
+                ("E", "long", 40000),
+                ("E", "main", 40000)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_trace_index() {
+        let mut trace = trace(vec![frame("main", "test.py", 1)]);
+        trace.pid = 1234;
+        trace.process_info = Some(Arc::new(ProcessInfo {
+            pid: 1234,
+            command_line: String::from("gunicorn app:wsgi"),
+            parent: None,
+            role: None,
+        }));
+        let mut index = TraceIndex::default();
+        index.increment(&trace, "trace-1234.json");
+        index.increment(&trace, "trace-1234.json");
+        trace.pid = 5678;
+        trace.process_info = None;
+        index.increment(&trace, "trace-5678.json");
+
+        let mut out = Vec::new();
+        index.write(&mut out).unwrap();
+        let index: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(
+            index["traces"],
+            serde_json::json!([
+                {"pid": 1234, "command_line": "gunicorn app:wsgi", "filename": "trace-1234.json", "samples": 2},
+                {"pid": 5678, "command_line": "Process 5678", "filename": "trace-5678.json", "samples": 1},
+            ])
+        );
+    }
+
+    #[test]
+    fn test_gil_wait() {
+        let buffer = SharedBuffer::default();
+        let mut chrometrace =
+            Chrometrace::new(Box::new(buffer.clone()), false, false, None, false).unwrap();
+        let mut trace = trace(vec![
+            frame("work", "test.py", 2),
+            frame("main", "test.py", 10),
+        ]);
+        trace.pid = 1234;
+        trace.owns_gil = true;
+        chrometrace.increment_at(&trace, 0).unwrap();
+        trace.owns_gil = false;
+        chrometrace.increment_at(&trace, 10000).unwrap();
+        chrometrace.increment_at(&trace, 20000).unwrap();
+        // changing frames while waiting starts a new wait slice under the new frame
+        trace.frames = vec![frame("other", "test.py", 4), frame("main", "test.py", 10)];
+        chrometrace.increment_at(&trace, 30000).unwrap();
+        trace.owns_gil = true;
+        chrometrace.increment_at(&trace, 40000).unwrap();
+        chrometrace.finish().unwrap();
+
+        let events: Vec<serde_json::Value> =
+            serde_json::from_slice(&buffer.0.lock().unwrap()).unwrap();
+        let slices: Vec<(&str, &str, u64)> = events
+            .iter()
+            .filter(|e| e["ph"] == "B" || e["ph"] == "E")
+            .map(|e| {
+                (
+                    e["ph"].as_str().unwrap(),
+                    e["name"].as_str().unwrap(),
+                    e["ts"].as_u64().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            slices,
+            vec![
+                ("B", "main", 0),
+                ("B", "work", 0),
+                ("B", "GIL wait", 10000),
+                ("E", "GIL wait", 30000),
+                ("E", "work", 30000),
+                ("B", "other", 30000),
+                ("B", "GIL wait", 30000),
+                ("E", "GIL wait", 40000),
+                ("E", "other", 40000),
+                ("E", "main", 40000),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_thread_states() {
+        let buffer = SharedBuffer::default();
+        let mut chrometrace =
+            Chrometrace::new(Box::new(buffer.clone()), false, false, None, false).unwrap();
+        chrometrace.thread_states = true;
+        let mut trace = trace(vec![frame("main", "test.py", 10)]);
+        trace.pid = 1234;
+        trace.thread_name = Some(String::from("MainThread"));
+        trace.owns_gil = true;
+        trace.status = Some(ThreadStatus::Running);
+        chrometrace.increment_at(&trace, 0).unwrap();
+        chrometrace.increment_at(&trace, 10000).unwrap();
+        trace.active = false;
+        trace.owns_gil = false;
+        trace.status = Some(ThreadStatus::BlockingIo);
+        chrometrace.increment_at(&trace, 20000).unwrap();
+        // traces without a status fall back to the activity and GIL of the thread
+        trace.status = None;
+        chrometrace.increment_at(&trace, 30000).unwrap();
+        chrometrace.finish().unwrap();
+
+        let events: Vec<serde_json::Value> =
+            serde_json::from_slice(&buffer.0.lock().unwrap()).unwrap();
+        let track = chrometrace.thread_ids[&1] + STATUS_TRACK_OFFSET;
+        let states: Vec<(&str, &str, &str, u64)> = events
+            .iter()
+            .filter(|e| e["tid"] == track && (e["ph"] == "B" || e["ph"] == "E"))
+            .map(|e| {
+                (
+                    e["ph"].as_str().unwrap(),
+                    e["name"].as_str().unwrap(),
+                    e["cname"].as_str().unwrap(),
+                    e["ts"].as_u64().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            states,
+            vec![
+                ("B", "running", "thread_state_running", 0),
+                ("E", "running", "thread_state_running", 20000),
+                ("B", "I/O", "thread_state_iowait", 20000),
+                ("E", "I/O", "thread_state_iowait", 30000),
+                ("B", "sleeping", "thread_state_sleeping", 30000),
+                ("E", "sleeping", "thread_state_sleeping", 30000),
+            ]
+        );
+
+        // the status track is named and sorted after its thread
+        let track_metadata: Vec<&serde_json::Value> = events
+            .iter()
+            .filter(|e| e["tid"] == track && e["ph"] == "M")
+            .map(|e| &e["args"])
+            .collect();
+        assert_eq!(track_metadata[0]["sort_index"], 0);
+        assert_eq!(track_metadata[1]["name"], "1: MainThread state");
+    }
+
+    #[test]
+    fn test_task_flows() {
+        let buffer = SharedBuffer::default();
+        let mut chrometrace =
+            Chrometrace::new(Box::new(buffer.clone()), false, false, None, false).unwrap();
+        let mut trace = trace(vec![
+            frame("task_a", "test.py", 1),
+            frame("run_forever", "test.py", 2),
+        ]);
+        trace.pid = 1234;
+        trace.owns_gil = true;
+        trace.task_id = Some(10);
+        chrometrace.increment_at(&trace, 0).unwrap();
+        chrometrace.increment_at(&trace, 10000).unwrap();
+        trace.frames[0] = frame("task_b", "test.py", 5);
+        trace.task_id = Some(20);
+        chrometrace.increment_at(&trace, 20000).unwrap();
+        trace.frames[0] = frame("task_a", "test.py", 1);
+        trace.task_id = Some(10);
+        chrometrace.increment_at(&trace, 30000).unwrap();
+        chrometrace.finish().unwrap();
+
+        let events: Vec<serde_json::Value> =
+            serde_json::from_slice(&buffer.0.lock().unwrap()).unwrap();
+        let flows: Vec<&serde_json::Value> = events
+            .iter()
+            .filter(|e| e["ph"] == "s" || e["ph"] == "f")
+            .collect();
+        // only resuming task_a should be linked, from the last time it was seen running
+        assert_eq!(flows.len(), 2);
+        assert_eq!(flows[0]["ph"], "s");
+        assert_eq!(flows[0]["ts"], 10000);
+        assert_eq!(flows[1]["ph"], "f");
+        assert_eq!(flows[1]["ts"], 30000);
+        assert_eq!(flows[1]["bp"], "e");
+        assert_eq!(flows[0]["id"], flows[1]["id"]);
+    }
+
+    #[test]
+    fn test_instant_events() {
+        let buffer = SharedBuffer::default();
+        let mut chrometrace =
+            Chrometrace::new(Box::new(buffer.clone()), false, false, None, false).unwrap();
+        let mut trace = trace(vec![
+            frame("parse", "test.py", 3),
+            frame("main", "test.py", 10),
+        ]);
+        trace.pid = 1234;
+        trace.owns_gil = true;
+        trace.exception = Some(String::from("ValueError"));
+        chrometrace.increment_at(&trace, 0).unwrap();
+        trace.exception = None;
+        chrometrace.increment_at(&trace, 10000).unwrap();
+        trace.in_gc = true;
+        chrometrace.increment_at(&trace, 20000).unwrap();
+        chrometrace.set_stats(&SamplerStats::new(100)).unwrap();
+        chrometrace.finish().unwrap();
+
+        let events: Vec<serde_json::Value> =
+            serde_json::from_slice(&buffer.0.lock().unwrap()).unwrap();
+        let instants: Vec<&serde_json::Value> = events.iter().filter(|e| e["ph"] == "i").collect();
+        assert_eq!(instants.len(), 3);
+        assert_eq!(instants[0]["name"], "exception");
+        assert_eq!(instants[0]["args"]["type"], "ValueError");
+        assert_eq!(instants[0]["ts"], 0);
+        assert_eq!(instants[0]["s"], "t");
+        assert_eq!(instants[1]["name"], "gc");
+        assert_eq!(instants[1]["ts"], 20000);
+        assert_eq!(instants[2]["name"], "sampler stats");
+        assert_eq!(instants[2]["s"], "g");
+        assert_eq!(instants[2]["args"]["requested_rate"], "100");
+    }
+
+    #[test]
+    fn test_thread_events() {
+        let buffer = SharedBuffer::default();
+        let mut chrometrace =
+            Chrometrace::new(Box::new(buffer.clone()), false, false, None, false).unwrap();
+        let mut main = trace(vec![frame("main", "test.py", 10)]);
+        main.pid = 1234;
+        main.thread_name = Some(String::from("MainThread"));
+        main.os_thread_id = Some(100);
+        main.owns_gil = true;
+        let mut worker = main.clone();
+        worker.thread_id = 2;
+        worker.thread_name = Some(String::from("worker"));
+        worker.os_thread_id = Some(101);
+        worker.frames = vec![frame("work", "test.py", 3), frame("run", "test.py", 20)];
+        let event = |kind, trace: &StackTrace| ThreadEvent {
+            kind,
+            thread: {
+                let mut stack = trace.clone();
+                stack.frames = Vec::new();
+                stack
+            },
+        };
+
+        chrometrace.increment_at(&main, 0).unwrap();
+        chrometrace
+            .add_thread_event(&event(ThreadEventKind::Started, &worker), 10000)
+            .unwrap();
+        chrometrace.increment_at(&worker, 10000).unwrap();
+        chrometrace.increment_at(&main, 10000).unwrap();
+        chrometrace
+            .add_thread_event(&event(ThreadEventKind::Exited, &worker), 20000)
+            .unwrap();
+        chrometrace.increment_at(&main, 20000).unwrap();
+        // a new thread that reuses the thread id of the one that exited
+        let mut reused = worker.clone();
+        reused.os_thread_id = Some(102);
+        chrometrace.increment_at(&reused, 30000).unwrap();
+        chrometrace.finish().unwrap();
+
+        let events: Vec<serde_json::Value> =
+            serde_json::from_slice(&buffer.0.lock().unwrap()).unwrap();
+        let instants: Vec<(&str, u64)> = events
+            .iter()
+            .filter(|e| e["ph"] == "i")
+            .map(|e| (e["name"].as_str().unwrap(), e["ts"].as_u64().unwrap()))
+            .collect();
+        assert_eq!(
+            instants,
+            vec![("thread started", 10000), ("thread exited", 20000)]
+        );
+
+        // the worker's slices end when it exits, rather than at the end of the trace
+        let worker_tid = events.iter().find(|e| e["name"] == "work").unwrap()["tid"].clone();
+        let ends: Vec<u64> = events
+            .iter()
+            .filter(|e| e["ph"] == "E" && e["tid"] == worker_tid)
+            .map(|e| e["ts"].as_u64().unwrap())
+            .collect();
+        assert_eq!(ends, vec![20000, 20000]);
+
+        // and the thread that reused its id gets a track of its own
+        let work_tids: Vec<&serde_json::Value> = events
+            .iter()
+            .filter(|e| e["name"] == "work" && e["ph"] == "B")
+            .map(|e| &e["tid"])
+            .collect();
+        assert_eq!(work_tids.len(), 2);
+        assert_ne!(work_tids[0], work_tids[1]);
+    }
+
+    #[test]
+    fn test_counters() {
+        let buffer = SharedBuffer::default();
+        let mut chrometrace =
+            Chrometrace::new(Box::new(buffer.clone()), false, false, None, false).unwrap();
+        let mut trace = trace(vec![]);
+        trace.pid = 1234;
+        trace.owns_gil = true;
+        let mut idle = trace.clone();
+        idle.thread_id = 2;
+        idle.active = false;
+        idle.owns_gil = false;
+
+        // the gil is held for half of the sampling intervals in the first window
+        for ts in [0, 10000, 20000, 30000] {
+            trace.owns_gil = ts < 20000;
+            chrometrace.increment_at(&trace, ts).unwrap();
+            chrometrace.increment_at(&idle, ts).unwrap();
+        }
+        trace.owns_gil = true;
+        chrometrace.increment_at(&trace, COUNTER_INTERVAL).unwrap();
+
+        chrometrace.finish().unwrap();
+        let events: Vec<serde_json::Value> =
+            serde_json::from_slice(&buffer.0.lock().unwrap()).unwrap();
+        let counters: Vec<&serde_json::Value> = events.iter().filter(|e| e["ph"] == "C").collect();
+        assert_eq!(counters.len(), 4);
+        assert_eq!(counters[0]["name"], "GIL");
+        assert_eq!(counters[0]["ts"], 0);
+        assert_eq!(counters[0]["args"]["held %"], 50.0);
+        assert_eq!(counters[1]["name"], "Threads");
+        assert_eq!(counters[1]["args"]["active"], 1.0);
+        assert_eq!(counters[1]["args"]["idle"], 1.0);
+        // and for all of the second window, which hasn't been finished yet
+        assert_eq!(counters[2]["ts"], COUNTER_INTERVAL);
+        assert_eq!(counters[2]["args"]["held %"], 100.0);
+        assert_eq!(counters[3]["args"]["idle"], 0.0);
+    }
+
+    #[test]
+    fn test_memory_counters() {
+        let buffer = SharedBuffer::default();
+        let mut chrometrace =
+            Chrometrace::new(Box::new(buffer.clone()), false, false, Some(1000), false).unwrap();
+        let counters = MemoryCounters {
+            rss: Some(50_000_000),
+            arenas: Some(12),
+            gc_counts: Some(vec![300, 2, 1]),
+        };
+        chrometrace
+            .add_memory_counters(1234, &counters, 500)
+            .unwrap();
+        // counters that couldn't be read are left out
+        let counters = MemoryCounters {
+            rss: Some(60_000_000),
+            ..Default::default()
+        };
+        chrometrace
+            .add_memory_counters(1234, &counters, 1500)
+            .unwrap();
+        chrometrace.finish().unwrap();
+
+        let events: Vec<serde_json::Value> =
+            serde_json::from_slice(&buffer.0.lock().unwrap()).unwrap();
+        let counters: Vec<(&str, u64)> = events
+            .iter()
+            .filter(|e| e["ph"] == "C")
+            .map(|e| (e["name"].as_str().unwrap(), e["ts"].as_u64().unwrap()))
+            .collect();
+        assert_eq!(
+            counters,
+            vec![
+                ("Memory", 1500),
+                ("pymalloc", 1500),
+                ("GC", 1500),
+                ("Memory", 2500)
+            ]
+        );
+        assert_eq!(events[0]["args"]["RSS MB"], 50.0);
+        assert_eq!(events[1]["args"]["arenas"], 12.0);
+        assert_eq!(events[2]["args"]["gen0"], 300.0);
+    }
+}
@@ -3,15 +3,24 @@ use clap::{
     PossibleValue,
 };
 use remoteprocess::Pid;
+use std::time::Duration;
+
+use crate::process_metadata::DEFAULT_METADATA_ENV;
+use crate::version::Version;
 
 /// Options on how to collect samples from a python process
 #[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
 pub struct Config {
     /// Whether or not we should stop the python process when taking samples.
     /// Setting this to false will reduce the performance impact on the target
     /// python process, but can lead to incorrect results like partial stack
     /// traces being returned or a higher sampling error rate
     pub blocking: LockingStrategy,
+    /// The longest the process can be paused for to collect a sample, after which the sample is
+    /// abandoned
+    #[doc(hidden)]
+    pub max_pause: Option<Duration>,
 
     /// Whether or not to profile native extensions. Note: this option can not be
     /// used with the nonblocking option, as we have to pause the process to collect
@@ -22,12 +31,22 @@ pub struct Config {
     #[doc(hidden)]
     pub command: String,
     #[doc(hidden)]
+    pub pids: Vec<Pid>,
+    /// The first of 'pids', from before several processes could be passed with --pid
+    #[doc(hidden)]
+    #[deprecated(note = "use 'pids', which has every process passed with --pid")]
     pub pid: Option<Pid>,
     #[doc(hidden)]
     pub python_program: Option<Vec<String>>,
     #[doc(hidden)]
     pub sampling_rate: u64,
     #[doc(hidden)]
+    pub adaptive_rate: bool,
+    #[doc(hidden)]
+    pub fixed_interval: bool,
+    #[doc(hidden)]
+    pub clock: Clock,
+    #[doc(hidden)]
     pub filename: Option<String>,
     #[doc(hidden)]
     pub format: Option<FileFormat>,
@@ -40,34 +59,196 @@ pub struct Config {
     #[doc(hidden)]
     pub include_thread_ids: bool,
     #[doc(hidden)]
+    pub tag_threads: bool,
+    #[doc(hidden)]
+    pub split_threads: bool,
+    #[doc(hidden)]
+    pub split_processes: Option<String>,
+    #[doc(hidden)]
+    pub compress: Option<Compression>,
+    #[doc(hidden)]
+    pub rotate_every: Option<u64>,
+    #[doc(hidden)]
+    pub rotate_size: Option<u64>,
+    #[doc(hidden)]
+    pub checkpoint_every: Option<u64>,
+    #[doc(hidden)]
+    pub threads_include: Option<String>,
+    #[doc(hidden)]
+    pub threads_exclude: Option<String>,
+    #[doc(hidden)]
+    pub thread_roles: bool,
+    #[doc(hidden)]
+    pub pid_name: Option<String>,
+    #[doc(hidden)]
+    pub all_matching: bool,
+    #[doc(hidden)]
+    pub pid_file: Option<String>,
+    #[doc(hidden)]
+    pub wait_for: bool,
+    #[doc(hidden)]
+    pub all_processes: bool,
+    #[doc(hidden)]
+    pub container: Option<String>,
+    #[doc(hidden)]
+    pub k8s_pod: Option<String>,
+    #[doc(hidden)]
+    pub k8s_container: Option<String>,
+    #[doc(hidden)]
+    pub force_version: Option<Version>,
+    #[doc(hidden)]
+    pub offsets_filename: Option<String>,
+    #[doc(hidden)]
+    pub process_metadata: bool,
+    #[doc(hidden)]
+    pub metadata_env: Vec<String>,
+    #[doc(hidden)]
+    pub start_on_function: Option<String>,
+    #[doc(hidden)]
+    pub stop_on_function: Option<String>,
+    #[doc(hidden)]
+    pub profile_for: Option<u64>,
+    #[doc(hidden)]
+    pub every: Option<u64>,
+    #[doc(hidden)]
+    pub palette: String,
+    #[doc(hidden)]
+    pub reverse: bool,
+    #[doc(hidden)]
+    pub speedscope_evented: bool,
+    #[doc(hidden)]
+    pub absolute_timestamps: bool,
+    #[doc(hidden)]
+    pub stack_samples: bool,
+    #[doc(hidden)]
+    pub min_slice_duration: u64,
+    #[doc(hidden)]
+    pub thread_states: bool,
+    // when the recording started, in microseconds since the unix epoch
+    #[doc(hidden)]
+    pub start_time: Option<u64>,
+    #[doc(hidden)]
+    pub report_template: Option<String>,
+    #[doc(hidden)]
+    pub report_top: usize,
+    #[doc(hidden)]
+    pub weight: Weight,
+    #[doc(hidden)]
+    pub granularity: Granularity,
+    #[doc(hidden)]
     pub subprocesses: bool,
     #[doc(hidden)]
     pub gil_only: bool,
     #[doc(hidden)]
+    pub offcpu: bool,
+    #[doc(hidden)]
+    pub importtime: bool,
+    #[doc(hidden)]
+    pub lock_contention: bool,
+    #[doc(hidden)]
+    pub asyncio: bool,
+    #[doc(hidden)]
+    pub exceptions: bool,
+    #[doc(hidden)]
+    pub memory: bool,
+    /// How often to read the memory counters of the process in seconds, with --memory-counters
+    #[doc(hidden)]
+    pub memory_counters: Option<u64>,
+    #[doc(hidden)]
+    pub trigger: Option<Trigger>,
+    #[doc(hidden)]
     pub hide_progress: bool,
     #[doc(hidden)]
+    pub stream_stats: bool,
+    #[doc(hidden)]
     pub capture_output: bool,
     #[doc(hidden)]
     pub dump_json: bool,
     #[doc(hidden)]
     pub dump_locals: u64,
     #[doc(hidden)]
+    pub args_only: bool,
+    #[doc(hidden)]
+    pub locals_depth: Option<usize>,
+    #[doc(hidden)]
+    pub locals_max_items: Option<usize>,
+    #[doc(hidden)]
+    pub locals_max_str_len: Option<usize>,
+    #[doc(hidden)]
+    pub locals_output: Option<String>,
+    #[doc(hidden)]
     pub full_filenames: bool,
     #[doc(hidden)]
+    pub qualified_names: bool,
+    #[doc(hidden)]
     pub lineno: LineNo,
     #[doc(hidden)]
     pub refresh_seconds: f64,
     #[doc(hidden)]
     pub core_filename: Option<String>,
+    #[doc(hidden)]
+    pub core_exe: Option<String>,
+    #[doc(hidden)]
+    pub input_filename: Option<String>,
+    #[doc(hidden)]
+    pub merge_filenames: Vec<String>,
+    #[doc(hidden)]
+    pub append_filename: Option<String>,
+    #[doc(hidden)]
+    pub upload_url: Option<String>,
+    #[doc(hidden)]
+    pub upload_period: u64,
+    #[doc(hidden)]
+    pub pyroscope_url: Option<String>,
+    #[doc(hidden)]
+    pub app_name: Option<String>,
+    #[doc(hidden)]
+    pub otlp_endpoint: Option<String>,
+    #[doc(hidden)]
+    pub ssh: Option<String>,
+    #[doc(hidden)]
+    pub tags: Vec<(String, String)>,
+    #[doc(hidden)]
+    pub baseline_filename: Option<String>,
+    #[doc(hidden)]
+    pub normalize_diff: bool,
+    #[doc(hidden)]
+    pub serve: Option<String>,
+    #[doc(hidden)]
+    pub metrics_address: Option<String>,
+    #[doc(hidden)]
+    pub agent_listen: String,
+    #[doc(hidden)]
+    pub agent_token: Option<String>,
+    #[doc(hidden)]
+    pub agent_dir: Option<String>,
 }
 
 #[allow(non_camel_case_types)]
 #[derive(ArgEnum, Debug, Copy, Clone, Eq, PartialEq)]
+#[non_exhaustive]
 pub enum FileFormat {
     flamegraph,
-    raw,
+    capture,
+    // 'raw' was the name of the collapsed output before the capture format was added
+    #[clap(alias = "raw")]
+    collapsed,
     speedscope,
     chrometrace,
+    pprof,
+    gecko,
+    callgrind,
+    html,
+    parquet,
+    sqlite,
+    jsonl,
+    perfetto,
+    csv,
+    report,
+    /// The old name of 'collapsed', from before the capture format was added
+    #[deprecated(note = "use 'collapsed', which 'raw' was renamed to")]
+    #[clap(skip)]
+    raw,
 }
 
 impl FileFormat {
@@ -105,6 +286,50 @@ pub enum RecordDuration {
     Seconds(u64),
 }
 
+/// How each stack is weighted in flamegraph and collapsed output
+#[derive(Debug, Clone, Eq, PartialEq, Copy)]
+pub enum Weight {
+    /// The number of samples
+    Samples,
+    /// The wall clock time covered by the samples, in microseconds
+    Wall,
+}
+
+/// How finely samples are aggregated in flamegraph, collapsed and report output
+#[derive(Debug, Clone, Eq, PartialEq, Copy)]
+pub enum Granularity {
+    /// Each frame is a function, labelled with the line it was on
+    Function,
+    /// Each frame is a function, split up by the lines within it that were being run
+    Line,
+}
+
+/// Decides how often each thread is sampled
+#[derive(Debug, Clone, Eq, PartialEq, Copy)]
+pub enum Clock {
+    /// Every thread is sampled at the sampling rate
+    Wall,
+    /// Each thread is sampled once for every 1/rate seconds of cpu time that it uses
+    Cpu,
+}
+
+/// Waits to start recording until the process has been using more than some cpu for a while,
+/// with --trigger
+#[derive(Debug, Clone, PartialEq, Copy)]
+pub struct Trigger {
+    /// The cpu usage to wait for, as a percentage of a single core
+    pub cpu_percent: f64,
+    /// How long the cpu usage has to stay above the threshold for, in seconds
+    pub window: u64,
+}
+
+/// Compression to apply to output files
+#[derive(Debug, Clone, Eq, PartialEq, Copy)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
 #[derive(Debug, Clone, Eq, PartialEq, Copy)]
 pub enum LineNo {
     NoLine,
@@ -114,31 +339,107 @@ pub enum LineNo {
 
 impl Default for Config {
     /// Initializes a new Config object with default parameters
-    #[allow(dead_code)]
+    #[allow(dead_code, deprecated)]
     fn default() -> Config {
         Config {
+            pids: Vec::new(),
             pid: None,
             python_program: None,
             filename: None,
             format: None,
             command: String::from("top"),
             blocking: LockingStrategy::Lock,
+            max_pause: None,
             show_line_numbers: false,
             sampling_rate: 100,
+            adaptive_rate: false,
+            fixed_interval: false,
+            clock: Clock::Wall,
             duration: RecordDuration::Unlimited,
             native: false,
             gil_only: false,
+            offcpu: false,
+            importtime: false,
+            lock_contention: false,
+            asyncio: false,
+            exceptions: false,
+            memory: false,
+            memory_counters: None,
+            trigger: None,
             include_idle: false,
             include_thread_ids: false,
+            tag_threads: false,
+            split_threads: false,
+            split_processes: None,
+            compress: None,
+            rotate_every: None,
+            checkpoint_every: None,
+            threads_include: None,
+            thread_roles: false,
+            pid_name: None,
+            all_matching: false,
+            pid_file: None,
+            wait_for: false,
+            all_processes: false,
+            container: None,
+            k8s_pod: None,
+            k8s_container: None,
+            force_version: None,
+            offsets_filename: None,
+            process_metadata: false,
+            metadata_env: DEFAULT_METADATA_ENV.iter().map(|s| s.to_string()).collect(),
+            threads_exclude: None,
+            start_on_function: None,
+            stop_on_function: None,
+            profile_for: None,
+            every: None,
+            rotate_size: None,
+            palette: String::from("hot"),
+            reverse: false,
+            speedscope_evented: false,
+            report_template: None,
+            report_top: 10,
+            absolute_timestamps: false,
+            stack_samples: false,
+            min_slice_duration: 0,
+            thread_states: false,
+            start_time: None,
+            weight: Weight::Samples,
+            granularity: Granularity::Function,
             hide_progress: false,
+            stream_stats: false,
             capture_output: true,
             dump_json: false,
             dump_locals: 0,
+            args_only: false,
+            locals_depth: None,
+            locals_max_items: None,
+            locals_max_str_len: None,
+            locals_output: None,
             subprocesses: false,
             full_filenames: false,
+            qualified_names: false,
             lineno: LineNo::LastInstruction,
             refresh_seconds: 1.0,
             core_filename: None,
+            core_exe: None,
+            input_filename: None,
+            merge_filenames: Vec::new(),
+            append_filename: None,
+            upload_url: None,
+            upload_period: 60,
+            pyroscope_url: None,
+            app_name: None,
+            otlp_endpoint: None,
+            ssh: None,
+            tags: Vec::new(),
+            baseline_filename: None,
+            normalize_diff: false,
+            serve: None,
+            metrics_address: None,
+            agent_listen: String::from("127.0.0.1:7777"),
+            agent_token: None,
+            agent_dir: None,
         }
     }
 }
@@ -157,7 +458,60 @@ impl Config {
             .short('p')
             .long("pid")
             .value_name("pid")
-            .help("PID of a running python program to spy on")
+            .help("PID of a running python program to spy on. Can be given more than once to spy on several programs together")
+            .multiple_occurrences(true)
+            .takes_value(true);
+
+        let pid_name = Arg::new("pid_name")
+            .long("pid-name")
+            .value_name("regex")
+            .help("Find the python program to spy on by matching a regex against the name and command line of each process, like 'gunicorn: worker'. Fails with a list of the matching processes if there's more than one, unless --all-matching is given")
+            .value_parser(parse_regex)
+            .conflicts_with("pid")
+            .takes_value(true);
+        let pid_file = Arg::new("pid_file")
+            .long("pid-file")
+            .value_name("filename")
+            .help("Spy on the python program whose pid is in this file, like the pid files written by gunicorn or celery")
+            .conflicts_with_all(&["pid", "pid_name"])
+            .takes_value(true);
+        let wait_for = Arg::new("wait_for")
+            .long("wait-for")
+            .help("Wait for the process given by --pid-name or --pid-file to start, and attach to it as soon as it does. For profiling short-lived jobs started by something else, like a scheduler");
+        let all_matching = Arg::new("all_matching")
+            .long("all-matching")
+            .help("Spy on every process matched by --pid-name")
+            .requires("pid_name")
+            .conflicts_with("pid");
+        let all_processes = Arg::new("all")
+            .long("all")
+            .help("Spy on every python program running on this machine, labelling each sample with the process it came from")
+            .conflicts_with_all(&[
+                "pid",
+                "pid_name",
+                "pid_file",
+                "python_program",
+                "container",
+                "k8s_pod",
+            ]);
+        let container = Arg::new("container")
+            .long("container")
+            .value_name("name-or-id")
+            .help("Spy on the python programs running in a docker or containerd container, given by its name or id")
+            .conflicts_with_all(&["pid", "pid_name", "pid_file"])
+            .takes_value(true);
+        let k8s_pod = Arg::new("k8s_pod")
+            .long("k8s-pod")
+            .value_name("namespace/pod")
+            .help("Spy on the python programs running in a kubernetes pod on this node. Uses crictl to find the pod's containers")
+            .conflicts_with_all(&["pid", "pid_name", "pid_file", "container"])
+            .takes_value(true);
+        let k8s_container = Arg::new("k8s_container")
+            .long("k8s-container")
+            .value_name("name")
+            .help("Only spy on the python programs in this container of the --k8s-pod")
+            .requires("k8s_pod")
+            .conflicts_with_all(&["pid", "pid_name", "pid_file", "container"])
             .takes_value(true);
 
         #[cfg(unwind)]
@@ -180,6 +534,22 @@ impl Config {
             .default_value("100")
             .takes_value(true);
 
+        let adaptive_rate = Arg::new("adaptive_rate").long("adaptive-rate").help(
+            "Lower the sampling rate when samples can't be collected fast enough (like with large numbers of threads or subprocesses), and raise it back when they can",
+        );
+
+        let fixed_interval = Arg::new("fixed_interval").long("fixed-interval").help(
+            "Sample at evenly spaced intervals, instead of randomizing the time between samples to avoid lining up with periodic work in the program",
+        );
+
+        let clock = Arg::new("clock")
+            .long("clock")
+            .value_name("clock")
+            .help("Sample every thread at the sampling rate ('wall'), or sample each thread in proportion to the cpu time it uses ('cpu', linux only)")
+            .possible_values(["wall", "cpu"])
+            .default_value("wall")
+            .takes_value(true);
+
         let subprocesses = Arg::new("subprocesses")
             .short('s')
             .long("subprocesses")
@@ -188,6 +558,9 @@ impl Config {
         let full_filenames = Arg::new("full_filenames").long("full-filenames").help(
             "Show full Python filenames, instead of shortening to show only the package part",
         );
+        let qualified_names = Arg::new("qualified_names")
+            .long("qualified-names")
+            .help("Show the qualified names of functions, like 'Class.method' instead of 'method'. Before python 3.11 the class is found from the 'self' or 'cls' argument of methods");
         let program = Arg::new("python_program")
             .help("commandline of a python program to run")
             .multiple_values(true);
@@ -202,6 +575,113 @@ impl Config {
             .long("gil")
             .help("Only include traces that are holding on to the GIL");
 
+        let offcpu = Arg::new("offcpu")
+            .long("offcpu")
+            .help("Only include threads that aren't running (linux only), with the leaf of each stack tagged with whether it was waiting on the GIL, waiting on a lock, sleeping or blocked in a syscall. Implies --idle")
+            .conflicts_with("gil");
+
+        let force_version = Arg::new("force_version")
+            .long("force-version")
+            .alias("python-version")
+            .value_name("version")
+            .help("Read the process as this version of python (like '2.7' or '3.9'), rather than the version found in its binary. For interpreters whose version string can't be found, like some embedded or patched builds")
+            .value_parser(|value: &str| value.parse::<Version>().map_err(|e| e.to_string()))
+            .takes_value(true);
+
+        let offsets = Arg::new("offsets")
+            .long("offsets")
+            .value_name("filename")
+            .help("TOML file with the offsets of struct fields that differ from stock CPython, for patched or prerelease interpreters (see the README)")
+            .takes_value(true);
+
+        let asyncio = Arg::new("asyncio")
+            .long("asyncio")
+            .help("Also include the stacks of asyncio tasks that are waiting to be resumed, from the coroutines each task is awaiting (python 3.7 to 3.11)");
+
+        let thread_roles = Arg::new("thread_roles").long("thread-roles").help(
+            "Label threads with the role they play when it can be told from their stacks, like 'Thread-3 [ThreadPoolExecutor worker]' (asyncio event loops, ThreadPoolExecutor and multiprocessing pool workers, gRPC pollers and Kafka consumer heartbeats)",
+        );
+
+        let tag_threads = Arg::new("tag_threads").long("tag-threads").help(
+            "Prefix stacks in flamegraph and collapsed output with the pid, thread name and gil/idle state",
+        );
+
+        let weight = Arg::new("weight")
+            .long("weight")
+            .value_name("weight")
+            .help("Weight stacks in flamegraph and collapsed output by sample count, or by wall clock time in microseconds")
+            .possible_values(["samples", "wall"])
+            .default_value("samples")
+            .takes_value(true);
+
+        let granularity = Arg::new("granularity")
+            .long("granularity")
+            .value_name("granularity")
+            .help("How finely to aggregate samples in flamegraph, collapsed and report output. 'line' gives each function a single frame, split up by the lines within it, and adds a table of the hottest lines of each top function to reports")
+            .possible_values(["function", "line"])
+            .default_value("function")
+            .takes_value(true);
+
+        let compress = Arg::new("compress")
+            .long("compress")
+            .value_name("compression")
+            .help("Compress the output file, adding a .gz or .zst extension to the filename")
+            .possible_values(["gzip", "zstd"])
+            .takes_value(true);
+
+        let palette = Arg::new("palette")
+            .long("palette")
+            .value_name("palette")
+            .help("Flamegraph color palette. 'package' colors frames by top level python package, 'native' separates native and python frames, and 'thread' colors frames by thread")
+            .possible_values(
+                ["package", "native", "thread"]
+                    .iter()
+                    .chain(inferno::flamegraph::Palette::VARIANTS),
+            )
+            .default_value("hot")
+            .takes_value(true);
+
+        let reverse = Arg::new("reverse").long("reverse").help(
+            "Generate a reversed flamegraph, merging stacks from the leaf function upwards to show what calls each function",
+        );
+
+        let evented = Arg::new("evented").long("evented").help(
+            "Write speedscope profiles in the evented format, with the time each frame was entered and exited",
+        );
+
+        let absolute_timestamps = Arg::new("absolute_timestamps")
+            .long("absolute-timestamps")
+            .help("Use microseconds since the unix epoch for timestamps in chrometrace output, so that events line up with timestamps in logs");
+
+        let stack_samples = Arg::new("stack_samples").long("stack-samples").help(
+            "Write chrometrace output as individual stack samples, instead of merging consecutive samples into slices",
+        );
+
+        let thread_states = Arg::new("thread_states").long("thread-states").help(
+            "Add a track under each thread in chrometrace output, showing whether it was running, waiting on the GIL, blocked on I/O or sleeping",
+        );
+
+        let min_slice_duration = Arg::new("min_slice_duration")
+            .long("min-slice-duration")
+            .value_name("microseconds")
+            .help("Leave slices shorter than this out of chrometrace output, to keep down the size of traces recorded at high sampling rates")
+            .value_parser(clap::value_parser!(u64))
+            .takes_value(true);
+
+        let report_template = Arg::new("report_template")
+            .long("report-template")
+            .value_name("template")
+            .help("Template for '--format report' output. Can use {samples}, {errors}, {duration}, {gil_percent}, {active_percent}, {gc_percent}, {top_functions}, {hot_lines} and {threads}")
+            .takes_value(true);
+
+        let report_top = Arg::new("report_top")
+            .long("report-top")
+            .value_name("count")
+            .help("Number of functions to list in '--format report' output")
+            .default_value("10")
+            .value_parser(clap::value_parser!(usize))
+            .takes_value(true);
+
         let top_delay = Arg::new("delay")
             .long("delay")
             .value_name("seconds")
@@ -210,17 +690,39 @@ impl Config {
             .value_parser(clap::value_parser!(f64))
             .takes_value(true);
 
+        let metrics = Arg::new("metrics")
+            .long("metrics")
+            .value_name("address")
+            .help("Serves metrics about the profiled program (like the share of samples each thread is active in, and that the GIL is held in) for Prometheus to scrape from /metrics on an address, like ':9090' or '127.0.0.1:9090'")
+            .takes_value(true);
+
         let record = Command::new("record")
-            .about("Records stack trace information to a flamegraph, speedscope or raw file")
+            .about("Records stack trace information to a flamegraph, speedscope or capture file")
             .arg(program.clone())
-            .arg(pid.clone().required_unless_present("python_program"))
+            .arg(pid.clone().required_unless_present_any([
+                "python_program",
+                "pid_name",
+                "pid_file",
+                "all",
+                "container",
+                "k8s_pod",
+            ]))
+            .arg(pid_name.clone())
+            .arg(all_matching.clone())
+            .arg(pid_file.clone())
+            .arg(wait_for.clone())
+            .arg(all_processes.clone())
+            .arg(container.clone())
+            .arg(k8s_pod.clone())
+            .arg(k8s_container.clone())
             .arg(full_filenames.clone())
+            .arg(qualified_names.clone())
             .arg(
                 Arg::new("output")
                     .short('o')
                     .long("output")
                     .value_name("filename")
-                    .help("Output filename")
+                    .help("Output filename, or '-' to write to stdout. Can include {pid}, {exe}, {timestamp} and {hostname} variables")
                     .takes_value(true)
                     .required(false),
             )
@@ -245,7 +747,18 @@ impl Config {
                     .takes_value(true),
             )
             .arg(rate.clone())
+            .arg(adaptive_rate.clone())
+            .arg(fixed_interval.clone())
+            .arg(clock.clone())
             .arg(subprocesses.clone())
+            .arg(
+                Arg::new("max_pause")
+                    .long("max-pause-us")
+                    .value_name("microseconds")
+                    .help("Abandon any sample that would pause the process for longer than this, to bound the latency added by profiling")
+                    .value_parser(clap::value_parser!(u64).range(1..))
+                    .takes_value(true),
+            )
             .arg(Arg::new("function").short('F').long("function").help(
                 "Aggregate samples by function's first line number, instead of current line number",
             ))
@@ -260,8 +773,270 @@ impl Config {
                     .long("threads")
                     .help("Show thread ids in the output"),
             )
+            .arg(
+                Arg::new("threads_include")
+                    .long("threads-include")
+                    .value_name("regex")
+                    .help("Only sample threads with names matching this regex. Threads without a name aren't sampled")
+                    .value_parser(parse_regex)
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("threads_exclude")
+                    .long("threads-exclude")
+                    .value_name("regex")
+                    .help("Don't sample threads with names matching this regex, like 'heartbeat|metrics'")
+                    .value_parser(parse_regex)
+                    .takes_value(true),
+            )
+            .arg(thread_roles.clone())
             .arg(gil.clone())
             .arg(idle.clone())
+            .arg(offcpu.clone())
+            .arg(asyncio.clone())
+            .arg(force_version.clone())
+            .arg(offsets.clone())
+            .arg(
+                Arg::new("lock_contention")
+                    .long("lock-contention")
+                    .help("Print a report at the end of the recording of the python locks that threads were blocked on, with the stacks that were waiting on and holding each lock")
+                    .conflicts_with("gil"),
+            )
+            .arg(
+                Arg::new("exceptions")
+                    .long("exceptions")
+                    .help("Also write a flamegraph of the samples where an exception was being raised, grouped by the type of exception, to a file named like 'profile.exceptions.svg'")
+                    .conflicts_with_all(&["upload_url", "pyroscope", "otlp"]),
+            )
+            .arg(
+                Arg::new("memory")
+                    .long("memory")
+                    .help("Profile the memory allocated by each stack rather than cpu usage, by reading the allocations traced by tracemalloc in the python program (which has to be started with PYTHONTRACEMALLOC=25 or tracemalloc.start(25)). Requires python 3.9 to 3.11")
+                    .conflicts_with_all(&[
+                        "gil",
+                        "idle",
+                        "offcpu",
+                        "lock_contention",
+                        "asyncio",
+                        "exceptions",
+                        "native",
+                        "subprocesses",
+                        "split_threads",
+                        "split_processes",
+                        "rotate_every",
+                        "rotate_size",
+                        "every",
+                        "append",
+                        "upload_url",
+                        "pyroscope",
+                        "otlp",
+                    ]),
+            )
+            .arg(
+                Arg::new("memory_counters")
+                    .long("memory-counters")
+                    .value_name("interval")
+                    .help("Record the RSS, pymalloc arena count and garbage collector counts of the process alongside the samples, in chrometrace, speedscope and capture output. Takes an optional interval to read these at, which defaults to '1s'")
+                    .value_parser(parse_duration)
+                    .takes_value(true)
+                    .min_values(0)
+                    .require_equals(true)
+                    .conflicts_with("memory"),
+            )
+            .arg(
+                Arg::new("trigger")
+                    .long("trigger")
+                    .value_name("condition")
+                    .help("Wait to start recording until the process has used more than some cpu for a while, like 'cpu>80%:30s' for more than 80% of a core for 30 seconds. The recording then lasts for --duration")
+                    .value_parser(parse_trigger)
+                    .takes_value(true)
+                    .conflicts_with_all(&["memory", "append"]),
+            )
+            .arg(tag_threads.clone())
+            .arg(weight.clone())
+            .arg(granularity.clone())
+            .arg(palette.clone())
+            .arg(reverse.clone())
+            .arg(evented.clone())
+            .arg(absolute_timestamps.clone())
+            .arg(stack_samples.clone())
+            .arg(min_slice_duration.clone())
+            .arg(thread_states.clone())
+            .arg(report_template.clone())
+            .arg(report_top.clone())
+            .arg(compress.clone().conflicts_with_all(&["upload_url", "pyroscope", "otlp"]))
+            .arg(
+                Arg::new("split_threads")
+                    .long("split-threads")
+                    .help("Write a separate output file for each thread, named after the thread")
+                    .conflicts_with_all(&["upload_url", "pyroscope", "otlp"]),
+            )
+            .arg(
+                Arg::new("split_processes")
+                    .long("split-processes")
+                    .value_name("template")
+                    .help("Also write a separate output file for each process with --subprocesses. Takes an optional filename template, which can use {pid} and {cmdline}")
+                    .takes_value(true)
+                    .min_values(0)
+                    .require_equals(true)
+                    .requires("subprocesses")
+                    .conflicts_with_all(&["split_threads", "upload_url", "pyroscope", "otlp"]),
+            )
+            .arg(
+                Arg::new("rotate_every")
+                    .long("rotate-every")
+                    .value_name("duration")
+                    .help("Start a new output file periodically, like every '10m' or '1h'. Output files are numbered, like 'profile.0001.capture'")
+                    .value_parser(parse_duration)
+                    .takes_value(true)
+                    .conflicts_with_all(&["split_threads", "split_processes", "upload_url", "pyroscope", "otlp"]),
+            )
+            .arg(
+                Arg::new("rotate_size")
+                    .long("rotate-size")
+                    .value_name("size")
+                    .help("Start a new output file once the output reaches this size, like '500MB'. Only supported for capture, jsonl and sqlite output")
+                    .value_parser(parse_rotate_size)
+                    .takes_value(true)
+                    .conflicts_with_all(&["split_threads", "split_processes", "upload_url", "pyroscope", "otlp"]),
+            )
+            .arg(
+                Arg::new("checkpoint_every")
+                    .long("checkpoint-every")
+                    .value_name("duration")
+                    .help("Write out everything recorded so far periodically, like every '1m', so that the output isn't lost if py-spy is killed")
+                    .value_parser(parse_duration)
+                    .takes_value(true)
+                    .conflicts_with_all(&["upload_url", "pyroscope", "otlp"]),
+            )
+            .arg(
+                Arg::new("start_on_function")
+                    .long("start-on-function")
+                    .value_name("pattern")
+                    .help("Only start recording once a function matching this regex is seen in a sample. The regex is matched against the function name, its filename and its qualified name like 'module.func'")
+                    .value_parser(parse_regex)
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("importtime")
+                    .long("importtime")
+                    .help("Profile the imports run when the program starts, stopping once the __main__ module starts running. Each module is shown as an 'import' frame under the module that imported it. Includes idle time (like waiting on the disk), and samples 1000 times a second unless --rate is given")
+                    .requires("python_program"),
+            )
+            .arg(
+                Arg::new("stop_on_function")
+                    .long("stop-on-function")
+                    .value_name("pattern")
+                    .help("Stop recording once a function matching this regex is seen in a sample, like --start-on-function")
+                    .value_parser(parse_regex)
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("profile_for")
+                    .long("profile-for")
+                    .value_name("duration")
+                    .help("Only sample for this long out of every --every, like '30s'")
+                    .value_parser(parse_duration)
+                    .takes_value(true)
+                    .requires("every"),
+            )
+            .arg(
+                Arg::new("every")
+                    .long("every")
+                    .value_name("duration")
+                    .help("Start sampling for --profile-for periodically, like every '10m'. Each window is written to its own numbered output file, like 'profile.0001.capture'")
+                    .value_parser(parse_duration)
+                    .takes_value(true)
+                    .requires("profile_for")
+                    .conflicts_with_all(&["split_threads", "split_processes", "rotate_every", "rotate_size", "upload_url", "pyroscope", "otlp"]),
+            )
+            .arg(
+                Arg::new("append")
+                    .long("append")
+                    .value_name("filename")
+                    .help("Include the samples from a previous 'record --format capture' file in the output. Unless given other options, the new samples are added to the end of the same capture file")
+                    .takes_value(true)
+                    .conflicts_with_all(&["split_threads", "split_processes", "rotate_every", "rotate_size", "every", "upload_url", "pyroscope", "otlp"]),
+            )
+            .arg(
+                Arg::new("upload_url")
+                    .long("upload-url")
+                    .value_name("url")
+                    .help("Continuously upload pprof profiles to this URL, instead of writing to a file")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("upload_period")
+                    .long("upload-period")
+                    .value_name("seconds")
+                    .help("How often to upload profiles with --upload-url (default 60) or --pyroscope (default 10)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("pyroscope")
+                    .long("pyroscope")
+                    .value_name("url")
+                    .help("Continuously push profiles to this Pyroscope server, instead of writing to a file")
+                    .takes_value(true)
+                    .requires("app_name")
+                    .conflicts_with("upload_url"),
+            )
+            .arg(
+                Arg::new("otlp")
+                    .long("otlp")
+                    .value_name("endpoint")
+                    .help("Continuously export profiles to this OpenTelemetry collector's OTLP/HTTP endpoint, instead of writing to a file")
+                    .takes_value(true)
+                    .conflicts_with_all(&["upload_url", "pyroscope"]),
+            )
+            .arg(
+                Arg::new("app_name")
+                    .long("app-name")
+                    .value_name("name")
+                    .help("Application name to use with --pyroscope, or the service.name to use with --otlp")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("tag")
+                    .long("tag")
+                    .value_name("key=value")
+                    .help("Tag to add to profiles pushed with --pyroscope, or resource attribute to add with --otlp. Can be passed multiple times")
+                    .takes_value(true)
+                    .multiple_occurrences(true),
+            )
+            .arg(
+                Arg::new("metadata_env")
+                    .long("metadata-env")
+                    .value_name("name")
+                    .help("Environment variable to store in the metadata of capture, speedscope and pprof profiles, along with the program's sys.argv, python version and package versions. Names ending in '*' match any variable starting with the rest of the name. Can be passed multiple times, and adds to the variables stored by default (like VIRTUAL_ENV and GIT_SHA)")
+                    .takes_value(true)
+                    .multiple_occurrences(true),
+            )
+            .arg(
+                Arg::new("ssh")
+                    .long("ssh")
+                    .value_name("destination")
+                    .help("Profile a process on another machine, by running py-spy on it over ssh (like 'user@host'). The samples are streamed back and written out here, in any format. If the machine doesn't have this version of py-spy, it's copied over")
+                    .takes_value(true)
+                    .conflicts_with_all(&[
+                        "python_program",
+                        "split_threads",
+                        "split_processes",
+                        "rotate_every",
+                        "rotate_size",
+                        "checkpoint_every",
+                        "every",
+                        "append",
+                        "upload_url",
+                        "pyroscope",
+                        "otlp",
+                        "metrics",
+                        // the remote host writes json lines to stdout, which these can't be used with
+                        "exceptions",
+                        "memory",
+                    ]),
+            )
+            .arg(metrics.clone())
             .arg(
                 Arg::new("capture")
                     .long("capture")
@@ -273,62 +1048,291 @@ impl Config {
                     .long("hideprogress")
                     .hide(true)
                     .help("Hides progress bar (useful for showing error output on record)"),
+            )
+            .arg(
+                Arg::new("stream_stats")
+                    .long("stream-stats")
+                    .hide(true)
+                    .help("Ends jsonl output with the statistics of the sampler (used by --ssh)"),
             );
 
         let top = Command::new("top")
             .about("Displays a top like view of functions consuming CPU")
             .arg(program.clone())
-            .arg(pid.clone().required_unless_present("python_program"))
+            .arg(pid.clone().required_unless_present_any([
+                "python_program",
+                "pid_name",
+                "pid_file",
+                "all",
+                "container",
+                "k8s_pod",
+            ]))
+            .arg(pid_name.clone())
+            .arg(all_matching.clone())
+            .arg(pid_file.clone())
+            .arg(wait_for.clone())
+            .arg(all_processes)
+            .arg(container.clone())
+            .arg(k8s_pod.clone())
+            .arg(k8s_container.clone())
             .arg(rate.clone())
+            .arg(adaptive_rate)
+            .arg(fixed_interval)
+            .arg(clock)
             .arg(subprocesses.clone())
             .arg(full_filenames.clone())
+            .arg(qualified_names.clone())
             .arg(gil.clone())
             .arg(idle.clone())
-            .arg(top_delay.clone());
-
-        #[cfg(target_os = "linux")]
-        let dump_pid = pid.clone().required_unless_present("core");
+            .arg(offcpu)
+            .arg(asyncio.clone())
+            .arg(force_version.clone())
+            .arg(offsets.clone())
+            .arg(thread_roles)
+            .arg(top_delay.clone())
+            .arg(metrics)
+            .arg(
+                Arg::new("serve")
+                    .long("serve")
+                    .value_name("address")
+                    .help("Serves a live flamegraph over HTTP on an address (like ':8080' for port 8080 on all interfaces, or '127.0.0.1:8080') instead of showing the view in the terminal")
+                    .takes_value(true),
+            );
 
-        #[cfg(not(target_os = "linux"))]
-        let dump_pid = pid.clone().required(true);
+        let dump_pid = pid.clone().required_unless_present_any([
+            "core",
+            "pid_name",
+            "pid_file",
+            "container",
+            "k8s_pod",
+        ]);
 
         let dump = Command::new("dump")
             .about("Dumps stack traces for a target program to stdout")
-            .arg(dump_pid);
-
-        #[cfg(target_os = "linux")]
-        let dump = dump.arg(
-            Arg::new("core")
-                .short('c')
-                .long("core")
-                .help("Filename of coredump to display python stack traces from")
-                .value_name("core")
-                .takes_value(true),
-        );
-
-        let dump = dump.arg(full_filenames.clone())
+            // keep 'py-spy d' working, even though it's also a prefix of 'diff'
+            .alias("d")
+            .arg(dump_pid)
+            .arg(pid_name)
+            .arg(all_matching)
+            .arg(pid_file)
+            .arg(wait_for)
+            .arg(container)
+            .arg(k8s_pod)
+            .arg(k8s_container)
+            .arg(
+                Arg::new("core")
+                    .short('c')
+                    .long("core")
+                    .help("Filename of coredump (or windows minidump) to display python stack traces from")
+                    .value_name("core")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("exe")
+                    .long("exe")
+                    .help("The python executable the coredump is from (or python dll, for minidumps), for when it's been moved or the coredump is from another machine. Defaults to the path stored in the coredump")
+                    .value_name("filename")
+                    .requires("core")
+                    .takes_value(true),
+            )
+            .arg(full_filenames.clone())
+            .arg(qualified_names)
             .arg(Arg::new("locals")
                 .short('l')
                 .long("locals")
                 .multiple_occurrences(true)
                 .help("Show local variables for each frame. Passing multiple times (-ll) increases verbosity"))
+            .arg(Arg::new("args_only")
+                .long("args-only")
+                .help("Only show the arguments of each frame, rather than all of its local variables. Implies --locals"))
+            .arg(Arg::new("locals_depth")
+                .long("locals-depth")
+                .value_name("depth")
+                .help("How many levels of nested dicts, lists, tuples and sets to show the contents of in local variables. Values are shown in full rather than truncated when this (or --locals-max-items or --locals-max-str-len) is set. Implies --locals")
+                .takes_value(true))
+            .arg(Arg::new("locals_max_items")
+                .long("locals-max-items")
+                .value_name("items")
+                .help("The most items of each dict, list, tuple or set to show in local variables. Implies --locals")
+                .takes_value(true))
+            .arg(Arg::new("locals_max_str_len")
+                .long("locals-max-str-len")
+                .value_name("length")
+                .help("The most characters of each string (or bytes of binary data, which is shown in hex) to show in local variables. Implies --locals")
+                .takes_value(true))
+            .arg(Arg::new("locals_output")
+                .long("locals-output")
+                .value_name("filename")
+                .help("Also write the stack traces with the full values of local variables to this JSON file, rather than truncating them. Implies --locals")
+                .takes_value(true))
             .arg(Arg::new("json")
                 .short('j')
                 .long("json")
                 .help("Format output as JSON"))
-            .arg(subprocesses.clone());
+            .arg(subprocesses.clone())
+            .arg(asyncio)
+            .arg(force_version)
+            .arg(offsets);
 
-        let completions = Command::new("completions")
-            .about("Generate shell completions")
-            .hide(true)
+        let convert = Command::new("convert")
+            .about(
+                "Converts a capture file from 'record --format capture' into another output format",
+            )
             .arg(
-                Arg::new("shell")
-                    .value_parser(value_parser!(clap_complete::Shell))
-                    .help("Shell type"),
-            );
-
-        // add native unwinding if appropriate
-        #[cfg(unwind)]
+                Arg::new("input")
+                    .value_name("input")
+                    .help("Capture file to convert")
+                    .takes_value(true)
+                    .required(true),
+            )
+            .arg(
+                Arg::new("output")
+                    .short('o')
+                    .long("output")
+                    .value_name("filename")
+                    .help("Output filename, or '-' to write to stdout")
+                    .takes_value(true)
+                    .required(false),
+            )
+            .arg(
+                Arg::new("format")
+                    .short('f')
+                    .long("format")
+                    .value_name("format")
+                    .help("Output file format")
+                    .takes_value(true)
+                    .possible_values(FileFormat::possible_values())
+                    .ignore_case(true)
+                    .default_value("flamegraph"),
+            )
+            .arg(tag_threads.clone())
+            .arg(weight.clone())
+            .arg(granularity.clone())
+            .arg(palette.clone())
+            .arg(reverse.clone())
+            .arg(evented.clone())
+            .arg(absolute_timestamps.clone())
+            .arg(stack_samples.clone())
+            .arg(min_slice_duration.clone())
+            .arg(thread_states.clone())
+            .arg(report_template.clone())
+            .arg(report_top.clone())
+            .arg(compress.clone());
+
+        let merge = Command::new("merge")
+            .about(
+                "Merges several capture files from 'record --format capture' into a single output",
+            )
+            .arg(
+                Arg::new("inputs")
+                    .value_name("inputs")
+                    .help("Capture files to merge")
+                    .takes_value(true)
+                    .multiple_values(true)
+                    .required(true),
+            )
+            .arg(
+                Arg::new("output")
+                    .short('o')
+                    .long("output")
+                    .value_name("filename")
+                    .help("Output filename, or '-' to write to stdout")
+                    .takes_value(true)
+                    .required(false),
+            )
+            .arg(
+                Arg::new("format")
+                    .short('f')
+                    .long("format")
+                    .value_name("format")
+                    .help("Output file format")
+                    .takes_value(true)
+                    .possible_values(FileFormat::possible_values())
+                    .ignore_case(true)
+                    .default_value("flamegraph"),
+            )
+            .arg(tag_threads)
+            .arg(weight)
+            .arg(granularity)
+            .arg(palette)
+            .arg(reverse)
+            .arg(evented)
+            .arg(absolute_timestamps)
+            .arg(stack_samples)
+            .arg(min_slice_duration)
+            .arg(thread_states)
+            .arg(report_template)
+            .arg(report_top)
+            .arg(compress);
+
+        let diff = Command::new("diff")
+            .about("Generates a differential flamegraph comparing two recordings")
+            .arg(
+                Arg::new("before")
+                    .value_name("before")
+                    .help("Baseline profile, either a capture file or collapsed stacks")
+                    .takes_value(true)
+                    .required(true),
+            )
+            .arg(
+                Arg::new("after")
+                    .value_name("after")
+                    .help("Profile to compare against the baseline")
+                    .takes_value(true)
+                    .required(true),
+            )
+            .arg(
+                Arg::new("output")
+                    .short('o')
+                    .long("output")
+                    .value_name("filename")
+                    .help("Output filename")
+                    .takes_value(true)
+                    .required(false),
+            )
+            .arg(
+                Arg::new("normalize")
+                    .short('n')
+                    .long("normalize")
+                    .help("Scale the baseline sample counts to match the total of the second profile. Useful when the recordings were of different lengths"),
+            );
+
+        let agent = Command::new("agent")
+            .about("Runs persistently, recording and dumping processes as requested over an HTTP API")
+            .arg(
+                Arg::new("listen")
+                    .long("listen")
+                    .value_name("address")
+                    .help("The address to serve the API on")
+                    .default_value("127.0.0.1:7777")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("token")
+                    .long("token")
+                    .value_name("token")
+                    .help("The bearer token that requests to the API have to be authorized with. Defaults to the PY_SPY_AGENT_TOKEN environment variable, or a random token that's printed out on startup")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("dir")
+                    .long("dir")
+                    .value_name("directory")
+                    .help("The directory to write profiles to. Defaults to a temporary directory")
+                    .takes_value(true),
+            );
+
+        let completions = Command::new("completions")
+            .about("Generate shell completions")
+            .hide(true)
+            .arg(
+                Arg::new("shell")
+                    .value_parser(value_parser!(clap_complete::Shell))
+                    .help("Shell type"),
+            );
+
+        // add native unwinding if appropriate
+        #[cfg(unwind)]
         let record = record.arg(native.clone());
         #[cfg(unwind)]
         let top = top.arg(native.clone());
@@ -353,6 +1357,10 @@ impl Config {
             .subcommand(record)
             .subcommand(top)
             .subcommand(dump)
+            .subcommand(convert)
+            .subcommand(merge)
+            .subcommand(diff)
+            .subcommand(agent)
             .subcommand(completions);
         let matches = app.clone().try_get_matches_from(args)?;
         info!("Command line args: {:?}", matches);
@@ -364,6 +1372,9 @@ impl Config {
         match subcommand {
             "record" => {
                 config.sampling_rate = matches.value_of_t("rate")?;
+                config.adaptive_rate = matches.occurrences_of("adaptive_rate") > 0;
+                config.fixed_interval = matches.occurrences_of("fixed_interval") > 0;
+                config.clock = parse_clock(matches);
                 config.duration = match matches.value_of("duration") {
                     Some("unlimited") | None => RecordDuration::Unlimited,
                     Some(seconds) => {
@@ -381,25 +1392,210 @@ impl Config {
                     LineNo::LastInstruction
                 };
                 config.include_thread_ids = matches.occurrences_of("threads") > 0;
+                config.lock_contention = matches.occurrences_of("lock_contention") > 0;
+                config.exceptions = matches.occurrences_of("exceptions") > 0;
+                config.memory = matches.occurrences_of("memory") > 0;
+                if matches.occurrences_of("memory_counters") > 0 {
+                    let interval = matches.get_one::<u64>("memory_counters").copied();
+                    config.memory_counters = Some(interval.unwrap_or(1));
+                }
+                config.max_pause = matches
+                    .get_one::<u64>("max_pause")
+                    .map(|us| Duration::from_micros(*us));
+                config.trigger = matches.get_one::<Trigger>("trigger").copied();
+                if config.trigger.is_some() && cfg!(not(target_os = "linux")) {
+                    eprintln!("--trigger is only supported on linux");
+                    std::process::exit(1);
+                }
+                if config.memory
+                    && !matches!(
+                        config.format,
+                        Some(FileFormat::flamegraph) | Some(FileFormat::collapsed)
+                    )
+                {
+                    eprintln!("--memory can only write flamegraph or collapsed output");
+                    std::process::exit(1);
+                }
                 if matches.occurrences_of("nolineno") > 0 && matches.occurrences_of("function") > 0
                 {
                     eprintln!("--function & --nolinenos can't be used together");
                     std::process::exit(1);
                 }
+                if matches.value_of("granularity") == Some("line")
+                    && config.lineno != LineNo::LastInstruction
+                {
+                    eprintln!("--granularity line can't be used with --function or --nolineno");
+                    std::process::exit(1);
+                }
                 config.hide_progress = matches.occurrences_of("hideprogress") > 0;
+                config.stream_stats = matches.occurrences_of("stream_stats") > 0;
+                config.split_threads = matches.occurrences_of("split_threads") > 0;
+                if matches.occurrences_of("split_processes") > 0 {
+                    let template = matches.value_of("split_processes").unwrap_or_default();
+                    config.split_processes = Some(template.to_owned());
+                }
+                if (config.split_threads || config.split_processes.is_some())
+                    && config.filename.as_deref() == Some("-")
+                {
+                    eprintln!("--split-threads and --split-processes can't be used when writing to stdout");
+                    std::process::exit(1);
+                }
+                config.rotate_every = matches.get_one::<u64>("rotate_every").copied();
+                config.rotate_size = matches.get_one::<u64>("rotate_size").copied();
+                if (config.rotate_every.is_some() || config.rotate_size.is_some())
+                    && config.filename.as_deref() == Some("-")
+                {
+                    eprintln!(
+                        "--rotate-every and --rotate-size can't be used when writing to stdout"
+                    );
+                    std::process::exit(1);
+                }
+                if config.rotate_size.is_some()
+                    && !matches!(
+                        config.format,
+                        Some(FileFormat::capture)
+                            | Some(FileFormat::jsonl)
+                            | Some(FileFormat::sqlite)
+                    )
+                {
+                    eprintln!(
+                        "--rotate-size is only supported for capture, jsonl and sqlite output"
+                    );
+                    std::process::exit(1);
+                }
+                config.checkpoint_every = matches.get_one::<u64>("checkpoint_every").copied();
+                if config.checkpoint_every.is_some() && config.filename.as_deref() == Some("-") {
+                    eprintln!("--checkpoint-every can't be used when writing to stdout");
+                    std::process::exit(1);
+                }
+                config.threads_include = matches.get_one::<String>("threads_include").cloned();
+                config.threads_exclude = matches.get_one::<String>("threads_exclude").cloned();
+                config.start_on_function = matches.get_one::<String>("start_on_function").cloned();
+                config.stop_on_function = matches.get_one::<String>("stop_on_function").cloned();
+                config.importtime = matches.occurrences_of("importtime") > 0;
+                // imports are usually over in well under a second
+                if config.importtime && matches.occurrences_of("rate") == 0 {
+                    config.sampling_rate = 1000;
+                }
+                config.profile_for = matches.get_one::<u64>("profile_for").copied();
+                config.every = matches.get_one::<u64>("every").copied();
+                if let (Some(profile_for), Some(every)) = (config.profile_for, config.every) {
+                    if profile_for >= every {
+                        eprintln!("--profile-for must be shorter than --every");
+                        std::process::exit(1);
+                    }
+                    if config.filename.as_deref() == Some("-") {
+                        eprintln!("--every can't be used when writing to stdout");
+                        std::process::exit(1);
+                    }
+                }
+                config.append_filename = matches.value_of("append").map(|f| f.to_owned());
+                if let Some(append) = config.append_filename.as_ref() {
+                    if matches.occurrences_of("format") == 0 {
+                        config.format = Some(FileFormat::capture);
+                    }
+                    if config.filename.is_none() {
+                        config.filename = Some(append.clone());
+                    }
+                }
+                config.upload_url = matches.value_of("upload_url").map(|f| f.to_owned());
+                config.pyroscope_url = matches.value_of("pyroscope").map(|f| f.to_owned());
+                config.otlp_endpoint = matches.value_of("otlp").map(|f| f.to_owned());
+                config.ssh = matches.value_of("ssh").map(|f| f.to_owned());
+                config.app_name = matches.value_of("app_name").map(|f| f.to_owned());
+                // the outputs that have somewhere to store where the profile came from
+                config.process_metadata = matches!(
+                    config.format,
+                    Some(FileFormat::capture)
+                        | Some(FileFormat::speedscope)
+                        | Some(FileFormat::pprof)
+                );
+                config.metadata_env.extend(
+                    matches
+                        .values_of("metadata_env")
+                        .into_iter()
+                        .flatten()
+                        .map(|name| name.to_owned()),
+                );
+                for tag in matches.values_of("tag").into_iter().flatten() {
+                    match tag.split_once('=') {
+                        Some((key, value)) => config.tags.push((key.to_owned(), value.to_owned())),
+                        None => {
+                            eprintln!("Invalid tag '{}', expected key=value", tag);
+                            std::process::exit(1);
+                        }
+                    }
+                }
+                // pyroscope expects profiles in 10 second windows
+                config.upload_period = match matches.value_of("upload_period") {
+                    Some(_) => matches.value_of_t("upload_period")?,
+                    None if config.pyroscope_url.is_some() => 10,
+                    None => 60,
+                };
+                if config.upload_period == 0 {
+                    eprintln!("--upload-period must be at least 1 second");
+                    std::process::exit(1);
+                }
             }
             "top" => {
                 config.sampling_rate = matches.value_of_t("rate")?;
+                config.adaptive_rate = matches.occurrences_of("adaptive_rate") > 0;
+                config.fixed_interval = matches.occurrences_of("fixed_interval") > 0;
+                config.clock = parse_clock(matches);
                 config.refresh_seconds = *matches.get_one::<f64>("delay").unwrap();
+                config.serve = matches.value_of("serve").map(|address| address.to_owned());
             }
             "dump" => {
                 config.dump_json = matches.occurrences_of("json") > 0;
                 config.dump_locals = matches.occurrences_of("locals");
-
-                #[cfg(target_os = "linux")]
+                config.args_only = matches.occurrences_of("args_only") > 0;
+                if matches.is_present("locals_depth") {
+                    config.locals_depth = Some(matches.value_of_t("locals_depth")?);
+                }
+                if matches.is_present("locals_max_items") {
+                    config.locals_max_items = Some(matches.value_of_t("locals_max_items")?);
+                }
+                if matches.is_present("locals_max_str_len") {
+                    config.locals_max_str_len = Some(matches.value_of_t("locals_max_str_len")?);
+                }
+                config.locals_output = matches.value_of("locals_output").map(|f| f.to_owned());
+                if config.args_only
+                    || config.locals_depth.is_some()
+                    || config.locals_max_items.is_some()
+                    || config.locals_max_str_len.is_some()
+                    || config.locals_output.is_some()
                 {
-                    config.core_filename = matches.value_of("core").map(|f| f.to_owned());
+                    config.dump_locals = config.dump_locals.max(1);
                 }
+
+                config.core_filename = matches.value_of("core").map(|f| f.to_owned());
+                config.core_exe = matches.value_of("exe").map(|f| f.to_owned());
+            }
+            "convert" => {
+                config.format = Some(matches.value_of_t("format")?);
+                config.filename = matches.value_of("output").map(|f| f.to_owned());
+                config.input_filename = matches.value_of("input").map(|f| f.to_owned());
+            }
+            "merge" => {
+                config.format = Some(matches.value_of_t("format")?);
+                config.filename = matches.value_of("output").map(|f| f.to_owned());
+                config.merge_filenames = matches
+                    .values_of("inputs")
+                    .into_iter()
+                    .flatten()
+                    .map(|f| f.to_owned())
+                    .collect();
+            }
+            "diff" => {
+                config.filename = matches.value_of("output").map(|f| f.to_owned());
+                config.baseline_filename = matches.value_of("before").map(|f| f.to_owned());
+                config.input_filename = matches.value_of("after").map(|f| f.to_owned());
+                config.normalize_diff = matches.occurrences_of("normalize") > 0;
+            }
+            "agent" => {
+                config.agent_listen = matches.value_of("listen").unwrap().to_owned();
+                config.agent_token = matches.value_of("token").map(|token| token.to_owned());
+                config.agent_dir = matches.value_of("dir").map(|dir| dir.to_owned());
             }
             "completions" => {
                 let shell = matches.get_one::<clap_complete::Shell>("shell").unwrap();
@@ -410,30 +1606,128 @@ impl Config {
             _ => {}
         }
 
+        match subcommand {
+            "record" | "convert" | "merge" => {
+                config.tag_threads = matches.occurrences_of("tag_threads") > 0;
+                config.weight = match matches.value_of("weight") {
+                    Some("wall") => Weight::Wall,
+                    _ => Weight::Samples,
+                };
+                config.granularity = match matches.value_of("granularity") {
+                    Some("line") => Granularity::Line,
+                    _ => Granularity::Function,
+                };
+                config.palette = matches.value_of("palette").unwrap_or("hot").to_owned();
+                config.reverse = matches.occurrences_of("reverse") > 0;
+                config.speedscope_evented = matches.occurrences_of("evented") > 0;
+                config.absolute_timestamps = matches.occurrences_of("absolute_timestamps") > 0;
+                config.stack_samples = matches.occurrences_of("stack_samples") > 0;
+                config.min_slice_duration = matches
+                    .get_one::<u64>("min_slice_duration")
+                    .copied()
+                    .unwrap_or(0);
+                config.thread_states = matches.occurrences_of("thread_states") > 0;
+                config.report_template = matches.value_of("report_template").map(|t| t.to_owned());
+                config.report_top = *matches.get_one::<usize>("report_top").unwrap();
+                // reports are meant to be read on the console, so default to writing to stdout
+                if config.format == Some(FileFormat::report) && config.filename.is_none() {
+                    config.filename = Some(String::from("-"));
+                }
+                if config.exceptions && config.filename.as_deref() == Some("-") {
+                    eprintln!("--exceptions can't be used when writing to stdout");
+                    std::process::exit(1);
+                }
+                config.compress = match matches.value_of("compress") {
+                    Some("gzip") => Some(Compression::Gzip),
+                    Some("zstd") => Some(Compression::Zstd),
+                    _ => None,
+                };
+                // pprof profiles are always gzipped, which is what 'go tool pprof' expects
+                if config.format == Some(FileFormat::pprof) {
+                    match config.compress {
+                        Some(Compression::Gzip) => config.compress = None,
+                        Some(Compression::Zstd) => {
+                            eprintln!("pprof profiles are already gzipped, and can't be compressed with zstd");
+                            std::process::exit(1);
+                        }
+                        None => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+
         match subcommand {
             "record" | "top" => {
                 config.python_program = matches
                     .values_of("python_program")
                     .map(|vals| vals.map(|v| v.to_owned()).collect());
                 config.gil_only = matches.occurrences_of("gil") > 0;
-                config.include_idle = matches.occurrences_of("idle") > 0;
+                config.offcpu = matches.occurrences_of("offcpu") > 0;
+                if config.offcpu {
+                    if cfg!(not(target_os = "linux")) {
+                        eprintln!("--offcpu is only supported on linux");
+                        std::process::exit(1);
+                    }
+                    if config.clock == Clock::Cpu {
+                        eprintln!("--offcpu can't be used with --clock cpu");
+                        std::process::exit(1);
+                    }
+                }
+                // off-cpu threads are all idle, and time spent importing counts even when it's
+                // spent waiting on the disk
+                config.include_idle =
+                    matches.occurrences_of("idle") > 0 || config.offcpu || config.importtime;
+                config.thread_roles = matches.occurrences_of("thread_roles") > 0;
+                config.all_processes = matches.occurrences_of("all") > 0;
+                config.metrics_address = matches.value_of("metrics").map(|a| a.to_owned());
             }
             _ => {}
         }
 
-        config.subprocesses = matches.occurrences_of("subprocesses") > 0;
         config.command = subcommand.to_owned();
 
+        // convert, merge, diff and agent don't attach to a process (the agent attaches to the
+        // processes it's asked to over its API), so none of the options below apply
+        if matches!(subcommand, "convert" | "merge" | "diff" | "agent") {
+            return Ok(config);
+        }
+
+        config.subprocesses = matches.occurrences_of("subprocesses") > 0;
+        config.asyncio = matches.occurrences_of("asyncio") > 0;
+        config.force_version = matches.get_one::<Version>("force_version").cloned();
+        config.offsets_filename = matches.value_of("offsets").map(|f| f.to_owned());
+
         // options that can be shared between subcommands
-        config.pid = matches
-            .value_of("pid")
-            .map(|p| p.parse().expect("invalid pid"));
+        config.pids = matches
+            .values_of("pid")
+            .map(|pids| pids.map(|p| p.parse().expect("invalid pid")).collect())
+            .unwrap_or_default();
+        #[allow(deprecated)]
+        {
+            config.pid = config.pids.first().copied();
+        }
+        config.pid_name = matches.get_one::<String>("pid_name").cloned();
+        config.all_matching = matches.occurrences_of("all_matching") > 0;
+        config.pid_file = matches.value_of("pid_file").map(|f| f.to_owned());
+        config.wait_for = matches.occurrences_of("wait_for") > 0;
+        config.container = matches.value_of("container").map(|c| c.to_owned());
+        config.k8s_pod = matches.value_of("k8s_pod").map(|p| p.to_owned());
+        config.k8s_container = matches.value_of("k8s_container").map(|c| c.to_owned());
+        if config.wait_for && config.pid_name.is_none() && config.pid_file.is_none() {
+            eprintln!("--wait-for needs the process to be given by --pid-name or --pid-file");
+            std::process::exit(1);
+        }
         config.full_filenames = matches.occurrences_of("full_filenames") > 0;
+        config.qualified_names = matches.occurrences_of("qualified_names") > 0;
         if cfg!(unwind) {
             config.native = matches.occurrences_of("native") > 0;
         }
 
-        config.capture_output = config.command != "record" || matches.occurrences_of("capture") > 0;
+        // when writing samples to stdout, don't let the output of the python program get mixed in
+        config.capture_output = config.command != "record"
+            || matches.occurrences_of("capture") > 0
+            || config.filename.as_deref() == Some("-");
         if !config.capture_output {
             config.hide_progress = true;
         }
@@ -445,6 +1739,10 @@ impl Config {
                 std::process::exit(1);
             }
             config.blocking = LockingStrategy::NonBlocking;
+            if config.max_pause.is_some() {
+                eprintln!("--max-pause-us can't be used with --nonblocking, which doesn't pause the process");
+                std::process::exit(1);
+            }
         }
 
         #[cfg(windows)]
@@ -461,7 +1759,7 @@ impl Config {
 
         #[cfg(target_os = "freebsd")]
         {
-            if config.pid.is_some() {
+            if !config.pids.is_empty() {
                 if std::env::var("PYSPY_ALLOW_FREEBSD_ATTACH").is_err() {
                     eprintln!("On FreeBSD, running py-spy can cause an exception in the profiled process if the process \
                         is calling 'socket.connect'.");
@@ -478,6 +1776,95 @@ impl Config {
     }
 }
 
+fn parse_clock(matches: &clap::ArgMatches) -> Clock {
+    match matches.value_of("clock") {
+        Some("cpu") => {
+            if cfg!(not(target_os = "linux")) {
+                eprintln!("--clock cpu is only supported on linux");
+                std::process::exit(1);
+            }
+            Clock::Cpu
+        }
+        _ => Clock::Wall,
+    }
+}
+
+/// Parses a duration (for --rotate-every, --profile-for or --every) like '30s', '10m' or '1h' into seconds. A plain number is
+/// taken to be in seconds
+fn parse_duration(value: &str) -> Result<u64, String> {
+    let (number, unit) = value.split_at(value.trim_end_matches(char::is_alphabetic).len());
+    let multiplier = match unit.to_lowercase().as_str() {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => {
+            return Err(format!(
+                "Unknown unit '{}', expected one of s, m, h or d",
+                unit
+            ))
+        }
+    };
+    match number.parse::<u64>() {
+        Ok(number) if number > 0 => Ok(number * multiplier),
+        _ => Err(format!("Invalid duration '{}'", value)),
+    }
+}
+
+/// Parses a --trigger condition like 'cpu>80%:30s'. The window can be left out, in which case
+/// recording starts as soon as the cpu usage goes over the threshold
+fn parse_trigger(value: &str) -> Result<Trigger, String> {
+    let invalid = || {
+        format!(
+            "Invalid trigger '{}', expected a condition like 'cpu>80%:30s'",
+            value
+        )
+    };
+    let condition = value.strip_prefix("cpu>").ok_or_else(invalid)?;
+    let (threshold, window) = match condition.split_once(':') {
+        Some((threshold, window)) => (threshold, parse_duration(window)?),
+        None => (condition, 1),
+    };
+    let threshold = threshold.strip_suffix('%').unwrap_or(threshold);
+    match threshold.parse::<f64>() {
+        Ok(cpu_percent) if cpu_percent >= 0.0 => Ok(Trigger {
+            cpu_percent,
+            window,
+        }),
+        _ => Err(invalid()),
+    }
+}
+
+/// Checks that a pattern given for an option that takes a regex is valid
+fn parse_regex(value: &str) -> Result<String, String> {
+    match regex::Regex::new(value) {
+        Ok(_) => Ok(value.to_owned()),
+        Err(e) => Err(format!("Invalid regex '{}': {}", value, e)),
+    }
+}
+
+/// Parses a --rotate-size size like '500MB' or '1GB' into bytes. A plain number is taken to be
+/// in bytes
+fn parse_rotate_size(value: &str) -> Result<u64, String> {
+    let (number, unit) = value.split_at(value.trim_end_matches(char::is_alphabetic).len());
+    let multiplier = match unit.to_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" => 1 << 10,
+        "M" | "MB" => 1 << 20,
+        "G" | "GB" => 1 << 30,
+        _ => {
+            return Err(format!(
+                "Unknown unit '{}', expected one of KB, MB or GB",
+                unit
+            ))
+        }
+    };
+    match number.parse::<u64>() {
+        Ok(number) if number > 0 => Ok(number * multiplier),
+        _ => Err(format!("Invalid size '{}'", value)),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -492,7 +1879,7 @@ mod tests {
     fn test_parse_record_args() {
         // basic use case
         let config = get_config("py-spy record --pid 1234 --output foo").unwrap();
-        assert_eq!(config.pid, Some(1234));
+        assert_eq!(config.pids, vec![1234]);
         assert_eq!(config.filename, Some(String::from("foo")));
         assert_eq!(config.format, Some(FileFormat::flamegraph));
         assert_eq!(config.command, String::from("record"));
@@ -501,6 +1888,14 @@ mod tests {
         let short_config = get_config("py-spy r -p 1234 -o foo").unwrap();
         assert_eq!(config, short_config);
 
+        // several processes can be recorded together
+        let config = get_config("py-spy record --pid 12 --pid 48 -p 99").unwrap();
+        assert_eq!(config.pids, vec![12, 48, 99]);
+        #[allow(deprecated)]
+        {
+            assert_eq!(config.pid, Some(12));
+        }
+
         // missing the --pid argument should fail
         assert_eq!(
             get_config("py-spy record -o foo").unwrap_err().kind,
@@ -513,7 +1908,7 @@ mod tests {
             program_config.python_program,
             Some(vec![String::from("python"), String::from("test.py")])
         );
-        assert_eq!(program_config.pid, None);
+        assert!(program_config.pids.is_empty());
 
         // passing an invalid file format should fail
         assert_eq!(
@@ -524,21 +1919,364 @@ mod tests {
         );
 
         // test out overriding these params by setting flags
-        assert_eq!(config.include_idle, false);
-        assert_eq!(config.gil_only, false);
-        assert_eq!(config.include_thread_ids, false);
+        assert!(!config.include_idle);
+        assert!(!config.gil_only);
+        assert!(!config.include_thread_ids);
 
         let config_flags = get_config("py-spy r -p 1234 -o foo --idle --gil --threads").unwrap();
-        assert_eq!(config_flags.include_idle, true);
-        assert_eq!(config_flags.gil_only, true);
-        assert_eq!(config_flags.include_thread_ids, true);
+        assert!(config_flags.include_idle);
+        assert!(config_flags.gil_only);
+        assert!(config_flags.include_thread_ids);
+    }
+
+    #[test]
+    fn test_parse_split_threads_args() {
+        let config = get_config("py-spy r -p 1234 -f speedscope --split-threads").unwrap();
+        assert!(config.split_threads);
+        assert!(!get_config("py-spy r -p 1234").unwrap().split_threads);
+        assert_eq!(
+            get_config("py-spy r -p 1234 --split-threads --upload-url http://localhost:8126")
+                .unwrap_err()
+                .kind,
+            clap::ErrorKind::ArgumentConflict
+        );
+    }
+
+    #[test]
+    fn test_parse_split_processes_args() {
+        let config = get_config("py-spy r -p 1234 --subprocesses --split-processes").unwrap();
+        assert_eq!(config.split_processes, Some(String::new()));
+        let config =
+            get_config("py-spy r -p 1234 -s --split-processes=worker-{pid}.svg -o all.svg")
+                .unwrap();
+        assert_eq!(
+            config.split_processes,
+            Some(String::from("worker-{pid}.svg"))
+        );
+        assert_eq!(config.filename, Some(String::from("all.svg")));
+        assert_eq!(
+            get_config("py-spy r -p 1234 --split-processes")
+                .unwrap_err()
+                .kind,
+            clap::ErrorKind::MissingRequiredArgument
+        );
+    }
+
+    #[test]
+    fn test_parse_adaptive_rate_args() {
+        let config = get_config("py-spy r -p 1234 --rate 1000 --adaptive-rate").unwrap();
+        assert!(config.adaptive_rate);
+        assert_eq!(config.sampling_rate, 1000);
+        assert!(!get_config("py-spy r -p 1234").unwrap().adaptive_rate);
+    }
+
+    #[test]
+    fn test_parse_fixed_interval_args() {
+        assert!(
+            get_config("py-spy r -p 1234 --fixed-interval")
+                .unwrap()
+                .fixed_interval
+        );
+        assert!(!get_config("py-spy r -p 1234").unwrap().fixed_interval);
+    }
+
+    #[test]
+    fn test_parse_clock_args() {
+        assert_eq!(get_config("py-spy r -p 1234").unwrap().clock, Clock::Wall);
+        #[cfg(target_os = "linux")]
+        assert_eq!(
+            get_config("py-spy r -p 1234 --clock cpu").unwrap().clock,
+            Clock::Cpu
+        );
+    }
+
+    #[test]
+    fn test_parse_offcpu_args() {
+        #[cfg(target_os = "linux")]
+        {
+            let config = get_config("py-spy r -p 1234 --offcpu").unwrap();
+            assert!(config.offcpu);
+            assert!(config.include_idle);
+        }
+        assert!(!get_config("py-spy r -p 1234").unwrap().offcpu);
+        assert_eq!(
+            get_config("py-spy r -p 1234 --offcpu --gil")
+                .unwrap_err()
+                .kind,
+            clap::ErrorKind::ArgumentConflict
+        );
+    }
+
+    #[test]
+    fn test_parse_lock_contention_args() {
+        assert!(
+            get_config("py-spy r -p 1234 --lock-contention")
+                .unwrap()
+                .lock_contention
+        );
+        assert!(!get_config("py-spy r -p 1234").unwrap().lock_contention);
+    }
+
+    #[test]
+    fn test_parse_exceptions_args() {
+        assert!(
+            get_config("py-spy r -p 1234 --exceptions")
+                .unwrap()
+                .exceptions
+        );
+        assert!(!get_config("py-spy r -p 1234").unwrap().exceptions);
+    }
+
+    #[test]
+    fn test_parse_memory_args() {
+        assert!(
+            get_config("py-spy r -p 1234 --memory -f collapsed")
+                .unwrap()
+                .memory
+        );
+        assert!(!get_config("py-spy r -p 1234").unwrap().memory);
+        assert_eq!(
+            get_config("py-spy r -p 1234 --memory --native")
+                .unwrap_err()
+                .kind,
+            clap::ErrorKind::ArgumentConflict
+        );
+    }
+
+    #[test]
+    fn test_parse_memory_counters_args() {
+        let config = get_config("py-spy r -p 1234 --memory-counters").unwrap();
+        assert_eq!(config.memory_counters, Some(1));
+        let config = get_config("py-spy r -p 1234 --memory-counters=1m").unwrap();
+        assert_eq!(config.memory_counters, Some(60));
+        assert_eq!(
+            get_config("py-spy r -p 1234").unwrap().memory_counters,
+            None
+        );
+    }
+
+    #[test]
+    fn test_parse_trigger_args() {
+        #[cfg(target_os = "linux")]
+        {
+            let config = get_config("py-spy r -p 1234 --trigger cpu>80%:30s -d 60").unwrap();
+            assert_eq!(
+                config.trigger,
+                Some(Trigger {
+                    cpu_percent: 80.0,
+                    window: 30
+                })
+            );
+        }
+        assert_eq!(
+            parse_trigger("cpu>150%"),
+            Ok(Trigger {
+                cpu_percent: 150.0,
+                window: 1
+            })
+        );
+        assert_eq!(parse_trigger("cpu>12.5:1m").unwrap().window, 60);
+        assert!(parse_trigger("mem>80%:30s").is_err());
+        assert!(parse_trigger("cpu>lots:30s").is_err());
+        assert!(parse_trigger("cpu>80%:soon").is_err());
+        assert_eq!(get_config("py-spy r -p 1234").unwrap().trigger, None);
+    }
+
+    #[test]
+    fn test_parse_max_pause_args() {
+        let config = get_config("py-spy r -p 1234 --max-pause-us 500").unwrap();
+        assert_eq!(config.max_pause, Some(Duration::from_micros(500)));
+        assert_eq!(
+            get_config("py-spy r -p 1234 --max-pause-us 0")
+                .unwrap_err()
+                .kind,
+            clap::ErrorKind::ValueValidation
+        );
+    }
+
+    #[test]
+    fn test_parse_asyncio_args() {
+        assert!(get_config("py-spy dump -p 1234 --asyncio").unwrap().asyncio);
+        assert!(!get_config("py-spy dump -p 1234").unwrap().asyncio);
+    }
+
+    #[test]
+    fn test_parse_qualified_names_args() {
+        assert!(
+            get_config("py-spy r -p 1234 --qualified-names")
+                .unwrap()
+                .qualified_names
+        );
+        assert!(!get_config("py-spy r -p 1234").unwrap().qualified_names);
+    }
+
+    #[test]
+    fn test_parse_args_only_args() {
+        let config = get_config("py-spy dump -p 1234 --args-only").unwrap();
+        assert!(config.args_only);
+        assert_eq!(config.dump_locals, 1);
+        let config = get_config("py-spy dump -p 1234 --args-only -ll").unwrap();
+        assert_eq!(config.dump_locals, 2);
+        assert!(!get_config("py-spy dump -p 1234").unwrap().args_only);
+    }
+
+    #[test]
+    fn test_parse_locals_depth_args() {
+        let config =
+            get_config("py-spy dump -p 1234 --locals-depth 3 --locals-max-items 20").unwrap();
+        assert_eq!(config.locals_depth, Some(3));
+        assert_eq!(config.locals_max_items, Some(20));
+        assert_eq!(config.dump_locals, 1);
+        assert_eq!(
+            get_config("py-spy dump -p 1234").unwrap().locals_depth,
+            None
+        );
+        assert!(get_config("py-spy dump -p 1234 --locals-depth deep").is_err());
+    }
+
+    #[test]
+    fn test_parse_locals_output_args() {
+        let config =
+            get_config("py-spy dump -p 1234 --locals-max-str-len 1000 --locals-output locals.json")
+                .unwrap();
+        assert_eq!(config.locals_max_str_len, Some(1000));
+        assert_eq!(config.locals_output, Some(String::from("locals.json")));
+        assert_eq!(config.dump_locals, 1);
+    }
+
+    #[test]
+    fn test_parse_format_args() {
+        let config = get_config("py-spy r -p 1234 -f capture").unwrap();
+        assert_eq!(config.format, Some(FileFormat::capture));
+        // 'raw' still writes collapsed stacks, like it did before the capture format was added
+        let config = get_config("py-spy r -p 1234 -f raw").unwrap();
+        assert_eq!(config.format, Some(FileFormat::collapsed));
+        assert_eq!("raw".parse::<FileFormat>(), Ok(FileFormat::collapsed));
+    }
+
+    #[test]
+    fn test_parse_rotate_args() {
+        let config = get_config("py-spy r -p 1234 -f capture --rotate-every 10m").unwrap();
+        assert_eq!(config.rotate_every, Some(600));
+        assert_eq!(config.rotate_size, None);
+        let config = get_config("py-spy r -p 1234 -f sqlite --rotate-size 500MB").unwrap();
+        assert_eq!(config.rotate_size, Some(500 * 1024 * 1024));
+        assert_eq!(
+            get_config("py-spy r -p 1234 --rotate-every 10x")
+                .unwrap_err()
+                .kind,
+            clap::ErrorKind::ValueValidation
+        );
+        assert_eq!(
+            get_config("py-spy r -p 1234 --rotate-every 1h --split-threads")
+                .unwrap_err()
+                .kind,
+            clap::ErrorKind::ArgumentConflict
+        );
+
+        assert_eq!(parse_duration("30"), Ok(30));
+        assert_eq!(parse_duration("1h"), Ok(3600));
+        assert!(parse_duration("0s").is_err());
+        assert!(parse_duration("m").is_err());
+        assert_eq!(parse_rotate_size("1024"), Ok(1024));
+        assert_eq!(parse_rotate_size("2kb"), Ok(2048));
+        assert_eq!(parse_rotate_size("1G"), Ok(1 << 30));
+        assert!(parse_rotate_size("1TB").is_err());
+    }
+
+    #[test]
+    fn test_parse_profile_for_args() {
+        let config =
+            get_config("py-spy r -p 1234 -f capture --profile-for 30s --every 10m").unwrap();
+        assert_eq!(config.profile_for, Some(30));
+        assert_eq!(config.every, Some(600));
+        assert_eq!(
+            get_config("py-spy r -p 1234 --every 10m").unwrap_err().kind,
+            clap::ErrorKind::MissingRequiredArgument
+        );
+        assert_eq!(
+            get_config("py-spy r -p 1234 --profile-for 30s --every 10m --rotate-every 1h")
+                .unwrap_err()
+                .kind,
+            clap::ErrorKind::ArgumentConflict
+        );
+    }
+
+    #[test]
+    fn test_parse_checkpoint_args() {
+        let config = get_config("py-spy r -p 1234 --checkpoint-every 1m").unwrap();
+        assert_eq!(config.checkpoint_every, Some(60));
+        assert_eq!(
+            get_config("py-spy r -p 1234 --checkpoint-every 1m --upload-url http://localhost")
+                .unwrap_err()
+                .kind,
+            clap::ErrorKind::ArgumentConflict
+        );
+    }
+
+    #[test]
+    fn test_parse_function_trigger_args() {
+        let config = get_config(
+            "py-spy r -p 1234 --start-on-function handlers.checkout --stop-on-function ^shutdown$",
+        )
+        .unwrap();
+        assert_eq!(
+            config.start_on_function,
+            Some(String::from("handlers.checkout"))
+        );
+        assert_eq!(config.stop_on_function, Some(String::from("^shutdown$")));
+        assert_eq!(
+            get_config("py-spy r -p 1234 --stop-on-function (")
+                .unwrap_err()
+                .kind,
+            clap::ErrorKind::ValueValidation
+        );
+    }
+
+    #[test]
+    fn test_parse_threads_include_args() {
+        let config =
+            get_config("py-spy r -p 1234 --threads-include ^worker --threads-exclude heartbeat")
+                .unwrap();
+        assert_eq!(config.threads_include, Some(String::from("^worker")));
+        assert_eq!(config.threads_exclude, Some(String::from("heartbeat")));
+    }
+
+    #[test]
+    fn test_parse_importtime_args() {
+        let config = get_config("py-spy r --importtime -- python app.py").unwrap();
+        assert!(config.importtime);
+        assert!(config.include_idle);
+        assert_eq!(config.sampling_rate, 1000);
+        let config = get_config("py-spy r --importtime -r 500 -- python app.py").unwrap();
+        assert_eq!(config.sampling_rate, 500);
+        assert!(get_config("py-spy r -p 1234 --importtime").is_err());
+    }
+
+    #[test]
+    fn test_parse_thread_roles_args() {
+        assert!(
+            get_config("py-spy top -p 1234 --thread-roles")
+                .unwrap()
+                .thread_roles
+        );
+        assert!(!get_config("py-spy top -p 1234").unwrap().thread_roles);
+    }
+
+    #[test]
+    fn test_parse_metadata_env_args() {
+        let config = get_config("py-spy r -p 1234 -f speedscope --metadata-env MYAPP_*").unwrap();
+        assert!(config.process_metadata);
+        assert!(config.metadata_env.contains(&String::from("GIT_SHA")));
+        assert!(config.metadata_env.contains(&String::from("MYAPP_*")));
+        assert!(!get_config("py-spy r -p 1234").unwrap().process_metadata);
     }
 
     #[test]
     fn test_parse_dump_args() {
         // basic use case
         let config = get_config("py-spy dump --pid 1234").unwrap();
-        assert_eq!(config.pid, Some(1234));
+        assert_eq!(config.pids, vec![1234]);
         assert_eq!(config.command, String::from("dump"));
 
         // short version
@@ -550,13 +2288,113 @@ mod tests {
             get_config("py-spy dump").unwrap_err().kind,
             clap::ErrorKind::MissingRequiredArgument
         );
+
+        let config = get_config("py-spy dump --core core.1234 --exe /usr/bin/python3").unwrap();
+        assert_eq!(config.core_filename, Some(String::from("core.1234")));
+        assert_eq!(config.core_exe, Some(String::from("/usr/bin/python3")));
+        assert!(get_config("py-spy dump -p 1234 --exe /usr/bin/python3").is_err());
+        let config = get_config("py-spy dump --core app.dmp").unwrap();
+        assert_eq!(config.core_filename, Some(String::from("app.dmp")));
+
+        let config =
+            get_config("py-spy dump -p 1234 --python-version 3.9.18 --offsets offsets.toml")
+                .unwrap();
+        assert_eq!(config.force_version, "3.9.18".parse().ok());
+        assert_eq!(config.offsets_filename, Some(String::from("offsets.toml")));
+        assert_eq!(
+            get_config("py-spy dump -p 1234 --force-version 3")
+                .unwrap_err()
+                .kind,
+            clap::ErrorKind::ValueValidation
+        );
+    }
+
+    #[test]
+    fn test_parse_otlp_args() {
+        let config = get_config("py-spy record --pid 1234 --otlp http://localhost:4318 --app-name myservice --tag host.name=web-1").unwrap();
+        assert_eq!(
+            config.otlp_endpoint,
+            Some(String::from("http://localhost:4318"))
+        );
+        assert_eq!(config.upload_period, 60);
+        assert_eq!(
+            get_config("py-spy record --pid 1234 --otlp http://localhost:4318 --upload-url http://localhost:8126")
+                .unwrap_err()
+                .kind,
+            clap::ErrorKind::ArgumentConflict
+        );
+    }
+
+    #[test]
+    fn test_parse_pid_name_args() {
+        let config = get_config("py-spy dump --pid-name gunicorn:.worker --all-matching").unwrap();
+        assert!(config.pids.is_empty());
+        assert_eq!(config.pid_name, Some(String::from("gunicorn:.worker")));
+        assert!(config.all_matching);
+        assert!(get_config("py-spy record --pid-name gunicorn -p 1234").is_err());
+        assert!(get_config("py-spy top --all-matching -p 1234").is_err());
+        assert!(get_config("py-spy top --pid-name (unclosed").is_err());
+    }
+
+    #[test]
+    fn test_parse_pid_file_args() {
+        let config = get_config("py-spy record --pid-file /run/app.pid --wait-for").unwrap();
+        assert_eq!(config.pid_file, Some(String::from("/run/app.pid")));
+        assert!(config.wait_for);
+        assert!(get_config("py-spy dump --pid-file app.pid -p 1234").is_err());
+    }
+
+    #[test]
+    fn test_parse_all_args() {
+        let config = get_config("py-spy top --all").unwrap();
+        assert!(config.all_processes);
+        assert!(config.pids.is_empty());
+        assert!(get_config("py-spy record --all -p 1234").is_err());
+        assert!(get_config("py-spy record --all -- python app.py").is_err());
+        assert!(get_config("py-spy dump --all").is_err());
+    }
+
+    #[test]
+    fn test_parse_ssh_args() {
+        let config = get_config("py-spy record --ssh app@web-1 -p 1234 -f speedscope").unwrap();
+        assert_eq!(config.ssh, Some(String::from("app@web-1")));
+        assert_eq!(config.pids, vec![1234]);
+        assert!(get_config("py-spy record --ssh web-1 -- python app.py").is_err());
+        assert!(get_config("py-spy record --ssh web-1 -p 1234 --split-threads").is_err());
+        assert!(get_config("py-spy record --ssh web-1 -p 1234 --exceptions").is_err());
+        assert!(get_config("py-spy record --ssh web-1 -p 1234 --memory").is_err());
+        assert!(!config.stream_stats);
+        assert!(
+            get_config("py-spy record -p 1234 -f jsonl -o - --stream-stats")
+                .unwrap()
+                .stream_stats
+        );
+    }
+
+    #[test]
+    fn test_parse_container_args() {
+        let config = get_config("py-spy dump --container web").unwrap();
+        assert_eq!(config.container, Some(String::from("web")));
+        assert!(config.pids.is_empty());
+        assert!(get_config("py-spy record --container web -p 1234").is_err());
+        assert!(get_config("py-spy top --container web --all").is_err());
+    }
+
+    #[test]
+    fn test_parse_k8s_args() {
+        let config =
+            get_config("py-spy record --k8s-pod payments/api --k8s-container app").unwrap();
+        assert_eq!(config.k8s_pod, Some(String::from("payments/api")));
+        assert_eq!(config.k8s_container, Some(String::from("app")));
+        assert!(get_config("py-spy dump --k8s-container app -p 1234").is_err());
+        assert!(get_config("py-spy dump --k8s-pod api --container web").is_err());
     }
 
     #[test]
     fn test_parse_top_args() {
         // basic use case
         let config = get_config("py-spy top --pid 1234").unwrap();
-        assert_eq!(config.pid, Some(1234));
+        assert_eq!(config.pids, vec![1234]);
         assert_eq!(config.command, String::from("top"));
 
         // short version
@@ -564,6 +2402,208 @@ mod tests {
         assert_eq!(config, short_config);
     }
 
+    #[test]
+    fn test_parse_upload_args() {
+        let config = get_config("py-spy record --pid 1234 --upload-url http://localhost:8126/profiling/v1/input --upload-period 10").unwrap();
+        assert_eq!(
+            config.upload_url,
+            Some(String::from("http://localhost:8126/profiling/v1/input"))
+        );
+        assert_eq!(config.upload_period, 10);
+
+        let config = get_config("py-spy record --pid 1234").unwrap();
+        assert_eq!(config.upload_url, None);
+        assert_eq!(config.upload_period, 60);
+
+        let config = get_config("py-spy record --pid 1234 --pyroscope http://localhost:4040 --app-name myservice --tag env=prod --tag host=a").unwrap();
+        assert_eq!(
+            config.pyroscope_url,
+            Some(String::from("http://localhost:4040"))
+        );
+        assert_eq!(config.app_name, Some(String::from("myservice")));
+        assert_eq!(
+            config.tags,
+            vec![
+                (String::from("env"), String::from("prod")),
+                (String::from("host"), String::from("a"))
+            ]
+        );
+        assert_eq!(config.upload_period, 10);
+
+        // pyroscope needs an application name
+        assert_eq!(
+            get_config("py-spy record --pid 1234 --pyroscope http://localhost:4040")
+                .unwrap_err()
+                .kind,
+            clap::ErrorKind::MissingRequiredArgument
+        );
+    }
+
+    #[test]
+    fn test_parse_convert_args() {
+        let config =
+            get_config("py-spy convert profile.capture -f speedscope -o out.json").unwrap();
+        assert_eq!(config.command, String::from("convert"));
+        assert_eq!(config.input_filename, Some(String::from("profile.capture")));
+        assert_eq!(config.filename, Some(String::from("out.json")));
+        assert_eq!(config.format, Some(FileFormat::speedscope));
+        assert!(!config.tag_threads);
+        assert_eq!(config.weight, Weight::Samples);
+
+        // reports are written to stdout unless given an output file
+        let config = get_config("py-spy convert profile.capture -f report --report-top 5").unwrap();
+        assert_eq!(config.filename, Some(String::from("-")));
+        assert_eq!(config.report_top, 5);
+
+        let config = get_config("py-spy record -p 1234 --granularity line").unwrap();
+        assert_eq!(config.granularity, Granularity::Line);
+
+        let config =
+            get_config("py-spy convert profile.capture -f collapsed --tag-threads --weight wall")
+                .unwrap();
+        assert!(config.tag_threads);
+        assert_eq!(config.weight, Weight::Wall);
+        assert_eq!(config.granularity, Granularity::Function);
+        assert_eq!(config.compress, None);
+        assert_eq!(config.palette, "hot");
+
+        let config =
+            get_config("py-spy convert profile.capture -f chrometrace --compress zstd").unwrap();
+        assert_eq!(config.compress, Some(Compression::Zstd));
+        assert!(!config.absolute_timestamps);
+        let config =
+            get_config("py-spy convert profile.capture -f chrometrace --absolute-timestamps")
+                .unwrap();
+        assert!(config.absolute_timestamps);
+        assert!(!config.stack_samples);
+        let config =
+            get_config("py-spy convert profile.capture -f chrometrace --stack-samples").unwrap();
+        assert!(config.stack_samples);
+        assert_eq!(config.min_slice_duration, 0);
+        let config =
+            get_config("py-spy convert profile.capture -f chrometrace --min-slice-duration 1000")
+                .unwrap();
+        assert_eq!(config.min_slice_duration, 1000);
+        assert!(!config.thread_states);
+        let config =
+            get_config("py-spy convert profile.capture -f chrometrace --thread-states").unwrap();
+        assert!(config.thread_states);
+
+        let config = get_config("py-spy convert profile.capture --palette package").unwrap();
+        assert_eq!(config.palette, "package");
+        assert!(!config.reverse);
+        let config = get_config("py-spy convert profile.capture --reverse").unwrap();
+        assert!(config.reverse);
+        let config = get_config("py-spy convert profile.capture -f speedscope --evented").unwrap();
+        assert!(config.speedscope_evented);
+        assert_eq!(
+            get_config("py-spy convert profile.capture --palette rainbow")
+                .unwrap_err()
+                .kind,
+            clap::ErrorKind::InvalidValue
+        );
+
+        // missing the input file should fail
+        assert_eq!(
+            get_config("py-spy convert -f speedscope").unwrap_err().kind,
+            clap::ErrorKind::MissingRequiredArgument
+        );
+    }
+
+    #[test]
+    fn test_parse_merge_args() {
+        let config = get_config("py-spy merge a.capture b.capture c.capture -o fleet.svg").unwrap();
+        assert_eq!(config.command, String::from("merge"));
+        assert_eq!(
+            config.merge_filenames,
+            vec!["a.capture", "b.capture", "c.capture"]
+        );
+        assert_eq!(config.filename, Some(String::from("fleet.svg")));
+        assert_eq!(config.format, Some(FileFormat::flamegraph));
+
+        let config =
+            get_config("py-spy merge a.capture b.capture -f speedscope --evented").unwrap();
+        assert_eq!(config.format, Some(FileFormat::speedscope));
+        assert!(config.speedscope_evented);
+
+        assert_eq!(
+            get_config("py-spy merge -o fleet.svg").unwrap_err().kind,
+            clap::ErrorKind::MissingRequiredArgument
+        );
+
+        // appending keeps on writing to the same capture file by default
+        let config = get_config("py-spy record -p 1234 --append profile.capture").unwrap();
+        assert_eq!(
+            config.append_filename,
+            Some(String::from("profile.capture"))
+        );
+        assert_eq!(config.filename, Some(String::from("profile.capture")));
+        assert_eq!(config.format, Some(FileFormat::capture));
+        let config =
+            get_config("py-spy record -p 1234 --append profile.capture -o out.svg -f flamegraph")
+                .unwrap();
+        assert_eq!(config.filename, Some(String::from("out.svg")));
+        assert_eq!(config.format, Some(FileFormat::flamegraph));
+    }
+
+    #[test]
+    fn test_parse_diff_args() {
+        let config = get_config("py-spy diff before.txt after.capture -n -o diff.svg").unwrap();
+        assert_eq!(config.command, String::from("diff"));
+        assert_eq!(config.baseline_filename, Some(String::from("before.txt")));
+        assert_eq!(config.input_filename, Some(String::from("after.capture")));
+        assert_eq!(config.filename, Some(String::from("diff.svg")));
+        assert!(config.normalize_diff);
+
+        // needs both a before and an after profile
+        assert_eq!(
+            get_config("py-spy diff before.txt").unwrap_err().kind,
+            clap::ErrorKind::MissingRequiredArgument
+        );
+    }
+
+    #[test]
+    fn test_parse_top_serve_args() {
+        let config = get_config("py-spy top --pid 1234").unwrap();
+        assert_eq!(config.serve, None);
+        let config = get_config("py-spy top --pid 1234 --serve :8080").unwrap();
+        assert_eq!(config.serve, Some(String::from(":8080")));
+    }
+
+    #[test]
+    fn test_parse_metrics_args() {
+        let config = get_config("py-spy record --pid 1234 --metrics :9090").unwrap();
+        assert_eq!(config.metrics_address, Some(String::from(":9090")));
+        let config = get_config("py-spy top --pid 1234 --metrics 127.0.0.1:9090").unwrap();
+        assert_eq!(config.metrics_address, Some(String::from("127.0.0.1:9090")));
+        assert_eq!(
+            get_config("py-spy top --pid 1234").unwrap().metrics_address,
+            None
+        );
+        // the metrics would be of py-spy on the remote host
+        assert_eq!(
+            get_config("py-spy record --pid 1234 --ssh web-1 --metrics :9090")
+                .unwrap_err()
+                .kind,
+            clap::ErrorKind::ArgumentConflict
+        );
+    }
+
+    #[test]
+    fn test_parse_agent_args() {
+        let config = get_config("py-spy agent").unwrap();
+        assert_eq!(config.command, String::from("agent"));
+        assert_eq!(config.agent_listen, "127.0.0.1:7777");
+        assert_eq!(config.agent_token, None);
+
+        let config =
+            get_config("py-spy agent --listen 0.0.0.0:8000 --token secret --dir /tmp/profiles")
+                .unwrap();
+        assert_eq!(config.agent_listen, "0.0.0.0:8000");
+        assert_eq!(config.agent_token, Some(String::from("secret")));
+        assert_eq!(config.agent_dir, Some(String::from("/tmp/profiles")));
+    }
+
     #[test]
     fn test_parse_args() {
         assert_eq!(
@@ -7,9 +7,11 @@ use std::vec::Vec;
 
 use anyhow::Error;
 use console::{style, Term};
+use remoteprocess::Pid;
 
 use crate::config::Config;
 use crate::stack_trace::{Frame, StackTrace};
+use crate::thread_lifetimes::ThreadLifetimes;
 use crate::version::Version;
 
 pub struct ConsoleViewer {
@@ -22,6 +24,8 @@ pub struct ConsoleViewer {
     options: Arc<Mutex<Options>>,
     stats: Stats,
     subprocesses: bool,
+    // the threads that have started and exited since the viewer was opened
+    thread_lifetimes: Option<ThreadLifetimes>,
     config: Config,
 }
 
@@ -43,7 +47,7 @@ impl ConsoleViewer {
             while input_running.load(atomic::Ordering::Relaxed) {
                 // TODO: there isn't a non-blocking version of stdin, so this will capture the
                 // next keystroke after the ConsoleViewer object has been destroyed =(
-                if let Some(Ok(key)) = std::io::stdin().bytes().next() {
+                if let Some(Ok(key)) = std::io::stdin().lock().bytes().next() {
                     let mut options = input_options.lock().unwrap();
                     options.dirty = true;
                     let previous_usage = options.usage;
@@ -73,6 +77,7 @@ impl ConsoleViewer {
             sampling_rate,
             subprocesses: config.subprocesses,
             stats: Stats::new(),
+            thread_lifetimes: ThreadLifetimes::from_config(config),
             config: config.clone(),
         })
     }
@@ -89,7 +94,7 @@ impl ConsoleViewer {
                 last_pid = Some(trace.pid);
             }
 
-            if !(self.config.include_idle || trace.active) {
+            if !(self.config.include_idle || trace.active || trace.task_name.is_some()) {
                 continue;
             }
 
@@ -129,6 +134,14 @@ impl ConsoleViewer {
         Ok(())
     }
 
+    /// Keeps track of the threads starting and exiting, from all the traces of a sample. The
+    /// processes in 'failed' couldn't be sampled
+    pub fn update_threads(&mut self, traces: &[StackTrace], failed: &[Pid]) {
+        if let Some(thread_lifetimes) = self.thread_lifetimes.as_mut() {
+            thread_lifetimes.update(traces, failed);
+        }
+    }
+
     pub fn display(&self) -> std::io::Result<()> {
         // Get the top aggregate function calls (either by line or by function as )
         let mut options = self.options.lock().unwrap();
@@ -143,10 +156,10 @@ impl ConsoleViewer {
 
         // TODO: subsort ?
         match options.sort_column {
-            1 => counts.sort_unstable_by(|a, b| b.0.current_own.cmp(&a.0.current_own)),
-            2 => counts.sort_unstable_by(|a, b| b.0.current_total.cmp(&a.0.current_total)),
-            3 => counts.sort_unstable_by(|a, b| b.0.overall_own.cmp(&a.0.overall_own)),
-            4 => counts.sort_unstable_by(|a, b| b.0.overall_total.cmp(&a.0.overall_total)),
+            1 => counts.sort_unstable_by_key(|a| std::cmp::Reverse(a.0.current_own)),
+            2 => counts.sort_unstable_by_key(|a| std::cmp::Reverse(a.0.current_total)),
+            3 => counts.sort_unstable_by_key(|a| std::cmp::Reverse(a.0.overall_own)),
+            4 => counts.sort_unstable_by_key(|a| std::cmp::Reverse(a.0.overall_total)),
             _ => panic!("unknown sort column. this really shouldn't happen"),
         }
         let term = Term::stdout();
@@ -205,11 +218,20 @@ impl ConsoleViewer {
             out!("Total Samples {}", style(self.stats.overall_samples).bold());
         }
 
+        let thread_churn = match self.thread_lifetimes.as_ref() {
+            Some(lifetimes) if lifetimes.started() > 0 || lifetimes.exited() > 0 => format!(
+                " ({} started, {} exited)",
+                lifetimes.started(),
+                lifetimes.exited()
+            ),
+            _ => "".to_owned(),
+        };
         out!(
-            "GIL: {:.2}%, Active: {:>.2}%, Threads: {}{}",
+            "GIL: {:.2}%, Active: {:>.2}%, Threads: {}{}{}",
             style(100.0 * self.stats.gil as f64 / self.stats.current_samples as f64).bold(),
             style(100.0 * self.stats.active as f64 / self.stats.current_samples as f64).bold(),
             style(self.stats.threads).bold(),
+            thread_churn,
             if self.subprocesses {
                 format!(", Processes {}", style(self.stats.processes).bold())
             } else {
@@ -376,14 +398,16 @@ impl Drop for ConsoleViewer {
 }
 
 #[derive(Eq, PartialEq, Ord, PartialOrd, Debug)]
-struct FunctionStatistics {
-    current_own: u64,
-    current_total: u64,
-    overall_own: u64,
-    overall_total: u64,
+pub struct FunctionStatistics {
+    pub current_own: u64,
+    pub current_total: u64,
+    pub overall_own: u64,
+    pub overall_total: u64,
 }
 
-fn update_function_statistics<K>(
+/// Counts the samples each function was running in (own) and on the stack for (total), both
+/// since the last refresh and overall. This is shared with the live web view ('top --serve')
+pub fn update_function_statistics<K>(
     counts: &mut HashMap<String, FunctionStatistics>,
     trace: &StackTrace,
     key_func: K,
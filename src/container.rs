@@ -0,0 +1,215 @@
+use std::path::Path;
+
+use anyhow::{Context, Error};
+use lazy_static::lazy_static;
+use regex::Regex;
+use remoteprocess::Pid;
+
+use crate::python_process_info::is_python_process;
+
+/*
+ * This file contains code for '--container', which finds the python processes running in a
+ * docker (or containerd, podman or kubernetes) container, from the container's name or id.
+ *
+ * The processes in a container are found by scanning the cgroup of every process, which has the
+ * id of the container it's in, so this doesn't need to talk to the docker daemon. Names are looked
+ * up in the config files docker keeps for each container. The processes are profiled by their pid
+ * on the host, since py-spy already reads the files of processes in containers through
+ * /proc/<pid>/root, and the pid each process has inside the container is shown when attaching so
+ * that it can be matched up with logs and tools run inside the container.
+ */
+
+/// Where docker keeps the config of each container, which has its name
+const DOCKER_CONTAINERS: &str = "/var/lib/docker/containers";
+
+/// A process running in a container, with its pid on the host and inside the container
+pub struct ContainerProcess {
+    pub pid: Pid,
+    pub container_pid: Option<Pid>,
+}
+
+/// Finds the python processes in the container with a name or id (or a prefix of an id, like
+/// 'docker ps' shows), ordered by pid
+pub fn find_container_processes(name_or_id: &str) -> Result<Vec<ContainerProcess>, Error> {
+    let cgroups = list_cgroups()?;
+    let id = resolve_container(name_or_id, cgroups.iter().map(|(_, id)| id.as_str()))?;
+    let mut processes: Vec<ContainerProcess> = cgroups
+        .iter()
+        .filter(|(_, container)| *container == id)
+        .filter(|(pid, _)| is_python_process(*pid))
+        .map(|(pid, _)| ContainerProcess {
+            pid: *pid,
+            container_pid: std::fs::read_to_string(format!("/proc/{}/status", pid))
+                .ok()
+                .and_then(|status| namespace_pid(&status)),
+        })
+        .collect();
+    processes.sort_by_key(|process| process.pid);
+    Ok(processes)
+}
+
+/// Describes the processes found in a container
+pub fn describe_processes(processes: &[ContainerProcess]) -> String {
+    processes
+        .iter()
+        .map(|process| match process.container_pid {
+            Some(container_pid) => format!(
+                "  {:>8}  (pid {} in the container)",
+                process.pid, container_pid
+            ),
+            None => format!("  {:>8}", process.pid),
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Lists the id of the container each process is in, for processes that are in one
+#[cfg(target_os = "linux")]
+fn list_cgroups() -> Result<Vec<(Pid, String)>, Error> {
+    let entries = std::fs::read_dir("/proc").context("Failed to list processes")?;
+    Ok(entries
+        .filter_map(|entry| {
+            let pid: Pid = entry.ok()?.file_name().to_str()?.parse().ok()?;
+            // processes can exit while we're listing them
+            let cgroup = std::fs::read_to_string(format!("/proc/{}/cgroup", pid)).ok()?;
+            Some((pid, container_id(&cgroup)?))
+        })
+        .collect())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn list_cgroups() -> Result<Vec<(Pid, String)>, Error> {
+    Err(format_err!("--container is only supported on linux"))
+}
+
+/// Finds the full id of a container from its name, id or a prefix of its id. Like docker, a full
+/// id is looked for first, then a name, and then an id that starts with it
+fn resolve_container<'a>(
+    name_or_id: &str,
+    ids: impl Iterator<Item = &'a str>,
+) -> Result<String, Error> {
+    let mut ids: Vec<&str> = ids.collect();
+    ids.sort_unstable();
+    ids.dedup();
+    if ids.contains(&name_or_id) {
+        return Ok(name_or_id.to_owned());
+    }
+    if let Some(id) = docker_container_id(Path::new(DOCKER_CONTAINERS), name_or_id)? {
+        return Ok(id);
+    }
+    let matched: Vec<&str> = ids
+        .into_iter()
+        .filter(|id| id.starts_with(name_or_id))
+        .collect();
+    match matched.as_slice() {
+        [id] => Ok(id.to_string()),
+        [] => Err(format_err!(
+            "No running container has the name or id '{}'",
+            name_or_id
+        )),
+        _ => Err(format_err!(
+            "Container id '{}' is ambiguous, it's the start of {} container ids",
+            name_or_id,
+            matched.len()
+        )),
+    }
+}
+
+/// Looks up the id of a docker container from its name
+fn docker_container_id(containers: &Path, name: &str) -> Result<Option<String>, Error> {
+    let entries = match std::fs::read_dir(containers) {
+        Ok(entries) => entries,
+        // docker isn't installed, or the container is run by something else
+        Err(_) => return Ok(None),
+    };
+    let name = name.strip_prefix('/').unwrap_or(name);
+    for entry in entries {
+        let path = entry?.path().join("config.v2.json");
+        let config: serde_json::Value = match std::fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .with_context(|| format!("Failed to parse '{}'", path.display()))?,
+            Err(_) => continue,
+        };
+        let container_name = config["Name"].as_str().unwrap_or_default();
+        if container_name.strip_prefix('/').unwrap_or(container_name) == name {
+            if let Some(id) = config["ID"].as_str() {
+                return Ok(Some(id.to_owned()));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// The id of the container a process is in, from the contents of /proc/<pid>/cgroup, like
+/// '0::/system.slice/docker-<id>.scope' or '12:pids:/docker/<id>'
+fn container_id(cgroup: &str) -> Option<String> {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(r"[/-]([0-9a-f]{64})(\.scope)?$").unwrap();
+    }
+    cgroup
+        .lines()
+        .find_map(|line| RE.captures(line.trim()))
+        .map(|captures| captures[1].to_owned())
+}
+
+/// The pid a process has in its own pid namespace, from the NSpid line of /proc/<pid>/status,
+/// which lists its pid in each namespace from the outermost in
+fn namespace_pid(status: &str) -> Option<Pid> {
+    let line = status
+        .lines()
+        .find_map(|line| line.strip_prefix("NSpid:"))?;
+    line.split_whitespace().last()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: &str = "4c01db0b339c4b1e1f6b2e8f1e1b6b0d5b3a2f1e0d9c8b7a6f5e4d3c2b1a0f9e";
+
+    #[test]
+    fn test_container_id() {
+        let docker = format!("0::/system.slice/docker-{}.scope\n", ID);
+        assert_eq!(container_id(&docker).as_deref(), Some(ID));
+        let cgroup_v1 = format!("12:pids:/docker/{}\n11:cpu:/docker/{}\n", ID, ID);
+        assert_eq!(container_id(&cgroup_v1).as_deref(), Some(ID));
+        let kubernetes = format!(
+            "0::/kubepods.slice/kubepods-pod1234.slice/cri-containerd-{}.scope\n",
+            ID
+        );
+        assert_eq!(container_id(&kubernetes).as_deref(), Some(ID));
+        assert_eq!(
+            container_id("0::/user.slice/user-1000.slice/session-2.scope\n"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_resolve_container() {
+        let other = "4c01ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff";
+        let ids = || [ID, ID, other].into_iter();
+        assert_eq!(resolve_container("4c01db", ids()).unwrap(), ID);
+        assert_eq!(resolve_container(ID, ids()).unwrap(), ID);
+        assert!(resolve_container("4c01", ids()).is_err());
+
+        // names are looked up in docker's config files
+        let containers = tempfile::tempdir().unwrap();
+        std::fs::create_dir(containers.path().join(ID)).unwrap();
+        std::fs::write(
+            containers.path().join(ID).join("config.v2.json"),
+            format!(r#"{{"ID": "{}", "Name": "/web"}}"#, ID),
+        )
+        .unwrap();
+        let found = docker_container_id(containers.path(), "web").unwrap();
+        assert_eq!(found.as_deref(), Some(ID));
+        let found = docker_container_id(containers.path(), "worker").unwrap();
+        assert_eq!(found, None);
+    }
+
+    #[test]
+    fn test_namespace_pid() {
+        let status = "Name:\tpython3\nTgid:\t4242\nNgid:\t0\nPid:\t4242\nNSpid:\t4242\t7\n";
+        assert_eq!(namespace_pid(status), Some(7));
+        assert_eq!(namespace_pid("Name:\tpython3\n"), None);
+    }
+}
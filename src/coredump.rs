@@ -13,19 +13,10 @@ use remoteprocess::ProcessMemory;
 
 use crate::binary_parser::{parse_binary, BinaryInfo};
 use crate::config::Config;
-use crate::dump::print_trace;
-use crate::python_bindings::{
-    v2_7_15, v3_10_0, v3_11_0, v3_3_7, v3_5_5, v3_6_6, v3_7_0, v3_8_0, v3_9_5,
-};
-use crate::python_data_access::format_variable;
-use crate::python_interpreters::InterpreterState;
-use crate::python_process_info::{
-    get_interpreter_address, get_python_version, get_threadstate_address, is_python_lib,
-    ContainsAddr, PythonProcessInfo,
-};
-use crate::python_threading::thread_names_from_interpreter;
-use crate::stack_trace::{get_stack_traces, StackTrace};
-use crate::version::Version;
+use crate::dump::{print_trace, write_locals_output};
+use crate::post_mortem::DumpedInterpreter;
+use crate::python_process_info::{is_python_lib, ContainsAddr, PythonProcessInfo};
+use crate::stack_trace::StackTrace;
 
 #[derive(Debug, Clone)]
 pub struct CoreMapRange {
@@ -61,6 +52,13 @@ impl ContainsAddr for Vec<CoreMapRange> {
         self.iter()
             .any(|map| (addr >= map.start()) && (addr < (map.start() + map.size())))
     }
+
+    fn writable_regions(&self) -> Vec<(usize, usize)> {
+        self.iter()
+            .filter(|map| map.is_read() && map.is_write())
+            .map(|map| (map.start(), map.size()))
+            .collect()
+    }
 }
 
 pub struct CoreDump {
@@ -88,26 +86,29 @@ impl CoreDump {
         let mut status = Vec::new();
         for note in notes.flatten() {
             if note.n_type == goblin::elf::note::NT_PRPSINFO {
-                psinfo = Some(unsafe { *(note.desc.as_ptr() as *const elfcore::elf_prpsinfo) });
+                psinfo = Some(unsafe {
+                    std::ptr::read_unaligned(note.desc.as_ptr() as *const elfcore::elf_prpsinfo)
+                });
             } else if note.n_type == goblin::elf::note::NT_PRSTATUS {
-                let thread_status =
-                    unsafe { *(note.desc.as_ptr() as *const elfcore::elf_prstatus) };
+                let thread_status = unsafe {
+                    std::ptr::read_unaligned(note.desc.as_ptr() as *const elfcore::elf_prstatus)
+                };
                 status.push(thread_status);
             } else if note.n_type == goblin::elf::note::NT_FILE {
                 let data = note.desc;
                 let ptrs = data.as_ptr() as *const usize;
 
-                let count = unsafe { *ptrs };
-                let _page_size = unsafe { *ptrs.offset(1) };
+                let count = unsafe { ptrs.read_unaligned() };
+                let _page_size = unsafe { ptrs.offset(1).read_unaligned() };
 
                 let string_table = &data[(std::mem::size_of::<usize>() * (2 + count * 3))..];
 
                 for (i, filename) in string_table.split(|chr| *chr == 0).enumerate() {
                     if i < count {
                         let i = i as isize;
-                        let start = unsafe { *ptrs.offset(i * 3 + 2) };
-                        let _end = unsafe { *ptrs.offset(i * 3 + 3) };
-                        let _page_offset = unsafe { *ptrs.offset(i * 3 + 4) };
+                        let start = unsafe { ptrs.offset(i * 3 + 2).read_unaligned() };
+                        let _end = unsafe { ptrs.offset(i * 3 + 3).read_unaligned() };
+                        let _page_offset = unsafe { ptrs.offset(i * 3 + 4).read_unaligned() };
 
                         let pathname = Path::new(&OsStr::from_bytes(filename)).to_path_buf();
                         filenames.insert(start, pathname);
@@ -174,13 +175,11 @@ impl ProcessMemory for CoreDump {
 
 pub struct PythonCoreDump {
     core: CoreDump,
-    version: Version,
-    interpreter_address: usize,
-    threadstate_address: usize,
+    interpreter: DumpedInterpreter,
 }
 
 impl PythonCoreDump {
-    pub fn new<P: AsRef<Path>>(filename: P) -> Result<PythonCoreDump, Error> {
+    pub fn new<P: AsRef<Path>>(filename: P, config: &Config) -> Result<PythonCoreDump, Error> {
         let core = CoreDump::new(filename)?;
         let maps = &core.maps;
 
@@ -190,10 +189,24 @@ impl PythonCoreDump {
                 .iter()
                 .find(|m| m.filename().is_some() & m.is_exec())
                 .ok_or_else(|| format_err!("Failed to get binary from coredump"))?;
-            let python_filename = map.filename().unwrap();
-            let python_binary = parse_binary(python_filename, map.start() as _, map.size() as _);
+            let python_filename = match &config.core_exe {
+                Some(exe) => PathBuf::from(exe),
+                None => map.filename().unwrap().to_owned(),
+            };
+            let python_binary = parse_binary(&python_filename, map.start() as _, map.size() as _)
+                .with_context(|| {
+                    let hint = match config.core_exe {
+                        Some(_) => "",
+                        None => " (pass --exe if the coredump is from another machine)",
+                    };
+                    format!(
+                        "Failed to parse python binary '{}'{}",
+                        python_filename.display(),
+                        hint
+                    )
+                });
             info!("Found python binary @ {}", python_filename.display());
-            (python_filename.to_owned(), python_binary)
+            (python_filename, python_binary)
         };
 
         // get the libpython binary (if any) from maps
@@ -221,7 +234,7 @@ impl PythonCoreDump {
 
         // If we have a libpython binary - we can tolerate failures on parsing the main python binary.
         let python_binary = match libpython_binary {
-            None => Some(python_binary.context("Failed to parse python binary")?),
+            None => Some(python_binary?),
             _ => python_binary.ok(),
         };
 
@@ -233,114 +246,18 @@ impl PythonCoreDump {
             dockerized: false,
         };
 
-        let version =
-            get_python_version(&python_info, &core).context("failed to get python version")?;
-        info!("Got python version {}", version);
-
-        let interpreter_address = get_interpreter_address(&python_info, &core, &version)?;
-        info!("Found interpreter at 0x{:016x}", interpreter_address);
-
-        // lets us figure out which thread has the GIL
-        let config = Config::default();
-        let threadstate_address = get_threadstate_address(&python_info, &version, &config)?;
-        info!("found threadstate at 0x{:016x}", threadstate_address);
-
-        Ok(PythonCoreDump {
-            core,
-            version,
-            interpreter_address,
-            threadstate_address,
-        })
+        let interpreter = DumpedInterpreter::new(&python_info, &core, None, config)?;
+        Ok(PythonCoreDump { core, interpreter })
     }
 
     pub fn get_stack(&self, config: &Config) -> Result<Vec<StackTrace>, Error> {
-        if config.native {
-            return Err(format_err!(
-                "Native unwinding isn't yet supported with coredumps"
-            ));
-        }
-
-        if config.subprocesses {
-            return Err(format_err!(
-                "Subprocesses can't be used for getting stacktraces from coredumps"
-            ));
-        }
-
-        // different versions have different layouts, check as appropriate
-        match self.version {
-            Version {
-                major: 2,
-                minor: 3..=7,
-                ..
-            } => self._get_stack::<v2_7_15::_is>(config),
-            Version {
-                major: 3, minor: 3, ..
-            } => self._get_stack::<v3_3_7::_is>(config),
-            Version {
-                major: 3,
-                minor: 4..=5,
-                ..
-            } => self._get_stack::<v3_5_5::_is>(config),
-            Version {
-                major: 3, minor: 6, ..
-            } => self._get_stack::<v3_6_6::_is>(config),
-            Version {
-                major: 3, minor: 7, ..
-            } => self._get_stack::<v3_7_0::_is>(config),
-            Version {
-                major: 3, minor: 8, ..
-            } => self._get_stack::<v3_8_0::_is>(config),
-            Version {
-                major: 3, minor: 9, ..
-            } => self._get_stack::<v3_9_5::_is>(config),
-            Version {
-                major: 3,
-                minor: 10,
-                ..
-            } => self._get_stack::<v3_10_0::_is>(config),
-            Version {
-                major: 3,
-                minor: 11,
-                ..
-            } => self._get_stack::<v3_11_0::_is>(config),
-            _ => Err(format_err!(
-                "Unsupported version of Python: {}",
-                self.version
-            )),
-        }
-    }
-
-    fn _get_stack<I: InterpreterState>(&self, config: &Config) -> Result<Vec<StackTrace>, Error> {
-        let interp: I = self.core.copy_struct(self.interpreter_address)?;
-
-        let mut traces =
-            get_stack_traces(&interp, &self.core, self.threadstate_address, Some(config))?;
-        let thread_names = thread_names_from_interpreter(&interp, &self.core, &self.version).ok();
-
-        for trace in &mut traces {
-            if let Some(ref thread_names) = thread_names {
-                trace.thread_name = thread_names.get(&trace.thread_id).cloned();
-            }
-
-            for frame in &mut trace.frames {
-                if let Some(locals) = frame.locals.as_mut() {
-                    let max_length = (128 * config.dump_locals) as isize;
-                    for local in locals {
-                        let repr = format_variable::<I, CoreDump>(
-                            &self.core,
-                            &self.version,
-                            local.addr,
-                            max_length,
-                        );
-                        local.repr = Some(repr.unwrap_or_else(|_| "?".to_owned()));
-                    }
-                }
-            }
-        }
-        Ok(traces)
+        self.interpreter.get_stack(&self.core, config)
     }
 
     pub fn print_traces(&self, traces: &Vec<StackTrace>, config: &Config) -> Result<(), Error> {
+        if let Some(filename) = &config.locals_output {
+            write_locals_output(traces, filename)?;
+        }
         if config.dump_json {
             println!("{}", serde_json::to_string_pretty(&traces)?);
             return Ok(());
@@ -361,7 +278,7 @@ impl PythonCoreDump {
                 OsStr::from_bytes(&psinfo.pr_psargs).to_string_lossy()
             );
         }
-        println!("Python v{}", style(&self.version).bold());
+        println!("Python v{}", style(&self.interpreter.version).bold());
         println!();
         for trace in traces.iter().rev() {
             print_trace(trace, false);
@@ -427,6 +344,7 @@ mod elfcore {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::version::Version;
     use py_spy_testdata::get_coredump_path;
 
     #[cfg(target_pointer_width = "64")]
@@ -435,7 +353,7 @@ mod test {
         // we won't have the python binary for the core dump here,
         // so we can't (yet) figure out the interpreter address & version.
         // Manually specify here to test out instead
-        let core = CoreDump::new(&get_coredump_path("python_3_9_threads")).unwrap();
+        let core = CoreDump::new(get_coredump_path("python_3_9_threads")).unwrap();
         let version = Version {
             major: 3,
             minor: 9,
@@ -445,9 +363,11 @@ mod test {
         };
         let python_core = PythonCoreDump {
             core,
-            version,
-            interpreter_address: 0x000055a8293dbe20,
-            threadstate_address: 0x000055a82745fe18,
+            interpreter: DumpedInterpreter {
+                version,
+                interpreter_address: 0x000055a8293dbe20,
+                threadstate_address: 0x000055a82745fe18,
+            },
         };
 
         let config = Config::default();
@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+
+use remoteprocess::Pid;
+
+use crate::stack_trace::StackTrace;
+use crate::utils::thread_cpu_times;
+
+/*
+ * This file contains code to sample threads in proportion to the cpu time they use, for
+ * '--clock cpu'.
+ *
+ * The sampler still wakes up at the sampling rate, but rather than taking a sample of every
+ * thread each time, it reads how much cpu time each thread has used since then. A thread is
+ * sampled once for every 1/rate seconds of cpu time it has used, so threads that are idle are
+ * never sampled, and the process isn't read from at all while every thread is idle. This gives
+ * an on-cpu profile, without needing the heuristics that --idle uses to detect idle threads.
+ *
+ * Threads are matched up with their cpu time by their OS thread id, so traces that don't have
+ * one are left out.
+ */
+
+#[derive(Default)]
+struct ThreadTime {
+    // the total cpu time the thread had used the last time it was read, in nanoseconds
+    last: u64,
+    // the cpu time the thread has used that hasn't been sampled yet, in nanoseconds
+    unsampled: u64,
+}
+
+pub struct CpuClock {
+    // the cpu time that each sample stands for, in nanoseconds
+    interval: u64,
+    // (pid, os thread id) -> cpu time of each thread
+    threads: HashMap<(Pid, u64), ThreadTime>,
+}
+
+impl CpuClock {
+    pub fn new(sampling_rate: u64) -> CpuClock {
+        CpuClock {
+            interval: 1_000_000_000 / sampling_rate.max(1),
+            threads: HashMap::new(),
+        }
+    }
+
+    /// Reads the cpu time used by the threads of a process, returning whether any of them have
+    /// used enough to be sampled
+    pub fn update(&mut self, pid: Pid) -> std::io::Result<bool> {
+        let times = thread_cpu_times(pid)?;
+        Ok(self.add_times(pid, &times))
+    }
+
+    fn add_times(&mut self, pid: Pid, times: &HashMap<u64, u64>) -> bool {
+        // forget about threads that have exited
+        self.threads
+            .retain(|(thread_pid, tid), _| *thread_pid != pid || times.contains_key(tid));
+
+        let mut ready = false;
+        for (tid, time) in times {
+            // the cpu time used before a thread was first seen isn't counted
+            let thread = self.threads.entry((pid, *tid)).or_insert(ThreadTime {
+                last: *time,
+                unsampled: 0,
+            });
+            thread.unsampled += time.saturating_sub(thread.last);
+            thread.last = *time;
+            ready |= thread.unsampled >= self.interval;
+        }
+        ready
+    }
+
+    /// Returns a trace for each sampling interval of cpu time that the threads have used since
+    /// they were last sampled. Threads that have used less than this aren't included
+    pub fn select(&mut self, traces: Vec<StackTrace>) -> Vec<StackTrace> {
+        let mut selected = Vec::new();
+        for mut trace in traces {
+            let thread = trace
+                .os_thread_id
+                .and_then(|tid| self.threads.get_mut(&(trace.pid, tid)));
+            let thread = match thread {
+                Some(thread) => thread,
+                None => continue,
+            };
+            let samples = thread.unsampled / self.interval;
+            thread.unsampled %= self.interval;
+            // the thread was running, whatever it's doing right now
+            trace.active = true;
+            for _ in 0..samples {
+                selected.push(trace.clone());
+            }
+        }
+        selected
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::trace;
+
+    fn thread(pid: Pid, os_thread_id: Option<u64>) -> StackTrace {
+        StackTrace {
+            pid,
+            thread_id: os_thread_id.unwrap_or(0),
+            os_thread_id,
+            active: false,
+            ..trace(vec![])
+        }
+    }
+
+    #[test]
+    fn test_cpu_clock() {
+        let ms = 1_000_000;
+        // 100Hz, so each sample stands for 10ms of cpu time
+        let mut clock = CpuClock::new(100);
+
+        // cpu time used before the threads were first seen isn't sampled
+        let times = HashMap::from([(1, 500 * ms), (2, 500 * ms)]);
+        assert!(!clock.add_times(1234, &times));
+
+        // thread 1 used 25ms of cpu, and thread 2 used only 5ms
+        let times = HashMap::from([(1, 525 * ms), (2, 505 * ms)]);
+        assert!(clock.add_times(1234, &times));
+        let traces = vec![
+            thread(1234, Some(1)),
+            thread(1234, Some(2)),
+            thread(1234, None),
+        ];
+        let selected = clock.select(traces.clone());
+        assert_eq!(selected.len(), 2);
+        assert!(selected
+            .iter()
+            .all(|t| t.os_thread_id == Some(1) && t.active));
+
+        // the remaining cpu time carries over to the next samples
+        let times = HashMap::from([(1, 530 * ms), (2, 510 * ms)]);
+        assert!(clock.add_times(1234, &times));
+        let selected = clock.select(traces);
+        let threads: Vec<Option<u64>> = selected.iter().map(|t| t.os_thread_id).collect();
+        assert_eq!(threads, vec![Some(1), Some(2)]);
+
+        // threads from other processes are kept track of separately
+        let times = HashMap::from([(1, 0)]);
+        assert!(!clock.add_times(5678, &times));
+        assert_eq!(clock.threads.len(), 3);
+        // and exited threads are forgotten
+        let times = HashMap::from([(1, 530 * ms)]);
+        assert!(!clock.add_times(1234, &times));
+        assert_eq!(clock.threads.len(), 2);
+    }
+}
@@ -0,0 +1,147 @@
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+
+use anyhow::Error;
+
+use crate::stack_trace::StackTrace;
+
+/*
+ * This file contains code to write out an aggregate report of the functions in a profile as
+ * CSV, for use in spreadsheets or CI dashboards.
+ *
+ * Each row is a function (or a line of a function, with line numbers enabled), with the number
+ * of samples where it was the leaf frame ('self') and the number of samples where it appeared
+ * anywhere on the stack ('total'). Rows are sorted by self samples, so that the first rows are
+ * the hottest functions.
+ */
+
+#[derive(Default)]
+struct Counts {
+    self_samples: u64,
+    total_samples: u64,
+}
+
+pub struct Csv {
+    // (function, filename, line) -> counts
+    functions: HashMap<(String, String, i32), Counts>,
+    samples: u64,
+    show_linenumbers: bool,
+}
+
+impl Csv {
+    pub fn new(show_linenumbers: bool) -> Csv {
+        Csv {
+            functions: HashMap::new(),
+            samples: 0,
+            show_linenumbers,
+        }
+    }
+
+    pub fn increment(&mut self, trace: &StackTrace) -> std::io::Result<()> {
+        // recursive functions should only be counted once per sample in the totals
+        let mut seen = HashSet::new();
+        for (depth, frame) in trace.frames.iter().enumerate() {
+            let filename = match &frame.short_filename {
+                Some(f) => f,
+                None => &frame.filename,
+            };
+            let line = if self.show_linenumbers { frame.line } else { 0 };
+            let key = (frame.name.clone(), filename.clone(), line);
+            if !seen.insert(key.clone()) {
+                continue;
+            }
+            let counts = self.functions.entry(key).or_default();
+            counts.total_samples += 1;
+            // frames are stored leaf first
+            if depth == 0 {
+                counts.self_samples += 1;
+            }
+        }
+        self.samples += 1;
+        Ok(())
+    }
+
+    pub fn write(&self, w: &mut dyn Write) -> Result<(), Error> {
+        writeln!(
+            w,
+            "function,file,line,self_samples,total_samples,self_percent,total_percent"
+        )?;
+
+        let mut rows: Vec<_> = self.functions.iter().collect();
+        rows.sort_by(|(a_key, a), (b_key, b)| {
+            b.self_samples
+                .cmp(&a.self_samples)
+                .then(b.total_samples.cmp(&a.total_samples))
+                .then(a_key.cmp(b_key))
+        });
+
+        let percent = |count: u64| 100.0 * count as f64 / self.samples.max(1) as f64;
+        for ((function, filename, line), counts) in rows {
+            let line = if self.show_linenumbers {
+                line.to_string()
+            } else {
+                String::new()
+            };
+            writeln!(
+                w,
+                "{},{},{},{},{},{:.2},{:.2}",
+                escape(function),
+                escape(filename),
+                line,
+                counts.self_samples,
+                counts.total_samples,
+                percent(counts.self_samples),
+                percent(counts.total_samples)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Quotes a CSV field if it contains any commas, quotes or newlines
+fn escape(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{frame, trace};
+
+    #[test]
+    fn test_csv_report() {
+        let mut csv = Csv::new(true);
+        let mut trace = trace(vec![
+            frame("busy", "test.py", 2),
+            frame("main", "test.py", 10),
+        ]);
+        csv.increment(&trace).unwrap();
+        csv.increment(&trace).unwrap();
+        csv.increment(&trace).unwrap();
+        // recursion shouldn't inflate the total samples
+        trace.frames = vec![
+            frame("f, g", "test.py", 5),
+            frame("f, g", "test.py", 5),
+            frame("main", "test.py", 10),
+        ];
+        csv.increment(&trace).unwrap();
+
+        let mut out = Vec::new();
+        csv.write(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                "function,file,line,self_samples,total_samples,self_percent,total_percent",
+                "busy,test.py,2,3,3,75.00,75.00",
+                "\"f, g\",test.py,5,1,1,25.00,25.00",
+                "main,test.py,10,0,4,0.00,100.00",
+            ]
+        );
+    }
+}
@@ -1,5 +1,6 @@
 use regex::Regex;
 use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
 
 use anyhow::Error;
 use lazy_static::lazy_static;
@@ -9,12 +10,17 @@ use crate::utils::resolve_filename;
 
 pub struct SourceMaps {
     maps: HashMap<String, Option<SourceMap>>,
+    // for generated C files that can't be found, the definitions in the .pyx file shipped with
+    // the extension instead
+    definitions: HashMap<String, Option<PyxDefinitions>>,
 }
 
 impl SourceMaps {
     pub fn new() -> SourceMaps {
-        let maps = HashMap::new();
-        SourceMaps { maps }
+        SourceMaps {
+            maps: HashMap::new(),
+            definitions: HashMap::new(),
+        }
     }
 
     pub fn translate(&mut self, frame: &mut Frame) {
@@ -34,8 +40,13 @@ impl SourceMaps {
         if let Some(map) = self.maps.get(&frame.filename) {
             if let Some(map) = map {
                 if let Some((file, line)) = map.lookup(line) {
-                    frame.filename = file.clone();
-                    frame.line = *line as i32;
+                    frame.filename = file.to_owned();
+                    frame.line = line as i32;
+                }
+            } else if let Some(Some(definitions)) = self.definitions.get(&frame.filename) {
+                if let Some(line) = definitions.lookup(&frame.name) {
+                    frame.filename = definitions.filename.clone();
+                    frame.line = line as i32;
                 }
             }
             return false;
@@ -55,6 +66,14 @@ impl SourceMaps {
             Err(e) => {
                 info!("Failed to load cython file {}: {:?}", &frame.filename, e);
                 self.maps.insert(frame.filename.clone(), None);
+                let definitions = PyxDefinitions::find(&frame.filename, &frame.module);
+                if let Some(definitions) = definitions.as_ref() {
+                    info!(
+                        "Using the function definitions in {} for {}",
+                        definitions.filename, frame.filename
+                    );
+                }
+                self.definitions.insert(frame.filename.clone(), definitions);
                 return;
             }
         };
@@ -63,8 +82,13 @@ impl SourceMaps {
     }
 }
 
+/// Maps the lines of a C file generated by cython to the lines of the cython source they came
+/// from. Each block of generated code starts with a comment like '/* "file.pyx":12', or with a
+/// '#line 12 "file.pyx"' directive when cython was run with --line-directives
 struct SourceMap {
-    lookup: BTreeMap<u32, (String, u32)>,
+    // line in the C file -> (cython file, cython line, whether the lines after it count up from
+    // the cython line like they do after a #line directive)
+    lookup: BTreeMap<u32, (String, u32, bool)>,
 }
 
 impl SourceMap {
@@ -80,18 +104,31 @@ impl SourceMap {
     ) -> Result<SourceMap, Error> {
         lazy_static! {
             static ref RE: Regex = Regex::new(r#"^\s*/\* "(.+\..+)":([0-9]+)"#).unwrap();
+            static ref LINE_DIRECTIVE: Regex =
+                Regex::new(r#"^\s*#line\s+([0-9]+)\s+"(.+)""#).unwrap();
         }
 
         let mut lookup = BTreeMap::new();
         let mut resolved: HashMap<String, String> = HashMap::new();
+        let cpp_name = Path::new(cpp_filename).file_name();
 
         let mut line_count = 0;
         for (lineno, line) in contents.lines().enumerate() {
-            if let Some(captures) = RE.captures(line) {
-                let cython_file = captures.get(1).map_or("", |m| m.as_str());
-                let cython_line = captures.get(2).map_or("", |m| m.as_str());
-
-                if let Ok(cython_line) = cython_line.parse::<u32>() {
+            let (captures, directive) = match RE.captures(line) {
+                Some(captures) => (Some((captures.get(1), captures.get(2))), false),
+                None => match LINE_DIRECTIVE.captures(line) {
+                    Some(captures) => (Some((captures.get(2), captures.get(1))), true),
+                    None => (None, false),
+                },
+            };
+            if let Some((cython_file, cython_line)) = captures {
+                let cython_file = cython_file.map_or("", |m| m.as_str());
+                let cython_line = cython_line.map_or("", |m| m.as_str());
+
+                // a #line directive back to the generated file ends the cython code before it
+                if directive && Path::new(cython_file).file_name() == cpp_name {
+                    lookup.insert(lineno as u32, ("".to_owned(), 0, false));
+                } else if let Ok(cython_line) = cython_line.parse::<u32>() {
                     // try resolving the cython filename
                     let filename = match resolved.get(cython_file) {
                         Some(filename) => filename.clone(),
@@ -102,26 +139,100 @@ impl SourceMap {
                         }
                     };
 
-                    lookup.insert(lineno as u32, (filename, cython_line));
+                    lookup.insert(lineno as u32, (filename, cython_line, directive));
                 }
             }
             line_count += 1;
         }
 
-        lookup.insert(line_count + 1, ("".to_owned(), 0));
+        lookup.insert(line_count + 1, ("".to_owned(), 0, false));
         Ok(SourceMap { lookup })
     }
 
-    pub fn lookup(&self, lineno: u32) -> Option<&(String, u32)> {
+    pub fn lookup(&self, lineno: u32) -> Option<(&str, u32)> {
         match self.lookup.range(..lineno).next_back() {
             // handle EOF
-            Some((_, (_, 0))) => None,
-            Some((_, val)) => Some(val),
+            Some((_, (_, 0, _))) => None,
+            // lines are numbered from 1, and the directive was on the (0 based) line 'start'
+            Some((start, (file, line, true))) => Some((file, line + lineno - start - 2)),
+            Some((_, (file, line, false))) => Some((file, *line)),
             None => None,
         }
     }
 }
 
+/// The lines that the functions in a cython source file are defined on, for when the C file
+/// generated from it can't be found to map lines exactly (like when an extension is installed
+/// from a wheel that only ships the .pyx file). Frames in the generated code are mapped to the
+/// definition of their function
+struct PyxDefinitions {
+    filename: String,
+    functions: Vec<(String, u32)>,
+}
+
+impl PyxDefinitions {
+    /// Looks for the cython source of a generated C file, next to the C file or the extension
+    /// module built from it
+    fn find(cpp_filename: &str, module: &Option<String>) -> Option<PyxDefinitions> {
+        let cpp_path = Path::new(cpp_filename);
+        let mut candidates = Vec::new();
+        for extension in ["pyx", "py"] {
+            candidates.push(cpp_path.with_extension(extension));
+            if let (Some(module), Some(stem)) = (module, cpp_path.file_stem()) {
+                let module = Path::new(module);
+                if let Some(parent) = module.parent() {
+                    candidates.push(parent.join(stem).with_extension(extension));
+                    // extensions are named like '_als.cpython-311-x86_64-linux-gnu.so'
+                    let module_name = module.file_name().and_then(|name| name.to_str());
+                    if let Some(name) = module_name.and_then(|name| name.split('.').next()) {
+                        candidates.push(parent.join(name).with_extension(extension));
+                    }
+                }
+            }
+        }
+        let path = candidates.into_iter().find(|path| path.exists())?;
+        let contents = std::fs::read_to_string(&path).ok()?;
+        let definitions = PyxDefinitions::from_contents(&contents, path.to_string_lossy().as_ref());
+        (!definitions.functions.is_empty()).then_some(definitions)
+    }
+
+    fn from_contents(contents: &str, filename: &str) -> PyxDefinitions {
+        lazy_static! {
+            static ref RE: Regex = Regex::new(
+                r"^\s*(?:async\s+)?(?:def|cdef|cpdef)\s+(?:[\w\[\]\*\.]+\s+)*?\**(\w+)\s*\("
+            )
+            .unwrap();
+        }
+        let functions = contents
+            .lines()
+            .enumerate()
+            .filter_map(|(lineno, line)| {
+                let name = RE.captures(line)?.get(1)?.as_str();
+                Some((name.to_owned(), lineno as u32 + 1))
+            })
+            .collect();
+        PyxDefinitions {
+            filename: filename.to_owned(),
+            functions,
+        }
+    }
+
+    /// The line a function is defined on. Demangled cython names can still have the module (or
+    /// class) before the function name, so this also matches the longest function name that the
+    /// name ends with
+    fn lookup(&self, name: &str) -> Option<u32> {
+        self.functions
+            .iter()
+            .filter(|(function, _)| {
+                name == function
+                    || (name.ends_with(function.as_str())
+                        && name[..name.len() - function.len()].ends_with('_'))
+            })
+            .max_by_key(|(function, _)| function.len())
+            .map(|(_, line)| *line)
+    }
+}
+
 pub fn ignore_frame(name: &str) -> bool {
     let ignorable = [
         "__Pyx_PyFunction_FastCallDict",
@@ -131,7 +242,7 @@ pub fn ignore_frame(name: &str) -> bool {
         "__pyx_FusedFunction_call",
     ];
 
-    ignorable.iter().any(|&f| f == name)
+    ignorable.contains(&name)
 }
 
 pub fn demangle(name: &str) -> &str {
@@ -262,7 +373,7 @@ mod tests {
         let lookup = |lineno: u32, cython_file: &str, cython_line: u32| match map.lookup(lineno) {
             Some((file, line)) => {
                 assert_eq!(file, cython_file);
-                assert_eq!(line, &cython_line);
+                assert_eq!(line, cython_line);
             }
             None => {
                 panic!(
@@ -275,4 +386,39 @@ mod tests {
         lookup(1647, "cython_test.pyx", 10);
         lookup(1763, "cython_test.pyx", 9);
     }
+
+    #[test]
+    fn test_line_directives() {
+        let contents = "static int x;\n\
+                        #line 6 \"cython_test.pyx\"\n\
+                        PyObject *a;\n\
+                        PyObject *b;\n\
+                        #line 7 \"build/cython_test.c\"\n\
+                        static int y;\n";
+        let map = SourceMap::from_contents(contents, "cython_test.c", &None).unwrap();
+        assert_eq!(map.lookup(1), None);
+        assert_eq!(map.lookup(3), Some(("cython_test.pyx", 6)));
+        assert_eq!(map.lookup(4), Some(("cython_test.pyx", 7)));
+        assert_eq!(map.lookup(6), None);
+    }
+
+    #[test]
+    fn test_pyx_definitions() {
+        let definitions = PyxDefinitions::from_contents(
+            include_str!("../ci/testdata/cython_test.pyx"),
+            "cython_test.pyx",
+        );
+        assert_eq!(definitions.functions, vec![(String::from("sqrt"), 6)]);
+        // the name of the function as demangled from '__pyx_fuse_0__pyx_f_11cython_test_sqrt'
+        assert_eq!(definitions.lookup("cython_test_sqrt"), Some(6));
+        assert_eq!(definitions.lookup("sqrt"), Some(6));
+        assert_eq!(definitions.lookup("cython_testsqrt"), None);
+
+        let definitions = PyxDefinitions::from_contents(
+            "cdef class Model:\n    cdef inline double* _least_squares(self, int n):\n        pass\n    def least_squares(self):\n        pass\n",
+            "als.pyx",
+        );
+        assert_eq!(definitions.lookup("_least_squares"), Some(2));
+        assert_eq!(definitions.lookup("als_least_squares"), Some(4));
+    }
 }
@@ -1,16 +1,27 @@
-use anyhow::Error;
+use std::path::Path;
+
+use anyhow::{Context, Error};
 use console::{style, Term};
 
 use crate::config::Config;
 use crate::python_spy::PythonSpy;
-use crate::stack_trace::StackTrace;
+use crate::stack_trace::{Frame, StackTrace};
+use crate::trio::TrioTask;
 
 use remoteprocess::Pid;
 
 pub fn print_traces(pid: Pid, config: &Config, parent: Option<Pid>) -> Result<(), Error> {
     let mut process = PythonSpy::new(pid, config)?;
+    // the full values of the locals of subprocesses are written to files of their own
+    let locals_output = config.locals_output.as_ref().map(|filename| match parent {
+        Some(_) => subprocess_filename(filename, pid),
+        None => filename.to_owned(),
+    });
     if config.dump_json {
         let traces = process.get_stack_traces()?;
+        if let Some(filename) = &locals_output {
+            write_locals_output(&traces, filename)?;
+        }
         println!("{}", serde_json::to_string_pretty(&traces)?);
         return Ok(());
     }
@@ -37,8 +48,39 @@ pub fn print_traces(pid: Pid, config: &Config, parent: Option<Pid>) -> Result<()
     }
     println!();
     let traces = process.get_stack_traces()?;
-    for trace in traces.iter().rev() {
+    if let Some(filename) = &locals_output {
+        write_locals_output(&traces, filename)?;
+        println!(
+            "{}\n",
+            style(format!(
+                "Wrote the full values of local variables to '{}'",
+                filename
+            ))
+            .dim()
+        );
+    }
+    // the trees of trio tasks are shown after the threads running them. Failing to read them
+    // shouldn't stop the stacks of the threads being shown
+    let trio_runs = process.get_trio_runs().unwrap_or_else(|e| {
+        warn!("Failed to read trio tasks: {}", e);
+        Vec::new()
+    });
+    // the suspended asyncio tasks are shown after the threads running them
+    let (tasks, threads): (Vec<_>, Vec<_>) = traces.iter().partition(|t| t.task_name.is_some());
+    for trace in threads.into_iter().rev().chain(tasks) {
         print_trace(trace, true);
+        if trace.task_name.is_none() {
+            for run in trio_runs
+                .iter()
+                .filter(|run| run.thread_id == trace.thread_id)
+            {
+                println!(
+                    "Trio tasks on thread {}",
+                    style(trace.format_threadid()).bold().yellow()
+                );
+                print_trio_task(&run.root, 1);
+            }
+        }
         if config.subprocesses {
             for (childpid, parentpid) in process
                 .process
@@ -61,19 +103,65 @@ pub fn print_traces(pid: Pid, config: &Config, parent: Option<Pid>) -> Result<()
     Ok(())
 }
 
+/// Writes stack traces to a JSON file with the whole values of their local variables in place of
+/// the truncated ones, for --locals-output
+pub fn write_locals_output(traces: &[StackTrace], filename: &str) -> Result<(), Error> {
+    let mut traces = traces.to_vec();
+    for frame in traces.iter_mut().flat_map(|trace| trace.frames.iter_mut()) {
+        for local in frame.locals.iter_mut().flatten() {
+            if let Some(full_repr) = local.full_repr.take() {
+                local.repr = Some(full_repr);
+            }
+        }
+    }
+    let file = std::fs::File::create(filename)
+        .with_context(|| format!("Failed to create file '{}'", filename))?;
+    serde_json::to_writer_pretty(std::io::BufWriter::new(file), &traces)?;
+    Ok(())
+}
+
+/// The file the locals of a subprocess are written to, like 'locals.1234.json' for 'locals.json'
+fn subprocess_filename(filename: &str, pid: Pid) -> String {
+    let path = Path::new(filename);
+    let stem = path.file_stem().map(|stem| stem.to_string_lossy());
+    let name = match (stem, path.extension()) {
+        (Some(stem), Some(extension)) => {
+            format!("{}.{}.{}", stem, pid, extension.to_string_lossy())
+        }
+        (Some(stem), None) => format!("{}.{}", stem, pid),
+        _ => return format!("{}.{}", filename, pid),
+    };
+    path.with_file_name(name).to_string_lossy().into_owned()
+}
+
 pub fn print_trace(trace: &StackTrace, include_activity: bool) {
     let thread_id = trace.format_threadid();
 
-    let status = if include_activity {
+    let status = if trace.task_name.is_some() {
+        // suspended asyncio tasks are waiting rather than idle, and never hold the gil
+        " (suspended)".to_owned()
+    } else if include_activity {
         format!(" ({})", trace.status_str())
     } else if trace.owns_gil {
         " (gil)".to_owned()
     } else {
         "".to_owned()
     };
+    let status = match trace.interpreter_id {
+        Some(id) => format!("{} (interpreter {})", status, id),
+        None => status,
+    };
 
-    match trace.thread_name.as_ref() {
-        Some(name) => {
+    match (trace.task_name.as_ref(), trace.thread_name.as_ref()) {
+        (Some(task_name), _) => {
+            println!(
+                "Task \"{}\"{} on thread {}",
+                task_name,
+                status,
+                style(thread_id).bold().yellow()
+            );
+        }
+        (None, Some(name)) => {
             println!(
                 "Thread {}{}: \"{}\"",
                 style(thread_id).bold().yellow(),
@@ -81,26 +169,53 @@ pub fn print_trace(trace: &StackTrace, include_activity: bool) {
                 name
             );
         }
-        None => {
+        (None, None) => {
             println!("Thread {}{}", style(thread_id).bold().yellow(), status);
         }
     };
 
-    for frame in &trace.frames {
+    print_frames(&trace.frames, 1);
+}
+
+/// Prints a trio task and its stack, followed by the tasks started in its nurseries indented
+/// under it
+fn print_trio_task(task: &TrioTask, depth: usize) {
+    let status = if task.frames.is_empty() {
+        " (running)"
+    } else {
+        ""
+    };
+    println!(
+        "{}Task \"{}\"{}",
+        "    ".repeat(depth),
+        style(&task.name).bold(),
+        status
+    );
+    print_frames(&task.frames, depth + 1);
+    for child in &task.children {
+        print_trio_task(child, depth + 1);
+    }
+}
+
+fn print_frames(frames: &[Frame], depth: usize) {
+    let indent = "    ".repeat(depth);
+    for frame in frames {
         let filename = match &frame.short_filename {
             Some(f) => f,
             None => &frame.filename,
         };
         if frame.line != 0 {
             println!(
-                "    {} ({}:{})",
+                "{}{} ({}:{})",
+                indent,
                 style(&frame.name).green(),
                 style(&filename).cyan(),
                 style(frame.line).dim()
             );
         } else {
             println!(
-                "    {} ({})",
+                "{}{} ({})",
+                indent,
                 style(&frame.name).green(),
                 style(&filename).cyan()
             );
@@ -111,16 +226,31 @@ pub fn print_trace(trace: &StackTrace, include_activity: bool) {
             let mut shown_locals = false;
             for local in locals {
                 if local.arg && !shown_args {
-                    println!("        {}", style("Arguments:").dim());
+                    println!("{}    {}", indent, style("Arguments:").dim());
                     shown_args = true;
                 } else if !local.arg && !shown_locals {
-                    println!("        {}", style("Locals:").dim());
+                    println!("{}    {}", indent, style("Locals:").dim());
                     shown_locals = true;
                 }
 
                 let repr = local.repr.as_deref().unwrap_or("?");
-                println!("            {}: {}", local.name, repr);
+                println!("{}        {}: {}", indent, local.name, repr);
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subprocess_filename() {
+        assert_eq!(subprocess_filename("locals.json", 1234), "locals.1234.json");
+        assert_eq!(
+            subprocess_filename("/tmp/out/locals.json", 1234),
+            "/tmp/out/locals.1234.json"
+        );
+        assert_eq!(subprocess_filename("locals", 1234), "locals.1234");
+    }
+}
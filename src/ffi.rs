@@ -0,0 +1,200 @@
+use std::cell::RefCell;
+use std::os::raw::c_char;
+use std::panic::catch_unwind;
+
+use anyhow::Error;
+
+use crate::config::Config;
+use crate::python_spy::PythonSpy;
+use crate::retry::FailureKind;
+
+/*
+ * This file contains the C ABI for taking a snapshot of the stack traces of a python process, so
+ * that agents written in other languages (like Go or C++) can link against py-spy instead of
+ * running it as a subprocess. The declarations are in include/py_spy.h.
+ *
+ * Nothing allocated by py-spy is handed across the boundary: the caller passes in the buffer the
+ * stack traces are written to, and gets back how long they are (or a negative error code), like
+ * snprintf. So there's nothing to free, and the caller decides how long the results live for.
+ * The message for the last error is kept per thread, so calls from different threads don't
+ * interfere with each other.
+ */
+
+/// A buffer was null with a non zero length
+pub const PYSPY_ERROR_INVALID_ARGUMENT: i64 = -1;
+/// The process doesn't exist (or exited while it was being read)
+pub const PYSPY_ERROR_NO_SUCH_PROCESS: i64 = -2;
+/// We don't have permission to read the memory of the process
+pub const PYSPY_ERROR_PERMISSION_DENIED: i64 = -3;
+/// The process couldn't be read as a python program, like when it isn't one
+pub const PYSPY_ERROR_ATTACH_FAILED: i64 = -4;
+/// The stack traces of the process couldn't be read
+pub const PYSPY_ERROR_SAMPLE_FAILED: i64 = -5;
+/// py-spy panicked, which is a bug in py-spy
+pub const PYSPY_ERROR_PANIC: i64 = -6;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Writes the stack traces of each thread in a python process to 'buf' as a NUL terminated
+/// JSON array, in the same format as 'py-spy dump --json'.
+///
+/// Returns the length of the JSON (without the NUL), or one of the negative PYSPY_ERROR codes.
+/// If the returned length is 'len' or more, the buffer was too small and nothing was written:
+/// calling again with a bigger buffer takes a new snapshot, which can be a different length.
+///
+/// # Safety
+///
+/// 'buf' has to be null (with a 'len' of 0), or point to at least 'len' writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn pyspy_snapshot(pid: i32, buf: *mut c_char, len: usize) -> i64 {
+    if buf.is_null() && len != 0 {
+        return fail(
+            PYSPY_ERROR_INVALID_ARGUMENT,
+            "buf is null, but len isn't 0".to_owned(),
+        );
+    }
+
+    let json = match catch_unwind(|| snapshot(pid as remoteprocess::Pid)) {
+        Ok(Ok(json)) => json,
+        Ok(Err((code, e))) => return fail(code, format!("{:#}", e)),
+        Err(_) => return fail(PYSPY_ERROR_PANIC, "py-spy panicked".to_owned()),
+    };
+    LAST_ERROR.with(|last| *last.borrow_mut() = None);
+    write_terminated(json.as_bytes(), buf, len)
+}
+
+/// Writes the message for the last error returned to this thread to 'buf', with the same
+/// return value as pyspy_snapshot. The message is empty if the last call succeeded.
+///
+/// # Safety
+///
+/// 'buf' has to be null (with a 'len' of 0), or point to at least 'len' writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn pyspy_last_error(buf: *mut c_char, len: usize) -> i64 {
+    if buf.is_null() && len != 0 {
+        return PYSPY_ERROR_INVALID_ARGUMENT;
+    }
+    LAST_ERROR.with(|last| {
+        let last = last.borrow();
+        write_terminated(last.as_deref().unwrap_or("").as_bytes(), buf, len)
+    })
+}
+
+fn snapshot(pid: remoteprocess::Pid) -> Result<String, (i64, Error)> {
+    let config = Config::default();
+    let mut process = PythonSpy::new(pid, &config).map_err(|e| (attach_error(pid, &e), e))?;
+    let traces = process
+        .get_stack_traces()
+        .map_err(|e| (error_code(&e, PYSPY_ERROR_SAMPLE_FAILED), e))?;
+    serde_json::to_string(&traces).map_err(|e| (PYSPY_ERROR_SAMPLE_FAILED, e.into()))
+}
+
+/// The error code for failing to attach to a process
+fn attach_error(pid: remoteprocess::Pid, e: &Error) -> i64 {
+    match error_code(e, PYSPY_ERROR_ATTACH_FAILED) {
+        // reading a process that doesn't exist doesn't always fail with ESRCH
+        PYSPY_ERROR_ATTACH_FAILED if !process_exists(pid) => PYSPY_ERROR_NO_SUCH_PROCESS,
+        code => code,
+    }
+}
+
+fn error_code(e: &Error, other: i64) -> i64 {
+    match FailureKind::classify(e) {
+        FailureKind::Exited => PYSPY_ERROR_NO_SUCH_PROCESS,
+        FailureKind::Permission => PYSPY_ERROR_PERMISSION_DENIED,
+        _ => other,
+    }
+}
+
+#[cfg(unix)]
+fn process_exists(pid: remoteprocess::Pid) -> bool {
+    // signal 0 only checks whether the process exists, and EPERM means it does
+    if unsafe { libc::kill(pid, 0) } == 0 {
+        return true;
+    }
+    std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn process_exists(pid: remoteprocess::Pid) -> bool {
+    remoteprocess::Process::new(pid).is_ok()
+}
+
+fn fail(code: i64, message: String) -> i64 {
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+    code
+}
+
+/// Copies 'data' to 'buf' followed by a NUL if it fits, returning the length of 'data'
+unsafe fn write_terminated(data: &[u8], buf: *mut c_char, len: usize) -> i64 {
+    if data.len() < len {
+        let buf = std::slice::from_raw_parts_mut(buf as *mut u8, len);
+        buf[..data.len()].copy_from_slice(data);
+        buf[data.len()] = 0;
+    }
+    data.len() as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CStr;
+
+    fn last_error() -> String {
+        let mut buf = [0 as c_char; 1024];
+        let written = unsafe { pyspy_last_error(buf.as_mut_ptr(), buf.len()) };
+        assert!(written >= 0 && (written as usize) < buf.len());
+        unsafe { CStr::from_ptr(buf.as_ptr()) }
+            .to_str()
+            .unwrap()
+            .to_owned()
+    }
+
+    #[test]
+    fn test_write_terminated() {
+        let mut buf = [1 as c_char; 4];
+        assert_eq!(unsafe { write_terminated(b"abc", buf.as_mut_ptr(), 4) }, 3);
+        assert_eq!(unsafe { CStr::from_ptr(buf.as_ptr()) }.to_bytes(), b"abc");
+
+        // nothing is written when it doesn't fit, including the NUL
+        let mut buf = [1 as c_char; 3];
+        assert_eq!(unsafe { write_terminated(b"abc", buf.as_mut_ptr(), 3) }, 3);
+        assert_eq!(buf, [1; 3]);
+        assert_eq!(
+            unsafe { write_terminated(b"abc", std::ptr::null_mut(), 0) },
+            3
+        );
+    }
+
+    #[test]
+    fn test_snapshot_errors() {
+        let mut buf = [0 as c_char; 1024];
+        assert_eq!(
+            unsafe { pyspy_snapshot(1, std::ptr::null_mut(), 10) },
+            PYSPY_ERROR_INVALID_ARGUMENT
+        );
+        assert_eq!(last_error(), "buf is null, but len isn't 0");
+
+        // this test isn't a python program
+        let pid = std::process::id() as i32;
+        assert_eq!(
+            unsafe { pyspy_snapshot(pid, buf.as_mut_ptr(), buf.len()) },
+            PYSPY_ERROR_ATTACH_FAILED
+        );
+        assert!(!last_error().is_empty());
+
+        #[cfg(unix)]
+        {
+            // a process that has exited, and been waited on so its pid isn't in use
+            let mut child = std::process::Command::new("true").spawn().unwrap();
+            let pid = child.id() as i32;
+            child.wait().unwrap();
+            assert_eq!(
+                unsafe { pyspy_snapshot(pid, buf.as_mut_ptr(), buf.len()) },
+                PYSPY_ERROR_NO_SUCH_PROCESS
+            );
+        }
+    }
+}
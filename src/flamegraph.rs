@@ -26,17 +26,46 @@ OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 SOFTWARE.
 */
 
+use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
 use std::io::Write;
+use std::time::Instant;
 
 use anyhow::Error;
-use inferno::flamegraph::{Direction, Options};
+use inferno::flamegraph::color::{Color, PaletteMap};
+use inferno::flamegraph::{Direction, Options, Palette};
 
+use crate::config::{Config, Granularity, Weight};
+use crate::frame_category::{category, package_name, Category};
 use crate::stack_trace::StackTrace;
 
+/// Palettes that color frames by what they belong to, rather than by function name. Any other
+/// palette name is passed through to inferno
+pub const GROUP_PALETTES: &[&str] = &["package", "native", "thread"];
+
 pub struct Flamegraph {
     pub counts: HashMap<String, usize>,
     pub show_linenumbers: bool,
+    /// Whether to prefix each stack with the pid, thread and gil/idle state of the sample
+    pub tag_threads: bool,
+    pub weight: Weight,
+    /// With Granularity::Line, each function has a single frame with a frame for each line under it
+    pub granularity: Granularity,
+    /// Color palette: either one of inferno's palettes or one of GROUP_PALETTES
+    pub palette: String,
+    /// Whether to merge stacks from the leaf function upwards, showing the callers of each function
+    pub reverse: bool,
+    /// What the counts of each stack are measuring, when they aren't samples or time (like 'bytes')
+    pub count_name: Option<String>,
+    // with GROUP_PALETTES, the weight of each group (package, native/python or thread) that a
+    // frame has been seen in. frames are colored by the group with the most weight
+    frame_groups: HashMap<String, HashMap<String, usize>>,
+    // timestamp of the most recent sampling interval, and the time (in microseconds) since the
+    // interval before it, which is used as the weight of each sample with Weight::Wall
+    timestamp: Option<u64>,
+    interval: u64,
+    start_ts: Instant,
 }
 
 impl Flamegraph {
@@ -44,33 +73,126 @@ impl Flamegraph {
         Flamegraph {
             counts: HashMap::new(),
             show_linenumbers,
+            tag_threads: false,
+            weight: Weight::Samples,
+            granularity: Granularity::Function,
+            palette: String::from("hot"),
+            reverse: false,
+            count_name: None,
+            frame_groups: HashMap::new(),
+            timestamp: None,
+            interval: 1_000_000 / 100,
+            start_ts: Instant::now(),
         }
     }
 
+    pub fn from_config(config: &Config) -> Flamegraph {
+        let mut flamegraph = Flamegraph::new(config.show_line_numbers);
+        flamegraph.tag_threads = config.tag_threads;
+        flamegraph.weight = config.weight;
+        flamegraph.granularity = config.granularity;
+        flamegraph.palette = config.palette.clone();
+        flamegraph.reverse = config.reverse;
+        flamegraph.interval = 1_000_000 / config.sampling_rate.max(1);
+        flamegraph
+    }
+
     pub fn increment(&mut self, trace: &StackTrace) -> std::io::Result<()> {
-        // convert the frame into a single ';' delimited String
-        let frame = trace
-            .frames
-            .iter()
-            .rev()
-            .map(|frame| {
-                let filename = match &frame.short_filename {
-                    Some(f) => f,
-                    None => &frame.filename,
+        let now = self.start_ts.elapsed().as_micros() as u64;
+        self.increment_at(trace, now)
+    }
+
+    pub fn increment_at(&mut self, trace: &StackTrace, timestamp: u64) -> std::io::Result<()> {
+        let weight = match self.weight {
+            Weight::Samples => 1,
+            Weight::Wall => {
+                // all the traces from a sampling interval share the same timestamp, and are
+                // weighted by the time since the previous interval. This means that samples
+                // taken after the sampler has fallen behind are given more weight
+                match self.timestamp {
+                    Some(last) if timestamp > last => self.interval = timestamp - last,
+                    _ => {}
+                }
+                self.timestamp = Some(timestamp.max(self.timestamp.unwrap_or(0)));
+                self.interval as usize
+            }
+        };
+        self.increment_by(trace, weight);
+        Ok(())
+    }
+
+    /// Adds a stack with an explicit weight, like the number of bytes allocated from it
+    pub fn increment_by(&mut self, trace: &StackTrace, weight: usize) {
+        let tags = if self.tag_threads {
+            let thread = match &trace.thread_name {
+                Some(name) => name.clone(),
+                None => trace.format_threadid(),
+            };
+            let state = if !trace.active {
+                "idle"
+            } else if trace.owns_gil {
+                "gil"
+            } else {
+                "active"
+            };
+            vec![
+                format!("pid:{}", trace.pid),
+                format!("thread:{}", thread),
+                format!("state:{}", state),
+            ]
+        } else {
+            Vec::new()
+        };
+
+        // the name of each frame, along with the frame it's for
+        let mut frames = Vec::new();
+        for frame in trace.frames.iter().rev() {
+            let filename = match &frame.short_filename {
+                Some(f) => f,
+                None => &frame.filename,
+            };
+            let split_lines = self.granularity == Granularity::Line && frame.line != 0;
+            let name = if self.show_linenumbers && frame.line != 0 && !split_lines {
+                format!("{} ({}:{})", frame.name, filename, frame.line)
+            } else if !filename.is_empty() {
+                format!("{} ({})", frame.name, filename)
+            } else {
+                frame.name.clone()
+            };
+            frames.push((name, frame));
+            if split_lines {
+                frames.push((format!("{}:{}", filename, frame.line), frame));
+            }
+        }
+
+        if GROUP_PALETTES.contains(&self.palette.as_str()) {
+            for (name, frame) in &frames {
+                let group = match self.palette.as_str() {
+                    "package" => package_name(frame),
+                    "native" if category(frame) == Category::Native => Some(String::from("native")),
+                    "native" => Some(String::from("python")),
+                    _ => Some(
+                        trace
+                            .thread_name
+                            .clone()
+                            .unwrap_or_else(|| trace.format_threadid()),
+                    ),
                 };
-                if self.show_linenumbers && frame.line != 0 {
-                    format!("{} ({}:{})", frame.name, filename, frame.line)
-                } else if !filename.is_empty() {
-                    format!("{} ({})", frame.name, filename)
-                } else {
-                    frame.name.clone()
+                if let Some(group) = group {
+                    let groups = self.frame_groups.entry(name.clone()).or_default();
+                    *groups.entry(group).or_insert(0) += weight;
                 }
-            })
+            }
+        }
+
+        // convert the frame into a single ';' delimited String
+        let frame = tags
+            .into_iter()
+            .chain(frames.into_iter().map(|(name, _)| name))
             .collect::<Vec<String>>()
             .join(";");
         // update counts for that frame
-        *self.counts.entry(frame).or_insert(0) += 1;
-        Ok(())
+        *self.counts.entry(frame).or_insert(0) += weight;
     }
 
     fn get_lines(&self) -> Vec<String> {
@@ -85,6 +207,27 @@ impl Flamegraph {
         opts.direction = Direction::Inverted;
         opts.min_width = 0.1;
         opts.title = std::env::args().collect::<Vec<String>>().join(" ");
+        if let Some(count_name) = &self.count_name {
+            opts.count_name = count_name.clone();
+        } else if self.weight == Weight::Wall {
+            opts.count_name = String::from("μs");
+        }
+        opts.reverse_stack_order = self.reverse;
+
+        let mut palette_map = PaletteMap::default();
+        if GROUP_PALETTES.contains(&self.palette.as_str()) {
+            for (frame, groups) in self.frame_groups.iter() {
+                if let Some((group, _)) = groups.iter().max_by_key(|(_, weight)| **weight) {
+                    palette_map.insert(frame, group_color(group));
+                }
+            }
+            opts.palette_map = Some(&mut palette_map);
+        } else {
+            opts.colors = self
+                .palette
+                .parse::<Palette>()
+                .map_err(|e| format_err!("{}", e))?;
+        }
 
         let lines = self.get_lines();
         inferno::flamegraph::from_lines(&mut opts, lines.iter().map(|x| x.as_str()), w)
@@ -100,3 +243,201 @@ impl Flamegraph {
         Ok(())
     }
 }
+
+/// Picks a color for a group of frames, so that each group gets a consistent color
+fn group_color(group: &str) -> Color {
+    match group {
+        "python" => Color {
+            r: 240,
+            g: 160,
+            b: 70,
+        },
+        "native" => Color {
+            r: 110,
+            g: 160,
+            b: 230,
+        },
+        _ => {
+            // pick a hue from the hash of the group name, with a fixed saturation and value
+            let mut hasher = DefaultHasher::new();
+            group.hash(&mut hasher);
+            let hue = (hasher.finish() % 360) as f64 / 60.0;
+            let (s, v) = (0.55, 0.92);
+            let c = v * s;
+            let x = c * (1.0 - (hue % 2.0 - 1.0).abs());
+            let (r, g, b) = match hue as u32 {
+                0 => (c, x, 0.0),
+                1 => (x, c, 0.0),
+                2 => (0.0, c, x),
+                3 => (0.0, x, c),
+                4 => (x, 0.0, c),
+                _ => (c, 0.0, x),
+            };
+            let m = v - c;
+            let channel = |value: f64| ((value + m) * 255.0) as u8;
+            Color {
+                r: channel(r),
+                g: channel(g),
+                b: channel(b),
+            }
+        }
+    }
+}
+
+/// Writes out a differential flamegraph from two sets of collapsed stacks. Frames are sized by
+/// the samples in 'after', and coloured red where they got slower and blue where they got faster
+pub fn write_differential(
+    before: &[u8],
+    after: &[u8],
+    normalize: bool,
+    w: &mut dyn Write,
+) -> Result<(), Error> {
+    let diff_opts = inferno::differential::Options {
+        normalize,
+        ..Default::default()
+    };
+
+    let mut lines = Vec::new();
+    inferno::differential::from_readers(diff_opts, before, after, &mut lines)?;
+
+    let mut opts = Options::default();
+    opts.direction = Direction::Inverted;
+    opts.min_width = 0.1;
+    opts.title = std::env::args().collect::<Vec<String>>().join(" ");
+    inferno::flamegraph::from_reader(&mut opts, lines.as_slice(), w)
+        .map_err(|e| format_err!("Failed to write differential flamegraph: {}", e))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{frame, trace};
+
+    #[test]
+    fn test_reverse() {
+        let mut flamegraph = Flamegraph::new(false);
+        flamegraph.reverse = true;
+        let frame = |name: &str| frame(name, "test.py", 1);
+        let mut trace = trace(vec![frame("hot"), frame("a"), frame("main")]);
+        flamegraph.increment(&trace).unwrap();
+        trace.frames = vec![frame("hot"), frame("b"), frame("main")];
+        flamegraph.increment(&trace).unwrap();
+
+        // 'hot' is the root of the reversed flamegraph, with both of its callers merged under it
+        let mut out = Vec::new();
+        flamegraph.write(&mut out).unwrap();
+        let svg = String::from_utf8(out).unwrap();
+        assert!(svg.contains("hot (test.py) (2 samples, 100.00%)"));
+        assert!(svg.contains("main (test.py) (1 samples, 50.00%)"));
+    }
+
+    #[test]
+    fn test_line_granularity() {
+        let mut config = Config::default();
+        config.show_line_numbers = true;
+        config.granularity = Granularity::Line;
+        let mut flamegraph = Flamegraph::from_config(&config);
+        let frame = |name: &str, line| frame(name, "test.py", line);
+        let mut trace = trace(vec![frame("hot", 3), frame("main", 10)]);
+        flamegraph.increment(&trace).unwrap();
+        trace.frames = vec![frame("hot", 4), frame("main", 10)];
+        flamegraph.increment(&trace).unwrap();
+        trace.frames = vec![frame("PyObject_Call", 0), frame("main", 10)];
+        trace.frames[0].filename = String::new();
+        flamegraph.increment(&trace).unwrap();
+
+        // each function is a single frame, with a frame under it for each of its lines
+        let mut out = Vec::new();
+        flamegraph.write_raw(&mut out).unwrap();
+        let mut lines: Vec<&str> = std::str::from_utf8(&out).unwrap().lines().collect();
+        lines.sort();
+        assert_eq!(
+            lines,
+            vec![
+                "main (test.py);test.py:10;PyObject_Call 1",
+                "main (test.py);test.py:10;hot (test.py);test.py:3 1",
+                "main (test.py);test.py:10;hot (test.py);test.py:4 1",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_package_palette() {
+        let in_module = |name: &str, filename: &str, module: Option<&str>| {
+            let mut f = frame(name, filename, 1);
+            f.module = module.map(|m| m.to_owned());
+            f
+        };
+        let handler = in_module("handle", "django/core/handlers/base.py", None);
+        assert_eq!(package_name(&handler), Some(String::from("django")));
+        let full = in_module(
+            "get",
+            "/usr/lib/python3/site-packages/requests/api.py",
+            None,
+        );
+        assert_eq!(package_name(&full), Some(String::from("requests")));
+        let script = in_module("main", "myscript.py", None);
+        assert_eq!(package_name(&script), Some(String::from("myscript")));
+        let native = in_module("PyObject_Call", "object.c", Some("libpython3.11.so"));
+        assert_eq!(package_name(&native), Some(String::from("native")));
+
+        let mut flamegraph = Flamegraph::new(false);
+        flamegraph.palette = String::from("package");
+        let trace = trace(vec![full, handler, script]);
+        flamegraph.increment(&trace).unwrap();
+        assert_eq!(flamegraph.frame_groups.len(), 3);
+        assert!(
+            flamegraph.frame_groups["handle (django/core/handlers/base.py)"].contains_key("django")
+        );
+
+        // frames in the same package get the same color
+        let mut out = Vec::new();
+        flamegraph.write(&mut out).unwrap();
+        let svg = String::from_utf8(out).unwrap();
+        let color = group_color("django");
+        assert!(svg.contains(&format!("rgb({},{},{})", color.r, color.g, color.b)));
+    }
+
+    #[test]
+    fn test_tags_and_wall_weights() {
+        let mut config = Config::default();
+        config.tag_threads = true;
+        config.weight = Weight::Wall;
+        let mut flamegraph = Flamegraph::from_config(&config);
+        let mut trace = trace(vec![frame("busy", "test.py", 4)]);
+        trace.pid = 1234;
+        trace.thread_name = Some(String::from("MainThread"));
+        trace.owns_gil = true;
+        // the first interval is weighted by the sampling rate, and later ones by the time
+        // since the previous interval
+        flamegraph.increment_at(&trace, 0).unwrap();
+        flamegraph.increment_at(&trace, 50_000).unwrap();
+        trace.owns_gil = false;
+        trace.thread_name = None;
+        flamegraph.increment_at(&trace, 50_000).unwrap();
+
+        let mut out = Vec::new();
+        flamegraph.write_raw(&mut out).unwrap();
+        let mut lines: Vec<&str> = std::str::from_utf8(&out).unwrap().lines().collect();
+        lines.sort();
+        assert_eq!(
+            lines,
+            vec![
+                "pid:1234;thread:0x1;state:active;busy (test.py) 50000",
+                "pid:1234;thread:MainThread;state:gil;busy (test.py) 60000",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_write_differential() {
+        let before = b"main (test.py);slow (test.py) 10\nmain (test.py);fast (test.py) 10\n";
+        let after = b"main (test.py);slow (test.py) 20\nmain (test.py);fast (test.py) 5\n";
+        let mut out = Vec::new();
+        write_differential(before, after, false, &mut out).unwrap();
+        let svg = String::from_utf8(out).unwrap();
+        assert!(svg.contains("slow (test.py) (20 samples, 80.00%; +40.00%)"));
+        assert!(svg.contains("fast (test.py) (5 samples, 20.00%; -20.00%)"));
+    }
+}
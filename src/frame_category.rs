@@ -0,0 +1,173 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+
+use crate::stack_trace::Frame;
+
+/*
+ * This file contains code to classify frames by where their code comes from (the standard
+ * library, an installed package, the application itself, or a native extension), and to work
+ * out the python module or package that a frame belongs to.
+ *
+ * This is only based on the filename of each frame, so that it works the same when converting
+ * recordings on another machine.
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Category {
+    Stdlib,
+    SitePackages,
+    Application,
+    Native,
+}
+
+impl Category {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Category::Stdlib => "stdlib",
+            Category::SitePackages => "site-packages",
+            Category::Application => "application",
+            Category::Native => "native",
+        }
+    }
+}
+
+/// Returns where the code for a frame comes from
+pub fn category(frame: &Frame) -> Category {
+    if frame.module.is_some() {
+        return Category::Native;
+    }
+    if site_packages_path(&frame.filename).is_some() {
+        return Category::SitePackages;
+    }
+    // frozen modules (like importlib) are compiled into the interpreter
+    if frame.filename.starts_with("<frozen ") || stdlib_path(&frame.filename).is_some() {
+        return Category::Stdlib;
+    }
+    Category::Application
+}
+
+/// The dotted python module path of a frame, like 'django.core.handlers.base' for a frame in
+/// 'django/core/handlers/base.py'. Returns None for native frames, and for code that wasn't
+/// loaded from a file
+pub fn module_path(frame: &Frame) -> Option<String> {
+    if frame.module.is_some() {
+        return None;
+    }
+    if let Some(name) = frame.filename.strip_prefix("<frozen ") {
+        return Some(name.trim_end_matches('>').to_owned());
+    }
+    let path = relative_path(frame);
+    if !path.ends_with(".py") {
+        return None;
+    }
+    let mut components: Vec<&str> = path
+        .trim_end_matches(".py")
+        .split(['/', '\\'])
+        .filter(|component| !component.is_empty())
+        .collect();
+    // paths that are still absolute aren't inside a package, so only the filename is the module
+    if is_absolute(path) {
+        components.drain(..components.len().saturating_sub(1));
+    }
+    if components.len() > 1 && components.last() == Some(&"__init__") {
+        components.pop();
+    }
+    Some(components.join("."))
+}
+
+/// The top level python package a frame belongs to, like 'django' for a frame in
+/// 'django/core/handlers/base.py'. Native frames are all grouped together
+pub fn package_name(frame: &Frame) -> Option<String> {
+    if frame.module.is_some() {
+        return Some(String::from("native"));
+    }
+    let package = relative_path(frame)
+        .split(['/', '\\'])
+        .find(|component| !component.is_empty())?;
+    Some(package.trim_end_matches(".py").to_owned())
+}
+
+/// The path of a frame's file relative to the directory it was imported from, when this can
+/// be worked out
+fn relative_path(frame: &Frame) -> &str {
+    let filename = match &frame.short_filename {
+        Some(f) => f,
+        None => &frame.filename,
+    };
+    // full filenames (with --full-filenames) are shortened to the path inside site-packages
+    // or the standard library
+    site_packages_path(filename)
+        .or_else(|| stdlib_path(filename))
+        .unwrap_or(filename)
+}
+
+fn site_packages_path(filename: &str) -> Option<&str> {
+    ["site-packages/", "dist-packages/", "site-packages\\"]
+        .iter()
+        .filter_map(|dir| filename.rsplit_once(dir).map(|(_, f)| f))
+        .next()
+}
+
+fn stdlib_path(filename: &str) -> Option<&str> {
+    lazy_static! {
+        // like '/usr/lib/python3.11/json/decoder.py' or 'C:\Python311\Lib\json\decoder.py'
+        static ref RE: Regex = Regex::new(r"(?:[/\\]lib[/\\]python[0-9.]*|\\Lib)[/\\]").unwrap();
+    }
+    RE.find(filename).map(|m| &filename[m.end()..])
+}
+
+fn is_absolute(path: &str) -> bool {
+    path.starts_with(['/', '\\']) || path.get(1..3) == Some(":\\")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::frame;
+
+    fn frame_in(filename: &str, short_filename: Option<&str>, module: Option<&str>) -> Frame {
+        let mut f = frame("f", filename, 1);
+        f.module = module.map(|m| m.to_owned());
+        f.short_filename = short_filename.map(|f| f.to_owned());
+        f
+    }
+
+    #[test]
+    fn test_category() {
+        let handler = frame_in(
+            "/venv/lib/python3.11/site-packages/django/core/handlers/base.py",
+            Some("django/core/handlers/base.py"),
+            None,
+        );
+        assert_eq!(category(&handler), Category::SitePackages);
+        assert_eq!(
+            module_path(&handler),
+            Some(String::from("django.core.handlers.base"))
+        );
+        assert_eq!(package_name(&handler), Some(String::from("django")));
+
+        let decoder = frame_in("/usr/lib/python3.11/json/decoder.py", None, None);
+        assert_eq!(category(&decoder), Category::Stdlib);
+        assert_eq!(module_path(&decoder), Some(String::from("json.decoder")));
+        let windows = frame_in("C:\\Python311\\Lib\\json\\__init__.py", None, None);
+        assert_eq!(category(&windows), Category::Stdlib);
+        assert_eq!(module_path(&windows), Some(String::from("json")));
+        let frozen = frame_in("<frozen importlib._bootstrap>", None, None);
+        assert_eq!(category(&frozen), Category::Stdlib);
+        assert_eq!(
+            module_path(&frozen),
+            Some(String::from("importlib._bootstrap"))
+        );
+
+        let app = frame_in("/srv/app/views/users.py", Some("views/users.py"), None);
+        assert_eq!(category(&app), Category::Application);
+        assert_eq!(module_path(&app), Some(String::from("views.users")));
+        let script = frame_in("/home/me/myscript.py", None, None);
+        assert_eq!(module_path(&script), Some(String::from("myscript")));
+
+        let native = frame_in("object.c", None, Some("libpython3.11.so"));
+        assert_eq!(category(&native), Category::Native);
+        assert_eq!(module_path(&native), None);
+        assert_eq!(package_name(&native), Some(String::from("native")));
+    }
+}
@@ -0,0 +1,69 @@
+use anyhow::Error;
+use regex::Regex;
+
+use crate::frame_category::module_path;
+use crate::stack_trace::{Frame, StackTrace};
+
+/*
+ * This file contains code to find when a function shows up in the samples, for
+ * '--start-on-function' and '--stop-on-function'.
+ *
+ * Patterns are regular expressions that are searched for in the name of each frame, its
+ * filename, and its qualified name (like 'app.handlers.checkout' for the 'checkout' function in
+ * 'app/handlers.py'). Every frame of every thread is checked, whether the thread is idle or not.
+ */
+
+pub struct FunctionMatcher {
+    pattern: Regex,
+}
+
+impl FunctionMatcher {
+    pub fn new(pattern: &str) -> Result<FunctionMatcher, Error> {
+        Ok(FunctionMatcher {
+            pattern: Regex::new(pattern)?,
+        })
+    }
+
+    /// Whether a frame is for a matching function
+    pub fn matches_frame(&self, frame: &Frame) -> bool {
+        if self.pattern.is_match(&frame.name) || self.pattern.is_match(&frame.filename) {
+            return true;
+        }
+        match module_path(frame) {
+            Some(module) => self.pattern.is_match(&format!("{}.{}", module, frame.name)),
+            None => false,
+        }
+    }
+
+    /// Whether any thread of a sample is in a matching function
+    pub fn matches(&self, traces: &[StackTrace]) -> bool {
+        traces
+            .iter()
+            .flat_map(|trace| trace.frames.iter())
+            .any(|frame| self.matches_frame(frame))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::frame;
+
+    #[test]
+    fn test_function_matcher() {
+        let checkout = frame("checkout", "/srv/app/handlers.py", 1);
+
+        let matcher = FunctionMatcher::new("^checkout$").unwrap();
+        assert!(matcher.matches_frame(&checkout));
+        assert!(!matcher.matches_frame(&frame("checkout_all", "/srv/app/handlers.py", 1)));
+
+        // qualified names and filenames can be matched too
+        let matcher = FunctionMatcher::new(r"handlers\.checkout$").unwrap();
+        assert!(matcher.matches_frame(&checkout));
+        assert!(!matcher.matches_frame(&frame("checkout", "/srv/app/views.py", 1)));
+        let matcher = FunctionMatcher::new(r"app/handlers\.py").unwrap();
+        assert!(matcher.matches_frame(&checkout));
+
+        assert!(FunctionMatcher::new("(").is_err());
+    }
+}
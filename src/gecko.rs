@@ -0,0 +1,411 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Error;
+use remoteprocess::Pid;
+use serde_derive::Serialize;
+
+use crate::stack_trace::{Frame, StackTrace};
+
+/*
+ * This file contains code to export py-spy profiles for use in the Firefox Profiler
+ * (https://profiler.firefox.com).
+ *
+ * We write out the 'gecko' profile format, which is the format that Firefox itself emits,
+ * and that the profiler upgrades and processes on load. Documentation on this format can be found
+ * at https://github.com/firefox-devtools/profiler/blob/main/docs-developer/gecko-profile-format.md
+ *
+ * Each table in this format is stored as a 'schema' mapping column names to indices, and
+ * a 'data' array of rows.
+ */
+
+// Indices into the categories array in the profile meta
+const CATEGORY_OTHER: usize = 0;
+const CATEGORY_PYTHON: usize = 1;
+const CATEGORY_NATIVE: usize = 2;
+
+#[derive(Debug, Serialize)]
+struct GeckoProfile<'a> {
+    meta: Meta,
+    libs: Vec<()>,
+    threads: Vec<&'a Thread>,
+    processes: Vec<()>,
+    #[serde(rename = "pausedRanges")]
+    paused_ranges: Vec<()>,
+}
+
+#[derive(Debug, Serialize)]
+struct Meta {
+    version: u32,
+    #[serde(rename = "startTime")]
+    start_time: f64,
+    #[serde(rename = "shutdownTime")]
+    shutdown_time: Option<f64>,
+    interval: f64,
+    #[serde(rename = "processType")]
+    process_type: u32,
+    product: String,
+    stackwalk: u32,
+    debug: u32,
+    gcpoison: u32,
+    asyncstack: u32,
+    presymbolicated: bool,
+    categories: Vec<Category>,
+    #[serde(rename = "markerSchema")]
+    marker_schema: Vec<()>,
+}
+
+#[derive(Debug, Serialize)]
+struct Category {
+    name: &'static str,
+    color: &'static str,
+    subcategories: Vec<&'static str>,
+}
+
+#[derive(Debug, Serialize)]
+struct Table<Schema, Row> {
+    schema: Schema,
+    data: Vec<Row>,
+}
+
+#[derive(Debug, Serialize)]
+struct SampleSchema {
+    stack: u32,
+    time: u32,
+    #[serde(rename = "eventDelay")]
+    event_delay: u32,
+}
+
+#[derive(Debug, Serialize)]
+struct MarkerSchema {
+    name: u32,
+    #[serde(rename = "startTime")]
+    start_time: u32,
+    #[serde(rename = "endTime")]
+    end_time: u32,
+    phase: u32,
+    category: u32,
+    data: u32,
+}
+
+#[derive(Debug, Serialize)]
+struct StackSchema {
+    prefix: u32,
+    frame: u32,
+}
+
+#[derive(Debug, Serialize)]
+struct FrameSchema {
+    location: u32,
+    #[serde(rename = "relevantForJS")]
+    relevant_for_js: u32,
+    #[serde(rename = "innerWindowID")]
+    inner_window_id: u32,
+    implementation: u32,
+    line: u32,
+    column: u32,
+    category: u32,
+    subcategory: u32,
+}
+
+// (stack, time, eventDelay)
+type SampleRow = (Option<usize>, f64, f64);
+// (prefix, frame)
+type StackRow = (Option<usize>, usize);
+// (location, relevantForJS, innerWindowID, implementation, line, column, category, subcategory)
+type FrameRow = (
+    usize,
+    bool,
+    u32,
+    Option<String>,
+    Option<u32>,
+    Option<u32>,
+    usize,
+    usize,
+);
+
+#[derive(Debug, Serialize)]
+struct Thread {
+    name: String,
+    #[serde(rename = "processType")]
+    process_type: String,
+    #[serde(rename = "processName")]
+    process_name: String,
+    pid: Pid,
+    tid: u64,
+    #[serde(rename = "registerTime")]
+    register_time: f64,
+    #[serde(rename = "unregisterTime")]
+    unregister_time: Option<f64>,
+    samples: Table<SampleSchema, SampleRow>,
+    markers: Table<MarkerSchema, ()>,
+    #[serde(rename = "stackTable")]
+    stack_table: Table<StackSchema, StackRow>,
+    #[serde(rename = "frameTable")]
+    frame_table: Table<FrameSchema, FrameRow>,
+    #[serde(rename = "stringTable")]
+    string_table: Vec<String>,
+
+    #[serde(skip)]
+    strings: HashMap<String, usize>,
+    #[serde(skip)]
+    frames: HashMap<(usize, Option<u32>, usize), usize>,
+    #[serde(skip)]
+    stacks: HashMap<StackRow, usize>,
+}
+
+impl Thread {
+    fn new(trace: &StackTrace, register_time: f64) -> Thread {
+        let name = match trace.thread_name.as_ref() {
+            Some(name) => format!("Thread {} \"{}\"", trace.format_threadid(), name),
+            None => format!("Thread {}", trace.format_threadid()),
+        };
+        let process_name = match trace.process_info.as_ref() {
+            Some(process_info) => process_info.name(),
+            None => format!("Process {}", trace.pid),
+        };
+        Thread {
+            name,
+            process_type: "default".to_owned(),
+            process_name,
+            pid: trace.pid,
+            tid: trace.os_thread_id.unwrap_or(trace.thread_id),
+            register_time,
+            unregister_time: None,
+            samples: Table {
+                schema: SampleSchema {
+                    stack: 0,
+                    time: 1,
+                    event_delay: 2,
+                },
+                data: Vec::new(),
+            },
+            markers: Table {
+                schema: MarkerSchema {
+                    name: 0,
+                    start_time: 1,
+                    end_time: 2,
+                    phase: 3,
+                    category: 4,
+                    data: 5,
+                },
+                data: Vec::new(),
+            },
+            stack_table: Table {
+                schema: StackSchema {
+                    prefix: 0,
+                    frame: 1,
+                },
+                data: Vec::new(),
+            },
+            frame_table: Table {
+                schema: FrameSchema {
+                    location: 0,
+                    relevant_for_js: 1,
+                    inner_window_id: 2,
+                    implementation: 3,
+                    line: 4,
+                    column: 5,
+                    category: 6,
+                    subcategory: 7,
+                },
+                data: Vec::new(),
+            },
+            string_table: Vec::new(),
+            strings: HashMap::new(),
+            frames: HashMap::new(),
+            stacks: HashMap::new(),
+        }
+    }
+
+    fn string_id(&mut self, s: String) -> usize {
+        let string_table = &mut self.string_table;
+        *self.strings.entry(s).or_insert_with_key(|s| {
+            string_table.push(s.clone());
+            string_table.len() - 1
+        })
+    }
+
+    fn frame_id(&mut self, frame: &Frame, show_linenumbers: bool) -> usize {
+        let filename = match &frame.short_filename {
+            Some(f) => f,
+            None => &frame.filename,
+        };
+        let location = if filename.is_empty() {
+            frame.name.clone()
+        } else {
+            format!("{} ({})", frame.name, filename)
+        };
+        let location = self.string_id(location);
+        let line = if show_linenumbers && frame.line > 0 {
+            Some(frame.line as u32)
+        } else {
+            None
+        };
+        let category = frame_category(frame);
+
+        let frame_table = &mut self.frame_table.data;
+        *self
+            .frames
+            .entry((location, line, category))
+            .or_insert_with(|| {
+                frame_table.push((location, false, 0, None, line, None, category, 0));
+                frame_table.len() - 1
+            })
+    }
+
+    fn stack_id(&mut self, prefix: Option<usize>, frame: usize) -> usize {
+        let stack_table = &mut self.stack_table.data;
+        *self.stacks.entry((prefix, frame)).or_insert_with(|| {
+            stack_table.push((prefix, frame));
+            stack_table.len() - 1
+        })
+    }
+}
+
+fn frame_category(frame: &Frame) -> usize {
+    // python frames don't have a module set, but native frames always do (or
+    // at least have a '?' filename if we couldn't symbolicate)
+    if frame.module.is_some() || frame.filename == "?" {
+        CATEGORY_NATIVE
+    } else if frame.filename.is_empty() {
+        // process and thread pseudo-frames
+        CATEGORY_OTHER
+    } else {
+        CATEGORY_PYTHON
+    }
+}
+
+pub struct Gecko {
+    threads: HashMap<(Pid, u64), Thread>,
+    start_ts: Instant,
+    start_time: SystemTime,
+    show_linenumbers: bool,
+    sampling_rate: u64,
+}
+
+impl Gecko {
+    pub fn new(show_linenumbers: bool, sampling_rate: u64) -> Gecko {
+        Gecko {
+            threads: HashMap::new(),
+            start_ts: Instant::now(),
+            start_time: SystemTime::now(),
+            show_linenumbers,
+            sampling_rate,
+        }
+    }
+
+    pub fn increment(&mut self, trace: &StackTrace) -> std::io::Result<()> {
+        let now = self.start_ts.elapsed().as_micros() as u64;
+        self.increment_at(trace, now)
+    }
+
+    /// Records a trace sampled at a given time, in microseconds since the start of the recording
+    pub fn increment_at(&mut self, trace: &StackTrace, timestamp: u64) -> std::io::Result<()> {
+        let now = timestamp as f64 / 1000.0;
+        let show_linenumbers = self.show_linenumbers;
+
+        let thread = self
+            .threads
+            .entry((trace.pid, trace.thread_id))
+            .or_insert_with(|| Thread::new(trace, now));
+
+        let mut stack = None;
+        for frame in trace.frames.iter().rev() {
+            let frame_id = thread.frame_id(frame, show_linenumbers);
+            stack = Some(thread.stack_id(stack, frame_id));
+        }
+        thread.samples.data.push((stack, now, 0.0));
+        Ok(())
+    }
+
+    pub fn write(&self, w: &mut dyn Write) -> Result<(), Error> {
+        let start_time = self
+            .start_time
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs_f64() * 1000.0)
+            .unwrap_or(0.0);
+
+        let mut threads: Vec<&Thread> = self.threads.values().collect();
+        threads.sort_by(|a, b| (a.pid, &a.name).cmp(&(b.pid, &b.name)));
+
+        let profile = GeckoProfile {
+            meta: Meta {
+                version: 24,
+                start_time,
+                shutdown_time: None,
+                interval: 1000.0 / self.sampling_rate as f64,
+                process_type: 0,
+                product: format!("py-spy@{}", env!("CARGO_PKG_VERSION")),
+                stackwalk: 1,
+                debug: 0,
+                gcpoison: 0,
+                asyncstack: 0,
+                presymbolicated: true,
+                categories: vec![
+                    Category {
+                        name: "Other",
+                        color: "grey",
+                        subcategories: vec!["Other"],
+                    },
+                    Category {
+                        name: "Python",
+                        color: "blue",
+                        subcategories: vec!["Other"],
+                    },
+                    Category {
+                        name: "Native",
+                        color: "orange",
+                        subcategories: vec!["Other"],
+                    },
+                ],
+                marker_schema: Vec::new(),
+            },
+            libs: Vec::new(),
+            threads,
+            processes: Vec::new(),
+            paused_ranges: Vec::new(),
+        };
+
+        writeln!(w, "{}", serde_json::to_string(&profile)?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{frame, trace};
+
+    #[test]
+    fn test_gecko_stack_table() {
+        let mut gecko = Gecko::new(true, 100);
+        let mut trace = trace(vec![
+            {
+                let mut f = frame("leaf", "libfoo.so", 1);
+                f.module = Some(String::from("libfoo.so"));
+                f
+            },
+            frame("outer", "test.py", 1),
+        ]);
+        trace.thread_name = Some("MainThread".to_owned());
+        gecko.increment(&trace).unwrap();
+        trace.frames.remove(0);
+        gecko.increment(&trace).unwrap();
+
+        let thread = &gecko.threads[&(1, 1)];
+        // the second sample should re-use the root stack entry from the first
+        assert_eq!(thread.stack_table.data, vec![(None, 0), (Some(0), 1)]);
+        assert_eq!(thread.samples.data[0].0, Some(1));
+        assert_eq!(thread.samples.data[1].0, Some(0));
+        assert_eq!(thread.frame_table.data[0].6, CATEGORY_PYTHON);
+        assert_eq!(thread.frame_table.data[1].6, CATEGORY_NATIVE);
+
+        let mut out = Vec::new();
+        gecko.write(&mut out).unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(value["threads"][0]["name"], "Thread 0x1 \"MainThread\"");
+        assert_eq!(value["meta"]["interval"], 10.0);
+    }
+}
@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+use std::io::Write;
+
+use anyhow::Error;
+use serde_derive::Serialize;
+
+use crate::stack_trace::StackTrace;
+
+/*
+ * This file contains code to write out a standalone HTML flamegraph. The aggregated samples
+ * are embedded as JSON in the same {name, value, children} layout that d3-flamegraph uses,
+ * along with a small viewer (src/templates/flamegraph.html) that supports zooming and
+ * searching, without needing to load anything from the network.
+ */
+
+const TEMPLATE: &str = include_str!("templates/flamegraph.html");
+
+#[derive(Debug, Default, Serialize)]
+struct Node {
+    name: String,
+    value: usize,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    children: Vec<Node>,
+    #[serde(skip)]
+    child_index: HashMap<String, usize>,
+}
+
+impl Node {
+    fn child(&mut self, name: String) -> &mut Node {
+        let children = &mut self.children;
+        let index = *self.child_index.entry(name).or_insert_with_key(|name| {
+            children.push(Node {
+                name: name.clone(),
+                ..Default::default()
+            });
+            children.len() - 1
+        });
+        &mut self.children[index]
+    }
+}
+
+pub struct HtmlFlamegraph {
+    root: Node,
+    show_linenumbers: bool,
+}
+
+impl HtmlFlamegraph {
+    pub fn new(show_linenumbers: bool) -> HtmlFlamegraph {
+        HtmlFlamegraph {
+            root: Node {
+                name: String::from("all"),
+                ..Default::default()
+            },
+            show_linenumbers,
+        }
+    }
+
+    pub fn increment(&mut self, trace: &StackTrace) -> std::io::Result<()> {
+        let mut node = &mut self.root;
+        node.value += 1;
+        for frame in trace.frames.iter().rev() {
+            let filename = match &frame.short_filename {
+                Some(f) => f,
+                None => &frame.filename,
+            };
+            let name = if self.show_linenumbers && frame.line != 0 {
+                format!("{} ({}:{})", frame.name, filename, frame.line)
+            } else if !filename.is_empty() {
+                format!("{} ({})", frame.name, filename)
+            } else {
+                frame.name.clone()
+            };
+            node = node.child(name);
+            node.value += 1;
+        }
+        Ok(())
+    }
+
+    /// The tree of samples, in the {name, value, children} layout the viewer draws
+    pub fn tree(&self) -> Result<serde_json::Value, Error> {
+        Ok(serde_json::to_value(&self.root)?)
+    }
+
+    pub fn write(&self, w: &mut dyn Write) -> Result<(), Error> {
+        // escape '<' so that function names can't close the script tag we're embedding in
+        let data = serde_json::to_string(&self.root)?.replace('<', "\\u003c");
+        let title = std::env::args()
+            .collect::<Vec<String>>()
+            .join(" ")
+            .replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;");
+        let html = TEMPLATE
+            .replace("{{TITLE}}", &title)
+            .replace(
+                "{{EXPORTER}}",
+                &format!("py-spy@{}", env!("CARGO_PKG_VERSION")),
+            )
+            .replace("{{DATA}}", &data);
+        w.write_all(html.as_bytes())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{frame, trace};
+
+    #[test]
+    fn test_html_tree() {
+        let mut html = HtmlFlamegraph::new(false);
+        let mut trace = trace(vec![
+            frame("<lambda>", "test.py", 1),
+            frame("main", "test.py", 1),
+        ]);
+        html.increment(&trace).unwrap();
+        trace.frames[0].name = String::from("other");
+        html.increment(&trace).unwrap();
+
+        assert_eq!(html.root.value, 2);
+        assert_eq!(html.root.children.len(), 1);
+        let main = &html.root.children[0];
+        assert_eq!(main.name, "main (test.py)");
+        assert_eq!(main.value, 2);
+        assert_eq!(main.children.len(), 2);
+
+        let mut out = Vec::new();
+        html.write(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("\\u003clambda> (test.py)"));
+        assert!(!out.contains("{{DATA}}"));
+    }
+}
@@ -0,0 +1,377 @@
+use std::collections::HashMap;
+use std::io::{BufRead, Read, Write};
+use std::net::TcpListener;
+
+use anyhow::{Context, Error};
+use base64::Engine;
+
+/*
+ * This file contains a minimal HTTP/1.1 server implementation, shared by 'py-spy agent' and
+ * 'top --serve'. It only supports what those need: reading a single request per connection
+ * (responses are always sent with 'Connection: close'), and upgrading a connection to a
+ * websocket that the server pushes text messages to.
+ */
+
+/// The largest request body that's read
+const MAX_BODY_SIZE: usize = 1 << 20;
+
+/// The longest the request line and headers can be, in total and for each line. These are read
+/// before the request is authorized, so they're kept small
+const MAX_HEADER_SIZE: u64 = 64 << 10;
+const MAX_HEADER_LINE: usize = 8 << 10;
+
+/// The GUID that websocket handshakes are hashed with, from RFC 6455
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// The error returned when the headers of a request are longer than MAX_HEADER_SIZE, or one of
+/// them is longer than MAX_HEADER_LINE
+#[derive(Debug)]
+pub struct HeadersTooLarge;
+
+impl std::fmt::Display for HeadersTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Request headers are too large")
+    }
+}
+
+impl std::error::Error for HeadersTooLarge {}
+
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub query: HashMap<String, String>,
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub headers: Vec<(&'static str, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn json<T: serde::Serialize>(status: u16, value: &T) -> Result<Response, Error> {
+        Ok(Response {
+            status,
+            content_type: "application/json",
+            headers: Vec::new(),
+            body: serde_json::to_vec_pretty(value)?,
+        })
+    }
+
+    pub fn new(status: u16, content_type: &'static str, body: Vec<u8>) -> Response {
+        Response {
+            status,
+            content_type,
+            headers: Vec::new(),
+            body,
+        }
+    }
+
+    pub fn error(status: u16, message: &str) -> Response {
+        let body = serde_json::json!({ "error": message });
+        Response {
+            status,
+            content_type: "application/json",
+            headers: Vec::new(),
+            body: body.to_string().into_bytes(),
+        }
+    }
+
+    /// The response to a request that couldn't be read
+    pub fn bad_request(e: &Error) -> Response {
+        let status = if e.is::<HeadersTooLarge>() { 431 } else { 400 };
+        Response::error(status, &e.to_string())
+    }
+
+    pub fn write(&self, w: &mut dyn Write) -> std::io::Result<()> {
+        write!(w, "HTTP/1.1 {} {}\r\n", self.status, reason(self.status))?;
+        write!(w, "Content-Type: {}\r\n", self.content_type)?;
+        write!(w, "Content-Length: {}\r\n", self.body.len())?;
+        for (name, value) in &self.headers {
+            write!(w, "{}: {}\r\n", name, value)?;
+        }
+        write!(w, "Connection: close\r\n\r\n")?;
+        w.write_all(&self.body)?;
+        w.flush()
+    }
+}
+
+/// Listens on an address, like '127.0.0.1:8080', or ':8080' for a port on all interfaces
+pub fn bind(address: &str) -> Result<TcpListener, Error> {
+    let address = match address.strip_prefix(':') {
+        Some(port) => format!("0.0.0.0:{}", port),
+        None => address.to_owned(),
+    };
+    TcpListener::bind(&address).with_context(|| format!("Failed to listen on {}", address))
+}
+
+pub fn read_request(reader: &mut dyn BufRead) -> Result<Request, Error> {
+    let mut head = (&mut *reader).take(MAX_HEADER_SIZE);
+    let line = read_header_line(&mut head)?;
+    let mut parts = line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version)) if version.starts_with("HTTP/1.") => {
+            (method.to_owned(), target.to_owned())
+        }
+        _ => return Err(format_err!("Invalid request line '{}'", line.trim())),
+    };
+
+    let mut headers = HashMap::new();
+    loop {
+        let line = read_header_line(&mut head)?;
+        if line.is_empty() {
+            return Err(format_err!(
+                "Connection closed before the end of the headers"
+            ));
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| format_err!("Invalid header '{}'", line))?;
+        headers.insert(name.trim().to_lowercase(), value.trim().to_owned());
+    }
+
+    let length: usize = match headers.get("content-length") {
+        Some(length) => length.parse().context("Invalid Content-Length")?,
+        None => 0,
+    };
+    if length > MAX_BODY_SIZE {
+        return Err(format_err!("Request body is too large"));
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+
+    let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+    let query = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (percent_decode(name), percent_decode(value))
+        })
+        .collect();
+    Ok(Request {
+        method,
+        path: path.to_owned(),
+        query,
+        headers,
+        body,
+    })
+}
+
+/// Reads the request line or a header, failing with HeadersTooLarge if it doesn't end before
+/// the limit on the size of the headers, or is longer than MAX_HEADER_LINE
+fn read_header_line<R: BufRead>(head: &mut std::io::Take<R>) -> Result<String, Error> {
+    let mut line = String::new();
+    head.read_line(&mut line)?;
+    if line.len() > MAX_HEADER_LINE || (!line.ends_with('\n') && head.limit() == 0) {
+        return Err(HeadersTooLarge.into());
+    }
+    Ok(line)
+}
+
+/// Decodes a query string parameter, like 'a%20b' or 'a+b' for 'a b'
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or_default();
+                match u8::from_str_radix(hex, 16) {
+                    Ok(byte) => {
+                        decoded.push(byte);
+                        i += 2;
+                    }
+                    Err(_) => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        431 => "Request Header Fields Too Large",
+        _ => "Internal Server Error",
+    }
+}
+
+/// Whether a request is asking to upgrade its connection to a websocket
+pub fn is_websocket_upgrade(request: &Request) -> bool {
+    let header_has = |name: &str, value: &str| {
+        request.headers.get(name).is_some_and(|header| {
+            header
+                .split(',')
+                .any(|part| part.trim().eq_ignore_ascii_case(value))
+        })
+    };
+    request.method == "GET"
+        && header_has("upgrade", "websocket")
+        && header_has("connection", "upgrade")
+        && request.headers.contains_key("sec-websocket-key")
+}
+
+/// Completes the handshake for a websocket upgrade request, after which messages can be sent
+/// with write_websocket_text
+pub fn accept_websocket(request: &Request, w: &mut dyn Write) -> std::io::Result<()> {
+    let key = request
+        .headers
+        .get("sec-websocket-key")
+        .map(String::as_str)
+        .unwrap_or_default();
+    write!(
+        w,
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        websocket_accept(key)
+    )?;
+    w.flush()
+}
+
+/// Sends a text message to a websocket client. Messages from servers aren't masked, and are
+/// sent as a single frame
+pub fn write_websocket_text(w: &mut dyn Write, message: &str) -> std::io::Result<()> {
+    let length = message.len();
+    // FIN with the text opcode
+    let mut header = vec![0x81];
+    if length < 126 {
+        header.push(length as u8);
+    } else if length <= u16::MAX as usize {
+        header.push(126);
+        header.extend_from_slice(&(length as u16).to_be_bytes());
+    } else {
+        header.push(127);
+        header.extend_from_slice(&(length as u64).to_be_bytes());
+    }
+    w.write_all(&header)?;
+    w.write_all(message.as_bytes())?;
+    w.flush()
+}
+
+fn websocket_accept(key: &str) -> String {
+    let digest = sha1(format!("{}{}", key.trim(), WEBSOCKET_GUID).as_bytes());
+    base64::engine::general_purpose::STANDARD.encode(digest)
+}
+
+/// SHA-1, which is only used for the websocket handshake (and isn't relied on for security)
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for block in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (h, x) in h.iter_mut().zip([a, b, c, d, e]) {
+            *h = h.wrapping_add(x);
+        }
+    }
+
+    let mut digest = [0; 20];
+    for (i, h) in h.iter().enumerate() {
+        digest[i * 4..i * 4 + 4].copy_from_slice(&h.to_be_bytes());
+    }
+    digest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_request() {
+        let raw = b"GET /dump?pid=12&name=a%20b+c HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer secret\r\n\r\n";
+        let request = read_request(&mut &raw[..]).unwrap();
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.path, "/dump");
+        assert_eq!(request.query["pid"], "12");
+        assert_eq!(request.query["name"], "a b c");
+        assert_eq!(request.headers["authorization"], "Bearer secret");
+        assert!(request.body.is_empty());
+        assert!(!is_websocket_upgrade(&request));
+
+        assert!(read_request(&mut &b"GET /\r\n\r\n"[..]).is_err());
+        assert!(read_request(&mut &b"GET / HTTP/1.1\r\nHost: localhost\r\n"[..]).is_err());
+
+        // headers that are too long are rejected without reading all of them
+        let long_header = format!("GET / HTTP/1.1\r\nCookie: {}\r\n\r\n", "a".repeat(10000));
+        let e = read_request(&mut long_header.as_bytes()).err().unwrap();
+        assert_eq!(Response::bad_request(&e).status, 431);
+        let many_headers = format!("GET / HTTP/1.1\r\n{}\r\n", "A: b\r\n".repeat(20000));
+        let e = read_request(&mut many_headers.as_bytes()).err().unwrap();
+        assert_eq!(Response::bad_request(&e).status, 431);
+        let e = read_request(&mut &b"GET /\r\n\r\n"[..]).err().unwrap();
+        assert_eq!(Response::bad_request(&e).status, 400);
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz%41"), "%zzA");
+    }
+
+    #[test]
+    fn test_websocket() {
+        // the example handshake from RFC 6455
+        let raw = b"GET /live HTTP/1.1\r\nUpgrade: websocket\r\nConnection: keep-alive, Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n";
+        let request = read_request(&mut &raw[..]).unwrap();
+        assert!(is_websocket_upgrade(&request));
+        let mut handshake = Vec::new();
+        accept_websocket(&request, &mut handshake).unwrap();
+        let handshake = String::from_utf8(handshake).unwrap();
+        assert!(handshake.starts_with("HTTP/1.1 101 "));
+        assert!(handshake.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+
+        let mut frame = Vec::new();
+        write_websocket_text(&mut frame, "hello").unwrap();
+        assert_eq!(frame, b"\x81\x05hello");
+        let mut frame = Vec::new();
+        write_websocket_text(&mut frame, &"a".repeat(300)).unwrap();
+        assert_eq!(&frame[..4], &[0x81, 126, 1, 44]);
+        assert_eq!(frame.len(), 304);
+    }
+}
@@ -0,0 +1,89 @@
+use crate::frame_category::module_path;
+use crate::stack_trace::{Frame, StackTrace};
+
+/*
+ * This file contains code for 'record --importtime', which profiles the imports a program runs
+ * when it starts up.
+ *
+ * On python 3, every import runs through importlib, whose frames are on the stack of the thread
+ * doing the import (as '<frozen importlib._bootstrap>' frames). Recording stops at the first
+ * sample where a thread is running the __main__ module without importing anything, which is
+ * when the program proper starts.
+ *
+ * To make the flamegraph read like an import tree, importlib's own frames are left out, and the
+ * '<module>' frame of each module being imported is renamed to 'import <module>', so that each
+ * module shows up under the module that imported it.
+ */
+
+/// Whether a frame is part of importlib's machinery for importing modules
+fn is_import_frame(frame: &Frame) -> bool {
+    let filename = frame.filename.replace('\\', "/");
+    filename.starts_with("<frozen importlib._bootstrap")
+        || filename.ends_with("importlib/_bootstrap.py")
+        || filename.ends_with("importlib/_bootstrap_external.py")
+}
+
+/// Whether any thread is running the __main__ module, rather than importing modules
+pub fn main_started(traces: &[StackTrace]) -> bool {
+    traces.iter().any(|trace| {
+        trace.frames.iter().any(|frame| frame.name == "<module>")
+            && !trace.frames.iter().any(is_import_frame)
+    })
+}
+
+/// Leaves out importlib's frames, and names the frame of each module being imported after the
+/// module
+pub fn import_frames(trace: &mut StackTrace) {
+    // frames are innermost first, so a module is being imported if the frame after it is
+    // importlib's
+    let imported: Vec<bool> = (0..trace.frames.len())
+        .map(|i| {
+            trace.frames[i].name == "<module>"
+                && trace.frames.get(i + 1).is_some_and(is_import_frame)
+        })
+        .collect();
+    for (frame, imported) in trace.frames.iter_mut().zip(imported) {
+        if imported {
+            let module = module_path(frame).unwrap_or_else(|| frame.filename.clone());
+            frame.name = format!("import {}", module);
+        }
+    }
+    trace.frames.retain(|frame| !is_import_frame(frame));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{frame, trace};
+
+    #[test]
+    fn test_importtime() {
+        let bootstrap = "<frozen importlib._bootstrap>";
+        let mut importing = trace(vec![
+            frame("compile", "re/_compiler.py", 1),
+            frame("<module>", "json/decoder.py", 1),
+            frame("_call_with_frames_removed", bootstrap, 1),
+            frame("_find_and_load", bootstrap, 1),
+            frame("<module>", "json/__init__.py", 1),
+            frame("exec_module", "<frozen importlib._bootstrap_external>", 1),
+            frame("_find_and_load", bootstrap, 1),
+            frame("<module>", "app.py", 1),
+        ]);
+        assert!(!main_started(&[importing.clone()]));
+
+        import_frames(&mut importing);
+        let names: Vec<&str> = importing.frames.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(
+            names,
+            vec!["compile", "import json.decoder", "import json", "<module>"]
+        );
+
+        // startup before any python code runs, and the main module running
+        assert!(!main_started(&[trace(Vec::new())]));
+        let running = trace(vec![
+            frame("main", "app.py", 1),
+            frame("<module>", "app.py", 1),
+        ]);
+        assert!(main_started(&[running]));
+    }
+}
@@ -0,0 +1,386 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use anyhow::Error;
+use remoteprocess::{Pid, StackFrame};
+
+/*
+ * This file contains code to symbolize the native frames of code generated at runtime by JIT
+ * compilers (like Numba, or LLVM based JITs in general), which isn't part of any binary on disk
+ * and so can't be symbolized from a binary's symbol table or debug info.
+ *
+ * JITs that support profiling with perf describe the code they generate in one of two ways:
+ *
+ *  - a perf map file at /tmp/perf-<pid>.map, with a 'START SIZE name' line (in hex) for each
+ *    function. Python 3.12+ writes these with '-X perf', naming its trampolines 'py::name:file'.
+ *  - a jitdump file named jit-<pid>.dump, which is a binary log of the functions that were loaded
+ *    and moved, along with the source lines they were generated from. The JIT maps the file into
+ *    its memory so that perf can find it, which is how it's found here too.
+ *
+ * Both files are appended to as code is generated, so they're read again when an address that
+ * isn't in either is seen (at most every RELOAD_INTERVAL).
+ */
+
+/// The most often the JIT symbol files are read again, when an address can't be symbolized
+const RELOAD_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The module name given to frames in JIT generated code
+pub const JIT_MODULE: &str = "[jit]";
+
+const JITDUMP_MAGIC: u32 = 0x4A695444;
+const JIT_CODE_LOAD: u32 = 0;
+const JIT_CODE_MOVE: u32 = 1;
+const JIT_CODE_DEBUG_INFO: u32 = 2;
+
+#[derive(Debug, Clone, PartialEq)]
+struct JitSymbol {
+    size: u64,
+    name: String,
+    // (address, filename, line) of the source lines the code was generated from, by address
+    lines: Vec<(u64, String, u64)>,
+}
+
+/// The functions generated by the JITs in a process, from its perf map and jitdump files
+pub struct JitSymbols {
+    pid: Pid,
+    // the start address of each function
+    symbols: BTreeMap<u64, JitSymbol>,
+    // the files that have been read, and how long each was when it was
+    sources: Vec<(PathBuf, u64)>,
+    last_load: Option<Instant>,
+}
+
+impl JitSymbols {
+    pub fn new(pid: Pid) -> JitSymbols {
+        let mut symbols = JitSymbols {
+            pid,
+            symbols: BTreeMap::new(),
+            sources: Vec::new(),
+            last_load: None,
+        };
+        symbols.reload();
+        symbols
+    }
+
+    /// Symbolizes an address in JIT generated code, reading the JIT's files again if it isn't in
+    /// them yet
+    pub fn lookup(&mut self, addr: u64) -> Option<StackFrame> {
+        if let Some(frame) = self.find(addr) {
+            return Some(frame);
+        }
+        if self
+            .last_load
+            .is_some_and(|last| last.elapsed() < RELOAD_INTERVAL)
+        {
+            return None;
+        }
+        if self.reload() {
+            return self.find(addr);
+        }
+        None
+    }
+
+    fn find(&self, addr: u64) -> Option<StackFrame> {
+        let (start, symbol) = self.symbols.range(..=addr).next_back()?;
+        if addr >= start.saturating_add(symbol.size) {
+            return None;
+        }
+        let line = symbol
+            .lines
+            .iter()
+            .take_while(|(line_addr, _, _)| *line_addr <= addr)
+            .last();
+        Some(StackFrame {
+            line: line.map(|(_, _, line)| *line),
+            filename: line.map(|(_, filename, _)| filename.clone()),
+            function: Some(symbol.name.clone()),
+            module: JIT_MODULE.to_owned(),
+            addr,
+        })
+    }
+
+    /// Reads any of the JIT's files that have changed since they were last read, returning true
+    /// if any had
+    fn reload(&mut self) -> bool {
+        self.last_load = Some(Instant::now());
+        let mut changed = false;
+        for path in self.source_paths() {
+            let len = match std::fs::metadata(&path) {
+                Ok(metadata) => metadata.len(),
+                Err(_) => continue,
+            };
+            if self.sources.contains(&(path.clone(), len)) {
+                continue;
+            }
+            if let Err(e) = self.load(&path) {
+                info!("Failed to read JIT symbols from {}: {}", path.display(), e);
+                continue;
+            }
+            self.sources.retain(|(source, _)| source != &path);
+            self.sources.push((path, len));
+            changed = true;
+        }
+        changed
+    }
+
+    fn load(&mut self, path: &Path) -> Result<(), Error> {
+        let data = std::fs::read(path)?;
+        let symbols = if data.starts_with(&JITDUMP_MAGIC.to_ne_bytes()) {
+            parse_jitdump(&data)?
+        } else {
+            parse_perf_map(&String::from_utf8_lossy(&data))
+        };
+        info!("Read {} JIT symbols from {}", symbols.len(), path.display());
+        self.symbols.extend(symbols);
+        Ok(())
+    }
+
+    /// The perf map and jitdump files of the process that exist
+    fn source_paths(&self) -> Vec<PathBuf> {
+        let mut paths = vec![PathBuf::from(format!("/tmp/perf-{}.map", self.pid))];
+        #[cfg(target_os = "linux")]
+        {
+            let filename = format!("jit-{}.dump", self.pid);
+            let maps = proc_maps::get_process_maps(self.pid).unwrap_or_default();
+            for map in maps {
+                let path = match map.filename() {
+                    Some(path) if path.file_name() == Some(filename.as_ref()) => path,
+                    _ => continue,
+                };
+                // the path is in the mount namespace of the process, which might not be ours
+                let root = Path::new("/proc")
+                    .join(self.pid.to_string())
+                    .join("root")
+                    .join(path.strip_prefix("/").unwrap_or(path));
+                let path = if root.exists() { root } else { path.to_owned() };
+                if !paths.contains(&path) {
+                    paths.push(path);
+                }
+            }
+        }
+        paths.retain(|path| path.exists());
+        paths
+    }
+}
+
+/// Whether a JIT symbol is one of the trampolines python 3.12+ runs each python function through
+/// with '-X perf'. The python functions themselves are already on the python stack
+pub fn is_python_trampoline(function: &str) -> bool {
+    function.starts_with("py::")
+}
+
+/// Parses the lines of a perf map file, like '7f5a3c001000 1a0 add_numbers'
+fn parse_perf_map(contents: &str) -> Vec<(u64, JitSymbol)> {
+    let parse_hex = |value: &str| u64::from_str_radix(value.trim_start_matches("0x"), 16).ok();
+    contents
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.trim().splitn(3, ' ');
+            let start = parse_hex(parts.next()?)?;
+            let size = parse_hex(parts.next()?)?;
+            let name = parts.next()?.trim();
+            if name.is_empty() {
+                return None;
+            }
+            let symbol = JitSymbol {
+                size,
+                name: name.to_owned(),
+                lines: Vec::new(),
+            };
+            Some((start, symbol))
+        })
+        .collect()
+}
+
+/// Reads the fields of a jitdump record, which are in the byte order of the machine it was
+/// written on (which the magic number at the start of the file is checked to match)
+struct RecordReader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> RecordReader<'a> {
+    fn u32(&mut self) -> Option<u32> {
+        let bytes = self.data.get(self.offset..self.offset + 4)?;
+        self.offset += 4;
+        Some(u32::from_ne_bytes(bytes.try_into().ok()?))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        let bytes = self.data.get(self.offset..self.offset + 8)?;
+        self.offset += 8;
+        Some(u64::from_ne_bytes(bytes.try_into().ok()?))
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let rest = self.data.get(self.offset..)?;
+        let length = rest.iter().position(|b| *b == 0)?;
+        self.offset += length + 1;
+        Some(String::from_utf8_lossy(&rest[..length]).into_owned())
+    }
+}
+
+/// Parses the functions loaded in a jitdump file. Records that were only partly written when the
+/// file was read are left out
+fn parse_jitdump(data: &[u8]) -> Result<Vec<(u64, JitSymbol)>, Error> {
+    let mut header = RecordReader { data, offset: 0 };
+    if header.u32() != Some(JITDUMP_MAGIC) {
+        return Err(format_err!("Not a jitdump file"));
+    }
+    let _version = header.u32();
+    let header_size = header
+        .u32()
+        .ok_or_else(|| format_err!("Truncated jitdump header"))? as usize;
+
+    let mut symbols: BTreeMap<u64, JitSymbol> = BTreeMap::new();
+    // the source lines of a function come in the record before the one that loads it
+    let mut pending_lines: BTreeMap<u64, Vec<(u64, String, u64)>> = BTreeMap::new();
+    let mut offset = header_size;
+    while offset + 16 <= data.len() {
+        let mut record = RecordReader { data, offset };
+        let (id, size) = match (record.u32(), record.u32()) {
+            (Some(id), Some(size)) if size >= 16 && offset + size as usize <= data.len() => {
+                (id, size as usize)
+            }
+            _ => break,
+        };
+        let _timestamp = record.u64();
+        record.data = &data[..offset + size];
+
+        match id {
+            JIT_CODE_LOAD => {
+                let (_pid, _tid, _vma) = (record.u32(), record.u32(), record.u64());
+                let fields = (record.u64(), record.u64(), record.u64(), record.string());
+                if let (Some(code_addr), Some(code_size), Some(_index), Some(name)) = fields {
+                    let symbol = JitSymbol {
+                        size: code_size,
+                        name,
+                        lines: pending_lines.remove(&code_addr).unwrap_or_default(),
+                    };
+                    symbols.insert(code_addr, symbol);
+                }
+            }
+            JIT_CODE_MOVE => {
+                let (_pid, _tid, _vma) = (record.u32(), record.u32(), record.u64());
+                if let (Some(old_addr), Some(new_addr)) = (record.u64(), record.u64()) {
+                    if let Some(mut symbol) = symbols.remove(&old_addr) {
+                        for line in symbol.lines.iter_mut() {
+                            line.0 = line.0.wrapping_sub(old_addr).wrapping_add(new_addr);
+                        }
+                        symbols.insert(new_addr, symbol);
+                    }
+                }
+            }
+            JIT_CODE_DEBUG_INFO => {
+                let (code_addr, entries) = match (record.u64(), record.u64()) {
+                    (Some(code_addr), Some(entries)) => (code_addr, entries),
+                    _ => break,
+                };
+                let mut lines: Vec<(u64, String, u64)> = Vec::new();
+                for _ in 0..entries {
+                    let (addr, line, _discriminator) = (record.u64(), record.u32(), record.u32());
+                    let (addr, line) = match (addr, line) {
+                        (Some(addr), Some(line)) => (addr, line as u64),
+                        _ => break,
+                    };
+                    // a filename of 0xff means the same file as the entry before
+                    let filename = match record.data.get(record.offset) {
+                        Some(0xff) => {
+                            record.offset += 2;
+                            lines.last().map(|(_, filename, _)| filename.clone())
+                        }
+                        _ => record.string(),
+                    };
+                    match filename {
+                        Some(filename) => lines.push((addr, filename, line)),
+                        None => break,
+                    }
+                }
+                lines.sort_by_key(|(addr, _, _)| *addr);
+                pending_lines.insert(code_addr, lines);
+            }
+            // the close and unwinding info records don't change the functions
+            _ => {}
+        }
+        offset += size;
+    }
+    Ok(symbols.into_iter().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_perf_map() {
+        let symbols = parse_perf_map(
+            "7f5a3c001000 1a0 _ZN8__main__3addB2v1Edd\n\
+             0x7f5a3c002000 0x40 py::work:/tmp/hot.py\n\
+             garbage\n",
+        );
+        assert_eq!(symbols.len(), 2);
+        assert_eq!(symbols[0].0, 0x7f5a3c001000);
+        assert_eq!(symbols[0].1.size, 0x1a0);
+        assert_eq!(symbols[0].1.name, "_ZN8__main__3addB2v1Edd");
+        assert_eq!(symbols[1].0, 0x7f5a3c002000);
+        assert!(is_python_trampoline(&symbols[1].1.name));
+    }
+
+    fn record(id: u32, body: &[u8]) -> Vec<u8> {
+        let mut record = Vec::new();
+        record.extend(id.to_ne_bytes());
+        record.extend((16 + body.len() as u32).to_ne_bytes());
+        record.extend(0u64.to_ne_bytes());
+        record.extend(body);
+        record
+    }
+
+    #[test]
+    fn test_parse_jitdump() {
+        let mut data = Vec::new();
+        data.extend(JITDUMP_MAGIC.to_ne_bytes());
+        data.extend(1u32.to_ne_bytes());
+        data.extend(40u32.to_ne_bytes());
+        data.resize(40, 0);
+
+        let mut debug_info = Vec::new();
+        debug_info.extend(0x1000u64.to_ne_bytes());
+        debug_info.extend(2u64.to_ne_bytes());
+        debug_info.extend(0x1000u64.to_ne_bytes());
+        debug_info.extend(10u32.to_ne_bytes());
+        debug_info.extend(0u32.to_ne_bytes());
+        debug_info.extend(b"kernels.py\0");
+        debug_info.extend(0x1010u64.to_ne_bytes());
+        debug_info.extend(12u32.to_ne_bytes());
+        debug_info.extend(0u32.to_ne_bytes());
+        debug_info.extend(b"\xff\0");
+        data.extend(record(JIT_CODE_DEBUG_INFO, &debug_info));
+
+        let mut load = Vec::new();
+        load.extend(1234u32.to_ne_bytes());
+        load.extend(1234u32.to_ne_bytes());
+        for value in [0x1000u64, 0x1000, 0x20, 1] {
+            load.extend(value.to_ne_bytes());
+        }
+        load.extend(b"add\0");
+        data.extend(record(JIT_CODE_LOAD, &load));
+        // a record that's still being written is left out
+        data.extend(&record(JIT_CODE_LOAD, &load)[..20]);
+
+        let mut jit = JitSymbols {
+            pid: 1234,
+            symbols: parse_jitdump(&data).unwrap().into_iter().collect(),
+            sources: Vec::new(),
+            last_load: Some(Instant::now()),
+        };
+        let frame = jit.lookup(0x1014).unwrap();
+        assert_eq!(frame.function.as_deref(), Some("add"));
+        assert_eq!(frame.filename.as_deref(), Some("kernels.py"));
+        assert_eq!(frame.line, Some(12));
+        assert_eq!(frame.module, JIT_MODULE);
+        assert_eq!(jit.lookup(0x1008).unwrap().line, Some(10));
+        assert!(jit.lookup(0x1020).is_none());
+        assert!(jit.lookup(0xfff).is_none());
+    }
+}
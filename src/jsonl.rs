@@ -0,0 +1,175 @@
+use std::io::Write;
+use std::time::Instant;
+
+use anyhow::Error;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::sampler_stats::SamplerStats;
+use crate::stack_trace::StackTrace;
+
+/*
+ * This file contains code to write out samples as JSON lines: a single JSON object per sample,
+ * written out as soon as the sample is taken. This lets the output be piped into other tools
+ * (like jq) while the recording is still in progress.
+ *
+ * When recording over --ssh, the remote py-spy also ends the output with a line holding the
+ * statistics of its sampler, so that the local py-spy can report how many samples failed.
+ */
+
+#[derive(Serialize)]
+struct Sample<'a> {
+    /// Time the sample was taken, in microseconds since the start of the recording
+    timestamp: u64,
+    #[serde(flatten)]
+    trace: &'a StackTrace,
+}
+
+// Sample, owning the trace, for reading samples back in
+#[derive(Deserialize)]
+struct OwnedSample {
+    timestamp: u64,
+    #[serde(flatten)]
+    trace: StackTrace,
+}
+
+// the last line of the output, with --stream-stats
+#[derive(Serialize, Deserialize)]
+struct Stats {
+    stats: SamplerStats,
+}
+
+/// Reads a sample written out by JsonLines, returning its timestamp and stack trace
+pub fn read_sample(line: &str) -> Result<(u64, StackTrace), Error> {
+    let sample: OwnedSample = serde_json::from_str(line)?;
+    Ok((sample.timestamp, sample.trace))
+}
+
+/// Reads the statistics of the sampler, if a line written out by JsonLines has them
+pub fn read_stats(line: &str) -> Option<SamplerStats> {
+    serde_json::from_str::<Stats>(line).ok().map(|s| s.stats)
+}
+
+pub struct JsonLines {
+    output: Box<dyn Write>,
+    start_ts: Instant,
+    bytes: u64,
+    /// Whether to end the output with the statistics of the sampler
+    pub write_stats: bool,
+}
+
+impl JsonLines {
+    pub fn new(output: Box<dyn Write>) -> JsonLines {
+        JsonLines {
+            output,
+            start_ts: Instant::now(),
+            bytes: 0,
+            write_stats: false,
+        }
+    }
+
+    pub fn increment(&mut self, trace: &StackTrace) -> Result<(), Error> {
+        let now = self.start_ts.elapsed().as_micros() as u64;
+        self.increment_at(trace, now)
+    }
+
+    /// The number of bytes written so far
+    pub fn size(&self) -> u64 {
+        self.bytes
+    }
+
+    pub fn increment_at(&mut self, trace: &StackTrace, timestamp: u64) -> Result<(), Error> {
+        self.write_line(&Sample { timestamp, trace })
+    }
+
+    /// Writes out the statistics of the sampler once recording has finished, with write_stats
+    pub fn set_stats(&mut self, stats: &SamplerStats) -> Result<(), Error> {
+        if !self.write_stats {
+            return Ok(());
+        }
+        self.write_line(&Stats {
+            stats: stats.clone(),
+        })
+    }
+
+    fn write_line<T: serde::Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        let mut line = serde_json::to_vec(value)?;
+        line.push(b'\n');
+        self.output.write_all(&line)?;
+        self.bytes += line.len() as u64;
+        // flush each line, so that consumers see samples as they're collected
+        self.output.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{frame, trace};
+    use std::sync::{Arc, Mutex};
+
+    // lets us inspect what was written, after handing ownership of the writer to JsonLines
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_jsonl_lines() {
+        let buffer = SharedBuffer::default();
+        let mut jsonl = JsonLines::new(Box::new(buffer.clone()));
+        let mut trace = trace(vec![frame("busy", "test.py", 4)]);
+        trace.pid = 1234;
+        jsonl.increment_at(&trace, 100).unwrap();
+        // each sample should be available as soon as it's recorded
+        assert_eq!(buffer.0.lock().unwrap().split(|&c| c == b'\n').count(), 2);
+        jsonl.increment_at(&trace, 200).unwrap();
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<serde_json::Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1]["timestamp"], 200);
+        assert_eq!(lines[1]["pid"], 1234);
+        assert_eq!(lines[1]["frames"][0]["name"], "busy");
+
+        let (timestamp, read) = read_sample(output.lines().nth(1).unwrap()).unwrap();
+        assert_eq!(timestamp, 200);
+        assert_eq!(read.pid, 1234);
+        assert_eq!(read.frames, trace.frames);
+        assert_eq!(read_stats(output.lines().nth(1).unwrap()), None);
+    }
+
+    #[test]
+    fn test_jsonl_stats() {
+        let buffer = SharedBuffer::default();
+        let mut jsonl = JsonLines::new(Box::new(buffer.clone()));
+        let stats = SamplerStats {
+            samples: 10,
+            errors: 3,
+            ..SamplerStats::new(100)
+        };
+        jsonl.set_stats(&stats).unwrap();
+        assert!(buffer.0.lock().unwrap().is_empty());
+
+        jsonl.write_stats = true;
+        jsonl
+            .increment_at(&trace(vec![frame("busy", "test.py", 4)]), 100)
+            .unwrap();
+        jsonl.set_stats(&stats).unwrap();
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(read_stats(lines[1]), Some(stats));
+        assert!(read_sample(lines[1]).is_err());
+    }
+}
@@ -0,0 +1,104 @@
+use anyhow::{Context, Error};
+
+use crate::container::{find_container_processes, ContainerProcess};
+
+/*
+ * This file contains code for '--k8s-pod', which finds the python processes running in a
+ * kubernetes pod on the node py-spy is run on, like from a privileged debug daemonset.
+ *
+ * The containers of the pod are looked up through the node's container runtime (containerd or
+ * CRI-O) with crictl, which talks to the runtime's CRI socket and is installed alongside it on
+ * most nodes. crictl finds the socket from /etc/crictl.yaml or CONTAINER_RUNTIME_ENDPOINT. Once
+ * the ids of the containers are known, their processes are found the same way as '--container'.
+ */
+
+/// Finds the python processes in a pod, given as 'namespace/pod' (or just 'pod', for pods in the
+/// default namespace), and optionally only the ones in one of its containers
+pub fn find_pod_processes(
+    pod: &str,
+    container: Option<&str>,
+) -> Result<Vec<ContainerProcess>, Error> {
+    let (namespace, name) = parse_pod(pod);
+    let pod_ids = crictl(&[
+        "pods",
+        "--namespace",
+        namespace,
+        "--name",
+        &exact(name),
+        "--state",
+        "ready",
+        "--quiet",
+    ])?;
+    let pod_id = match pod_ids.as_slice() {
+        [pod_id] => pod_id,
+        [] => return Err(format_err!("No running pod '{}' on this node", pod)),
+        _ => return Err(format_err!("More than one pod '{}' on this node", pod)),
+    };
+
+    let mut args = vec!["ps", "--pod", pod_id, "--state", "running", "--quiet"];
+    let container_name = container.map(exact);
+    if let Some(container_name) = container_name.as_ref() {
+        args.extend(["--name", container_name]);
+    }
+    let container_ids = crictl(&args)?;
+    if container_ids.is_empty() {
+        return Err(match container {
+            Some(container) => {
+                format_err!("Pod '{}' has no running container '{}'", pod, container)
+            }
+            None => format_err!("Pod '{}' has no running containers", pod),
+        });
+    }
+
+    let mut processes = Vec::new();
+    for container_id in container_ids {
+        processes.extend(find_container_processes(&container_id)?);
+    }
+    processes.sort_by_key(|process| process.pid);
+    Ok(processes)
+}
+
+/// Splits a pod into its namespace and name
+fn parse_pod(pod: &str) -> (&str, &str) {
+    pod.split_once('/').unwrap_or(("default", pod))
+}
+
+/// A regex that only matches a name, since crictl matches the names it's given as regexes
+fn exact(name: &str) -> String {
+    format!("^{}$", regex::escape(name))
+}
+
+/// Runs crictl, returning the lines it writes
+fn crictl(args: &[&str]) -> Result<Vec<String>, Error> {
+    let output = std::process::Command::new("crictl")
+        .args(args)
+        .output()
+        .context("Failed to run crictl, which --k8s-pod uses to find the pod's containers")?;
+    if !output.status.success() {
+        return Err(format_err!(
+            "crictl {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(|line| line.trim().to_owned())
+        .filter(|line| !line.is_empty())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_pod() {
+        assert_eq!(
+            parse_pod("payments/api-7d9f8-x2x4q"),
+            ("payments", "api-7d9f8-x2x4q")
+        );
+        assert_eq!(parse_pod("api-7d9f8-x2x4q"), ("default", "api-7d9f8-x2x4q"));
+        assert_eq!(exact("api.v2"), "^api\\.v2$");
+    }
+}
@@ -24,34 +24,85 @@
 //!     Ok(())
 //! }
 //! ```
+//!
+//! To sample a process continuously instead, a [`Sampler`] takes a [`Sample`] of every thread
+//! at the rate in the config, on a thread of its own, until the process exits:
+//!
+//! ```rust,no_run
+//! fn count_samples(pid: py_spy::Pid) -> Result<u64, anyhow::Error> {
+//!     let mut config = py_spy::Config::default();
+//!     config.sampling_rate = 100;
+//!     let sampler = py_spy::Sampler::new(pid, &config)?;
+//!     println!("Sampling python {}", sampler.version.as_ref().unwrap());
+//!
+//!     let mut samples = 0;
+//!     for sample in sampler.take(1000) {
+//!         if let Some(errors) = sample.sampling_errors {
+//!             for (pid, e) in errors {
+//!                 eprintln!("Failed to sample {}: {}", pid, e);
+//!             }
+//!         }
+//!         samples += sample.traces.len() as u64;
+//!     }
+//!     Ok(samples)
+//! }
+//! ```
+//!
+//! The items exported from the root of this crate, and the public modules they come from, are
+//! the stable API: they only change in incompatible ways with a new major version. Modules that
+//! are hidden from these docs are only public so that the py-spy binary can use them, and can
+//! change in any release.
 #[macro_use]
 extern crate anyhow;
 #[macro_use]
 extern crate log;
 
+mod asyncio;
 pub mod binary_parser;
 pub mod config;
 #[cfg(target_os = "linux")]
 pub mod coredump;
+mod cpu_clock;
 #[cfg(unwind)]
 mod cython;
 pub mod dump;
+pub mod ffi;
+#[cfg(unwind)]
+mod jit_symbols;
+mod memory;
+mod memory_scan;
+pub mod minidump;
 #[cfg(unwind)]
 mod native_stack_trace;
+mod offcpu;
+mod post_mortem;
+mod process_metadata;
+mod process_role;
 mod python_bindings;
 mod python_data_access;
 mod python_interpreters;
 pub mod python_process_info;
 pub mod python_spy;
 mod python_threading;
+mod retry;
 pub mod sampler;
 pub mod stack_trace;
+mod struct_offsets;
+#[cfg(test)]
+mod testing;
+mod thread_roles;
 pub mod timer;
+pub mod trio;
 mod utils;
-mod version;
+pub mod version;
 
 pub use config::Config;
+pub use memory::MemoryCounters;
+pub use process_metadata::ProcessMetadata;
 pub use python_spy::PythonSpy;
 pub use remoteprocess::Pid;
+pub use retry::FailureKind;
+pub use sampler::{Sample, Sampler};
 pub use stack_trace::Frame;
 pub use stack_trace::StackTrace;
+pub use version::Version;
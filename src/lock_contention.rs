@@ -0,0 +1,188 @@
+use std::collections::HashMap;
+use std::io::Write;
+
+use anyhow::Error;
+use remoteprocess::Pid;
+
+use crate::stack_trace::StackTrace;
+
+/*
+ * This file contains code to aggregate the python locks that threads were blocked on over a
+ * recording, for '--lock-contention'.
+ *
+ * Each sample where a thread was waiting on a lock is counted against that lock, along with the
+ * stack that was waiting and the stack of the thread holding the lock in the same sample. The
+ * report lists the locks with the most samples waiting first, with the most common waiting and
+ * holding stacks for each.
+ */
+
+// the number of waiting and holding stacks shown for each lock
+const TOP_STACKS: usize = 3;
+// the number of frames shown for each stack, starting from the innermost frame
+const STACK_DEPTH: usize = 4;
+
+#[derive(Default)]
+struct LockCounts {
+    kind: String,
+    samples: u64,
+    waiting: HashMap<String, u64>,
+    holding: HashMap<String, u64>,
+}
+
+pub struct LockContention {
+    show_linenumbers: bool,
+    // (pid, lock address) -> counts
+    locks: HashMap<(Pid, u64), LockCounts>,
+}
+
+impl LockContention {
+    pub fn new(show_linenumbers: bool) -> LockContention {
+        LockContention {
+            show_linenumbers,
+            locks: HashMap::new(),
+        }
+    }
+
+    /// Counts the threads waiting on locks in a sample. This takes all the traces from the sample,
+    /// so that the stack of the thread holding each lock can be found
+    pub fn add_sample(&mut self, traces: &[StackTrace]) {
+        for trace in traces {
+            let wait = match &trace.lock_wait {
+                Some(wait) => wait,
+                None => continue,
+            };
+            let holder = wait.holder.and_then(|holder| {
+                traces
+                    .iter()
+                    .find(|other| other.pid == trace.pid && other.thread_id == holder)
+            });
+            let holding = match holder {
+                Some(holder) => self.format_stack(holder),
+                None => String::from("(unknown)"),
+            };
+            let waiting = self.format_stack(trace);
+
+            let counts = self
+                .locks
+                .entry((trace.pid, wait.lock))
+                .or_insert_with(|| LockCounts {
+                    kind: wait.kind.clone(),
+                    ..Default::default()
+                });
+            counts.samples += 1;
+            *counts.waiting.entry(waiting).or_default() += 1;
+            *counts.holding.entry(holding).or_default() += 1;
+        }
+    }
+
+    pub fn write(&self, w: &mut dyn Write) -> Result<(), Error> {
+        if self.locks.is_empty() {
+            writeln!(w, "Lock contention: no threads were seen waiting on locks")?;
+            return Ok(());
+        }
+        let total: u64 = self.locks.values().map(|counts| counts.samples).sum();
+        writeln!(
+            w,
+            "Lock contention: {} samples waiting on {} locks",
+            total,
+            self.locks.len()
+        )?;
+
+        let mut locks: Vec<_> = self.locks.iter().collect();
+        locks.sort_by(|(a_key, a), (b_key, b)| b.samples.cmp(&a.samples).then(a_key.cmp(b_key)));
+        for ((pid, lock), counts) in locks {
+            writeln!(
+                w,
+                "\n{} at {:#x} in process {}: {} samples waiting",
+                counts.kind, lock, pid, counts.samples
+            )?;
+            for (label, stacks) in [("waiting", &counts.waiting), ("holding", &counts.holding)] {
+                let mut stacks: Vec<_> = stacks.iter().collect();
+                stacks.sort_by(|(a_stack, a), (b_stack, b)| b.cmp(a).then(a_stack.cmp(b_stack)));
+                for (stack, samples) in stacks.into_iter().take(TOP_STACKS) {
+                    writeln!(w, "  {} {:>6}  {}", label, samples, stack)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Formats the innermost frames of a stack on one line, leaving out frames that don't come
+    /// from python code (like the tags added by --offcpu)
+    fn format_stack(&self, trace: &StackTrace) -> String {
+        let frames: Vec<String> = trace
+            .frames
+            .iter()
+            .filter(|frame| !frame.filename.is_empty())
+            .take(STACK_DEPTH)
+            .map(|frame| {
+                let filename = match &frame.short_filename {
+                    Some(f) => f,
+                    None => &frame.filename,
+                };
+                if self.show_linenumbers {
+                    format!("{} ({}:{})", frame.name, filename, frame.line)
+                } else {
+                    format!("{} ({})", frame.name, filename)
+                }
+            })
+            .collect();
+        frames.join(" < ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stack_trace::LockWait;
+    use crate::testing::{frame, trace};
+
+    fn thread(thread_id: u64, frames: &[(&str, i32)], lock_wait: Option<LockWait>) -> StackTrace {
+        let mut stack = trace(
+            frames
+                .iter()
+                .map(|(name, line)| frame(name, "app.py", *line))
+                .collect(),
+        );
+        stack.pid = 1234;
+        stack.thread_id = thread_id;
+        stack.active = false;
+        stack.lock_wait = lock_wait;
+        stack
+    }
+
+    #[test]
+    fn test_lock_contention() {
+        let mut contention = LockContention::new(true);
+        let wait = |holder| LockWait {
+            lock: 0x1000,
+            kind: String::from("_thread.RLock"),
+            holder,
+        };
+        let holder = thread(1, &[("save", 20), ("main", 5)], None);
+        let waiter = thread(2, &[("load", 10), ("main", 6)], Some(wait(Some(1))));
+        contention.add_sample(&[holder.clone(), waiter.clone()]);
+        contention.add_sample(&[holder, waiter]);
+        // the holder of plain locks isn't always known
+        let waiter = thread(3, &[("load", 10), ("main", 6)], Some(wait(None)));
+        contention.add_sample(&[waiter]);
+
+        let mut out = Vec::new();
+        contention.write(&mut out).unwrap();
+        let expected = "Lock contention: 3 samples waiting on 1 locks
+
+_thread.RLock at 0x1000 in process 1234: 3 samples waiting
+  waiting      3  load (app.py:10) < main (app.py:6)
+  holding      2  save (app.py:20) < main (app.py:5)
+  holding      1  (unknown)
+";
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+
+        let mut out = Vec::new();
+        LockContention::new(true).write(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "Lock contention: no threads were seen waiting on locks\n"
+        );
+    }
+}
@@ -3,42 +3,71 @@ extern crate anyhow;
 #[macro_use]
 extern crate log;
 
-mod binary_parser;
+mod agent;
+mod callgrind;
 mod chrometrace;
-mod config;
 mod console_viewer;
-#[cfg(target_os = "linux")]
-mod coredump;
-#[cfg(unwind)]
-mod cython;
-mod dump;
+mod container;
+mod csv;
 mod flamegraph;
-#[cfg(unwind)]
-mod native_stack_trace;
-mod python_bindings;
-mod python_data_access;
-mod python_interpreters;
-mod python_process_info;
-mod python_spy;
-mod python_threading;
-mod sampler;
+mod frame_category;
+mod function_match;
+mod gecko;
+mod html;
+mod http;
+mod importtime;
+mod jsonl;
+mod kubernetes;
+mod lock_contention;
+mod metrics;
+mod otlp;
+mod parquet;
+mod perfetto;
+mod pprof;
+mod process_search;
+mod process_tree;
+mod protobuf;
+mod raw;
+mod report;
+mod sampler_stats;
+mod signals;
 mod speedscope;
-mod stack_trace;
-mod timer;
+mod sqlite;
+mod ssh;
+mod systemd;
+#[cfg(test)]
+mod testing;
+mod thread_lifetimes;
+mod trigger;
+mod upload;
+// the library has its own copy of these helpers, and uses the ones this doesn't
+#[allow(dead_code)]
 mod utils;
-mod version;
+mod web_viewer;
+
+// the sampling engine is the py-spy library crate, which this binary is a frontend for
+#[cfg(target_os = "linux")]
+use py_spy::coredump;
+use py_spy::{
+    config, dump, minidump, python_process_info, python_spy, sampler, stack_trace, version,
+    FailureKind, MemoryCounters, ProcessMetadata,
+};
 
+use std::collections::BTreeMap;
 use std::io::{Read, Write};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
-use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 
-use anyhow::Error;
+use anyhow::{Context, Error};
 use console::style;
 
-use config::{Config, FileFormat, RecordDuration};
+use config::{Compression, Config, FileFormat, LockingStrategy, RecordDuration};
 use console_viewer::ConsoleViewer;
-use stack_trace::{Frame, StackTrace};
+use python_spy::PauseBudgetExceeded;
+use sampler_stats::SamplerStats;
+use stack_trace::{interpreter_frame, task_frame, Frame, StackTrace};
+use thread_lifetimes::{ThreadEvent, ThreadLifetimes};
 
 use chrono::{Local, SecondsFormat};
 
@@ -57,30 +86,71 @@ fn permission_denied(err: &Error) -> bool {
     })
 }
 
-fn sample_console(pid: remoteprocess::Pid, config: &Config) -> Result<(), Error> {
-    let sampler = sampler::Sampler::new(pid, config)?;
+fn sample_console(pids: &[remoteprocess::Pid], config: &Config) -> Result<(), Error> {
+    let pid = pids[0];
+    let sampler = sampler::Sampler::new_multiple(pids, config)?;
 
     let display = match remoteprocess::Process::new(pid)?.cmdline() {
+        _ if pids.len() > 1 => format!("{} processes", pids.len()),
         Ok(cmdline) => cmdline.join(" "),
         Err(_) => format!("Pid {}", pid),
     };
 
+    let metrics = match config.metrics_address.as_ref() {
+        Some(address) => {
+            let metrics = Arc::new(Mutex::new(metrics::Metrics::new()));
+            let address = metrics::serve(address, metrics.clone())?;
+            eprintln!("Serving metrics on http://{}/metrics", address);
+            Some(metrics)
+        }
+        None => None,
+    };
+    let add_metrics = |sample: &sampler::Sample| {
+        if let Some(metrics) = metrics.as_ref() {
+            metrics.lock().unwrap().add_sample(sample);
+        }
+    };
+
+    if let Some(address) = config.serve.as_ref() {
+        let mut viewer = web_viewer::WebViewer::new(address, &display, config)?;
+        eprintln!(
+            "Serving a live flamegraph of {} on http://{}",
+            display,
+            viewer.address()
+        );
+        for sample in sampler {
+            add_metrics(&sample);
+            for (_, error) in sample.sampling_errors.into_iter().flatten() {
+                viewer.increment_error(&error)?;
+            }
+            viewer.increment(&sample.traces)?;
+        }
+        if !config.subprocesses && pids.len() == 1 {
+            eprintln!("process {} ended", pid);
+        }
+        return Ok(());
+    }
+
     let mut console =
         ConsoleViewer::new(config.show_line_numbers, &display, &sampler.version, config)?;
     for sample in sampler {
+        add_metrics(&sample);
         if let Some(elapsed) = sample.late {
             console.increment_late_sample(elapsed);
         }
 
+        let mut failed = Vec::new();
         if let Some(errors) = sample.sampling_errors {
-            for (_, error) in errors {
-                console.increment_error(&error)?
+            for (pid, error) in errors {
+                console.increment_error(&error)?;
+                failed.push(pid);
             }
         }
+        console.update_threads(&sample.traces, &failed);
         console.increment(&sample.traces)?;
     }
 
-    if !config.subprocesses {
+    if !config.subprocesses && pids.len() == 1 {
         println!("\nprocess {} ended", pid);
     }
     Ok(())
@@ -88,28 +158,322 @@ fn sample_console(pid: remoteprocess::Pid, config: &Config) -> Result<(), Error>
 
 pub trait Recorder {
     fn increment(&mut self, trace: &StackTrace) -> Result<(), Error>;
+    /// Records a trace sampled at a given time (in microseconds since the start of the
+    /// recording). All the traces from a single sampling interval share the same timestamp
+    fn increment_at(&mut self, trace: &StackTrace, _timestamp: u64) -> Result<(), Error> {
+        self.increment(trace)
+    }
     fn write(&self, w: &mut dyn Write) -> Result<(), Error>;
+    /// Whether samples are written out as they're recorded, rather than all at once in 'write'
+    fn streams(&self) -> bool {
+        false
+    }
+    /// Called once recording has finished, for recorders that stream out their samples
+    fn finish(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+    /// Called to make sure everything streamed out so far has been written, while recording
+    /// carries on (see checkpoint_output)
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+    /// The approximate size of the output so far in bytes, for formats where this is cheap to
+    /// find out. Used by --rotate-size
+    fn output_size(&self) -> Option<u64> {
+        None
+    }
+    /// Called with the number of sampling errors once recording has finished, for outputs that
+    /// report them
+    fn set_errors(&mut self, _errors: usize) {}
+    /// Called with the statistics of the sampler once recording has finished, for outputs that
+    /// can store them as metadata
+    fn set_stats(&mut self, _stats: &SamplerStats) -> Result<(), Error> {
+        Ok(())
+    }
+    /// Called when --adaptive-rate changes the number of samples taken a second, with the
+    /// time of the change, for outputs that record it
+    fn set_sampling_rate(&mut self, _rate: u64, _timestamp: u64) -> Result<(), Error> {
+        Ok(())
+    }
+    /// Called with where the profile came from when recording starts, for outputs that can
+    /// store it as metadata
+    fn set_metadata(&mut self, _metadata: &ProcessMetadata) -> Result<(), Error> {
+        Ok(())
+    }
+    /// Called at the end of each --profile-for window, for outputs that write each window to
+    /// its own file
+    fn end_window(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+    /// Called with the memory usage of a process read with --memory-counters, with the time it
+    /// was read at, for outputs that record it
+    fn add_memory_counters(
+        &mut self,
+        _pid: remoteprocess::Pid,
+        _counters: &MemoryCounters,
+        _timestamp: u64,
+    ) -> Result<(), Error> {
+        Ok(())
+    }
+    /// Called when a thread starts or exits during the recording, with the time it was seen
+    /// doing so, for outputs that mark it
+    fn add_thread_event(&mut self, _event: &ThreadEvent, _timestamp: u64) -> Result<(), Error> {
+        Ok(())
+    }
 }
 
 impl Recorder for speedscope::Stats {
     fn increment(&mut self, trace: &StackTrace) -> Result<(), Error> {
         Ok(self.record(trace)?)
     }
+    fn increment_at(&mut self, trace: &StackTrace, timestamp: u64) -> Result<(), Error> {
+        Ok(self.record_at(trace, timestamp)?)
+    }
     fn write(&self, w: &mut dyn Write) -> Result<(), Error> {
         self.write(w)
     }
+    fn set_metadata(&mut self, metadata: &ProcessMetadata) -> Result<(), Error> {
+        self.set_metadata(metadata);
+        Ok(())
+    }
+    fn set_stats(&mut self, stats: &SamplerStats) -> Result<(), Error> {
+        self.set_stats(stats);
+        Ok(())
+    }
+    fn add_memory_counters(
+        &mut self,
+        pid: remoteprocess::Pid,
+        counters: &MemoryCounters,
+        timestamp: u64,
+    ) -> Result<(), Error> {
+        self.add_memory_counters(pid, counters, timestamp);
+        Ok(())
+    }
 }
 
 impl Recorder for flamegraph::Flamegraph {
     fn increment(&mut self, trace: &StackTrace) -> Result<(), Error> {
         Ok(self.increment(trace)?)
     }
+    fn increment_at(&mut self, trace: &StackTrace, timestamp: u64) -> Result<(), Error> {
+        Ok(self.increment_at(trace, timestamp)?)
+    }
     fn write(&self, w: &mut dyn Write) -> Result<(), Error> {
         self.write(w)
     }
 }
 
 impl Recorder for chrometrace::Chrometrace {
+    fn increment(&mut self, trace: &StackTrace) -> Result<(), Error> {
+        Ok(self.increment(trace)?)
+    }
+    fn increment_at(&mut self, trace: &StackTrace, timestamp: u64) -> Result<(), Error> {
+        Ok(self.increment_at(trace, timestamp)?)
+    }
+    fn write(&self, _w: &mut dyn Write) -> Result<(), Error> {
+        Ok(())
+    }
+    fn streams(&self) -> bool {
+        true
+    }
+    fn finish(&mut self) -> Result<(), Error> {
+        self.finish()
+    }
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(self.flush()?)
+    }
+    fn set_stats(&mut self, stats: &SamplerStats) -> Result<(), Error> {
+        Ok(self.set_stats(stats)?)
+    }
+    fn set_sampling_rate(&mut self, rate: u64, timestamp: u64) -> Result<(), Error> {
+        Ok(self.set_sampling_rate(rate, timestamp)?)
+    }
+    fn add_memory_counters(
+        &mut self,
+        pid: remoteprocess::Pid,
+        counters: &MemoryCounters,
+        timestamp: u64,
+    ) -> Result<(), Error> {
+        Ok(self.add_memory_counters(pid, counters, timestamp)?)
+    }
+    fn add_thread_event(&mut self, event: &ThreadEvent, timestamp: u64) -> Result<(), Error> {
+        Ok(self.add_thread_event(event, timestamp)?)
+    }
+}
+
+impl Recorder for gecko::Gecko {
+    fn increment(&mut self, trace: &StackTrace) -> Result<(), Error> {
+        Ok(self.increment(trace)?)
+    }
+    fn increment_at(&mut self, trace: &StackTrace, timestamp: u64) -> Result<(), Error> {
+        Ok(self.increment_at(trace, timestamp)?)
+    }
+    fn write(&self, w: &mut dyn Write) -> Result<(), Error> {
+        self.write(w)
+    }
+}
+
+impl Recorder for pprof::Pprof {
+    fn increment(&mut self, trace: &StackTrace) -> Result<(), Error> {
+        Ok(self.increment(trace)?)
+    }
+    fn write(&self, w: &mut dyn Write) -> Result<(), Error> {
+        self.write(w)
+    }
+    fn set_stats(&mut self, stats: &SamplerStats) -> Result<(), Error> {
+        self.set_stats(stats);
+        Ok(())
+    }
+    fn set_metadata(&mut self, metadata: &ProcessMetadata) -> Result<(), Error> {
+        self.set_metadata(metadata);
+        Ok(())
+    }
+}
+
+impl Recorder for callgrind::Callgrind {
+    fn increment(&mut self, trace: &StackTrace) -> Result<(), Error> {
+        Ok(self.increment(trace)?)
+    }
+    fn write(&self, w: &mut dyn Write) -> Result<(), Error> {
+        self.write(w)
+    }
+}
+
+impl Recorder for raw::RawWriter {
+    fn increment(&mut self, trace: &StackTrace) -> Result<(), Error> {
+        self.increment(trace)
+    }
+    fn increment_at(&mut self, trace: &StackTrace, timestamp: u64) -> Result<(), Error> {
+        self.increment_at(trace, timestamp)
+    }
+    fn write(&self, w: &mut dyn Write) -> Result<(), Error> {
+        self.write(w)
+    }
+    fn output_size(&self) -> Option<u64> {
+        Some(self.size())
+    }
+    fn set_stats(&mut self, stats: &SamplerStats) -> Result<(), Error> {
+        self.set_stats(stats);
+        Ok(())
+    }
+    fn set_sampling_rate(&mut self, rate: u64, timestamp: u64) -> Result<(), Error> {
+        self.set_sampling_rate(rate, timestamp);
+        Ok(())
+    }
+    fn set_metadata(&mut self, metadata: &ProcessMetadata) -> Result<(), Error> {
+        self.set_metadata(metadata);
+        Ok(())
+    }
+    fn add_memory_counters(
+        &mut self,
+        pid: remoteprocess::Pid,
+        counters: &MemoryCounters,
+        timestamp: u64,
+    ) -> Result<(), Error> {
+        self.add_memory_counters(pid, counters, timestamp);
+        Ok(())
+    }
+    fn add_thread_event(&mut self, event: &ThreadEvent, timestamp: u64) -> Result<(), Error> {
+        self.add_thread_event(event, timestamp);
+        Ok(())
+    }
+}
+
+impl Recorder for html::HtmlFlamegraph {
+    fn increment(&mut self, trace: &StackTrace) -> Result<(), Error> {
+        Ok(self.increment(trace)?)
+    }
+    fn write(&self, w: &mut dyn Write) -> Result<(), Error> {
+        self.write(w)
+    }
+}
+
+impl Recorder for parquet::Parquet {
+    fn increment(&mut self, trace: &StackTrace) -> Result<(), Error> {
+        Ok(self.increment(trace)?)
+    }
+    fn increment_at(&mut self, trace: &StackTrace, timestamp: u64) -> Result<(), Error> {
+        Ok(self.increment_at(trace, timestamp)?)
+    }
+    fn write(&self, w: &mut dyn Write) -> Result<(), Error> {
+        self.write(w)
+    }
+}
+
+impl Recorder for sqlite::Sqlite {
+    fn increment(&mut self, trace: &StackTrace) -> Result<(), Error> {
+        self.increment(trace)
+    }
+    fn increment_at(&mut self, trace: &StackTrace, timestamp: u64) -> Result<(), Error> {
+        self.increment_at(trace, timestamp)
+    }
+    fn write(&self, w: &mut dyn Write) -> Result<(), Error> {
+        self.write(w)
+    }
+    fn output_size(&self) -> Option<u64> {
+        self.size().ok()
+    }
+    fn set_stats(&mut self, stats: &SamplerStats) -> Result<(), Error> {
+        self.set_stats(stats)
+    }
+}
+
+impl Recorder for jsonl::JsonLines {
+    fn increment(&mut self, trace: &StackTrace) -> Result<(), Error> {
+        self.increment(trace)
+    }
+    fn increment_at(&mut self, trace: &StackTrace, timestamp: u64) -> Result<(), Error> {
+        self.increment_at(trace, timestamp)
+    }
+    fn write(&self, _w: &mut dyn Write) -> Result<(), Error> {
+        Ok(())
+    }
+    fn streams(&self) -> bool {
+        true
+    }
+    fn output_size(&self) -> Option<u64> {
+        Some(self.size())
+    }
+    fn set_stats(&mut self, stats: &SamplerStats) -> Result<(), Error> {
+        self.set_stats(stats)
+    }
+}
+
+impl Recorder for perfetto::Perfetto {
+    fn increment(&mut self, trace: &StackTrace) -> Result<(), Error> {
+        Ok(self.increment(trace)?)
+    }
+    fn increment_at(&mut self, trace: &StackTrace, timestamp: u64) -> Result<(), Error> {
+        Ok(self.increment_at(trace, timestamp)?)
+    }
+    fn write(&self, w: &mut dyn Write) -> Result<(), Error> {
+        self.write(w)
+    }
+    fn add_thread_event(&mut self, event: &ThreadEvent, timestamp: u64) -> Result<(), Error> {
+        self.add_thread_event(event, timestamp);
+        Ok(())
+    }
+}
+
+impl Recorder for upload::Uploader {
+    fn increment(&mut self, trace: &StackTrace) -> Result<(), Error> {
+        self.increment(trace)
+    }
+    fn write(&self, _w: &mut dyn Write) -> Result<(), Error> {
+        Ok(())
+    }
+    fn streams(&self) -> bool {
+        true
+    }
+    fn finish(&mut self) -> Result<(), Error> {
+        self.finish()
+    }
+    fn flush(&mut self) -> Result<(), Error> {
+        self.upload()
+    }
+}
+
+impl Recorder for csv::Csv {
     fn increment(&mut self, trace: &StackTrace) -> Result<(), Error> {
         Ok(self.increment(trace)?)
     }
@@ -118,56 +482,754 @@ impl Recorder for chrometrace::Chrometrace {
     }
 }
 
-pub struct RawFlamegraph(flamegraph::Flamegraph);
+impl Recorder for report::Report {
+    fn increment(&mut self, trace: &StackTrace) -> Result<(), Error> {
+        Ok(self.increment(trace)?)
+    }
+    fn increment_at(&mut self, trace: &StackTrace, timestamp: u64) -> Result<(), Error> {
+        Ok(self.increment_at(trace, timestamp)?)
+    }
+    fn write(&self, w: &mut dyn Write) -> Result<(), Error> {
+        self.write(w)
+    }
+    fn set_errors(&mut self, errors: usize) {
+        self.set_errors(errors)
+    }
+}
+
+pub struct CollapsedFlamegraph(flamegraph::Flamegraph);
 
-impl Recorder for RawFlamegraph {
+impl Recorder for CollapsedFlamegraph {
     fn increment(&mut self, trace: &StackTrace) -> Result<(), Error> {
         Ok(self.0.increment(trace)?)
     }
 
+    fn increment_at(&mut self, trace: &StackTrace, timestamp: u64) -> Result<(), Error> {
+        Ok(self.0.increment_at(trace, timestamp)?)
+    }
+
     fn write(&self, w: &mut dyn Write) -> Result<(), Error> {
         self.0.write_raw(w)
     }
 }
 
-fn record_samples(pid: remoteprocess::Pid, config: &Config) -> Result<(), Error> {
-    let mut output: Box<dyn Recorder> = match config.format {
-        Some(FileFormat::flamegraph) => {
-            Box::new(flamegraph::Flamegraph::new(config.show_line_numbers))
+/// Splits up samples by thread (--split-threads) or by process (--split-processes), recording
+/// each to its own output file
+pub struct SplitOutput {
+    config: Config,
+    filename: String,
+    // when splitting by process, all the samples are also recorded to the original output
+    merged: Option<Box<dyn Recorder>>,
+    // except for chrome traces, which get an index of the trace for each process instead
+    index: Option<chrometrace::TraceIndex>,
+    // (pid, thread id) -> (output filename, recorder). thread id is 0 when splitting by process
+    outputs: BTreeMap<(remoteprocess::Pid, u64), (String, Box<dyn Recorder>)>,
+    // where the profile came from, which is added to the file for each thread as it's created.
+    // It's only for the process that was asked for, so isn't added to the file for each process
+    metadata: Option<ProcessMetadata>,
+}
+
+impl SplitOutput {
+    pub fn new(config: &Config, filename: &str) -> Result<SplitOutput, Error> {
+        let index = match (&config.split_processes, &config.format) {
+            (Some(_), Some(FileFormat::chrometrace)) => Some(chrometrace::TraceIndex::default()),
+            _ => None,
+        };
+        let merged = match config.split_processes {
+            Some(_) if index.is_none() => Some(new_recorder(config, filename)?),
+            _ => None,
+        };
+        // the compression extension is added back on after the thread or process name
+        let ext = compressed_filename(config, "");
+        let filename = filename.strip_suffix(&ext).unwrap_or(filename);
+        Ok(SplitOutput {
+            config: config.clone(),
+            filename: filename.to_owned(),
+            merged,
+            index,
+            outputs: BTreeMap::new(),
+            metadata: None,
+        })
+    }
+
+    fn recorder(&mut self, trace: &StackTrace) -> Result<&mut Box<dyn Recorder>, Error> {
+        let key = match self.config.split_processes {
+            Some(_) => (trace.pid, 0),
+            None => (trace.pid, trace.thread_id),
+        };
+        if !self.outputs.contains_key(&key) {
+            let filename = match self.config.split_processes {
+                Some(_) => {
+                    // command lines can be long, so use the filename of each argument rather
+                    // than the full path, and only use the start of the result
+                    let cmdline = remoteprocess::Process::new(trace.pid)
+                        .and_then(|process| process.cmdline())
+                        .unwrap_or_default()
+                        .iter()
+                        .map(|arg| match std::path::Path::new(arg).file_name() {
+                            Some(name) => name.to_string_lossy().to_string(),
+                            None => arg.clone(),
+                        })
+                        .collect::<Vec<String>>()
+                        .join(" ");
+                    let cmdline: String = cmdline.chars().take(64).collect();
+                    process_filename_template(&self.config, &self.filename)
+                        .replace("{pid}", &trace.pid.to_string())
+                        .replace("{cmdline}", &sanitize_filename(&cmdline))
+                }
+                None => {
+                    let thread = match &trace.thread_name {
+                        Some(name) => format!("{}-{}", name, trace.format_threadid()),
+                        None => format!("thread-{}", trace.format_threadid()),
+                    };
+                    thread_filename(&self.filename, &sanitize_filename(&thread))
+                }
+            };
+            let filename = compressed_filename(&self.config, &filename);
+            let mut recorder = new_recorder(&self.config, &filename)?;
+            if let (Some(metadata), None) = (&self.metadata, &self.config.split_processes) {
+                recorder.set_metadata(metadata)?;
+            }
+            self.outputs.insert(key, (filename, recorder));
+        }
+        let (filename, recorder) = self.outputs.get_mut(&key).unwrap();
+        if let Some(index) = self.index.as_mut() {
+            index.increment(trace, filename);
+        }
+        Ok(recorder)
+    }
+}
+
+impl Recorder for SplitOutput {
+    fn increment(&mut self, trace: &StackTrace) -> Result<(), Error> {
+        if let Some(merged) = self.merged.as_mut() {
+            merged.increment(trace)?;
+        }
+        self.recorder(trace)?.increment(trace)
+    }
+    fn increment_at(&mut self, trace: &StackTrace, timestamp: u64) -> Result<(), Error> {
+        if let Some(merged) = self.merged.as_mut() {
+            merged.increment_at(trace, timestamp)?;
+        }
+        self.recorder(trace)?.increment_at(trace, timestamp)
+    }
+    fn write(&self, _w: &mut dyn Write) -> Result<(), Error> {
+        Ok(())
+    }
+    fn streams(&self) -> bool {
+        true
+    }
+    fn finish(&mut self) -> Result<(), Error> {
+        if let Some(merged) = self.merged.as_mut() {
+            let filename = compressed_filename(&self.config, &self.filename);
+            write_output(merged.as_mut(), &filename, self.config.compress)?;
+        }
+        if let Some(index) = self.index.as_ref() {
+            let mut file = std::fs::File::create(&self.filename)
+                .with_context(|| format!("Failed to create index file '{}'", self.filename))?;
+            index.write(&mut file)?;
+        }
+        for (filename, recorder) in self.outputs.values_mut() {
+            write_output(recorder.as_mut(), filename, self.config.compress)?;
+        }
+        Ok(())
+    }
+    fn flush(&mut self) -> Result<(), Error> {
+        if let Some(merged) = self.merged.as_mut() {
+            let filename = compressed_filename(&self.config, &self.filename);
+            checkpoint_output(merged.as_mut(), &filename, self.config.compress)?;
+        }
+        for (filename, recorder) in self.outputs.values_mut() {
+            checkpoint_output(recorder.as_mut(), filename, self.config.compress)?;
+        }
+        Ok(())
+    }
+    fn set_sampling_rate(&mut self, rate: u64, timestamp: u64) -> Result<(), Error> {
+        if let Some(merged) = self.merged.as_mut() {
+            merged.set_sampling_rate(rate, timestamp)?;
+        }
+        for (_, recorder) in self.outputs.values_mut() {
+            recorder.set_sampling_rate(rate, timestamp)?;
+        }
+        Ok(())
+    }
+    fn add_memory_counters(
+        &mut self,
+        pid: remoteprocess::Pid,
+        counters: &MemoryCounters,
+        timestamp: u64,
+    ) -> Result<(), Error> {
+        if let Some(merged) = self.merged.as_mut() {
+            merged.add_memory_counters(pid, counters, timestamp)?;
+        }
+        for ((output_pid, _), (_, recorder)) in self.outputs.iter_mut() {
+            if *output_pid == pid {
+                recorder.add_memory_counters(pid, counters, timestamp)?;
+            }
+        }
+        Ok(())
+    }
+    fn set_metadata(&mut self, metadata: &ProcessMetadata) -> Result<(), Error> {
+        if let Some(merged) = self.merged.as_mut() {
+            merged.set_metadata(metadata)?;
+        }
+        self.metadata = Some(metadata.clone());
+        Ok(())
+    }
+    fn set_stats(&mut self, stats: &SamplerStats) -> Result<(), Error> {
+        if let Some(merged) = self.merged.as_mut() {
+            merged.set_stats(stats)?;
+        }
+        for (_, recorder) in self.outputs.values_mut() {
+            recorder.set_stats(stats)?;
+        }
+        Ok(())
+    }
+    fn add_thread_event(&mut self, event: &ThreadEvent, timestamp: u64) -> Result<(), Error> {
+        if let Some(merged) = self.merged.as_mut() {
+            merged.add_thread_event(event, timestamp)?;
+        }
+        // only passed on to the outputs that already exist, rather than starting a new file
+        // for a thread that hasn't been sampled
+        let thread = &event.thread;
+        let key = match self.config.split_processes {
+            Some(_) => (thread.pid, 0),
+            None => (thread.pid, thread.thread_id),
+        };
+        if let Some((_, recorder)) = self.outputs.get_mut(&key) {
+            recorder.add_thread_event(event, timestamp)?;
+        }
+        Ok(())
+    }
+}
+
+/// Finishes off the output file and starts a new one every --rotate-every seconds, or once the
+/// output is bigger than --rotate-size, or for each --profile-for window. Files are numbered, so
+/// that 'profile.capture' is written out as 'profile.0001.capture', 'profile.0002.capture' etc
+pub struct RotatingOutput {
+    config: Config,
+    filename: String,
+    // (output filename, recorder) for the file currently being written. This is None between
+    // --profile-for windows, so that the file for the next window is only created once it starts
+    current: Option<(String, Box<dyn Recorder>)>,
+    index: usize,
+    started: std::time::Instant,
+    last_size_check: std::time::Instant,
+    // (rate, timestamp) of the last change to the sampling rate, to pass on to new files
+    sampling_rate: Option<(u64, u64)>,
+    // where the profile came from, which is added to every file
+    metadata: Option<ProcessMetadata>,
+}
+
+impl RotatingOutput {
+    pub fn new(config: &Config, filename: &str) -> Result<RotatingOutput, Error> {
+        // the compression extension is added back on after the file number
+        let ext = compressed_filename(config, "");
+        let filename = filename.strip_suffix(&ext).unwrap_or(filename).to_owned();
+        let current = RotatingOutput::open(config, &filename, 1)?;
+        Ok(RotatingOutput {
+            config: config.clone(),
+            filename,
+            current: Some(current),
+            index: 1,
+            started: std::time::Instant::now(),
+            last_size_check: std::time::Instant::now(),
+            sampling_rate: None,
+            metadata: None,
+        })
+    }
+
+    fn open(
+        config: &Config,
+        filename: &str,
+        index: usize,
+    ) -> Result<(String, Box<dyn Recorder>), Error> {
+        let filename = thread_filename(filename, &format!("{:04}", index));
+        let filename = compressed_filename(config, &filename);
+        let recorder = new_recorder(config, &filename)?;
+        Ok((filename, recorder))
+    }
+
+    fn should_rotate(&mut self) -> bool {
+        if let Some(seconds) = self.config.rotate_every {
+            if self.started.elapsed() >= Duration::from_secs(seconds) {
+                return true;
+            }
+        }
+        // finding the size of sqlite output needs a query, so only check once a second
+        if let Some(max_size) = self.config.rotate_size {
+            if self.last_size_check.elapsed() >= Duration::from_secs(1) {
+                self.last_size_check = std::time::Instant::now();
+                let size = self.current.as_ref().and_then(|(_, r)| r.output_size());
+                return size.unwrap_or(0) >= max_size;
+            }
+        }
+        false
+    }
+
+    fn rotate(&mut self) -> Result<(), Error> {
+        self.end_window()?;
+        self.current()?;
+        Ok(())
+    }
+
+    /// The recorder for the file currently being written, starting a new file if needed
+    fn current(&mut self) -> Result<&mut Box<dyn Recorder>, Error> {
+        if self.current.is_none() {
+            self.index += 1;
+            let (filename, mut recorder) =
+                RotatingOutput::open(&self.config, &self.filename, self.index)?;
+            if let Some((rate, timestamp)) = self.sampling_rate {
+                recorder.set_sampling_rate(rate, timestamp)?;
+            }
+            if let Some(metadata) = self.metadata.as_ref() {
+                recorder.set_metadata(metadata)?;
+            }
+            self.current = Some((filename, recorder));
+            self.started = std::time::Instant::now();
+        }
+        Ok(&mut self.current.as_mut().unwrap().1)
+    }
+}
+
+impl Recorder for RotatingOutput {
+    fn increment(&mut self, trace: &StackTrace) -> Result<(), Error> {
+        if self.should_rotate() {
+            self.rotate()?;
+        }
+        self.current()?.increment(trace)
+    }
+    fn increment_at(&mut self, trace: &StackTrace, timestamp: u64) -> Result<(), Error> {
+        if self.should_rotate() {
+            self.rotate()?;
+        }
+        self.current()?.increment_at(trace, timestamp)
+    }
+    fn write(&self, _w: &mut dyn Write) -> Result<(), Error> {
+        Ok(())
+    }
+    fn streams(&self) -> bool {
+        true
+    }
+    fn finish(&mut self) -> Result<(), Error> {
+        self.end_window()
+    }
+    fn flush(&mut self) -> Result<(), Error> {
+        match self.current.as_mut() {
+            Some((filename, recorder)) => {
+                checkpoint_output(recorder.as_mut(), filename, self.config.compress)
+            }
+            None => Ok(()),
+        }
+    }
+    fn set_sampling_rate(&mut self, rate: u64, timestamp: u64) -> Result<(), Error> {
+        self.sampling_rate = Some((rate, timestamp));
+        match self.current.as_mut() {
+            Some((_, recorder)) => recorder.set_sampling_rate(rate, timestamp),
+            None => Ok(()),
+        }
+    }
+    fn set_metadata(&mut self, metadata: &ProcessMetadata) -> Result<(), Error> {
+        self.metadata = Some(metadata.clone());
+        match self.current.as_mut() {
+            Some((_, recorder)) => recorder.set_metadata(metadata),
+            None => Ok(()),
+        }
+    }
+    fn add_memory_counters(
+        &mut self,
+        pid: remoteprocess::Pid,
+        counters: &MemoryCounters,
+        timestamp: u64,
+    ) -> Result<(), Error> {
+        self.current()?
+            .add_memory_counters(pid, counters, timestamp)
+    }
+    fn add_thread_event(&mut self, event: &ThreadEvent, timestamp: u64) -> Result<(), Error> {
+        self.current()?.add_thread_event(event, timestamp)
+    }
+    /// The statistics are for the whole recording, so are only added to the last file
+    fn set_stats(&mut self, stats: &SamplerStats) -> Result<(), Error> {
+        match self.current.as_mut() {
+            Some((_, recorder)) => recorder.set_stats(stats),
+            None => Ok(()),
+        }
+    }
+    fn end_window(&mut self) -> Result<(), Error> {
+        match self.current.take() {
+            Some((filename, mut recorder)) => {
+                write_output(recorder.as_mut(), &filename, self.config.compress)
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// Thread names and command lines can contain anything, so only keep characters that are safe
+/// to use in filenames
+fn sanitize_filename(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Inserts a thread name before the extension of an output file, so that 'profile.svg'
+/// becomes 'profile.MainThread-1234.svg'
+fn thread_filename(filename: &str, thread: &str) -> String {
+    let path = std::path::Path::new(filename);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{}.{}.{}", stem, thread, ext.to_string_lossy()),
+        None => format!("{}.{}", stem, thread),
+    };
+    path.with_file_name(name).to_string_lossy().to_string()
+}
+
+/// The filename for the --exceptions flamegraph, like 'profile.exceptions.svg' for an output file
+/// of 'profile.json'
+fn exceptions_filename(config: &Config, filename: &str) -> String {
+    let base = filename
+        .strip_suffix(&compressed_filename(config, ""))
+        .unwrap_or(filename);
+    let path = std::path::Path::new(base);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = format!("{}.exceptions.svg", stem);
+    compressed_filename(config, &path.with_file_name(name).to_string_lossy())
+}
+
+/// The filename template for --split-processes, which defaults to 'profile-{pid}-{cmdline}.svg'
+/// for an output file of 'profile.svg'
+fn process_filename_template(config: &Config, filename: &str) -> String {
+    match config.split_processes.as_deref() {
+        Some(template) if !template.is_empty() => template.to_owned(),
+        _ => {
+            let path = std::path::Path::new(filename);
+            let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+            let name = match path.extension() {
+                Some(ext) => format!("{}-{{pid}}-{{cmdline}}.{}", stem, ext.to_string_lossy()),
+                None => format!("{}-{{pid}}-{{cmdline}}", stem),
+            };
+            path.with_file_name(name).to_string_lossy().to_string()
         }
+    }
+}
+
+fn new_recorder(config: &Config, filename: &str) -> Result<Box<dyn Recorder>, Error> {
+    if let Some(url) = config.upload_url.as_ref() {
+        return Ok(Box::new(upload::Uploader::new(config, url)));
+    }
+    if let (Some(url), Some(app_name)) = (config.pyroscope_url.as_ref(), config.app_name.as_ref()) {
+        return Ok(Box::new(upload::Uploader::pyroscope(config, url, app_name)));
+    }
+    if let Some(endpoint) = config.otlp_endpoint.as_ref() {
+        return Ok(Box::new(upload::Uploader::otlp(config, endpoint)));
+    }
+
+    let recorder: Box<dyn Recorder> = match config.format {
+        Some(FileFormat::flamegraph) => Box::new(flamegraph::Flamegraph::from_config(config)),
         Some(FileFormat::speedscope) => Box::new(speedscope::Stats::new(config)),
-        Some(FileFormat::raw) => Box::new(RawFlamegraph(flamegraph::Flamegraph::new(
-            config.show_line_numbers,
-        ))),
+        Some(FileFormat::capture) => Box::new(raw::RawWriter::new(config)),
+        Some(FileFormat::collapsed) => Box::new(CollapsedFlamegraph(
+            flamegraph::Flamegraph::from_config(config),
+        )),
         Some(FileFormat::chrometrace) => {
-            Box::new(chrometrace::Chrometrace::new(config.show_line_numbers))
+            // cpu and memory usage can only be read while the process is running
+            let process_stats = config.command == "record" && config.append_filename.is_none();
+            let mut chrometrace = chrometrace::Chrometrace::new(
+                streaming_output(filename, config.compress)?,
+                config.show_line_numbers,
+                process_stats,
+                config.start_time.filter(|_| config.absolute_timestamps),
+                config.stack_samples,
+            )?;
+            chrometrace.min_slice_duration = config.min_slice_duration;
+            chrometrace.thread_states = config.thread_states;
+            Box::new(chrometrace)
+        }
+        Some(FileFormat::pprof) => Box::new(pprof::Pprof::new(
+            config.show_line_numbers,
+            config.sampling_rate,
+            false,
+        )),
+        Some(FileFormat::gecko) => Box::new(gecko::Gecko::new(
+            config.show_line_numbers,
+            config.sampling_rate,
+        )),
+        Some(FileFormat::callgrind) => {
+            Box::new(callgrind::Callgrind::new(config.show_line_numbers))
+        }
+        Some(FileFormat::html) => Box::new(html::HtmlFlamegraph::new(config.show_line_numbers)),
+        Some(FileFormat::parquet) => Box::new(parquet::Parquet::new(config.show_line_numbers)),
+        Some(FileFormat::sqlite) => Box::new(sqlite::Sqlite::new(
+            config.show_line_numbers,
+            config.sampling_rate,
+        )?),
+        Some(FileFormat::jsonl) => {
+            let mut jsonl = jsonl::JsonLines::new(streaming_output(filename, config.compress)?);
+            jsonl.write_stats = config.stream_stats;
+            Box::new(jsonl)
         }
+        Some(FileFormat::perfetto) => Box::new(perfetto::Perfetto::new(config.show_line_numbers)),
+        Some(FileFormat::csv) => Box::new(csv::Csv::new(config.show_line_numbers)),
+        Some(FileFormat::report) => Box::new(report::Report::new(config)?),
+        // the deprecated 'raw' format, which is only ever parsed as collapsed
+        Some(format) => return Err(format_err!("Can't write {:?} output", format)),
         None => return Err(format_err!("A file format is required to record samples")),
     };
+    Ok(recorder)
+}
 
-    let filename = match config.filename.clone() {
+fn file_extension(format: &FileFormat) -> &'static str {
+    match format {
+        FileFormat::flamegraph => "svg",
+        FileFormat::speedscope => "json",
+        FileFormat::capture => "capture",
+        FileFormat::collapsed => "txt",
+        FileFormat::chrometrace => "json",
+        FileFormat::pprof => "pb.gz",
+        FileFormat::gecko => "json",
+        FileFormat::callgrind => "callgrind",
+        FileFormat::html => "html",
+        FileFormat::parquet => "parquet",
+        FileFormat::sqlite => "sqlite",
+        FileFormat::jsonl => "jsonl",
+        FileFormat::perfetto => "pftrace",
+        FileFormat::csv => "csv",
+        FileFormat::report => "txt",
+        _ => "out",
+    }
+}
+
+/// Opens the output for recorders that write out samples as they're recorded, with a filename
+/// of '-' meaning stdout. Compressed output is finished off when the writer is dropped
+fn streaming_output(
+    filename: &str,
+    compress: Option<Compression>,
+) -> Result<Box<dyn Write>, Error> {
+    let out: Box<dyn Write> = if filename == "-" {
+        Box::new(std::io::stdout())
+    } else {
+        Box::new(std::io::BufWriter::new(std::fs::File::create(filename)?))
+    };
+    Ok(match compress {
+        None => out,
+        Some(Compression::Gzip) => Box::new(flate2::write::GzEncoder::new(
+            out,
+            flate2::Compression::default(),
+        )),
+        Some(Compression::Zstd) => Box::new(zstd::Encoder::new(out, 0)?.auto_finish()),
+    })
+}
+
+/// Writes out the recorded samples, with a filename of '-' meaning stdout
+fn write_output(
+    output: &mut dyn Recorder,
+    filename: &str,
+    compress: Option<Compression>,
+) -> Result<(), Error> {
+    if output.streams() {
+        return output.finish();
+    }
+    if filename == "-" {
+        return write_compressed(output, Box::new(std::io::stdout().lock()), compress);
+    }
+    // regular files are written to a temporary file that then replaces the output, so that a
+    // crash while writing doesn't lose the output written out by an earlier checkpoint
+    let atomic = std::fs::metadata(filename)
+        .map(|metadata| metadata.is_file())
+        .unwrap_or(true);
+    if !atomic {
+        let out = std::io::BufWriter::new(std::fs::File::create(filename)?);
+        return write_compressed(output, Box::new(out), compress);
+    }
+    let temp_filename = format!("{}.tmp", filename);
+    let out = std::io::BufWriter::new(std::fs::File::create(&temp_filename)?);
+    if let Err(e) = write_compressed(output, Box::new(out), compress) {
+        let _ = std::fs::remove_file(&temp_filename);
+        return Err(e);
+    }
+    std::fs::rename(&temp_filename, filename)?;
+    Ok(())
+}
+
+fn write_compressed(
+    output: &mut dyn Recorder,
+    out: Box<dyn Write + '_>,
+    compress: Option<Compression>,
+) -> Result<(), Error> {
+    match compress {
+        None => {
+            let mut out = out;
+            output.write(&mut out)?;
+            out.flush()?;
+        }
+        Some(Compression::Gzip) => {
+            let mut encoder = flate2::write::GzEncoder::new(out, flate2::Compression::default());
+            output.write(&mut encoder)?;
+            encoder.finish()?.flush()?;
+        }
+        Some(Compression::Zstd) => {
+            let mut encoder = zstd::Encoder::new(out, 0)?;
+            output.write(&mut encoder)?;
+            encoder.finish()?.flush()?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes out everything recorded so far while recording carries on, for SIGUSR2 and
+/// --checkpoint-every. Outputs that are written all at once replace the file with everything so
+/// far, and will be replaced again once recording finishes, while streaming outputs flush what
+/// they've written
+fn checkpoint_output(
+    output: &mut dyn Recorder,
+    filename: &str,
+    compress: Option<Compression>,
+) -> Result<(), Error> {
+    if output.streams() {
+        output.flush()
+    } else {
+        write_output(output, filename, compress)
+    }
+}
+
+/// Adds a '.gz' or '.zst' extension to the output filename when compressing, if the filename
+/// doesn't already have it
+fn compressed_filename(config: &Config, filename: &str) -> String {
+    let ext = match config.compress {
+        Some(Compression::Gzip) => ".gz",
+        Some(Compression::Zstd) => ".zst",
+        None => return filename.to_owned(),
+    };
+    if filename == "-" || filename.ends_with(ext) {
+        filename.to_owned()
+    } else {
+        format!("{}{}", filename, ext)
+    }
+}
+
+/// Expands the variables in an output filename template, like '/profiles/{exe}-{pid}.svg'
+fn output_filename(
+    template: &str,
+    pid: remoteprocess::Pid,
+    config: &Config,
+) -> Result<String, Error> {
+    let exe = match config.python_program.as_ref() {
+        Some(prog) => Some(prog[0].clone()),
+        None => remoteprocess::Process::new(pid)
+            .and_then(|process| process.exe())
+            .ok(),
+    };
+    let exe = match exe {
+        Some(exe) => match std::path::Path::new(&exe).file_name() {
+            Some(name) => name.to_string_lossy().to_string(),
+            None => exe,
+        },
+        None => String::from("unknown"),
+    };
+    let vars = [
+        ("pid", pid.to_string()),
+        ("exe", exe),
+        (
+            "timestamp",
+            Local::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+        ),
+        (
+            "hostname",
+            utils::hostname().unwrap_or_else(|| String::from("unknown")),
+        ),
+    ];
+    utils::expand_template(template, &vars).context("Invalid output filename")
+}
+
+/// Gets the filename to record to, from --output or named after the program being profiled
+fn record_filename(pid: remoteprocess::Pid, config: &Config) -> Result<String, Error> {
+    let template = match config.filename.clone() {
         Some(filename) => filename,
         None => {
             let ext = match config.format.as_ref() {
-                Some(FileFormat::flamegraph) => "svg",
-                Some(FileFormat::speedscope) => "json",
-                Some(FileFormat::raw) => "txt",
-                Some(FileFormat::chrometrace) => "json",
+                Some(format) => file_extension(format),
                 None => return Err(format_err!("A file format is required to record samples")),
             };
-            let local_time = Local::now().to_rfc3339_opts(SecondsFormat::Secs, true);
-            let name = match config.python_program.as_ref() {
-                Some(prog) => prog[0].to_string(),
-                None => match config.pid.as_ref() {
-                    Some(pid) => pid.to_string(),
-                    None => String::from("unknown"),
-                },
-            };
-            format!("{}-{}.{}", name, local_time, ext)
+            // name the file after the program we're running, or the pid we're attaching to
+            match config.python_program {
+                Some(_) => format!("{{exe}}-{{timestamp}}.{}", ext),
+                None => format!("{{pid}}-{{timestamp}}.{}", ext),
+            }
         }
     };
+    Ok(compressed_filename(
+        config,
+        &output_filename(&template, pid, config)?,
+    ))
+}
+
+fn record_samples(pids: &[remoteprocess::Pid], config: &Config) -> Result<(), Error> {
+    let pid = pids[0];
+    // sample timestamps are relative to the start of the recording, which is also kept as a
+    // wall clock time so that outputs can be lined up with other timestamps
+    let start_ts = std::time::Instant::now();
+    let mut config = config.clone();
+    config.start_time = Some(utils::unix_micros(SystemTime::now()));
+
+    let filename = record_filename(pid, &config)?;
+
+    // the previous recording has to be read in before creating the output, since by default
+    // we're writing back to the same file. a missing file is treated like an empty recording
+    let previous = match config.append_filename.as_ref() {
+        Some(append) => match std::fs::read(append) {
+            Ok(contents) => Some(contents),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => {
+                return Err(Error::new(e).context(format!("Failed to open '{}'", append)));
+            }
+        },
+        None => None,
+    };
+    // the samples being appended are relative to the start of the previous recording
+    if let Some(previous) = previous.as_ref() {
+        config.start_time = raw::RawReader::new(previous.as_slice())?.header.start_time;
+    }
+    let config = &config;
 
-    let sampler = sampler::Sampler::new(pid, config)?;
+    let mut output: Box<dyn Recorder> = if config.split_threads || config.split_processes.is_some()
+    {
+        Box::new(SplitOutput::new(config, &filename)?)
+    } else if config.rotate_every.is_some()
+        || config.rotate_size.is_some()
+        || config.every.is_some()
+    {
+        Box::new(RotatingOutput::new(config, &filename)?)
+    } else {
+        new_recorder(config, &filename)?
+    };
+    let sampler = sampler::Sampler::new_multiple(pids, config)?;
+    if config.importtime && matches!(&sampler.version, Some(version) if version.major < 3) {
+        return Err(format_err!(
+            "--importtime needs python 3, where imports are run by importlib"
+        ));
+    }
+    if let Some(metadata) = sampler.metadata.as_ref() {
+        output.set_metadata(metadata)?;
+    }
+
+    // when writing samples to stdout, status messages go to stderr instead
+    let to_stdout = filename == "-";
+    macro_rules! status {
+        ($($arg:tt)*) => {
+            if to_stdout {
+                eprintln!($($arg)*)
+            } else {
+                println!($($arg)*)
+            }
+        };
+    }
 
     // if we're not showing a progress bar, it's probably because we've spawned the process and
     // are displaying its stderr/stdout. In that case add a prefix to our println messages so
@@ -178,23 +1240,48 @@ fn record_samples(pid: remoteprocess::Pid, config: &Config) -> Result<(), Error>
         "".to_owned()
     };
 
+    // the number of samples taken a second changes with --adaptive-rate, and nothing is sampled
+    // between --every windows, so the duration is timed instead of counting samples
+    let mut deadline = None;
     let max_intervals = match &config.duration {
         RecordDuration::Unlimited => {
-            println!(
+            status!(
                 "{}Sampling process {} times a second. Press Control-C to exit.",
-                lede, config.sampling_rate
+                lede,
+                config.sampling_rate
             );
             None
         }
         RecordDuration::Seconds(sec) => {
-            println!(
+            status!(
                 "{}Sampling process {} times a second for {} seconds. Press Control-C to exit.",
-                lede, config.sampling_rate, sec
+                lede,
+                config.sampling_rate,
+                sec
             );
-            Some(sec * config.sampling_rate)
+            if config.adaptive_rate || config.every.is_some() {
+                deadline = Some(Duration::from_secs(*sec));
+                None
+            } else {
+                Some(sec * config.sampling_rate)
+            }
         }
     };
 
+    if let Some(url) = config
+        .upload_url
+        .as_ref()
+        .or(config.pyroscope_url.as_ref())
+        .or(config.otlp_endpoint.as_ref())
+    {
+        status!(
+            "{}Uploading profiles to '{}' every {} seconds.",
+            lede,
+            url,
+            config.upload_period
+        );
+    }
+
     use indicatif::ProgressBar;
     let progress = match (config.hide_progress, &config.duration) {
         (true, _) => ProgressBar::hidden(),
@@ -216,9 +1303,10 @@ fn record_samples(pid: remoteprocess::Pid, config: &Config) -> Result<(), Error>
     };
 
     let mut errors = 0;
+    let mut abandoned = 0;
     let mut intervals = 0;
     let mut samples = 0;
-    println!();
+    status!();
 
     let running = Arc::new(AtomicBool::new(true));
     let r = running.clone();
@@ -227,9 +1315,108 @@ fn record_samples(pid: remoteprocess::Pid, config: &Config) -> Result<(), Error>
     })?;
 
     let mut exit_message = "Stopped sampling because process exited";
+    let mut start_on_function = match config.start_on_function.as_ref() {
+        Some(pattern) => {
+            status!(
+                "{}Waiting for '{}' to be seen before recording",
+                lede,
+                pattern
+            );
+            Some(function_match::FunctionMatcher::new(pattern)?)
+        }
+        None => None,
+    };
+    let stop_on_function = match config.stop_on_function.as_ref() {
+        Some(pattern) => Some(function_match::FunctionMatcher::new(pattern)?),
+        None => None,
+    };
+    let stop_message = config
+        .stop_on_function
+        .as_ref()
+        .map(|pattern| format!("Stopped sampling because '{}' was seen", pattern))
+        .unwrap_or_default();
+    // with --start-on-function, the duration is timed from when recording started
+    let mut duration_start = start_ts;
     let mut last_late_message = std::time::Instant::now();
 
+    // new samples are timestamped to come after any samples from the recording we're appending to
+    let mut timestamp_offset = 0;
+    if let Some(previous) = previous {
+        let reader = raw::RawReader::new(previous.as_slice())?;
+        timestamp_offset = replay_raw(reader, output.as_mut(), 0)?.1;
+    }
+
+    // samples where an exception was being raised, for --exceptions
+    let mut exceptions = if config.exceptions {
+        Some(flamegraph::Flamegraph::from_config(config))
+    } else {
+        None
+    };
+    let mut exception_samples = 0;
+    let mut gc_samples = 0;
+
+    let mut lock_contention = if config.lock_contention {
+        Some(lock_contention::LockContention::new(
+            config.show_line_numbers,
+        ))
+    } else {
+        None
+    };
+
+    let mut thread_lifetimes = ThreadLifetimes::from_config(config);
+
+    let metrics = match config.metrics_address.as_ref() {
+        Some(address) => {
+            let metrics = Arc::new(Mutex::new(metrics::Metrics::new()));
+            let address = metrics::serve(address, metrics.clone())?;
+            status!("{}Serving metrics on http://{}/metrics", lede, address);
+            Some(metrics)
+        }
+        None => None,
+    };
+
+    // SIGUSR1 pauses and resumes sampling, and SIGUSR2 writes out what's been recorded so far
+    signals::install()?;
+    let pause = sampler.pause_handle();
+
+    let checkpoint_every = config.checkpoint_every.map(Duration::from_secs);
+    let mut last_checkpoint = std::time::Instant::now();
+
+    let mut stats = SamplerStats::new(config.sampling_rate);
+    // the time spent paused isn't counted in the achieved sampling rate
+    let mut sampling_start = std::time::Instant::now();
+    let mut paused_since = None;
+    let mut paused_time = Duration::from_secs(0);
+
+    let mut sampling_rate = config.sampling_rate;
+    let mut paused = false;
     for mut sample in sampler {
+        let timestamp = timestamp_offset + start_ts.elapsed().as_micros() as u64;
+        if sample.sampling_rate != sampling_rate {
+            let change = if sample.sampling_rate < sampling_rate {
+                "Lowered"
+            } else {
+                "Raised"
+            };
+            sampling_rate = sample.sampling_rate;
+            if config.hide_progress {
+                status!(
+                    "{}{} the sampling rate to {} times a second",
+                    lede,
+                    change,
+                    sampling_rate
+                );
+            } else {
+                progress.println(format!(
+                    "{} the sampling rate to {} times a second",
+                    change, sampling_rate
+                ));
+            }
+            output.set_sampling_rate(sampling_rate, timestamp)?;
+        }
+        for (pid, counters) in &sample.memory_counters {
+            output.add_memory_counters(*pid, counters, timestamp)?;
+        }
         if let Some(delay) = sample.late {
             if delay > Duration::from_secs(1) {
                 if config.hide_progress {
@@ -237,12 +1424,16 @@ fn record_samples(pid: remoteprocess::Pid, config: &Config) -> Result<(), Error>
                     let now = std::time::Instant::now();
                     if now - last_late_message > Duration::from_secs(1) {
                         last_late_message = now;
-                        println!("{}{:.2?} behind in sampling, results may be inaccurate. Try reducing the sampling rate", lede, delay)
+                        status!("{}{:.2?} behind in sampling, results may be inaccurate. Try reducing the sampling rate", lede, delay)
                     }
                 } else {
-                    let term = console::Term::stdout();
+                    let term = if to_stdout {
+                        console::Term::stderr()
+                    } else {
+                        console::Term::stdout()
+                    };
                     term.move_cursor_up(2)?;
-                    println!("{:.2?} behind in sampling, results may be inaccurate. Try reducing the sampling rate.", delay);
+                    term.write_line(&format!("{:.2?} behind in sampling, results may be inaccurate. Try reducing the sampling rate.", delay))?;
                     term.move_cursor_down(1)?;
                 }
             }
@@ -253,6 +1444,82 @@ fn record_samples(pid: remoteprocess::Pid, config: &Config) -> Result<(), Error>
             break;
         }
 
+        if let Some(deadline) = deadline {
+            if duration_start.elapsed() >= deadline {
+                exit_message = "";
+                break;
+            }
+        }
+
+        if signals::toggle_requested() {
+            if pause.fetch_xor(true, Ordering::SeqCst) {
+                status!("{}Resumed sampling", lede);
+            } else {
+                status!("{}Paused sampling until SIGUSR1 is received again", lede);
+            }
+        }
+        if signals::checkpoint_requested() {
+            if to_stdout {
+                status!(
+                    "{}Can't write out the samples so far when writing to stdout",
+                    lede
+                );
+            } else {
+                checkpoint_output(output.as_mut(), &filename, config.compress)?;
+                last_checkpoint = std::time::Instant::now();
+                status!("{}Wrote out the samples so far to '{}'", lede, filename);
+            }
+        }
+        if let Some(checkpoint_every) = checkpoint_every {
+            if last_checkpoint.elapsed() >= checkpoint_every {
+                checkpoint_output(output.as_mut(), &filename, config.compress)?;
+                last_checkpoint = std::time::Instant::now();
+            }
+        }
+
+        if sample.paused {
+            paused_since.get_or_insert_with(std::time::Instant::now);
+        } else if let Some(since) = paused_since.take() {
+            paused_time += since.elapsed();
+        }
+
+        // the output for each --profile-for window is finished off as soon as the window ends
+        if sample.paused {
+            // samples paused by SIGUSR1 don't end a window
+            if !paused && config.profile_for.is_some() && !pause.load(Ordering::SeqCst) {
+                paused = true;
+                output.end_window()?;
+                status!("{}Paused sampling until the next window", lede);
+            }
+            continue;
+        }
+        paused = false;
+
+        if let Some(matcher) = start_on_function.as_ref() {
+            if !matcher.matches(&sample.traces) {
+                continue;
+            }
+            start_on_function = None;
+            duration_start = std::time::Instant::now();
+            sampling_start = duration_start;
+            paused_time = Duration::from_secs(0);
+            status!("{}Started recording", lede);
+        }
+        if let Some(matcher) = stop_on_function.as_ref() {
+            if matcher.matches(&sample.traces) {
+                exit_message = &stop_message;
+                break;
+            }
+        }
+        if config.importtime && importtime::main_started(&sample.traces) {
+            exit_message = "Stopped sampling because __main__ started running";
+            break;
+        }
+
+        stats.add_sample(&sample);
+        if let Some(metrics) = metrics.as_ref() {
+            metrics.lock().unwrap().add_sample(&sample);
+        }
         intervals += 1;
         if let Some(max_intervals) = max_intervals {
             if intervals >= max_intervals {
@@ -261,74 +1528,403 @@ fn record_samples(pid: remoteprocess::Pid, config: &Config) -> Result<(), Error>
             }
         }
 
-        for trace in sample.traces.iter_mut() {
-            if !(config.include_idle || trace.active) {
-                continue;
-            }
+        // these need the stacks of every thread, before any are filtered out below
+        if let Some(lock_contention) = lock_contention.as_mut() {
+            lock_contention.add_sample(&sample.traces);
+        }
+        if let Some(thread_lifetimes) = thread_lifetimes.as_mut() {
+            let failed: Vec<remoteprocess::Pid> = sample
+                .sampling_errors
+                .iter()
+                .flatten()
+                .map(|(pid, _)| *pid)
+                .collect();
+            for event in thread_lifetimes.update(&sample.traces, &failed) {
+                output.add_thread_event(&event, timestamp)?;
+            }
+        }
+
+        for trace in sample.traces.iter_mut() {
+            // suspended asyncio tasks are idle, but are only there because they were asked for
+            if !(config.include_idle || trace.active || trace.task_name.is_some()) {
+                continue;
+            }
+
+            if config.gil_only && !trace.owns_gil {
+                continue;
+            }
+
+            if config.importtime {
+                importtime::import_frames(trace);
+            }
+
+            if let Some(task_name) = &trace.task_name {
+                trace.frames.push(task_frame(task_name));
+            }
+
+            if config.include_thread_ids {
+                let threadid = trace.format_threadid();
+                let thread_fmt = if let Some(thread_name) = &trace.thread_name {
+                    format!("thread ({}): {}", threadid, thread_name)
+                } else {
+                    format!("thread ({})", threadid)
+                };
+                trace.frames.push(Frame::new(&thread_fmt, "", 0));
+            }
+
+            if let Some(interpreter_id) = trace.interpreter_id {
+                trace.frames.push(interpreter_frame(interpreter_id));
+            }
+
+            if let Some(process_info) = trace.process_info.as_ref() {
+                trace.frames.push(process_info.to_frame());
+                let mut parent = process_info.parent.as_ref();
+                while parent.is_some() {
+                    if let Some(process_info) = parent {
+                        trace.frames.push(process_info.to_frame());
+                        parent = process_info.parent.as_ref();
+                    }
+                }
+            }
+
+            samples += 1;
+            if trace.in_gc {
+                gc_samples += 1;
+            }
+            output.increment_at(trace, timestamp)?;
+
+            if let (Some(exceptions), Some(exception)) =
+                (exceptions.as_mut(), trace.exception.as_ref())
+            {
+                // grouped by the type of exception at the root of the flamegraph
+                let mut trace = trace.clone();
+                trace.frames.push(Frame::new(exception, "", 0));
+                exception_samples += 1;
+                exceptions.increment_at(&trace, timestamp)?;
+            }
+        }
+
+        if let Some(sampling_errors) = sample.sampling_errors {
+            for (pid, e) in sampling_errors {
+                // samples abandoned with --max-pause-us aren't errors
+                if e.is::<PauseBudgetExceeded>() {
+                    abandoned += 1;
+                    continue;
+                }
+                warn!("Failed to get stack trace from {}: {}", pid, e);
+                errors += 1;
+            }
+        }
+
+        if config.duration == RecordDuration::Unlimited {
+            let msg = if errors > 0 {
+                format!("Collected {} samples ({} errors)", samples, errors)
+            } else {
+                format!("Collected {} samples", samples)
+            };
+            progress.set_message(msg);
+        }
+        progress.inc(1);
+    }
+    progress.finish();
+    // write out a message here (so as not to interfere with progress bar) if we ended earlier
+    if !exit_message.is_empty() {
+        status!("\n{}{}", lede, exit_message);
+    }
+
+    output.set_errors(errors);
+    stats.errors = errors as u64;
+    stats.abandoned_samples = abandoned;
+    let paused_time = paused_time + paused_since.map_or(Duration::from_secs(0), |s| s.elapsed());
+    stats.sampling_time = sampling_start
+        .elapsed()
+        .saturating_sub(paused_time)
+        .as_secs_f64();
+    output.set_stats(&stats)?;
+    write_output(output.as_mut(), &filename, config.compress)?;
+
+    if let Some(exceptions) = exceptions.as_mut() {
+        let exceptions_filename = exceptions_filename(config, &filename);
+        if exception_samples == 0 {
+            status!(
+                "{}No exceptions were seen being raised, so '{}' wasn't written",
+                lede,
+                exceptions_filename
+            );
+        } else {
+            write_output(exceptions, &exceptions_filename, config.compress)?;
+            status!(
+                "{}Wrote a flamegraph of the samples raising exceptions to '{}'. Samples: {}",
+                lede,
+                exceptions_filename,
+                exception_samples
+            );
+        }
+    }
+
+    if gc_samples > 0 {
+        status!(
+            "{}{:.2}% of samples were in the garbage collector",
+            lede,
+            100.0 * gc_samples as f64 / samples as f64
+        );
+    }
+
+    if abandoned > 0 {
+        status!(
+            "{}Abandoned {} samples that took longer than --max-pause-us to collect",
+            lede,
+            abandoned
+        );
+    }
+    // samples taken without pausing the process fail more often, so it's worth knowing how many did
+    if config.blocking == LockingStrategy::NonBlocking {
+        status!("{}{}", lede, stats.summary());
+    }
+
+    if let Some(thread_lifetimes) = thread_lifetimes.as_ref() {
+        if thread_lifetimes.started() > 0 || thread_lifetimes.exited() > 0 {
+            status!(
+                "{}{} threads started and {} threads exited during the recording",
+                lede,
+                thread_lifetimes.started(),
+                thread_lifetimes.exited()
+            );
+        }
+    }
+
+    if let Some(lock_contention) = lock_contention.as_ref() {
+        if to_stdout {
+            lock_contention.write(&mut std::io::stderr())?;
+        } else {
+            lock_contention.write(&mut std::io::stdout())?;
+        }
+    }
+
+    print_output_summary(config, &lede, &filename, samples, errors)
+}
+
+/// Waits for the condition from --trigger before recording. Returns false if the process exited
+/// before then
+fn wait_for_trigger(pid: remoteprocess::Pid, config: &Config, trigger: config::Trigger) -> bool {
+    let lede = if config.hide_progress {
+        format!("{}{} ", style("py-spy").bold().green(), style(">").dim())
+    } else {
+        "".to_owned()
+    };
+    let to_stdout = config.filename.as_deref() == Some("-");
+    let status = |msg: String| {
+        if to_stdout {
+            eprintln!("{}{}", lede, msg)
+        } else {
+            println!("{}{}", lede, msg)
+        }
+    };
+
+    status(format!(
+        "Waiting for process {} to use more than {}% cpu for {} seconds before recording",
+        pid, trigger.cpu_percent, trigger.window
+    ));
+    if trigger::wait_for_trigger(pid, trigger) {
+        status(format!(
+            "Process {} used more than {}% cpu for {} seconds, starting to record",
+            pid, trigger.cpu_percent, trigger.window
+        ));
+        true
+    } else {
+        status(String::from(
+            "Stopped waiting because the process exited before the trigger fired",
+        ));
+        false
+    }
+}
+
+// how often --memory reads the allocations traced in the process
+const MEMORY_SNAPSHOT_INTERVAL: Duration = Duration::from_millis(250);
 
-            if config.gil_only && !trace.owns_gil {
-                continue;
-            }
+/// Profiles memory for --memory, by taking snapshots of the allocations traced by tracemalloc
+/// until the recording finishes, and writing a flamegraph of the allocations that were live in
+/// the last snapshot sized by the bytes allocated from each stack
+fn record_memory(pid: remoteprocess::Pid, config: &Config) -> Result<(), Error> {
+    let filename = record_filename(pid, config)?;
+    let mut spy = python_spy::PythonSpy::retry_new(pid, config, 3)?;
 
-            if config.include_thread_ids {
-                let threadid = trace.format_threadid();
-                let thread_fmt = if let Some(thread_name) = &trace.thread_name {
-                    format!("thread ({}): {}", threadid, thread_name)
-                } else {
-                    format!("thread ({})", threadid)
-                };
-                trace.frames.push(Frame {
-                    name: thread_fmt,
-                    filename: String::from(""),
-                    module: None,
-                    short_filename: None,
-                    line: 0,
-                    locals: None,
-                    is_entry: true,
-                });
+    let lede = if config.hide_progress {
+        format!("{}{} ", style("py-spy").bold().green(), style(">").dim())
+    } else {
+        "".to_owned()
+    };
+    let to_stdout = filename == "-";
+    macro_rules! status {
+        ($($arg:tt)*) => {
+            if to_stdout {
+                eprintln!($($arg)*)
+            } else {
+                println!($($arg)*)
             }
+        };
+    }
 
-            if let Some(process_info) = trace.process_info.as_ref() {
-                trace.frames.push(process_info.to_frame());
-                let mut parent = process_info.parent.as_ref();
-                while parent.is_some() {
-                    if let Some(process_info) = parent {
-                        trace.frames.push(process_info.to_frame());
-                        parent = process_info.parent.as_ref();
-                    }
-                }
-            }
+    let deadline = match config.duration {
+        RecordDuration::Seconds(sec) => Some(Duration::from_secs(sec)),
+        RecordDuration::Unlimited => None,
+    };
+    status!(
+        "{}Reading the allocations traced by tracemalloc in process {}. Press Control-C to exit.",
+        lede,
+        pid
+    );
 
-            samples += 1;
-            output.increment(trace)?;
-        }
+    let running = Arc::new(AtomicBool::new(true));
+    let r = running.clone();
+    ctrlc::set_handler(move || {
+        r.store(false, Ordering::SeqCst);
+    })?;
 
-        if let Some(sampling_errors) = sample.sampling_errors {
-            for (pid, e) in sampling_errors {
-                warn!("Failed to get stack trace from {}: {}", pid, e);
+    let progress = if config.hide_progress {
+        indicatif::ProgressBar::hidden()
+    } else {
+        indicatif::ProgressBar::new_spinner()
+    };
+    let start = std::time::Instant::now();
+    let mut snapshot = None;
+    let mut last_error = None;
+    let mut errors = 0;
+    while running.load(Ordering::SeqCst) {
+        match spy.get_allocations() {
+            Ok(allocations) => {
+                let size: u64 = allocations.iter().map(|a| a.size).sum();
+                let count: u64 = allocations.iter().map(|a| a.count).sum();
+                progress.set_message(format!(
+                    "{:.1} MB allocated in {} live allocations",
+                    size as f64 / 1e6,
+                    count
+                ));
+                snapshot = Some(allocations);
+            }
+            Err(e) => {
+                if spy.process.exe().is_err() {
+                    status!(
+                        "\n{}Stopped reading allocations because the process exited",
+                        lede
+                    );
+                    break;
+                }
+                warn!("Failed to read allocations from {}: {}", pid, e);
+                progress.set_message(format!("Failed to read allocations: {}", e));
                 errors += 1;
+                last_error = Some(e);
             }
         }
+        progress.tick();
 
-        if config.duration == RecordDuration::Unlimited {
-            let msg = if errors > 0 {
-                format!("Collected {} samples ({} errors)", samples, errors)
-            } else {
-                format!("Collected {} samples", samples)
-            };
-            progress.set_message(msg);
+        let next = std::time::Instant::now() + MEMORY_SNAPSHOT_INTERVAL;
+        while running.load(Ordering::SeqCst) && std::time::Instant::now() < next {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        if deadline.is_some_and(|deadline| start.elapsed() >= deadline) {
+            break;
         }
-        progress.inc(1);
     }
     progress.finish();
-    // write out a message here (so as not to interfere with progress bar) if we ended earlier
-    if !exit_message.is_empty() {
-        println!("\n{}{}", lede, exit_message);
+
+    let allocations = match (snapshot, last_error) {
+        (Some(allocations), _) => allocations,
+        (None, Some(e)) => return Err(e),
+        (None, None) => return Err(format_err!("No allocations were read from process {}", pid)),
+    };
+
+    let mut flamegraph = flamegraph::Flamegraph::from_config(config);
+    // tracemalloc doesn't keep function names, so the line numbers are already in the frame names
+    flamegraph.show_linenumbers = false;
+    flamegraph.count_name = Some(String::from("bytes"));
+    let mut size = 0;
+    for allocation in allocations {
+        let trace = StackTrace::new(pid, 0, allocation.frames);
+        flamegraph.increment_by(&trace, allocation.size as usize);
+        size += allocation.size;
     }
+    let mut output: Box<dyn Recorder> = match config.format {
+        Some(FileFormat::collapsed) => Box::new(CollapsedFlamegraph(flamegraph)),
+        _ => Box::new(flamegraph),
+    };
+    write_output(output.as_mut(), &filename, config.compress)?;
+    status!(
+        "{}Wrote a flamegraph of {:.1} MB of live allocations to '{}'. Errors: {}",
+        lede,
+        size as f64 / 1e6,
+        filename,
+        errors
+    );
+    Ok(())
+}
 
+fn print_output_summary(
+    config: &Config,
+    lede: &str,
+    filename: &str,
+    samples: usize,
+    errors: usize,
+) -> Result<(), Error> {
+    if let Some(url) = config
+        .upload_url
+        .as_ref()
+        .or(config.pyroscope_url.as_ref())
+        .or(config.otlp_endpoint.as_ref())
     {
-        let mut out_file = std::fs::File::create(&filename)?;
-        output.write(&mut out_file)?;
+        println!(
+            "{}Uploaded profiles to '{}'. Samples: {} Errors: {}",
+            lede, url, samples, errors
+        );
+        return Ok(());
+    }
+
+    // per thread and process files have the compression extension added after the thread or
+    // process name
+    let base_filename = filename
+        .strip_suffix(&compressed_filename(config, ""))
+        .unwrap_or(filename);
+    if config.split_threads {
+        println!(
+            "{}Wrote a profile for each thread to '{}'. Samples: {} Errors: {}",
+            lede,
+            compressed_filename(config, &thread_filename(base_filename, "<thread>")),
+            samples,
+            errors
+        );
+        return Ok(());
+    }
+    if config.rotate_every.is_some() || config.rotate_size.is_some() || config.every.is_some() {
+        println!(
+            "{}Wrote rotated output files to '{}'. Samples: {} Errors: {}",
+            lede,
+            compressed_filename(config, &thread_filename(base_filename, "<n>")),
+            samples,
+            errors
+        );
+        return Ok(());
+    }
+    if config.split_processes.is_some() {
+        println!(
+            "{}Wrote a profile for each process to '{}'",
+            lede,
+            compressed_filename(config, &process_filename_template(config, base_filename))
+        );
+        if config.format == Some(FileFormat::chrometrace) {
+            println!(
+                "{}Wrote an index of the traces to '{}'. Samples: {} Errors: {}",
+                lede, base_filename, samples, errors
+            );
+            return Ok(());
+        }
+    }
+
+    if filename == "-" {
+        eprintln!(
+            "{}Wrote samples to stdout. Samples: {} Errors: {}",
+            lede, samples, errors
+        );
+        return Ok(());
     }
 
     match config.format.as_ref().unwrap() {
@@ -341,7 +1937,7 @@ fn record_samples(pid: remoteprocess::Pid, config: &Config) -> Result<(), Error>
             // you might be SSH'ed into a server somewhere and this isn't desired, but on
             // that is pretty unlikely for osx) (note to self: xdg-open will open on linux)
             #[cfg(target_os = "macos")]
-            std::process::Command::new("open").arg(&filename).spawn()?;
+            std::process::Command::new("open").arg(filename).spawn()?;
         }
         FileFormat::speedscope => {
             println!(
@@ -350,9 +1946,19 @@ fn record_samples(pid: remoteprocess::Pid, config: &Config) -> Result<(), Error>
             );
             println!("{}Visit https://www.speedscope.app/ to view", lede);
         }
-        FileFormat::raw => {
+        FileFormat::capture => {
+            println!(
+                "{}Wrote captured samples to '{}'. Samples: {} Errors: {}",
+                lede, filename, samples, errors
+            );
+            println!(
+                "{}Use 'py-spy convert {}' to convert to another format",
+                lede, filename
+            );
+        }
+        FileFormat::collapsed => {
             println!(
-                "{}Wrote raw flamegraph data to '{}'. Samples: {} Errors: {}",
+                "{}Wrote collapsed flamegraph data to '{}'. Samples: {} Errors: {}",
                 lede, filename, samples, errors
             );
             println!("{}You can use the flamegraph.pl script from https://github.com/brendangregg/flamegraph to generate a SVG", lede);
@@ -364,21 +1970,400 @@ fn record_samples(pid: remoteprocess::Pid, config: &Config) -> Result<(), Error>
             );
             println!("{}Visit chrome://tracing to view", lede);
         }
+        FileFormat::pprof => {
+            println!(
+                "{}Wrote pprof profile to '{}'. Samples: {} Errors: {}",
+                lede, filename, samples, errors
+            );
+            println!("{}Run 'go tool pprof -http=: {}' to view", lede, filename);
+        }
+        FileFormat::gecko => {
+            println!(
+                "{}Wrote Firefox Profiler file to '{}'. Samples: {} Errors: {}",
+                lede, filename, samples, errors
+            );
+            println!("{}Visit https://profiler.firefox.com/ to view", lede);
+        }
+        FileFormat::callgrind => {
+            println!(
+                "{}Wrote callgrind file to '{}'. Samples: {} Errors: {}",
+                lede, filename, samples, errors
+            );
+            println!("{}Use KCachegrind or QCachegrind to view", lede);
+        }
+        FileFormat::html => {
+            println!(
+                "{}Wrote HTML flamegraph to '{}'. Samples: {} Errors: {}",
+                lede, filename, samples, errors
+            );
+            #[cfg(target_os = "macos")]
+            std::process::Command::new("open").arg(filename).spawn()?;
+        }
+        FileFormat::parquet => {
+            println!(
+                "{}Wrote parquet file to '{}'. Samples: {} Errors: {}",
+                lede, filename, samples, errors
+            );
+            println!(
+                "{}Each row is a single frame of a sampled stack trace",
+                lede
+            );
+        }
+        FileFormat::sqlite => {
+            println!(
+                "{}Wrote SQLite database to '{}'. Samples: {} Errors: {}",
+                lede, filename, samples, errors
+            );
+            println!(
+                "{}Query the samples, stacks and frames tables with 'sqlite3 {}'",
+                lede, filename
+            );
+        }
+        FileFormat::jsonl => {
+            println!(
+                "{}Wrote JSON lines to '{}'. Samples: {} Errors: {}",
+                lede, filename, samples, errors
+            );
+        }
+        FileFormat::perfetto => {
+            println!(
+                "{}Wrote perfetto trace to '{}'. Samples: {} Errors: {}",
+                lede, filename, samples, errors
+            );
+            println!("{}Visit https://ui.perfetto.dev/ to view", lede);
+        }
+        FileFormat::csv => {
+            println!(
+                "{}Wrote CSV report to '{}'. Samples: {} Errors: {}",
+                lede, filename, samples, errors
+            );
+        }
+        FileFormat::report => {
+            println!(
+                "{}Wrote report to '{}'. Samples: {} Errors: {}",
+                lede, filename, samples, errors
+            );
+        }
+        _ => {
+            println!(
+                "{}Wrote '{}'. Samples: {} Errors: {}",
+                lede, filename, samples, errors
+            );
+        }
+    };
+
+    Ok(())
+}
+
+/// Records a process on another machine with --ssh, writing out the samples that py-spy streams
+/// back from it
+fn record_remote(destination: &str, config: &Config) -> Result<(), Error> {
+    let mut config = config.clone();
+    config.start_time = Some(utils::unix_micros(SystemTime::now()));
+    let config = &config;
+    let filename = remote_filename(destination, config)?;
+    let mut output = new_recorder(config, &filename)?;
+
+    // control-c is passed on to ssh, which stops recording on the remote host. py-spy carries on
+    // to write out the samples it got
+    ctrlc::set_handler(|| {})?;
+    eprintln!(
+        "Recording on {} over ssh. Press Control-C to exit.",
+        destination
+    );
+    let args: Vec<String> = std::env::args().skip(2).collect();
+    let mut recording = ssh::RemoteRecording::start(destination, &args)?;
+    let mut samples = 0;
+    let mut errors = 0;
+    while let Some(line) = recording.next_line()? {
+        // the remote py-spy finishes off with the statistics of its sampler
+        if let Some(stats) = jsonl::read_stats(&line) {
+            errors = stats.errors as usize;
+            output.set_stats(&stats)?;
+            continue;
+        }
+        let (timestamp, trace) = jsonl::read_sample(&line)
+            .with_context(|| format!("Failed to read sample from {}", destination))?;
+        output.increment_at(&trace, timestamp)?;
+        samples += 1;
+    }
+
+    write_output(output.as_mut(), &filename, config.compress)?;
+    print_output_summary(config, "", &filename, samples, errors)
+}
+
+/// Gets the filename to write a recording from --ssh to. The {hostname} in it is the remote
+/// host, and its {exe} isn't known
+fn remote_filename(destination: &str, config: &Config) -> Result<String, Error> {
+    let template = match config.filename.clone() {
+        Some(filename) => filename,
+        None => {
+            let ext = file_extension(config.format.as_ref().unwrap());
+            match config.pids.first() {
+                Some(_) => format!("{{hostname}}-{{pid}}-{{timestamp}}.{}", ext),
+                None => format!("{{hostname}}-{{timestamp}}.{}", ext),
+            }
+        }
+    };
+    let host = destination.rsplit('@').next().unwrap_or(destination);
+    let vars = [
+        (
+            "pid",
+            config
+                .pids
+                .first()
+                .map_or_else(String::new, |pid| pid.to_string()),
+        ),
+        ("exe", String::from("unknown")),
+        (
+            "timestamp",
+            Local::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+        ),
+        ("hostname", host.to_owned()),
+    ];
+    let filename = utils::expand_template(&template, &vars).context("Invalid output filename")?;
+    Ok(compressed_filename(config, &filename))
+}
+
+/// Converts a file written with '--format capture' into one of the other output formats
+fn convert_samples(config: &Config) -> Result<(), Error> {
+    let input = config
+        .input_filename
+        .as_ref()
+        .ok_or_else(|| format_err!("An input file is required to convert samples"))?;
+    let file = std::fs::File::open(input)
+        .with_context(|| format!("Failed to open input file '{}'", input))?;
+    let reader = raw::RawReader::new(file)?;
+
+    // use the same settings as the original recording
+    let mut config = config.clone();
+    config.sampling_rate = reader.header.sampling_rate;
+    config.show_line_numbers = reader.header.show_line_numbers;
+    config.subprocesses = reader.header.subprocesses;
+    config.start_time = reader.header.start_time;
+    warn_missing_start_time(&config, input);
+
+    let filename = match config.filename.clone() {
+        Some(filename) => filename,
+        None => {
+            let ext = file_extension(config.format.as_ref().unwrap());
+            std::path::Path::new(input)
+                .with_extension(ext)
+                .to_string_lossy()
+                .to_string()
+        }
+    };
+    let filename = compressed_filename(&config, &filename);
+
+    let stats = reader.header.stats.clone();
+    let mut output = new_recorder(&config, &filename)?;
+    if let Some(metadata) = reader.header.metadata.as_ref() {
+        output.set_metadata(metadata)?;
+    }
+    let (samples, _) = replay_raw(reader, output.as_mut(), 0)?;
+    if let Some(stats) = stats.as_ref() {
+        output.set_stats(stats)?;
+    }
+
+    write_output(output.as_mut(), &filename, config.compress)?;
+    let errors = stats.map_or(0, |stats| stats.errors as usize);
+    print_output_summary(&config, "", &filename, samples, errors)
+}
+
+/// Merges several captured recordings, like those taken from each replica of a service, into a
+/// single output
+fn merge_samples(config: &Config) -> Result<(), Error> {
+    let mut readers = Vec::new();
+    for input in config.merge_filenames.iter() {
+        let file = std::fs::File::open(input)
+            .with_context(|| format!("Failed to open input file '{}'", input))?;
+        let reader = raw::RawReader::new(file)
+            .with_context(|| format!("Failed to read input file '{}'", input))?;
+        readers.push(reader);
+    }
+
+    let mut header = match readers.first() {
+        Some(reader) => reader.header.clone(),
+        None => return Err(format_err!("An input file is required to merge samples")),
+    };
+    for (reader, input) in readers.iter().zip(config.merge_filenames.iter()).skip(1) {
+        if reader.header.sampling_rate != header.sampling_rate {
+            eprintln!(
+                "Warning: '{}' was sampled at {} samples/s, but '{}' was sampled at {} samples/s",
+                input, reader.header.sampling_rate, config.merge_filenames[0], header.sampling_rate
+            );
+        }
+        header.merge(&reader.header);
+    }
+
+    let mut config = config.clone();
+    config.sampling_rate = header.sampling_rate;
+    config.show_line_numbers = header.show_line_numbers;
+    config.subprocesses = header.subprocesses;
+    config.start_time = header.start_time;
+    warn_missing_start_time(&config, &config.merge_filenames[0]);
+
+    let filename = match config.filename.clone() {
+        Some(filename) => filename,
+        None => format!("merged.{}", file_extension(config.format.as_ref().unwrap())),
+    };
+    let filename = compressed_filename(&config, &filename);
+
+    // each recording is placed after the previous one, so that timestamps keep on increasing
+    let mut output = new_recorder(&config, &filename)?;
+    if let Some(metadata) = header.metadata.as_ref() {
+        output.set_metadata(metadata)?;
+    }
+    let mut samples = 0;
+    let mut offset = 0;
+    for reader in readers {
+        let (count, end) = replay_raw(reader, output.as_mut(), offset)?;
+        samples += count;
+        offset = end;
+    }
+    if let Some(stats) = header.stats.as_ref() {
+        output.set_stats(stats)?;
+    }
+
+    write_output(output.as_mut(), &filename, config.compress)?;
+    let errors = header
+        .stats
+        .as_ref()
+        .map_or(0, |stats| stats.errors as usize);
+    print_output_summary(&config, "", &filename, samples, errors)
+}
+
+fn warn_missing_start_time(config: &Config, input: &str) {
+    if config.absolute_timestamps && config.start_time.is_none() {
+        eprintln!(
+            "Warning: '{}' doesn't include when it was recorded, so timestamps will be relative to the start of the recording",
+            input
+        );
+    }
+}
+
+/// Records the samples from a capture file to an output, shifting their timestamps by 'offset'.
+/// Returns the number of samples, and the timestamp that any following samples should start at
+fn replay_raw<R: std::io::Read>(
+    reader: raw::RawReader<R>,
+    output: &mut dyn Recorder,
+    offset: u64,
+) -> Result<(usize, u64), Error> {
+    let mut interval = 1_000_000 / reader.header.sampling_rate.max(1);
+    // changes to the sampling rate from --adaptive-rate are passed on as the samples they
+    // happened before are replayed
+    let mut rate_changes = reader.header.rate_changes.clone().into_iter().peekable();
+    // and so are the memory counters and the threads starting and exiting
+    let mut memory_counters = reader.header.memory_counters.clone().into_iter().peekable();
+    let mut thread_events = reader.header.thread_events.clone().into_iter().peekable();
+    let mut samples = 0;
+    let mut end = offset;
+    for sample in reader {
+        let sample = sample?;
+        while let Some((rate, ts)) = rate_changes.next_if(|(_, ts)| *ts <= sample.timestamp) {
+            output.set_sampling_rate(rate, offset + ts)?;
+            interval = 1_000_000 / rate.max(1);
+        }
+        while let Some((pid, ts, counters)) =
+            memory_counters.next_if(|(_, ts, _)| *ts <= sample.timestamp)
+        {
+            output.add_memory_counters(pid, &counters, offset + ts)?;
+        }
+        while let Some((ts, event)) = thread_events.next_if(|(ts, _)| *ts <= sample.timestamp) {
+            output.add_thread_event(&event, offset + ts)?;
+        }
+        let timestamp = offset + sample.timestamp;
+        output.increment_at(&sample.trace, timestamp)?;
+        end = end.max(timestamp + interval);
+        samples += 1;
+    }
+    for (pid, ts, counters) in memory_counters {
+        output.add_memory_counters(pid, &counters, offset + ts)?;
+    }
+    for (ts, event) in thread_events {
+        output.add_thread_event(&event, offset + ts)?;
+    }
+    Ok((samples, end))
+}
+
+/// Loads a profile as collapsed stacks, from either a '--format capture' or '--format collapsed' file
+fn load_collapsed(filename: &str) -> Result<Vec<u8>, Error> {
+    let contents = std::fs::read(filename)
+        .with_context(|| format!("Failed to open input file '{}'", filename))?;
+
+    let reader = match raw::RawReader::new(contents.as_slice()) {
+        Ok(reader) => reader,
+        // not a capture file, assume that this is already collapsed
+        Err(_) => return Ok(contents),
     };
 
+    let mut flamegraph = flamegraph::Flamegraph::new(reader.header.show_line_numbers);
+    for sample in reader {
+        flamegraph.increment(&sample?.trace)?;
+    }
+    let mut collapsed = Vec::new();
+    flamegraph.write_raw(&mut collapsed)?;
+    Ok(collapsed)
+}
+
+/// Writes out a differential flamegraph comparing two previous recordings
+fn diff_samples(config: &Config) -> Result<(), Error> {
+    let (before, after) = match (&config.baseline_filename, &config.input_filename) {
+        (Some(before), Some(after)) => (before, after),
+        _ => return Err(format_err!("Two input files are required to diff")),
+    };
+
+    let filename = match config.filename.clone() {
+        Some(filename) => filename,
+        None => std::path::Path::new(after)
+            .with_extension("diff.svg")
+            .to_string_lossy()
+            .to_string(),
+    };
+    {
+        let mut out_file = std::fs::File::create(&filename)?;
+        flamegraph::write_differential(
+            &load_collapsed(before)?,
+            &load_collapsed(after)?,
+            config.normalize_diff,
+            &mut out_file,
+        )?;
+    }
+    println!(
+        "Wrote differential flamegraph to '{}'. Red frames got slower, blue frames got faster.",
+        filename
+    );
+    #[cfg(target_os = "macos")]
+    std::process::Command::new("open").arg(&filename).spawn()?;
     Ok(())
 }
 
-fn run_spy_command(pid: remoteprocess::Pid, config: &config::Config) -> Result<(), Error> {
+fn run_spy_command(pids: &[remoteprocess::Pid], config: &config::Config) -> Result<(), Error> {
+    let pid = pids[0];
+    if pids.len() > 1 && (config.memory || config.trigger.is_some()) {
+        return Err(format_err!(
+            "--memory and --trigger can only be used with a single process"
+        ));
+    }
     match config.command.as_ref() {
         "dump" => {
-            dump::print_traces(pid, config, None)?;
+            for &pid in pids {
+                dump::print_traces(pid, config, None)?;
+            }
+        }
+        "record" if config.memory => {
+            record_memory(pid, config)?;
         }
         "record" => {
-            record_samples(pid, config)?;
+            if let Some(trigger) = config.trigger {
+                if !wait_for_trigger(pid, config, trigger) {
+                    return Ok(());
+                }
+            }
+            record_samples(pids, config)?;
         }
         "top" => {
-            sample_console(pid, config)?;
+            sample_console(pids, config)?;
         }
         _ => {
             // shouldn't happen
@@ -391,6 +2376,40 @@ fn run_spy_command(pid: remoteprocess::Pid, config: &config::Config) -> Result<(
 fn pyspy_main() -> Result<(), Error> {
     let config = config::Config::from_commandline();
 
+    // converting, merging or diffing previous recordings doesn't need to attach to any processes
+    match config.command.as_ref() {
+        "convert" => return convert_samples(&config),
+        "merge" => return merge_samples(&config),
+        "diff" => return diff_samples(&config),
+        _ => {}
+    }
+
+    if let Some(destination) = config.ssh.as_ref() {
+        return record_remote(destination, &config);
+    }
+
+    if let Some(ref core_filename) = config.core_filename {
+        let core_filename = std::path::Path::new(core_filename);
+        if minidump::is_minidump(core_filename) {
+            let dump = minidump::PythonMinidump::new(core_filename, &config)?;
+            let traces = dump.get_stack(&config)?;
+            return dump.print_traces(&traces, &config);
+        }
+
+        #[cfg(target_os = "linux")]
+        {
+            let core = coredump::PythonCoreDump::new(core_filename, &config)?;
+            let traces = core.get_stack(&config)?;
+            return core.print_traces(&traces, &config);
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        return Err(format_err!(
+            "{} isn't a minidump, and linux core dumps can only be read on linux",
+            core_filename.display()
+        ));
+    }
+
     #[cfg(target_os = "macos")]
     {
         if unsafe { libc::geteuid() } != 0 {
@@ -400,17 +2419,81 @@ fn pyspy_main() -> Result<(), Error> {
         }
     }
 
-    #[cfg(target_os = "linux")]
-    {
-        if let Some(ref core_filename) = config.core_filename {
-            let core = coredump::PythonCoreDump::new(std::path::Path::new(&core_filename))?;
-            let traces = core.get_stack(&config)?;
-            return core.print_traces(&traces, &config);
+    if config.command == "agent" {
+        return agent::run(&config);
+    }
+
+    if let Some(filename) = config.pid_file.as_ref() {
+        let pid = if config.wait_for {
+            eprintln!("Waiting for a process to write its pid to '{}'", filename);
+            process_search::wait_for_pid_file(filename)
+        } else {
+            process_search::read_pid_file(filename)?
+        };
+        return run_spy_command(&[pid], &config);
+    }
+
+    let container = match (config.container.as_ref(), config.k8s_pod.as_ref()) {
+        (Some(name_or_id), _) => Some((
+            format!("container '{}'", name_or_id),
+            container::find_container_processes(name_or_id)?,
+        )),
+        (_, Some(pod)) => Some((
+            format!("pod '{}'", pod),
+            kubernetes::find_pod_processes(pod, config.k8s_container.as_deref())?,
+        )),
+        _ => None,
+    };
+    if let Some((description, processes)) = container {
+        if processes.is_empty() {
+            return Err(format_err!("No python processes found in {}", description));
+        }
+        eprintln!(
+            "Found {} python processes in {}:\n{}",
+            processes.len(),
+            description,
+            container::describe_processes(&processes)
+        );
+        let pids: Vec<remoteprocess::Pid> = processes.iter().map(|process| process.pid).collect();
+        return run_spy_command(&pids, &config);
+    }
+
+    if config.all_processes {
+        let processes = process_search::find_python_processes()?;
+        if processes.is_empty() {
+            return Err(format_err!("No python processes found"));
+        }
+        eprintln!("Found {} python processes", processes.len());
+        let pids: Vec<remoteprocess::Pid> = processes.iter().map(|process| process.pid).collect();
+        return run_spy_command(&pids, &config);
+    }
+
+    if let Some(pattern) = config.pid_name.as_ref() {
+        let processes = if config.wait_for {
+            eprintln!("Waiting for a process matching '{}' to start", pattern);
+            process_search::wait_for_processes(pattern)?
+        } else {
+            process_search::find_processes(pattern)?
+        };
+        match processes.len() {
+            0 => return Err(format_err!("No processes match --pid-name '{}'", pattern)),
+            1 => {}
+            _ if config.all_matching => {}
+            count => {
+                return Err(format_err!(
+                    "{} processes match --pid-name '{}', pass --all-matching to spy on all of them or --pid to pick one:\n{}",
+                    count,
+                    pattern,
+                    process_search::describe_processes(&processes)
+                ))
+            }
         }
+        let pids: Vec<remoteprocess::Pid> = processes.iter().map(|process| process.pid).collect();
+        return run_spy_command(&pids, &config);
     }
 
-    if let Some(pid) = config.pid {
-        run_spy_command(pid, &config)?;
+    if !config.pids.is_empty() {
+        run_spy_command(&config.pids, &config)?;
     } else if let Some(ref subprocess) = config.python_program {
         // Dump out stdout/stderr from the process to a temp file, so we can view it later if needed
         let mut process_output = tempfile::NamedTempFile::new()?;
@@ -449,7 +2532,7 @@ fn pyspy_main() -> Result<(), Error> {
             // sleep just in case: https://jvns.ca/blog/2018/01/28/mac-freeze/
             std::thread::sleep(Duration::from_millis(50));
         }
-        let result = run_spy_command(command.id() as _, &config);
+        let result = run_spy_command(&[command.id() as _], &config);
 
         // check exit code of subprocess
         std::thread::sleep(Duration::from_millis(1));
@@ -521,3 +2604,37 @@ fn main() {
         std::process::exit(1);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{frame, trace};
+
+    #[test]
+    fn test_write_compressed_pprof() {
+        let args: Vec<String> = "py-spy record -p 1234 -f pprof --compress gzip"
+            .split_whitespace()
+            .map(|x| x.to_owned())
+            .collect();
+        let config = Config::from_args(&args).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let filename =
+            compressed_filename(&config, &dir.path().join("profile.pb.gz").to_string_lossy());
+        assert!(filename.ends_with("profile.pb.gz"));
+
+        let mut output = new_recorder(&config, &filename).unwrap();
+        output
+            .increment(&trace(vec![frame("inner", "test.py", 2)]))
+            .unwrap();
+        write_output(output.as_mut(), &filename, config.compress).unwrap();
+
+        // pprof profiles are gzipped once, so what's inside is the protobuf rather than gzip again
+        let compressed = std::fs::read(&filename).unwrap();
+        let mut decoded = Vec::new();
+        flate2::read::GzDecoder::new(compressed.as_slice())
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded[0], 0x0a);
+        assert!(decoded.windows(7).any(|w| w == b"test.py"));
+    }
+}
@@ -0,0 +1,304 @@
+use std::collections::HashMap;
+
+use anyhow::{Context, Error, Result};
+use remoteprocess::ProcessMemory;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::python_data_access::copy_string;
+use crate::python_interpreters::StringObject;
+use crate::python_process_info::PythonProcessInfo;
+use crate::stack_trace::Frame;
+use crate::version::Version;
+
+/*
+ * This file contains code to read the memory allocations traced by tracemalloc in another
+ * process, for '--memory'.
+ *
+ * When tracemalloc is tracing, it keeps a hash table of every live allocation, mapping the
+ * address of the allocation to its size and the traceback it was allocated from. Tracebacks are
+ * shared by all the allocations made from the same place, and only store the filename and line
+ * number of each frame (the most recent frame first).
+ *
+ * The hash table and its config are static variables in CPython, so this needs the symbols for
+ * them, and only supports the hash table layout used by python 3.9 to 3.11.
+ *
+ * This also has the counters of how much memory a process is using that are read periodically
+ * with '--memory-counters', which don't need tracemalloc.
+ */
+
+// the most frames read from a traceback, in case it's being modified while it's read
+const MAX_FRAMES: usize = 1024;
+
+// _Py_hashtable_t
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct Hashtable {
+    nentries: usize,
+    nbuckets: usize,
+    buckets: usize,
+}
+
+// _Py_hashtable_entry_t, where the key is the address of the allocation and the value is a
+// pointer to a trace_t
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct HashtableEntry {
+    next: usize,
+    key_hash: usize,
+    key: usize,
+    value: usize,
+}
+
+// trace_t
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct Trace {
+    size: usize,
+    traceback: usize,
+}
+
+// struct _PyTraceMalloc_Config
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct TraceMallocConfig {
+    initialized: i32,
+    tracing: i32,
+    max_nframe: i32,
+}
+
+// traceback_t starts with a hash and two uint16_t frame counts, followed by an array of frame_t,
+// which is packed (a PyObject* filename and an unsigned int lineno, with no padding)
+const POINTER_SIZE: usize = std::mem::size_of::<usize>();
+const TRACEBACK_FRAMES_OFFSET: usize = POINTER_SIZE + 4;
+const FRAME_SIZE: usize = POINTER_SIZE + 4;
+
+/// How much memory a python process was using, read periodically with --memory-counters. Each
+/// counter is None if it couldn't be read
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MemoryCounters {
+    /// The resident set size of the process, in bytes
+    pub rss: Option<u64>,
+    /// The number of arenas that pymalloc has allocated for small objects
+    pub arenas: Option<u64>,
+    /// The count for each generation of the garbage collector, like gc.get_count() returns
+    pub gc_counts: Option<Vec<i64>>,
+}
+
+/// The memory allocated from one traceback that's still live
+#[derive(Debug, Clone)]
+pub struct Allocation {
+    /// The frames the memory was allocated from, the most recent call first
+    pub frames: Vec<Frame>,
+    /// The total size of the allocations in bytes
+    pub size: u64,
+    /// The number of allocations
+    pub count: u64,
+}
+
+/// The addresses of tracemalloc's state in a python process
+pub struct TraceMalloc {
+    config: usize,
+    traces: usize,
+}
+
+impl TraceMalloc {
+    pub fn new(python_info: &PythonProcessInfo, version: &Version) -> Result<TraceMalloc, Error> {
+        if version.major != 3 || !(9..=11).contains(&version.minor) {
+            return Err(format_err!(
+                "--memory is only supported for python 3.9 to 3.11, not {}",
+                version
+            ));
+        }
+        let symbol = |name: &str| {
+            python_info
+                .get_symbol(name)
+                .map(|addr| *addr as usize)
+                .ok_or_else(|| {
+                    format_err!(
+                        "Failed to find the '{}' symbol, which is needed to read tracemalloc's traces",
+                        name
+                    )
+                })
+        };
+        Ok(TraceMalloc {
+            config: symbol("_Py_tracemalloc_config")?,
+            traces: symbol("tracemalloc_traces")?,
+        })
+    }
+
+    /// Whether tracemalloc is tracing allocations in the process
+    pub fn is_tracing<P: ProcessMemory>(&self, process: &P) -> Result<bool, Error> {
+        let config: TraceMallocConfig = process
+            .copy_struct(self.config)
+            .context("Failed to read tracemalloc's config")?;
+        Ok(config.tracing != 0)
+    }
+
+    /// Reads all the live allocations that tracemalloc has traced, grouped by the traceback they
+    /// were allocated from. The filenames in each frame are python string objects of type S
+    pub fn allocations<S: StringObject, P: ProcessMemory>(
+        &self,
+        process: &P,
+    ) -> Result<Vec<Allocation>, Error> {
+        let table_addr: usize = process.copy_struct(self.traces)?;
+        if table_addr == 0 {
+            return Ok(Vec::new());
+        }
+        let table: Hashtable = process.copy_struct(table_addr)?;
+        // each bucket is the head of a linked list of entries
+        let buckets: Vec<usize> = process.copy_vec(table.buckets, table.nbuckets)?;
+
+        // traceback address -> (size, count)
+        let mut tracebacks: HashMap<usize, (u64, u64)> = HashMap::new();
+        let mut entries = 0;
+        for bucket in buckets {
+            let mut entry_addr = bucket;
+            while entry_addr != 0 {
+                let entry: HashtableEntry = process.copy_struct(entry_addr)?;
+                let trace: Trace = process.copy_struct(entry.value)?;
+                let totals = tracebacks.entry(trace.traceback).or_default();
+                totals.0 += trace.size as u64;
+                totals.1 += 1;
+
+                // guard against looping forever if the table changed while being read
+                entries += 1;
+                if entries > table.nentries {
+                    return Err(format_err!("tracemalloc's traces changed while being read"));
+                }
+                entry_addr = entry.next;
+            }
+        }
+
+        let mut filenames = HashMap::new();
+        let mut allocations = Vec::with_capacity(tracebacks.len());
+        for (traceback, (size, count)) in tracebacks {
+            allocations.push(Allocation {
+                frames: read_traceback::<S, P>(process, traceback, &mut filenames)?,
+                size,
+                count,
+            });
+        }
+        Ok(allocations)
+    }
+}
+
+fn read_traceback<S: StringObject, P: ProcessMemory>(
+    process: &P,
+    traceback: usize,
+    filenames: &mut HashMap<usize, String>,
+) -> Result<Vec<Frame>, Error> {
+    let f: u16 = process.copy_struct(traceback + POINTER_SIZE)?;
+    let f = (f as usize).min(MAX_FRAMES);
+    let data = process.copy(traceback + TRACEBACK_FRAMES_OFFSET, f * FRAME_SIZE)?;
+
+    let mut frames = Vec::with_capacity(f);
+    for frame in data.chunks_exact(FRAME_SIZE) {
+        let filename_addr = usize::from_ne_bytes(frame[..POINTER_SIZE].try_into()?);
+        let line = u32::from_ne_bytes(frame[POINTER_SIZE..].try_into()?);
+        let filename = match filenames.get(&filename_addr) {
+            Some(filename) => filename.clone(),
+            None => {
+                let filename = copy_string(filename_addr as *const S, process)?;
+                filenames.insert(filename_addr, filename.clone());
+                filename
+            }
+        };
+        // tracemalloc doesn't keep the names of functions, so frames are named by their line
+        frames.push(Frame {
+            name: format!("line {}", line),
+            filename,
+            module: None,
+            short_filename: None,
+            line: line as i32,
+            locals: None,
+            is_entry: true,
+        });
+    }
+    Ok(frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::python_bindings::v3_11_0::PyUnicodeObject;
+    use remoteprocess::LocalProcess;
+
+    #[test]
+    fn test_allocations() {
+        let config = TraceMallocConfig {
+            initialized: 1,
+            tracing: 1,
+            max_nframe: 1,
+        };
+        // tracebacks without any frames, so that no strings have to be read
+        let first_traceback: [u64; 2] = [0; 2];
+        let second_traceback: [u64; 2] = [0; 2];
+        let traces = [
+            Trace {
+                size: 100,
+                traceback: &first_traceback as *const _ as usize,
+            },
+            Trace {
+                size: 50,
+                traceback: &first_traceback as *const _ as usize,
+            },
+            Trace {
+                size: 10,
+                traceback: &second_traceback as *const _ as usize,
+            },
+        ];
+        let entry = |trace: &Trace, next: usize| HashtableEntry {
+            next,
+            key_hash: 0,
+            key: 0,
+            value: trace as *const _ as usize,
+        };
+        // two entries chained in the first bucket, and one in the third
+        let chained = entry(&traces[1], 0);
+        let entries = [
+            entry(&traces[0], &chained as *const _ as usize),
+            entry(&traces[2], 0),
+        ];
+        let buckets = [
+            &entries[0] as *const _ as usize,
+            0,
+            &entries[1] as *const _ as usize,
+        ];
+        let table = Hashtable {
+            nentries: 3,
+            nbuckets: buckets.len(),
+            buckets: &buckets as *const _ as usize,
+        };
+        let table_addr = &table as *const _ as usize;
+
+        let tracemalloc = TraceMalloc {
+            config: &config as *const _ as usize,
+            traces: &table_addr as *const _ as usize,
+        };
+        assert!(tracemalloc.is_tracing(&LocalProcess).unwrap());
+
+        let mut allocations = tracemalloc
+            .allocations::<PyUnicodeObject, _>(&LocalProcess)
+            .unwrap();
+        allocations.sort_by_key(|allocation| allocation.size);
+        let totals: Vec<(u64, u64)> = allocations
+            .iter()
+            .map(|allocation| (allocation.size, allocation.count))
+            .collect();
+        assert_eq!(totals, vec![(10, 1), (150, 2)]);
+
+        // a table that's changing while being read is an error rather than an endless loop
+        let table = Hashtable {
+            nentries: 2,
+            ..table
+        };
+        let table_addr = &table as *const _ as usize;
+        let tracemalloc = TraceMalloc {
+            traces: &table_addr as *const _ as usize,
+            ..tracemalloc
+        };
+        assert!(tracemalloc
+            .allocations::<PyUnicodeObject, _>(&LocalProcess)
+            .is_err());
+    }
+}
@@ -0,0 +1,124 @@
+use std::collections::HashSet;
+use std::mem::size_of;
+
+use log::info;
+use remoteprocess::ProcessMemory;
+
+/*
+ * This file contains code to find the python interpreter by scanning all the writable memory of
+ * a process, for when it can't be found from python's symbols or by scanning its BSS section.
+ * This is usually because python is embedded in another program (like Blender or Maya) that has
+ * been stripped of its symbols.
+ *
+ * The interpreter is recognized by its structure instead: a PyInterpreterState has a pointer to
+ * its first thread, which has a pointer back to the interpreter. There are far too many pointers
+ * in a process to read each of them from the process, so the memory is copied once and these
+ * pointers are followed in the copy. Where the pointers are in the structs depends on the version
+ * of python, so the version has to be known already. The candidates found are then checked by
+ * getting their stack traces, like the candidates from the BSS section are.
+ */
+
+/// The most memory that's copied from the process to scan
+const MAX_SCANNED_MEMORY: usize = 1 << 30;
+
+/// A copy of the writable memory of a process
+pub struct MemorySnapshot {
+    // the start address and contents of each region, ordered by address
+    regions: Vec<(usize, Vec<u8>)>,
+}
+
+impl MemorySnapshot {
+    /// Copies each (address, size) region from the process. Regions that can't be read are
+    /// skipped, and regions past MAX_SCANNED_MEMORY aren't copied
+    pub fn new<P: ProcessMemory>(process: &P, mut regions: Vec<(usize, usize)>) -> MemorySnapshot {
+        regions.sort_unstable();
+        let mut copied = Vec::new();
+        let mut total = 0;
+        for (start, size) in regions {
+            if total + size > MAX_SCANNED_MEMORY {
+                info!("Only scanning the first {} bytes of writable memory", total);
+                break;
+            }
+            match process.copy(start, size) {
+                Ok(data) => {
+                    total += size;
+                    copied.push((start, data));
+                }
+                Err(e) => info!("Failed to copy memory at 0x{:016x}: {}", start, e),
+            }
+        }
+        MemorySnapshot { regions: copied }
+    }
+
+    /// Reads a pointer sized value at an address, if that address was copied
+    fn read_usize(&self, addr: usize) -> Option<usize> {
+        let index = self
+            .regions
+            .partition_point(|(start, _)| *start <= addr)
+            .checked_sub(1)?;
+        let (start, data) = &self.regions[index];
+        let offset = addr - start;
+        let bytes = data.get(offset..offset.checked_add(size_of::<usize>())?)?;
+        Some(usize::from_ne_bytes(bytes.try_into().ok()?))
+    }
+
+    /// Finds every value in memory that looks like a pointer to a PyInterpreterState: the value
+    /// at head_offset from it is a pointer to a thread, and the value at interp_offset from that
+    /// thread points back to it. Each is returned once, in the order they were found in
+    pub fn interpreter_candidates(&self, head_offset: usize, interp_offset: usize) -> Vec<usize> {
+        let mut seen = HashSet::new();
+        let mut candidates = Vec::new();
+        for (_, data) in &self.regions {
+            for word in data.chunks_exact(size_of::<usize>()) {
+                let addr = usize::from_ne_bytes(word.try_into().unwrap());
+                if addr == 0 || addr % size_of::<usize>() != 0 {
+                    continue;
+                }
+                let head = match self.read_usize(addr.wrapping_add(head_offset)) {
+                    Some(head) if head != 0 => head,
+                    _ => continue,
+                };
+                if self.read_usize(head.wrapping_add(interp_offset)) == Some(addr)
+                    && seen.insert(addr)
+                {
+                    candidates.push(addr);
+                }
+            }
+        }
+        candidates
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region(start: usize, words: &[usize]) -> (usize, Vec<u8>) {
+        let data = words.iter().flat_map(|word| word.to_ne_bytes()).collect();
+        (start, data)
+    }
+
+    #[test]
+    fn test_interpreter_candidates() {
+        let word = size_of::<usize>();
+        let snapshot = MemorySnapshot {
+            regions: vec![
+                // a pointer to the interpreter (twice), and some values that aren't
+                region(0x1000, &[0, 0x2000, 0x1234, 0x2000, 0x3000 + 3]),
+                // the interpreter, whose head thread is the second word
+                region(0x2000, &[0, 0x3000]),
+                // the thread, whose interpreter is the third word
+                region(0x3000, &[0, 0, 0x2000]),
+            ],
+        };
+        assert_eq!(snapshot.read_usize(0x2000 + word), Some(0x3000));
+        assert_eq!(snapshot.read_usize(0x1234), None);
+        assert_eq!(snapshot.read_usize(0x3000 + 3 * word), None);
+
+        assert_eq!(
+            snapshot.interpreter_candidates(word, 2 * word),
+            vec![0x2000]
+        );
+        assert!(snapshot.interpreter_candidates(0, 2 * word).is_empty());
+    }
+}
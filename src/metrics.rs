@@ -0,0 +1,387 @@
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io::BufReader;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Error;
+use remoteprocess::Pid;
+
+use crate::http::{bind, read_request, Response};
+use crate::sampler::Sample;
+use crate::stack_trace::StackTrace;
+
+/*
+ * This file contains code for serving metrics about the profiled program in the Prometheus text
+ * format, with 'record --metrics', 'top --metrics' and the /metrics endpoint of 'py-spy agent'.
+ *
+ * These are meant to be cheap to scrape continuously, so rather than the whole profile only a few
+ * low cardinality series are kept: how many samples were taken and failed, and over the last
+ * window of samples the share of them where the GIL was held, where each thread was active and
+ * where each of the busiest functions was running. The shares are of the last full window (rather
+ * than since the last scrape), so that they don't depend on how often they're scraped or on how
+ * many things are scraping them.
+ */
+
+/// How long each window of samples that the shares are calculated over is
+const WINDOW: Duration = Duration::from_secs(10);
+
+/// How many of the busiest functions have their share of samples reported
+const TOP_FUNCTIONS: usize = 10;
+
+/// The metrics that are served, in the order they're written out
+const METRICS: &[(&str, &str, &str)] = &[
+    (
+        "py_spy_samples_total",
+        "counter",
+        "Samples taken of the profiled program",
+    ),
+    (
+        "py_spy_sampling_errors_total",
+        "counter",
+        "Samples of a process that failed",
+    ),
+    (
+        "py_spy_samples_per_second",
+        "gauge",
+        "Samples taken a second, over the last window",
+    ),
+    (
+        "py_spy_sampling_error_ratio",
+        "gauge",
+        "Share of the samples of a process that failed, over the last window",
+    ),
+    (
+        "py_spy_gil_held_ratio",
+        "gauge",
+        "Share of samples where a thread was holding the GIL, over the last window",
+    ),
+    (
+        "py_spy_thread_active_ratio",
+        "gauge",
+        "Share of samples where each thread was active, over the last window",
+    ),
+    (
+        "py_spy_function_share",
+        "gauge",
+        "Share of the samples of active threads where each of the busiest functions was running, over the last window",
+    ),
+];
+
+type Labels = Vec<(&'static str, String)>;
+
+/// The labels and value of a series
+type Series = (Labels, f64);
+
+#[derive(Debug, Default, Clone)]
+struct Window {
+    samples: u64,
+    errors: u64,
+    gil: u64,
+    // samples of each thread, and how many of them it was active in
+    threads: HashMap<(Pid, String), (u64, u64)>,
+    // samples where each function was running in an active thread
+    functions: HashMap<String, u64>,
+    // samples of active threads
+    active: u64,
+}
+
+#[derive(Debug)]
+pub struct Metrics {
+    samples: u64,
+    errors: u64,
+    current: Window,
+    current_start: Instant,
+    last: Option<(Window, Duration)>,
+}
+
+impl Default for Metrics {
+    fn default() -> Metrics {
+        Metrics::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            samples: 0,
+            errors: 0,
+            current: Window::default(),
+            current_start: Instant::now(),
+            last: None,
+        }
+    }
+
+    /// Adds a sample, from before any of its traces are filtered out
+    pub fn add_sample(&mut self, sample: &Sample) {
+        if self.current_start.elapsed() >= WINDOW {
+            let window = std::mem::take(&mut self.current);
+            self.last = Some((window, self.current_start.elapsed()));
+            self.current_start = Instant::now();
+        }
+
+        let errors = sample
+            .sampling_errors
+            .as_ref()
+            .map_or(0, |e| e.len() as u64);
+        self.samples += 1;
+        self.errors += errors;
+        let window = &mut self.current;
+        window.samples += 1;
+        window.errors += errors;
+        if sample.traces.iter().any(|trace| trace.owns_gil) {
+            window.gil += 1;
+        }
+        for trace in &sample.traces {
+            let thread = window
+                .threads
+                .entry((trace.pid, thread_name(trace)))
+                .or_default();
+            thread.0 += 1;
+            if trace.active {
+                thread.1 += 1;
+                window.active += 1;
+                if let Some(frame) = trace.frames.first() {
+                    let filename = frame.short_filename.as_ref().unwrap_or(&frame.filename);
+                    let function = format!("{} ({})", frame.name, filename);
+                    *window.functions.entry(function).or_default() += 1;
+                }
+            }
+        }
+    }
+
+    /// The value of each metric, keyed by the name of the metric
+    fn series(&self) -> HashMap<&'static str, Vec<Series>> {
+        let mut series: HashMap<&'static str, Vec<Series>> = HashMap::new();
+        series.insert("py_spy_samples_total", vec![(vec![], self.samples as f64)]);
+        series.insert(
+            "py_spy_sampling_errors_total",
+            vec![(vec![], self.errors as f64)],
+        );
+
+        // until the first window is over, the shares are of the samples so far
+        let (window, elapsed) = match self.last.as_ref() {
+            Some((window, elapsed)) => (window, *elapsed),
+            None => (&self.current, self.current_start.elapsed()),
+        };
+        let ratio = |count: u64, total: u64| {
+            if total == 0 {
+                0.0
+            } else {
+                count as f64 / total as f64
+            }
+        };
+        series.insert(
+            "py_spy_samples_per_second",
+            vec![(
+                vec![],
+                window.samples as f64 / elapsed.as_secs_f64().max(1e-3),
+            )],
+        );
+        series.insert(
+            "py_spy_sampling_error_ratio",
+            vec![(vec![], ratio(window.errors, window.samples + window.errors))],
+        );
+        series.insert(
+            "py_spy_gil_held_ratio",
+            vec![(vec![], ratio(window.gil, window.samples))],
+        );
+
+        let mut threads: Vec<_> = window.threads.iter().collect();
+        threads.sort();
+        series.insert(
+            "py_spy_thread_active_ratio",
+            threads
+                .into_iter()
+                .map(|((pid, thread), (samples, active))| {
+                    (
+                        vec![("pid", pid.to_string()), ("thread", thread.clone())],
+                        ratio(*active, *samples),
+                    )
+                })
+                .collect(),
+        );
+
+        let mut functions: Vec<(&String, &u64)> = window.functions.iter().collect();
+        functions.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        series.insert(
+            "py_spy_function_share",
+            functions
+                .into_iter()
+                .take(TOP_FUNCTIONS)
+                .map(|(function, count)| {
+                    (
+                        vec![("function", function.clone())],
+                        ratio(*count, window.active),
+                    )
+                })
+                .collect(),
+        );
+        series
+    }
+}
+
+/// Writes out the metrics in the Prometheus text format. Each set of metrics has labels added to
+/// all of its series (like the recording they're from, for the agent)
+pub fn render(sources: &[(Labels, &Metrics)]) -> String {
+    let series: Vec<(&Labels, HashMap<&'static str, Vec<Series>>)> = sources
+        .iter()
+        .map(|(labels, metrics)| (labels, metrics.series()))
+        .collect();
+    let mut out = String::new();
+    for (name, kind, help) in METRICS {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        for (extra, series) in &series {
+            for (labels, value) in series.get(name).into_iter().flatten() {
+                let labels: Vec<String> = extra
+                    .iter()
+                    .chain(labels.iter())
+                    .map(|(name, value)| format!("{}=\"{}\"", name, escape_label(value)))
+                    .collect();
+                if labels.is_empty() {
+                    let _ = writeln!(out, "{} {}", name, value);
+                } else {
+                    let _ = writeln!(out, "{}{{{}}} {}", name, labels.join(","), value);
+                }
+            }
+        }
+    }
+    out
+}
+
+/// The content type of the Prometheus text format
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Serves metrics on '/metrics' from a thread, returning the address they're served on
+pub fn serve(address: &str, metrics: Arc<Mutex<Metrics>>) -> Result<SocketAddr, Error> {
+    let listener = bind(address)?;
+    let local_address = listener.local_addr()?;
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let metrics = metrics.clone();
+            std::thread::spawn(move || {
+                if let Err(e) = serve_request(stream, &metrics) {
+                    info!("Failed to serve metrics: {}", e);
+                }
+            });
+        }
+    });
+    Ok(local_address)
+}
+
+fn serve_request(mut stream: TcpStream, metrics: &Mutex<Metrics>) -> Result<(), Error> {
+    stream.set_read_timeout(Some(Duration::from_secs(30)))?;
+    let request = match read_request(&mut BufReader::new(&stream)) {
+        Ok(request) => request,
+        Err(e) => {
+            Response::bad_request(&e).write(&mut stream)?;
+            return Ok(());
+        }
+    };
+    let response = match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/metrics") => {
+            let body = render(&[(vec![], &metrics.lock().unwrap())]);
+            Response::new(200, CONTENT_TYPE, body.into_bytes())
+        }
+        _ => Response::error(404, "Not found"),
+    };
+    response.write(&mut stream)?;
+    Ok(())
+}
+
+fn thread_name(trace: &StackTrace) -> String {
+    match trace.thread_name.as_ref() {
+        Some(name) => name.clone(),
+        None => format!("{:#X}", trace.thread_id),
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{frame, trace};
+
+    fn thread(thread: &str, function: Option<&str>, owns_gil: bool) -> StackTrace {
+        let mut stack = trace(vec![{
+            let mut f = frame(function.unwrap_or("wait"), "/src/app.py", 1);
+            f.short_filename = Some(String::from("app.py"));
+            f
+        }]);
+        stack.pid = 10;
+        stack.thread_name = Some(thread.to_owned());
+        stack.active = function.is_some();
+        stack.owns_gil = owns_gil;
+        stack
+    }
+
+    fn sample(traces: Vec<StackTrace>) -> Sample {
+        Sample {
+            traces,
+            sampling_errors: None,
+            late: None,
+            sampling_rate: 100,
+            paused: false,
+            memory_counters: Vec::new(),
+            pauses: Vec::new(),
+            retried: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_metrics() {
+        let mut metrics = Metrics::new();
+        metrics.add_sample(&sample(vec![
+            thread("MainThread", Some("work"), true),
+            thread("worker \"1\"", None, false),
+        ]));
+        metrics.add_sample(&sample(vec![
+            thread("MainThread", Some("work"), false),
+            thread("worker \"1\"", Some("send"), true),
+        ]));
+        metrics.add_sample(&sample(vec![
+            thread("MainThread", None, false),
+            thread("worker \"1\"", None, false),
+        ]));
+
+        let out = render(&[(vec![("recording", String::from("1"))], &metrics)]);
+        assert!(out.contains("# TYPE py_spy_samples_total counter\n"));
+        assert!(out.contains("py_spy_samples_total{recording=\"1\"} 3\n"));
+        assert!(out.contains("py_spy_sampling_error_ratio{recording=\"1\"} 0\n"));
+        assert!(out.contains(&format!(
+            "py_spy_gil_held_ratio{{recording=\"1\"}} {}\n",
+            2.0 / 3.0
+        )));
+        assert!(out.contains(&format!(
+            "py_spy_thread_active_ratio{{recording=\"1\",pid=\"10\",thread=\"MainThread\"}} {}\n",
+            2.0 / 3.0
+        )));
+        assert!(out.contains(&format!(
+            "py_spy_thread_active_ratio{{recording=\"1\",pid=\"10\",thread=\"worker \\\"1\\\"\"}} {}\n",
+            1.0 / 3.0
+        )));
+        assert!(out.contains(&format!(
+            "py_spy_function_share{{recording=\"1\",function=\"work (app.py)\"}} {}\n",
+            2.0 / 3.0
+        )));
+        assert!(out.contains(&format!(
+            "py_spy_function_share{{recording=\"1\",function=\"send (app.py)\"}} {}\n",
+            1.0 / 3.0
+        )));
+        // every metric has a single HELP and TYPE, even with several sets of metrics
+        let out = render(&[
+            (vec![("recording", String::from("1"))], &metrics),
+            (vec![("recording", String::from("2"))], &Metrics::new()),
+        ]);
+        assert_eq!(out.matches("# TYPE py_spy_samples_total ").count(), 1);
+        assert!(out.contains("py_spy_samples_total{recording=\"2\"} 0\n"));
+    }
+}
@@ -0,0 +1,593 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Error, Result};
+use console::style;
+use lazy_static::lazy_static;
+use memmap::Mmap;
+use regex::{Regex, RegexBuilder};
+use remoteprocess::ProcessMemory;
+
+use crate::binary_parser::{parse_binary, BinaryInfo};
+use crate::config::Config;
+use crate::dump::{print_trace, write_locals_output};
+use crate::post_mortem::DumpedInterpreter;
+use crate::python_process_info::{ContainsAddr, PythonProcessInfo};
+use crate::stack_trace::StackTrace;
+use crate::version::Version;
+
+/*
+ * This file contains code for reading the python stacks from windows minidumps (.dmp files),
+ * like the ones written by Windows Error Reporting, crashpad or procdump when a program crashes,
+ * with 'dump --core'.
+ *
+ * A minidump is a directory of streams: the ones read here are the list of modules that were
+ * loaded (to find python3X.dll and its version), the memory that was saved (either as a list of
+ * ranges, or for full dumps as one contiguous block of ranges at the end of the file), which of
+ * that memory was writable, and the exception and process id of the crash. The python dll itself
+ * isn't in the dump: if it's found (at the path it was loaded from, or passed with --exe), its
+ * exported symbols are used to find the interpreter, and otherwise the writable memory in the dump
+ * is scanned for it.
+ */
+
+/// The 'MDMP' that minidumps start with
+const SIGNATURE: u32 = 0x504d_444d;
+
+const MODULE_LIST_STREAM: u32 = 4;
+const MEMORY_LIST_STREAM: u32 = 5;
+const EXCEPTION_STREAM: u32 = 6;
+const MEMORY_64_LIST_STREAM: u32 = 9;
+const MISC_INFO_STREAM: u32 = 15;
+const MEMORY_INFO_LIST_STREAM: u32 = 16;
+
+const MODULE_SIZE: usize = 108;
+const MEMORY_DESCRIPTOR_SIZE: usize = 16;
+const MISC1_PROCESS_ID: u32 = 1;
+
+/// The PAGE_READWRITE, PAGE_WRITECOPY, PAGE_EXECUTE_READWRITE and PAGE_EXECUTE_WRITECOPY
+/// memory protections
+const WRITABLE_PROTECTION: u32 = 0x04 | 0x08 | 0x40 | 0x80;
+
+/// Returns whether a file is a minidump (rather than an ELF core dump)
+pub fn is_minidump(filename: &Path) -> bool {
+    let mut signature = [0; 4];
+    File::open(filename)
+        .and_then(|mut file| file.read_exact(&mut signature))
+        .is_ok()
+        && u32::from_le_bytes(signature) == SIGNATURE
+}
+
+/// A dll (or exe) that was loaded in the process
+#[derive(Debug, Clone)]
+pub struct MinidumpModule {
+    pub base: usize,
+    pub size: usize,
+    /// The path the module was loaded from, on the machine the minidump is from
+    pub name: String,
+    file_version: (u32, u32),
+}
+
+impl MinidumpModule {
+    /// The filename of the module, without its directory
+    pub fn basename(&self) -> &str {
+        self.name.rsplit(['\\', '/']).next().unwrap_or(&self.name)
+    }
+
+    /// Whether this is the python dll, like python311.dll
+    pub fn is_python_dll(&self) -> bool {
+        lazy_static! {
+            static ref RE: Regex = RegexBuilder::new(r"^python\d\d\d?(m|d|u|t)?(_d)?\.dll$")
+                .case_insensitive(true)
+                .build()
+                .unwrap();
+        }
+        RE.is_match(self.basename())
+    }
+
+    /// The version of python from the version resource of the python dll, which python sets to
+    /// major.minor.(micro * 1000 + release level * 10 + serial).build
+    pub fn python_version(&self) -> Option<Version> {
+        let (most_significant, least_significant) = self.file_version;
+        if most_significant == 0 {
+            return None;
+        }
+        Some(Version {
+            major: (most_significant >> 16) as u64,
+            minor: (most_significant & 0xffff) as u64,
+            patch: ((least_significant >> 16) / 1000) as u64,
+            release_flags: "".to_owned(),
+            build_metadata: None,
+        })
+    }
+}
+
+/// A range of memory that was saved in the minidump
+#[derive(Debug, Clone)]
+pub struct MemoryRegion {
+    start: usize,
+    size: usize,
+    // where the memory is in the minidump file
+    offset: usize,
+    writable: bool,
+}
+
+impl MemoryRegion {
+    fn contains(&self, addr: usize) -> bool {
+        addr >= self.start && addr < self.start + self.size
+    }
+}
+
+impl ContainsAddr for Vec<MemoryRegion> {
+    fn contains_addr(&self, addr: usize) -> bool {
+        self.iter().any(|region| region.contains(addr))
+    }
+
+    fn writable_regions(&self) -> Vec<(usize, usize)> {
+        self.iter()
+            .filter(|region| region.writable)
+            .map(|region| (region.start, region.size))
+            .collect()
+    }
+}
+
+pub struct Minidump {
+    filename: PathBuf,
+    contents: Mmap,
+    regions: Vec<MemoryRegion>,
+    modules: Vec<MinidumpModule>,
+    pid: Option<u32>,
+    exception_code: Option<u32>,
+}
+
+impl Minidump {
+    pub fn new<P: AsRef<Path>>(filename: P) -> Result<Minidump, Error> {
+        let filename = filename.as_ref();
+        let file = File::open(filename)?;
+        let contents = unsafe { Mmap::map(&file)? };
+        if read_u32(&contents, 0)? != SIGNATURE {
+            return Err(format_err!("{} isn't a minidump", filename.display()));
+        }
+
+        let stream_count = read_u32(&contents, 8)? as usize;
+        let directory = read_u32(&contents, 12)? as usize;
+        let mut modules = Vec::new();
+        let mut regions = Vec::new();
+        // the (start, size) of the writable memory in the process, if the dump says
+        let mut writable = None;
+        let mut pid = None;
+        let mut exception_code = None;
+        for i in 0..stream_count {
+            let entry = directory + i * 12;
+            let stream_type = read_u32(&contents, entry)?;
+            let rva = read_u32(&contents, entry + 8)? as usize;
+            match stream_type {
+                MODULE_LIST_STREAM => {
+                    for i in 0..read_u32(&contents, rva)? as usize {
+                        let module = rva + 4 + i * MODULE_SIZE;
+                        modules.push(MinidumpModule {
+                            base: read_u64(&contents, module)? as usize,
+                            size: read_u32(&contents, module + 8)? as usize,
+                            name: read_string(
+                                &contents,
+                                read_u32(&contents, module + 20)? as usize,
+                            )?,
+                            file_version: (
+                                read_u32(&contents, module + 32)?,
+                                read_u32(&contents, module + 36)?,
+                            ),
+                        });
+                    }
+                }
+                MEMORY_LIST_STREAM => {
+                    for i in 0..read_u32(&contents, rva)? as usize {
+                        let descriptor = rva + 4 + i * MEMORY_DESCRIPTOR_SIZE;
+                        regions.push(MemoryRegion {
+                            start: read_u64(&contents, descriptor)? as usize,
+                            size: read_u32(&contents, descriptor + 8)? as usize,
+                            offset: read_u32(&contents, descriptor + 12)? as usize,
+                            writable: true,
+                        });
+                    }
+                }
+                MEMORY_64_LIST_STREAM => {
+                    // the memory of each range follows on from the previous one
+                    let mut offset = read_u64(&contents, rva + 8)? as usize;
+                    for i in 0..read_u64(&contents, rva)? as usize {
+                        let descriptor = rva + 16 + i * MEMORY_DESCRIPTOR_SIZE;
+                        let size = read_u64(&contents, descriptor + 8)? as usize;
+                        regions.push(MemoryRegion {
+                            start: read_u64(&contents, descriptor)? as usize,
+                            size,
+                            offset,
+                            writable: true,
+                        });
+                        offset += size;
+                    }
+                }
+                MEMORY_INFO_LIST_STREAM => {
+                    let header_size = read_u32(&contents, rva)? as usize;
+                    let entry_size = read_u32(&contents, rva + 4)? as usize;
+                    let mut infos = Vec::new();
+                    for i in 0..read_u64(&contents, rva + 8)? as usize {
+                        let info = rva + header_size + i * entry_size;
+                        let protection = read_u32(&contents, info + 36)?;
+                        if protection & WRITABLE_PROTECTION != 0 {
+                            infos.push((
+                                read_u64(&contents, info)? as usize,
+                                read_u64(&contents, info + 24)? as usize,
+                            ));
+                        }
+                    }
+                    writable = Some(infos);
+                }
+                MISC_INFO_STREAM if read_u32(&contents, rva + 4)? & MISC1_PROCESS_ID != 0 => {
+                    pid = Some(read_u32(&contents, rva + 8)?);
+                }
+                EXCEPTION_STREAM => {
+                    exception_code = Some(read_u32(&contents, rva + 8)?);
+                }
+                _ => {}
+            }
+        }
+
+        for region in regions.iter_mut() {
+            if let Some(writable) = &writable {
+                region.writable = writable
+                    .iter()
+                    .any(|&(start, size)| region.start >= start && region.start < start + size);
+            }
+            if region.offset + region.size > contents.len() {
+                return Err(format_err!(
+                    "{} is truncated, it doesn't have the memory at 0x{:016x}",
+                    filename.display(),
+                    region.start
+                ));
+            }
+            debug!(
+                "region: {:016x}-{:016x} {}",
+                region.start,
+                region.start + region.size,
+                if region.writable { "rw" } else { "r-" }
+            );
+        }
+        for module in &modules {
+            info!(
+                "module: {:016x}-{:016x} {}",
+                module.base,
+                module.base + module.size,
+                module.name
+            );
+        }
+
+        Ok(Minidump {
+            filename: filename.to_owned(),
+            contents,
+            regions,
+            modules,
+            pid,
+            exception_code,
+        })
+    }
+
+    pub fn modules(&self) -> &[MinidumpModule] {
+        &self.modules
+    }
+}
+
+impl ProcessMemory for Minidump {
+    fn read(&self, addr: usize, buf: &mut [u8]) -> Result<(), remoteprocess::Error> {
+        // reads can span ranges that are next to each other
+        let mut copied = 0;
+        while copied < buf.len() {
+            let addr = addr + copied;
+            let region = self
+                .regions
+                .iter()
+                .find(|region| region.contains(addr))
+                .ok_or_else(|| {
+                    remoteprocess::Error::Other(format!(
+                        "0x{:016x} isn't in the memory of the minidump",
+                        addr
+                    ))
+                })?;
+            let size = (region.start + region.size - addr).min(buf.len() - copied);
+            let offset = region.offset + addr - region.start;
+            buf[copied..copied + size].copy_from_slice(&self.contents[offset..offset + size]);
+            copied += size;
+        }
+        Ok(())
+    }
+}
+
+pub struct PythonMinidump {
+    dump: Minidump,
+    python_dll: MinidumpModule,
+    interpreter: DumpedInterpreter,
+}
+
+impl PythonMinidump {
+    pub fn new<P: AsRef<Path>>(filename: P, config: &Config) -> Result<PythonMinidump, Error> {
+        let dump = Minidump::new(filename)?;
+        let python_dll = dump
+            .modules()
+            .iter()
+            .find(|module| module.is_python_dll())
+            .cloned()
+            .ok_or_else(|| format_err!("Failed to find the python dll in the minidump"))?;
+        info!(
+            "Found python dll @ 0x{:016x}: {}",
+            python_dll.base, python_dll.name
+        );
+
+        // the dll is only on disk if the minidump is from this machine, or it's been passed
+        let (python_filename, libpython_binary) = match &config.core_exe {
+            Some(exe) => {
+                let filename = PathBuf::from(exe);
+                let binary = parse_python_dll(&filename, &python_dll).with_context(|| {
+                    format!("Failed to parse python dll '{}'", filename.display())
+                })?;
+                (filename, Some(binary))
+            }
+            None => {
+                let filename = PathBuf::from(&python_dll.name);
+                match parse_python_dll(&filename, &python_dll) {
+                    Ok(binary) => (filename, Some(binary)),
+                    Err(e) => {
+                        info!("Failed to parse {}: {}", filename.display(), e);
+                        (filename, None)
+                    }
+                }
+            }
+        };
+        if libpython_binary.is_none() {
+            warn!(
+                "Failed to read '{}', scanning the minidump for the interpreter (pass the dll with --exe to use its symbols)",
+                python_dll.basename()
+            );
+        }
+
+        let python_info = PythonProcessInfo {
+            python_binary: None,
+            libpython_binary,
+            maps: Box::new(dump.regions.clone()),
+            python_filename,
+            #[cfg(target_os = "linux")]
+            dockerized: false,
+        };
+        let interpreter =
+            DumpedInterpreter::new(&python_info, &dump, python_dll.python_version(), config)?;
+        Ok(PythonMinidump {
+            dump,
+            python_dll,
+            interpreter,
+        })
+    }
+
+    pub fn get_stack(&self, config: &Config) -> Result<Vec<StackTrace>, Error> {
+        self.interpreter.get_stack(&self.dump, config)
+    }
+
+    pub fn print_traces(&self, traces: &Vec<StackTrace>, config: &Config) -> Result<(), Error> {
+        if let Some(filename) = &config.locals_output {
+            write_locals_output(traces, filename)?;
+        }
+        if config.dump_json {
+            println!("{}", serde_json::to_string_pretty(&traces)?);
+            return Ok(());
+        }
+
+        if let Some(code) = self.dump.exception_code {
+            println!(
+                "Exception {}: {}",
+                style(format!("0x{:08x}", code)).bold().yellow(),
+                self.dump.filename.display()
+            );
+        }
+
+        if let Some(pid) = self.dump.pid {
+            let exe = self
+                .dump
+                .modules()
+                .first()
+                .map(|module| module.name.as_str());
+            println!(
+                "Process {}: {}",
+                style(pid).bold().yellow(),
+                exe.unwrap_or_default()
+            );
+        }
+        println!(
+            "Python v{} ({})",
+            style(&self.interpreter.version).bold(),
+            self.python_dll.basename()
+        );
+        println!();
+        for trace in traces.iter().rev() {
+            print_trace(trace, false);
+        }
+        Ok(())
+    }
+}
+
+/// Parses the python dll, with its exports at the addresses they were loaded at
+fn parse_python_dll(filename: &Path, module: &MinidumpModule) -> Result<BinaryInfo, Error> {
+    let mut binary = parse_binary(filename, module.base as u64, module.size as u64)?;
+    // parse_binary gives the exports at their offset in the file rather than where they are in
+    // memory, which is the same for code but not for data like _PyRuntime
+    let contents = std::fs::read(filename)?;
+    let pe = goblin::pe::PE::parse(&contents)?;
+    for export in pe.exports {
+        if let Some(name) = export.name {
+            let addr = module.base as u64 + export.rva as u64;
+            binary.symbols.insert(name.to_owned(), addr);
+        }
+    }
+    Ok(binary)
+}
+
+fn read_bytes(contents: &[u8], offset: usize, size: usize) -> Result<&[u8], Error> {
+    contents
+        .get(offset..offset + size)
+        .ok_or_else(|| format_err!("minidump is truncated at offset 0x{:x}", offset))
+}
+
+fn read_u32(contents: &[u8], offset: usize) -> Result<u32, Error> {
+    let bytes = read_bytes(contents, offset, 4)?;
+    Ok(u32::from_le_bytes(bytes.try_into()?))
+}
+
+fn read_u64(contents: &[u8], offset: usize) -> Result<u64, Error> {
+    let bytes = read_bytes(contents, offset, 8)?;
+    Ok(u64::from_le_bytes(bytes.try_into()?))
+}
+
+/// Reads a MINIDUMP_STRING, which is the length in bytes followed by UTF-16
+fn read_string(contents: &[u8], offset: usize) -> Result<String, Error> {
+    let size = read_u32(contents, offset)? as usize;
+    let units: Vec<u16> = read_bytes(contents, offset + 4, size)?
+        .chunks_exact(2)
+        .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+        .collect();
+    Ok(String::from_utf16_lossy(&units))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes a minidump with the python dll, some memory and the process id
+    fn write_minidump(file: &mut File) {
+        use std::io::Write;
+
+        fn u32s(values: &[u32]) -> Vec<u8> {
+            values.iter().flat_map(|v| v.to_le_bytes()).collect()
+        }
+        fn u64s(values: &[u64]) -> Vec<u8> {
+            values.iter().flat_map(|v| v.to_le_bytes()).collect()
+        }
+
+        let name = "C:\\Program Files\\App\\python311.dll";
+        let mut contents = Vec::new();
+        let streams = 5;
+        let directory = 32;
+        let mut rva = directory + streams * 12;
+
+        let mut name_data = u32s(&[name.len() as u32 * 2]);
+        name_data.extend(name.encode_utf16().flat_map(|unit| unit.to_le_bytes()));
+        let name_rva = rva;
+        rva += name_data.len();
+
+        let mut modules = u32s(&[1]);
+        let mut module = u64s(&[0x7ff8_0000_0000]);
+        module.extend(u32s(&[
+            0x10_0000,
+            0,
+            0,
+            name_rva as u32,
+            0xfeef04bd,
+            0x10000,
+        ]));
+        // 3.11.7 final
+        module.extend(u32s(&[0x0003_000b, 0x1bfe_03f5]));
+        module.resize(MODULE_SIZE, 0);
+        modules.extend(module);
+        let modules_rva = rva;
+        rva += modules.len();
+
+        let mut memory = u32s(&[1]);
+        memory.extend(u64s(&[0x1000]));
+        memory.extend(u32s(&[4, 0]));
+        let memory_rva = rva;
+        rva += memory.len();
+
+        // two ranges next to each other, and one that isn't writable
+        let mut memory64 = u64s(&[3, 0]);
+        memory64.extend(u64s(&[0x2000, 4, 0x2004, 4, 0x3000, 4]));
+        let memory64_rva = rva;
+        rva += memory64.len();
+
+        let mut infos = u32s(&[16, 48]);
+        infos.extend(u64s(&[1]));
+        let mut info = u64s(&[0x2000, 0x2000, 0x04, 0x1000]);
+        info.extend(u32s(&[0x1000, 0x04, 0x20000, 0]));
+        infos.extend(info);
+        let infos_rva = rva;
+        rva += infos.len();
+
+        let misc = u32s(&[24, MISC1_PROCESS_ID, 4242, 0, 0, 0]);
+        let misc_rva = rva;
+        rva += misc.len();
+
+        let data_rva = rva;
+        let memory_data = b"ABCDefghIJKLmnop";
+
+        // the memory list points at the first four bytes, and the 64 bit list at all of them
+        let memory = [&memory[..16], &u32s(&[data_rva as u32])[..]].concat();
+        let memory64 = [&u64s(&[3, data_rva as u64])[..], &memory64[16..]].concat();
+
+        contents.extend(u32s(&[SIGNATURE, 0xa793, streams as u32, directory as u32]));
+        contents.extend(u32s(&[0; 4]));
+        for (stream_type, data, rva) in [
+            (MODULE_LIST_STREAM, &modules, modules_rva),
+            (MEMORY_LIST_STREAM, &memory, memory_rva),
+            (MEMORY_64_LIST_STREAM, &memory64, memory64_rva),
+            (MEMORY_INFO_LIST_STREAM, &infos, infos_rva),
+            (MISC_INFO_STREAM, &misc, misc_rva),
+        ] {
+            contents.extend(u32s(&[stream_type, data.len() as u32, rva as u32]));
+        }
+        for data in [name_data, modules, memory, memory64, infos, misc] {
+            contents.extend(data);
+        }
+        contents.extend(memory_data);
+        file.write_all(&contents).unwrap();
+    }
+
+    #[test]
+    fn test_minidump() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        write_minidump(file.as_file_mut());
+        assert!(is_minidump(file.path()));
+        let dump = Minidump::new(file.path()).unwrap();
+
+        assert_eq!(dump.pid, Some(4242));
+        assert_eq!(dump.exception_code, None);
+        let module = &dump.modules()[0];
+        assert_eq!(module.base, 0x7ff8_0000_0000);
+        assert_eq!(module.basename(), "python311.dll");
+        assert!(module.is_python_dll());
+        let version = module.python_version().unwrap();
+        assert_eq!((version.major, version.minor, version.patch), (3, 11, 7));
+
+        assert_eq!(dump.copy(0x1000, 4).unwrap(), b"ABCD");
+        // reads can span ranges that are next to each other
+        assert_eq!(dump.copy(0x2002, 4).unwrap(), b"CDef");
+        assert_eq!(dump.copy(0x3000, 4).unwrap(), b"IJKL");
+        assert!(dump.copy(0x3002, 4).is_err());
+        assert!(dump.regions.contains_addr(0x2007));
+        assert!(!dump.regions.contains_addr(0x2008));
+        assert_eq!(
+            dump.regions.writable_regions(),
+            vec![(0x2000, 4), (0x2004, 4)]
+        );
+    }
+
+    #[test]
+    fn test_is_python_dll() {
+        let module = |name: &str| MinidumpModule {
+            base: 0,
+            size: 0,
+            name: name.to_owned(),
+            file_version: (0, 0),
+        };
+        assert!(module("C:\\Python39\\python39.dll").is_python_dll());
+        assert!(
+            module("C:\\Users\\app\\AppData\\Local\\Temp\\_MEI1234\\PYTHON310.DLL").is_python_dll()
+        );
+        assert!(!module("C:\\Python39\\python3.dll").is_python_dll());
+        assert!(!module("C:\\Python39\\python.exe").is_python_dll());
+        assert!(!module("C:\\Windows\\System32\\kernel32.dll").is_python_dll());
+        assert_eq!(module("C:\\Python39\\python39.dll").python_version(), None);
+    }
+}
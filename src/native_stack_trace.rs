@@ -9,6 +9,7 @@ use remoteprocess::{self, Pid};
 
 use crate::binary_parser::BinaryInfo;
 use crate::cython;
+use crate::jit_symbols::{is_python_trampoline, JitSymbols};
 use crate::stack_trace::Frame;
 use crate::utils::resolve_filename;
 
@@ -17,6 +18,7 @@ pub struct NativeStack {
     python: Option<BinaryInfo>,
     libpython: Option<BinaryInfo>,
     cython_maps: cython::SourceMaps,
+    jit_symbols: JitSymbols,
     unwinder: remoteprocess::Unwinder,
     symbolicator: remoteprocess::Symbolicator,
     // TODO: right now on windows if we don't hold on the process handle unwinding will fail
@@ -39,6 +41,7 @@ impl NativeStack {
 
         Ok(NativeStack {
             cython_maps,
+            jit_symbols: JitSymbols::new(pid),
             unwinder,
             symbolicator,
             should_reload: false,
@@ -79,8 +82,8 @@ impl NativeStack {
             let cached_symbol = self.symbol_cache.get(&addr).cloned();
 
             // merges a remoteprocess::StackFrame into the current merged vec
-            let is_python_addr = self.python.as_ref().map_or(false, |m| m.contains(addr))
-                || self.libpython.as_ref().map_or(false, |m| m.contains(addr));
+            let is_python_addr = self.python.as_ref().is_some_and(|m| m.contains(addr))
+                || self.libpython.as_ref().is_some_and(|m| m.contains(addr));
             let merge_frame = &mut |frame: &remoteprocess::StackFrame| {
                 match self.get_merge_strategy(is_python_addr, frame) {
                     MergeType::Ignore => {}
@@ -121,37 +124,49 @@ impl NativeStack {
             let mut symbolicated_count = 0;
             let mut first_frame = None;
 
-            self.symbolicator
-                .symbolicate(
-                    addr,
-                    !is_python_addr,
-                    &mut |frame: &remoteprocess::StackFrame| {
-                        symbolicated_count += 1;
-                        if symbolicated_count == 1 {
-                            first_frame = Some(frame.clone());
-                        }
-                        merge_frame(frame);
-                    },
-                )
-                .unwrap_or_else(|e| {
-                    if let remoteprocess::Error::NoBinaryForAddress(_) = e {
-                        debug!(
-                            "don't have a binary for symbols at 0x{:x} - reloading",
-                            addr
-                        );
-                        self.should_reload = true;
+            let symbolicated = self.symbolicator.symbolicate(
+                addr,
+                !is_python_addr,
+                &mut |frame: &remoteprocess::StackFrame| {
+                    symbolicated_count += 1;
+                    if symbolicated_count == 1 {
+                        first_frame = Some(frame.clone());
+                    }
+                    merge_frame(frame);
+                },
+            );
+
+            // code generated by JITs isn't in any binary, but can be symbolized from the perf map
+            // and jitdump files they write
+            if symbolicated.is_err() {
+                if let Some(frame) = self.jit_symbols.lookup(addr) {
+                    let trampoline = frame.function.as_deref().is_some_and(is_python_trampoline);
+                    if !trampoline {
+                        merged.extend(self.translate_native_frame(&frame));
                     }
-                    // if we can't symbolicate, just insert a stub here.
-                    merged.push(Frame {
-                        filename: "?".to_owned(),
-                        name: format!("0x{:x}", addr),
-                        line: 0,
-                        short_filename: None,
-                        module: None,
-                        locals: None,
-                        is_entry: true,
-                    });
+                    continue;
+                }
+            }
+
+            symbolicated.unwrap_or_else(|e| {
+                if let remoteprocess::Error::NoBinaryForAddress(_) = e {
+                    debug!(
+                        "don't have a binary for symbols at 0x{:x} - reloading",
+                        addr
+                    );
+                    self.should_reload = true;
+                }
+                // if we can't symbolicate, just insert a stub here.
+                merged.push(Frame {
+                    filename: "?".to_owned(),
+                    name: format!("0x{:x}", addr),
+                    line: 0,
+                    short_filename: None,
+                    module: None,
+                    locals: None,
+                    is_entry: true,
                 });
+            });
 
             if symbolicated_count == 1 {
                 self.symbol_cache.put(addr, first_frame.unwrap());
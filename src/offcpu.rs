@@ -0,0 +1,256 @@
+use crate::stack_trace::{Frame, ThreadStatus};
+
+/*
+ * This file contains code to work out why a thread isn't running, for '--offcpu'.
+ *
+ * The scheduler state of each thread is read from /proc/<pid>/task/<tid>/stat, and the kernel
+ * function it's blocked in from /proc/<pid>/task/<tid>/wchan. Threads that are running are left
+ * out of off-cpu profiles, and the rest are classified as:
+ *
+ *  waiting on GIL     - blocked on a futex without holding the GIL, while running python code
+ *  waiting on lock    - blocked on a futex in a lock, condition, queue or join
+ *  sleeping           - blocked in nanosleep (like time.sleep)
+ *  blocked in syscall - blocked in any other system call, like a read or a poll
+ *
+ * Futexes are used both for the GIL and for python locks. These are told apart by the arguments
+ * of the futex call from /proc/<pid>/task/<tid>/syscall: python locks are semaphores, which glibc
+ * waits on with FUTEX_WAIT_BITSET and no timeout unless one was given to acquire(), while
+ * waiting for the GIL either locks a mutex (FUTEX_WAIT) or waits on a condition with a timeout.
+ * Timed waits are told apart by what the thread was doing in python. Some kernels hide wchan (it
+ * then reads as '0'), in which case the thread is classified from its python stack alone.
+ */
+
+const FUTEX_WAIT_BITSET: u64 = 9;
+// the futex operation, without the FUTEX_PRIVATE_FLAG and FUTEX_CLOCK_REALTIME flags
+const FUTEX_CMD_MASK: u64 = 0x7f;
+
+/// The scheduler state of an OS thread
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OsThreadState {
+    /// The state from /proc/<pid>/task/<tid>/stat, like 'R' for running or 'S' for sleeping
+    pub state: char,
+    /// The kernel function the thread is blocked in, if this is available
+    pub wchan: Option<String>,
+    /// The arguments of the system call the thread is blocked in, if this is available
+    pub syscall_args: Option<Vec<u64>>,
+}
+
+#[cfg(target_os = "linux")]
+pub fn os_thread_state(
+    pid: remoteprocess::Pid,
+    tid: remoteprocess::Tid,
+) -> std::io::Result<OsThreadState> {
+    let task = format!("/proc/{}/task/{}", pid, tid);
+    let stat = std::fs::read_to_string(format!("{}/stat", task))?;
+    // the command name in the second field can contain spaces, so skip past it
+    let state = stat
+        .rsplit_once(')')
+        .and_then(|(_, rest)| rest.trim_start().chars().next())
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Failed to parse {}/stat", task),
+            )
+        })?;
+    let wchan = std::fs::read_to_string(format!("{}/wchan", task))
+        .ok()
+        .map(|wchan| wchan.trim().to_owned())
+        .filter(|wchan| !wchan.is_empty() && wchan != "0");
+    // this is the syscall number followed by its six arguments (then the stack pointer and
+    // program counter), or 'running' if the thread isn't in a system call
+    let syscall_args = std::fs::read_to_string(format!("{}/syscall", task))
+        .ok()
+        .and_then(|syscall| {
+            syscall
+                .split_whitespace()
+                .skip(1)
+                .take(6)
+                .map(|arg| u64::from_str_radix(arg.trim_start_matches("0x"), 16).ok())
+                .collect::<Option<Vec<u64>>>()
+        })
+        .filter(|args| args.len() == 6);
+    Ok(OsThreadState {
+        state,
+        wchan,
+        syscall_args,
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn os_thread_state(
+    _pid: remoteprocess::Pid,
+    _tid: remoteprocess::Tid,
+) -> std::io::Result<OsThreadState> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "reading the state of threads is only supported on linux",
+    ))
+}
+
+/// Returns why a thread wasn't running, or None if it was running on a cpu. 'fallback' is the
+/// status worked out from the python stack, for when the kernel doesn't say what the thread is
+/// blocked in
+pub fn wait_reason(
+    os_state: &OsThreadState,
+    owns_gil: bool,
+    frames: &[Frame],
+    fallback: ThreadStatus,
+) -> Option<ThreadStatus> {
+    if os_state.state == 'R' {
+        return None;
+    }
+    let wchan = match &os_state.wchan {
+        Some(wchan) => wchan,
+        None if is_lock_wait(frames) => return Some(ThreadStatus::WaitingOnLock),
+        None if fallback == ThreadStatus::Running => return Some(ThreadStatus::BlockingIo),
+        None => return Some(fallback),
+    };
+    Some(if wchan.contains("futex") {
+        // a thread can't be waiting for the GIL while holding it
+        if owns_gil || is_semaphore_wait(os_state) || is_lock_wait(frames) {
+            ThreadStatus::WaitingOnLock
+        } else {
+            ThreadStatus::WaitingForGil
+        }
+    } else if wchan.contains("nanosleep") {
+        ThreadStatus::Sleeping
+    } else {
+        ThreadStatus::BlockingIo
+    })
+}
+
+/// A frame to add to the leaf of off-cpu stacks, so that flamegraphs show why they were waiting
+pub fn wait_frame(reason: ThreadStatus) -> Frame {
+    Frame {
+        name: format!("[{}]", reason.wait_reason()),
+        filename: String::from(""),
+        module: None,
+        short_filename: None,
+        line: 0,
+        locals: None,
+        is_entry: true,
+    }
+}
+
+/// Whether a thread blocked on a futex is waiting on a semaphore without a timeout, which is how
+/// python locks are acquired
+fn is_semaphore_wait(os_state: &OsThreadState) -> bool {
+    match &os_state.syscall_args {
+        // futex(uaddr, op, val, timeout, ..)
+        Some(args) => args[1] & FUTEX_CMD_MASK == FUTEX_WAIT_BITSET && args[3] == 0,
+        None => false,
+    }
+}
+
+/// Whether the leaf python frame is waiting on a threading primitive or a queue
+fn is_lock_wait(frames: &[Frame]) -> bool {
+    match frames.first() {
+        Some(frame) => {
+            (frame.filename.ends_with("threading.py")
+                && ["wait", "acquire", "join", "_wait_for_tstate_lock"]
+                    .contains(&frame.name.as_str()))
+                || (frame.filename.ends_with("queue.py")
+                    && ["get", "put", "join"].contains(&frame.name.as_str()))
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::frame;
+
+    fn os_state(state: char, wchan: Option<&str>) -> OsThreadState {
+        OsThreadState {
+            state,
+            wchan: wchan.map(|w| w.to_owned()),
+            syscall_args: None,
+        }
+    }
+
+    fn futex(op: u64, timeout: u64) -> OsThreadState {
+        OsThreadState {
+            syscall_args: Some(vec![0x7f00, op, 0, timeout, 0, 0]),
+            ..os_state('S', Some("futex_do_wait"))
+        }
+    }
+
+    #[test]
+    fn test_wait_reason() {
+        let busy = vec![frame("busy", "app.py", 1)];
+        let lock = vec![frame("wait", "/usr/lib/python3.11/threading.py", 1)];
+        let fallback = ThreadStatus::Sleeping;
+
+        // running threads aren't off-cpu
+        let running = os_state('R', None);
+        assert_eq!(wait_reason(&running, true, &busy, fallback), None);
+
+        let futex_wait = os_state('S', Some("futex_wait_queue"));
+        assert_eq!(
+            wait_reason(&futex_wait, false, &busy, fallback),
+            Some(ThreadStatus::WaitingForGil)
+        );
+        assert_eq!(
+            wait_reason(&futex_wait, false, &lock, fallback),
+            Some(ThreadStatus::WaitingOnLock)
+        );
+        // python locks are semaphores, while the GIL is a mutex and a timed condition
+        let semaphore = futex(0x189, 0);
+        assert_eq!(
+            wait_reason(&semaphore, false, &busy, fallback),
+            Some(ThreadStatus::WaitingOnLock)
+        );
+        let mutex = futex(0x80, 0);
+        assert_eq!(
+            wait_reason(&mutex, false, &busy, fallback),
+            Some(ThreadStatus::WaitingForGil)
+        );
+        let condition = futex(0x89, 0x7f10);
+        assert_eq!(
+            wait_reason(&condition, false, &busy, fallback),
+            Some(ThreadStatus::WaitingForGil)
+        );
+        // native code can block on a lock without releasing the GIL
+        assert_eq!(
+            wait_reason(&futex_wait, true, &busy, fallback),
+            Some(ThreadStatus::WaitingOnLock)
+        );
+
+        let sleep = os_state('S', Some("hrtimer_nanosleep"));
+        assert_eq!(
+            wait_reason(&sleep, false, &busy, fallback),
+            Some(ThreadStatus::Sleeping)
+        );
+        let read = os_state('S', Some("unix_stream_data_wait"));
+        assert_eq!(
+            wait_reason(&read, false, &busy, fallback),
+            Some(ThreadStatus::BlockingIo)
+        );
+        let disk = os_state('D', Some("io_schedule"));
+        assert_eq!(
+            wait_reason(&disk, true, &busy, fallback),
+            Some(ThreadStatus::BlockingIo)
+        );
+
+        // without wchan, the python stack is used
+        let hidden = os_state('S', None);
+        assert_eq!(
+            wait_reason(&hidden, false, &lock, fallback),
+            Some(ThreadStatus::WaitingOnLock)
+        );
+        assert_eq!(
+            wait_reason(&hidden, false, &busy, ThreadStatus::WaitingForGil),
+            Some(ThreadStatus::WaitingForGil)
+        );
+        assert_eq!(
+            wait_reason(&hidden, true, &busy, ThreadStatus::Running),
+            Some(ThreadStatus::BlockingIo)
+        );
+
+        assert_eq!(
+            wait_frame(ThreadStatus::WaitingOnLock).name,
+            "[waiting on lock]"
+        );
+    }
+}
@@ -0,0 +1,305 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Error;
+
+use crate::protobuf::{encode_message, encode_packed, encode_varint_field};
+use crate::stack_trace::{Frame, StackTrace};
+
+/*
+ * This file contains code to export profiles with the OpenTelemetry (OTLP) profiles signal,
+ * so that py-spy can send profiles to an existing OpenTelemetry collector.
+ *
+ * The profiles signal is still experimental, and its schema has changed between releases of
+ * opentelemetry-proto. This targets the 'v1development' schema from opentelemetry-proto v1.5:
+ * https://github.com/open-telemetry/opentelemetry-proto/blob/v1.5.0/opentelemetry/proto/profiles/v1development/profiles.proto
+ *
+ * Each profile is written out as an ExportProfilesServiceRequest, containing a single resource
+ * (with attributes like service.name and host.name) and a single pprof-like profile. Like the
+ * pprof exporter, the messages are encoded by hand (see protobuf.rs).
+ */
+
+pub struct Otlp {
+    resource: Vec<(String, String)>,
+    strings: Vec<String>,
+    string_index: HashMap<String, i64>,
+    // (name, filename) string indices -> index in function_table
+    functions: HashMap<(i64, i64), u64>,
+    function_table: Vec<(i64, i64)>,
+    // (function index, line) -> index in location_table
+    locations: HashMap<(u64, i64), u64>,
+    location_table: Vec<(u64, i64)>,
+    // (key, value) string indices -> index in attribute_table
+    attributes: HashMap<(i64, i64), u64>,
+    attribute_table: Vec<(i64, i64)>,
+    // (stack of location indices (leaf first), attribute indices) -> number of samples
+    samples: HashMap<(Vec<u64>, Vec<u64>), i64>,
+    show_linenumbers: bool,
+    sampling_rate: u64,
+    start_time: SystemTime,
+}
+
+impl Otlp {
+    pub fn new(
+        show_linenumbers: bool,
+        sampling_rate: u64,
+        resource: Vec<(String, String)>,
+    ) -> Otlp {
+        Otlp {
+            resource,
+            // like pprof, the first entry of the string table has to be ""
+            strings: vec![String::new()],
+            string_index: HashMap::new(),
+            functions: HashMap::new(),
+            function_table: Vec::new(),
+            locations: HashMap::new(),
+            location_table: Vec::new(),
+            attributes: HashMap::new(),
+            attribute_table: Vec::new(),
+            samples: HashMap::new(),
+            show_linenumbers,
+            sampling_rate,
+            start_time: SystemTime::now(),
+        }
+    }
+
+    /// The resource attributes this profile is reported with
+    pub fn resource(&self) -> &[(String, String)] {
+        &self.resource
+    }
+
+    pub fn increment(&mut self, trace: &StackTrace) -> std::io::Result<()> {
+        let stack = trace
+            .frames
+            .iter()
+            .map(|frame| self.location_index(frame))
+            .collect();
+
+        // thread attributes use the OpenTelemetry semantic conventions
+        let mut attributes = vec![self.attribute_index("thread.id", &trace.format_threadid())];
+        if let Some(name) = trace.thread_name.as_ref() {
+            attributes.push(self.attribute_index("thread.name", name));
+        }
+        *self.samples.entry((stack, attributes)).or_insert(0) += 1;
+        Ok(())
+    }
+
+    pub fn write(&self, w: &mut dyn Write) -> Result<(), Error> {
+        w.write_all(&self.encode())?;
+        Ok(())
+    }
+
+    fn string_index(&mut self, s: &str) -> i64 {
+        if s.is_empty() {
+            return 0;
+        }
+        if let Some(index) = self.string_index.get(s) {
+            return *index;
+        }
+        let index = self.strings.len() as i64;
+        self.strings.push(s.to_owned());
+        self.string_index.insert(s.to_owned(), index);
+        index
+    }
+
+    fn attribute_index(&mut self, key: &str, value: &str) -> u64 {
+        let key = (self.string_index(key), self.string_index(value));
+        let attribute_table = &mut self.attribute_table;
+        *self.attributes.entry(key).or_insert_with(|| {
+            attribute_table.push(key);
+            (attribute_table.len() - 1) as u64
+        })
+    }
+
+    fn location_index(&mut self, frame: &Frame) -> u64 {
+        let filename = match &frame.short_filename {
+            Some(f) => f,
+            None => &frame.filename,
+        };
+        let key = (self.string_index(&frame.name), self.string_index(filename));
+        let function_table = &mut self.function_table;
+        let function_index = *self.functions.entry(key).or_insert_with(|| {
+            function_table.push(key);
+            (function_table.len() - 1) as u64
+        });
+
+        let line = if self.show_linenumbers {
+            frame.line as i64
+        } else {
+            0
+        };
+        let location_table = &mut self.location_table;
+        *self
+            .locations
+            .entry((function_index, line))
+            .or_insert_with(|| {
+                location_table.push((function_index, line));
+                (location_table.len() - 1) as u64
+            })
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        // string table indices for our sample/period types, added to a copy of the string table
+        // since it is frozen by the time we're writing out
+        let mut strings = self.strings.clone();
+        let mut intern = |s: &str| match self.string_index.get(s) {
+            Some(index) => *index as u64,
+            None => {
+                strings.push(s.to_owned());
+                (strings.len() - 1) as u64
+            }
+        };
+        let samples_str = intern("samples");
+        let count_str = intern("count");
+        let cpu_str = intern("cpu");
+        let nanoseconds_str = intern("nanoseconds");
+
+        let mut profile = Vec::new();
+
+        // sample_type = 1
+        let mut sample_type = Vec::new();
+        encode_varint_field(&mut sample_type, 1, samples_str);
+        encode_varint_field(&mut sample_type, 2, count_str);
+        encode_message(&mut profile, 1, &sample_type);
+
+        // sample = 2, with each sample pointing to a range of location_indices
+        let mut location_indices = Vec::new();
+        for ((stack, attributes), count) in self.samples.iter() {
+            let mut sample = Vec::new();
+            encode_varint_field(&mut sample, 1, location_indices.len() as u64);
+            encode_varint_field(&mut sample, 2, stack.len() as u64);
+            encode_packed(&mut sample, 3, std::iter::once(*count as u64));
+            encode_packed(&mut sample, 4, attributes.iter().cloned());
+            encode_message(&mut profile, 2, &sample);
+            location_indices.extend(stack.iter().cloned());
+        }
+
+        // location_table = 4
+        for (function_index, line) in self.location_table.iter() {
+            let mut line_message = Vec::new();
+            encode_varint_field(&mut line_message, 1, *function_index);
+            encode_varint_field(&mut line_message, 2, *line as u64);
+            let mut location = Vec::new();
+            encode_message(&mut location, 3, &line_message);
+            encode_message(&mut profile, 4, &location);
+        }
+
+        // location_indices = 5
+        encode_packed(&mut profile, 5, location_indices);
+
+        // function_table = 6
+        for (name, filename) in self.function_table.iter() {
+            let mut function = Vec::new();
+            encode_varint_field(&mut function, 1, *name as u64);
+            encode_varint_field(&mut function, 2, *name as u64);
+            encode_varint_field(&mut function, 3, *filename as u64);
+            encode_message(&mut profile, 6, &function);
+        }
+
+        // attribute_table = 7
+        for (key, value) in self.attribute_table.iter() {
+            encode_message(
+                &mut profile,
+                7,
+                &key_value(&strings[*key as usize], &strings[*value as usize]),
+            );
+        }
+
+        // string_table = 10
+        for s in strings.iter() {
+            encode_message(&mut profile, 10, s.as_bytes());
+        }
+
+        // time_nanos = 11, duration_nanos = 12
+        let start = self
+            .start_time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let duration = self.start_time.elapsed().unwrap_or_default();
+        encode_varint_field(&mut profile, 11, start.as_nanos() as u64);
+        encode_varint_field(&mut profile, 12, duration.as_nanos() as u64);
+
+        // period_type = 13, period = 14
+        let mut period_type = Vec::new();
+        encode_varint_field(&mut period_type, 1, cpu_str);
+        encode_varint_field(&mut period_type, 2, nanoseconds_str);
+        encode_message(&mut profile, 13, &period_type);
+        encode_varint_field(&mut profile, 14, 1_000_000_000 / self.sampling_rate.max(1));
+
+        // profile_id = 17, which has to be 16 random bytes
+        encode_message(&mut profile, 17, &rand::random::<[u8; 16]>());
+
+        // ScopeProfiles { scope = 1, profiles = 2 }
+        let mut scope = Vec::new();
+        encode_message(&mut scope, 1, b"py-spy");
+        encode_message(&mut scope, 2, env!("CARGO_PKG_VERSION").as_bytes());
+        let mut scope_profiles = Vec::new();
+        encode_message(&mut scope_profiles, 1, &scope);
+        encode_message(&mut scope_profiles, 2, &profile);
+
+        // ResourceProfiles { resource = 1, scope_profiles = 2 }
+        let mut resource = Vec::new();
+        for (key, value) in self.resource.iter() {
+            encode_message(&mut resource, 1, &key_value(key, value));
+        }
+        let mut resource_profiles = Vec::new();
+        encode_message(&mut resource_profiles, 1, &resource);
+        encode_message(&mut resource_profiles, 2, &scope_profiles);
+
+        // ExportProfilesServiceRequest { resource_profiles = 1 }
+        let mut out = Vec::new();
+        encode_message(&mut out, 1, &resource_profiles);
+        out
+    }
+}
+
+/// Encodes a KeyValue message with a string AnyValue
+fn key_value(key: &str, value: &str) -> Vec<u8> {
+    let mut any_value = Vec::new();
+    encode_message(&mut any_value, 1, value.as_bytes());
+    let mut out = Vec::new();
+    encode_message(&mut out, 1, key.as_bytes());
+    encode_message(&mut out, 2, &any_value);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{frame, trace};
+
+    #[test]
+    fn test_otlp_tables() {
+        let resource = vec![(String::from("service.name"), String::from("myservice"))];
+        let mut otlp = Otlp::new(true, 100, resource);
+        let mut trace = trace(vec![
+            frame("inner", "test.py", 2),
+            frame("outer", "test.py", 10),
+        ]);
+        trace.pid = 1234;
+        trace.thread_name = Some(String::from("MainThread"));
+        otlp.increment(&trace).unwrap();
+        otlp.increment(&trace).unwrap();
+        trace.frames.remove(0);
+        otlp.increment(&trace).unwrap();
+
+        assert_eq!(otlp.samples.len(), 2);
+        assert_eq!(otlp.function_table.len(), 2);
+        assert_eq!(otlp.location_table.len(), 2);
+        // thread.id and thread.name are shared by all the samples
+        assert_eq!(otlp.attribute_table.len(), 2);
+
+        // unlike pprof, indices into the tables start at 0
+        let inner = otlp.string_index("inner");
+        assert_eq!(otlp.function_table[0].0, inner);
+        assert_eq!(otlp.location_table[0], (0, 2));
+
+        let mut out = Vec::new();
+        otlp.write(&mut out).unwrap();
+        // a single ResourceProfiles message, with the service.name resource attribute
+        assert_eq!(out[0], 0x0a);
+        assert!(out.windows(9).any(|w| w == b"myservice"));
+        assert!(out.windows(11).any(|w| w == b"thread.name"));
+    }
+}
@@ -0,0 +1,261 @@
+use std::io::Write;
+use std::sync::Arc;
+use std::time::Instant;
+
+use ::parquet::basic::Compression;
+use ::parquet::data_type::{BoolType, ByteArray, ByteArrayType, Int32Type, Int64Type};
+use ::parquet::file::properties::WriterProperties;
+use ::parquet::file::writer::SerializedFileWriter;
+use ::parquet::schema::parser::parse_message_type;
+use anyhow::Error;
+
+use crate::stack_trace::StackTrace;
+
+/*
+ * This file contains code to export samples as a Parquet table, so that profiles can be queried
+ * with tools like pandas or DuckDB.
+ *
+ * Unlike the other output formats, nothing is aggregated here: there is one row for every frame
+ * of every sampled stack trace. Frames belonging to the same stack trace share a 'sample' id,
+ * and 'depth' is the position of the frame in the stack, with 0 being the outermost frame.
+ */
+
+const SCHEMA: &str = "
+message py_spy_sample {
+    REQUIRED INT64 sample;
+    REQUIRED INT64 timestamp;
+    REQUIRED INT64 pid;
+    REQUIRED INT64 thread_id;
+    OPTIONAL INT64 os_thread_id;
+    OPTIONAL BYTE_ARRAY thread_name (UTF8);
+    REQUIRED BYTE_ARRAY function (UTF8);
+    REQUIRED BYTE_ARRAY filename (UTF8);
+    OPTIONAL BYTE_ARRAY module (UTF8);
+    REQUIRED INT32 line;
+    REQUIRED INT32 depth;
+    REQUIRED BOOLEAN active;
+    REQUIRED BOOLEAN owns_gil;
+}
+";
+
+#[derive(Default)]
+struct Columns {
+    sample: Vec<i64>,
+    timestamp: Vec<i64>,
+    pid: Vec<i64>,
+    thread_id: Vec<i64>,
+    os_thread_id: OptionalColumn<i64>,
+    thread_name: OptionalColumn<ByteArray>,
+    function: Vec<ByteArray>,
+    filename: Vec<ByteArray>,
+    module: OptionalColumn<ByteArray>,
+    line: Vec<i32>,
+    depth: Vec<i32>,
+    active: Vec<bool>,
+    owns_gil: Vec<bool>,
+}
+
+// parquet stores nullable columns as the non-null values, along with a 'definition level'
+// for each row that is 0 when the value is null
+struct OptionalColumn<T> {
+    values: Vec<T>,
+    def_levels: Vec<i16>,
+}
+
+impl<T> Default for OptionalColumn<T> {
+    fn default() -> Self {
+        OptionalColumn {
+            values: Vec::new(),
+            def_levels: Vec::new(),
+        }
+    }
+}
+
+impl<T> OptionalColumn<T> {
+    fn push(&mut self, value: Option<T>) {
+        match value {
+            Some(value) => {
+                self.values.push(value);
+                self.def_levels.push(1);
+            }
+            None => self.def_levels.push(0),
+        }
+    }
+}
+
+pub struct Parquet {
+    columns: Columns,
+    samples: i64,
+    start_ts: Instant,
+    show_linenumbers: bool,
+}
+
+impl Parquet {
+    pub fn new(show_linenumbers: bool) -> Parquet {
+        Parquet {
+            columns: Columns::default(),
+            samples: 0,
+            start_ts: Instant::now(),
+            show_linenumbers,
+        }
+    }
+
+    pub fn increment(&mut self, trace: &StackTrace) -> std::io::Result<()> {
+        let now = self.start_ts.elapsed().as_micros() as u64;
+        self.increment_at(trace, now)
+    }
+
+    pub fn increment_at(&mut self, trace: &StackTrace, timestamp: u64) -> std::io::Result<()> {
+        let columns = &mut self.columns;
+        for (depth, frame) in trace.frames.iter().rev().enumerate() {
+            columns.sample.push(self.samples);
+            columns.timestamp.push(timestamp as i64);
+            columns.pid.push(trace.pid as i64);
+            columns.thread_id.push(trace.thread_id as i64);
+            columns
+                .os_thread_id
+                .push(trace.os_thread_id.map(|tid| tid as i64));
+            columns
+                .thread_name
+                .push(trace.thread_name.as_deref().map(ByteArray::from));
+            columns.function.push(ByteArray::from(frame.name.as_str()));
+            let filename = match &frame.short_filename {
+                Some(f) => f,
+                None => &frame.filename,
+            };
+            columns.filename.push(ByteArray::from(filename.as_str()));
+            columns
+                .module
+                .push(frame.module.as_deref().map(ByteArray::from));
+            columns
+                .line
+                .push(if self.show_linenumbers { frame.line } else { 0 });
+            columns.depth.push(depth as i32);
+            columns.active.push(trace.active);
+            columns.owns_gil.push(trace.owns_gil);
+        }
+        self.samples += 1;
+        Ok(())
+    }
+
+    pub fn write(&self, w: &mut dyn Write) -> Result<(), Error> {
+        let schema = Arc::new(parse_message_type(SCHEMA)?);
+        let props = Arc::new(
+            WriterProperties::builder()
+                .set_compression(Compression::SNAPPY)
+                .set_created_by(format!("py-spy version {}", env!("CARGO_PKG_VERSION")))
+                .build(),
+        );
+
+        // the parquet writer needs to own a Send writer, so buffer up the output first
+        let mut buffer = Vec::new();
+        let mut writer = SerializedFileWriter::new(&mut buffer, schema, props)?;
+        let mut row_group = writer.next_row_group()?;
+        let columns = &self.columns;
+        let mut index = 0;
+        while let Some(mut column) = row_group.next_column()? {
+            match index {
+                0 => column
+                    .typed::<Int64Type>()
+                    .write_batch(&columns.sample, None, None)?,
+                1 => column
+                    .typed::<Int64Type>()
+                    .write_batch(&columns.timestamp, None, None)?,
+                2 => column
+                    .typed::<Int64Type>()
+                    .write_batch(&columns.pid, None, None)?,
+                3 => column
+                    .typed::<Int64Type>()
+                    .write_batch(&columns.thread_id, None, None)?,
+                4 => column.typed::<Int64Type>().write_batch(
+                    &columns.os_thread_id.values,
+                    Some(&columns.os_thread_id.def_levels),
+                    None,
+                )?,
+                5 => column.typed::<ByteArrayType>().write_batch(
+                    &columns.thread_name.values,
+                    Some(&columns.thread_name.def_levels),
+                    None,
+                )?,
+                6 => column
+                    .typed::<ByteArrayType>()
+                    .write_batch(&columns.function, None, None)?,
+                7 => column
+                    .typed::<ByteArrayType>()
+                    .write_batch(&columns.filename, None, None)?,
+                8 => column.typed::<ByteArrayType>().write_batch(
+                    &columns.module.values,
+                    Some(&columns.module.def_levels),
+                    None,
+                )?,
+                9 => column
+                    .typed::<Int32Type>()
+                    .write_batch(&columns.line, None, None)?,
+                10 => column
+                    .typed::<Int32Type>()
+                    .write_batch(&columns.depth, None, None)?,
+                11 => column
+                    .typed::<BoolType>()
+                    .write_batch(&columns.active, None, None)?,
+                12 => column
+                    .typed::<BoolType>()
+                    .write_batch(&columns.owns_gil, None, None)?,
+                _ => return Err(format_err!("Unexpected parquet column {}", index)),
+            };
+            column.close()?;
+            index += 1;
+        }
+        row_group.close()?;
+        writer.close()?;
+
+        w.write_all(&buffer)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{frame, trace};
+    use ::parquet::file::reader::{FileReader, SerializedFileReader};
+    use ::parquet::record::RowAccessor;
+
+    #[test]
+    fn test_parquet_rows() {
+        let mut parquet = Parquet::new(true);
+        let mut trace = trace(vec![
+            frame("inner", "test.py", 2),
+            frame("outer", "test.py", 10),
+        ]);
+        trace.pid = 1234;
+        trace.thread_name = Some(String::from("MainThread"));
+        parquet.increment_at(&trace, 100).unwrap();
+        parquet.increment_at(&trace, 200).unwrap();
+
+        let mut file = tempfile::tempfile().unwrap();
+        parquet.write(&mut file).unwrap();
+
+        let reader = SerializedFileReader::new(file).unwrap();
+        assert_eq!(reader.metadata().file_metadata().num_rows(), 4);
+        let rows: Vec<_> = reader
+            .get_row_iter(None)
+            .unwrap()
+            .map(|row| row.unwrap())
+            .collect();
+
+        // frames are written out root first
+        assert_eq!(rows[0].get_string(6).unwrap(), "outer");
+        assert_eq!(rows[0].get_int(10).unwrap(), 0);
+        assert_eq!(rows[1].get_string(6).unwrap(), "inner");
+        assert_eq!(rows[1].get_int(9).unwrap(), 2);
+        assert_eq!(rows[1].get_int(10).unwrap(), 1);
+
+        assert_eq!(rows[2].get_long(0).unwrap(), 1);
+        assert_eq!(rows[2].get_long(1).unwrap(), 200);
+        assert_eq!(rows[2].get_long(2).unwrap(), 1234);
+        assert_eq!(rows[2].get_string(5).unwrap(), "MainThread");
+        assert!(rows[2].get_long(4).is_err());
+        assert!(rows[2].get_bool(11).unwrap());
+        assert!(!rows[2].get_bool(12).unwrap());
+    }
+}
@@ -0,0 +1,408 @@
+use std::cmp::min;
+use std::collections::HashMap;
+use std::io::Write;
+use std::time::Instant;
+
+use anyhow::Error;
+use remoteprocess::Pid;
+
+use crate::protobuf::{encode_message, encode_varint_field};
+use crate::stack_trace::{Frame, StackTrace};
+use crate::thread_lifetimes::{ThreadEvent, ThreadEventKind};
+
+/*
+ * This file contains code to export py-spy profiles as a native Perfetto trace, for viewing
+ * in https://ui.perfetto.dev
+ *
+ * The trace is a stream of TracePacket protocol buffers, with the schema defined here:
+ * https://github.com/google/perfetto/tree/master/protos/perfetto/trace
+ *
+ * Like the chrometrace output, consecutive samples with the same frames are merged into
+ * slices. Unlike chrometrace, each process and thread gets its own TrackDescriptor - so we
+ * don't need to remap thread ids into 32 bits - and function names and source locations are
+ * interned so that they're only written out once. Each thread also gets a counter track that
+ * shows when it was holding the GIL.
+ *
+ * Threads that start or exit during the recording are marked with instant events, and the slices
+ * that were open on a thread are ended when it exits.
+ */
+
+// all of our packets are written out on a single sequence
+const SEQUENCE_ID: u64 = 1;
+const SEQ_INCREMENTAL_STATE_CLEARED: u64 = 1;
+const SEQ_NEEDS_INCREMENTAL_STATE: u64 = 2;
+
+const TYPE_SLICE_BEGIN: u64 = 1;
+const TYPE_SLICE_END: u64 = 2;
+const TYPE_INSTANT: u64 = 3;
+const TYPE_COUNTER: u64 = 4;
+
+struct ThreadTrack {
+    uuid: u64,
+    gil_uuid: u64,
+    owns_gil: Option<bool>,
+}
+
+pub struct Perfetto {
+    // encoded Trace message, written out as we go
+    packets: Vec<u8>,
+    start_ts: Instant,
+    end_ts: u64,
+    process_tracks: HashMap<Pid, u64>,
+    thread_tracks: HashMap<(Pid, u64), ThreadTrack>,
+    prev_traces: HashMap<(Pid, u64), StackTrace>,
+    event_names: HashMap<String, u64>,
+    source_locations: HashMap<(String, String, i32), u64>,
+    next_uuid: u64,
+    incremental_state: bool,
+    show_linenumbers: bool,
+}
+
+impl Perfetto {
+    pub fn new(show_linenumbers: bool) -> Perfetto {
+        Perfetto {
+            packets: Vec::new(),
+            start_ts: Instant::now(),
+            end_ts: 0,
+            process_tracks: HashMap::new(),
+            thread_tracks: HashMap::new(),
+            prev_traces: HashMap::new(),
+            event_names: HashMap::new(),
+            source_locations: HashMap::new(),
+            next_uuid: 1,
+            incremental_state: false,
+            show_linenumbers,
+        }
+    }
+
+    pub fn increment(&mut self, trace: &StackTrace) -> std::io::Result<()> {
+        let now = self.start_ts.elapsed().as_micros() as u64;
+        self.increment_at(trace, now)
+    }
+
+    /// Records a trace sampled at a given time, in microseconds since the start of the recording
+    pub fn increment_at(&mut self, trace: &StackTrace, now: u64) -> std::io::Result<()> {
+        self.end_ts = self.end_ts.max(now);
+        let key = (trace.pid, trace.thread_id);
+        let track = self.thread_track(trace);
+
+        let prev_frames = self
+            .prev_traces
+            .remove(&key)
+            .map(|t| t.frames)
+            .unwrap_or_default();
+
+        // Find the index where we first see new frames.
+        let new_idx = prev_frames
+            .iter()
+            .rev()
+            .zip(trace.frames.iter().rev())
+            .position(|(a, b)| !self.should_merge_frames(a, b))
+            .unwrap_or(min(prev_frames.len(), trace.frames.len()));
+
+        // End slices for the previous frames that got dropped, and start slices for the
+        // frames that got added in the most recent trace.
+        for _ in prev_frames.iter().rev().skip(new_idx) {
+            self.slice_end(now, track);
+        }
+        for frame in trace.frames.iter().rev().skip(new_idx) {
+            self.slice_begin(now, track, frame);
+        }
+
+        // Update the GIL counter for this thread when it changes
+        let thread = self.thread_tracks.get_mut(&key).unwrap();
+        if thread.owns_gil != Some(trace.owns_gil) {
+            thread.owns_gil = Some(trace.owns_gil);
+            let mut event = Vec::new();
+            encode_varint_field(&mut event, 9, TYPE_COUNTER);
+            encode_varint_field(&mut event, 11, thread.gil_uuid);
+            encode_varint_field(&mut event, 30, trace.owns_gil as u64);
+            self.push_event(now, &event, &[]);
+        }
+
+        self.prev_traces.insert(key, trace.clone());
+        Ok(())
+    }
+
+    /// Marks a thread starting or exiting at a given time. The slices that were open on a thread
+    /// are ended when it exits, and a new thread that reuses its thread id gets its own track
+    pub fn add_thread_event(&mut self, event: &ThreadEvent, now: u64) {
+        self.end_ts = self.end_ts.max(now);
+        let trace = &event.thread;
+        let key = (trace.pid, trace.thread_id);
+        let track = self.thread_track(trace);
+        let name = match event.kind {
+            ThreadEventKind::Started => "thread started",
+            ThreadEventKind::Exited => "thread exited",
+        };
+        if event.kind == ThreadEventKind::Exited {
+            if let Some(prev_trace) = self.prev_traces.remove(&key) {
+                for _ in prev_trace.frames.iter() {
+                    self.slice_end(now, track);
+                }
+            }
+            self.thread_tracks.remove(&key);
+        }
+
+        let mut interned = Vec::new();
+        let name_iid = self.event_name(name, &mut interned);
+        let mut event = Vec::new();
+        encode_varint_field(&mut event, 9, TYPE_INSTANT);
+        encode_varint_field(&mut event, 10, name_iid);
+        encode_varint_field(&mut event, 11, track);
+        self.push_event(now, &event, &interned);
+    }
+
+    pub fn write(&self, w: &mut dyn Write) -> Result<(), Error> {
+        w.write_all(&self.packets)?;
+
+        // End any unfinished slices.
+        let mut packets = Vec::new();
+        for (key, trace) in self.prev_traces.iter() {
+            let track = self.thread_tracks[key].uuid;
+            for _ in trace.frames.iter() {
+                let mut event = Vec::new();
+                encode_varint_field(&mut event, 9, TYPE_SLICE_END);
+                encode_varint_field(&mut event, 11, track);
+                let packet = event_packet(self.end_ts, &event, &[], SEQ_NEEDS_INCREMENTAL_STATE);
+                encode_message(&mut packets, 1, &packet);
+            }
+        }
+        w.write_all(&packets)?;
+        Ok(())
+    }
+
+    // Return whether these frames are similar enough such that we should merge
+    // them, instead of creating separate slices for them.
+    fn should_merge_frames(&self, a: &Frame, b: &Frame) -> bool {
+        a.name == b.name && a.filename == b.filename && (!self.show_linenumbers || a.line == b.line)
+    }
+
+    /// Returns the interned id of an event name, adding it to 'interned' if it's new
+    fn event_name(&mut self, name: &str, interned: &mut Vec<u8>) -> u64 {
+        let next_iid = self.event_names.len() as u64 + 1;
+        *self.event_names.entry(name.to_owned()).or_insert_with(|| {
+            let mut event_name = Vec::new();
+            encode_varint_field(&mut event_name, 1, next_iid);
+            encode_message(&mut event_name, 2, name.as_bytes());
+            encode_message(interned, 2, &event_name);
+            next_iid
+        })
+    }
+
+    fn slice_begin(&mut self, now: u64, track: u64, frame: &Frame) {
+        let mut interned = Vec::new();
+        let name_iid = self.event_name(&frame.name, &mut interned);
+
+        let line = if self.show_linenumbers { frame.line } else { 0 };
+        let next_iid = self.source_locations.len() as u64 + 1;
+        let location_iid = *self
+            .source_locations
+            .entry((frame.filename.clone(), frame.name.clone(), line))
+            .or_insert_with(|| {
+                let mut location = Vec::new();
+                encode_varint_field(&mut location, 1, next_iid);
+                encode_message(&mut location, 2, frame.filename.as_bytes());
+                encode_message(&mut location, 3, frame.name.as_bytes());
+                if line > 0 {
+                    encode_varint_field(&mut location, 4, line as u64);
+                }
+                encode_message(&mut interned, 4, &location);
+                next_iid
+            });
+
+        let mut event = Vec::new();
+        encode_varint_field(&mut event, 9, TYPE_SLICE_BEGIN);
+        encode_varint_field(&mut event, 10, name_iid);
+        encode_varint_field(&mut event, 11, track);
+        encode_varint_field(&mut event, 34, location_iid);
+        self.push_event(now, &event, &interned);
+    }
+
+    fn slice_end(&mut self, now: u64, track: u64) {
+        let mut event = Vec::new();
+        encode_varint_field(&mut event, 9, TYPE_SLICE_END);
+        encode_varint_field(&mut event, 11, track);
+        self.push_event(now, &event, &[]);
+    }
+
+    fn push_event(&mut self, now: u64, event: &[u8], interned: &[u8]) {
+        // the first event on the sequence needs to let perfetto know that it can start
+        // interning data
+        let flags = if self.incremental_state {
+            SEQ_NEEDS_INCREMENTAL_STATE
+        } else {
+            self.incremental_state = true;
+            SEQ_INCREMENTAL_STATE_CLEARED | SEQ_NEEDS_INCREMENTAL_STATE
+        };
+        let packet = event_packet(now, event, interned, flags);
+        encode_message(&mut self.packets, 1, &packet);
+    }
+
+    fn push_track_descriptor(&mut self, descriptor: &[u8]) {
+        let mut packet = Vec::new();
+        encode_varint_field(&mut packet, 10, SEQUENCE_ID);
+        encode_message(&mut packet, 60, descriptor);
+        encode_message(&mut self.packets, 1, &packet);
+    }
+
+    fn new_uuid(&mut self) -> u64 {
+        let uuid = self.next_uuid;
+        self.next_uuid += 1;
+        uuid
+    }
+
+    fn process_track(&mut self, trace: &StackTrace) -> u64 {
+        if let Some(uuid) = self.process_tracks.get(&trace.pid) {
+            return *uuid;
+        }
+        let uuid = self.new_uuid();
+        self.process_tracks.insert(trace.pid, uuid);
+
+        let mut process = Vec::new();
+        encode_varint_field(&mut process, 1, trace.pid as u64);
+        if let Some(info) = trace.process_info.as_ref() {
+            encode_message(&mut process, 6, info.name().as_bytes());
+        }
+        let mut descriptor = Vec::new();
+        encode_varint_field(&mut descriptor, 1, uuid);
+        encode_message(&mut descriptor, 3, &process);
+        self.push_track_descriptor(&descriptor);
+        uuid
+    }
+
+    fn thread_track(&mut self, trace: &StackTrace) -> u64 {
+        let key = (trace.pid, trace.thread_id);
+        if let Some(thread) = self.thread_tracks.get(&key) {
+            return thread.uuid;
+        }
+        let process_uuid = self.process_track(trace);
+        let uuid = self.new_uuid();
+        let gil_uuid = self.new_uuid();
+        self.thread_tracks.insert(
+            key,
+            ThreadTrack {
+                uuid,
+                gil_uuid,
+                owns_gil: None,
+            },
+        );
+
+        let mut descriptor = Vec::new();
+        encode_varint_field(&mut descriptor, 1, uuid);
+        // perfetto needs a 32 bit OS thread id to associate the track with a thread. If we
+        // don't have one, fall back to a named track under the process
+        match trace.os_thread_id.and_then(|tid| i32::try_from(tid).ok()) {
+            Some(tid) => {
+                let mut thread = Vec::new();
+                encode_varint_field(&mut thread, 1, trace.pid as u64);
+                encode_varint_field(&mut thread, 2, tid as u64);
+                if let Some(name) = trace.thread_name.as_ref() {
+                    encode_message(&mut thread, 5, name.as_bytes());
+                }
+                encode_message(&mut descriptor, 4, &thread);
+            }
+            None => {
+                let name = match trace.thread_name.as_ref() {
+                    Some(name) => format!("{}: {}", trace.format_threadid(), name),
+                    None => format!("Thread {}", trace.format_threadid()),
+                };
+                encode_varint_field(&mut descriptor, 5, process_uuid);
+                encode_message(&mut descriptor, 2, name.as_bytes());
+            }
+        }
+        self.push_track_descriptor(&descriptor);
+
+        let mut descriptor = Vec::new();
+        encode_varint_field(&mut descriptor, 1, gil_uuid);
+        encode_varint_field(&mut descriptor, 5, uuid);
+        encode_message(&mut descriptor, 2, b"GIL");
+        encode_message(&mut descriptor, 8, &[]);
+        self.push_track_descriptor(&descriptor);
+        uuid
+    }
+}
+
+fn event_packet(now: u64, event: &[u8], interned: &[u8], flags: u64) -> Vec<u8> {
+    let mut packet = Vec::new();
+    // timestamps in perfetto are in nanoseconds
+    encode_varint_field(&mut packet, 8, now * 1000);
+    encode_varint_field(&mut packet, 10, SEQUENCE_ID);
+    encode_message(&mut packet, 11, event);
+    if !interned.is_empty() {
+        encode_message(&mut packet, 12, interned);
+    }
+    encode_varint_field(&mut packet, 13, flags);
+    packet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{frame, trace};
+
+    #[test]
+    fn test_perfetto_slices() {
+        let mut perfetto = Perfetto::new(true);
+        let mut trace = trace(vec![
+            frame("inner", "test.py", 2),
+            frame("outer", "test.py", 10),
+        ]);
+        trace.thread_id = 0x7f00_0000_0001;
+        trace.thread_name = Some(String::from("MainThread"));
+        trace.os_thread_id = Some(10);
+        trace.owns_gil = true;
+        perfetto.increment_at(&trace, 0).unwrap();
+        perfetto.increment_at(&trace, 10).unwrap();
+        trace.frames.remove(0);
+        trace.owns_gil = false;
+        perfetto.increment_at(&trace, 20).unwrap();
+
+        // a single process and thread track, plus a GIL counter track for the thread
+        assert_eq!(perfetto.process_tracks.len(), 1);
+        assert_eq!(perfetto.thread_tracks.len(), 1);
+        assert_eq!(perfetto.next_uuid, 4);
+
+        // the function names should only be interned once
+        assert_eq!(perfetto.event_names.len(), 2);
+        let inner = perfetto.packets.windows(5).filter(|w| w == b"inner");
+        // once as the event name, once as the source location function name
+        assert_eq!(inner.count(), 2);
+
+        let mut out = Vec::new();
+        perfetto.write(&mut out).unwrap();
+        assert_eq!(out[0], 0x0a);
+        assert!(out.len() > perfetto.packets.len());
+    }
+
+    #[test]
+    fn test_perfetto_thread_exited() {
+        let mut perfetto = Perfetto::new(true);
+        let mut trace = trace(vec![
+            frame("work", "test.py", 3),
+            frame("run", "test.py", 20),
+        ]);
+        trace.thread_id = 2;
+        trace.thread_name = Some(String::from("worker"));
+        trace.os_thread_id = Some(11);
+        trace.owns_gil = true;
+        perfetto.increment_at(&trace, 0).unwrap();
+        let event = ThreadEvent {
+            kind: ThreadEventKind::Exited,
+            thread: trace.clone(),
+        };
+        perfetto.add_thread_event(&event, 10);
+
+        // the slices have been ended, so there's nothing left to end when writing
+        assert!(perfetto.prev_traces.is_empty());
+        assert!(perfetto.thread_tracks.is_empty());
+        assert!(perfetto.event_names.contains_key("thread exited"));
+        let mut out = Vec::new();
+        perfetto.write(&mut out).unwrap();
+        assert_eq!(out.len(), perfetto.packets.len());
+
+        // a new thread with the same thread id gets a new track
+        perfetto.increment_at(&trace, 20).unwrap();
+        assert_eq!(perfetto.thread_tracks[&(1, 2)].uuid, 4);
+    }
+}
@@ -0,0 +1,177 @@
+use anyhow::{Context, Error, Result};
+use remoteprocess::ProcessMemory;
+
+use crate::config::Config;
+#[cfg(feature = "python2")]
+use crate::python_bindings::v2_7_15;
+use crate::python_bindings::{v3_10_0, v3_11_0, v3_3_7, v3_5_5, v3_6_6, v3_7_0, v3_8_0, v3_9_5};
+use crate::python_data_access::{format_variable, FormatLimits};
+use crate::python_interpreters::InterpreterState;
+use crate::python_process_info::{
+    get_interpreter_address, get_python_version, get_threadstate_address, PythonProcessInfo,
+};
+use crate::python_threading::thread_names_from_interpreter;
+use crate::stack_trace::{get_stack_traces, StackTrace};
+use crate::struct_offsets::{copy_struct, load_offsets, set_offsets};
+use crate::version::Version;
+
+/*
+ * This file contains the code shared by the different kinds of dumps of a crashed process that
+ * 'dump --core' can read (linux core dumps and windows minidumps): finding the python interpreter
+ * in the memory of the dump, and reading the stack traces of its threads from it.
+ */
+
+/// The python interpreter in the memory of a dumped process
+pub struct DumpedInterpreter {
+    pub version: Version,
+    pub interpreter_address: usize,
+    pub threadstate_address: usize,
+}
+
+impl DumpedInterpreter {
+    /// Finds the interpreter in the memory of a dump. The version is read from the memory of the
+    /// dump when it can be, falling back to the version the dump says python is (if any)
+    pub fn new<P: ProcessMemory>(
+        python_info: &PythonProcessInfo,
+        memory: &P,
+        dumped_version: Option<Version>,
+        config: &Config,
+    ) -> Result<DumpedInterpreter, Error> {
+        let version = match config.force_version.clone() {
+            Some(version) => version,
+            None => match get_python_version(python_info, memory) {
+                Ok(version) => version,
+                Err(err) => dumped_version
+                    .ok_or(err)
+                    .context("failed to get python version")?,
+            },
+        };
+        info!("Got python version {}", version);
+        if let Some(filename) = &config.offsets_filename {
+            set_offsets(load_offsets(filename, &version)?)?;
+        }
+        #[cfg(not(feature = "python2"))]
+        if version.major == 2 {
+            return Err(format_err!(
+                "Python {} isn't supported by this build of py-spy, which was built without the 'python2' feature",
+                version
+            ));
+        }
+
+        let interpreter_address = get_interpreter_address(python_info, memory, &version)?;
+        info!("Found interpreter at 0x{:016x}", interpreter_address);
+
+        // lets us figure out which thread has the GIL
+        let threadstate_address = get_threadstate_address(python_info, &version, config)?;
+        info!("found threadstate at 0x{:016x}", threadstate_address);
+
+        Ok(DumpedInterpreter {
+            version,
+            interpreter_address,
+            threadstate_address,
+        })
+    }
+
+    pub fn get_stack<P: ProcessMemory>(
+        &self,
+        memory: &P,
+        config: &Config,
+    ) -> Result<Vec<StackTrace>, Error> {
+        if config.native {
+            return Err(format_err!(
+                "Native unwinding isn't yet supported with coredumps"
+            ));
+        }
+
+        if config.subprocesses {
+            return Err(format_err!(
+                "Subprocesses can't be used for getting stacktraces from coredumps"
+            ));
+        }
+
+        // different versions have different layouts, check as appropriate
+        match self.version {
+            #[cfg(feature = "python2")]
+            Version {
+                major: 2,
+                minor: 3..=7,
+                ..
+            } => self._get_stack::<v2_7_15::_is, P>(memory, config),
+            Version {
+                major: 3, minor: 3, ..
+            } => self._get_stack::<v3_3_7::_is, P>(memory, config),
+            Version {
+                major: 3,
+                minor: 4..=5,
+                ..
+            } => self._get_stack::<v3_5_5::_is, P>(memory, config),
+            Version {
+                major: 3, minor: 6, ..
+            } => self._get_stack::<v3_6_6::_is, P>(memory, config),
+            Version {
+                major: 3, minor: 7, ..
+            } => self._get_stack::<v3_7_0::_is, P>(memory, config),
+            Version {
+                major: 3, minor: 8, ..
+            } => self._get_stack::<v3_8_0::_is, P>(memory, config),
+            Version {
+                major: 3, minor: 9, ..
+            } => self._get_stack::<v3_9_5::_is, P>(memory, config),
+            Version {
+                major: 3,
+                minor: 10,
+                ..
+            } => self._get_stack::<v3_10_0::_is, P>(memory, config),
+            Version {
+                major: 3,
+                minor: 11,
+                ..
+            } => self._get_stack::<v3_11_0::_is, P>(memory, config),
+            _ => Err(format_err!(
+                "Unsupported version of Python: {}",
+                self.version
+            )),
+        }
+    }
+
+    fn _get_stack<I: InterpreterState, P: ProcessMemory>(
+        &self,
+        memory: &P,
+        config: &Config,
+    ) -> Result<Vec<StackTrace>, Error> {
+        let interp: I = copy_struct(memory, self.interpreter_address)?;
+
+        let mut traces = get_stack_traces(&interp, memory, self.threadstate_address, Some(config))?;
+        let thread_names = thread_names_from_interpreter(&interp, memory, &self.version).ok();
+
+        for trace in &mut traces {
+            if let Some(ref thread_names) = thread_names {
+                trace.thread_name = thread_names.get(&trace.thread_id).cloned();
+            }
+
+            for frame in &mut trace.frames {
+                if let Some(locals) = frame.locals.as_mut() {
+                    if config.args_only {
+                        locals.retain(|local| local.arg);
+                    }
+                    let limits = FormatLimits::from_config(config);
+                    for local in locals {
+                        let repr =
+                            format_variable::<I, P>(memory, &self.version, local.addr, &limits);
+                        local.repr = Some(repr.unwrap_or_else(|_| "?".to_owned()));
+                        if config.locals_output.is_some() {
+                            let full_repr = format_variable::<I, P>(
+                                memory,
+                                &self.version,
+                                local.addr,
+                                &FormatLimits::full(),
+                            );
+                            local.full_repr = Some(full_repr.unwrap_or_else(|_| "?".to_owned()));
+                        }
+                    }
+                }
+            }
+        }
+        Ok(traces)
+    }
+}
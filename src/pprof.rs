@@ -0,0 +1,297 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Error;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use crate::protobuf::{encode_message, encode_packed, encode_varint_field};
+use crate::sampler_stats::SamplerStats;
+use crate::stack_trace::{Frame, StackTrace};
+use crate::ProcessMetadata;
+
+/*
+ * This file contains code to export py-spy profiles in the pprof format, as used by
+ * `go tool pprof`, Parca and Pyroscope.
+ *
+ * The format is a gzip compressed protocol buffer, with the schema defined here:
+ * https://github.com/google/pprof/blob/main/proto/profile.proto
+ *
+ * Rather than pull in a protobuf code generator for a single message type, we encode the
+ * handful of messages we need by hand (see protobuf.rs).
+ */
+
+// (key, value) string table indices
+type Label = (i64, i64);
+
+pub struct Pprof {
+    strings: Vec<String>,
+    string_index: HashMap<String, i64>,
+    // (name, filename) string indices -> function id
+    functions: HashMap<(i64, i64), u64>,
+    function_table: Vec<(i64, i64)>,
+    // (function id, line) -> location id
+    locations: HashMap<(u64, i64), u64>,
+    location_table: Vec<(u64, i64)>,
+    // (stack of location ids (leaf first), labels) -> number of samples
+    samples: HashMap<(Vec<u64>, Vec<Label>), i64>,
+    show_linenumbers: bool,
+    // whether to label samples with the thread they came from
+    thread_labels: bool,
+    sampling_rate: u64,
+    start_time: SystemTime,
+    start_ts: Instant,
+    // free-form comments on the profile, as string table indices
+    comments: Vec<i64>,
+}
+
+impl Pprof {
+    pub fn new(show_linenumbers: bool, sampling_rate: u64, thread_labels: bool) -> Pprof {
+        Pprof {
+            // the pprof spec requires that the first entry of the string table is ""
+            strings: vec![String::new()],
+            string_index: HashMap::new(),
+            functions: HashMap::new(),
+            function_table: Vec::new(),
+            locations: HashMap::new(),
+            location_table: Vec::new(),
+            samples: HashMap::new(),
+            show_linenumbers,
+            thread_labels,
+            sampling_rate,
+            start_time: SystemTime::now(),
+            start_ts: Instant::now(),
+            comments: Vec::new(),
+        }
+    }
+
+    /// Adds where the profile came from as comments, like 'package.requests=2.31.0'
+    pub fn set_metadata(&mut self, metadata: &ProcessMetadata) {
+        for (key, value) in metadata.key_values() {
+            let comment = self.string_id(&format!("{}={}", key, value));
+            self.comments.push(comment);
+        }
+    }
+
+    /// Adds the statistics of the sampler to the profile as comments, like 'errors=3'
+    pub fn set_stats(&mut self, stats: &SamplerStats) {
+        for (key, value) in stats.metadata() {
+            let comment = self.string_id(&format!("{}={}", key, value));
+            self.comments.push(comment);
+        }
+    }
+
+    pub fn increment(&mut self, trace: &StackTrace) -> std::io::Result<()> {
+        let stack = trace
+            .frames
+            .iter()
+            .map(|frame| self.location_id(frame))
+            .collect();
+
+        let mut labels = Vec::new();
+        if self.thread_labels {
+            let thread_id = trace.format_threadid();
+            labels.push((self.string_id("thread id"), self.string_id(&thread_id)));
+            if let Some(name) = trace.thread_name.as_ref() {
+                labels.push((self.string_id("thread name"), self.string_id(name)));
+            }
+        }
+        *self.samples.entry((stack, labels)).or_insert(0) += 1;
+        Ok(())
+    }
+
+    pub fn write(&self, w: &mut dyn Write) -> Result<(), Error> {
+        let mut encoder = GzEncoder::new(w, Compression::default());
+        encoder.write_all(&self.encode())?;
+        encoder.finish()?;
+        Ok(())
+    }
+
+    fn string_id(&mut self, s: &str) -> i64 {
+        if s.is_empty() {
+            return 0;
+        }
+        if let Some(id) = self.string_index.get(s) {
+            return *id;
+        }
+        let id = self.strings.len() as i64;
+        self.strings.push(s.to_owned());
+        self.string_index.insert(s.to_owned(), id);
+        id
+    }
+
+    fn location_id(&mut self, frame: &Frame) -> u64 {
+        let filename = match &frame.short_filename {
+            Some(f) => f,
+            None => &frame.filename,
+        };
+        let key = (self.string_id(&frame.name), self.string_id(filename));
+        let function_table = &mut self.function_table;
+        let function_id = *self.functions.entry(key).or_insert_with(|| {
+            function_table.push(key);
+            function_table.len() as u64
+        });
+
+        let line = if self.show_linenumbers {
+            frame.line as i64
+        } else {
+            0
+        };
+        let location_table = &mut self.location_table;
+        *self
+            .locations
+            .entry((function_id, line))
+            .or_insert_with(|| {
+                location_table.push((function_id, line));
+                location_table.len() as u64
+            })
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        // string table indices for our sample/period types. these are looked up rather than
+        // added, since the string table is frozen by the time we're writing out
+        let mut strings = self.strings.clone();
+        let mut intern = |s: &str| match self.string_index.get(s) {
+            Some(id) => *id as u64,
+            None => {
+                strings.push(s.to_owned());
+                (strings.len() - 1) as u64
+            }
+        };
+        let samples_str = intern("samples");
+        let count_str = intern("count");
+        let cpu_str = intern("cpu");
+        let nanoseconds_str = intern("nanoseconds");
+
+        let period = 1_000_000_000 / self.sampling_rate.max(1) as i64;
+
+        let mut out = Vec::new();
+
+        // sample_type = 1
+        encode_message(&mut out, 1, &value_type(samples_str, count_str));
+        encode_message(&mut out, 1, &value_type(cpu_str, nanoseconds_str));
+
+        // sample = 2
+        for ((stack, labels), count) in self.samples.iter() {
+            let mut sample = Vec::new();
+            encode_packed(&mut sample, 1, stack.iter().copied());
+            encode_packed(&mut sample, 2, [*count as u64, (count * period) as u64]);
+            for (key, value) in labels.iter() {
+                let mut label = Vec::new();
+                encode_varint_field(&mut label, 1, *key as u64);
+                encode_varint_field(&mut label, 2, *value as u64);
+                encode_message(&mut sample, 3, &label);
+            }
+            encode_message(&mut out, 2, &sample);
+        }
+
+        // location = 4
+        for (i, (function_id, line)) in self.location_table.iter().enumerate() {
+            let mut line_msg = Vec::new();
+            encode_varint_field(&mut line_msg, 1, *function_id);
+            encode_varint_field(&mut line_msg, 2, *line as u64);
+
+            let mut location = Vec::new();
+            encode_varint_field(&mut location, 1, i as u64 + 1);
+            encode_message(&mut location, 4, &line_msg);
+            encode_message(&mut out, 4, &location);
+        }
+
+        // function = 5
+        for (i, (name, filename)) in self.function_table.iter().enumerate() {
+            let mut function = Vec::new();
+            encode_varint_field(&mut function, 1, i as u64 + 1);
+            encode_varint_field(&mut function, 2, *name as u64);
+            encode_varint_field(&mut function, 3, *name as u64);
+            encode_varint_field(&mut function, 4, *filename as u64);
+            encode_message(&mut out, 5, &function);
+        }
+
+        // string_table = 6
+        for s in strings.iter() {
+            encode_message(&mut out, 6, s.as_bytes());
+        }
+
+        // time_nanos = 9, duration_nanos = 10
+        let time_nanos = self
+            .start_time
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        encode_varint_field(&mut out, 9, time_nanos);
+        encode_varint_field(&mut out, 10, self.start_ts.elapsed().as_nanos() as u64);
+
+        // period_type = 11, period = 12
+        encode_message(&mut out, 11, &value_type(cpu_str, nanoseconds_str));
+        encode_varint_field(&mut out, 12, period as u64);
+
+        // comment = 13
+        encode_packed(&mut out, 13, self.comments.iter().map(|id| *id as u64));
+        out
+    }
+}
+
+fn value_type(type_: u64, unit: u64) -> Vec<u8> {
+    let mut msg = Vec::new();
+    encode_varint_field(&mut msg, 1, type_);
+    encode_varint_field(&mut msg, 2, unit);
+    msg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{frame, trace};
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    #[test]
+    fn test_pprof_aggregation() {
+        let mut pprof = Pprof::new(true, 100, false);
+        let trace = trace(vec![
+            frame("inner", "test.py", 2),
+            frame("outer", "test.py", 10),
+        ]);
+        pprof.increment(&trace).unwrap();
+        pprof.increment(&trace).unwrap();
+
+        // the same stack should only be stored once, with a count of two
+        assert_eq!(pprof.samples.len(), 1);
+        assert_eq!(pprof.samples.values().next(), Some(&2));
+        assert_eq!(pprof.function_table.len(), 2);
+        assert_eq!(pprof.strings, vec!["", "inner", "test.py", "outer"]);
+
+        let mut compressed = Vec::new();
+        pprof.write(&mut compressed).unwrap();
+        let mut decoded = Vec::new();
+        GzDecoder::new(compressed.as_slice())
+            .read_to_end(&mut decoded)
+            .unwrap();
+        // should start with the sample_type message, and contain the string table
+        assert_eq!(decoded[0], 0x0a);
+        assert!(decoded.windows(7).any(|w| w == b"test.py"));
+    }
+
+    #[test]
+    fn test_pprof_thread_labels() {
+        let mut pprof = Pprof::new(true, 100, true);
+        let mut trace = trace(vec![frame("inner", "test.py", 2)]);
+        trace.thread_name = Some(String::from("MainThread"));
+        trace.os_thread_id = Some(100);
+        pprof.increment(&trace).unwrap();
+        trace.os_thread_id = Some(101);
+        trace.thread_name = Some(String::from("worker"));
+        pprof.increment(&trace).unwrap();
+
+        // the same stack on different threads should be stored separately
+        assert_eq!(pprof.samples.len(), 2);
+        assert!(pprof.strings.contains(&String::from("thread name")));
+        assert!(pprof.strings.contains(&String::from("worker")));
+
+        let stats = SamplerStats::new(100);
+        pprof.set_stats(&stats);
+        assert!(pprof.strings.contains(&String::from("requested_rate=100")));
+        assert_eq!(pprof.comments.len(), stats.metadata().len());
+    }
+}
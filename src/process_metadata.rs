@@ -0,0 +1,413 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+use anyhow::Error;
+use remoteprocess::{Pid, ProcessMemory};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::python_bindings::{v3_10_0, v3_11_0, v3_6_6, v3_7_0, v3_8_0, v3_9_5};
+use crate::python_data_access::{copy_string, find_key, object_dict, DictIterator};
+use crate::python_interpreters::{InterpreterState, ListObject, Object, TypeObject};
+use crate::python_spy::PythonSpy;
+use crate::version::Version;
+
+/*
+ * This file contains code to read where a profile came from when recording starts: the
+ * sys.argv and sys.version of the program, some of its environment variables, and the versions
+ * of the packages it has imported. This is stored in the outputs that have somewhere to put it
+ * (capture, speedscope and pprof), so that archived profiles can be matched up with the build and
+ * configuration they were taken from.
+ *
+ * The versions of packages come from the __version__ of each module imported from a
+ * site-packages directory, or otherwise from the name of the .dist-info directory of the package
+ * that installed it. Environment variables can hold secrets, so only the ones on an allowlist are
+ * read (see --metadata-env).
+ */
+
+/// The environment variables that are stored by default. Names ending in '*' match any variable
+/// starting with the rest of the name
+pub const DEFAULT_METADATA_ENV: &[&str] = &[
+    "VIRTUAL_ENV",
+    "CONDA_DEFAULT_ENV",
+    "PYTHONPATH",
+    "PYTHONHASHSEED",
+    "DJANGO_SETTINGS_MODULE",
+    "GIT_COMMIT",
+    "GIT_SHA",
+    "BUILD_ID",
+    "IMAGE_TAG",
+];
+
+// the most modules looked at for package versions
+const MAX_MODULES: usize = 1 << 14;
+
+/// Where a profile came from
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ProcessMetadata {
+    pub argv: Vec<String>,
+    pub python_version: String,
+    /// The environment variables on the allowlist that were set
+    #[serde(default)]
+    pub environment: BTreeMap<String, String>,
+    /// The version of each top level package imported from site-packages
+    #[serde(default)]
+    pub packages: BTreeMap<String, String>,
+}
+
+impl ProcessMetadata {
+    /// The metadata as (key, value) pairs, for outputs that only store strings
+    pub fn key_values(&self) -> Vec<(String, String)> {
+        let mut values = vec![
+            ("argv".to_owned(), self.argv.join(" ")),
+            ("python_version".to_owned(), self.python_version.clone()),
+        ];
+        for (name, value) in &self.environment {
+            values.push((format!("env.{}", name), value.clone()));
+        }
+        for (name, version) in &self.packages {
+            values.push((format!("package.{}", name), version.clone()));
+        }
+        values
+    }
+}
+
+/// Reads the metadata of the program a PythonSpy is attached to, with the environment variables
+/// named in 'environment'
+pub fn read_process_metadata(
+    spy: &PythonSpy,
+    environment: &[String],
+) -> Result<ProcessMetadata, Error> {
+    let mut metadata = match spy.version {
+        Version {
+            major: 3, minor: 6, ..
+        } => _read_process_metadata::<v3_6_6::_is>(spy),
+        Version {
+            major: 3, minor: 7, ..
+        } => _read_process_metadata::<v3_7_0::_is>(spy),
+        Version {
+            major: 3, minor: 8, ..
+        } => _read_process_metadata::<v3_8_0::_is>(spy),
+        Version {
+            major: 3, minor: 9, ..
+        } => _read_process_metadata::<v3_9_5::_is>(spy),
+        Version {
+            major: 3,
+            minor: 10,
+            ..
+        } => _read_process_metadata::<v3_10_0::_is>(spy),
+        Version {
+            major: 3,
+            minor: 11,
+            ..
+        } => _read_process_metadata::<v3_11_0::_is>(spy),
+        _ => Err(format_err!(
+            "Reading metadata isn't supported for python {}",
+            spy.version
+        )),
+    }?;
+    metadata.environment = read_environment(spy.pid, environment);
+    Ok(metadata)
+}
+
+fn _read_process_metadata<I: InterpreterState>(spy: &PythonSpy) -> Result<ProcessMetadata, Error> {
+    let process = &spy.process;
+    let version = &spy.version;
+    // the main interpreter is the last one in the list. Interpreters are big structs, so this
+    // only keeps the address of its modules
+    let modules = spy
+        ._get_interpreters::<I>()?
+        .last()
+        .map(|(_, interp)| interp.modules() as usize)
+        .ok_or_else(|| format_err!("Failed to find the python interpreter"))?;
+
+    let mut metadata = ProcessMetadata {
+        python_version: spy.version.to_string(),
+        ..Default::default()
+    };
+    let mut dist_info = DistInfo::new(spy);
+    let modules = DictIterator::from(process, version, modules)?;
+    for entry in modules.take(MAX_MODULES) {
+        let (key, module) = entry?;
+        if !is_str::<I, _>(process, key) {
+            continue;
+        }
+        let name = copy_string(key as *const I::StringObject, process)?;
+        if name == "sys" {
+            let argv = find_key::<I::StringObject, _>(
+                object_dict::<I, _>(process, version, module)?,
+                process,
+                "argv",
+            )?;
+            if let Some(argv) = argv {
+                metadata.argv = copy_str_list::<I, _>(process, argv)?;
+            }
+            let sys_version = find_key::<I::StringObject, _>(
+                object_dict::<I, _>(process, version, module)?,
+                process,
+                "version",
+            )?;
+            if let Some(sys_version) = sys_version.filter(|v| is_str::<I, _>(process, *v)) {
+                metadata.python_version =
+                    copy_string(sys_version as *const I::StringObject, process)?;
+            }
+            continue;
+        }
+        // only top level packages, and not private modules like '_distutils_hack'
+        if name.contains('.') || name.starts_with('_') {
+            continue;
+        }
+        // modules that can't be read are skipped, since they could be changing as they're read
+        let (file, module_version) = match module_file::<I, _>(process, version, module) {
+            Ok(values) => values,
+            Err(_) => continue,
+        };
+        let site_packages = match file.as_deref().and_then(site_packages_dir) {
+            Some(site_packages) => site_packages,
+            None => continue,
+        };
+        if let Some(version) = module_version.or_else(|| dist_info.version(&site_packages, &name)) {
+            metadata.packages.insert(name, version);
+        }
+    }
+    Ok(metadata)
+}
+
+/// The __file__ and __version__ of a module, when they're set to strings. Builtin modules
+/// don't have a __file__
+fn module_file<I: InterpreterState, P: ProcessMemory>(
+    process: &P,
+    version: &Version,
+    module: usize,
+) -> Result<(Option<String>, Option<String>), Error> {
+    let mut file = None;
+    let mut module_version = None;
+    for entry in object_dict::<I, _>(process, version, module)? {
+        let (key, value) = entry?;
+        if !is_str::<I, _>(process, key) || !is_str::<I, _>(process, value) {
+            continue;
+        }
+        match copy_string(key as *const I::StringObject, process)?.as_str() {
+            "__file__" => file = Some(copy_string(value as *const I::StringObject, process)?),
+            "__version__" => {
+                module_version = Some(copy_string(value as *const I::StringObject, process)?)
+            }
+            _ => {}
+        }
+    }
+    Ok((file, module_version))
+}
+
+/// Whether the python object at an address is a str
+fn is_str<I: InterpreterState, P: ProcessMemory>(process: &P, addr: usize) -> bool {
+    let object: I::Object = match process.copy_struct(addr) {
+        Ok(object) => object,
+        Err(_) => return false,
+    };
+    let object_type = match process.copy_pointer(object.ob_type()) {
+        Ok(object_type) => object_type,
+        Err(_) => return false,
+    };
+    matches!(process.copy(object_type.name() as usize, 4), Ok(name) if name == b"str\0")
+}
+
+/// Copies a python list of strings, like sys.argv
+fn copy_str_list<I: InterpreterState, P: ProcessMemory>(
+    process: &P,
+    addr: usize,
+) -> Result<Vec<String>, Error> {
+    let list: I::ListObject = process.copy_struct(addr)?;
+    if list.size() > MAX_MODULES {
+        return Err(format_err!(
+            "Refusing to read a list of {} items",
+            list.size()
+        ));
+    }
+    let items: Vec<usize> = process.copy_vec(list.item() as usize, list.size())?;
+    let mut strings = Vec::new();
+    for item in items {
+        if is_str::<I, _>(process, item) {
+            strings.push(copy_string(item as *const I::StringObject, process)?);
+        }
+    }
+    Ok(strings)
+}
+
+/// The site-packages directory a module was imported from, if it was imported from one
+fn site_packages_dir(file: &str) -> Option<PathBuf> {
+    Path::new(file)
+        .ancestors()
+        .find(|dir| {
+            matches!(
+                dir.file_name().and_then(|name| name.to_str()),
+                Some("site-packages") | Some("dist-packages")
+            )
+        })
+        .map(Path::to_path_buf)
+}
+
+/// Finds the versions of installed packages from the .dist-info directories in site-packages
+struct DistInfo {
+    // on linux the process could be running in docker, where its files are under /proc/pid/root
+    root: Option<PathBuf>,
+    // the version of each top level module, for each site-packages directory read so far
+    versions: HashMap<PathBuf, HashMap<String, String>>,
+}
+
+impl DistInfo {
+    fn new(spy: &PythonSpy) -> DistInfo {
+        #[cfg(target_os = "linux")]
+        let root = spy
+            .dockerized
+            .then(|| PathBuf::from(format!("/proc/{}/root", spy.pid)));
+        #[cfg(not(target_os = "linux"))]
+        let root = {
+            let _ = spy;
+            None
+        };
+        DistInfo {
+            root,
+            versions: HashMap::new(),
+        }
+    }
+
+    fn version(&mut self, site_packages: &Path, module: &str) -> Option<String> {
+        if !self.versions.contains_key(site_packages) {
+            let dir = match &self.root {
+                Some(root) => root.join(site_packages.strip_prefix("/").unwrap_or(site_packages)),
+                None => site_packages.to_path_buf(),
+            };
+            self.versions
+                .insert(site_packages.to_path_buf(), dist_info_versions(&dir));
+        }
+        self.versions[site_packages].get(module).cloned()
+    }
+}
+
+/// The version of each top level module installed in a site-packages directory, from the names
+/// of the .dist-info (or .egg-info) directories in it. Packages whose module has another name
+/// than the package (like PyYAML's 'yaml') list their modules in top_level.txt
+fn dist_info_versions(site_packages: &Path) -> HashMap<String, String> {
+    let mut versions = HashMap::new();
+    let entries = match std::fs::read_dir(site_packages) {
+        Ok(entries) => entries,
+        Err(_) => return versions,
+    };
+    for entry in entries.flatten() {
+        let file_name = entry.file_name();
+        let file_name = file_name.to_string_lossy();
+        let (package, version) = match parse_dist_info(&file_name) {
+            Some(parsed) => parsed,
+            None => continue,
+        };
+        if let Ok(top_level) = std::fs::read_to_string(entry.path().join("top_level.txt")) {
+            for module in top_level.lines().map(str::trim).filter(|m| !m.is_empty()) {
+                versions.insert(module.to_owned(), version.to_owned());
+            }
+        }
+        versions
+            .entry(package.to_lowercase().replace('-', "_"))
+            .or_insert_with(|| version.to_owned());
+    }
+    versions
+}
+
+/// The package name and version of a directory like 'requests-2.31.0.dist-info'
+fn parse_dist_info(file_name: &str) -> Option<(&str, &str)> {
+    let name = file_name
+        .strip_suffix(".dist-info")
+        .or_else(|| file_name.strip_suffix(".egg-info"))?;
+    let (package, version) = name.split_once('-')?;
+    // egg-info directories have the python version after the package version
+    let version = version.split('-').next()?;
+    Some((package, version))
+}
+
+/// Reads the environment variables of a process that are on an allowlist
+#[cfg(target_os = "linux")]
+fn read_environment(pid: Pid, allowlist: &[String]) -> BTreeMap<String, String> {
+    let environ = std::fs::read(format!("/proc/{}/environ", pid)).unwrap_or_default();
+    environ
+        .split(|&c| c == 0)
+        .filter_map(|var| {
+            let var = String::from_utf8_lossy(var);
+            let (name, value) = var.split_once('=')?;
+            allowed(name, allowlist).then(|| (name.to_owned(), value.to_owned()))
+        })
+        .collect()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn read_environment(_pid: Pid, _allowlist: &[String]) -> BTreeMap<String, String> {
+    BTreeMap::new()
+}
+
+/// Whether an environment variable is on an allowlist
+fn allowed(name: &str, allowlist: &[String]) -> bool {
+    allowlist
+        .iter()
+        .any(|pattern| match pattern.strip_suffix('*') {
+            Some(prefix) => name.starts_with(prefix),
+            None => name == pattern,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dist_info() {
+        assert_eq!(
+            parse_dist_info("requests-2.31.0.dist-info"),
+            Some(("requests", "2.31.0"))
+        );
+        assert_eq!(
+            parse_dist_info("six-1.16.0-py3.11.egg-info"),
+            Some(("six", "1.16.0"))
+        );
+        assert_eq!(parse_dist_info("requests"), None);
+
+        let dir = tempfile::tempdir().unwrap();
+        let yaml = dir.path().join("PyYAML-6.0.1.dist-info");
+        std::fs::create_dir(&yaml).unwrap();
+        std::fs::write(yaml.join("top_level.txt"), "_yaml\nyaml\n").unwrap();
+        std::fs::create_dir(dir.path().join("typing_extensions-4.9.0.dist-info")).unwrap();
+        let versions = dist_info_versions(dir.path());
+        assert_eq!(versions.get("yaml").map(String::as_str), Some("6.0.1"));
+        assert_eq!(
+            versions.get("typing_extensions").map(String::as_str),
+            Some("4.9.0")
+        );
+
+        assert_eq!(
+            site_packages_dir("/venv/lib/python3.11/site-packages/yaml/__init__.py"),
+            Some(PathBuf::from("/venv/lib/python3.11/site-packages"))
+        );
+        assert_eq!(site_packages_dir("/app/yaml/__init__.py"), None);
+    }
+
+    #[test]
+    fn test_metadata_env() {
+        let allowlist = vec!["GIT_SHA".to_owned(), "APP_*".to_owned()];
+        assert!(allowed("GIT_SHA", &allowlist));
+        assert!(allowed("APP_RELEASE", &allowlist));
+        assert!(!allowed("GIT_SHA_SHORT", &allowlist));
+        assert!(!allowed("AWS_SECRET_ACCESS_KEY", &allowlist));
+
+        let metadata = ProcessMetadata {
+            argv: vec!["app.py".to_owned(), "--workers".to_owned(), "4".to_owned()],
+            python_version: "3.11.7".to_owned(),
+            environment: BTreeMap::from([("GIT_SHA".to_owned(), "abc123".to_owned())]),
+            packages: BTreeMap::from([("requests".to_owned(), "2.31.0".to_owned())]),
+        };
+        assert_eq!(
+            metadata.key_values(),
+            vec![
+                ("argv".to_owned(), "app.py --workers 4".to_owned()),
+                ("python_version".to_owned(), "3.11.7".to_owned()),
+                ("env.GIT_SHA".to_owned(), "abc123".to_owned()),
+                ("package.requests".to_owned(), "2.31.0".to_owned()),
+            ]
+        );
+    }
+}
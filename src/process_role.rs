@@ -0,0 +1,194 @@
+/*
+ * This file contains code to label the processes of gunicorn, celery and uwsgi with the role they
+ * play, for 'record --subprocesses'. These servers fork a process for each worker, so profiling
+ * one gives dozens of processes whose command lines are the same, and the label tells them apart.
+ *
+ * The role comes from the command line of the process, which these servers rewrite to say what
+ * each process is when the setproctitle module is installed (or with --auto-procname for uwsgi),
+ * like 'gunicorn: worker [app:wsgi]' or '[celeryd: celery@host:ForkPoolWorker-1]'. Otherwise
+ * the workers have the same command line as the process that forked them, so processes whose
+ * parent is running the same server are taken to be its workers.
+ */
+
+/// The role a process plays in a gunicorn, celery or uwsgi server, from its command line and the
+/// command line of its parent process
+pub fn process_role(command_line: &str, parent_command_line: Option<&str>) -> Option<String> {
+    if let Some(role) = process_title(command_line) {
+        return Some(role);
+    }
+    let server = Server::from_command_line(command_line)?;
+    let forked = parent_command_line
+        .and_then(Server::from_command_line)
+        .is_some_and(|parent| parent.name() == server.name());
+    Some(match (server, forked) {
+        (Server::Gunicorn(app), false) => format!("gunicorn master {}", app).trim().to_owned(),
+        (Server::Gunicorn(app), true) => format!("gunicorn worker {}", app).trim().to_owned(),
+        (Server::Celery(command, name), false) => {
+            format!("celery {} {}", command, name).trim().to_owned()
+        }
+        (Server::Celery(_, name), true) => format!("celery pool worker {}", name).trim().to_owned(),
+        (Server::Uwsgi, false) => "uwsgi master".to_owned(),
+        (Server::Uwsgi, true) => "uwsgi worker".to_owned(),
+    })
+}
+
+/// The role of a process whose command line was rewritten by setproctitle
+fn process_title(command_line: &str) -> Option<String> {
+    // 'gunicorn: master [app:wsgi]' and 'gunicorn: worker [app:wsgi]'
+    if let Some(title) = command_line.strip_prefix("gunicorn: ") {
+        let title = title.replace(['[', ']'], "");
+        return Some(format!("gunicorn {}", title.trim()));
+    }
+    // '[celeryd: celery@host:MainProcess] -active- (worker -A proj)' for the worker, and
+    // '[celeryd: celery@host:ForkPoolWorker-1]' for the processes in its pool
+    if let Some(title) = command_line.strip_prefix("[celeryd: ") {
+        let title = title.split(']').next().unwrap_or(title);
+        return Some(match title.rsplit_once(':') {
+            Some((name, "MainProcess")) => format!("celery worker {}", name),
+            Some((name, process)) => format!("celery {} {}", process, name),
+            None => format!("celery worker {}", title),
+        });
+    }
+    if let Some(title) = command_line.strip_prefix("[celery beat]") {
+        return Some(format!("celery beat{}", title).trim().to_owned());
+    }
+    // 'uWSGI master', 'uWSGI worker 1', 'uWSGI mule 1' and so on
+    if let Some(title) = command_line.strip_prefix("uWSGI ") {
+        return Some(format!("uwsgi {}", title.trim()));
+    }
+    None
+}
+
+/// The server a command line is running, if it's one of the ones that have roles
+enum Server {
+    // the app being served
+    Gunicorn(String),
+    // the celery command (like 'worker' or 'beat'), and the name of the worker
+    Celery(String, String),
+    Uwsgi,
+}
+
+impl Server {
+    fn from_command_line(command_line: &str) -> Option<Server> {
+        let args: Vec<&str> = command_line.split_whitespace().collect();
+        // the program is either run directly, run by python as a script, or run with 'python -m'
+        let program = args.iter().take(3).position(|arg| {
+            let name = program_name(arg);
+            name == "gunicorn" || name == "celery" || name == "uwsgi"
+        })?;
+        let rest = &args[program + 1..];
+        match program_name(args[program]) {
+            "gunicorn" => {
+                let app = rest
+                    .iter()
+                    .rev()
+                    .find(|arg| !arg.starts_with('-') && arg.contains(':'))
+                    .unwrap_or(&"");
+                Some(Server::Gunicorn(app.to_string()))
+            }
+            "celery" => {
+                let command = rest.iter().find(|arg| is_celery_command(arg))?;
+                Some(Server::Celery(
+                    command.to_string(),
+                    option_value(rest, &["-n", "--hostname"]).unwrap_or_default(),
+                ))
+            }
+            _ => Some(Server::Uwsgi),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Server::Gunicorn(_) => "gunicorn",
+            Server::Celery(..) => "celery",
+            Server::Uwsgi => "uwsgi",
+        }
+    }
+}
+
+/// The name of a program without its directory or file extension, like 'gunicorn' for
+/// '/venv/bin/gunicorn'
+fn program_name(arg: &str) -> &str {
+    let name = arg.rsplit(['/', '\\']).next().unwrap_or(arg);
+    name.strip_suffix(".exe")
+        .or_else(|| name.strip_suffix(".py"))
+        .unwrap_or(name)
+}
+
+fn is_celery_command(arg: &str) -> bool {
+    matches!(arg, "worker" | "beat" | "flower" | "events" | "multi")
+}
+
+/// The value of a command line option, given as either '-n value', '--hostname value' or
+/// '--hostname=value'
+fn option_value(args: &[&str], names: &[&str]) -> Option<String> {
+    args.iter().enumerate().find_map(|(i, arg)| {
+        if names.contains(arg) {
+            return args.get(i + 1).map(|value| value.to_string());
+        }
+        let (name, value) = arg.split_once('=')?;
+        names.contains(&name).then(|| value.to_owned())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_process_role() {
+        let role = |command_line, parent| process_role(command_line, parent);
+
+        // forked workers have the same command line as the process that started them
+        let gunicorn = "/venv/bin/python /venv/bin/gunicorn -w 4 app:wsgi";
+        assert_eq!(
+            role(gunicorn, Some("bash")).as_deref(),
+            Some("gunicorn master app:wsgi")
+        );
+        assert_eq!(
+            role(gunicorn, Some(gunicorn)).as_deref(),
+            Some("gunicorn worker app:wsgi")
+        );
+        let celery = "python -m celery -A proj worker -n billing@%h --loglevel=INFO";
+        assert_eq!(
+            role(celery, None).as_deref(),
+            Some("celery worker billing@%h")
+        );
+        assert_eq!(
+            role(celery, Some(celery)).as_deref(),
+            Some("celery pool worker billing@%h")
+        );
+        assert_eq!(
+            role("celery -A proj beat", None).as_deref(),
+            Some("celery beat")
+        );
+        assert_eq!(
+            role("uwsgi --ini app.ini", Some("uwsgi --ini app.ini")).as_deref(),
+            Some("uwsgi worker")
+        );
+
+        // command lines rewritten by setproctitle
+        assert_eq!(
+            role("gunicorn: worker [app:wsgi]", None).as_deref(),
+            Some("gunicorn worker app:wsgi")
+        );
+        assert_eq!(
+            role("[celeryd: celery@web1:ForkPoolWorker-3]", None).as_deref(),
+            Some("celery ForkPoolWorker-3 celery@web1")
+        );
+        assert_eq!(
+            role(
+                "[celeryd: celery@web1:MainProcess] -active- (worker -A proj)",
+                None
+            )
+            .as_deref(),
+            Some("celery worker celery@web1")
+        );
+        assert_eq!(role("uWSGI mule 2", None).as_deref(), Some("uwsgi mule 2"));
+
+        // other programs don't have roles
+        assert_eq!(role("python app.py --workers 4", None), None);
+        assert_eq!(role("python -m celery_helpers worker", None), None);
+        assert_eq!(role("celery --help", None), None);
+    }
+}
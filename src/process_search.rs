@@ -0,0 +1,241 @@
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use anyhow::{Context, Error};
+use regex::Regex;
+use remoteprocess::Pid;
+
+use crate::python_process_info::is_python_process;
+
+/*
+ * This file contains code for '--pid-name', which finds the process to profile by matching a regex
+ * against the name and command line of every process, so that the pid doesn't have to be hunted
+ * down by hand (like in containers that don't have ps).
+ *
+ * The command line of py-spy itself has the regex in it, and so do the command lines of the
+ * processes that started it (like 'sudo py-spy ...'), so these are left out.
+ *
+ * It also has the code for '--pid-file', which reads the pid from a file that the program writes
+ * (like gunicorn's --pid), and for '--wait-for', which polls for either of these to turn up a
+ * process so that short-lived jobs can be profiled from the moment they start, and for '--all',
+ * which finds every process on the system that's running python.
+ */
+
+/// How often to look for the process when waiting for it to start
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A process on the system, with its parent and what it can be found by
+pub struct ProcessEntry {
+    pub pid: Pid,
+    pub parent: Option<Pid>,
+    pub name: String,
+    pub command_line: String,
+}
+
+/// Finds the processes whose name or command line match a regex, ordered by pid
+pub fn find_processes(pattern: &str) -> Result<Vec<ProcessEntry>, Error> {
+    let regex = Regex::new(pattern)?;
+    let processes = list_processes()?;
+    Ok(matching_processes(
+        &regex,
+        processes,
+        std::process::id() as Pid,
+    ))
+}
+
+/// Waits for at least one process to match a regex
+pub fn wait_for_processes(pattern: &str) -> Result<Vec<ProcessEntry>, Error> {
+    loop {
+        let processes = find_processes(pattern)?;
+        if !processes.is_empty() {
+            return Ok(processes);
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Reads the pid from a pid file
+pub fn read_pid_file(filename: &str) -> Result<Pid, Error> {
+    let contents = std::fs::read_to_string(filename)
+        .with_context(|| format!("Failed to read pid file '{}'", filename))?;
+    parse_pid(&contents).ok_or_else(|| format_err!("Pid file '{}' doesn't contain a pid", filename))
+}
+
+/// Waits for a pid file to have the pid of a running process in it. Pid files are often left
+/// behind by the previous run of a program, so the process has to be running too
+pub fn wait_for_pid_file(filename: &str) -> Pid {
+    loop {
+        if let Ok(pid) = read_pid_file(filename) {
+            if remoteprocess::Process::new(pid).is_ok_and(|process| process.exe().is_ok()) {
+                return pid;
+            }
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+fn parse_pid(contents: &str) -> Option<Pid> {
+    // the file is written by the program as it starts, so can be read before it's complete
+    let line = contents.lines().next()?.trim();
+    line.parse().ok().filter(|&pid: &Pid| pid > 0)
+}
+
+/// Describes the processes that matched, for when more than one did
+pub fn describe_processes(processes: &[ProcessEntry]) -> String {
+    processes
+        .iter()
+        .map(|process| format!("  {:>8}  {}", process.pid, process.command_line))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Finds every process running python, ordered by pid
+pub fn find_python_processes() -> Result<Vec<ProcessEntry>, Error> {
+    let processes = list_processes()?;
+    let mut found: Vec<ProcessEntry> = without_ancestors(processes, std::process::id() as Pid)
+        .into_iter()
+        .filter(|process| is_python_process(process.pid))
+        .collect();
+    found.sort_by_key(|process| process.pid);
+    Ok(found)
+}
+
+fn matching_processes(
+    regex: &Regex,
+    processes: Vec<ProcessEntry>,
+    own_pid: Pid,
+) -> Vec<ProcessEntry> {
+    let mut matched: Vec<ProcessEntry> = without_ancestors(processes, own_pid)
+        .into_iter()
+        .filter(|process| regex.is_match(&process.name) || regex.is_match(&process.command_line))
+        .collect();
+    matched.sort_by_key(|process| process.pid);
+    matched
+}
+
+/// Leaves out a process and the processes that started it
+fn without_ancestors(processes: Vec<ProcessEntry>, own_pid: Pid) -> Vec<ProcessEntry> {
+    let parents: HashMap<Pid, Pid> = processes
+        .iter()
+        .filter_map(|process| Some((process.pid, process.parent?)))
+        .collect();
+    let mut ancestors = HashSet::new();
+    let mut pid = Some(own_pid);
+    while let Some(current) = pid {
+        if !ancestors.insert(current) {
+            break;
+        }
+        pid = parents.get(&current).copied();
+    }
+
+    processes
+        .into_iter()
+        .filter(|process| !ancestors.contains(&process.pid))
+        .collect()
+}
+
+/// Lists every process, from the processes started by init
+#[cfg(unix)]
+fn list_processes() -> Result<Vec<ProcessEntry>, Error> {
+    let init = remoteprocess::Process::new(1)?;
+    let mut pids = vec![(1, None)];
+    pids.extend(
+        init.child_processes()?
+            .into_iter()
+            .map(|(pid, parent)| (pid, Some(parent))),
+    );
+    Ok(pids
+        .into_iter()
+        .filter_map(|(pid, parent)| {
+            // processes can exit while we're listing them
+            let process = remoteprocess::Process::new(pid).ok()?;
+            let command_line = process.cmdline().ok()?.join(" ");
+            // the executable of processes owned by other users can't always be read
+            let name = process
+                .exe()
+                .ok()
+                .and_then(|exe| {
+                    std::path::Path::new(&exe)
+                        .file_name()
+                        .map(|name| name.to_string_lossy().into_owned())
+                })
+                .unwrap_or_default();
+            Some(ProcessEntry {
+                pid,
+                parent,
+                name,
+                command_line,
+            })
+        })
+        .collect())
+}
+
+#[cfg(windows)]
+fn list_processes() -> Result<Vec<ProcessEntry>, Error> {
+    Err(format_err!(
+        "--pid-name and --all aren't supported on windows yet"
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn process(pid: Pid, parent: Pid, name: &str, command_line: &str) -> ProcessEntry {
+        ProcessEntry {
+            pid,
+            parent: Some(parent),
+            name: name.to_owned(),
+            command_line: command_line.to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_matching_processes() {
+        let processes = || {
+            vec![
+                process(12, 1, "python3.11", "gunicorn: worker [app:wsgi]"),
+                process(10, 1, "python3.11", "gunicorn: master [app:wsgi]"),
+                process(11, 10, "python3.11", "gunicorn: worker [app:wsgi]"),
+                process(
+                    20,
+                    1,
+                    "sudo",
+                    "sudo py-spy record --pid-name gunicorn: worker",
+                ),
+                process(
+                    21,
+                    20,
+                    "py-spy",
+                    "py-spy record --pid-name gunicorn: worker",
+                ),
+                process(30, 1, "celery", "/venv/bin/celery -A proj worker"),
+            ]
+        };
+
+        // py-spy and the sudo that started it are left out
+        let regex = Regex::new("gunicorn: worker").unwrap();
+        let matched = matching_processes(&regex, processes(), 21);
+        let pids: Vec<Pid> = matched.iter().map(|process| process.pid).collect();
+        assert_eq!(pids, vec![11, 12]);
+        assert_eq!(
+            describe_processes(&matched),
+            "        11  gunicorn: worker [app:wsgi]\n        12  gunicorn: worker [app:wsgi]"
+        );
+
+        // the name of the executable is matched too
+        let regex = Regex::new("^celery$").unwrap();
+        let matched = matching_processes(&regex, processes(), 21);
+        assert_eq!(matched.len(), 1);
+        assert_eq!(matched[0].pid, 30);
+    }
+
+    #[test]
+    fn test_parse_pid() {
+        assert_eq!(parse_pid("1234\n"), Some(1234));
+        assert_eq!(parse_pid("  1234  "), Some(1234));
+        assert_eq!(parse_pid(""), None);
+        assert_eq!(parse_pid("12ab"), None);
+        assert_eq!(parse_pid("0\n"), None);
+    }
+}
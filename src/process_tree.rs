@@ -0,0 +1,134 @@
+use std::collections::BTreeMap;
+
+use remoteprocess::Pid;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::stack_trace::ProcessInfo;
+
+/*
+ * This file contains code to keep track of the tree of processes sampled with --subprocesses,
+ * so that the speedscope and chrome trace outputs can show which process was forked from which
+ * (like the workers of a gunicorn master), and when each process was being sampled.
+ *
+ * The parent of each process comes from the process info the sampler attaches to its traces, so
+ * the processes in between a sampled process and the process py-spy was asked to profile (like
+ * a shell script that started python) are in the tree too, even though they aren't sampled.
+ */
+
+/// A process in the tree, with when it was first and last sampled
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProcessNode {
+    pub pid: Pid,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent: Option<Pid>,
+    pub command_line: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role: Option<String>,
+    // microseconds since the start of the recording, which processes that are only the parents
+    // of sampled processes don't have
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub first_sampled: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_sampled: Option<u64>,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct ProcessTree {
+    processes: BTreeMap<Pid, ProcessNode>,
+}
+
+impl ProcessTree {
+    /// Records that a process was sampled at a time (in microseconds since the start of the
+    /// recording), along with the processes it was started by
+    pub fn record(&mut self, process: &ProcessInfo, timestamp: u64) {
+        let node = self.add(process);
+        node.first_sampled.get_or_insert(timestamp);
+        node.last_sampled = Some(timestamp);
+        let mut parent = process.parent.as_deref();
+        while let Some(process) = parent {
+            self.add(process);
+            parent = process.parent.as_deref();
+        }
+    }
+
+    fn add(&mut self, process: &ProcessInfo) -> &mut ProcessNode {
+        self.processes
+            .entry(process.pid)
+            .or_insert_with(|| ProcessNode {
+                pid: process.pid,
+                parent: process.parent.as_ref().map(|parent| parent.pid),
+                command_line: process.command_line.clone(),
+                role: process.role.clone(),
+                first_sampled: None,
+                last_sampled: None,
+            })
+    }
+
+    /// The processes in the tree, ordered by pid
+    pub fn processes(&self) -> Vec<ProcessNode> {
+        self.processes.values().cloned().collect()
+    }
+}
+
+/// Describes the process that a process was forked from, like 'forked from gunicorn master
+/// app:wsgi (pid 10)'
+pub fn forked_from(process: &ProcessInfo) -> Option<String> {
+    let parent = process.parent.as_ref()?;
+    Some(match (&parent.role, parent.command_line.as_str()) {
+        (None, "") => format!("forked from process {}", parent.pid),
+        (None, command_line) => format!("forked from {} (pid {})", command_line, parent.pid),
+        // the name of processes with roles already has their pid
+        (Some(_), _) => format!("forked from {}", parent.name()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn process(pid: Pid, command_line: &str, parent: Option<ProcessInfo>) -> ProcessInfo {
+        ProcessInfo {
+            pid,
+            command_line: command_line.to_owned(),
+            parent: parent.map(Box::new),
+            role: None,
+        }
+    }
+
+    #[test]
+    fn test_process_tree() {
+        let wrapper = process(5, "bash run.sh", None);
+        let master = process(10, "python app.py", Some(wrapper));
+        let worker = process(11, "python app.py", Some(master.clone()));
+
+        let mut tree = ProcessTree::default();
+        tree.record(&master, 100);
+        tree.record(&worker, 200);
+        tree.record(&master, 300);
+        let processes = tree.processes();
+        let pids: Vec<Pid> = processes.iter().map(|process| process.pid).collect();
+        assert_eq!(pids, vec![5, 10, 11]);
+        // the wrapper is only in the tree as the parent of the master
+        assert_eq!(processes[0].parent, None);
+        assert_eq!(processes[0].first_sampled, None);
+        assert_eq!(processes[1].parent, Some(5));
+        assert_eq!(processes[1].first_sampled, Some(100));
+        assert_eq!(processes[1].last_sampled, Some(300));
+        assert_eq!(processes[2].parent, Some(10));
+        assert_eq!(processes[2].first_sampled, Some(200));
+        assert_eq!(processes[2].last_sampled, Some(200));
+
+        assert_eq!(
+            forked_from(&worker).as_deref(),
+            Some("forked from python app.py (pid 10)")
+        );
+        assert_eq!(forked_from(&process(1, "init", None)), None);
+        let mut master = master;
+        master.role = Some("gunicorn master app:wsgi".to_owned());
+        let worker = process(12, "gunicorn: worker", Some(master));
+        assert_eq!(
+            forked_from(&worker).as_deref(),
+            Some("forked from gunicorn master app:wsgi (pid 10)")
+        );
+    }
+}
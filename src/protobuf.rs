@@ -0,0 +1,56 @@
+/*
+ * Minimal helpers for encoding protocol buffers by hand, shared by the pprof and perfetto
+ * output formats. Only the wire types we need are supported: varints and length delimited
+ * fields (strings, bytes, embedded messages and packed repeated varints).
+ *
+ * https://protobuf.dev/programming-guides/encoding/
+ */
+
+pub fn encode_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+pub fn encode_varint_field(out: &mut Vec<u8>, field: u64, value: u64) {
+    encode_varint(out, field << 3);
+    encode_varint(out, value);
+}
+
+pub fn encode_message(out: &mut Vec<u8>, field: u64, data: &[u8]) {
+    encode_varint(out, (field << 3) | 2);
+    encode_varint(out, data.len() as u64);
+    out.extend_from_slice(data);
+}
+
+pub fn encode_packed<I: IntoIterator<Item = u64>>(out: &mut Vec<u8>, field: u64, values: I) {
+    let mut data = Vec::new();
+    for value in values {
+        encode_varint(&mut data, value);
+    }
+    encode_message(out, field, &data);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_varint() {
+        let mut out = Vec::new();
+        encode_varint(&mut out, 1);
+        encode_varint(&mut out, 300);
+        assert_eq!(out, vec![0x01, 0xac, 0x02]);
+    }
+
+    #[test]
+    fn test_message() {
+        let mut inner = Vec::new();
+        encode_varint_field(&mut inner, 1, 150);
+        let mut out = Vec::new();
+        encode_message(&mut out, 3, &inner);
+        assert_eq!(out, vec![0x1a, 0x03, 0x08, 0x96, 0x01]);
+    }
+}
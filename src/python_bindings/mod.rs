@@ -1,3 +1,4 @@
+#[cfg(feature = "python2")]
 pub mod v2_7_15;
 pub mod v3_10_0;
 pub mod v3_11_0;
@@ -1,6 +1,7 @@
 #![allow(clippy::unnecessary_cast)]
 use anyhow::Error;
 
+use crate::config::Config;
 use crate::python_interpreters::{
     BytesObject, InterpreterState, ListObject, Object, StringObject, TupleObject, TypeObject,
 };
@@ -23,8 +24,34 @@ pub fn copy_string<T: StringObject, P: ProcessMemory>(
     let kind = obj.kind();
 
     let bytes = process.copy(obj.address(ptr as usize), obj.size() * kind as usize)?;
+    decode_string(bytes, kind, obj.ascii())
+}
+
+// the most characters of a string that are copied from the process
+const MAX_STRING_LENGTH: usize = 1 << 20;
+
+/// Copies the first 'max_chars' characters of a string from a target process, returning them along
+/// with the length of the whole string. Unlike copy_string, this works for strings of any length
+pub fn copy_string_prefix<T: StringObject, P: ProcessMemory>(
+    ptr: *const T,
+    process: &P,
+    max_chars: usize,
+) -> Result<(String, usize), Error> {
+    let obj = process.copy_pointer(ptr)?;
+    let length = obj.size();
+    let chars = length.min(max_chars).min(MAX_STRING_LENGTH);
+    let kind = obj.kind();
+    let bytes = process.copy(obj.address(ptr as usize), chars * kind as usize)?;
+    // python 2 strs are bytes, which might have been cut off part way through a utf8 character
+    let value = match (kind, obj.ascii()) {
+        (1, true) => String::from_utf8_lossy(&bytes).into_owned(),
+        _ => decode_string(bytes, kind, obj.ascii())?,
+    };
+    Ok((value, length))
+}
 
-    match (kind, obj.ascii()) {
+fn decode_string(bytes: Vec<u8>, kind: u32, ascii: bool) -> Result<String, Error> {
+    match (kind, ascii) {
         (4, _) => {
             #[allow(clippy::cast_ptr_alignment)]
             let chars = unsafe {
@@ -94,14 +121,21 @@ pub fn copy_long<P: ProcessMemory>(process: &P, addr: usize) -> Result<(i64, boo
 }
 
 /// Copies a i64 from a python 2.7 PyIntObject
+#[cfg(feature = "python2")]
 pub fn copy_int<P: ProcessMemory>(process: &P, addr: usize) -> Result<i64, Error> {
     let value =
         process.copy_pointer(addr as *const crate::python_bindings::v2_7_15::PyIntObject)?;
     Ok(value.ob_ival as i64)
 }
 
-/// Allows iteration of a python dictionary. Only supports python 3.6+ right now
+#[cfg(not(feature = "python2"))]
+pub fn copy_int<P: ProcessMemory>(_process: &P, _addr: usize) -> Result<i64, Error> {
+    Err(format_err!(
+        "reading python 2 ints needs the 'python2' feature"
+    ))
+}
 
+/// Allows iteration of a python dictionary. Only supports python 3.6+ right now
 pub struct DictIterator<'a, P: 'a> {
     process: &'a P,
     entries_addr: usize,
@@ -204,6 +238,109 @@ impl<'a, P: ProcessMemory> DictIterator<'a, P> {
     }
 }
 
+/// Finds the value of a string key in a python dictionary
+pub fn find_key<T: StringObject, P: ProcessMemory>(
+    dict: DictIterator<P>,
+    process: &P,
+    name: &str,
+) -> Result<Option<usize>, Error> {
+    for entry in dict {
+        let (key, value) = entry?;
+        if copy_string(key as *const T, process)? == name {
+            return Ok(Some(value));
+        }
+    }
+    Ok(None)
+}
+
+/// Allows iteration of the attributes of a python object, from its __dict__
+pub fn object_dict<'a, I: InterpreterState, P: ProcessMemory>(
+    process: &'a P,
+    version: &'a Version,
+    addr: usize,
+) -> Result<DictIterator<'a, P>, Error> {
+    let object: I::Object = process.copy_struct(addr)?;
+    let object_type = process.copy_pointer(object.ob_type())?;
+    if object_type.flags() & PY_TPFLAGS_MANAGED_DICT != 0 {
+        return DictIterator::from_managed_dict(process, version, addr, object.ob_type() as usize);
+    }
+    let dict_offset = object_type.dictoffset();
+    if dict_offset == 0 {
+        return Err(format_err!(
+            "Object at 0x{:x} doesn't have a __dict__",
+            addr
+        ));
+    }
+    let dict_addr: usize = process.copy_struct((addr as isize + dict_offset) as usize)?;
+    DictIterator::from(process, version, dict_addr)
+}
+
+/// A python PyMemberDef, which has had the same layout since python 2
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct MemberDef {
+    name: usize,
+    member_type: i32,
+    offset: isize,
+    flags: i32,
+    doc: usize,
+}
+
+/// The offset of the slot with a name in objects of a type, from the PyMemberDef arrays of the
+/// type and the types it inherits from. This is None if there's no slot with this name
+pub fn slot_offset<T: TypeObject, P: ProcessMemory>(
+    process: &P,
+    type_addr: usize,
+    name: &str,
+) -> Result<Option<usize>, Error> {
+    let mut current = type_addr;
+    for _ in 0..64 {
+        if current == 0 {
+            break;
+        }
+        let object_type: T = process.copy_struct(current)?;
+        let mut member_addr = object_type.members();
+        while member_addr != 0 {
+            let member: MemberDef = process.copy_struct(member_addr)?;
+            if member.name == 0 {
+                break;
+            }
+            let member_name = process.copy(member.name, name.len() + 1)?;
+            if &member_name[..name.len()] == name.as_bytes() && member_name[name.len()] == 0 {
+                return Ok(Some(member.offset as usize));
+            }
+            member_addr += std::mem::size_of::<MemberDef>();
+        }
+        current = object_type.base();
+    }
+    Ok(None)
+}
+
+/// Gets an attribute of a python object from its slots, or otherwise its __dict__. This is None
+/// if the attribute isn't set
+pub fn get_attribute<I: InterpreterState, P: ProcessMemory>(
+    process: &P,
+    version: &Version,
+    addr: usize,
+    name: &str,
+) -> Result<Option<usize>, Error> {
+    let object: I::Object = process.copy_struct(addr)?;
+    let type_addr = object.ob_type() as usize;
+    let value = match slot_offset::<<I::Object as Object>::TypeObject, P>(process, type_addr, name)?
+    {
+        Some(offset) => process.copy_struct(addr + offset)?,
+        None => {
+            let object_type = process.copy_pointer(object.ob_type())?;
+            if object_type.flags() & PY_TPFLAGS_MANAGED_DICT == 0 && object_type.dictoffset() == 0 {
+                return Ok(None);
+            }
+            let dict = object_dict::<I, P>(process, version, addr)?;
+            find_key::<I::StringObject, P>(dict, process, name)?.unwrap_or(0)
+        }
+    };
+    Ok((value != 0).then_some(value))
+}
+
 impl<'a, P: ProcessMemory> Iterator for DictIterator<'a, P> {
     type Item = Result<(usize, usize), Error>;
 
@@ -267,6 +404,38 @@ impl<'a, P: ProcessMemory> Iterator for DictIterator<'a, P> {
     }
 }
 
+/// The start of a python set object, which has had the same layout since python 3.5
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct SetObject {
+    ob_refcnt: isize,
+    ob_type: usize,
+    fill: isize,
+    used: isize,
+    mask: isize,
+    table: usize,
+}
+
+// the largest table of a set that is read
+const MAX_SET_SIZE: usize = 1 << 17;
+
+/// Gets the items in a python set, for python 3.5+
+pub fn set_items<P: ProcessMemory>(process: &P, addr: usize) -> Result<Vec<usize>, Error> {
+    let set: SetObject = process.copy_struct(addr)?;
+    let size = set.mask as usize + 1;
+    if set.mask < 0 || size > MAX_SET_SIZE {
+        return Err(format_err!("Refusing to read a set of {} items", size));
+    }
+    // each entry of the table is a pointer to the item and its hash. removed items are replaced
+    // with a placeholder with a hash of -1, which no item can have
+    let table: Vec<usize> = process.copy_vec(set.table, 2 * size)?;
+    Ok(table
+        .chunks(2)
+        .filter(|entry| entry[0] != 0 && entry[1] as isize != -1)
+        .map(|entry| entry[0])
+        .collect())
+}
+
 pub const PY_TPFLAGS_MANAGED_DICT: usize = 1 << 4;
 const PY_TPFLAGS_INT_SUBCLASS: usize = 1 << 23;
 const PY_TPFLAGS_LONG_SUBCLASS: usize = 1 << 24;
@@ -275,13 +444,130 @@ const PY_TPFLAGS_TUPLE_SUBCLASS: usize = 1 << 26;
 const PY_TPFLAGS_BYTES_SUBCLASS: usize = 1 << 27;
 const PY_TPFLAGS_STRING_SUBCLASS: usize = 1 << 28;
 const PY_TPFLAGS_DICT_SUBCLASS: usize = 1 << 29;
+pub const PY_TPFLAGS_TYPE_SUBCLASS: usize = 1 << 31;
+
+/// How much of a python variable to format
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FormatLimits {
+    /// The longest the formatted string can be
+    pub max_length: isize,
+    /// How many levels of nested containers to format the items of. Containers nested deeper
+    /// than this are shown as '[...]'
+    pub max_depth: Option<usize>,
+    /// The most items of each container to format
+    pub max_items: Option<usize>,
+    /// The most characters of a string, or bytes of binary data, to show
+    pub max_str_len: Option<usize>,
+}
+
+// the longest a variable is formatted to when it's limited by its depth and number of items
+const MAX_DEEP_LENGTH: isize = 1 << 20;
+// the deepest nested containers are formatted to when there's room for more, which stops
+// containers that contain themselves from being formatted forever
+const MAX_NESTED_DEPTH: usize = 64;
+
+impl FormatLimits {
+    /// The limits for formatting local variables. These are truncated to 128 characters (for each
+    /// time --locals is passed), unless they're limited by --locals-depth, --locals-max-items or
+    /// --locals-max-str-len
+    pub fn from_config(config: &Config) -> FormatLimits {
+        let max_length = if config.locals_depth.is_some()
+            || config.locals_max_items.is_some()
+            || config.locals_max_str_len.is_some()
+        {
+            MAX_DEEP_LENGTH
+        } else {
+            (128 * config.dump_locals) as isize
+        };
+        FormatLimits {
+            max_length,
+            max_depth: Some(config.locals_depth.unwrap_or(MAX_NESTED_DEPTH)),
+            max_items: config.locals_max_items,
+            max_str_len: config.locals_max_str_len,
+        }
+    }
+
+    /// The limits for formatting the whole value of a variable, for --locals-output
+    pub fn full() -> FormatLimits {
+        FormatLimits {
+            max_length: MAX_DEEP_LENGTH,
+            max_depth: Some(MAX_NESTED_DEPTH),
+            max_items: None,
+            max_str_len: None,
+        }
+    }
+
+    /// Whether the items of a container can be formatted
+    fn formats_items(&self) -> bool {
+        self.max_depth != Some(0)
+    }
+}
+
+/// Collects the formatted items of a container, until there's no room left for more
+struct FormattedItems {
+    limits: FormatLimits,
+    values: Vec<String>,
+    remaining: isize,
+    truncated: bool,
+}
+
+impl FormattedItems {
+    /// Starts formatting the items of a container, whose brackets take up 'overhead' characters
+    fn new(limits: &FormatLimits, overhead: isize) -> FormattedItems {
+        FormattedItems {
+            limits: *limits,
+            values: Vec::new(),
+            remaining: limits.max_length - overhead,
+            truncated: false,
+        }
+    }
+
+    /// The limits to format the next item with, or None (after adding an ellipsis) once the most
+    /// items have been formatted
+    fn next(&mut self) -> Option<FormatLimits> {
+        if self.truncated {
+            return None;
+        }
+        if self.limits.max_items == Some(self.values.len()) {
+            self.truncate();
+            return None;
+        }
+        Some(FormatLimits {
+            max_length: self.remaining,
+            max_depth: self.limits.max_depth.map(|depth| depth.saturating_sub(1)),
+            max_items: self.limits.max_items,
+            max_str_len: self.limits.max_str_len,
+        })
+    }
+
+    /// Adds a formatted item, returning false (after adding an ellipsis instead) if there isn't
+    /// room for it
+    fn push(&mut self, value: String) -> bool {
+        self.remaining -= value.len() as isize + 2;
+        if self.remaining <= 5 {
+            self.truncate();
+            return false;
+        }
+        self.values.push(value);
+        true
+    }
+
+    fn truncate(&mut self) {
+        self.values.push("...".to_owned());
+        self.truncated = true;
+    }
+
+    fn join(&self) -> String {
+        self.values.join(", ")
+    }
+}
 
 /// Converts a python variable in the other process to a human readable string
 pub fn format_variable<I, P>(
     process: &P,
     version: &Version,
     addr: usize,
-    max_length: isize,
+    limits: &FormatLimits,
 ) -> Result<String, Error>
 where
     I: InterpreterState,
@@ -289,21 +575,15 @@ where
 {
     // We need at least 5 characters remaining for all this code to work, replace with an ellipsis if
     // we're out of space
+    let max_length = limits.max_length;
     if max_length <= 5 {
         return Ok("...".to_owned());
     }
 
     let value: I::Object = process.copy_struct(addr)?;
     let value_type = process.copy_pointer(value.ob_type())?;
-
-    // get the typename (truncating to 128 bytes if longer)
-    let max_type_len = 128;
-    let value_type_name = process.copy(value_type.name() as usize, max_type_len)?;
-    let length = value_type_name
-        .iter()
-        .position(|&x| x == 0)
-        .unwrap_or(max_type_len);
-    let value_type_name = std::str::from_utf8(&value_type_name[..length])?;
+    let value_type_name = copy_type_name(&value_type, process)?;
+    let value_type_name = value_type_name.as_str();
 
     let format_int = |value: i64| {
         if value_type_name == "bool" {
@@ -332,30 +612,41 @@ where
     } else if flags & PY_TPFLAGS_STRING_SUBCLASS != 0
         || (version.major == 2 && (flags & PY_TPFLAGS_BYTES_SUBCLASS != 0))
     {
-        let value = copy_string(addr as *const I::StringObject, process)?
-            .replace('\'', "\\\"")
-            .replace('\n', "\\n");
-        if value.len() as isize >= max_length - 5 {
-            format!("\"{}...\"", &value[..(max_length - 5) as usize])
+        let max_chars = limits
+            .max_str_len
+            .unwrap_or(usize::MAX)
+            .min((max_length - 5) as usize);
+        let (value, length) =
+            copy_string_prefix(addr as *const I::StringObject, process, max_chars)?;
+        let value = value.replace('\'', "\\\"").replace('\n', "\\n");
+        if length > max_chars || value.chars().count() > max_chars {
+            let value: String = value.chars().take(max_chars).collect();
+            format!("\"{}...\"", value)
         } else {
             format!("\"{}\"", value)
         }
+    } else if flags & PY_TPFLAGS_BYTES_SUBCLASS != 0 {
+        format_bytes(process, addr, limits)?
     } else if flags & PY_TPFLAGS_DICT_SUBCLASS != 0 {
         if version.major == 3 && version.minor >= 6 {
-            let mut values = Vec::new();
-            let mut remaining = max_length - 2;
-            for entry in DictIterator::from(process, version, addr)? {
-                let (key, value) = entry?;
-                let key = format_variable::<I, P>(process, version, key, remaining)?;
-                let value = format_variable::<I, P>(process, version, value, remaining)?;
-                remaining -= (key.len() + value.len()) as isize + 4;
-                if remaining <= 5 {
-                    values.push("...".to_owned());
-                    break;
+            let mut items = FormattedItems::new(limits, 2);
+            if limits.formats_items() {
+                for entry in DictIterator::from(process, version, addr)? {
+                    let item_limits = match items.next() {
+                        Some(item_limits) => item_limits,
+                        None => break,
+                    };
+                    let (key, value) = entry?;
+                    let key = format_variable::<I, P>(process, version, key, &item_limits)?;
+                    let value = format_variable::<I, P>(process, version, value, &item_limits)?;
+                    if !items.push(format!("{}: {}", key, value)) {
+                        break;
+                    }
                 }
-                values.push(format!("{}: {}", key, value));
+            } else if DictIterator::from(process, version, addr)?.next().is_some() {
+                items.truncate();
             }
-            format!("{{{}}}", values.join(", "))
+            format!("{{{}}}", items.join())
         } else {
             // TODO: support getting dictionaries from older versions of python
             "dict".to_owned()
@@ -363,35 +654,34 @@ where
     } else if flags & PY_TPFLAGS_LIST_SUBCLASS != 0 {
         let object: I::ListObject = process.copy_struct(addr)?;
         let addr = object.item() as usize;
-        let mut values = Vec::new();
-        let mut remaining = max_length - 2;
-        for i in 0..object.size() {
-            let valueptr: *mut I::Object =
-                process.copy_struct(addr + i * std::mem::size_of::<*mut I::Object>())?;
-            let value = format_variable::<I, P>(process, version, valueptr as usize, remaining)?;
-            remaining -= value.len() as isize + 2;
-            if remaining <= 5 {
-                values.push("...".to_owned());
-                break;
-            }
-            values.push(value);
-        }
-        format!("[{}]", values.join(", "))
+        let values = (0..object.size())
+            .map(|i| process.copy_struct(addr + i * std::mem::size_of::<*mut I::Object>()));
+        format!(
+            "[{}]",
+            format_sequence::<I, P>(process, version, values, limits)?
+        )
     } else if flags & PY_TPFLAGS_TUPLE_SUBCLASS != 0 {
         let object: I::TupleObject = process.copy_struct(addr)?;
-        let mut values = Vec::new();
-        let mut remaining = max_length - 2;
-        for i in 0..object.size() {
-            let value_addr: *mut I::Object = process.copy_struct(object.address(addr, i))?;
-            let value = format_variable::<I, P>(process, version, value_addr as usize, remaining)?;
-            remaining -= value.len() as isize + 2;
-            if remaining <= 5 {
-                values.push("...".to_owned());
-                break;
+        let values = (0..object.size()).map(|i| process.copy_struct(object.address(addr, i)));
+        format!(
+            "({})",
+            format_sequence::<I, P>(process, version, values, limits)?
+        )
+    } else if matches!(value_type_name, "set" | "frozenset")
+        && (version.major > 3 || (version.major == 3 && version.minor >= 5))
+    {
+        let values = set_items(process, addr)?;
+        if values.is_empty() {
+            format!("{}()", value_type_name)
+        } else {
+            let values = values.into_iter().map(Ok);
+            let values = format_sequence::<I, P>(process, version, values, limits)?;
+            if value_type_name == "set" {
+                format!("{{{}}}", values)
+            } else {
+                format!("frozenset({{{}}})", values)
             }
-            values.push(value);
         }
-        format!("({})", values.join(", "))
     } else if value_type_name == "float" {
         let value =
             process.copy_pointer(addr as *const crate::python_bindings::v3_7_0::PyFloatObject)?;
@@ -399,19 +689,497 @@ where
     } else if value_type_name == "NoneType" {
         "None".to_owned()
     } else {
-        format!("<{} at 0x{:x}>", value_type_name, addr)
+        // objects that can't be read (like those that change while being read) are shown the
+        // same way as objects of types that aren't understood
+        match format_object::<I, P>(process, version, addr, &value_type, limits) {
+            Ok(Some(formatted)) => formatted,
+            _ => format!("<{} at 0x{:x}>", value_type_name, addr),
+        }
     };
 
     Ok(formatted)
 }
 
+/// The layout of the start of a python 3 bytes object, which is followed by its data
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct BytesObjectHeader {
+    ob_refcnt: isize,
+    ob_type: usize,
+    ob_size: isize,
+    ob_shash: isize,
+}
+
+/// Formats binary data as its length and contents in hex, like '<5 bytes: 68656c6c6f>'
+fn format_bytes<P: ProcessMemory>(
+    process: &P,
+    addr: usize,
+    limits: &FormatLimits,
+) -> Result<String, Error> {
+    let header: BytesObjectHeader = process.copy_struct(addr)?;
+    let length = header.ob_size.max(0) as usize;
+    let prefix = format!("<{} bytes: ", length);
+    let room = ((limits.max_length - prefix.len() as isize - 4) / 2).max(0) as usize;
+    let shown = length
+        .min(limits.max_str_len.unwrap_or(usize::MAX))
+        .min(room);
+    let data = process.copy(addr + std::mem::size_of::<BytesObjectHeader>(), shown)?;
+    let hex: String = data.iter().map(|b| format!("{:02x}", b)).collect();
+    let ellipsis = if shown < length { "..." } else { "" };
+    Ok(format!("{}{}{}>", prefix, hex, ellipsis))
+}
+
+/// Formats the items of a list, tuple or set, separated by commas
+fn format_sequence<I, P>(
+    process: &P,
+    version: &Version,
+    values: impl ExactSizeIterator<Item = Result<usize, remoteprocess::Error>>,
+    limits: &FormatLimits,
+) -> Result<String, Error>
+where
+    I: InterpreterState,
+    P: ProcessMemory,
+{
+    let mut items = FormattedItems::new(limits, 2);
+    if !limits.formats_items() {
+        if values.len() > 0 {
+            items.truncate();
+        }
+        return Ok(items.join());
+    }
+    for value in values {
+        let item_limits = match items.next() {
+            Some(item_limits) => item_limits,
+            None => break,
+        };
+        let value = format_variable::<I, P>(process, version, value?, &item_limits)?;
+        if !items.push(value) {
+            break;
+        }
+    }
+    Ok(items.join())
+}
+
+// the layouts of the start of numpy's PyArrayObject and PyArray_Descr, which are part of numpy's
+// public C api and have stayed the same since numpy 1.7
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct ArrayObject {
+    ob_refcnt: isize,
+    ob_type: usize,
+    data: usize,
+    nd: i32,
+    dimensions: usize,
+    strides: usize,
+    base: usize,
+    descr: usize,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct ArrayDescr {
+    ob_refcnt: isize,
+    ob_type: usize,
+    typeobj: usize,
+}
+
+// the most dimensions a numpy array can have
+const MAX_ARRAY_DIMENSIONS: i32 = 64;
+
+/// The layout of a python range object
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct RangeObject {
+    ob_refcnt: isize,
+    ob_type: usize,
+    start: usize,
+    stop: usize,
+    step: usize,
+    length: usize,
+}
+
+/// Formats objects of types that are understood from their layout or attributes: numpy arrays
+/// and pandas DataFrames (which are shown by their shape), pathlib paths, enums and dataclasses.
+/// This is None for objects of any other type
+fn format_object<I, P>(
+    process: &P,
+    version: &Version,
+    addr: usize,
+    value_type: &<I::Object as Object>::TypeObject,
+    limits: &FormatLimits,
+) -> Result<Option<String>, Error>
+where
+    I: InterpreterState,
+    P: ProcessMemory,
+{
+    let type_name = copy_type_name(value_type, process)?;
+    match type_name.as_str() {
+        "numpy.ndarray" => {
+            return format_ndarray::<<I::Object as Object>::TypeObject, P>(process, addr).map(Some)
+        }
+        "PosixPath" | "PurePosixPath" => return format_path::<I, P>(process, version, addr, "/"),
+        "WindowsPath" | "PureWindowsPath" => {
+            return format_path::<I, P>(process, version, addr, "\\")
+        }
+        _ => {}
+    }
+
+    // the rest are recognized from the __dict__ of their type, which can only be read from 3.6
+    if version.major != 3 || version.minor < 6 || value_type.dict() == 0 {
+        return Ok(None);
+    }
+    let type_dict = || DictIterator::from(process, version, value_type.dict());
+    if matches!(type_name.as_str(), "DataFrame" | "Series") {
+        let module = find_key::<I::StringObject, P>(type_dict()?, process, "__module__")?;
+        let module = match module {
+            Some(module) => copy_string(module as *const I::StringObject, process)?,
+            None => return Ok(None),
+        };
+        if module.starts_with("pandas.") {
+            return format_pandas::<I, P>(process, version, addr, &type_name);
+        }
+    }
+    if let Some(fields) =
+        find_key::<I::StringObject, P>(type_dict()?, process, "__dataclass_fields__")?
+    {
+        return format_dataclass::<I, P>(process, version, addr, &type_name, fields, limits)
+            .map(Some);
+    }
+    if get_attribute::<I, P>(process, version, addr, "_value_")?.is_some() {
+        if let Some(name) = get_attribute::<I, P>(process, version, addr, "_name_")? {
+            let name = copy_string(name as *const I::StringObject, process)?;
+            return Ok(Some(format!("{}.{}", type_name, name)));
+        }
+    }
+    Ok(None)
+}
+
+/// The shape of a numpy array
+fn ndarray_shape<P: ProcessMemory>(process: &P, addr: usize) -> Result<Vec<isize>, Error> {
+    let array: ArrayObject = process.copy_struct(addr)?;
+    if !(0..=MAX_ARRAY_DIMENSIONS).contains(&array.nd) {
+        return Err(format_err!("Invalid number of dimensions {}", array.nd));
+    }
+    Ok(process.copy_vec(array.dimensions, array.nd as usize)?)
+}
+
+/// Formats a numpy array as its shape and dtype, like 'ndarray(shape=(3, 4), dtype=float64)'
+fn format_ndarray<T: TypeObject, P: ProcessMemory>(
+    process: &P,
+    addr: usize,
+) -> Result<String, Error> {
+    let shape = ndarray_shape(process, addr)?;
+    let array: ArrayObject = process.copy_struct(addr)?;
+    let descr: ArrayDescr = process.copy_struct(array.descr)?;
+    let scalar_type: T = process.copy_struct(descr.typeobj)?;
+    let dtype = copy_type_name(&scalar_type, process)?;
+    let dtype = dtype.strip_prefix("numpy.").unwrap_or(&dtype);
+    Ok(format!(
+        "ndarray(shape={}, dtype={})",
+        format_shape(&shape),
+        dtype
+    ))
+}
+
+/// Formats a shape like python does, like '(3,)' or '(3, 4)'
+fn format_shape(shape: &[isize]) -> String {
+    match shape {
+        [length] => format!("({},)", length),
+        _ => format!(
+            "({})",
+            shape
+                .iter()
+                .map(|length| length.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+}
+
+/// Formats a pathlib path, like 'PosixPath("/tmp/data.csv")'
+fn format_path<I, P>(
+    process: &P,
+    version: &Version,
+    addr: usize,
+    separator: &str,
+) -> Result<Option<String>, Error>
+where
+    I: InterpreterState,
+    P: ProcessMemory,
+{
+    let object: I::Object = process.copy_struct(addr)?;
+    let type_name = copy_type_name(&process.copy_pointer(object.ob_type())?, process)?;
+    let attribute = |name| get_attribute::<I, P>(process, version, addr, name);
+    let string = |addr| copy_string(addr as *const I::StringObject, process);
+
+    // paths only cache their string once it's been asked for, otherwise it's joined from its parts
+    let path = match attribute("_str")? {
+        Some(path) => string(path)?,
+        None => {
+            let (drive, root, parts) = match (
+                attribute("_drv")?,
+                attribute("_root")?,
+                attribute("_parts")?,
+            ) {
+                (Some(drive), Some(root), Some(parts)) => (string(drive)?, string(root)?, parts),
+                _ => return Ok(None),
+            };
+            let parts: I::ListObject = process.copy_struct(parts)?;
+            let mut names = Vec::new();
+            for i in 0..parts.size().min(1024) {
+                let part: usize = process
+                    .copy_struct(parts.item() as usize + i * std::mem::size_of::<usize>())?;
+                names.push(string(part)?);
+            }
+            // the first part is the drive and root when there is one
+            if !drive.is_empty() || !root.is_empty() {
+                format!(
+                    "{}{}{}",
+                    drive,
+                    root,
+                    names.get(1..).unwrap_or_default().join(separator)
+                )
+            } else {
+                names.join(separator)
+            }
+        }
+    };
+    Ok(Some(format!("{}(\"{}\")", type_name, path)))
+}
+
+/// Formats a pandas DataFrame or Series by its shape, like 'DataFrame(shape=(100, 3))'
+fn format_pandas<I, P>(
+    process: &P,
+    version: &Version,
+    addr: usize,
+    type_name: &str,
+) -> Result<Option<String>, Error>
+where
+    I: InterpreterState,
+    P: ProcessMemory,
+{
+    // the data is kept in a BlockManager, which is called '_data' before pandas 1.1
+    let manager = match get_attribute::<I, P>(process, version, addr, "_mgr")? {
+        Some(manager) => manager,
+        None => match get_attribute::<I, P>(process, version, addr, "_data")? {
+            Some(manager) => manager,
+            None => return Ok(None),
+        },
+    };
+    let axes = match pandas_axes::<I, P>(process, version, manager)? {
+        Some(axes) => axes,
+        None => return Ok(None),
+    };
+    let mut shape = Vec::new();
+    for axis in axes {
+        match index_length::<I, P>(process, version, axis)? {
+            Some(length) => shape.push(length),
+            None => return Ok(None),
+        }
+    }
+    // the axes of a BlockManager are stored as (columns, rows)
+    shape.reverse();
+    Ok(Some(format!(
+        "{}(shape={})",
+        type_name,
+        format_shape(&shape)
+    )))
+}
+
+/// Gets the indices of the axes of a pandas BlockManager
+fn pandas_axes<I, P>(
+    process: &P,
+    version: &Version,
+    manager: usize,
+) -> Result<Option<Vec<usize>>, Error>
+where
+    I: InterpreterState,
+    P: ProcessMemory,
+{
+    let list_items = |addr: usize| -> Result<Vec<usize>, Error> {
+        let list: I::ListObject = process.copy_struct(addr)?;
+        if list.size() > 32 {
+            return Err(format_err!("Too many pandas axes"));
+        }
+        Ok(process.copy_vec(list.item() as usize, list.size())?)
+    };
+    // the BlockManager is a python class with 'axes' as a slot before pandas 2, and a cython
+    // class since. the fields of cython classes aren't described anywhere, so the axes are
+    // found as the field that is a list of indices
+    if let Some(axes) = get_attribute::<I, P>(process, version, manager, "axes")? {
+        return list_items(axes).map(Some);
+    }
+    let fields: Vec<usize> = process.copy_vec(manager, 16)?;
+    for &field in fields.iter().skip(2) {
+        if field == 0 || field % std::mem::size_of::<usize>() != 0 {
+            continue;
+        }
+        if !matches!(
+            object_type_name::<I, P>(process, field).as_deref(),
+            Ok("list")
+        ) {
+            continue;
+        }
+        let items = list_items(field)?;
+        let is_index = |item: &usize| {
+            object_type_name::<I, P>(process, *item).is_ok_and(|name| name.ends_with("Index"))
+        };
+        if !items.is_empty() && items.iter().all(is_index) {
+            return Ok(Some(items));
+        }
+    }
+    Ok(None)
+}
+
+/// The length of a pandas Index, which is None for indices that aren't backed by a numpy array
+fn index_length<I, P>(process: &P, version: &Version, index: usize) -> Result<Option<isize>, Error>
+where
+    I: InterpreterState,
+    P: ProcessMemory,
+{
+    if let Some(range) = get_attribute::<I, P>(process, version, index, "_range")? {
+        let range: RangeObject = process.copy_struct(range)?;
+        return Ok(Some(copy_long(process, range.length)?.0 as isize));
+    }
+    let data = match get_attribute::<I, P>(process, version, index, "_data")? {
+        Some(data) => data,
+        None => return Ok(None),
+    };
+    if object_type_name::<I, P>(process, data)? != "numpy.ndarray" {
+        return Ok(None);
+    }
+    Ok(ndarray_shape(process, data)?.first().copied())
+}
+
+/// Formats a dataclass with the values of its fields, like 'Point(x=1, y=2)'
+fn format_dataclass<I, P>(
+    process: &P,
+    version: &Version,
+    addr: usize,
+    type_name: &str,
+    fields: usize,
+    limits: &FormatLimits,
+) -> Result<String, Error>
+where
+    I: InterpreterState,
+    P: ProcessMemory,
+{
+    let mut items = FormattedItems::new(limits, type_name.len() as isize + 2);
+    for entry in DictIterator::from(process, version, fields)? {
+        let (name, _) = entry?;
+        let name = copy_string(name as *const I::StringObject, process)?;
+        // fields that aren't set on the object (like ClassVars) are skipped
+        let value = match get_attribute::<I, P>(process, version, addr, &name)? {
+            Some(value) => value,
+            None => continue,
+        };
+        if !limits.formats_items() {
+            items.truncate();
+            break;
+        }
+        let item_limits = match items.next() {
+            Some(item_limits) => item_limits,
+            None => break,
+        };
+        let value = format_variable::<I, P>(process, version, value, &item_limits)?;
+        if !items.push(format!("{}={}", name, value)) {
+            break;
+        }
+    }
+    Ok(format!("{}({})", type_name, items.join()))
+}
+
+/// The name of the type of a python object
+fn object_type_name<I: InterpreterState, P: ProcessMemory>(
+    process: &P,
+    addr: usize,
+) -> Result<String, Error> {
+    let object: I::Object = process.copy_struct(addr)?;
+    copy_type_name(&process.copy_pointer(object.ob_type())?, process)
+}
+
+/// Copies the name of a python type (truncating to 128 bytes if longer)
+fn copy_type_name<T: TypeObject, P: ProcessMemory>(
+    value_type: &T,
+    process: &P,
+) -> Result<String, Error> {
+    let max_type_len = 128;
+    let name = process.copy(value_type.name() as usize, max_type_len)?;
+    let length = name.iter().position(|&x| x == 0).unwrap_or(max_type_len);
+    Ok(std::str::from_utf8(&name[..length])?.to_owned())
+}
+
+/// The state of a python lock object
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PythonLock {
+    /// The type of the lock, '_thread.lock' or '_thread.RLock'
+    pub kind: String,
+    pub locked: bool,
+    /// The python thread id of the thread holding the lock. Only RLocks keep track of this
+    pub owner: Option<u64>,
+}
+
+// the layouts of lockobject and rlockobject from Modules/_threadmodule.c, which haven't changed
+// in any of the python 3 versions we support
+#[repr(C)]
+struct LockObject {
+    ob_refcnt: isize,
+    ob_type: usize,
+    lock_lock: usize,
+    in_weakreflist: usize,
+    locked: std::os::raw::c_char,
+}
+
+#[repr(C)]
+struct RLockObject {
+    ob_refcnt: isize,
+    ob_type: usize,
+    rlock_lock: usize,
+    rlock_owner: std::os::raw::c_ulong,
+    rlock_count: std::os::raw::c_ulong,
+    in_weakreflist: usize,
+}
+
+/// Copies a '_thread.lock' or '_thread.RLock' object from the target process, returning None
+/// if the object at this address is any other type
+pub fn copy_lock<I, P>(process: &P, addr: usize) -> Result<Option<PythonLock>, Error>
+where
+    I: InterpreterState,
+    P: ProcessMemory,
+{
+    let value: I::Object = process.copy_struct(addr)?;
+    let value_type = process.copy_pointer(value.ob_type())?;
+    let kind = copy_type_name(&value_type, process)?;
+    let lock = match kind.as_str() {
+        "_thread.lock" => {
+            let lock: LockObject = process.copy_struct(addr)?;
+            PythonLock {
+                kind,
+                locked: lock.locked != 0,
+                owner: None,
+            }
+        }
+        "_thread.RLock" => {
+            let lock: RLockObject = process.copy_struct(addr)?;
+            let locked = lock.rlock_count > 0;
+            PythonLock {
+                kind,
+                locked,
+                owner: Some(lock.rlock_owner as u64).filter(|_| locked),
+            }
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(lock))
+}
+
 #[cfg(test)]
 pub mod tests {
     // the idea here is to create various cpython interpretator structs locally
     // and then test out that the above code handles appropriately
     use super::*;
     use crate::python_bindings::v3_7_0::{
-        PyASCIIObject, PyBytesObject, PyUnicodeObject, PyVarObject,
+        PyASCIIObject, PyBytesObject, PyTypeObject, PyUnicodeObject, PyVarObject,
     };
     use remoteprocess::LocalProcess;
     use std::ptr::copy_nonoverlapping;
@@ -470,7 +1238,7 @@ pub mod tests {
         };
         unsafe {
             let ptr = &mut ret as *mut AllocatedPyASCIIObject as *mut u8;
-            let dst = ptr.offset(std::mem::size_of::<PyASCIIObject>() as isize);
+            let dst = ptr.add(std::mem::size_of::<PyASCIIObject>());
             copy_nonoverlapping(bytes.as_ptr(), dst, bytes.len());
         }
         ret
@@ -486,6 +1254,82 @@ pub mod tests {
         assert_eq!(copied, original);
     }
 
+    #[test]
+    fn test_format_ndarray() {
+        let float64 = PyTypeObject {
+            tp_name: c"numpy.float64".as_ptr(),
+            ..Default::default()
+        };
+        let descr = ArrayDescr {
+            ob_refcnt: 1,
+            ob_type: 0,
+            typeobj: &float64 as *const PyTypeObject as usize,
+        };
+        let dimensions: [isize; 2] = [3, 4];
+        let mut array = ArrayObject {
+            ob_refcnt: 1,
+            ob_type: 0,
+            data: 0,
+            nd: 2,
+            dimensions: dimensions.as_ptr() as usize,
+            strides: 0,
+            base: 0,
+            descr: &descr as *const ArrayDescr as usize,
+        };
+        let addr = &array as *const ArrayObject as usize;
+        assert_eq!(
+            format_ndarray::<PyTypeObject, LocalProcess>(&LocalProcess, addr).unwrap(),
+            "ndarray(shape=(3, 4), dtype=float64)"
+        );
+
+        array.nd = 1;
+        let addr = &array as *const ArrayObject as usize;
+        assert_eq!(
+            format_ndarray::<PyTypeObject, LocalProcess>(&LocalProcess, addr).unwrap(),
+            "ndarray(shape=(3,), dtype=float64)"
+        );
+        assert_eq!(format_shape(&[]), "()");
+    }
+
+    #[test]
+    fn test_format_bytes() {
+        #[repr(C)]
+        struct Bytes {
+            header: BytesObjectHeader,
+            data: [u8; 4],
+        }
+        let bytes = Bytes {
+            header: BytesObjectHeader {
+                ob_refcnt: 1,
+                ob_type: 0,
+                ob_size: 4,
+                ob_shash: -1,
+            },
+            data: [0xde, 0xad, 0xbe, 0xef],
+        };
+        let addr = &bytes as *const Bytes as usize;
+        let mut limits = FormatLimits::full();
+        assert_eq!(
+            format_bytes(&LocalProcess, addr, &limits).unwrap(),
+            "<4 bytes: deadbeef>"
+        );
+
+        limits.max_str_len = Some(2);
+        assert_eq!(
+            format_bytes(&LocalProcess, addr, &limits).unwrap(),
+            "<4 bytes: dead...>"
+        );
+    }
+
+    #[test]
+    #[cfg(target_pointer_width = "64")]
+    fn test_member_def_layout() {
+        assert_eq!(std::mem::size_of::<MemberDef>(), 40);
+        assert_eq!(std::mem::offset_of!(MemberDef, offset), 16);
+        assert_eq!(std::mem::offset_of!(ArrayObject, descr), 56);
+        assert_eq!(std::mem::offset_of!(SetObject, table), 40);
+    }
+
     #[test]
     fn test_copy_bytes() {
         let original = [10_u8, 20, 30, 40, 50, 70, 80];
@@ -11,25 +11,43 @@ This means we can't dereference them directly.
 
 // these bindings are automatically generated by rust bindgen
 // using the generate_bindings.py script
-use crate::python_bindings::{
-    v2_7_15, v3_10_0, v3_11_0, v3_3_7, v3_5_5, v3_6_6, v3_7_0, v3_8_0, v3_9_5,
-};
+#[cfg(feature = "python2")]
+use crate::python_bindings::v2_7_15;
+use crate::python_bindings::{v3_10_0, v3_11_0, v3_3_7, v3_5_5, v3_6_6, v3_7_0, v3_8_0, v3_9_5};
+use crate::struct_offsets::{remote_offset, StructLayout};
 
-pub trait InterpreterState {
+pub trait InterpreterState: StructLayout {
     type ThreadState: ThreadState;
     type Object: Object;
     type StringObject: StringObject;
     type ListObject: ListObject;
     type TupleObject: TupleObject;
     fn head(&self) -> *mut Self::ThreadState;
+    // the offset of the head() pointer in the struct, so that it can be read without copying the
+    // whole interpreter
+    fn head_offset() -> usize;
     fn modules(&self) -> *mut Self::Object;
+    // the next interpreter in the runtime's list of interpreters, which subinterpreters are added
+    // to the front of
+    fn next(&self) -> *mut Self;
+    // the id of the interpreter, where the main interpreter is 0 (python 3.7+)
+    fn id(&self) -> Option<i64>;
+    // whether the garbage collector is running, for versions where this is stored on the
+    // interpreter (python 3.9+)
+    fn gc_collecting(&self) -> Option<bool>;
+    // the count of each generation of the garbage collector, like gc.get_count() returns (also
+    // only for python 3.9+)
+    fn gc_counts(&self) -> Option<[i64; 3]>;
 }
 
-pub trait ThreadState {
+pub trait ThreadState: StructLayout {
     type FrameObject: FrameObject;
     type InterpreterState: InterpreterState;
+    type TypeObject: TypeObject;
 
     fn interp(&self) -> *mut Self::InterpreterState;
+    // the offset of the interp() pointer in the struct
+    fn interp_offset() -> usize;
 
     // starting in python 3.11, there is an extra level of indirection
     // in getting the frame. this returns the address
@@ -39,9 +57,12 @@ pub trait ThreadState {
     fn thread_id(&self) -> u64;
     fn native_thread_id(&self) -> Option<u64>;
     fn next(&self) -> *mut Self;
+
+    // the type of the exception currently being raised on this thread (or null)
+    fn curexc_type(&self) -> *mut Self::TypeObject;
 }
 
-pub trait FrameObject {
+pub trait FrameObject: StructLayout {
     type CodeObject: CodeObject;
 
     fn code(&self) -> *mut Self::CodeObject;
@@ -50,17 +71,21 @@ pub trait FrameObject {
     fn is_entry(&self) -> bool;
 }
 
-pub trait CodeObject {
+pub trait CodeObject: StructLayout {
     type StringObject: StringObject;
     type BytesObject: BytesObject;
     type TupleObject: TupleObject;
 
     fn name(&self) -> *mut Self::StringObject;
+    // the qualified name of the function (like 'Class.method'), which is only on the code object
+    // from python 3.11
+    fn qualname(&self) -> Option<*mut Self::StringObject>;
     fn filename(&self) -> *mut Self::StringObject;
     fn line_table(&self) -> *mut Self::BytesObject;
     fn first_lineno(&self) -> i32;
     fn nlocals(&self) -> i32;
     fn argcount(&self) -> i32;
+    fn flags(&self) -> i32;
     fn varnames(&self) -> *mut Self::TupleObject;
 
     fn get_line_number(&self, lasti: i32, table: &[u8]) -> i32;
@@ -98,6 +123,12 @@ pub trait TypeObject {
     fn name(&self) -> *const ::std::os::raw::c_char;
     fn dictoffset(&self) -> isize;
     fn flags(&self) -> usize;
+    /// The address of the PyMemberDef array describing the slots (and other members) of the type
+    fn members(&self) -> usize;
+    /// The address of the type this one inherits from
+    fn base(&self) -> usize;
+    /// The address of the __dict__ of the type
+    fn dict(&self) -> usize;
 }
 
 fn offset_of<T, M>(object: *const T, member: *const M) -> usize {
@@ -109,6 +140,34 @@ fn offset_of<T, M>(object: *const T, member: *const M) -> usize {
 /// String handling changes substantially between python versions, and is handled separately.
 macro_rules! PythonCommonImpl {
     ($py: ident, $stringobject: ident) => {
+        PythonCommonImpl!($py, $stringobject, false);
+    };
+    (@gc_collecting $interp: ident, true) => {
+        Some($interp.gc.collecting != 0)
+    };
+    (@gc_collecting $interp: ident, false) => {
+        None
+    };
+    (@gc_counts $interp: ident, true) => {
+        Some([
+            $interp.gc.generations[0].count as i64,
+            $interp.gc.generations[1].count as i64,
+            $interp.gc.generations[2].count as i64,
+        ])
+    };
+    (@gc_counts $interp: ident, false) => {
+        None
+    };
+    (@id $interp: ident, true) => {
+        Some($interp.id)
+    };
+    (@id $interp: ident, false) => {
+        None
+    };
+    ($py: ident, $stringobject: ident, $has_gc: tt) => {
+        PythonCommonImpl!($py, $stringobject, $has_gc, $has_gc);
+    };
+    ($py: ident, $stringobject: ident, $has_gc: tt, $has_id: tt) => {
         impl InterpreterState for $py::PyInterpreterState {
             type ThreadState = $py::PyThreadState;
             type Object = $py::PyObject;
@@ -118,14 +177,30 @@ macro_rules! PythonCommonImpl {
             fn head(&self) -> *mut Self::ThreadState {
                 self.tstate_head
             }
+            fn head_offset() -> usize {
+                std::mem::offset_of!($py::PyInterpreterState, tstate_head)
+            }
             fn modules(&self) -> *mut Self::Object {
                 self.modules
             }
+            fn next(&self) -> *mut Self {
+                self.next
+            }
+            fn id(&self) -> Option<i64> {
+                PythonCommonImpl!(@id self, $has_id)
+            }
+            fn gc_collecting(&self) -> Option<bool> {
+                PythonCommonImpl!(@gc_collecting self, $has_gc)
+            }
+            fn gc_counts(&self) -> Option<[i64; 3]> {
+                PythonCommonImpl!(@gc_counts self, $has_gc)
+            }
         }
 
         impl ThreadState for $py::PyThreadState {
             type FrameObject = $py::PyFrameObject;
             type InterpreterState = $py::PyInterpreterState;
+            type TypeObject = $py::PyTypeObject;
             fn frame_address(&self) -> Option<usize> {
                 None
             }
@@ -144,6 +219,12 @@ macro_rules! PythonCommonImpl {
             fn interp(&self) -> *mut Self::InterpreterState {
                 self.interp
             }
+            fn interp_offset() -> usize {
+                std::mem::offset_of!($py::PyThreadState, interp)
+            }
+            fn curexc_type(&self) -> *mut Self::TypeObject {
+                self.curexc_type as *mut Self::TypeObject
+            }
         }
 
         impl FrameObject for $py::PyFrameObject {
@@ -180,6 +261,15 @@ macro_rules! PythonCommonImpl {
             fn flags(&self) -> usize {
                 self.tp_flags as usize
             }
+            fn members(&self) -> usize {
+                self.tp_members as usize
+            }
+            fn base(&self) -> usize {
+                self.tp_base as usize
+            }
+            fn dict(&self) -> usize {
+                self.tp_dict as usize
+            }
         }
     };
 }
@@ -195,6 +285,9 @@ macro_rules! PythonCodeObjectImpl {
             fn name(&self) -> *mut Self::StringObject {
                 self.co_name as *mut Self::StringObject
             }
+            fn qualname(&self) -> Option<*mut Self::StringObject> {
+                None
+            }
             fn filename(&self) -> *mut Self::StringObject {
                 self.co_filename as *mut Self::StringObject
             }
@@ -210,6 +303,9 @@ macro_rules! PythonCodeObjectImpl {
             fn argcount(&self) -> i32 {
                 self.co_argcount
             }
+            fn flags(&self) -> i32 {
+                self.co_flags
+            }
             fn varnames(&self) -> *mut Self::TupleObject {
                 self.co_varnames as *mut Self::TupleObject
             }
@@ -315,14 +411,34 @@ impl InterpreterState for v3_11_0::PyInterpreterState {
     fn head(&self) -> *mut Self::ThreadState {
         self.threads.head
     }
+    fn head_offset() -> usize {
+        std::mem::offset_of!(v3_11_0::PyInterpreterState, threads.head)
+    }
     fn modules(&self) -> *mut Self::Object {
         self.modules
     }
+    fn next(&self) -> *mut Self {
+        self.next
+    }
+    fn id(&self) -> Option<i64> {
+        Some(self.id)
+    }
+    fn gc_collecting(&self) -> Option<bool> {
+        Some(self.gc.collecting != 0)
+    }
+    fn gc_counts(&self) -> Option<[i64; 3]> {
+        Some([
+            self.gc.generations[0].count as i64,
+            self.gc.generations[1].count as i64,
+            self.gc.generations[2].count as i64,
+        ])
+    }
 }
 
 impl ThreadState for v3_11_0::PyThreadState {
     type FrameObject = v3_11_0::_PyInterpreterFrame;
     type InterpreterState = v3_11_0::PyInterpreterState;
+    type TypeObject = v3_11_0::PyTypeObject;
     fn frame_address(&self) -> Option<usize> {
         // There must be a way to get the offset here without actually creating the object
         let cframe: v3_11_0::_PyCFrame = Default::default();
@@ -344,6 +460,12 @@ impl ThreadState for v3_11_0::PyThreadState {
     fn interp(&self) -> *mut Self::InterpreterState {
         self.interp
     }
+    fn interp_offset() -> usize {
+        std::mem::offset_of!(v3_11_0::PyThreadState, interp)
+    }
+    fn curexc_type(&self) -> *mut Self::TypeObject {
+        self.curexc_type as *mut Self::TypeObject
+    }
 }
 
 impl FrameObject for v3_11_0::_PyInterpreterFrame {
@@ -383,6 +505,15 @@ impl TypeObject for v3_11_0::PyTypeObject {
     fn flags(&self) -> usize {
         self.tp_flags as usize
     }
+    fn members(&self) -> usize {
+        self.tp_members as usize
+    }
+    fn base(&self) -> usize {
+        self.tp_base as usize
+    }
+    fn dict(&self) -> usize {
+        self.tp_dict as usize
+    }
 }
 
 fn read_varint(index: &mut usize, table: &[u8]) -> usize {
@@ -418,6 +549,9 @@ impl CodeObject for v3_11_0::PyCodeObject {
     fn name(&self) -> *mut Self::StringObject {
         self.co_name as *mut Self::StringObject
     }
+    fn qualname(&self) -> Option<*mut Self::StringObject> {
+        Some(self.co_qualname as *mut Self::StringObject)
+    }
     fn filename(&self) -> *mut Self::StringObject {
         self.co_filename as *mut Self::StringObject
     }
@@ -433,6 +567,9 @@ impl CodeObject for v3_11_0::PyCodeObject {
     fn argcount(&self) -> i32 {
         self.co_argcount
     }
+    fn flags(&self) -> i32 {
+        self.co_flags
+    }
     fn varnames(&self) -> *mut Self::TupleObject {
         self.co_localsplusnames as *mut Self::TupleObject
     }
@@ -440,7 +577,10 @@ impl CodeObject for v3_11_0::PyCodeObject {
     fn get_line_number(&self, lasti: i32, table: &[u8]) -> i32 {
         // unpack compressed table format from python 3.11
         // https://github.com/python/cpython/pull/91666/files
-        let lasti = lasti - offset_of(self, &self.co_code_adaptive) as i32;
+        // lasti is from the start of the code object in the process, so needs the offset of
+        // co_code_adaptive there rather than in the bindings
+        let code_offset = remote_offset::<Self>(offset_of(self, &self.co_code_adaptive));
+        let lasti = lasti - code_offset as i32;
         let mut line_number: i32 = self.first_lineno();
         let mut bytecode_address: i32 = 0;
 
@@ -485,7 +625,7 @@ impl CodeObject for v3_11_0::PyCodeObject {
 
 // Python 3.10
 Python3Impl!(v3_10_0);
-PythonCommonImpl!(v3_10_0, PyUnicodeObject);
+PythonCommonImpl!(v3_10_0, PyUnicodeObject, true);
 
 impl CodeObject for v3_10_0::PyCodeObject {
     type BytesObject = v3_10_0::PyBytesObject;
@@ -495,6 +635,9 @@ impl CodeObject for v3_10_0::PyCodeObject {
     fn name(&self) -> *mut Self::StringObject {
         self.co_name as *mut Self::StringObject
     }
+    fn qualname(&self) -> Option<*mut Self::StringObject> {
+        None
+    }
     fn filename(&self) -> *mut Self::StringObject {
         self.co_filename as *mut Self::StringObject
     }
@@ -510,6 +653,9 @@ impl CodeObject for v3_10_0::PyCodeObject {
     fn argcount(&self) -> i32 {
         self.co_argcount
     }
+    fn flags(&self) -> i32 {
+        self.co_flags
+    }
     fn varnames(&self) -> *mut Self::TupleObject {
         self.co_varnames as *mut Self::TupleObject
     }
@@ -528,7 +674,7 @@ impl CodeObject for v3_10_0::PyCodeObject {
         let mut bytecode_address: i32 = 0;
         while (i + 1) < size {
             let delta: u8 = table[i];
-            let line_delta: i8 = unsafe { std::mem::transmute(table[i + 1]) };
+            let line_delta = table[i + 1] as i8;
             i += 2;
 
             if line_delta == -128 {
@@ -547,17 +693,17 @@ impl CodeObject for v3_10_0::PyCodeObject {
 }
 
 // Python 3.9
-PythonCommonImpl!(v3_9_5, PyUnicodeObject);
+PythonCommonImpl!(v3_9_5, PyUnicodeObject, true);
 PythonCodeObjectImpl!(v3_9_5, PyBytesObject, PyUnicodeObject);
 Python3Impl!(v3_9_5);
 
 // Python 3.8
-PythonCommonImpl!(v3_8_0, PyUnicodeObject);
+PythonCommonImpl!(v3_8_0, PyUnicodeObject, false, true);
 PythonCodeObjectImpl!(v3_8_0, PyBytesObject, PyUnicodeObject);
 Python3Impl!(v3_8_0);
 
 // Python 3.7
-PythonCommonImpl!(v3_7_0, PyUnicodeObject);
+PythonCommonImpl!(v3_7_0, PyUnicodeObject, false, true);
 PythonCodeObjectImpl!(v3_7_0, PyBytesObject, PyUnicodeObject);
 Python3Impl!(v3_7_0);
 
@@ -577,8 +723,11 @@ PythonCodeObjectImpl!(v3_3_7, PyBytesObject, PyUnicodeObject);
 Python3Impl!(v3_3_7);
 
 // Python 2.7
+#[cfg(feature = "python2")]
 PythonCommonImpl!(v2_7_15, PyStringObject);
+#[cfg(feature = "python2")]
 PythonCodeObjectImpl!(v2_7_15, PyStringObject, PyStringObject);
+#[cfg(feature = "python2")]
 impl BytesObject for v2_7_15::PyStringObject {
     fn size(&self) -> usize {
         self.ob_size as usize
@@ -588,6 +737,7 @@ impl BytesObject for v2_7_15::PyStringObject {
     }
 }
 
+#[cfg(feature = "python2")]
 impl StringObject for v2_7_15::PyStringObject {
     fn ascii(&self) -> bool {
         true
@@ -603,6 +753,7 @@ impl StringObject for v2_7_15::PyStringObject {
     }
 }
 
+#[cfg(feature = "python2")]
 impl ListObject for v2_7_15::PyListObject {
     type Object = v2_7_15::PyObject;
     fn size(&self) -> usize {
@@ -613,6 +764,7 @@ impl ListObject for v2_7_15::PyListObject {
     }
 }
 
+#[cfg(feature = "python2")]
 impl TupleObject for v2_7_15::PyTupleObject {
     fn size(&self) -> usize {
         self.ob_size as usize
@@ -641,4 +793,48 @@ mod tests {
         ];
         assert_eq!(code.get_line_number(214, &table), 5);
     }
+
+    // the offsets of the fields py-spy reads from python 2.7, from a 64-bit build of cpython 2.7
+    #[cfg(all(feature = "python2", target_pointer_width = "64"))]
+    #[test]
+    fn test_py2_7_layout() {
+        use crate::python_bindings::v2_7_15::{
+            PyCodeObject, PyFrameObject, PyInterpreterState, PyStringObject, PyThreadState,
+        };
+        use std::mem::offset_of;
+
+        assert_eq!(offset_of!(PyInterpreterState, tstate_head), 8);
+        assert_eq!(offset_of!(PyInterpreterState, modules), 16);
+        assert_eq!(offset_of!(PyThreadState, frame), 16);
+        assert_eq!(offset_of!(PyThreadState, thread_id), 144);
+        assert_eq!(offset_of!(PyFrameObject, f_back), 24);
+        assert_eq!(offset_of!(PyFrameObject, f_code), 32);
+        assert_eq!(offset_of!(PyFrameObject, f_lasti), 120);
+        assert_eq!(offset_of!(PyFrameObject, f_lineno), 124);
+        assert_eq!(offset_of!(PyCodeObject, co_filename), 80);
+        assert_eq!(offset_of!(PyCodeObject, co_name), 88);
+        assert_eq!(offset_of!(PyCodeObject, co_firstlineno), 96);
+        assert_eq!(offset_of!(PyCodeObject, co_lnotab), 104);
+        assert_eq!(offset_of!(PyStringObject, ob_sval), 36);
+
+        let string = PyStringObject::default();
+        assert_eq!(StringObject::address(&string, 0x1000), 0x1000 + 36);
+    }
+
+    #[cfg(feature = "python2")]
+    #[test]
+    fn test_py2_7_line_numbers() {
+        use crate::python_bindings::v2_7_15::PyCodeObject;
+        let code = PyCodeObject {
+            co_firstlineno: 10,
+            ..Default::default()
+        };
+
+        // pairs of (bytecode offset, line) increments
+        let table = [6_u8, 1, 8, 2, 4, 1];
+        assert_eq!(code.get_line_number(0, &table), 10);
+        assert_eq!(code.get_line_number(10, &table), 11);
+        assert_eq!(code.get_line_number(14, &table), 13);
+        assert_eq!(code.get_line_number(40, &table), 14);
+    }
 }
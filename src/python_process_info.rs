@@ -14,11 +14,15 @@ use remoteprocess::{Pid, ProcessMemory};
 
 use crate::binary_parser::{parse_binary, BinaryInfo};
 use crate::config::Config;
+use crate::memory_scan::MemorySnapshot;
+#[cfg(feature = "python2")]
+use crate::python_bindings::v2_7_15;
 use crate::python_bindings::{
-    pyruntime, v2_7_15, v3_10_0, v3_11_0, v3_3_7, v3_5_5, v3_6_6, v3_7_0, v3_8_0, v3_9_5,
+    pyruntime, v3_10_0, v3_11_0, v3_3_7, v3_5_5, v3_6_6, v3_7_0, v3_8_0, v3_9_5,
 };
 use crate::python_interpreters::{InterpreterState, ThreadState};
 use crate::stack_trace::get_stack_traces;
+use crate::struct_offsets::{copy_pointer, copy_struct, remote_offset};
 use crate::version::Version;
 
 /// Holds information about the python process: memory map layout, parsed binary info
@@ -223,6 +227,17 @@ impl PythonProcessInfo {
             libpython_binary
         };
 
+        // frozen applications (PyInstaller, PyOxidizer, Nuitka etc) can load python from a library
+        // that isn't named like libpython. If python isn't linked into the executable either,
+        // look for it in every library that has been loaded
+        #[cfg(not(windows))]
+        let libpython_binary = match libpython_binary {
+            None if !python_binary.as_ref().is_ok_and(has_python_symbols) => {
+                find_embedded_python(process, &maps, &filename)
+            }
+            libpython_binary => libpython_binary,
+        };
+
         // If we have a libpython binary - we can tolerate failures on parsing the main python binary.
         let python_binary = match libpython_binary {
             None => Some(python_binary.context("Failed to parse python binary")?),
@@ -263,6 +278,125 @@ impl PythonProcessInfo {
     }
 }
 
+/// Whether a binary has a python interpreter in it
+fn has_python_symbols(binary: &BinaryInfo) -> bool {
+    ["Py_GetVersion", "_PyRuntime", "interp_head"]
+        .iter()
+        .any(|symbol| binary.symbols.contains_key(*symbol))
+}
+
+/// Looks for a library with a python interpreter in it amongst all the libraries loaded by a
+/// process, for when python isn't in a library named like libpython
+#[cfg(not(windows))]
+fn find_embedded_python(
+    process: &remoteprocess::Process,
+    maps: &[MapRange],
+    executable: &str,
+) -> Option<BinaryInfo> {
+    let mut seen = std::collections::HashSet::new();
+    for map in maps.iter().filter(|map| map.is_exec()) {
+        let pathname = match map.filename() {
+            Some(pathname) => pathname,
+            None => continue,
+        };
+        if pathname.to_str() == Some(executable) || !seen.insert(pathname) {
+            continue;
+        }
+
+        // on linux the process could be running in docker, access the filename through procfs
+        #[cfg(target_os = "linux")]
+        let filename =
+            std::path::PathBuf::from(format!("/proc/{}/root{}", process.pid, pathname.display()));
+        #[cfg(not(target_os = "linux"))]
+        let filename = pathname.to_path_buf();
+
+        if let Ok(binary) = parse_binary(&filename, map.start() as u64, map.size() as u64) {
+            if has_python_symbols(&binary) {
+                info!("Found python embedded in {}", pathname.display());
+                return Some(binary);
+            }
+        }
+    }
+    None
+}
+
+/// Describes what was found in a process that no version of python could be found in, to make
+/// it clearer why it can't be profiled
+pub fn describe_missing_python(
+    process: &remoteprocess::Process,
+    python_info: &PythonProcessInfo,
+) -> String {
+    let executable = process.exe().unwrap_or_default();
+    let mut description = match (&python_info.libpython_binary, &python_info.python_binary) {
+        (Some(libpython), _) => {
+            let root = format!("/proc/{}/root", process.pid);
+            let filename = libpython.filename.display().to_string();
+            format!(
+                "found a python interpreter in '{}', but couldn't read its version",
+                filename.strip_prefix(&root).unwrap_or(&filename)
+            )
+        }
+        (None, Some(binary)) if has_python_symbols(binary) => format!(
+            "found a python interpreter linked into '{}', but couldn't read its version",
+            executable
+        ),
+        _ => format!(
+            "'{}' doesn't have python linked in, and hasn't loaded a python library",
+            executable
+        ),
+    };
+
+    // PyInstaller unpacks python into a _MEIxxxxxx directory
+    let pyinstaller = executable.contains("_MEI")
+        || get_process_maps(process.pid).is_ok_and(|maps| {
+            maps.iter().any(|map| {
+                map.filename()
+                    .is_some_and(|pathname| pathname.to_string_lossy().contains("_MEI"))
+            })
+        });
+    if pyinstaller {
+        description += " (this looks like a PyInstaller application)";
+    }
+
+    // wrappers like PyInstaller's onefile bootloader or shell scripts run python in a child process
+    if let Some(child) = python_child(process) {
+        description += &format!(
+            ". Its child process {} is running python: profile that instead, or use --subprocesses",
+            child
+        );
+    }
+    description
+}
+
+/// Returns a child process of a process that looks like it's running python
+fn python_child(process: &remoteprocess::Process) -> Option<Pid> {
+    let children = process.child_processes().ok()?;
+    children
+        .into_iter()
+        .filter(|(_, parent)| *parent == process.pid)
+        .map(|(child, _)| child)
+        .find(|child| is_python_process(*child))
+}
+
+/// Whether a process looks like it's running python, from the name of its executable or the
+/// libraries it has loaded
+pub fn is_python_process(pid: Pid) -> bool {
+    let python_exe = remoteprocess::Process::new(pid)
+        .and_then(|process| process.exe())
+        .is_ok_and(|exe| {
+            Path::new(&exe)
+                .file_name()
+                .is_some_and(|name| name.to_string_lossy().starts_with("python"))
+        });
+    python_exe
+        || get_process_maps(pid).is_ok_and(|maps| {
+            maps.iter().any(|map| {
+                map.filename()
+                    .is_some_and(|pathname| is_python_lib(&pathname.to_string_lossy()))
+            })
+        })
+}
+
 /// Returns the version of python running in the process.
 pub fn get_python_version<P>(python_info: &PythonProcessInfo, process: &P) -> Result<Version, Error>
 where
@@ -330,6 +464,27 @@ where
     ))
 }
 
+/// Returns the address of the pointer to the head of the runtime's list of interpreters, if it
+/// can be found from the symbols. New (sub)interpreters are added to the front of this list, so
+/// it has to be read again to find the interpreters created after attaching
+pub fn get_interpreter_head_address(
+    python_info: &PythonProcessInfo,
+    version: &Version,
+) -> Option<usize> {
+    match version {
+        Version {
+            major: 3,
+            minor: 7..=11,
+            ..
+        } => python_info
+            .get_symbol("_PyRuntime")
+            .map(|&addr| addr as usize + pyruntime::get_interp_head_offset(version)),
+        _ => python_info
+            .get_symbol("interp_head")
+            .map(|&addr| addr as usize),
+    }
+}
+
 pub fn get_interpreter_address<P>(
     python_info: &PythonProcessInfo,
     process: &P,
@@ -389,17 +544,101 @@ where
         None
     };
     // Before giving up, try again if there is a libpython.so
-    if let Some(ref lpb) = python_info.libpython_binary {
+    let err = if let Some(ref lpb) = python_info.libpython_binary {
         info!("Failed to get interpreter from binary BSS, scanning libpython BSS");
         match get_interpreter_address_from_binary(lpb, &*python_info.maps, process, version) {
-            Ok(addr) => Ok(addr),
+            Ok(addr) => return Ok(addr),
             lib_err => err.unwrap_or(lib_err),
         }
     } else {
-        err.expect("Both python and libpython are invalid.")
+        err.unwrap_or_else(|| Err(format_err!("Failed to find python binary")))
+    };
+
+    // As a last resort (when the symbols have been stripped from a program embedding python),
+    // look through all of the writable memory for something shaped like the interpreter
+    info!("Failed to get interpreter from BSS, scanning writable memory");
+    match get_interpreter_address_from_memory(&*python_info.maps, process, version) {
+        Ok(addr) => Ok(addr),
+        Err(e) => {
+            info!("Failed to get interpreter from writable memory: {}", e);
+            err
+        }
     }
 }
 
+fn get_interpreter_address_from_memory<P>(
+    maps: &dyn ContainsAddr,
+    process: &P,
+    version: &Version,
+) -> Result<usize, Error>
+where
+    P: ProcessMemory,
+{
+    fn offsets<I: InterpreterState>() -> (usize, usize) {
+        (
+            remote_offset::<I>(I::head_offset()),
+            remote_offset::<I::ThreadState>(<I::ThreadState as ThreadState>::interp_offset()),
+        )
+    }
+
+    let (head_offset, interp_offset) = match version {
+        #[cfg(feature = "python2")]
+        Version {
+            major: 2,
+            minor: 3..=7,
+            ..
+        } => offsets::<v2_7_15::_is>(),
+        Version {
+            major: 3, minor: 3, ..
+        } => offsets::<v3_3_7::_is>(),
+        Version {
+            major: 3,
+            minor: 4..=5,
+            ..
+        } => offsets::<v3_5_5::_is>(),
+        Version {
+            major: 3, minor: 6, ..
+        } => offsets::<v3_6_6::_is>(),
+        Version {
+            major: 3, minor: 7, ..
+        } => offsets::<v3_7_0::_is>(),
+        Version {
+            major: 3,
+            minor: 8,
+            patch: 0,
+            ..
+        } => match version.release_flags.as_ref() {
+            "a1" | "a2" | "a3" => offsets::<v3_7_0::_is>(),
+            _ => offsets::<v3_8_0::_is>(),
+        },
+        Version {
+            major: 3, minor: 8, ..
+        } => offsets::<v3_8_0::_is>(),
+        Version {
+            major: 3, minor: 9, ..
+        } => offsets::<v3_9_5::_is>(),
+        Version {
+            major: 3,
+            minor: 10,
+            ..
+        } => offsets::<v3_10_0::_is>(),
+        Version {
+            major: 3,
+            minor: 11,
+            ..
+        } => offsets::<v3_11_0::_is>(),
+        _ => return Err(format_err!("Unsupported version of Python: {}", version)),
+    };
+
+    let snapshot = MemorySnapshot::new(process, maps.writable_regions());
+    let candidates = snapshot.interpreter_candidates(head_offset, interp_offset);
+    info!(
+        "Found {} possible interpreters in writable memory",
+        candidates.len()
+    );
+    check_interpreter_addresses(&candidates, maps, process, version)
+}
+
 fn get_interpreter_address_from_binary<P>(
     binary: &BinaryInfo,
     maps: &dyn ContainsAddr,
@@ -441,7 +680,7 @@ where
             if maps.contains_addr(addr) {
                 // this address points to valid memory. try loading it up as a PyInterpreterState
                 // to further check
-                let interp: I = match process.copy_struct(addr) {
+                let interp: I = match copy_struct(process, addr) {
                     Ok(interp) => interp,
                     Err(_) => continue,
                 };
@@ -452,7 +691,7 @@ where
                 if maps.contains_addr(threads as usize) {
                     // If the threadstate points back to the interpreter like we expect, then
                     // this is almost certainly the address of the intrepreter
-                    let thread = match process.copy_pointer(threads) {
+                    let thread = match copy_pointer(process, threads) {
                         Ok(thread) => thread,
                         Err(_) => continue,
                     };
@@ -473,6 +712,7 @@ where
 
     // different versions have different layouts, check as appropriate
     match version {
+        #[cfg(feature = "python2")]
         Version {
             major: 2,
             minor: 3..=7,
@@ -599,6 +839,8 @@ fn error_if_gil(config: &Config, version: &Version, msg: &str) -> Result<(), Err
 
 pub trait ContainsAddr {
     fn contains_addr(&self, addr: usize) -> bool;
+    // the (address, size) of each region of memory that can be read and written to
+    fn writable_regions(&self) -> Vec<(usize, usize)>;
 }
 
 impl ContainsAddr for Vec<MapRange> {
@@ -613,6 +855,15 @@ impl ContainsAddr for Vec<MapRange> {
     fn contains_addr(&self, addr: usize) -> bool {
         proc_maps::maps_contain_addr(addr, self)
     }
+
+    fn writable_regions(&self) -> Vec<(usize, usize)> {
+        self.iter()
+            .filter(|map| map.is_read() && map.is_write())
+            // memory mapped devices (like gpus) can be huge, and won't have the interpreter in them
+            .filter(|map| !map.filename().is_some_and(|f| f.starts_with("/dev/")))
+            .map(|map| (map.start(), map.size()))
+            .collect()
+    }
 }
 
 #[cfg(target_os = "linux")]
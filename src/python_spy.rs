@@ -1,3 +1,4 @@
+use regex::Regex;
 #[cfg(windows)]
 use regex::RegexBuilder;
 use std::collections::HashMap;
@@ -6,33 +7,67 @@ use std::collections::HashSet;
 #[cfg(all(target_os = "linux", unwind))]
 use std::iter::FromIterator;
 use std::path::Path;
+use std::time::{Duration, Instant};
 
 use anyhow::{Context, Error, Result};
 use remoteprocess::{Pid, Process, ProcessMemory, Tid};
 
+use crate::asyncio::{AsyncioTask, SuspendedFrame, TaskReader};
 use crate::config::{Config, LockingStrategy};
+use crate::memory::{Allocation, MemoryCounters, TraceMalloc};
 #[cfg(unwind)]
 use crate::native_stack_trace::NativeStack;
-use crate::python_bindings::{
-    v2_7_15, v3_10_0, v3_11_0, v3_3_7, v3_5_5, v3_6_6, v3_7_0, v3_8_0, v3_9_5,
-};
-use crate::python_data_access::format_variable;
+use crate::offcpu::{os_thread_state, wait_frame, wait_reason, OsThreadState};
+#[cfg(feature = "python2")]
+use crate::python_bindings::v2_7_15;
+use crate::python_bindings::{v3_10_0, v3_11_0, v3_3_7, v3_5_5, v3_6_6, v3_7_0, v3_8_0, v3_9_5};
+use crate::python_data_access::{copy_lock, find_key, format_variable, DictIterator, FormatLimits};
 use crate::python_interpreters::{InterpreterState, ThreadState};
 use crate::python_process_info::{
-    get_interpreter_address, get_python_version, get_threadstate_address, PythonProcessInfo,
+    describe_missing_python, get_interpreter_address, get_interpreter_head_address,
+    get_python_version, get_threadstate_address, PythonProcessInfo,
 };
 use crate::python_threading::thread_name_lookup;
-use crate::stack_trace::{get_gil_threadid, get_stack_trace, StackTrace};
+use crate::stack_trace::{
+    gc_frame, get_gil_threadid, get_stack_trace, LockWait, StackTrace, ThreadStatus,
+};
+use crate::struct_offsets::{copy_pointer, copy_struct, load_offsets, set_offsets};
+use crate::thread_roles::ThreadRoles;
+use crate::trio::{find_runner, TrioReader, TrioRun, TrioTask};
+use crate::utils::process_usage;
 use crate::version::Version;
 
+/// The error returned when collecting a sample would pause the process for longer than
+/// --max-pause-us. The sample is abandoned, and the process is resumed straight away
+#[derive(Debug)]
+pub struct PauseBudgetExceeded {
+    pub budget: Duration,
+}
+
+impl std::fmt::Display for PauseBudgetExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Abandoned the sample after pausing the process for {:?}",
+            self.budget
+        )
+    }
+}
+
+impl std::error::Error for PauseBudgetExceeded {}
+
 /// Lets you retrieve stack traces of a running python program
 pub struct PythonSpy {
     pub pid: Pid,
     pub process: Process,
     pub version: Version,
     pub interpreter_address: usize,
+    // where the head of the list of interpreters is stored, to find subinterpreters with
+    interpreter_head_address: Option<usize>,
     pub threadstate_address: usize,
+    #[allow(dead_code)]
     pub python_filename: std::path::PathBuf,
+    #[allow(dead_code)]
     pub version_string: String,
     pub config: Config,
     #[cfg(unwind)]
@@ -40,6 +75,21 @@ pub struct PythonSpy {
     pub short_filenames: HashMap<String, Option<String>>,
     pub python_thread_ids: HashMap<u64, Tid>,
     pub python_thread_names: HashMap<u64, String>,
+    /// Only threads with names matching this are sampled, for --threads-include
+    pub threads_include: Option<Regex>,
+    /// Threads with names matching this aren't sampled, for --threads-exclude
+    pub threads_exclude: Option<Regex>,
+    pub tracemalloc: Option<TraceMalloc>,
+    /// The address of pymalloc's count of allocated arenas, for --memory-counters
+    pub narenas_address: Option<usize>,
+    /// When the memory counters were last read
+    pub memory_counters_read: Option<Instant>,
+    /// How long the process was paused for to take the last sample. None if it wasn't paused
+    pub last_pause: Option<Duration>,
+    /// The index of the field that is the coroutine in each type of asyncio task, for --asyncio
+    asyncio_coroutine_fields: HashMap<usize, usize>,
+    /// The role of each thread, for --thread-roles
+    thread_roles: ThreadRoles,
     #[cfg(target_os = "linux")]
     pub dockerized: bool,
 }
@@ -59,17 +109,69 @@ impl PythonSpy {
         #[cfg(target_os = "freebsd")]
         let _lock = process.lock();
 
-        let version = get_python_version(&python_info, &process)?;
+        let version = match config.force_version.clone() {
+            Some(version) => Ok(version),
+            None => get_python_version(&python_info, &process),
+        };
+        let version = match version {
+            Ok(version) => version,
+            Err(e) => {
+                let description = describe_missing_python(&process, &python_info);
+                return Err(format_err!("{}: {}", e, description));
+            }
+        };
         info!("python version {} detected", version);
 
+        if let Some(filename) = &config.offsets_filename {
+            set_offsets(load_offsets(filename, &version)?)?;
+        }
+        #[cfg(not(feature = "python2"))]
+        if version.major == 2 {
+            return Err(format_err!(
+                "Python {} isn't supported by this build of py-spy, which was built without the 'python2' feature",
+                version
+            ));
+        }
+
         let interpreter_address = get_interpreter_address(&python_info, &process, &version)?;
         info!("Found interpreter at 0x{:016x}", interpreter_address);
+        let interpreter_head_address = get_interpreter_head_address(&python_info, &version);
 
         // lets us figure out which thread has the GIL
         let threadstate_address = get_threadstate_address(&python_info, &version, config)?;
 
         let version_string = format!("python{}.{}", version.major, version.minor);
 
+        if config.asyncio && !(version.major == 3 && (7..=11).contains(&version.minor)) {
+            return Err(format_err!(
+                "--asyncio isn't supported for Python {} (it needs python 3.7 to 3.11)",
+                version
+            ));
+        }
+
+        let tracemalloc = if config.memory {
+            Some(TraceMalloc::new(&python_info, &version)?)
+        } else {
+            None
+        };
+        // this is a static variable, so is only available when python has its full symbols
+        let narenas_address = match config.memory_counters {
+            Some(_) => python_info
+                .get_symbol("narenas_currently_allocated")
+                .map(|addr| *addr as usize),
+            None => None,
+        };
+
+        // the patterns have already been checked when parsing the arguments
+        let threads_include = match config.threads_include.as_ref() {
+            Some(pattern) => Some(Regex::new(pattern)?),
+            None => None,
+        };
+        let threads_exclude = match config.threads_exclude.as_ref() {
+            Some(pattern) => Some(Regex::new(pattern)?),
+            None => None,
+        };
+
         #[cfg(unwind)]
         let native = if config.native {
             Some(NativeStack::new(
@@ -86,6 +188,7 @@ impl PythonSpy {
             process,
             version,
             interpreter_address,
+            interpreter_head_address,
             threadstate_address,
             python_filename: python_info.python_filename,
             version_string,
@@ -97,6 +200,14 @@ impl PythonSpy {
             short_filenames: HashMap::new(),
             python_thread_ids: HashMap::new(),
             python_thread_names: HashMap::new(),
+            threads_include,
+            threads_exclude,
+            tracemalloc,
+            narenas_address,
+            memory_counters_read: None,
+            last_pause: None,
+            asyncio_coroutine_fields: HashMap::new(),
+            thread_roles: ThreadRoles::default(),
         })
     }
 
@@ -111,6 +222,8 @@ impl PythonSpy {
                     // verify that we can load a stack trace before returning success
                     match process.get_stack_traces() {
                         Ok(_) => return Ok(process),
+                        // the stacks could be read, they just took too long
+                        Err(err) if err.is::<PauseBudgetExceeded>() => return Ok(process),
                         Err(err) => err,
                     }
                 }
@@ -131,6 +244,7 @@ impl PythonSpy {
     pub fn get_stack_traces(&mut self) -> Result<Vec<StackTrace>, Error> {
         match self.version {
             // ABI for 2.3/2.4/2.5/2.6/2.7 is compatible for our purpose
+            #[cfg(feature = "python2")]
             Version {
                 major: 2,
                 minor: 3..=7,
@@ -185,10 +299,116 @@ impl PythonSpy {
         }
     }
 
+    /// Reads how much memory the process is using once every --memory-counters interval. Returns
+    /// None when it isn't time to read the counters again yet
+    pub fn poll_memory_counters(&mut self) -> Option<MemoryCounters> {
+        let interval = Duration::from_secs(self.config.memory_counters?);
+        let now = Instant::now();
+        if let Some(last) = self.memory_counters_read {
+            if now < last + interval {
+                return None;
+            }
+        }
+        self.memory_counters_read = Some(now);
+        Some(self.get_memory_counters())
+    }
+
+    /// Reads how much memory the process is using
+    pub fn get_memory_counters(&self) -> MemoryCounters {
+        let gc_counts = match self.version {
+            Version {
+                major: 3, minor: 9, ..
+            } => self._get_gc_counts::<v3_9_5::_is>(),
+            Version {
+                major: 3,
+                minor: 10,
+                ..
+            } => self._get_gc_counts::<v3_10_0::_is>(),
+            Version {
+                major: 3,
+                minor: 11,
+                ..
+            } => self._get_gc_counts::<v3_11_0::_is>(),
+            // older versions keep the gc state outside of the interpreter
+            _ => None,
+        };
+        let arenas = self
+            .narenas_address
+            .and_then(|addr| self.process.copy_struct::<usize>(addr).ok());
+        MemoryCounters {
+            rss: process_usage(self.pid).map(|(_, rss)| rss),
+            arenas: arenas.map(|arenas| arenas as u64),
+            gc_counts: gc_counts.map(|counts| counts.to_vec()),
+        }
+    }
+
+    fn _get_gc_counts<I: InterpreterState>(&self) -> Option<[i64; 3]> {
+        let interp: I = copy_struct(&self.process, self.interpreter_address).ok()?;
+        interp.gc_counts()
+    }
+
+    /// Gets the memory allocations that tracemalloc has traced in the process, grouped by the
+    /// stack they were allocated from. This needs the spy to have been created with --memory
+    pub fn get_allocations(&mut self) -> Result<Vec<Allocation>, Error> {
+        match self.version {
+            Version {
+                major: 3, minor: 9, ..
+            } => self._get_allocations::<v3_9_5::_is>(),
+            Version {
+                major: 3,
+                minor: 10,
+                ..
+            } => self._get_allocations::<v3_10_0::_is>(),
+            Version {
+                major: 3,
+                minor: 11,
+                ..
+            } => self._get_allocations::<v3_11_0::_is>(),
+            _ => Err(format_err!(
+                "Reading allocations isn't supported for Python {}",
+                self.version
+            )),
+        }
+    }
+
+    fn _get_allocations<I: InterpreterState>(&mut self) -> Result<Vec<Allocation>, Error> {
+        let tracemalloc = self
+            .tracemalloc
+            .as_ref()
+            .ok_or_else(|| format_err!("Reading allocations requires --memory"))?;
+
+        let mut allocations = {
+            // the traces are changed on every allocation, so need to be read with the process
+            // suspended to be consistent
+            let _lock = if self.config.blocking == LockingStrategy::Lock {
+                Some(self.process.lock().context("Failed to suspend process")?)
+            } else {
+                None
+            };
+            if !tracemalloc.is_tracing(&self.process)? {
+                return Err(format_err!(
+                    "tracemalloc isn't tracing allocations in process {}. Run python with \
+                     PYTHONTRACEMALLOC=25, or call tracemalloc.start(25) in the program",
+                    self.pid
+                ));
+            }
+            tracemalloc.allocations::<I::StringObject, Process>(&self.process)?
+        };
+
+        for allocation in &mut allocations {
+            for frame in &mut allocation.frames {
+                frame.short_filename = self.shorten_filename(&frame.filename);
+            }
+        }
+        Ok(allocations)
+    }
+
     // implementation of get_stack_traces, where we have a type for the InterpreterState
     fn _get_stack_traces<I: InterpreterState>(&mut self) -> Result<Vec<StackTrace>, Error> {
         // Query the OS to get if each thread in the process is running or not
         let mut thread_activity = HashMap::new();
+        // with --offcpu or --lock-contention, also get what each thread is blocked on
+        let mut os_thread_states = HashMap::new();
         if self.config.gil_only {
             // Don't need to collect thread activity if we're only getting the
             // GIL thread: If we're holding the GIL we're by definition active.
@@ -196,6 +416,11 @@ impl PythonSpy {
             for thread in self.process.threads()?.iter() {
                 let threadid: Tid = thread.id()?;
                 thread_activity.insert(threadid, thread.active()?);
+                if self.config.offcpu || self.config.lock_contention {
+                    if let Ok(state) = os_thread_state(self.process.pid, threadid) {
+                        os_thread_states.insert(threadid, state);
+                    }
+                }
             }
         }
 
@@ -208,6 +433,8 @@ impl PythonSpy {
         } else {
             None
         };
+        let pause_start = _lock.as_ref().map(|_| Instant::now());
+        self.last_pause = None;
 
         // TODO: hoist most of this code out to stack_trace.rs, and
         // then annotate the output of that with things like native stack traces etc
@@ -215,125 +442,509 @@ impl PythonSpy {
         let gil_thread_id =
             get_gil_threadid::<I, Process>(self.threadstate_address, &self.process)?;
 
-        // Get the python interpreter, and loop over all the python threads
-        let interp: I = self
-            .process
-            .copy_struct(self.interpreter_address)
-            .context("Failed to copy PyInterpreterState from process")?;
+        // Get the python interpreters, and loop over all the python threads in each of them.
+        // Stacks are only labelled with the interpreter they came from when there are
+        // subinterpreters
+        let interps = self._get_interpreters::<I>()?;
+        let labelled = interps.len() > 1;
 
         let mut traces = Vec::new();
-        let mut threads = interp.head();
-        while !threads.is_null() {
-            // Get the stack trace of the python thread
-            let thread = self
-                .process
-                .copy_pointer(threads)
-                .context("Failed to copy PyThreadState")?;
-            threads = thread.next();
+        'interpreters: for (interpreter_id, interp) in interps {
+            let interpreter_id = labelled.then_some(interpreter_id);
+            let mut threads = interp.head();
+            while !threads.is_null() {
+                self._check_pause(pause_start)?;
+
+                // Get the stack trace of the python thread
+                let thread =
+                    copy_pointer(&self.process, threads).context("Failed to copy PyThreadState")?;
+                threads = thread.next();
+
+                let python_thread_id = thread.thread_id();
+                let owns_gil = python_thread_id == gil_thread_id;
+
+                if self.config.gil_only && !owns_gil {
+                    continue;
+                }
 
-            let python_thread_id = thread.thread_id();
-            let owns_gil = python_thread_id == gil_thread_id;
+                // threads are filtered by name before their stacks are read, so that skipped threads
+                // don't cost anything to sample
+                let thread_name = self._get_python_thread_name(python_thread_id);
+                if !self._is_thread_sampled(thread_name.as_deref()) {
+                    continue;
+                }
 
-            if self.config.gil_only && !owns_gil {
-                continue;
-            }
+                // finding the locks that threads are waiting on needs the locals of each frame
+                let mut trace = get_stack_trace(
+                    &thread,
+                    &self.process,
+                    self.config.dump_locals > 0 || self.config.lock_contention,
+                    self.config.lineno,
+                    self.config.qualified_names,
+                )?;
+
+                // Try getting the native thread id
+
+                // python 3.11+ has the native thread id directly on the PyThreadState object,
+                // for older versions of python, try using OS specific code to get the native
+                // thread id (doesn't work on freebsd, or on arm/i686 processors on linux)
+                if trace.os_thread_id.is_none() {
+                    let mut os_thread_id = self._get_os_thread_id(python_thread_id, &interp)?;
+
+                    // linux can see issues where pthread_ids get recycled for new OS threads,
+                    // which totally breaks the caching we were doing here. Detect this and retry
+                    if let Some(tid) = os_thread_id {
+                        if !thread_activity.is_empty() && !thread_activity.contains_key(&tid) {
+                            info!("clearing away thread id caches, thread {} has exited", tid);
+                            self.python_thread_ids.clear();
+                            self.python_thread_names.clear();
+                            os_thread_id = self._get_os_thread_id(python_thread_id, &interp)?;
+                        }
+                    }
 
-            let mut trace = get_stack_trace(
-                &thread,
-                &self.process,
-                self.config.dump_locals > 0,
-                self.config.lineno,
-            )?;
-
-            // Try getting the native thread id
-
-            // python 3.11+ has the native thread id directly on the PyThreadState object,
-            // for older versions of python, try using OS specific code to get the native
-            // thread id (doesn't work on freebsd, or on arm/i686 processors on linux)
-            if trace.os_thread_id.is_none() {
-                let mut os_thread_id = self._get_os_thread_id(python_thread_id, &interp)?;
-
-                // linux can see issues where pthread_ids get recycled for new OS threads,
-                // which totally breaks the caching we were doing here. Detect this and retry
-                if let Some(tid) = os_thread_id {
-                    if !thread_activity.is_empty() && !thread_activity.contains_key(&tid) {
-                        info!("clearing away thread id caches, thread {} has exited", tid);
-                        self.python_thread_ids.clear();
-                        self.python_thread_names.clear();
-                        os_thread_id = self._get_os_thread_id(python_thread_id, &interp)?;
+                    trace.os_thread_id = os_thread_id.map(|id| id as u64);
+                }
+
+                // the thread name is looked up again in case the caches were cleared above
+                trace.thread_name = self._get_python_thread_name(python_thread_id);
+                if self.config.thread_roles {
+                    let thread_name = trace.thread_name.take();
+                    trace.thread_name =
+                        self.thread_roles
+                            .label(python_thread_id, thread_name, &trace.frames);
+                }
+                trace.owns_gil = owns_gil;
+                trace.pid = self.process.pid;
+                trace.interpreter_id = interpreter_id;
+
+                // the garbage collector runs on whichever thread holds the gil
+                trace.in_gc = owns_gil && interp.gc_collecting().unwrap_or(false);
+
+                // Figure out if the thread is sleeping from the OS if possible
+                trace.active = true;
+                if let Some(id) = trace.os_thread_id {
+                    let id = id as Tid;
+                    if let Some(active) = thread_activity.get(&id as _) {
+                        trace.active = *active;
                     }
                 }
 
-                trace.os_thread_id = os_thread_id.map(|id| id as u64);
-            }
+                // fallback to using a heuristic if we think the thread is still active
+                // Note that on linux the OS thread activity can only be gotten on x86_64
+                // processors and even then seems to be wrong occasionally in thinking 'select'
+                // calls are active (which seems related to the thread locking code,
+                // this problem doesn't seem to happen with the --nonblocking option)
+                // Note: this should be done before the native merging for correct results
+                if trace.active {
+                    trace.active = !self._heuristic_is_thread_idle(&trace);
+                }
 
-            trace.thread_name = self._get_python_thread_name(python_thread_id);
-            trace.owns_gil = owns_gil;
-            trace.pid = self.process.pid;
+                trace.status = Some(if !trace.active {
+                    if self._heuristic_is_blocking_io(&trace) {
+                        ThreadStatus::BlockingIo
+                    } else {
+                        ThreadStatus::Sleeping
+                    }
+                } else if owns_gil {
+                    ThreadStatus::Running
+                } else {
+                    ThreadStatus::WaitingForGil
+                });
+
+                // off-cpu profiles only include threads that weren't running, tagged with why
+                if self.config.offcpu {
+                    let os_state = trace
+                        .os_thread_id
+                        .and_then(|id| os_thread_states.get(&(id as Tid)));
+                    let reason = match os_state {
+                        Some(os_state) => {
+                            wait_reason(os_state, owns_gil, &trace.frames, trace.thread_status())
+                        }
+                        None => Some(trace.thread_status()).filter(|_| !trace.active),
+                    };
+                    match reason {
+                        Some(reason) => {
+                            trace.status = Some(reason);
+                            trace.active = false;
+                        }
+                        None => continue,
+                    }
+                }
 
-            // Figure out if the thread is sleeping from the OS if possible
-            trace.active = true;
-            if let Some(id) = trace.os_thread_id {
-                let id = id as Tid;
-                if let Some(active) = thread_activity.get(&id as _) {
-                    trace.active = *active;
+                // Merge in the native stack frames if necessary
+                #[cfg(unwind)]
+                {
+                    if self.config.native {
+                        if let Some(native) = self.native.as_mut() {
+                            let thread_id = trace
+                                .os_thread_id
+                                .ok_or_else(|| format_err!("failed to get os threadid"))?;
+                            let os_thread = remoteprocess::Thread::new(thread_id as Tid)?;
+                            trace.frames = native.merge_native_thread(&trace.frames, &os_thread)?
+                        }
+                    }
                 }
-            }
 
-            // fallback to using a heuristic if we think the thread is still active
-            // Note that on linux the OS thread activity can only be gotten on x86_64
-            // processors and even then seems to be wrong occasionally in thinking 'select'
-            // calls are active (which seems related to the thread locking code,
-            // this problem doesn't seem to happen with the --nonblocking option)
-            // Note: this should be done before the native merging for correct results
-            if trace.active {
-                trace.active = !self._heuristic_is_thread_idle(&trace);
-            }
+                // with --native, collections can also be seen from the gc module's frames (which is
+                // the only way of telling on python versions before 3.9)
+                trace.in_gc |= trace.frames.iter().any(|frame| {
+                    frame.module.is_some()
+                        && (frame.filename.ends_with("gcmodule.c")
+                            || frame.name.starts_with("gc_collect"))
+                });
+
+                for frame in &mut trace.frames {
+                    frame.short_filename = self.shorten_filename(&frame.filename);
+                    if self.config.dump_locals == 0 {
+                        continue;
+                    }
+                    if let Some(locals) = frame.locals.as_mut() {
+                        if self.config.args_only {
+                            locals.retain(|local| local.arg);
+                        }
+                        let limits = FormatLimits::from_config(&self.config);
+                        for local in locals {
+                            let repr = format_variable::<I, Process>(
+                                &self.process,
+                                &self.version,
+                                local.addr,
+                                &limits,
+                            );
+                            local.repr = Some(repr.unwrap_or_else(|_| "?".to_owned()));
+                            if self.config.locals_output.is_some() {
+                                let full_repr = format_variable::<I, Process>(
+                                    &self.process,
+                                    &self.version,
+                                    local.addr,
+                                    &FormatLimits::full(),
+                                );
+                                local.full_repr =
+                                    Some(full_repr.unwrap_or_else(|_| "?".to_owned()));
+                            }
+                        }
+                    }
+                }
 
-            // Merge in the native stack frames if necessary
-            #[cfg(unwind)]
-            {
-                if self.config.native {
-                    if let Some(native) = self.native.as_mut() {
-                        let thread_id = trace
-                            .os_thread_id
-                            .ok_or_else(|| format_err!("failed to get os threadid"))?;
-                        let os_thread = remoteprocess::Thread::new(thread_id as Tid)?;
-                        trace.frames = native.merge_native_thread(&trace.frames, &os_thread)?
+                // these are added after merging in native frames, which expects only python frames
+                if trace.in_gc {
+                    trace.frames.insert(0, gc_frame());
+                }
+                if self.config.offcpu {
+                    if let Some(reason) = trace.status {
+                        trace.frames.insert(0, wait_frame(reason));
                     }
                 }
+
+                traces.push(trace);
+
+                // This seems to happen occasionally when scanning BSS addresses for valid interpreters
+                if traces.len() > 4096 {
+                    return Err(format_err!("Max thread recursion depth reached"));
+                }
+
+                if self.config.gil_only {
+                    // There's only one GIL thread and we've captured it, so we can
+                    // stop now
+                    break 'interpreters;
+                }
             }
+        }
+
+        if self.config.asyncio && !self.config.gil_only {
+            let tasks = self._get_asyncio_traces(&traces)?;
+            traces.extend(tasks);
+        }
+
+        if self.config.lock_contention {
+            self._find_lock_waits::<I>(&mut traces, &os_thread_states);
+            if self.config.dump_locals == 0 {
+                for frame in traces.iter_mut().flat_map(|trace| trace.frames.iter_mut()) {
+                    frame.locals = None;
+                }
+            }
+        }
+        self._check_pause(pause_start)?;
+        self.last_pause = pause_start.map(|start| start.elapsed());
+        Ok(traces)
+    }
+
+    /// Whether a module has been imported, from sys.modules
+    fn _module_imported<I: InterpreterState>(&self, name: &str) -> Result<bool, Error> {
+        let interp: I = copy_struct(&self.process, self.interpreter_address)?;
+        let modules = DictIterator::from(&self.process, &self.version, interp.modules() as usize)?;
+        Ok(find_key::<I::StringObject, Process>(modules, &self.process, name)?.is_some())
+    }
 
-            for frame in &mut trace.frames {
+    /// Gets the stacks of the suspended asyncio tasks of the event loops running on the threads
+    /// that were sampled, as if each task was a thread of its own
+    fn _get_asyncio_traces(&mut self, traces: &[StackTrace]) -> Result<Vec<StackTrace>, Error> {
+        let tasks = match self.version {
+            Version {
+                major: 3, minor: 7, ..
+            } => self._get_asyncio_tasks::<v3_7_0::_is>()?,
+            Version {
+                major: 3, minor: 8, ..
+            } => self._get_asyncio_tasks::<v3_8_0::_is>()?,
+            Version {
+                major: 3, minor: 9, ..
+            } => self._get_asyncio_tasks::<v3_9_5::_is>()?,
+            Version {
+                major: 3,
+                minor: 10,
+                ..
+            } => self._get_asyncio_tasks::<v3_10_0::_is>()?,
+            Version {
+                major: 3,
+                minor: 11,
+                ..
+            } => self._get_asyncio_tasks::<v3_11_0::_is>()?,
+            _ => return Ok(Vec::new()),
+        };
+
+        let mut task_traces = Vec::new();
+        for task in tasks {
+            let thread = match traces.iter().find(|t| t.thread_id == task.thread_id) {
+                Some(thread) => thread,
+                None => continue,
+            };
+            let mut frames = task.frames;
+            for frame in &mut frames {
                 frame.short_filename = self.shorten_filename(&frame.filename);
-                if let Some(locals) = frame.locals.as_mut() {
-                    let max_length = (128 * self.config.dump_locals) as isize;
-                    for local in locals {
-                        let repr = format_variable::<I, Process>(
-                            &self.process,
-                            &self.version,
-                            local.addr,
-                            max_length,
-                        );
-                        local.repr = Some(repr.unwrap_or_else(|_| "?".to_owned()));
+            }
+            task_traces.push(StackTrace {
+                pid: thread.pid,
+                thread_id: thread.thread_id,
+                thread_name: thread.thread_name.clone(),
+                os_thread_id: thread.os_thread_id,
+                active: false,
+                owns_gil: false,
+                frames,
+                process_info: None,
+                task_id: Some(task.task_id),
+                exception: None,
+                in_gc: false,
+                status: None,
+                lock_wait: None,
+                interpreter_id: thread.interpreter_id,
+                task_name: Some(task.name),
+            });
+        }
+        Ok(task_traces)
+    }
+
+    fn _get_asyncio_tasks<I: InterpreterState>(&mut self) -> Result<Vec<AsyncioTask>, Error>
+    where
+        <I::ThreadState as ThreadState>::FrameObject: SuspendedFrame,
+    {
+        let interps = self._get_interpreters::<I>()?;
+        let mut reader = TaskReader::<I, Process>::new(
+            &self.process,
+            &self.version,
+            self.config.lineno,
+            self.config.qualified_names,
+            &mut self.asyncio_coroutine_fields,
+        );
+        let mut tasks = Vec::new();
+        for (_, interp) in interps {
+            tasks.extend(reader.suspended_tasks(&interp)?);
+        }
+        Ok(tasks)
+    }
+
+    /// Gets the tree of tasks of each trio run in the process. This is empty if trio hasn't been
+    /// imported, or the python version isn't 3.7 to 3.11
+    pub fn get_trio_runs(&mut self) -> Result<Vec<TrioRun>, Error> {
+        match self.version {
+            Version {
+                major: 3, minor: 7, ..
+            } => self._get_trio_runs::<v3_7_0::_is>(),
+            Version {
+                major: 3, minor: 8, ..
+            } => self._get_trio_runs::<v3_8_0::_is>(),
+            Version {
+                major: 3, minor: 9, ..
+            } => self._get_trio_runs::<v3_9_5::_is>(),
+            Version {
+                major: 3,
+                minor: 10,
+                ..
+            } => self._get_trio_runs::<v3_10_0::_is>(),
+            Version {
+                major: 3,
+                minor: 11,
+                ..
+            } => self._get_trio_runs::<v3_11_0::_is>(),
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    fn _get_trio_runs<I: InterpreterState>(&mut self) -> Result<Vec<TrioRun>, Error>
+    where
+        <I::ThreadState as ThreadState>::FrameObject: SuspendedFrame,
+    {
+        if !self._module_imported::<I>("trio._core._run")? {
+            return Ok(Vec::new());
+        }
+        let mut runs = Vec::new();
+        {
+            // tasks are started and finished all the time, so the tree is read with the process
+            // suspended to be consistent
+            let _lock = if self.config.blocking == LockingStrategy::Lock {
+                Some(self.process.lock().context("Failed to suspend process")?)
+            } else {
+                None
+            };
+
+            let mut coroutine_fields = HashMap::new();
+            let tasks = TaskReader::<I, Process>::new(
+                &self.process,
+                &self.version,
+                self.config.lineno,
+                self.config.qualified_names,
+                &mut coroutine_fields,
+            );
+            let mut reader = TrioReader::new(&self.process, &self.version, tasks);
+            for (_, interp) in self._get_interpreters::<I>()? {
+                let mut threads = interp.head();
+                while !threads.is_null() {
+                    let thread = copy_pointer(&self.process, threads)
+                        .context("Failed to copy PyThreadState")?;
+                    threads = thread.next();
+
+                    // the runner is a local of the trio.run frame
+                    let trace =
+                        get_stack_trace(&thread, &self.process, true, self.config.lineno, false)?;
+                    if let Some(runner) = find_runner(&trace.frames) {
+                        runs.push(TrioRun {
+                            thread_id: thread.thread_id(),
+                            root: reader.task_tree(runner)?,
+                        });
                     }
                 }
             }
+        }
+        for run in &mut runs {
+            self._shorten_task_filenames(&mut run.root);
+        }
+        Ok(runs)
+    }
 
-            traces.push(trace);
+    fn _shorten_task_filenames(&mut self, task: &mut TrioTask) {
+        for frame in &mut task.frames {
+            frame.short_filename = self.shorten_filename(&frame.filename);
+        }
+        for child in &mut task.children {
+            self._shorten_task_filenames(child);
+        }
+    }
+
+    /// Reads every interpreter in the process along with its id, starting from the most recently
+    /// created. Only the interpreter found when attaching (and the ones created before it) can be
+    /// found when the head of the list of interpreters isn't in the symbols
+    pub(crate) fn _get_interpreters<I: InterpreterState>(&self) -> Result<Vec<(i64, I)>, Error> {
+        let head = match self.interpreter_head_address {
+            Some(address) => self.process.copy_struct::<usize>(address)?,
+            None => 0,
+        };
+        let mut address = if head != 0 {
+            head
+        } else {
+            self.interpreter_address
+        };
 
-            // This seems to happen occasionally when scanning BSS addresses for valid interpreters
-            if traces.len() > 4096 {
-                return Err(format_err!("Max thread recursion depth reached"));
+        let mut interps = Vec::new();
+        while address != 0 {
+            let interp: I = copy_struct(&self.process, address)
+                .context("Failed to copy PyInterpreterState from process")?;
+            address = interp.next() as usize;
+            interps.push(interp);
+            if interps.len() > 256 {
+                return Err(format_err!("Max interpreter recursion depth reached"));
             }
+        }
 
-            if self.config.gil_only {
-                // There's only one GIL thread and we've captured it, so we can
-                // stop now
-                break;
+        // python versions before 3.7 don't keep track of interpreter ids, so count from the
+        // main interpreter at the end of the list instead
+        let count = interps.len();
+        Ok(interps
+            .into_iter()
+            .enumerate()
+            .map(|(index, interp)| {
+                let id = interp.id().unwrap_or((count - index - 1) as i64);
+                (id, interp)
+            })
+            .collect())
+    }
+
+    /// Finds the threads that are blocked acquiring a python lock, and the threads holding them.
+    /// The lock is found in the locals of the innermost python frame of the waiting thread, and
+    /// the holder is the owner of RLocks. Plain locks don't keep track of their owner, so the
+    /// holder is the only other thread with the lock in the locals of any of its frames
+    fn _find_lock_waits<I: InterpreterState>(
+        &self,
+        traces: &mut [StackTrace],
+        os_thread_states: &HashMap<Tid, OsThreadState>,
+    ) {
+        let mut waits = Vec::new();
+        for (index, trace) in traces.iter().enumerate() {
+            if trace.active || trace.status == Some(ThreadStatus::BlockingIo) {
+                continue;
+            }
+            // threads blocked on a lock are waiting on a futex, when the kernel tells us this
+            let os_state = trace
+                .os_thread_id
+                .and_then(|id| os_thread_states.get(&(id as Tid)));
+            if let Some(wchan) = os_state.and_then(|state| state.wchan.as_ref()) {
+                if !wchan.contains("futex") {
+                    continue;
+                }
+            }
+            let frame = match trace.frames.iter().find(|frame| frame.locals.is_some()) {
+                Some(frame) => frame,
+                None => continue,
+            };
+            // Condition.wait and Thread.join block on locks that are only released by a notify
+            // or the thread exiting, which isn't contention
+            if frame.filename.ends_with("threading.py")
+                && (frame.name == "wait" || frame.name == "_wait_for_tstate_lock")
+            {
+                continue;
+            }
+            let locals = frame.locals.iter().flatten();
+            let lock = locals
+                .filter_map(|local| {
+                    let lock = copy_lock::<I, Process>(&self.process, local.addr).ok()??;
+                    Some((local.addr, lock))
+                })
+                .find(|(_, lock)| lock.locked && lock.owner != Some(trace.thread_id));
+            if let Some((addr, lock)) = lock {
+                waits.push((index, addr, lock));
             }
         }
-        Ok(traces)
+
+        for (index, addr, lock) in waits.iter() {
+            let holder = lock.owner.or_else(|| {
+                let mut holders = traces.iter().enumerate().filter(|(other, trace)| {
+                    !waits
+                        .iter()
+                        .any(|(waiter, lock, _)| waiter == other && lock == addr)
+                        && trace
+                            .frames
+                            .iter()
+                            .flat_map(|frame| frame.locals.iter().flatten())
+                            .any(|local| local.addr == *addr)
+                });
+                match (holders.next(), holders.next()) {
+                    (Some((_, holder)), None) => Some(holder.thread_id),
+                    _ => None,
+                }
+            });
+            traces[*index].lock_wait = Some(LockWait {
+                lock: *addr as u64,
+                kind: lock.kind.clone(),
+                holder,
+            });
+        }
     }
 
     // heuristic fallback for determining if a thread is active, used
@@ -356,6 +967,19 @@ impl PythonSpy {
         }
     }
 
+    /// Whether an idle thread is waiting on I/O, rather than sleeping or waiting on a lock
+    fn _heuristic_is_blocking_io(&self, trace: &StackTrace) -> bool {
+        match trace.frames.first() {
+            Some(frame) => {
+                (frame.name == "select" || frame.name == "poll")
+                    || frame.filename.ends_with("socket.py")
+                    || frame.filename.ends_with("ssl.py")
+                    || (frame.name == "communicate" && frame.filename.ends_with("subprocess.py"))
+            }
+            None => false,
+        }
+    }
+
     #[cfg(windows)]
     fn _get_os_thread_id<I: InterpreterState>(
         &mut self,
@@ -424,10 +1048,8 @@ impl PythonSpy {
         let mut all_python_threads = HashSet::new();
         let mut threads = interp.head();
         while !threads.is_null() {
-            let thread = self
-                .process
-                .copy_pointer(threads)
-                .context("Failed to copy PyThreadState")?;
+            let thread =
+                copy_pointer(&self.process, threads).context("Failed to copy PyThreadState")?;
             let current = thread.thread_id();
             all_python_threads.insert(current);
             threads = thread.next();
@@ -516,6 +1138,34 @@ impl PythonSpy {
         Ok(None)
     }
 
+    /// With --max-pause-us, abandons the sample as soon as the process has been paused for too long
+    fn _check_pause(&mut self, pause_start: Option<Instant>) -> Result<(), PauseBudgetExceeded> {
+        if let (Some(start), Some(budget)) = (pause_start, self.config.max_pause) {
+            let elapsed = start.elapsed();
+            if elapsed > budget {
+                self.last_pause = Some(elapsed);
+                return Err(PauseBudgetExceeded { budget });
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether a thread with this name should be sampled, given --threads-include and
+    /// --threads-exclude
+    fn _is_thread_sampled(&self, thread_name: Option<&str>) -> bool {
+        if let Some(include) = self.threads_include.as_ref() {
+            if !thread_name.is_some_and(|name| include.is_match(name)) {
+                return false;
+            }
+        }
+        if let Some(exclude) = self.threads_exclude.as_ref() {
+            if thread_name.is_some_and(|name| exclude.is_match(name)) {
+                return false;
+            }
+        }
+        true
+    }
+
     fn _get_python_thread_name(&mut self, python_thread_id: u64) -> Option<String> {
         match self.python_thread_names.get(&python_thread_id) {
             Some(thread_name) => Some(thread_name.clone()),
@@ -3,8 +3,8 @@ use std::collections::HashMap;
 use anyhow::Error;
 
 use crate::python_bindings::{v3_10_0, v3_11_0, v3_6_6, v3_7_0, v3_8_0, v3_9_5};
-use crate::python_data_access::{copy_long, copy_string, DictIterator, PY_TPFLAGS_MANAGED_DICT};
-use crate::python_interpreters::{InterpreterState, Object, TypeObject};
+use crate::python_data_access::{copy_long, copy_string, object_dict, DictIterator};
+use crate::python_interpreters::InterpreterState;
 use crate::python_spy::PythonSpy;
 
 use crate::version::Version;
@@ -23,10 +23,7 @@ pub fn thread_names_from_interpreter<I: InterpreterState, P: ProcessMemory>(
         let (key, value) = entry?;
         let module_name = copy_string(key as *const I::StringObject, process)?;
         if module_name == "threading" {
-            let module: I::Object = process.copy_struct(value)?;
-            let module_type = process.copy_pointer(module.ob_type())?;
-            let dictptr: usize = process.copy_struct(value + module_type.dictoffset() as usize)?;
-            for i in DictIterator::from(process, version, dictptr)? {
+            for i in object_dict::<I, P>(process, version, value)? {
                 let (key, value) = i?;
                 let name = copy_string(key as *const I::StringObject, process)?;
 
@@ -35,24 +32,7 @@ pub fn thread_names_from_interpreter<I: InterpreterState, P: ProcessMemory>(
                         let (key, value) = i?;
                         let (threadid, _) = copy_long(process, key)?;
 
-                        let thread: I::Object = process.copy_struct(value)?;
-                        let thread_type = process.copy_pointer(thread.ob_type())?;
-
-                        let dict_iter = if thread_type.flags() & PY_TPFLAGS_MANAGED_DICT != 0 {
-                            DictIterator::from_managed_dict(
-                                process,
-                                version,
-                                value,
-                                thread.ob_type() as usize,
-                            )?
-                        } else {
-                            let dict_offset = thread_type.dictoffset();
-                            let dict_addr = (value as isize + dict_offset) as usize;
-                            let thread_dict_addr: usize = process.copy_struct(dict_addr)?;
-                            DictIterator::from(process, version, thread_dict_addr)?
-                        };
-
-                        for i in dict_iter {
+                        for i in object_dict::<I, P>(process, version, value)? {
                             let (key, value) = i?;
                             let varname = copy_string(key as *const I::StringObject, process)?;
 
@@ -74,12 +54,22 @@ pub fn thread_names_from_interpreter<I: InterpreterState, P: ProcessMemory>(
 }
 
 /// Returns a hashmap of threadid: threadname, by inspecting the '_active' variable in the
-/// 'threading' module.
+/// 'threading' module of each interpreter. Threads keep the name they were given in the main
+/// interpreter when they also run code in a subinterpreter
 fn _thread_name_lookup<I: InterpreterState>(
     spy: &PythonSpy,
 ) -> Result<HashMap<u64, String>, Error> {
-    let interp: I = spy.process.copy_struct(spy.interpreter_address)?;
-    thread_names_from_interpreter(&interp, &spy.process, &spy.version)
+    let mut names = HashMap::new();
+    // the main interpreter is the last one in the list
+    for (_, interp) in spy._get_interpreters::<I>()?.iter().rev() {
+        if let Ok(interp_names) = thread_names_from_interpreter(interp, &spy.process, &spy.version)
+        {
+            for (threadid, name) in interp_names {
+                names.entry(threadid).or_insert(name);
+            }
+        }
+    }
+    Ok(names)
 }
 
 // try getting the threadnames, but don't sweat it if we can't. Since this relies on dictionary
@@ -0,0 +1,297 @@
+use std::io::{BufReader, Read, Write};
+use std::time::Instant;
+
+use anyhow::{Context, Error};
+use remoteprocess::Pid;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::sampler_stats::SamplerStats;
+use crate::stack_trace::StackTrace;
+use crate::thread_lifetimes::ThreadEvent;
+use crate::MemoryCounters;
+use crate::ProcessMetadata;
+
+/*
+ * This file contains code for 'record --format capture', which saves every sampled stack trace to
+ * disk so that they can be converted into any of the other output formats after the fact with
+ * 'py-spy convert'.
+ *
+ * The file consists of a msgpack encoded RawHeader, followed by a msgpack encoded RawSample
+ * for every stack trace that was recorded.
+ */
+
+const MAGIC: &str = "py-spy capture";
+const VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RawHeader {
+    pub magic: String,
+    pub version: u32,
+    pub exporter: String,
+    pub sampling_rate: u64,
+    pub show_line_numbers: bool,
+    pub subprocesses: bool,
+    /// When the recording started, in microseconds since the unix epoch
+    #[serde(default)]
+    pub start_time: Option<u64>,
+    /// (rate, timestamp) for each time --adaptive-rate changed the sampling rate
+    #[serde(default)]
+    pub rate_changes: Vec<(u64, u64)>,
+    /// (pid, timestamp, counters) for each time the memory counters were read, with
+    /// --memory-counters
+    #[serde(default)]
+    pub memory_counters: Vec<(Pid, u64, MemoryCounters)>,
+    /// (timestamp, event) for each thread that started or exited during the recording
+    #[serde(default)]
+    pub thread_events: Vec<(u64, ThreadEvent)>,
+    /// How well sampling went over the recording
+    #[serde(default)]
+    pub stats: Option<SamplerStats>,
+    /// Where the profile came from
+    #[serde(default)]
+    pub metadata: Option<ProcessMetadata>,
+}
+
+impl RawHeader {
+    /// Combines the settings of another recording into this one, when merging recordings.
+    /// Line numbers are only kept if all the recordings have them, the sampling statistics are
+    /// added together, and the metadata of the first recording that has any is kept
+    pub fn merge(&mut self, other: &RawHeader) {
+        self.show_line_numbers &= other.show_line_numbers;
+        self.subprocesses |= other.subprocesses;
+        match (self.stats.as_mut(), other.stats.as_ref()) {
+            (Some(stats), Some(other)) => stats.merge(other),
+            (None, Some(other)) => self.stats = Some(other.clone()),
+            _ => {}
+        }
+        if self.metadata.is_none() {
+            self.metadata = other.metadata.clone();
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RawSample {
+    /// Time the sample was taken, in microseconds since the start of the recording
+    pub timestamp: u64,
+    pub trace: StackTrace,
+}
+
+pub struct RawWriter {
+    header: RawHeader,
+    samples: Vec<u8>,
+    start_ts: Instant,
+}
+
+impl RawWriter {
+    pub fn new(config: &Config) -> RawWriter {
+        RawWriter {
+            header: RawHeader {
+                magic: MAGIC.to_owned(),
+                version: VERSION,
+                exporter: format!("py-spy@{}", env!("CARGO_PKG_VERSION")),
+                sampling_rate: config.sampling_rate,
+                show_line_numbers: config.show_line_numbers,
+                subprocesses: config.subprocesses,
+                start_time: config.start_time,
+                rate_changes: Vec::new(),
+                memory_counters: Vec::new(),
+                thread_events: Vec::new(),
+                stats: None,
+                metadata: None,
+            },
+            samples: Vec::new(),
+            start_ts: Instant::now(),
+        }
+    }
+
+    pub fn increment(&mut self, trace: &StackTrace) -> Result<(), Error> {
+        let timestamp = self.start_ts.elapsed().as_micros() as u64;
+        self.increment_at(trace, timestamp)
+    }
+
+    pub fn increment_at(&mut self, trace: &StackTrace, timestamp: u64) -> Result<(), Error> {
+        // we're storing the encoded samples in memory, which is considerably more compact than
+        // holding onto the StackTrace objects themselves
+        rmp_serde::encode::write(&mut self.samples, &RawSampleRef { timestamp, trace })?;
+        Ok(())
+    }
+
+    /// Records that the sampling rate changed at a given time
+    pub fn set_sampling_rate(&mut self, rate: u64, timestamp: u64) {
+        self.header.rate_changes.push((rate, timestamp));
+    }
+
+    /// Records the memory counters of a process, read at a given time
+    pub fn add_memory_counters(&mut self, pid: Pid, counters: &MemoryCounters, timestamp: u64) {
+        self.header
+            .memory_counters
+            .push((pid, timestamp, counters.clone()));
+    }
+
+    /// Records a thread starting or exiting at a given time
+    pub fn add_thread_event(&mut self, event: &ThreadEvent, timestamp: u64) {
+        self.header.thread_events.push((timestamp, event.clone()));
+    }
+
+    /// Records the statistics of the sampler, once recording has finished
+    pub fn set_stats(&mut self, stats: &SamplerStats) {
+        self.header.stats = Some(stats.clone());
+    }
+
+    /// Records where the profile came from
+    pub fn set_metadata(&mut self, metadata: &ProcessMetadata) {
+        self.header.metadata = Some(metadata.clone());
+    }
+
+    /// The number of bytes of samples recorded so far
+    pub fn size(&self) -> u64 {
+        self.samples.len() as u64
+    }
+
+    pub fn write(&self, w: &mut dyn Write) -> Result<(), Error> {
+        rmp_serde::encode::write(w, &self.header)?;
+        w.write_all(&self.samples)?;
+        Ok(())
+    }
+}
+
+// RawSample, but borrowing the trace so that we don't need to clone it when writing out
+#[derive(Serialize)]
+struct RawSampleRef<'a> {
+    timestamp: u64,
+    trace: &'a StackTrace,
+}
+
+/// Reads samples from a file written out with RawWriter
+pub struct RawReader<R: Read> {
+    pub header: RawHeader,
+    reader: BufReader<R>,
+}
+
+impl<R: Read> RawReader<R> {
+    pub fn new(reader: R) -> Result<RawReader<R>, Error> {
+        let mut reader = BufReader::new(reader);
+        let header: RawHeader = rmp_serde::decode::from_read(&mut reader)
+            .context("Failed to read header from py-spy capture file")?;
+        if header.magic != MAGIC {
+            return Err(format_err!("File is not a py-spy capture file"));
+        }
+        if header.version > VERSION {
+            return Err(format_err!(
+                "Unsupported capture file version {} (written by {})",
+                header.version,
+                header.exporter
+            ));
+        }
+        Ok(RawReader { header, reader })
+    }
+}
+
+impl<R: Read> Iterator for RawReader<R> {
+    type Item = Result<RawSample, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match rmp_serde::decode::from_read(&mut self.reader) {
+            Ok(sample) => Some(Ok(sample)),
+            Err(rmp_serde::decode::Error::InvalidMarkerRead(e))
+                if e.kind() == std::io::ErrorKind::UnexpectedEof =>
+            {
+                None
+            }
+            Err(e) => Some(Err(e.into())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{frame, trace};
+    use crate::thread_lifetimes::ThreadEventKind;
+
+    #[test]
+    fn test_raw_roundtrip() {
+        let mut config = Config::default();
+        config.sampling_rate = 250;
+        let mut writer = RawWriter::new(&config);
+        let mut trace = trace(vec![frame("test", "test.py", 4)]);
+        trace.thread_id = 12;
+        trace.thread_name = Some("MainThread".to_owned());
+        trace.os_thread_id = Some(1);
+        trace.active = false;
+        trace.owns_gil = true;
+        writer.increment(&trace).unwrap();
+        writer.set_sampling_rate(125, 1000);
+        writer.increment(&trace).unwrap();
+        let counters = MemoryCounters {
+            rss: Some(1 << 20),
+            arenas: Some(4),
+            gc_counts: Some(vec![120, 3, 1]),
+        };
+        writer.add_memory_counters(1, &counters, 2000);
+        let event = ThreadEvent {
+            kind: ThreadEventKind::Exited,
+            thread: trace.clone(),
+        };
+        writer.add_thread_event(&event, 3000);
+        let stats = SamplerStats {
+            samples: 2,
+            errors: 1,
+            ..SamplerStats::new(250)
+        };
+        writer.set_stats(&stats);
+        let metadata = ProcessMetadata {
+            argv: vec!["app.py".to_owned()],
+            python_version: "3.11.7".to_owned(),
+            ..Default::default()
+        };
+        writer.set_metadata(&metadata);
+
+        let mut out = Vec::new();
+        writer.write(&mut out).unwrap();
+
+        let reader = RawReader::new(out.as_slice()).unwrap();
+        assert_eq!(reader.header.sampling_rate, 250);
+        assert_eq!(reader.header.rate_changes, vec![(125, 1000)]);
+        assert_eq!(reader.header.memory_counters, vec![(1, 2000, counters)]);
+        assert_eq!(reader.header.thread_events.len(), 1);
+        assert_eq!(reader.header.thread_events[0].0, 3000);
+        assert_eq!(
+            reader.header.thread_events[0].1.kind,
+            ThreadEventKind::Exited
+        );
+        assert_eq!(reader.header.stats, Some(stats));
+        assert_eq!(reader.header.metadata, Some(metadata));
+        let samples: Vec<RawSample> = reader.map(|s| s.unwrap()).collect();
+        assert_eq!(samples.len(), 2);
+        assert!(samples[0].timestamp <= samples[1].timestamp);
+        assert_eq!(samples[1].trace.thread_name, trace.thread_name);
+        assert_eq!(samples[1].trace.frames, trace.frames);
+        assert!(samples[1].trace.owns_gil);
+        assert!(!samples[1].trace.active);
+
+        // random data shouldn't be accepted
+        assert!(RawReader::new(&b"flamegraph;data 1"[..]).is_err());
+    }
+
+    #[test]
+    fn test_merge_headers() {
+        let mut header = RawWriter::new(&Config::default()).header;
+        header.show_line_numbers = true;
+        let mut other = header.clone();
+        other.show_line_numbers = false;
+        other.subprocesses = true;
+        let mut stats = SamplerStats::new(100);
+        stats.errors = 2;
+        other.stats = Some(stats);
+        header.merge(&other);
+        assert!(!header.show_line_numbers);
+        assert!(header.subprocesses);
+        assert_eq!(header.stats.as_ref().unwrap().errors, 2);
+
+        header.merge(&other);
+        assert_eq!(header.stats.as_ref().unwrap().errors, 4);
+    }
+}
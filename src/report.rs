@@ -0,0 +1,427 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::Write;
+use std::time::Instant;
+
+use anyhow::Error;
+
+use crate::config::{Config, Granularity};
+use crate::stack_trace::StackTrace;
+use crate::utils::expand_template;
+
+/*
+ * This file contains code to write out a short, human readable summary of a profile as text,
+ * so that CI jobs can print the results of a recording without any other tools.
+ *
+ * The report is generated from a template, which can be changed with --report-template.
+ * Templates can use these variables:
+ *
+ *  {samples}        - the number of samples recorded
+ *  {errors}         - the number of errors encountered while sampling
+ *  {duration}       - the length of the recording in seconds
+ *  {gil_percent}    - the percentage of samples where the thread was holding the GIL
+ *  {active_percent} - the percentage of samples where the thread wasn't idle
+ *  {gc_percent}     - the percentage of samples in the garbage collector
+ *  {top_functions}  - a table of the functions with the most samples (see --report-top)
+ *  {hot_lines}      - for each of the top functions, a table of its lines with the most samples.
+ *                     This is only filled in with '--granularity line', which adds it to the
+ *                     default template
+ *  {threads}        - a line for each thread, with its share of the samples
+ */
+
+pub const DEFAULT_TEMPLATE: &str = "Samples: {samples} over {duration} seconds ({errors} errors)
+GIL held: {gil_percent}%, active: {active_percent}%
+
+Top functions:
+{top_functions}
+
+Threads:
+{threads}
+";
+
+// added to the default template after the top functions with '--granularity line'
+const HOT_LINES_TEMPLATE: &str = "
+Hot lines:
+{hot_lines}
+";
+
+const VARIABLES: [&str; 9] = [
+    "samples",
+    "errors",
+    "duration",
+    "gil_percent",
+    "active_percent",
+    "gc_percent",
+    "top_functions",
+    "hot_lines",
+    "threads",
+];
+
+#[derive(Default)]
+struct Counts {
+    self_samples: u64,
+    total_samples: u64,
+}
+
+#[derive(Default)]
+struct ThreadCounts {
+    name: Option<String>,
+    thread_id: String,
+    samples: u64,
+    gil: u64,
+}
+
+pub struct Report {
+    template: String,
+    top: usize,
+    show_linenumbers: bool,
+    granularity: Granularity,
+    // (function, filename, line) -> counts
+    functions: HashMap<(String, String, i32), Counts>,
+    // with Granularity::Line, (function, filename) -> line -> counts
+    lines: HashMap<(String, String), BTreeMap<i32, Counts>>,
+    // (pid, thread id) -> counts
+    threads: BTreeMap<(remoteprocess::Pid, u64), ThreadCounts>,
+    samples: u64,
+    gil: u64,
+    active: u64,
+    gc: u64,
+    errors: usize,
+    // first and last timestamps, in microseconds since the start of the recording
+    timestamps: Option<(u64, u64)>,
+    sampling_rate: u64,
+    start_ts: Instant,
+}
+
+impl Report {
+    pub fn new(config: &Config) -> Result<Report, Error> {
+        let template = match config.report_template.as_ref() {
+            // let templates passed on the command line use '\n' for newlines
+            Some(template) => template.replace("\\n", "\n"),
+            None if config.granularity == Granularity::Line => DEFAULT_TEMPLATE.replacen(
+                "\nThreads:",
+                &format!("{}\nThreads:", HOT_LINES_TEMPLATE),
+                1,
+            ),
+            None => DEFAULT_TEMPLATE.to_owned(),
+        };
+        // check for unknown variables now, rather than once the recording has finished
+        let vars: Vec<(&str, String)> = VARIABLES.iter().map(|v| (*v, String::new())).collect();
+        expand_template(&template, &vars)?;
+
+        Ok(Report {
+            template,
+            top: config.report_top,
+            show_linenumbers: config.show_line_numbers,
+            granularity: config.granularity,
+            functions: HashMap::new(),
+            lines: HashMap::new(),
+            threads: BTreeMap::new(),
+            samples: 0,
+            gil: 0,
+            active: 0,
+            gc: 0,
+            errors: 0,
+            timestamps: None,
+            sampling_rate: config.sampling_rate,
+            start_ts: Instant::now(),
+        })
+    }
+
+    pub fn increment(&mut self, trace: &StackTrace) -> std::io::Result<()> {
+        let timestamp = self.start_ts.elapsed().as_micros() as u64;
+        self.increment_at(trace, timestamp)
+    }
+
+    pub fn increment_at(&mut self, trace: &StackTrace, timestamp: u64) -> std::io::Result<()> {
+        // recursive functions should only be counted once per sample in the totals
+        let mut seen = HashSet::new();
+        let mut seen_lines = HashSet::new();
+        for (depth, frame) in trace.frames.iter().enumerate() {
+            let filename = match &frame.short_filename {
+                Some(f) => f,
+                None => &frame.filename,
+            };
+            let split_lines = self.granularity == Granularity::Line;
+            if split_lines && frame.line != 0 {
+                let key = (frame.name.clone(), filename.clone());
+                if seen_lines.insert((key.clone(), frame.line)) {
+                    let counts = self
+                        .lines
+                        .entry(key)
+                        .or_default()
+                        .entry(frame.line)
+                        .or_default();
+                    counts.total_samples += 1;
+                    if depth == 0 {
+                        counts.self_samples += 1;
+                    }
+                }
+            }
+
+            let line = if self.show_linenumbers && !split_lines {
+                frame.line
+            } else {
+                0
+            };
+            let key = (frame.name.clone(), filename.clone(), line);
+            if !seen.insert(key.clone()) {
+                continue;
+            }
+            let counts = self.functions.entry(key).or_default();
+            counts.total_samples += 1;
+            if depth == 0 {
+                counts.self_samples += 1;
+            }
+        }
+
+        let thread = self
+            .threads
+            .entry((trace.pid, trace.thread_id))
+            .or_insert_with(|| ThreadCounts {
+                thread_id: trace.format_threadid(),
+                ..Default::default()
+            });
+        if trace.thread_name.is_some() {
+            thread.name = trace.thread_name.clone();
+        }
+        thread.samples += 1;
+
+        self.samples += 1;
+        if trace.owns_gil {
+            self.gil += 1;
+            thread.gil += 1;
+        }
+        if trace.active {
+            self.active += 1;
+        }
+        if trace.in_gc {
+            self.gc += 1;
+        }
+        self.timestamps = match self.timestamps {
+            Some((first, last)) => Some((first.min(timestamp), last.max(timestamp))),
+            None => Some((timestamp, timestamp)),
+        };
+        Ok(())
+    }
+
+    pub fn set_errors(&mut self, errors: usize) {
+        self.errors = errors;
+    }
+
+    pub fn write(&self, w: &mut dyn Write) -> Result<(), Error> {
+        let percent =
+            |count: u64| format!("{:.2}", 100.0 * count as f64 / self.samples.max(1) as f64);
+
+        // the last sample covers a whole sampling interval
+        let duration = match self.timestamps {
+            Some((first, last)) => last - first + 1_000_000 / self.sampling_rate.max(1),
+            None => 0,
+        };
+
+        let vars = [
+            ("samples", self.samples.to_string()),
+            ("errors", self.errors.to_string()),
+            ("duration", format!("{:.1}", duration as f64 / 1e6)),
+            ("gil_percent", percent(self.gil)),
+            ("active_percent", percent(self.active)),
+            ("gc_percent", percent(self.gc)),
+            ("top_functions", self.top_functions()),
+            ("hot_lines", self.hot_lines()),
+            ("threads", self.threads()),
+        ];
+        w.write_all(expand_template(&self.template, &vars)?.as_bytes())?;
+        Ok(())
+    }
+
+    /// The functions with the most samples of their own, and then in total
+    fn sorted_functions(&self) -> Vec<(&(String, String, i32), &Counts)> {
+        let mut rows: Vec<_> = self.functions.iter().collect();
+        rows.sort_by(|(a_key, a), (b_key, b)| {
+            b.self_samples
+                .cmp(&a.self_samples)
+                .then(b.total_samples.cmp(&a.total_samples))
+                .then(a_key.cmp(b_key))
+        });
+        rows.truncate(self.top);
+        rows
+    }
+
+    fn top_functions(&self) -> String {
+        let percent = |count: u64| 100.0 * count as f64 / self.samples.max(1) as f64;
+        let mut lines = vec![format!("{:>8} {:>8}  Function", "%Own", "%Total")];
+        for ((function, filename, line), counts) in self.sorted_functions() {
+            let location = if self.show_linenumbers && self.granularity != Granularity::Line {
+                format!("{}:{}", filename, line)
+            } else {
+                filename.clone()
+            };
+            lines.push(format!(
+                "{:>7.2}% {:>7.2}%  {} ({})",
+                percent(counts.self_samples),
+                percent(counts.total_samples),
+                function,
+                location
+            ));
+        }
+        lines.join("\n")
+    }
+
+    fn hot_lines(&self) -> String {
+        let percent = |count: u64| 100.0 * count as f64 / self.samples.max(1) as f64;
+        let mut tables = Vec::new();
+        for ((function, filename, _), _) in self.sorted_functions() {
+            let lines = match self.lines.get(&(function.clone(), filename.clone())) {
+                Some(lines) => lines,
+                None => continue,
+            };
+            let mut rows: Vec<_> = lines.iter().collect();
+            rows.sort_by(|(a_line, a), (b_line, b)| {
+                b.self_samples
+                    .cmp(&a.self_samples)
+                    .then(b.total_samples.cmp(&a.total_samples))
+                    .then(a_line.cmp(b_line))
+            });
+            let mut table = vec![
+                format!("{} ({})", function, filename),
+                format!("{:>8} {:>8}  Line", "%Own", "%Total"),
+            ];
+            for (line, counts) in rows.into_iter().take(self.top) {
+                table.push(format!(
+                    "{:>7.2}% {:>7.2}%  {}",
+                    percent(counts.self_samples),
+                    percent(counts.total_samples),
+                    line
+                ));
+            }
+            tables.push(table.join("\n"));
+        }
+        tables.join("\n\n")
+    }
+
+    fn threads(&self) -> String {
+        let percent = |count: u64, total: u64| 100.0 * count as f64 / total.max(1) as f64;
+        self.threads
+            .iter()
+            .map(|((pid, _), thread)| {
+                let name = match &thread.name {
+                    Some(name) => format!("{} ({})", name, thread.thread_id),
+                    None => format!("Thread {}", thread.thread_id),
+                };
+                format!(
+                    "  {} in process {}: {:.2}% of samples, holding the GIL for {:.2}%",
+                    name,
+                    pid,
+                    percent(thread.samples, self.samples),
+                    percent(thread.gil, thread.samples)
+                )
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{frame, trace};
+
+    #[test]
+    fn test_report() {
+        let mut config = Config::default();
+        config.show_line_numbers = true;
+        config.report_top = 2;
+        let mut report = Report::new(&config).unwrap();
+        let mut trace = trace(vec![
+            frame("busy", "test.py", 2),
+            frame("main", "test.py", 10),
+        ]);
+        trace.pid = 1234;
+        trace.thread_name = Some(String::from("MainThread"));
+        trace.owns_gil = true;
+        report.increment_at(&trace, 0).unwrap();
+        report.increment_at(&trace, 10000).unwrap();
+        report.increment_at(&trace, 20000).unwrap();
+        trace.owns_gil = false;
+        trace.frames = vec![frame("wait", "test.py", 5), frame("main", "test.py", 10)];
+        report.increment_at(&trace, 30000).unwrap();
+        report.set_errors(3);
+
+        let mut out = Vec::new();
+        report.write(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let expected = "Samples: 4 over 0.0 seconds (3 errors)
+GIL held: 75.00%, active: 100.00%
+
+Top functions:
+    %Own   %Total  Function
+  75.00%   75.00%  busy (test.py:2)
+  25.00%   25.00%  wait (test.py:5)
+
+Threads:
+  MainThread (0x1) in process 1234: 100.00% of samples, holding the GIL for 75.00%
+";
+        assert_eq!(out, expected);
+
+        let mut config = Config::default();
+        config.report_template = Some(String::from("gc: {gc_percent}%"));
+        let mut report = Report::new(&config).unwrap();
+        report.increment_at(&trace, 0).unwrap();
+        trace.in_gc = true;
+        report.increment_at(&trace, 10000).unwrap();
+        let mut out = Vec::new();
+        report.write(&mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "gc: 50.00%");
+
+        // templates can only use known variables
+        let mut config = Config::default();
+        config.report_template = Some(String::from("{samples} samples\\n{unknown}"));
+        assert!(Report::new(&config).is_err());
+    }
+
+    #[test]
+    fn test_report_hot_lines() {
+        let mut config = Config::default();
+        config.show_line_numbers = true;
+        config.granularity = Granularity::Line;
+        config.report_template = Some(String::from("{top_functions}\n\n{hot_lines}"));
+        let mut report = Report::new(&config).unwrap();
+        let mut trace = trace(vec![
+            frame("busy", "test.py", 2),
+            frame("main", "test.py", 10),
+        ]);
+        trace.pid = 1234;
+        trace.owns_gil = true;
+        report.increment_at(&trace, 0).unwrap();
+        report.increment_at(&trace, 10000).unwrap();
+        trace.frames = vec![frame("busy", "test.py", 3), frame("main", "test.py", 10)];
+        report.increment_at(&trace, 20000).unwrap();
+        trace.frames = vec![frame("main", "test.py", 12)];
+        report.increment_at(&trace, 30000).unwrap();
+
+        // functions are counted across all of their lines, which are broken down after
+        let mut out = Vec::new();
+        report.write(&mut out).unwrap();
+        let expected = "    %Own   %Total  Function
+  75.00%   75.00%  busy (test.py)
+  25.00%  100.00%  main (test.py)
+
+busy (test.py)
+    %Own   %Total  Line
+  50.00%   50.00%  2
+  25.00%   25.00%  3
+
+main (test.py)
+    %Own   %Total  Line
+  25.00%   25.00%  12
+   0.00%   75.00%  10";
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+
+        // and are added to the default template
+        let mut config = Config::default();
+        config.granularity = Granularity::Line;
+        assert!(Report::new(&config)
+            .unwrap()
+            .template
+            .contains("\n\nHot lines:\n{hot_lines}\n\nThreads:"));
+    }
+}
@@ -0,0 +1,258 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::Error;
+
+use crate::python_spy::PauseBudgetExceeded;
+use crate::sampler::DeadlineMissed;
+
+/*
+ * This file contains code to retry samples that fail for reasons that are usually transient,
+ * like a thread exiting while its stack is being read, or the program changing memory out from
+ * under us while we read it.
+ *
+ * Each kind of failure is retried a bounded number of times with exponential backoff before the
+ * sample is given up on. A process that hasn't been sampled successfully for SUSTAINED_FAILURE
+ * is set up to be sampled again from scratch (which picks up a process that has exec'ed), and
+ * sampling only stops for it if that fails too.
+ */
+
+/// How long every sample of a process has to fail for before it's set up to be sampled again
+pub const SUSTAINED_FAILURE: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum FailureKind {
+    /// The process or the thread being read exited
+    Exited,
+    /// The threads of the process couldn't be suspended
+    Suspend,
+    /// Memory couldn't be read at an address, usually because it changed while being read
+    BadAddress,
+    /// We don't have permission to read from the process
+    Permission,
+    /// The sample was abandoned for taking longer than --max-pause-us
+    PauseBudget,
+    /// The process wasn't sampled in time to be combined with the other processes
+    Deadline,
+    Other,
+}
+
+impl FailureKind {
+    pub fn classify(e: &Error) -> FailureKind {
+        for cause in e.chain() {
+            if cause.is::<PauseBudgetExceeded>() {
+                return FailureKind::PauseBudget;
+            }
+            if cause.is::<DeadlineMissed>() {
+                return FailureKind::Deadline;
+            }
+            if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+                return classify_io_error(e);
+            }
+            match cause.downcast_ref::<remoteprocess::Error>() {
+                Some(remoteprocess::Error::IOError(e)) => return classify_io_error(e),
+                #[cfg(target_os = "linux")]
+                Some(remoteprocess::Error::NixError(_)) => return FailureKind::Suspend,
+                _ => {}
+            }
+        }
+        FailureKind::Other
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FailureKind::Exited => "exited",
+            FailureKind::Suspend => "suspend",
+            FailureKind::BadAddress => "bad_address",
+            FailureKind::Permission => "permission",
+            FailureKind::PauseBudget => "pause_budget",
+            FailureKind::Deadline => "deadline",
+            FailureKind::Other => "other",
+        }
+    }
+
+    /// The most times to retry a sample that failed like this, and how long to wait before the
+    /// first retry. The wait doubles with each retry after that
+    fn retry_policy(&self) -> (u32, Duration) {
+        match self {
+            FailureKind::Exited => (2, Duration::from_micros(100)),
+            FailureKind::Suspend => (3, Duration::from_micros(200)),
+            FailureKind::BadAddress => (3, Duration::from_micros(50)),
+            FailureKind::Other => (1, Duration::from_micros(50)),
+            // retrying these would either fail again, or pause the process for even longer
+            FailureKind::Permission | FailureKind::PauseBudget | FailureKind::Deadline => {
+                (0, Duration::ZERO)
+            }
+        }
+    }
+}
+
+fn classify_io_error(e: &std::io::Error) -> FailureKind {
+    if e.kind() == std::io::ErrorKind::PermissionDenied {
+        return FailureKind::Permission;
+    }
+    #[cfg(unix)]
+    match e.raw_os_error() {
+        Some(libc::ESRCH) => return FailureKind::Exited,
+        Some(libc::EFAULT) | Some(libc::EIO) => return FailureKind::BadAddress,
+        _ => {}
+    }
+    FailureKind::Other
+}
+
+/// Calls 'sample' until it succeeds, retrying the failures that are usually transient. The kind
+/// of each failure that was retried is added to 'retried'
+pub fn with_retries<T>(
+    retried: &mut Vec<FailureKind>,
+    mut sample: impl FnMut() -> Result<T, Error>,
+) -> Result<T, Error> {
+    let mut attempts: Vec<FailureKind> = Vec::new();
+    loop {
+        let e = match sample() {
+            Ok(value) => return Ok(value),
+            Err(e) => e,
+        };
+        let kind = FailureKind::classify(&e);
+        let (max_retries, delay) = kind.retry_policy();
+        let retries = attempts.iter().filter(|k| **k == kind).count() as u32;
+        if retries >= max_retries {
+            return Err(e);
+        }
+        attempts.push(kind);
+        retried.push(kind);
+        thread::sleep(delay * 2u32.pow(retries));
+    }
+}
+
+/// Whether a process has exited. Its executable can briefly fail to resolve while it execs, so
+/// this checks a few times with backoff before deciding that it has
+pub fn process_exited(process: &remoteprocess::Process) -> bool {
+    let mut delay = Duration::from_millis(1);
+    for _ in 0..3 {
+        if process.exe().is_ok() {
+            return false;
+        }
+        thread::sleep(delay);
+        delay *= 2;
+    }
+    process.exe().is_err()
+}
+
+/// Keeps track of how long a process has been failing to be sampled for
+#[derive(Debug, Default)]
+pub struct FailureTracker {
+    failing_since: Option<Instant>,
+}
+
+impl FailureTracker {
+    pub fn new() -> FailureTracker {
+        FailureTracker::default()
+    }
+
+    /// Records the error the latest sample failed with if any, returning true once every sample
+    /// has failed for SUSTAINED_FAILURE. Abandoned samples don't count as failures, since the
+    /// process could be read fine
+    pub fn update(&mut self, error: Option<&Error>) -> bool {
+        let failed = error.is_some_and(|e| !e.is::<PauseBudgetExceeded>());
+        self.update_at(failed, Instant::now())
+    }
+
+    fn update_at(&mut self, failed: bool, now: Instant) -> bool {
+        if !failed {
+            self.failing_since = None;
+            return false;
+        }
+        let since = *self.failing_since.get_or_insert(now);
+        now.duration_since(since) >= SUSTAINED_FAILURE
+    }
+
+    pub fn reset(&mut self) {
+        self.failing_since = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn os_error(code: i32) -> Error {
+        Error::from(remoteprocess::Error::IOError(
+            std::io::Error::from_raw_os_error(code),
+        ))
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_classify() {
+        assert_eq!(
+            FailureKind::classify(&os_error(libc::ESRCH)),
+            FailureKind::Exited
+        );
+        assert_eq!(
+            FailureKind::classify(&os_error(libc::EFAULT).context("Failed to copy PyThreadState")),
+            FailureKind::BadAddress
+        );
+        assert_eq!(
+            FailureKind::classify(&os_error(libc::EPERM)),
+            FailureKind::Permission
+        );
+        let abandoned = Error::from(PauseBudgetExceeded {
+            budget: Duration::from_micros(100),
+        });
+        assert_eq!(FailureKind::classify(&abandoned), FailureKind::PauseBudget);
+        let missed = Error::from(DeadlineMissed {
+            deadline: Duration::from_millis(10),
+        });
+        assert_eq!(FailureKind::classify(&missed), FailureKind::Deadline);
+        let other = anyhow::format_err!("Max thread recursion depth reached");
+        assert_eq!(FailureKind::classify(&other), FailureKind::Other);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_with_retries() {
+        // bad reads are retried until they succeed
+        let mut retried = Vec::new();
+        let mut failures = 2;
+        let result = with_retries(&mut retried, || {
+            if failures > 0 {
+                failures -= 1;
+                return Err(os_error(libc::EFAULT));
+            }
+            Ok(42)
+        });
+        assert_eq!(result.unwrap(), 42);
+        assert_eq!(retried, vec![FailureKind::BadAddress; 2]);
+
+        // but only so many times
+        let mut retried = Vec::new();
+        let mut calls = 0;
+        let result: Result<(), Error> = with_retries(&mut retried, || {
+            calls += 1;
+            Err(os_error(libc::EFAULT))
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 4);
+
+        // and permission errors aren't retried at all
+        let mut retried = Vec::new();
+        let result: Result<(), Error> = with_retries(&mut retried, || Err(os_error(libc::EPERM)));
+        assert!(result.is_err());
+        assert!(retried.is_empty());
+    }
+
+    #[test]
+    fn test_failure_tracker() {
+        let mut tracker = FailureTracker::new();
+        let start = Instant::now();
+        assert!(!tracker.update_at(true, start));
+        assert!(!tracker.update_at(true, start + Duration::from_secs(4)));
+        assert!(tracker.update_at(true, start + SUSTAINED_FAILURE));
+
+        // a single successful sample resets the clock
+        assert!(!tracker.update_at(false, start + Duration::from_secs(6)));
+        assert!(!tracker.update_at(true, start + Duration::from_secs(7)));
+        assert!(!tracker.update_at(true, start + Duration::from_secs(11)));
+        assert!(tracker.update_at(true, start + Duration::from_secs(12)));
+    }
+}
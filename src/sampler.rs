@@ -1,48 +1,182 @@
 #![allow(clippy::type_complexity)]
 
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{self, Receiver, Sender};
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use anyhow::Error;
 
 use remoteprocess::Pid;
 
-use crate::config::Config;
+use crate::config::{Clock, Config};
+use crate::cpu_clock::CpuClock;
+use crate::memory::MemoryCounters;
+use crate::process_metadata::{read_process_metadata, ProcessMetadata};
+use crate::process_role::process_role;
 use crate::python_spy::PythonSpy;
+use crate::retry::{process_exited, with_retries, FailureKind, FailureTracker, SUSTAINED_FAILURE};
 use crate::stack_trace::{ProcessInfo, StackTrace};
-use crate::timer::Timer;
+use crate::timer::{DutyCycle, Timer};
 use crate::version::Version;
 
 pub struct Sampler {
     pub version: Option<Version>,
+    /// Where the profile came from, for outputs that store it (see process_metadata)
+    pub metadata: Option<ProcessMetadata>,
     rx: Option<Receiver<Sample>>,
     sampling_thread: Option<thread::JoinHandle<()>>,
+    paused: Arc<AtomicBool>,
 }
 
 pub struct Sample {
     pub traces: Vec<StackTrace>,
     pub sampling_errors: Option<Vec<(Pid, Error)>>,
     pub late: Option<Duration>,
+    /// The number of samples being taken a second, which can change with --adaptive-rate
+    pub sampling_rate: u64,
+    /// Whether sampling is paused, between --profile-for windows or through the pause handle.
+    /// Paused samples don't have any traces, and are sent periodically so that the recording can
+    /// be stopped while paused
+    pub paused: bool,
+    /// The memory counters read with this sample for each process, with --memory-counters.
+    /// These are only read once every --memory-counters interval
+    pub memory_counters: Vec<(Pid, MemoryCounters)>,
+    /// How long each process was paused for to take this sample. Empty with --nonblocking
+    pub pauses: Vec<Duration>,
+    /// The kind of each failure that was retried to take this sample
+    pub retried: Vec<FailureKind>,
+}
+
+// how often paused samples are sent while sampling is paused
+const PAUSE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The error reported for a process that wasn't sampled before the deadline shared by all the
+/// processes in a sampling interval, with --subprocesses. Its sample is left out, so that the
+/// samples that are kept are all taken at about the same time
+#[derive(Debug)]
+pub struct DeadlineMissed {
+    pub deadline: Duration,
+}
+
+impl std::fmt::Display for DeadlineMissed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Sample wasn't collected within {:?} of the other processes",
+            self.deadline
+        )
+    }
+}
+
+impl std::error::Error for DeadlineMissed {}
+
+fn new_cpu_clock(config: &Config) -> Option<CpuClock> {
+    match config.clock {
+        Clock::Cpu => Some(CpuClock::new(config.sampling_rate)),
+        Clock::Wall => None,
+    }
+}
+
+fn new_duty_cycle(config: &Config) -> Option<DutyCycle> {
+    match (config.profile_for, config.every) {
+        (Some(profile_for), Some(every)) => Some(DutyCycle::new(profile_for, every)),
+        _ => None,
+    }
+}
+
+fn new_timer(config: &Config) -> Timer {
+    let timer = if config.fixed_interval {
+        Timer::fixed(config.sampling_rate)
+    } else {
+        Timer::new(config.sampling_rate)
+    };
+    if config.adaptive_rate {
+        timer.adaptive()
+    } else {
+        timer
+    }
+}
+
+/// How much longer sampling is paused for, either until the next --profile-for window starts or
+/// for as long as it's paused through Sampler::pause_handle
+fn paused_for(duty_cycle: Option<&DutyCycle>, paused: &AtomicBool) -> Option<Duration> {
+    if paused.load(Ordering::Relaxed) {
+        return Some(PAUSE_INTERVAL);
+    }
+    duty_cycle.and_then(|duty_cycle| duty_cycle.paused())
+}
+
+/// Waits for sampling to be resumed. Returns false if the recording was stopped or the process
+/// exited in the meantime
+fn wait_for_window(
+    duty_cycle: Option<&DutyCycle>,
+    paused: &AtomicBool,
+    processes: &[remoteprocess::Process],
+    tx: &Sender<Sample>,
+    sampling_rate: u64,
+) -> bool {
+    while let Some(remaining) = paused_for(duty_cycle, paused) {
+        if processes.iter().all(process_exited) {
+            let pids: Vec<String> = processes.iter().map(|p| p.pid.to_string()).collect();
+            info!(
+                "stopped sampling pid {} because the process exited",
+                pids.join(", ")
+            );
+            return false;
+        }
+        let sample = Sample {
+            traces: Vec::new(),
+            sampling_errors: None,
+            late: None,
+            sampling_rate,
+            paused: true,
+            memory_counters: Vec::new(),
+            pauses: Vec::new(),
+            retried: Vec::new(),
+        };
+        if tx.send(sample).is_err() {
+            return false;
+        }
+        thread::sleep(remaining.min(PAUSE_INTERVAL));
+    }
+    true
 }
 
 impl Sampler {
     pub fn new(pid: Pid, config: &Config) -> Result<Sampler, Error> {
         if config.subprocesses {
-            Self::new_subprocess_sampler(pid, config)
+            Self::new_multiprocess_sampler(&[pid], config)
         } else {
             Self::new_sampler(pid, config)
         }
     }
 
+    /// Creates a new sampler that samples each of a set of processes (and their subprocesses
+    /// with --subprocesses), like the processes matched by --pid-name with --all-matching
+    pub fn new_multiple(pids: &[Pid], config: &Config) -> Result<Sampler, Error> {
+        match pids {
+            [pid] => Self::new(*pid, config),
+            _ => Self::new_multiprocess_sampler(pids, config),
+        }
+    }
+
+    /// Returns a flag that stops sampling while it's set, until it's cleared again. Paused
+    /// samples are still sent periodically while sampling is stopped
+    pub fn pause_handle(&self) -> Arc<AtomicBool> {
+        self.paused.clone()
+    }
+
     /// Creates a new sampler object, reading from a single process only
     fn new_sampler(pid: Pid, config: &Config) -> Result<Sampler, Error> {
         let (tx, rx): (Sender<Sample>, Receiver<Sample>) = mpsc::channel();
+        let paused = Arc::new(AtomicBool::new(false));
+        let thread_paused = paused.clone();
         let (initialized_tx, initialized_rx): (
-            Sender<Result<Version, Error>>,
-            Receiver<Result<Version, Error>>,
+            Sender<Result<Initialized, Error>>,
+            Receiver<Result<Initialized, Error>>,
         ) = mpsc::channel();
         let config = config.clone();
         let sampling_thread = thread::spawn(move || {
@@ -50,7 +184,7 @@ impl Sampler {
             // have the Send trait implemented on linux
             let mut spy = match PythonSpy::retry_new(pid, &config, 20) {
                 Ok(spy) => {
-                    if initialized_tx.send(Ok(spy.version.clone())).is_err() {
+                    if initialized_tx.send(Ok(initialization(&spy))).is_err() {
                         return;
                     }
                     spy
@@ -61,12 +195,63 @@ impl Sampler {
                 }
             };
 
-            for sleep in Timer::new(spy.config.sampling_rate as f64) {
+            let duty_cycle = new_duty_cycle(&spy.config);
+            let mut cpu_clock = new_cpu_clock(&spy.config);
+            let mut timer = new_timer(&spy.config);
+            let mut failures = FailureTracker::new();
+            while let Some(sleep) = timer.next() {
+                if paused_for(duty_cycle.as_ref(), &thread_paused).is_some() {
+                    if !wait_for_window(
+                        duty_cycle.as_ref(),
+                        &thread_paused,
+                        std::slice::from_ref(&spy.process),
+                        &tx,
+                        timer.rate(),
+                    ) {
+                        break;
+                    }
+                    // don't try to catch up on the samples that weren't taken while paused
+                    timer = new_timer(&spy.config);
+                }
+
+                // with --clock cpu, the process is only sampled once one of its threads has used
+                // enough cpu time. (failing to read the cpu time falls through to sampling, which
+                // finds out if the process has exited)
+                let cpu_idle = match cpu_clock.as_mut() {
+                    Some(cpu_clock) => !cpu_clock.update(spy.pid).unwrap_or(true),
+                    None => false,
+                };
+
                 let mut sampling_errors = None;
-                let traces = match spy.get_stack_traces() {
+                let mut pauses = Vec::new();
+                let mut retried = Vec::new();
+                let traces = if cpu_idle {
+                    // a process that has exited but hasn't been reaped yet never uses any more
+                    // cpu time, so this has to check for it without sampling
+                    if process_exited(&spy.process) {
+                        info!(
+                            "stopped sampling pid {} because the process exited",
+                            spy.pid
+                        );
+                        break;
+                    }
+                    Ok(Vec::new())
+                } else {
+                    let traces = with_retries(&mut retried, || spy.get_stack_traces());
+                    pauses.extend(spy.last_pause);
+                    traces
+                };
+                if failures.update(traces.as_ref().err()) {
+                    spy = match reinitialize(spy) {
+                        Some(spy) => spy,
+                        None => break,
+                    };
+                    failures.reset();
+                }
+                let traces = match traces {
                     Ok(traces) => traces,
                     Err(e) => {
-                        if spy.process.exe().is_err() {
+                        if process_exited(&spy.process) {
                             info!(
                                 "stopped sampling pid {} because the process exited",
                                 spy.pid
@@ -77,6 +262,14 @@ impl Sampler {
                         Vec::new()
                     }
                 };
+                let traces = match cpu_clock.as_mut() {
+                    Some(cpu_clock) => cpu_clock.select(traces),
+                    None => traces,
+                };
+                let memory_counters = spy
+                    .poll_memory_counters()
+                    .map(|counters| vec![(spy.pid, counters)])
+                    .unwrap_or_default();
 
                 let late = sleep.err();
                 if tx
@@ -84,6 +277,11 @@ impl Sampler {
                         traces,
                         sampling_errors,
                         late,
+                        sampling_rate: timer.rate(),
+                        paused: false,
+                        memory_counters,
+                        pauses,
+                        retried,
                     })
                     .is_err()
                 {
@@ -92,37 +290,38 @@ impl Sampler {
             }
         });
 
-        let version = initialized_rx.recv()??;
+        let (version, metadata) = initialized_rx.recv()??;
         Ok(Sampler {
             rx: Some(rx),
             version: Some(version),
+            metadata,
             sampling_thread: Some(sampling_thread),
+            paused,
         })
     }
 
     /// Creates a new sampler object that samples any python process in the
-    /// process or child processes
-    fn new_subprocess_sampler(pid: Pid, config: &Config) -> Result<Sampler, Error> {
-        let process = remoteprocess::Process::new(pid)?;
+    /// processes, or (with --subprocesses) their child processes
+    fn new_multiprocess_sampler(pids: &[Pid], config: &Config) -> Result<Sampler, Error> {
+        let processes = pids
+            .iter()
+            .map(|&pid| remoteprocess::Process::new(pid))
+            .collect::<Result<Vec<_>, _>>()?;
 
         // Initialize a PythonSpy object per child, and build up the process tree
         let mut spies = HashMap::new();
         let mut retries = 10;
-        spies.insert(pid, PythonSpyThread::new(pid, None, config)?);
+        for &pid in pids {
+            spies.insert(pid, PythonSpyThread::new(pid, None, config)?);
+        }
 
         loop {
-            for (childpid, parentpid) in process.child_processes()? {
-                // If we can't create the child process, don't worry about it
-                // can happen with zombie child processes etc
-                match PythonSpyThread::new(childpid, Some(parentpid), config) {
-                    Ok(spy) => {
-                        spies.insert(childpid, spy);
-                    }
-                    Err(e) => {
-                        warn!("Failed to open process {}: {}", childpid, e);
-                    }
-                }
-            }
+            add_spies(
+                &mut spies,
+                pids,
+                child_processes(&processes, config)?,
+                config,
+            );
 
             // wait for all the various python spy objects to initialize, and break out of here
             // if we have one of them started.
@@ -130,12 +329,15 @@ impl Sampler {
                 break;
             }
 
-            // Otherwise sleep for a short time and retry
+            // Otherwise sleep for a short time and retry. With --subprocesses, python can be
+            // started at any time by a wrapper like a shell script, so keep waiting while it runs
             retries -= 1;
-            if retries == 0 {
+            let waiting = config.subprocesses && processes.iter().any(|p| p.exe().is_ok());
+            if retries <= 0 && !waiting {
+                let pids: Vec<String> = pids.iter().map(|pid| pid.to_string()).collect();
                 return Err(format_err!(
                     "No python processes found in process {} or any of its subprocesses",
-                    pid
+                    pids.join(", ")
                 ));
             }
             std::thread::sleep(std::time::Duration::from_millis(100));
@@ -143,29 +345,24 @@ impl Sampler {
 
         // Create a new thread to periodically monitor for new child processes, and update
         // the procesess map
+        // the metadata is from the process that was asked for, unless it isn't running python
+        let metadata = spies
+            .get(&pids[0])
+            .into_iter()
+            .chain(spies.values())
+            .find_map(|spy| spy.metadata());
+
         let spies = Arc::new(Mutex::new(spies));
         let monitor_spies = spies.clone();
         let monitor_config = config.clone();
+        let monitor_pids = pids.to_vec();
         std::thread::spawn(move || {
-            while process.exe().is_ok() {
+            while processes.iter().any(|process| process.exe().is_ok()) {
                 match monitor_spies.lock() {
                     Ok(mut spies) => {
-                        for (childpid, parentpid) in process
-                            .child_processes()
-                            .expect("failed to get subprocesses")
-                        {
-                            if spies.contains_key(&childpid) {
-                                continue;
-                            }
-                            match PythonSpyThread::new(childpid, Some(parentpid), &monitor_config) {
-                                Ok(spy) => {
-                                    spies.insert(childpid, spy);
-                                }
-                                Err(e) => {
-                                    warn!("Failed to create spy for {}: {}", childpid, e);
-                                }
-                            }
-                        }
+                        let children = child_processes(&processes, &monitor_config)
+                            .expect("failed to get subprocesses");
+                        add_spies(&mut spies, &monitor_pids, children, &monitor_config);
                     }
                     Err(e) => {
                         error!("Failed to acquire lock: {}", e);
@@ -178,12 +375,38 @@ impl Sampler {
         let mut process_info = HashMap::new();
 
         // Create a new thread to generate samples
+        let roots = pids
+            .iter()
+            .map(|&pid| remoteprocess::Process::new(pid))
+            .collect::<Result<Vec<_>, _>>()?;
         let config = config.clone();
         let (tx, rx): (Sender<Sample>, Receiver<Sample>) = mpsc::channel();
+        let paused = Arc::new(AtomicBool::new(false));
+        let thread_paused = paused.clone();
         let sampling_thread = std::thread::spawn(move || {
-            for sleep in Timer::new(config.sampling_rate as f64) {
+            let duty_cycle = new_duty_cycle(&config);
+            let mut cpu_clock = new_cpu_clock(&config);
+            let mut timer = new_timer(&config);
+            let mut tick = 0;
+            while let Some(sleep) = timer.next() {
+                if paused_for(duty_cycle.as_ref(), &thread_paused).is_some() {
+                    if !wait_for_window(
+                        duty_cycle.as_ref(),
+                        &thread_paused,
+                        &roots,
+                        &tx,
+                        timer.rate(),
+                    ) {
+                        break;
+                    }
+                    timer = new_timer(&config);
+                }
+
                 let mut traces = Vec::new();
                 let mut sampling_errors = None;
+                let mut memory_counters = Vec::new();
+                let mut pauses = Vec::new();
+                let mut retried = Vec::new();
 
                 let mut spies = match spies.lock() {
                     Ok(current) => current,
@@ -193,22 +416,43 @@ impl Sampler {
                     }
                 };
 
-                // Notify all the initialized spies to generate a trace
+                // Notify all the initialized spies to generate a trace, so that each process is
+                // sampled in parallel on its own thread. Processes that haven't been sampled by the
+                // time the next sample is due are left out, rather than holding up the others
+                tick += 1;
+                let interval = Duration::from_secs_f64(1.0 / timer.rate() as f64);
+                let deadline = Instant::now() + interval;
                 for spy in spies.values_mut() {
                     if spy.initialized() {
-                        spy.notify();
+                        spy.notify(tick);
                     }
                 }
 
                 // collect the traces from each python spy if possible
                 for spy in spies.values_mut() {
-                    match spy.collect() {
-                        Some(Ok(mut t)) => traces.append(&mut t),
-                        Some(Err(e)) => {
+                    let sample = match spy.collect(tick, deadline) {
+                        Collected::Sample(sample) => sample,
+                        Collected::Missed => {
+                            let errors = sampling_errors.get_or_insert_with(Vec::new);
+                            let e = DeadlineMissed { deadline: interval };
+                            errors.push((spy.process.pid, e.into()));
+                            continue;
+                        }
+                        Collected::NotSampled => continue,
+                    };
+                    pauses.extend(sample.pause);
+                    retried.extend(sample.retried);
+                    match sample.result {
+                        Ok((mut t, counters)) => {
+                            traces.append(&mut t);
+                            if let Some(counters) = counters {
+                                memory_counters.push((spy.process.pid, counters));
+                            }
+                        }
+                        Err(e) => {
                             let errors = sampling_errors.get_or_insert_with(Vec::new);
                             errors.push((spy.process.pid, e));
                         }
-                        None => {}
                     }
                 }
 
@@ -222,6 +466,14 @@ impl Sampler {
                     trace.process_info = process.clone();
                 }
 
+                if let Some(cpu_clock) = cpu_clock.as_mut() {
+                    for pid in spies.keys() {
+                        // processes that have exited are picked up by their spy
+                        cpu_clock.update(*pid).ok();
+                    }
+                    traces = cpu_clock.select(traces);
+                }
+
                 // Send the collected info back
                 let late = sleep.err();
                 if tx
@@ -229,14 +481,21 @@ impl Sampler {
                         traces,
                         sampling_errors,
                         late,
+                        sampling_rate: timer.rate(),
+                        paused: false,
+                        memory_counters,
+                        pauses,
+                        retried,
                     })
                     .is_err()
                 {
                     break;
                 }
 
-                // If all of our spies have stopped, we're done
-                if spies.len() == 0 || spies.values().all(|x| !x.running) {
+                // If all of our spies have stopped, we're done. With --subprocesses, the processes
+                // that were asked for can still start python in a new subprocess
+                let stopped = spies.is_empty() || spies.values().all(|x| !x.running);
+                if stopped && !(config.subprocesses && roots.iter().any(|p| p.exe().is_ok())) {
                     break;
                 }
             }
@@ -245,7 +504,9 @@ impl Sampler {
         Ok(Sampler {
             rx: Some(rx),
             version: None,
+            metadata,
             sampling_thread: Some(sampling_thread),
+            paused,
         })
     }
 }
@@ -266,42 +527,81 @@ impl Drop for Sampler {
     }
 }
 
+/// Sets a process that has been failing to be sampled for SUSTAINED_FAILURE up to be sampled
+/// again, in case it has exec'ed a different python. Returns None if it can't be
+fn reinitialize(spy: PythonSpy) -> Option<PythonSpy> {
+    match PythonSpy::retry_new(spy.pid, &spy.config, 5) {
+        Ok(spy) => {
+            warn!(
+                "Set pid {} up to be sampled again after it failed to be sampled for {:?}",
+                spy.pid, SUSTAINED_FAILURE
+            );
+            Some(spy)
+        }
+        Err(e) => {
+            error!(
+                "Stopped sampling pid {} after it failed to be sampled for {:?}: {}",
+                spy.pid, SUSTAINED_FAILURE, e
+            );
+            None
+        }
+    }
+}
+
+/// The result of sampling a single process, sent back from its PythonSpyThread
+struct SpySample {
+    /// The sampling interval this sample was taken for
+    tick: u64,
+    result: Result<(Vec<StackTrace>, Option<MemoryCounters>), Error>,
+    /// How long the process was paused for to take the sample
+    pause: Option<Duration>,
+    /// The kind of each failure that was retried to take the sample
+    retried: Vec<FailureKind>,
+}
+
+enum Collected {
+    Sample(SpySample),
+    /// The process was notified, but wasn't sampled before the deadline
+    Missed,
+    /// The process wasn't notified, because it hasn't been initialized or has stopped running
+    NotSampled,
+}
+
 struct PythonSpyThread {
-    initialized_rx: Receiver<Result<Version, Error>>,
-    notify_tx: Sender<()>,
-    sample_rx: Receiver<Result<Vec<StackTrace>, Error>>,
-    initialized: Option<Result<Version, Error>>,
+    initialized_rx: Receiver<Result<Initialized, Error>>,
+    notify_tx: Sender<u64>,
+    sample_rx: Receiver<SpySample>,
+    initialized: Option<Result<Initialized, Error>>,
     pub running: bool,
-    notified: bool,
+    // the sampling interval that the process is being sampled for, if it hasn't sent it back yet
+    pending: Option<u64>,
     pub process: remoteprocess::Process,
     pub parent: Option<Pid>,
     pub command_line: String,
+    pub role: Option<String>,
+    // the executable the process was running when it was found
+    exe: Option<String>,
 }
 
 impl PythonSpyThread {
     fn new(pid: Pid, parent: Option<Pid>, config: &Config) -> Result<PythonSpyThread, Error> {
         let (initialized_tx, initialized_rx): (
-            Sender<Result<Version, Error>>,
-            Receiver<Result<Version, Error>>,
-        ) = mpsc::channel();
-        let (notify_tx, notify_rx): (Sender<()>, Receiver<()>) = mpsc::channel();
-        let (sample_tx, sample_rx): (
-            Sender<Result<Vec<StackTrace>, Error>>,
-            Receiver<Result<Vec<StackTrace>, Error>>,
+            Sender<Result<Initialized, Error>>,
+            Receiver<Result<Initialized, Error>>,
         ) = mpsc::channel();
+        let (notify_tx, notify_rx): (Sender<u64>, Receiver<u64>) = mpsc::channel();
+        let (sample_tx, sample_rx) = mpsc::channel();
         let config = config.clone();
         let process = remoteprocess::Process::new(pid)?;
-        let command_line = process
-            .cmdline()
-            .map(|x| x.join(" "))
-            .unwrap_or_else(|_| "".to_owned());
+        let (command_line, role) = command_line(&process, parent);
+        let exe = process.exe().ok();
 
         thread::spawn(move || {
             // We need to create this object inside the thread here since PythonSpy objects don't
             // have the Send trait implemented on linux
             let mut spy = match PythonSpy::retry_new(pid, &config, 5) {
                 Ok(spy) => {
-                    if initialized_tx.send(Ok(spy.version.clone())).is_err() {
+                    if initialized_tx.send(Ok(initialization(&spy))).is_err() {
                         return;
                     }
                     spy
@@ -313,16 +613,33 @@ impl PythonSpyThread {
                 }
             };
 
-            for _ in notify_rx.iter() {
-                let result = spy.get_stack_traces();
-                if result.is_err() && spy.process.exe().is_err() {
+            let mut failures = FailureTracker::new();
+            for tick in notify_rx.iter() {
+                let mut retried = Vec::new();
+                let result = with_retries(&mut retried, || spy.get_stack_traces());
+                if result.is_err() && process_exited(&spy.process) {
                     info!(
                         "stopped sampling pid {} because the process exited",
                         spy.pid
                     );
                     break;
                 }
-                if sample_tx.send(result).is_err() {
+                let pause = spy.last_pause;
+                if failures.update(result.as_ref().err()) {
+                    spy = match reinitialize(spy) {
+                        Some(spy) => spy,
+                        None => break,
+                    };
+                    failures.reset();
+                }
+                let result = result.map(|traces| (traces, spy.poll_memory_counters()));
+                let sample = SpySample {
+                    tick,
+                    result,
+                    pause,
+                    retried,
+                };
+                if sample_tx.send(sample).is_err() {
                     break;
                 }
             }
@@ -333,20 +650,21 @@ impl PythonSpyThread {
             sample_rx,
             process,
             command_line,
+            role,
             parent,
+            exe,
             initialized: None,
             running: false,
-            notified: false,
+            pending: None,
         })
     }
 
     fn wait_initialized(&mut self) -> bool {
+        if let Some(init) = self.initialized.as_ref() {
+            return init.is_ok();
+        }
         match self.initialized_rx.recv() {
-            Ok(status) => {
-                self.running = status.is_ok();
-                self.initialized = Some(status);
-                self.running
-            }
+            Ok(status) => self.set_initialized(status),
             Err(e) => {
                 // shouldn't happen, but will be ok if it does
                 warn!(
@@ -363,11 +681,7 @@ impl PythonSpyThread {
             return init.is_ok();
         }
         match self.initialized_rx.try_recv() {
-            Ok(status) => {
-                self.running = status.is_ok();
-                self.initialized = Some(status);
-                self.running
-            }
+            Ok(status) => self.set_initialized(status),
             Err(std::sync::mpsc::TryRecvError::Empty) => false,
             Err(std::sync::mpsc::TryRecvError::Disconnected) => {
                 // this *shouldn't* happen
@@ -377,10 +691,45 @@ impl PythonSpyThread {
         }
     }
 
-    fn notify(&mut self) {
-        match self.notify_tx.send(()) {
+    /// Whether the process wasn't running python when it was found, but has since exec'ed a
+    /// different program (like a shell script that execs python once it's set things up)
+    fn execed(&mut self) -> bool {
+        if self.initialized() || !matches!(self.initialized, Some(Err(_))) {
+            return false;
+        }
+        self.process
+            .exe()
+            .is_ok_and(|exe| Some(&exe) != self.exe.as_ref())
+    }
+
+    /// The metadata read when the process was initialized, if it was asked for
+    fn metadata(&self) -> Option<ProcessMetadata> {
+        match self.initialized.as_ref() {
+            Some(Ok((_, metadata))) => metadata.clone(),
+            _ => None,
+        }
+    }
+
+    fn set_initialized(&mut self, status: Result<Initialized, Error>) -> bool {
+        // a process we spawned can be found before it has run python, when its command line is
+        // still empty
+        if status.is_ok() && self.command_line.is_empty() {
+            (self.command_line, self.role) = command_line(&self.process, self.parent);
+        }
+        self.running = status.is_ok();
+        self.initialized = Some(status);
+        self.running
+    }
+
+    /// Starts sampling the process for a sampling interval. Processes that are still being
+    /// sampled for an earlier interval are skipped, and miss this one
+    fn notify(&mut self, tick: u64) {
+        if self.pending.is_some() {
+            return;
+        }
+        match self.notify_tx.send(tick) {
             Ok(_) => {
-                self.notified = true;
+                self.pending = Some(tick);
             }
             Err(_) => {
                 self.running = false;
@@ -388,19 +737,114 @@ impl PythonSpyThread {
         }
     }
 
-    fn collect(&mut self) -> Option<Result<Vec<StackTrace>, Error>> {
-        if !self.notified {
-            return None;
+    /// Waits until the deadline for the process to be sampled for a sampling interval. A sample
+    /// that comes back for an earlier interval is dropped, since it was taken too long ago to be
+    /// combined with the samples of the other processes
+    fn collect(&mut self, tick: u64, deadline: Instant) -> Collected {
+        if self.pending.is_none() {
+            return Collected::NotSampled;
         }
-        self.notified = false;
-        match self.sample_rx.recv() {
-            Ok(sample) => Some(sample),
-            Err(_) => {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        match self.sample_rx.recv_timeout(timeout) {
+            Ok(sample) => {
+                self.pending = None;
+                if sample.tick == tick {
+                    Collected::Sample(sample)
+                } else {
+                    Collected::Missed
+                }
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => Collected::Missed,
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                self.pending = None;
                 self.running = false;
+                Collected::NotSampled
+            }
+        }
+    }
+}
+
+/// The version of python a process is running, and its metadata if it's asked for
+type Initialized = (Version, Option<ProcessMetadata>);
+
+fn initialization(spy: &PythonSpy) -> Initialized {
+    let metadata = if spy.config.process_metadata {
+        match read_process_metadata(spy, &spy.config.metadata_env) {
+            Ok(metadata) => Some(metadata),
+            Err(e) => {
+                warn!("Failed to read metadata from process {}: {}", spy.pid, e);
                 None
             }
         }
+    } else {
+        None
+    };
+    (spy.version.clone(), metadata)
+}
+
+/// The child processes of each process (along with their parents), when sampling subprocesses.
+/// Processes that have exited don't have any
+fn child_processes(
+    processes: &[remoteprocess::Process],
+    config: &Config,
+) -> Result<Vec<(Pid, Pid)>, Error> {
+    let mut children = Vec::new();
+    if !config.subprocesses {
+        return Ok(children);
+    }
+    for process in processes {
+        match process.child_processes() {
+            Ok(mut pids) => children.append(&mut pids),
+            Err(e) if processes.len() == 1 => return Err(e.into()),
+            Err(_) => {}
+        }
     }
+    Ok(children)
+}
+
+/// Starts sampling the child processes that haven't been seen before, and starts again on the
+/// processes that weren't running python when they were seen but have since exec'ed a different
+/// program
+fn add_spies(
+    spies: &mut HashMap<Pid, PythonSpyThread>,
+    pids: &[Pid],
+    children: Vec<(Pid, Pid)>,
+    config: &Config,
+) {
+    let processes = pids.iter().map(|&pid| (pid, None)).chain(
+        children
+            .into_iter()
+            .map(|(pid, parent)| (pid, Some(parent))),
+    );
+    for (pid, parent) in processes {
+        if spies.get_mut(&pid).is_some_and(|spy| !spy.execed()) {
+            continue;
+        }
+        // If we can't create the child process, don't worry about it
+        // can happen with zombie child processes etc
+        match PythonSpyThread::new(pid, parent, config) {
+            Ok(spy) => {
+                spies.insert(pid, spy);
+            }
+            Err(e) => {
+                warn!("Failed to open process {}: {}", pid, e);
+            }
+        }
+    }
+}
+
+/// The command line of a process, and the role it plays in a server that forks workers
+fn command_line(process: &remoteprocess::Process, parent: Option<Pid>) -> (String, Option<String>) {
+    let command_line = process
+        .cmdline()
+        .map(|x| x.join(" "))
+        .unwrap_or_else(|_| "".to_owned());
+    let parent_command_line = parent
+        .and_then(|parent| remoteprocess::Process::new(parent).ok())
+        .and_then(|parent| parent.cmdline().ok())
+        .map(|x| x.join(" "));
+    let role = process_role(&command_line, parent_command_line.as_deref());
+    (command_line, role)
 }
 
 fn get_process_info(pid: Pid, spies: &HashMap<Pid, PythonSpyThread>) -> Option<Box<ProcessInfo>> {
@@ -412,6 +856,7 @@ fn get_process_info(pid: Pid, spies: &HashMap<Pid, PythonSpyThread>) -> Option<B
             pid,
             parent,
             command_line: spy.command_line.clone(),
+            role: spy.role.clone(),
         })
     })
 }
@@ -0,0 +1,263 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::sampler::Sample;
+use crate::FailureKind;
+
+/*
+ * This file contains code to keep statistics about how well sampling went over a recording,
+ * so that a profile can be trusted (or not): how close the sampling rate got to the requested
+ * rate, how many samples were late, abandoned or failed (and why), and how long the process was
+ * paused for to take each sample.
+ *
+ * These are printed at the end of a recording, and stored in the outputs that have somewhere to
+ * put metadata (capture, sqlite, pprof, speedscope and chrometrace).
+ */
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SamplerStats {
+    /// The number of samples a second that were asked for
+    pub requested_rate: u64,
+    /// The number of times the processes were sampled
+    pub samples: u64,
+    /// How long sampling went on for in seconds, not counting the time it was paused for
+    pub sampling_time: f64,
+    /// The number of samples that were taken behind schedule
+    pub late_samples: u64,
+    /// The number of samples abandoned for taking longer than --max-pause-us
+    pub abandoned_samples: u64,
+    /// The number of samples that failed, usually from reading a partial stack
+    pub errors: u64,
+    /// The number of times a process was paused to take a sample, and for how long in total and
+    /// at most (in microseconds)
+    pub pauses: u64,
+    pub total_pause_us: u64,
+    pub max_pause_us: u64,
+    /// The number of failed reads that were retried
+    #[serde(default)]
+    pub retries: u64,
+    /// The number of samples that failed even after being retried, by the kind of failure
+    #[serde(default)]
+    pub failures: BTreeMap<String, u64>,
+}
+
+impl SamplerStats {
+    pub fn new(requested_rate: u64) -> SamplerStats {
+        SamplerStats {
+            requested_rate,
+            ..Default::default()
+        }
+    }
+
+    /// Records a sample that wasn't paused. Errors are counted separately, since the sample is
+    /// consumed when they're reported
+    pub fn add_sample(&mut self, sample: &Sample) {
+        self.samples += 1;
+        if sample.late.is_some() {
+            self.late_samples += 1;
+        }
+        self.retries += sample.retried.len() as u64;
+        for (_, e) in sample.sampling_errors.iter().flatten() {
+            let kind = FailureKind::classify(e);
+            if kind != FailureKind::PauseBudget {
+                *self.failures.entry(kind.as_str().to_owned()).or_default() += 1;
+            }
+        }
+        for pause in &sample.pauses {
+            let pause = pause.as_micros() as u64;
+            self.pauses += 1;
+            self.total_pause_us += pause;
+            self.max_pause_us = self.max_pause_us.max(pause);
+        }
+    }
+
+    /// Adds the counts from another recording to these, when merging recordings
+    pub fn merge(&mut self, other: &SamplerStats) {
+        self.requested_rate = self.requested_rate.max(other.requested_rate);
+        self.samples += other.samples;
+        self.sampling_time += other.sampling_time;
+        self.late_samples += other.late_samples;
+        self.abandoned_samples += other.abandoned_samples;
+        self.errors += other.errors;
+        self.pauses += other.pauses;
+        self.total_pause_us += other.total_pause_us;
+        self.max_pause_us = self.max_pause_us.max(other.max_pause_us);
+        self.retries += other.retries;
+        for (kind, count) in &other.failures {
+            *self.failures.entry(kind.clone()).or_default() += count;
+        }
+    }
+
+    /// The number of samples taken a second on average
+    pub fn achieved_rate(&self) -> f64 {
+        if self.sampling_time > 0.0 {
+            self.samples as f64 / self.sampling_time
+        } else {
+            0.0
+        }
+    }
+
+    /// How long a process was paused for to take a sample on average
+    pub fn average_pause(&self) -> Option<Duration> {
+        self.total_pause_us
+            .checked_div(self.pauses)
+            .map(Duration::from_micros)
+    }
+
+    /// The statistics as (key, value) pairs, for outputs that store metadata
+    pub fn metadata(&self) -> Vec<(&'static str, String)> {
+        let mut metadata = vec![
+            ("requested_rate", self.requested_rate.to_string()),
+            ("achieved_rate", format!("{:.2}", self.achieved_rate())),
+            ("samples", self.samples.to_string()),
+            ("sampling_time", format!("{:.3}", self.sampling_time)),
+            ("late_samples", self.late_samples.to_string()),
+            ("abandoned_samples", self.abandoned_samples.to_string()),
+            ("errors", self.errors.to_string()),
+        ];
+        if let Some(average) = self.average_pause() {
+            metadata.push(("average_pause_us", average.as_micros().to_string()));
+            metadata.push(("max_pause_us", self.max_pause_us.to_string()));
+        }
+        metadata.push(("retries", self.retries.to_string()));
+        if !self.failures.is_empty() {
+            metadata.push(("failures", self.failure_counts("=", ",")));
+        }
+        metadata
+    }
+
+    /// The number of failures of each kind, like "bad_address=3,exited=1"
+    fn failure_counts(&self, separator: &str, delimiter: &str) -> String {
+        self.failures
+            .iter()
+            .map(|(kind, count)| format!("{}{}{}", kind, separator, count))
+            .collect::<Vec<_>>()
+            .join(delimiter)
+    }
+
+    /// A one line summary of the statistics, printed at the end of a recording
+    pub fn summary(&self) -> String {
+        let mut summary = format!(
+            "Sampled {:.1} times a second on average ({} requested), with {} late samples, {} abandoned and {} errors",
+            self.achieved_rate(),
+            self.requested_rate,
+            self.late_samples,
+            self.abandoned_samples,
+            self.errors
+        );
+        if let Some(average) = self.average_pause() {
+            summary += &format!(
+                ". Paused the process for {:.2?} on average per sample ({:.2?} at most)",
+                average,
+                Duration::from_micros(self.max_pause_us)
+            );
+        }
+        if self.retries > 0 {
+            summary += &format!(". Retried {} failed reads", self.retries);
+        }
+        if !self.failures.is_empty() {
+            summary += &format!(" (failures: {})", self.failure_counts(" ", ", "));
+        }
+        summary
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(late: bool, pauses: &[u64]) -> Sample {
+        Sample {
+            traces: Vec::new(),
+            sampling_errors: None,
+            late: late.then(|| Duration::from_millis(5)),
+            sampling_rate: 100,
+            paused: false,
+            memory_counters: Vec::new(),
+            pauses: pauses.iter().map(|us| Duration::from_micros(*us)).collect(),
+            retried: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_sampler_stats() {
+        let mut stats = SamplerStats::new(100);
+        assert_eq!(stats.average_pause(), None);
+        stats.add_sample(&sample(false, &[100, 300]));
+        stats.add_sample(&sample(true, &[50]));
+        stats.add_sample(&sample(false, &[]));
+        stats.sampling_time = 0.5;
+        stats.errors = 1;
+
+        assert_eq!(stats.samples, 3);
+        assert_eq!(stats.late_samples, 1);
+        assert_eq!(stats.achieved_rate(), 6.0);
+        assert_eq!(stats.average_pause(), Some(Duration::from_micros(150)));
+        assert_eq!(stats.max_pause_us, 300);
+
+        let metadata = stats.metadata();
+        assert!(metadata.contains(&("achieved_rate", String::from("6.00"))));
+        assert!(metadata.contains(&("errors", String::from("1"))));
+        assert!(metadata.contains(&("max_pause_us", String::from("300"))));
+        assert!(metadata.contains(&("retries", String::from("0"))));
+    }
+
+    #[test]
+    fn test_merge_sampler_stats() {
+        let mut stats = SamplerStats::new(100);
+        stats.add_sample(&sample(false, &[100, 300]));
+        stats.sampling_time = 1.0;
+        stats.errors = 1;
+        stats.failures.insert("exited".to_owned(), 1);
+
+        let mut other = SamplerStats::new(50);
+        other.add_sample(&sample(true, &[500]));
+        other.sampling_time = 2.0;
+        other.errors = 2;
+        other.failures.insert("exited".to_owned(), 2);
+        stats.merge(&other);
+
+        assert_eq!(stats.requested_rate, 100);
+        assert_eq!(stats.samples, 2);
+        assert_eq!(stats.sampling_time, 3.0);
+        assert_eq!(stats.late_samples, 1);
+        assert_eq!(stats.errors, 3);
+        assert_eq!(stats.pauses, 3);
+        assert_eq!(stats.max_pause_us, 500);
+        assert_eq!(stats.failures.get("exited"), Some(&3));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_sampler_stats_failures() {
+        let os_error = |code| {
+            anyhow::Error::from(remoteprocess::Error::IOError(
+                std::io::Error::from_raw_os_error(code),
+            ))
+        };
+        let mut stats = SamplerStats::new(100);
+        let mut failed = sample(false, &[]);
+        failed.retried = vec![FailureKind::BadAddress, FailureKind::BadAddress];
+        failed.sampling_errors = Some(vec![
+            (1, os_error(libc::EFAULT)),
+            (2, os_error(libc::ESRCH)),
+        ]);
+        stats.add_sample(&failed);
+
+        let mut retried = sample(false, &[]);
+        retried.retried = vec![FailureKind::Exited];
+        stats.add_sample(&retried);
+
+        assert_eq!(stats.retries, 3);
+        assert_eq!(stats.failures.get("bad_address"), Some(&1));
+        assert_eq!(stats.failures.get("exited"), Some(&1));
+        assert!(stats
+            .metadata()
+            .contains(&("failures", String::from("bad_address=1,exited=1"))));
+        assert!(stats
+            .summary()
+            .ends_with("Retried 3 failed reads (failures: bad_address 1, exited 1)"));
+    }
+}
@@ -0,0 +1,99 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::Error;
+
+/*
+ * This file contains code to control a recording with signals, so that scripts can split a long
+ * recording up into the parts they care about:
+ *
+ *  SIGUSR1 - pauses sampling, or resumes it if it was paused
+ *  SIGUSR2 - writes out everything recorded so far, and carries on recording
+ *
+ * The signal handlers only set a flag, which the recording loop checks after each sample. This
+ * is only supported on unix.
+ *
+ * 'py-spy agent' also handles SIGTERM and SIGINT (or Control-C on windows) this way, so that it
+ * can finish writing out the recordings it's running before it exits.
+ */
+
+static TOGGLE_REQUESTED: AtomicBool = AtomicBool::new(false);
+static CHECKPOINT_REQUESTED: AtomicBool = AtomicBool::new(false);
+static TERMINATE_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+extern "C" fn handle_signal(signal: libc::c_int) {
+    match signal {
+        libc::SIGUSR1 => TOGGLE_REQUESTED.store(true, Ordering::SeqCst),
+        libc::SIGUSR2 => CHECKPOINT_REQUESTED.store(true, Ordering::SeqCst),
+        libc::SIGTERM | libc::SIGINT => TERMINATE_REQUESTED.store(true, Ordering::SeqCst),
+        _ => {}
+    }
+}
+
+/// Installs the handlers for SIGUSR1 and SIGUSR2
+#[cfg(unix)]
+pub fn install() -> Result<(), Error> {
+    install_handlers(&[libc::SIGUSR1, libc::SIGUSR2])
+}
+
+#[cfg(not(unix))]
+pub fn install() -> Result<(), Error> {
+    Ok(())
+}
+
+/// Installs the handlers for SIGTERM and SIGINT, instead of them exiting straight away
+#[cfg(unix)]
+pub fn install_terminate() -> Result<(), Error> {
+    install_handlers(&[libc::SIGTERM, libc::SIGINT])
+}
+
+#[cfg(not(unix))]
+pub fn install_terminate() -> Result<(), Error> {
+    ctrlc::set_handler(|| TERMINATE_REQUESTED.store(true, Ordering::SeqCst))?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn install_handlers(signals: &[libc::c_int]) -> Result<(), Error> {
+    for signal in signals {
+        let handler = handle_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        if unsafe { libc::signal(*signal, handler) } == libc::SIG_ERR {
+            return Err(std::io::Error::last_os_error().into());
+        }
+    }
+    Ok(())
+}
+
+/// Whether SIGUSR1 has been received since this was last called
+pub fn toggle_requested() -> bool {
+    TOGGLE_REQUESTED.swap(false, Ordering::SeqCst)
+}
+
+/// Whether SIGUSR2 has been received since this was last called
+pub fn checkpoint_requested() -> bool {
+    CHECKPOINT_REQUESTED.swap(false, Ordering::SeqCst)
+}
+
+/// Whether SIGTERM or SIGINT has been received
+pub fn terminate_requested() -> bool {
+    TERMINATE_REQUESTED.load(Ordering::SeqCst)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_signals() {
+        install().unwrap();
+        assert!(!toggle_requested());
+        unsafe { libc::raise(libc::SIGUSR1) };
+        assert!(toggle_requested());
+        // each signal is only handled once
+        assert!(!toggle_requested());
+        assert!(!checkpoint_requested());
+        unsafe { libc::raise(libc::SIGUSR2) };
+        assert!(checkpoint_requested());
+    }
+}
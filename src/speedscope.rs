@@ -29,6 +29,7 @@ SOFTWARE.
 use std::collections::HashMap;
 use std::io;
 use std::io::Write;
+use std::time::Instant;
 
 use crate::stack_trace;
 use remoteprocess::{Pid, Tid};
@@ -37,6 +38,10 @@ use anyhow::Error;
 use serde_derive::{Deserialize, Serialize};
 
 use crate::config::Config;
+use crate::process_tree::{forked_from, ProcessNode, ProcessTree};
+use crate::sampler_stats::SamplerStats;
+use crate::MemoryCounters;
+use crate::ProcessMetadata;
 
 /*
  * This file contains code to export rbspy profiles for use in https://speedscope.app
@@ -52,8 +57,13 @@ use crate::config::Config;
  * given JSON schema.
  *
  * There are multiple variants of the file format. The variant we're going to generate
- * is the "type: sampled" profile, since it most closely maps to rbspy's data recording
- * structure.
+ * by default is the "type: sampled" profile, since it most closely maps to rbspy's data recording
+ * structure. With --evented, we instead generate "type: evented" profiles, where frames are
+ * opened and closed at the time they were first and last seen on the stack (like the
+ * chrometrace output), giving a time ordered view of each thread.
+ *
+ * The memory counters read with --memory-counters aren't part of the speedscope format, so are
+ * written to an extra 'counters' field that speedscope ignores.
  */
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -69,6 +79,30 @@ struct SpeedscopeFile {
     exporter: Option<String>,
 
     name: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    counters: Vec<Counters>,
+
+    // where the profile came from, which speedscope itself ignores
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    metadata: Option<ProcessMetadata>,
+
+    // how well sampling went over the recording, which speedscope also ignores
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    stats: Option<SamplerStats>,
+
+    // the tree of processes sampled with --subprocesses, which speedscope also ignores
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    processes: Vec<ProcessNode>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+struct Counters {
+    pid: Pid,
+    // microseconds since the start of the recording
+    timestamp: u64,
+    #[serde(flatten)]
+    counters: MemoryCounters,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -85,8 +119,29 @@ struct Profile {
     #[serde(rename = "endValue")]
     end_value: f64,
 
+    // sampled profiles have samples and weights, while evented profiles have events
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     samples: Vec<Vec<usize>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     weights: Vec<f64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    events: Vec<Event>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Event {
+    #[serde(rename = "type")]
+    event_type: EventType,
+    at: f64,
+    frame: usize,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+enum EventType {
+    #[serde(rename = "O")]
+    OpenFrame,
+    #[serde(rename = "C")]
+    CloseFrame,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -154,12 +209,57 @@ impl SpeedscopeFile {
                     end_value: scaled_end_value,
                     samples: samples.clone(),
                     weights,
+                    events: Vec::new(),
                 }
             })
             .collect();
 
         profiles.sort_by(|a, b| a.name.cmp(&b.name));
+        SpeedscopeFile::from_profiles(profiles, frames)
+    }
 
+    pub fn evented(
+        threads: &HashMap<(Pid, Tid), ThreadEvents>,
+        frames: &[Frame],
+        thread_name_map: &HashMap<(Pid, Tid), String>,
+        sample_rate: u64,
+    ) -> SpeedscopeFile {
+        // the last sample for each thread is assumed to last for one sampling interval
+        let interval = 1_000_000 / sample_rate.max(1);
+        let mut profiles: Vec<Profile> = threads
+            .iter()
+            .map(|(thread_id, thread)| {
+                let end = thread.end + interval;
+                let mut events = thread.events.clone();
+                // close any frames that are still open at the end of the recording
+                for frame in thread.stack.iter().rev() {
+                    events.push(Event {
+                        event_type: EventType::CloseFrame,
+                        at: end as f64,
+                        frame: *frame,
+                    });
+                }
+
+                Profile {
+                    profile_type: ProfileType::Evented,
+                    name: thread_name_map
+                        .get(thread_id)
+                        .map_or_else(|| "py-spy".to_string(), |x| x.clone()),
+                    unit: ValueUnit::Microseconds,
+                    start_value: thread.start as f64,
+                    end_value: end as f64,
+                    samples: Vec::new(),
+                    weights: Vec::new(),
+                    events,
+                }
+            })
+            .collect();
+
+        profiles.sort_by(|a, b| a.name.cmp(&b.name));
+        SpeedscopeFile::from_profiles(profiles, frames)
+    }
+
+    fn from_profiles(profiles: Vec<Profile>, frames: &[Frame]) -> SpeedscopeFile {
         SpeedscopeFile {
             // This is always the same
             schema: "https://www.speedscope.app/file-format-schema.json".to_string(),
@@ -170,6 +270,10 @@ impl SpeedscopeFile {
             shared: Shared {
                 frames: frames.to_owned(),
             },
+            counters: Vec::new(),
+            metadata: None,
+            stats: None,
+            processes: Vec::new(),
         }
     }
 }
@@ -190,26 +294,59 @@ impl Frame {
     }
 }
 
+/// The open frames and events for a thread, for evented profiles
+#[derive(Default)]
+struct ThreadEvents {
+    events: Vec<Event>,
+    // frame indices currently open, outermost first
+    stack: Vec<usize>,
+    // timestamps (in microseconds) of the first and last samples for the thread
+    start: u64,
+    end: u64,
+}
+
 pub struct Stats {
     samples: HashMap<(Pid, Tid), Vec<Vec<usize>>>,
+    threads: HashMap<(Pid, Tid), ThreadEvents>,
     frames: Vec<Frame>,
     frame_to_index: HashMap<stack_trace::Frame, usize>,
     thread_name_map: HashMap<(Pid, Tid), String>,
+    counters: Vec<Counters>,
+    metadata: Option<ProcessMetadata>,
+    stats: Option<SamplerStats>,
+    processes: ProcessTree,
     config: Config,
+    start_ts: Instant,
 }
 
 impl Stats {
     pub fn new(config: &Config) -> Stats {
         Stats {
             samples: HashMap::new(),
+            threads: HashMap::new(),
             frames: vec![],
             frame_to_index: HashMap::new(),
             thread_name_map: HashMap::new(),
+            counters: Vec::new(),
+            metadata: None,
+            stats: None,
+            processes: ProcessTree::default(),
             config: config.clone(),
+            start_ts: Instant::now(),
         }
     }
 
     pub fn record(&mut self, stack: &stack_trace::StackTrace) -> Result<(), io::Error> {
+        let now = self.start_ts.elapsed().as_micros() as u64;
+        self.record_at(stack, now)
+    }
+
+    /// Records a trace sampled at a given time, in microseconds since the start of the recording
+    pub fn record_at(
+        &mut self,
+        stack: &stack_trace::StackTrace,
+        timestamp: u64,
+    ) -> Result<(), io::Error> {
         let show_line_numbers = self.config.show_line_numbers;
         let mut frame_indices: Vec<usize> = stack
             .frames
@@ -231,14 +368,58 @@ impl Stats {
 
         let key = (stack.pid as Pid, stack.thread_id as Tid);
 
-        self.samples.entry(key).or_default().push(frame_indices);
+        if self.config.speedscope_evented {
+            let thread = self.threads.entry(key).or_insert_with(|| ThreadEvents {
+                start: timestamp,
+                ..Default::default()
+            });
+            thread.end = timestamp;
+            let at = timestamp as f64;
+
+            // close the frames that are no longer on the stack, and open the new ones
+            let common = thread
+                .stack
+                .iter()
+                .zip(frame_indices.iter())
+                .take_while(|(a, b)| a == b)
+                .count();
+            for frame in thread.stack.drain(common..).rev() {
+                thread.events.push(Event {
+                    event_type: EventType::CloseFrame,
+                    at,
+                    frame,
+                });
+            }
+            for frame in &frame_indices[common..] {
+                thread.events.push(Event {
+                    event_type: EventType::OpenFrame,
+                    at,
+                    frame: *frame,
+                });
+                thread.stack.push(*frame);
+            }
+        } else {
+            self.samples.entry(key).or_default().push(frame_indices);
+        }
+        if let Some(process_info) = stack.process_info.as_ref() {
+            self.processes.record(process_info, timestamp);
+        }
         let subprocesses = self.config.subprocesses;
         self.thread_name_map.entry(key).or_insert_with(|| {
             let thread_name = stack
                 .thread_name
                 .as_ref()
                 .map_or_else(|| "".to_string(), |x| x.clone());
-            if subprocesses {
+            let forked_from = stack.process_info.as_deref().and_then(forked_from);
+            if let (true, Some(forked_from)) = (subprocesses, forked_from) {
+                format!(
+                    "Process {} ({}) Thread {} \"{}\"",
+                    stack.pid,
+                    forked_from,
+                    stack.format_threadid(),
+                    thread_name
+                )
+            } else if subprocesses {
                 format!(
                     "Process {} Thread {} \"{}\"",
                     stack.pid,
@@ -253,13 +434,46 @@ impl Stats {
         Ok(())
     }
 
+    /// Records the memory counters of a process, read at a given time
+    pub fn add_memory_counters(&mut self, pid: Pid, counters: &MemoryCounters, timestamp: u64) {
+        self.counters.push(Counters {
+            pid,
+            timestamp,
+            counters: counters.clone(),
+        });
+    }
+
+    /// Stores where the profile came from in the file
+    pub fn set_metadata(&mut self, metadata: &ProcessMetadata) {
+        self.metadata = Some(metadata.clone());
+    }
+
+    /// Stores the statistics of the sampler in the file, once recording has finished
+    pub fn set_stats(&mut self, stats: &SamplerStats) {
+        self.stats = Some(stats.clone());
+    }
+
     pub fn write(&self, w: &mut dyn Write) -> Result<(), Error> {
-        let json = serde_json::to_string(&SpeedscopeFile::new(
-            &self.samples,
-            &self.frames,
-            &self.thread_name_map,
-            self.config.sampling_rate,
-        ))?;
+        let mut file = if self.config.speedscope_evented {
+            SpeedscopeFile::evented(
+                &self.threads,
+                &self.frames,
+                &self.thread_name_map,
+                self.config.sampling_rate,
+            )
+        } else {
+            SpeedscopeFile::new(
+                &self.samples,
+                &self.frames,
+                &self.thread_name_map,
+                self.config.sampling_rate,
+            )
+        };
+        file.counters = self.counters.clone();
+        file.metadata = self.metadata.clone();
+        file.stats = self.stats.clone();
+        file.processes = self.processes.processes();
+        let json = serde_json::to_string(&file)?;
         writeln!(w, "{}", json)?;
         Ok(())
     }
@@ -268,41 +482,33 @@ impl Stats {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::testing::{frame, trace};
     use std::io::{Cursor, Read, Seek, SeekFrom};
 
     #[test]
     fn test_speedscope_units() {
         let sample_rate = 100;
-        let config = Config {
-            show_line_numbers: true,
-            sampling_rate: sample_rate,
-            ..Default::default()
-        };
+        let mut config = Config::default();
+        config.show_line_numbers = true;
+        config.sampling_rate = sample_rate;
         let mut stats = Stats::new(&config);
         let mut cursor = Cursor::new(Vec::new());
 
-        let frame = stack_trace::Frame {
-            name: String::from("test"),
-            filename: String::from("test.py"),
-            module: None,
-            short_filename: None,
-            line: 0,
-            locals: None,
-            is_entry: true,
-        };
-
-        let trace = stack_trace::StackTrace {
-            pid: 1,
-            thread_id: 1,
-            thread_name: None,
-            os_thread_id: None,
-            active: true,
-            owns_gil: false,
-            frames: vec![frame],
-            process_info: None,
-        };
+        let trace = trace(vec![frame("test", "test.py", 0)]);
 
         stats.record(&trace).unwrap();
+        let counters = MemoryCounters {
+            rss: Some(1 << 20),
+            arenas: None,
+            gc_counts: Some(vec![10, 0, 0]),
+        };
+        stats.add_memory_counters(1, &counters, 5000);
+        let sampler_stats = SamplerStats {
+            samples: 1,
+            retries: 2,
+            ..SamplerStats::new(sample_rate)
+        };
+        stats.set_stats(&sampler_stats);
         stats.write(&mut cursor).unwrap();
 
         cursor.seek(SeekFrom::Start(0)).unwrap();
@@ -313,5 +519,56 @@ mod tests {
 
         assert_eq!(trace.profiles[0].unit, ValueUnit::Seconds);
         assert_eq!(trace.profiles[0].end_value, 1.0 / sample_rate as f64);
+        assert_eq!(
+            trace.counters,
+            vec![Counters {
+                pid: 1,
+                timestamp: 5000,
+                counters
+            }]
+        );
+        assert_eq!(trace.stats, Some(sampler_stats));
+    }
+
+    #[test]
+    fn test_speedscope_evented() {
+        let mut config = Config::default();
+        config.show_line_numbers = true;
+        config.speedscope_evented = true;
+        let mut stats = Stats::new(&config);
+        let mut trace = trace(vec![frame("a", "test.py", 1), frame("main", "test.py", 1)]);
+        stats.record_at(&trace, 100).unwrap();
+        stats.record_at(&trace, 200).unwrap();
+        trace.frames = vec![frame("b", "test.py", 1), frame("main", "test.py", 1)];
+        stats.record_at(&trace, 300).unwrap();
+
+        let mut out = Vec::new();
+        stats.write(&mut out).unwrap();
+        let file: SpeedscopeFile = serde_json::from_slice(&out).unwrap();
+        let profile = &file.profiles[0];
+        assert_eq!(profile.unit, ValueUnit::Microseconds);
+        assert_eq!(profile.start_value, 100.0);
+        assert_eq!(profile.end_value, 300.0 + 10_000.0);
+        assert!(profile.samples.is_empty());
+
+        let events: Vec<(EventType, f64, &str)> = profile
+            .events
+            .iter()
+            .map(|e| {
+                let name = file.shared.frames[e.frame].name.as_str();
+                (e.event_type, e.at, name)
+            })
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                (EventType::OpenFrame, 100.0, "main"),
+                (EventType::OpenFrame, 100.0, "a"),
+                (EventType::CloseFrame, 300.0, "a"),
+                (EventType::OpenFrame, 300.0, "b"),
+                (EventType::CloseFrame, 10_300.0, "b"),
+                (EventType::CloseFrame, 10_300.0, "main"),
+            ]
+        );
     }
 }
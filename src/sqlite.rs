@@ -0,0 +1,317 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::time::Instant;
+
+use anyhow::Error;
+use rusqlite::{params, Connection};
+use tempfile::NamedTempFile;
+
+use crate::sampler_stats::SamplerStats;
+use crate::stack_trace::{Frame, StackTrace};
+
+/*
+ * This file contains code to write samples out to a SQLite database, for long recordings
+ * that would otherwise need to be held in memory, and for ad-hoc analysis with SQL.
+ *
+ * Samples are inserted into a temporary database file as they are collected, and the
+ * database is copied to the output file when the recording finishes. Frames and stacks are
+ * deduplicated, so that each sample only needs to store a single stack id:
+ *
+ *  frames(id, name, filename, module, line)
+ *  stacks(id, leaf_frame_id, num_frames)
+ *  stack_frames(stack_id, depth, frame_id) - depth 0 is the outermost frame
+ *  samples(id, timestamp, pid, thread_id, os_thread_id, thread_name, stack_id, active, owns_gil)
+ *  metadata(key, value)
+ */
+
+const SCHEMA: &str = "
+CREATE TABLE metadata (key TEXT PRIMARY KEY, value TEXT NOT NULL);
+CREATE TABLE frames (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL,
+    filename TEXT NOT NULL,
+    module TEXT,
+    line INTEGER NOT NULL
+);
+CREATE TABLE stacks (
+    id INTEGER PRIMARY KEY,
+    leaf_frame_id INTEGER REFERENCES frames(id),
+    num_frames INTEGER NOT NULL
+);
+CREATE TABLE stack_frames (
+    stack_id INTEGER NOT NULL REFERENCES stacks(id),
+    depth INTEGER NOT NULL,
+    frame_id INTEGER NOT NULL REFERENCES frames(id),
+    PRIMARY KEY (stack_id, depth)
+);
+CREATE TABLE samples (
+    id INTEGER PRIMARY KEY,
+    timestamp INTEGER NOT NULL,
+    pid INTEGER NOT NULL,
+    thread_id INTEGER NOT NULL,
+    os_thread_id INTEGER,
+    thread_name TEXT,
+    stack_id INTEGER NOT NULL REFERENCES stacks(id),
+    active INTEGER NOT NULL,
+    owns_gil INTEGER NOT NULL
+);
+";
+
+// indices are created when the output is first written, which is quicker than updating them on
+// every insert. Checkpoints write the output more than once, so they might already exist
+const INDICES: &str = "
+CREATE INDEX IF NOT EXISTS samples_stack_id ON samples(stack_id);
+CREATE INDEX IF NOT EXISTS samples_timestamp ON samples(timestamp);
+CREATE INDEX IF NOT EXISTS samples_thread ON samples(pid, thread_id);
+CREATE INDEX IF NOT EXISTS stack_frames_frame_id ON stack_frames(frame_id);
+CREATE INDEX IF NOT EXISTS stacks_leaf_frame_id ON stacks(leaf_frame_id);
+CREATE INDEX IF NOT EXISTS frames_name ON frames(name);
+";
+
+// how many samples to insert before committing the current transaction
+const BATCH_SIZE: u64 = 10000;
+
+pub struct Sqlite {
+    file: NamedTempFile,
+    conn: Connection,
+    // (name, filename, module, line) -> frame id
+    frame_ids: HashMap<(String, String, Option<String>, i32), i64>,
+    // frame ids, leaf first -> stack id
+    stack_ids: HashMap<Vec<i64>, i64>,
+    samples: u64,
+    start_ts: Instant,
+    show_linenumbers: bool,
+}
+
+impl Sqlite {
+    pub fn new(show_linenumbers: bool, sampling_rate: u64) -> Result<Sqlite, Error> {
+        let file = NamedTempFile::new()?;
+        let conn = Connection::open(file.path())?;
+        // we're only ever writing to a temporary file, so we don't need to be durable
+        conn.execute_batch("PRAGMA journal_mode = OFF; PRAGMA synchronous = OFF;")?;
+        conn.execute_batch(SCHEMA)?;
+
+        let metadata = [
+            ("exporter", format!("py-spy@{}", env!("CARGO_PKG_VERSION"))),
+            ("sampling_rate", sampling_rate.to_string()),
+            ("show_line_numbers", show_linenumbers.to_string()),
+        ];
+        for (key, value) in metadata.iter() {
+            conn.execute(
+                "INSERT INTO metadata (key, value) VALUES (?1, ?2)",
+                params![key, value],
+            )?;
+        }
+
+        conn.execute_batch("BEGIN")?;
+        Ok(Sqlite {
+            file,
+            conn,
+            frame_ids: HashMap::new(),
+            stack_ids: HashMap::new(),
+            samples: 0,
+            start_ts: Instant::now(),
+            show_linenumbers,
+        })
+    }
+
+    /// Adds the statistics of the sampler to the metadata table, once recording has finished
+    pub fn set_stats(&mut self, stats: &SamplerStats) -> Result<(), Error> {
+        for (key, value) in stats.metadata() {
+            self.conn.execute(
+                "INSERT OR REPLACE INTO metadata (key, value) VALUES (?1, ?2)",
+                params![key, value],
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn increment(&mut self, trace: &StackTrace) -> Result<(), Error> {
+        let now = self.start_ts.elapsed().as_micros() as u64;
+        self.increment_at(trace, now)
+    }
+
+    pub fn increment_at(&mut self, trace: &StackTrace, timestamp: u64) -> Result<(), Error> {
+        let stack_id = self.stack_id(&trace.frames)?;
+        self.conn
+            .prepare_cached(
+                "INSERT INTO samples (timestamp, pid, thread_id, os_thread_id, thread_name,
+                                      stack_id, active, owns_gil)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            )?
+            .execute(params![
+                timestamp as i64,
+                trace.pid as i64,
+                trace.thread_id as i64,
+                trace.os_thread_id.map(|tid| tid as i64),
+                trace.thread_name,
+                stack_id,
+                trace.active,
+                trace.owns_gil,
+            ])?;
+
+        self.samples += 1;
+        if self.samples.is_multiple_of(BATCH_SIZE) {
+            self.conn.execute_batch("COMMIT; BEGIN")?;
+        }
+        Ok(())
+    }
+
+    /// The size of the database so far, including the samples that haven't been committed yet
+    pub fn size(&self) -> Result<u64, Error> {
+        let size: i64 = self.conn.query_row(
+            "SELECT page_count * page_size FROM pragma_page_count(), pragma_page_size()",
+            [],
+            |row| row.get(0),
+        )?;
+        Ok(size as u64)
+    }
+
+    /// Copies the database to 'w'. This can be called more than once (like for
+    /// --checkpoint-every), with samples still being added in between
+    pub fn write(&self, w: &mut dyn Write) -> Result<(), Error> {
+        if !self.conn.is_autocommit() {
+            self.conn.execute_batch("COMMIT")?;
+        }
+        self.conn.execute_batch(INDICES)?;
+        std::io::copy(&mut self.file.reopen()?, w)?;
+        self.conn.execute_batch("BEGIN")?;
+        Ok(())
+    }
+
+    fn stack_id(&mut self, frames: &[Frame]) -> Result<i64, Error> {
+        let frame_ids = frames
+            .iter()
+            .map(|frame| self.frame_id(frame))
+            .collect::<Result<Vec<i64>, Error>>()?;
+
+        if let Some(id) = self.stack_ids.get(&frame_ids) {
+            return Ok(*id);
+        }
+
+        self.conn
+            .prepare_cached("INSERT INTO stacks (leaf_frame_id, num_frames) VALUES (?1, ?2)")?
+            .execute(params![frame_ids.first(), frame_ids.len() as i64])?;
+        let id = self.conn.last_insert_rowid();
+
+        let mut insert = self.conn.prepare_cached(
+            "INSERT INTO stack_frames (stack_id, depth, frame_id) VALUES (?1, ?2, ?3)",
+        )?;
+        for (depth, frame_id) in frame_ids.iter().rev().enumerate() {
+            insert.execute(params![id, depth as i64, frame_id])?;
+        }
+        self.stack_ids.insert(frame_ids, id);
+        Ok(id)
+    }
+
+    fn frame_id(&mut self, frame: &Frame) -> Result<i64, Error> {
+        let filename = match &frame.short_filename {
+            Some(f) => f,
+            None => &frame.filename,
+        };
+        let line = if self.show_linenumbers { frame.line } else { 0 };
+        let key = (
+            frame.name.clone(),
+            filename.clone(),
+            frame.module.clone(),
+            line,
+        );
+        if let Some(id) = self.frame_ids.get(&key) {
+            return Ok(*id);
+        }
+
+        self.conn
+            .prepare_cached(
+                "INSERT INTO frames (name, filename, module, line) VALUES (?1, ?2, ?3, ?4)",
+            )?
+            .execute(params![key.0, key.1, key.2, key.3])?;
+        let id = self.conn.last_insert_rowid();
+        self.frame_ids.insert(key, id);
+        Ok(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{frame, trace};
+
+    #[test]
+    fn test_sqlite_tables() {
+        let mut sqlite = Sqlite::new(true, 100).unwrap();
+        let mut trace = trace(vec![
+            frame("inner", "test.py", 2),
+            frame("outer", "test.py", 10),
+        ]);
+        trace.pid = 1234;
+        trace.thread_name = Some(String::from("MainThread"));
+        sqlite.increment_at(&trace, 100).unwrap();
+        sqlite.increment_at(&trace, 200).unwrap();
+        trace.frames.remove(0);
+        sqlite.increment_at(&trace, 300).unwrap();
+        sqlite.set_stats(&SamplerStats::new(100)).unwrap();
+
+        let mut output = NamedTempFile::new().unwrap();
+        sqlite.write(&mut output).unwrap();
+        output.flush().unwrap();
+
+        let conn = Connection::open(output.path()).unwrap();
+        let count = |sql: &str| -> i64 { conn.query_row(sql, [], |row| row.get(0)).unwrap() };
+        assert_eq!(count("SELECT COUNT(*) FROM samples"), 3);
+        assert_eq!(count("SELECT COUNT(*) FROM stacks"), 2);
+        assert_eq!(count("SELECT COUNT(*) FROM frames"), 2);
+        assert_eq!(count("SELECT COUNT(*) FROM stack_frames"), 3);
+        let requested_rate: String = conn
+            .query_row(
+                "SELECT value FROM metadata WHERE key = 'requested_rate'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(requested_rate, "100");
+
+        // self time by function
+        let leaf_samples = count(
+            "SELECT COUNT(*) FROM samples
+             JOIN stacks ON samples.stack_id = stacks.id
+             JOIN frames ON stacks.leaf_frame_id = frames.id
+             WHERE frames.name = 'inner'",
+        );
+        assert_eq!(leaf_samples, 2);
+
+        // outermost frame is at depth 0
+        let outer = count(
+            "SELECT frames.line FROM stack_frames
+             JOIN frames ON stack_frames.frame_id = frames.id
+             WHERE stack_frames.depth = 0 LIMIT 1",
+        );
+        assert_eq!(outer, 10);
+    }
+
+    #[test]
+    fn test_sqlite_write_twice() {
+        let mut sqlite = Sqlite::new(true, 100).unwrap();
+        let trace = trace(vec![frame("inner", "test.py", 2)]);
+        sqlite.increment_at(&trace, 100).unwrap();
+
+        let count = |output: &NamedTempFile| -> i64 {
+            Connection::open(output.path())
+                .unwrap()
+                .query_row("SELECT COUNT(*) FROM samples", [], |row| row.get(0))
+                .unwrap()
+        };
+        let mut output = NamedTempFile::new().unwrap();
+        sqlite.write(&mut output).unwrap();
+        assert_eq!(count(&output), 1);
+
+        // samples can still be added after a checkpoint, including past the end of a batch
+        let mut output = NamedTempFile::new().unwrap();
+        sqlite.write(&mut output).unwrap();
+        for timestamp in 0..BATCH_SIZE + 1 {
+            sqlite.increment_at(&trace, timestamp).unwrap();
+        }
+        let mut output = NamedTempFile::new().unwrap();
+        sqlite.write(&mut output).unwrap();
+        assert_eq!(count(&output), BATCH_SIZE as i64 + 2);
+    }
+}
@@ -0,0 +1,253 @@
+use std::io::{BufRead, BufReader};
+use std::process::{Child, ChildStdout, Command, Stdio};
+
+use anyhow::{Context, Error};
+
+/*
+ * This file contains code for 'record --ssh', which profiles a process on another machine.
+ *
+ * py-spy is run on the remote host over ssh, with the same arguments it was given locally
+ * (other than the ones for the output), writing samples out to stdout as JSON lines followed by
+ * the statistics of its sampler. The samples are read back as they come in over the ssh
+ * connection and recorded locally, so that they can be written out in any format. If the remote
+ * host doesn't have the same version of py-spy on its PATH, a copy of this binary is uploaded to
+ * ~/.cache/py-spy on it, as long as it's the same os and architecture.
+ */
+
+/// The arguments that are for writing out the profile, which is done locally rather than on the
+/// remote host, as (long name, short name, whether it takes a value)
+const LOCAL_ARGS: &[(&str, Option<char>, bool)] = &[
+    ("--ssh", None, true),
+    ("--output", Some('o'), true),
+    ("--format", Some('f'), true),
+    ("--compress", None, true),
+    ("--tag-threads", None, false),
+    ("--weight", None, true),
+    ("--granularity", None, true),
+    ("--palette", None, true),
+    ("--reverse", None, false),
+    ("--evented", None, false),
+    ("--absolute-timestamps", None, false),
+    ("--stack-samples", None, false),
+    ("--min-slice-duration", None, true),
+    ("--thread-states", None, false),
+    ("--report-template", None, true),
+    ("--report-top", None, true),
+];
+
+/// py-spy, running on a remote host
+pub struct RemoteRecording {
+    child: Child,
+    stdout: BufReader<ChildStdout>,
+}
+
+impl RemoteRecording {
+    /// Starts recording on the remote host, with the arguments py-spy was run with locally
+    pub fn start(destination: &str, args: &[String]) -> Result<RemoteRecording, Error> {
+        let py_spy = remote_py_spy(destination)?;
+        let mut command = vec![shell_quote(&py_spy), "record".to_owned()];
+        command.extend(remote_args(args).iter().map(|arg| shell_quote(arg)));
+        command.extend(["--format", "jsonl", "--output", "-", "--stream-stats"].map(String::from));
+        info!("Running '{}' on {}", command.join(" "), destination);
+
+        let mut child = ssh(destination)
+            .arg(command.join(" "))
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .spawn()
+            .context("Failed to run ssh")?;
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+        Ok(RemoteRecording { child, stdout })
+    }
+
+    /// Reads the next line of samples written by the remote py-spy, returning None once it's
+    /// finished recording
+    pub fn next_line(&mut self) -> Result<Option<String>, Error> {
+        let mut line = String::new();
+        if self.stdout.read_line(&mut line)? == 0 {
+            let status = self.child.wait()?;
+            if !status.success() {
+                return Err(format_err!("py-spy failed on the remote host ({})", status));
+            }
+            return Ok(None);
+        }
+        Ok(Some(line))
+    }
+}
+
+/// Finds a copy of py-spy on the remote host with the same version as this one, uploading this
+/// binary if there isn't one
+fn remote_py_spy(destination: &str) -> Result<String, Error> {
+    let version = env!("CARGO_PKG_VERSION");
+    let cached = format!("$HOME/.cache/py-spy/py-spy-{}", version);
+    let script = format!(
+        r#"if [ -x "{cached}" ]; then echo "found {cached}"; elif [ "$(py-spy --version 2>/dev/null)" = "py-spy {version}" ]; then echo "found $(command -v py-spy)"; else echo "missing $(uname -sm)"; fi"#,
+    );
+    let output = run_ssh(destination, &script, Stdio::null())?;
+    if let Some(path) = output.strip_prefix("found ") {
+        return Ok(path.to_owned());
+    }
+
+    let uname = output.strip_prefix("missing ").unwrap_or(&output);
+    if !same_platform(uname) {
+        return Err(format_err!(
+            "{} is '{}', but this py-spy was built for {} {}. Install py-spy {} on it to profile it with --ssh",
+            destination,
+            uname,
+            std::env::consts::OS,
+            std::env::consts::ARCH,
+            version
+        ));
+    }
+    eprintln!(
+        "Copying py-spy {} to {}:{}",
+        version,
+        destination,
+        cached.replace("$HOME", "~")
+    );
+    let exe = std::env::current_exe()?;
+    let binary =
+        std::fs::File::open(&exe).with_context(|| format!("Failed to open '{}'", exe.display()))?;
+    let script = format!(
+        r#"mkdir -p "$HOME/.cache/py-spy" && cat > "{cached}.tmp" && chmod +x "{cached}.tmp" && mv "{cached}.tmp" "{cached}" && echo "{cached}""#,
+    );
+    run_ssh(destination, &script, Stdio::from(binary))
+}
+
+/// Runs a shell command on the remote host, returning what it writes to stdout
+fn run_ssh(destination: &str, script: &str, stdin: Stdio) -> Result<String, Error> {
+    let output = ssh(destination)
+        .arg(script)
+        .stdin(stdin)
+        .stderr(Stdio::inherit())
+        .output()
+        .context("Failed to run ssh")?;
+    if !output.status.success() {
+        return Err(format_err!(
+            "Failed to connect to {} with ssh ({})",
+            destination,
+            output.status
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+fn ssh(destination: &str) -> Command {
+    let mut command = Command::new("ssh");
+    // the destination can't be mistaken for one of ssh's options after '--'
+    command.arg("--").arg(destination);
+    command
+}
+
+/// Whether the remote host (from 'uname -sm') can run this binary
+fn same_platform(uname: &str) -> bool {
+    let (os, arch) = uname.split_once(' ').unwrap_or((uname, ""));
+    let os = match os {
+        "Linux" => "linux",
+        "Darwin" => "macos",
+        "FreeBSD" => "freebsd",
+        _ => return false,
+    };
+    let arch = match arch {
+        "amd64" => "x86_64",
+        "arm64" => "aarch64",
+        "i686" | "i386" => "x86",
+        arch => arch,
+    };
+    os == std::env::consts::OS && arch == std::env::consts::ARCH
+}
+
+/// The arguments to pass on to py-spy on the remote host, which are the ones py-spy was run
+/// with other than the ones for writing out the profile
+fn remote_args(args: &[String]) -> Vec<String> {
+    let mut remote = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let local = LOCAL_ARGS.iter().find_map(|(long, short, takes_value)| {
+            let short = short.map(|short| format!("-{}", short));
+            if arg == long || Some(arg) == short.as_ref() {
+                // the value is the next argument
+                Some(*takes_value)
+            } else if arg.starts_with(&format!("{}=", long))
+                || short.is_some_and(|short| arg.starts_with(&short))
+            {
+                Some(false)
+            } else {
+                None
+            }
+        });
+        match local {
+            Some(true) => {
+                args.next();
+            }
+            Some(false) => {}
+            None => remote.push(arg.clone()),
+        }
+    }
+    remote
+}
+
+/// Quotes an argument for the remote shell, which ssh runs the command with
+fn shell_quote(arg: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "-_./=:,@%+".contains(c);
+    if !arg.is_empty() && arg.chars().all(safe) {
+        return arg.to_owned();
+    }
+    format!("'{}'", arg.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &str) -> Vec<String> {
+        args.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn test_remote_args() {
+        assert_eq!(
+            remote_args(&args(
+                "--ssh app@web-1 --pid 1234 -o profile.svg --rate 250 --format=speedscope --idle"
+            )),
+            args("--pid 1234 --rate 250 --idle")
+        );
+        assert_eq!(
+            remote_args(&args("-p 1234 -fcapture --compress gzip --ssh=web-1 -d 10")),
+            args("-p 1234 -d 10")
+        );
+        // options for the output are left out, since the remote host only writes json lines
+        assert_eq!(
+            remote_args(&args(
+                "--ssh web-1 -p 1234 --reverse --palette=package --report-top 5 --gil --thread-states"
+            )),
+            args("-p 1234 --gil")
+        );
+    }
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("--pid"), "--pid");
+        assert_eq!(
+            shell_quote("/root/.cache/py-spy/py-spy-0.3.14"),
+            "/root/.cache/py-spy/py-spy-0.3.14"
+        );
+        assert_eq!(shell_quote("^run$"), "'^run$'");
+        assert_eq!(shell_quote("main.py:run"), "main.py:run");
+        assert_eq!(shell_quote("worker thread"), "'worker thread'");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+        assert_eq!(shell_quote(""), "''");
+    }
+
+    #[test]
+    fn test_same_platform() {
+        let uname = match (std::env::consts::OS, std::env::consts::ARCH) {
+            ("linux", "x86_64") => "Linux x86_64",
+            ("macos", "aarch64") => "Darwin arm64",
+            _ => return,
+        };
+        assert!(same_platform(uname));
+        assert!(!same_platform("Linux riscv64"));
+        assert!(!same_platform("SunOS i86pc"));
+    }
+}
@@ -3,16 +3,22 @@ use std::sync::Arc;
 use anyhow::{Context, Error, Result};
 
 use remoteprocess::{Pid, ProcessMemory};
-use serde_derive::Serialize;
+use serde_derive::{Deserialize, Serialize};
 
 use crate::config::{Config, LineNo};
-use crate::python_data_access::{copy_bytes, copy_string};
+use crate::python_data_access::{copy_bytes, copy_string, PY_TPFLAGS_TYPE_SUBCLASS};
 use crate::python_interpreters::{
-    CodeObject, FrameObject, InterpreterState, ThreadState, TupleObject,
+    CodeObject, FrameObject, InterpreterState, ThreadState, TupleObject, TypeObject,
 };
+use crate::struct_offsets::{copy_pointer, copy_struct};
+
+// code object flags for coroutines, from cpython's Include/cpython/code.h
+const CO_COROUTINE: i32 = 0x80;
+const CO_ITERABLE_COROUTINE: i32 = 0x100;
 
 /// Call stack for a single python thread
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[non_exhaustive]
 pub struct StackTrace {
     /// The process id than generated this stack trace
     pub pid: Pid,
@@ -30,10 +36,81 @@ pub struct StackTrace {
     pub frames: Vec<Frame>,
     /// process commandline / parent process info
     pub process_info: Option<Arc<ProcessInfo>>,
+    /// Identifies the asyncio task (or other coroutine) that was running on this thread
+    #[serde(default)]
+    pub task_id: Option<u64>,
+    /// The type of the exception being raised on this thread, if it was unwinding one
+    #[serde(default)]
+    pub exception: Option<String>,
+    /// Whether or not this thread was running the garbage collector
+    #[serde(default)]
+    pub in_gc: bool,
+    /// What the thread was doing when it was sampled (see thread_status)
+    #[serde(default)]
+    pub status: Option<ThreadStatus>,
+    /// The python lock this thread was blocked on acquiring, with --lock-contention
+    #[serde(default)]
+    pub lock_wait: Option<LockWait>,
+    /// The id of the (sub)interpreter running this thread, when the process has more than one
+    #[serde(default)]
+    pub interpreter_id: Option<i64>,
+    /// The name of the asyncio task, for the stacks of suspended tasks read with --asyncio
+    #[serde(default)]
+    pub task_name: Option<String>,
+}
+
+/// A python lock that a thread was blocked on acquiring
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LockWait {
+    /// The address of the lock object
+    pub lock: u64,
+    /// The type of the lock, like '_thread.lock' or '_thread.RLock'
+    pub kind: String,
+    /// The python thread id of the thread holding the lock, if this could be found
+    pub holder: Option<u64>,
+}
+
+/// What a thread was doing when it was sampled
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ThreadStatus {
+    /// Running python code while holding the GIL
+    Running,
+    /// Runnable, but waiting on another thread to release the GIL
+    WaitingForGil,
+    /// Idle in a select/poll loop, or a read or write on a socket
+    BlockingIo,
+    /// Idle for any other reason, like sleeping or waiting on a lock
+    Sleeping,
+    /// Blocked on a lock, condition or queue (only told apart from sleeping with --offcpu)
+    WaitingOnLock,
+}
+
+impl ThreadStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ThreadStatus::Running => "running",
+            ThreadStatus::WaitingForGil => "GIL wait",
+            ThreadStatus::BlockingIo => "I/O",
+            ThreadStatus::Sleeping => "sleeping",
+            ThreadStatus::WaitingOnLock => "lock wait",
+        }
+    }
+
+    /// Why a thread that wasn't running was waiting, for tagging off-cpu stacks
+    pub fn wait_reason(&self) -> &'static str {
+        match self {
+            ThreadStatus::Running => "running",
+            ThreadStatus::WaitingForGil => "waiting on GIL",
+            ThreadStatus::BlockingIo => "blocked in syscall",
+            ThreadStatus::Sleeping => "sleeping",
+            ThreadStatus::WaitingOnLock => "waiting on lock",
+        }
+    }
 }
 
 /// Information about a single function call in a stack trace
-#[derive(Debug, Hash, Eq, PartialEq, Ord, PartialOrd, Clone, Serialize)]
+#[derive(Debug, Hash, Eq, PartialEq, Ord, PartialOrd, Clone, Serialize, Deserialize)]
+#[non_exhaustive]
 pub struct Frame {
     /// The function name
     pub name: String,
@@ -51,19 +128,41 @@ pub struct Frame {
     pub is_entry: bool,
 }
 
-#[derive(Debug, Hash, Eq, PartialEq, Ord, PartialOrd, Clone, Serialize)]
+impl Frame {
+    /// A call to a function at a line of a file, for frames that aren't read from python (like
+    /// the ones labelling threads and processes, which have an empty filename and a line of 0)
+    pub fn new(name: &str, filename: &str, line: i32) -> Frame {
+        Frame {
+            name: name.to_owned(),
+            filename: filename.to_owned(),
+            module: None,
+            short_filename: None,
+            line,
+            locals: None,
+            is_entry: true,
+        }
+    }
+}
+
+#[derive(Debug, Hash, Eq, PartialEq, Ord, PartialOrd, Clone, Serialize, Deserialize)]
 pub struct LocalVariable {
     pub name: String,
     pub addr: usize,
     pub arg: bool,
     pub repr: Option<String>,
+    /// The whole value of the variable, when it's being written to --locals-output
+    #[serde(skip)]
+    pub full_repr: Option<String>,
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ProcessInfo {
     pub pid: Pid,
     pub command_line: String,
     pub parent: Option<Box<ProcessInfo>>,
+    /// The role the process plays in a gunicorn, celery or uwsgi server, like 'gunicorn worker'
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role: Option<String>,
 }
 
 /// Given an InterpreterState, this function returns a vector of stack traces for each thread
@@ -84,14 +183,19 @@ where
 
     let lineno = config.map(|c| c.lineno).unwrap_or(LineNo::NoLine);
     let dump_locals = config.map(|c| c.dump_locals).unwrap_or(0);
+    let qualified_names = config.map(|c| c.qualified_names).unwrap_or(false);
 
     while !threads.is_null() {
-        let thread = process
-            .copy_pointer(threads)
-            .context("Failed to copy PyThreadState")?;
+        let thread = copy_pointer(process, threads).context("Failed to copy PyThreadState")?;
 
-        let mut trace = get_stack_trace(&thread, process, dump_locals > 0, lineno)?;
+        let mut trace =
+            get_stack_trace(&thread, process, dump_locals > 0, lineno, qualified_names)?;
         trace.owns_gil = trace.thread_id == gil_thread_id;
+        // the garbage collector runs on whichever thread holds the gil
+        trace.in_gc = trace.owns_gil && interpreter.gc_collecting().unwrap_or(false);
+        if trace.in_gc {
+            trace.frames.insert(0, gc_frame());
+        }
 
         ret.push(trace);
         // This seems to happen occasionally when scanning BSS addresses for valid interpreters
@@ -109,6 +213,7 @@ pub fn get_stack_trace<T, P>(
     process: &P,
     copy_locals: bool,
     lineno: LineNo,
+    qualified_names: bool,
 ) -> Result<StackTrace, Error>
 where
     T: ThreadState,
@@ -123,54 +228,40 @@ where
         frame_address = Some(process.copy_struct(addr)?);
     }
 
+    // coroutines keep the same frame for as long as they're alive, so the outermost coroutine
+    // frame on the stack identifies the task that is running, even after being suspended
+    let mut task_id = None;
+
     let mut frame_ptr = thread.frame(frame_address);
     while !frame_ptr.is_null() {
-        let frame = process
-            .copy_pointer(frame_ptr)
-            .context("Failed to copy PyFrameObject")?;
-        let code = process
-            .copy_pointer(frame.code())
-            .context("Failed to copy PyCodeObject")?;
-
-        let filename = copy_string(code.filename(), process).context("Failed to copy filename")?;
-        let name = copy_string(code.name(), process).context("Failed to copy function name")?;
-
-        let line = match lineno {
-            LineNo::NoLine => 0,
-            LineNo::First => code.first_lineno(),
-            LineNo::LastInstruction => match get_line_number(&code, frame.lasti(), process) {
-                Ok(line) => line,
-                Err(e) => {
-                    // Failling to get the line number really shouldn't be fatal here, but
-                    // can happen in extreme cases (https://github.com/benfred/py-spy/issues/164)
-                    // Rather than fail set the linenumber to 0. This is used by the native extensions
-                    // to indicate that we can't load a line number and it should be handled gracefully
-                    warn!(
-                        "Failed to get line number from {}.{}: {}",
-                        filename, name, e
-                    );
-                    0
-                }
-            },
-        };
+        let frame = copy_pointer(process, frame_ptr).context("Failed to copy PyFrameObject")?;
+        let code = copy_pointer(process, frame.code()).context("Failed to copy PyCodeObject")?;
 
-        let locals = if copy_locals {
-            Some(get_locals(&code, frame_ptr, &frame, process)?)
-        } else {
-            None
-        };
+        let mut python_frame = get_frame(&code, frame.lasti(), process, lineno)?;
+        if copy_locals {
+            python_frame.locals = Some(get_locals(&code, frame_ptr, &frame, process)?);
+        }
+        if qualified_names {
+            // the name is left as it is if the class can't be found
+            if let Ok(Some(name)) = get_qualified_name::<T::TypeObject, _, _, P>(
+                &code,
+                frame_ptr as usize,
+                &frame,
+                process,
+            ) {
+                python_frame.name = name;
+            }
+        }
+        python_frame.is_entry = frame.is_entry();
 
-        let is_entry = frame.is_entry();
+        if code.flags() & (CO_COROUTINE | CO_ITERABLE_COROUTINE) != 0 {
+            // frame addresses can be reused once the coroutine finishes, so include the code
+            // object too to make it less likely that a different task gets the same id. (this
+            // also keeps the id small enough to be exactly represented in javascript)
+            task_id = Some(frame_ptr as u64 ^ frame.code() as u64);
+        }
 
-        frames.push(Frame {
-            name,
-            filename,
-            line,
-            short_filename: None,
-            module: None,
-            locals,
-            is_entry,
-        });
+        frames.push(python_frame);
         if frames.len() > 4096 {
             return Err(format_err!("Max frame recursion depth reached"));
         }
@@ -187,10 +278,102 @@ where
         active: true,
         os_thread_id: thread.native_thread_id(),
         process_info: None,
+        task_id,
+        exception: get_exception_type(thread, process)?,
+        in_gc: false,
+        status: None,
+        lock_wait: None,
+        interpreter_id: None,
+        task_name: None,
+    })
+}
+
+/// Gets the function, filename and line number of the python frame running a code object,
+/// without its locals
+pub fn get_frame<C, P>(code: &C, lasti: i32, process: &P, lineno: LineNo) -> Result<Frame, Error>
+where
+    C: CodeObject,
+    P: ProcessMemory,
+{
+    let filename = copy_string(code.filename(), process).context("Failed to copy filename")?;
+    let name = copy_string(code.name(), process).context("Failed to copy function name")?;
+
+    let line = match lineno {
+        LineNo::NoLine => 0,
+        LineNo::First => code.first_lineno(),
+        LineNo::LastInstruction => match get_line_number(code, lasti, process) {
+            Ok(line) => line,
+            Err(e) => {
+                // Failling to get the line number really shouldn't be fatal here, but
+                // can happen in extreme cases (https://github.com/benfred/py-spy/issues/164)
+                // Rather than fail set the linenumber to 0. This is used by the native extensions
+                // to indicate that we can't load a line number and it should be handled gracefully
+                warn!(
+                    "Failed to get line number from {}.{}: {}",
+                    filename, name, e
+                );
+                0
+            }
+        },
+    };
+
+    Ok(Frame {
+        name,
+        filename,
+        line,
+        short_filename: None,
+        module: None,
+        locals: None,
+        is_entry: false,
     })
 }
 
+/// Returns the type name of the exception currently being raised on a thread, if any
+fn get_exception_type<T, P>(thread: &T, process: &P) -> Result<Option<String>, Error>
+where
+    T: ThreadState,
+    P: ProcessMemory,
+{
+    let exception_type = thread.curexc_type();
+    if exception_type.is_null() {
+        return Ok(None);
+    }
+    let exception_type = process
+        .copy_pointer(exception_type)
+        .context("Failed to copy exception type")?;
+
+    // get the typename (truncating to 128 bytes if longer)
+    let max_len = 128;
+    let name = process
+        .copy(exception_type.name() as usize, max_len)
+        .context("Failed to copy exception type name")?;
+    let length = name.iter().position(|&x| x == 0).unwrap_or(max_len);
+    Ok(Some(String::from_utf8_lossy(&name[..length]).into_owned()))
+}
+
 impl StackTrace {
+    /// A stack trace of an active thread, with the innermost frame first. The other fields are
+    /// empty, and can be set afterwards
+    pub fn new(pid: Pid, thread_id: u64, frames: Vec<Frame>) -> StackTrace {
+        StackTrace {
+            pid,
+            thread_id,
+            thread_name: None,
+            os_thread_id: None,
+            active: true,
+            owns_gil: false,
+            frames,
+            process_info: None,
+            task_id: None,
+            exception: None,
+            in_gc: false,
+            status: None,
+            lock_wait: None,
+            interpreter_id: None,
+            task_name: None,
+        }
+    }
+
     pub fn status_str(&self) -> &str {
         match (self.owns_gil, self.active) {
             (_, false) => "idle",
@@ -199,6 +382,16 @@ impl StackTrace {
         }
     }
 
+    /// What the thread was doing, which is worked out from whether it was active and held the
+    /// GIL for traces that were recorded without a status
+    pub fn thread_status(&self) -> ThreadStatus {
+        self.status.unwrap_or(match (self.owns_gil, self.active) {
+            (_, false) => ThreadStatus::Sleeping,
+            (true, true) => ThreadStatus::Running,
+            (false, true) => ThreadStatus::WaitingForGil,
+        })
+    }
+
     pub fn format_threadid(&self) -> String {
         // native threadids in osx are kinda useless, use the pthread id instead
         #[cfg(target_os = "macos")]
@@ -224,6 +417,67 @@ fn get_line_number<C: CodeObject, P: ProcessMemory>(
     Ok(code.get_line_number(lasti, &table))
 }
 
+/// Gets the qualified name of the function a frame is running, like 'Class.method'. This is on
+/// the code object from python 3.11, and is rebuilt from the class of the 'self' or 'cls'
+/// argument of methods for older versions
+pub fn get_qualified_name<T, C, F, P>(
+    code: &C,
+    frameptr: usize,
+    frame: &F,
+    process: &P,
+) -> Result<Option<String>, Error>
+where
+    T: TypeObject,
+    C: CodeObject,
+    P: ProcessMemory,
+{
+    if let Some(qualname) = code.qualname() {
+        return Ok(Some(copy_string(qualname, process)?));
+    }
+    if code.argcount() == 0 {
+        return Ok(None);
+    }
+    let varnames = process.copy_pointer(code.varnames())?;
+    let nameptr: *const C::StringObject =
+        process.copy_struct(varnames.address(code.varnames() as usize, 0))?;
+    let arg = copy_string(nameptr, process)?;
+    if arg != "self" && arg != "cls" {
+        return Ok(None);
+    }
+    let value: usize = process.copy_struct(locals_address(frameptr, frame))?;
+    if value == 0 {
+        return Ok(None);
+    }
+
+    // the type of an object comes after its reference count
+    let ptr_size = std::mem::size_of::<*const i32>();
+    let value_type: *mut T = process.copy_struct(value + ptr_size)?;
+    let class: *mut T = if arg == "self" {
+        value_type
+    } else {
+        let value_type = process.copy_pointer(value_type)?;
+        if value_type.flags() & PY_TPFLAGS_TYPE_SUBCLASS == 0 {
+            return Ok(None);
+        }
+        value as *mut T
+    };
+    let class = process.copy_pointer(class)?;
+
+    // builtin types are named with their module, like 'collections.OrderedDict'
+    let max_len = 128;
+    let class_name = process.copy(class.name() as usize, max_len)?;
+    let length = class_name.iter().position(|&x| x == 0).unwrap_or(max_len);
+    let class_name = String::from_utf8_lossy(&class_name[..length]).into_owned();
+    let class_name = class_name.rsplit('.').next().unwrap_or_default();
+    let name = copy_string(code.name(), process)?;
+    Ok(Some(format!("{}.{}", class_name, name)))
+}
+
+/// The address of the local variables of a frame, which are stored after it
+fn locals_address<F>(frameptr: usize, frame: &F) -> usize {
+    frameptr + std::mem::size_of_val(frame) - std::mem::size_of::<*const i32>()
+}
+
 fn get_locals<C: CodeObject, F: FrameObject, P: ProcessMemory>(
     code: &C,
     frameptr: *const F,
@@ -235,7 +489,7 @@ fn get_locals<C: CodeObject, F: FrameObject, P: ProcessMemory>(
     let varnames = process.copy_pointer(code.varnames())?;
 
     let ptr_size = std::mem::size_of::<*const i32>();
-    let locals_addr = frameptr as usize + std::mem::size_of_val(frame) - ptr_size;
+    let locals_addr = locals_address(frameptr as usize, frame);
 
     let mut ret = Vec::new();
 
@@ -252,6 +506,7 @@ fn get_locals<C: CodeObject, F: FrameObject, P: ProcessMemory>(
             addr,
             arg: i < argcount,
             repr: None,
+            full_repr: None,
         });
     }
     Ok(ret)
@@ -267,25 +522,46 @@ pub fn get_gil_threadid<I: InterpreterState, P: ProcessMemory>(
 
         // if the addr is 0, no thread is currently holding the GIL
         if addr != 0 {
-            let threadstate: I::ThreadState = process.copy_struct(addr)?;
+            let threadstate: I::ThreadState = copy_struct(process, addr)?;
             return Ok(threadstate.thread_id());
         }
     }
     Ok(0)
 }
 
+/// A frame to add to the leaf of stacks that were running the garbage collector, so that the
+/// time spent collecting isn't attributed to whatever allocation triggered the collection
+pub fn gc_frame() -> Frame {
+    Frame::new("[gc]", "", 0)
+}
+
+/// A frame labelling the stacks of suspended asyncio tasks with the name of the task
+pub fn task_frame(name: &str) -> Frame {
+    Frame::new(&format!("task {}", name), "", 0)
+}
+
+/// A frame labelling the stacks of each interpreter, in processes that have subinterpreters
+pub fn interpreter_frame(id: i64) -> Frame {
+    Frame::new(&format!("interpreter {}", id), "", 0)
+}
+
 impl ProcessInfo {
-    pub fn to_frame(&self) -> Frame {
-        Frame {
-            name: format!("process {}:\"{}\"", self.pid, self.command_line),
-            filename: String::from(""),
-            module: None,
-            short_filename: None,
-            line: 0,
-            locals: None,
-            is_entry: true,
+    /// The name to show for the process: its role when it has one, and otherwise its command line
+    pub fn name(&self) -> String {
+        match &self.role {
+            Some(role) => format!("{} (pid {})", role, self.pid),
+            None if self.command_line.is_empty() => format!("Process {}", self.pid),
+            None => self.command_line.clone(),
         }
     }
+
+    pub fn to_frame(&self) -> Frame {
+        let name = match &self.role {
+            Some(role) => format!("process {}:\"{}\"", self.pid, role),
+            None => format!("process {}:\"{}\"", self.pid, self.command_line),
+        };
+        Frame::new(&name, "", 0)
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,393 @@
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+use anyhow::{Context, Error};
+use remoteprocess::ProcessMemory;
+use serde_derive::Deserialize;
+
+#[cfg(feature = "python2")]
+use crate::python_bindings::v2_7_15;
+use crate::python_bindings::{v3_10_0, v3_11_0, v3_3_7, v3_5_5, v3_6_6, v3_7_0, v3_8_0, v3_9_5};
+use crate::python_interpreters::{FrameObject, InterpreterState, ThreadState};
+use crate::version::Version;
+
+/*
+ * This file contains code for '--offsets', which reads the structs of interpreters whose layout
+ * differs from the stock CPython build py-spy has bindings for, like patched or prerelease
+ * interpreters that add a field to PyThreadState.
+ *
+ * The offsets are given in a TOML file, with a table for each struct that has moved fields:
+ *
+ *     [PyThreadState]
+ *     size = 280
+ *     frame = 32
+ *     thread_id = 184
+ *
+ * When one of these structs is copied out of the process, it's read with the given size, and each
+ * of the given fields is moved from its offset in the process to where the bindings expect it.
+ * Fields that aren't listed are read from the same offset as in the bindings.
+ */
+
+/// A field that py-spy reads from a struct, with its offset and size in the bindings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Field {
+    pub name: &'static str,
+    pub offset: usize,
+    pub size: usize,
+}
+
+/// The structs of the interpreter whose layout can be given in an offsets file
+pub trait StructLayout: Sized {
+    /// The name of the struct in the offsets file, like 'PyThreadState'
+    const NAME: &'static str;
+    /// The fields of the struct that py-spy reads
+    fn fields() -> Vec<Field>;
+}
+
+/// The size and field offsets of one struct, from an offsets file
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct StructOffsets {
+    pub size: Option<usize>,
+    #[serde(flatten)]
+    pub fields: HashMap<String, usize>,
+}
+
+/// The struct offsets from an offsets file, by the name of the struct
+pub type Offsets = HashMap<String, StructOffsets>;
+
+static OFFSETS: OnceLock<Offsets> = OnceLock::new();
+
+/// Reads an offsets file, and checks that the structs and fields in it are ones py-spy reads
+/// from this version of python
+pub fn load_offsets(filename: &str, version: &Version) -> Result<Offsets, Error> {
+    let contents = std::fs::read_to_string(filename)
+        .with_context(|| format!("Failed to read offsets file '{}'", filename))?;
+    let offsets: Offsets = toml::from_str(&contents)
+        .with_context(|| format!("Failed to parse offsets file '{}'", filename))?;
+    check_offsets(&offsets, version)
+        .with_context(|| format!("Invalid offsets file '{}'", filename))?;
+    Ok(offsets)
+}
+
+/// Uses the offsets when reading structs from here on. The offsets can only be set once, since
+/// every process being profiled is running the same build of python
+pub fn set_offsets(offsets: Offsets) -> Result<(), Error> {
+    if let Err(offsets) = OFFSETS.set(offsets) {
+        if OFFSETS.get() != Some(&offsets) {
+            return Err(format_err!(
+                "A different set of struct offsets is already in use"
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn struct_offsets<T: StructLayout>() -> Option<&'static StructOffsets> {
+    OFFSETS.get().and_then(|offsets| offsets.get(T::NAME))
+}
+
+/// Copies a struct from the process, using the offsets from the offsets file if it has any for
+/// the struct
+pub fn copy_struct<T, P>(process: &P, addr: usize) -> Result<T, Error>
+where
+    T: StructLayout,
+    P: ProcessMemory,
+{
+    match struct_offsets::<T>() {
+        Some(offsets) => {
+            let size = offsets.size.unwrap_or(std::mem::size_of::<T>());
+            remap(&process.copy(addr, size)?, offsets)
+        }
+        None => Ok(process.copy_struct(addr)?),
+    }
+}
+
+/// Copies the struct a pointer in the process points to, like copy_struct
+pub fn copy_pointer<T, P>(process: &P, ptr: *const T) -> Result<T, Error>
+where
+    T: StructLayout,
+    P: ProcessMemory,
+{
+    copy_struct(process, ptr as usize)
+}
+
+/// The offset of a field in the process, from its offset in the bindings
+pub fn remote_offset<T: StructLayout>(offset: usize) -> usize {
+    struct_offsets::<T>()
+        .and_then(|offsets| {
+            let field = T::fields()
+                .into_iter()
+                .find(|field| field.offset == offset)?;
+            offsets.fields.get(field.name).copied()
+        })
+        .unwrap_or(offset)
+}
+
+/// Moves the fields of a struct read from the process to where the bindings expect them
+fn remap<T: StructLayout>(remote: &[u8], offsets: &StructOffsets) -> Result<T, Error> {
+    let mut local = vec![0_u8; std::mem::size_of::<T>()];
+    let common = local.len().min(remote.len());
+    local[..common].copy_from_slice(&remote[..common]);
+    for field in T::fields() {
+        if let Some(&offset) = offsets.fields.get(field.name) {
+            let value = remote.get(offset..offset + field.size).ok_or_else(|| {
+                format_err!("{}.{} is past the end of the struct", T::NAME, field.name)
+            })?;
+            local[field.offset..field.offset + field.size].copy_from_slice(value);
+        }
+    }
+    // the bindings are plain old data, so any bytes are a valid value
+    Ok(unsafe { std::ptr::read_unaligned(local.as_ptr() as *const T) })
+}
+
+fn check_offsets(offsets: &Offsets, version: &Version) -> Result<(), Error> {
+    fn check<I: InterpreterState>(offsets: &Offsets) -> Result<(), Error> {
+        type Frame<I> = <<I as InterpreterState>::ThreadState as ThreadState>::FrameObject;
+        type Code<I> = <Frame<I> as FrameObject>::CodeObject;
+        let layouts = [
+            (I::NAME, I::fields(), std::mem::size_of::<I>()),
+            (
+                I::ThreadState::NAME,
+                I::ThreadState::fields(),
+                std::mem::size_of::<I::ThreadState>(),
+            ),
+            (
+                Frame::<I>::NAME,
+                Frame::<I>::fields(),
+                std::mem::size_of::<Frame<I>>(),
+            ),
+            (
+                Code::<I>::NAME,
+                Code::<I>::fields(),
+                std::mem::size_of::<Code<I>>(),
+            ),
+        ];
+        for (name, struct_offsets) in offsets {
+            let (_, fields, size) = layouts
+                .iter()
+                .find(|(layout, _, _)| *layout == name.as_str())
+                .ok_or_else(|| {
+                    let names: Vec<&str> = layouts.iter().map(|(name, _, _)| *name).collect();
+                    format_err!(
+                        "Unknown struct '{}', expected one of {}",
+                        name,
+                        names.join(", ")
+                    )
+                })?;
+            let size = struct_offsets.size.unwrap_or(*size);
+            for (field_name, offset) in &struct_offsets.fields {
+                let field = fields
+                    .iter()
+                    .find(|field| field.name == field_name)
+                    .ok_or_else(|| {
+                        let names: Vec<&str> = fields.iter().map(|field| field.name).collect();
+                        format_err!(
+                            "Unknown field '{}' of {}, expected one of {}",
+                            field_name,
+                            name,
+                            names.join(", ")
+                        )
+                    })?;
+                if offset + field.size > size {
+                    return Err(format_err!(
+                        "{}.{} at offset {} is past the end of the struct ({} bytes)",
+                        name,
+                        field_name,
+                        offset,
+                        size
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    match version {
+        #[cfg(feature = "python2")]
+        Version {
+            major: 2,
+            minor: 3..=7,
+            ..
+        } => check::<v2_7_15::_is>(offsets),
+        Version {
+            major: 3, minor: 3, ..
+        } => check::<v3_3_7::_is>(offsets),
+        Version {
+            major: 3,
+            minor: 4..=5,
+            ..
+        } => check::<v3_5_5::_is>(offsets),
+        Version {
+            major: 3, minor: 6, ..
+        } => check::<v3_6_6::_is>(offsets),
+        Version {
+            major: 3, minor: 7, ..
+        } => check::<v3_7_0::_is>(offsets),
+        Version {
+            major: 3,
+            minor: 8,
+            patch: 0,
+            ..
+        } => match version.release_flags.as_ref() {
+            "a1" | "a2" | "a3" => check::<v3_7_0::_is>(offsets),
+            _ => check::<v3_8_0::_is>(offsets),
+        },
+        Version {
+            major: 3, minor: 8, ..
+        } => check::<v3_8_0::_is>(offsets),
+        Version {
+            major: 3, minor: 9, ..
+        } => check::<v3_9_5::_is>(offsets),
+        Version {
+            major: 3,
+            minor: 10,
+            ..
+        } => check::<v3_10_0::_is>(offsets),
+        Version {
+            major: 3,
+            minor: 11,
+            ..
+        } => check::<v3_11_0::_is>(offsets),
+        _ => Err(format_err!("Unsupported version of Python: {}", version)),
+    }
+}
+
+/// The size of a field, from a closure that borrows it
+fn field_size<T, F>(_field: impl Fn(&T) -> &F) -> usize {
+    std::mem::size_of::<F>()
+}
+
+macro_rules! StructLayout {
+    ($type: ty, $name: expr, [$($($field: ident).+),*]) => {
+        impl StructLayout for $type {
+            const NAME: &'static str = $name;
+            fn fields() -> Vec<Field> {
+                vec![$(Field {
+                    name: concat!($(stringify!($field), "."),+).trim_end_matches('.'),
+                    offset: std::mem::offset_of!($type, $($field).+),
+                    size: field_size(|s: &$type| &s.$($field).+),
+                }),*]
+            }
+        }
+    };
+}
+
+macro_rules! CommonLayout {
+    ($py: ident, [$($interp: ident),*], $line_table: ident) => {
+        StructLayout!($py::PyInterpreterState, "PyInterpreterState", [next, tstate_head, modules $(, $interp)*]);
+        StructLayout!($py::PyThreadState, "PyThreadState", [next, interp, frame, thread_id, curexc_type]);
+        StructLayout!($py::PyFrameObject, "PyFrameObject", [f_back, f_code, f_lasti]);
+        StructLayout!(
+            $py::PyCodeObject,
+            "PyCodeObject",
+            [co_argcount, co_nlocals, co_flags, co_firstlineno, co_filename, co_name, co_varnames, $line_table]
+        );
+    };
+}
+
+StructLayout!(
+    v3_11_0::PyInterpreterState,
+    "PyInterpreterState",
+    [next, threads.head, modules, id]
+);
+StructLayout!(
+    v3_11_0::PyThreadState,
+    "PyThreadState",
+    [
+        next,
+        interp,
+        cframe,
+        thread_id,
+        native_thread_id,
+        curexc_type
+    ]
+);
+StructLayout!(
+    v3_11_0::_PyInterpreterFrame,
+    "_PyInterpreterFrame",
+    [f_code, previous, prev_instr, is_entry]
+);
+StructLayout!(
+    v3_11_0::PyCodeObject,
+    "PyCodeObject",
+    [
+        co_argcount,
+        co_nlocals,
+        co_flags,
+        co_firstlineno,
+        co_filename,
+        co_name,
+        co_qualname,
+        co_localsplusnames,
+        co_linetable,
+        co_code_adaptive
+    ]
+);
+CommonLayout!(v3_10_0, [id], co_linetable);
+CommonLayout!(v3_9_5, [id], co_lnotab);
+CommonLayout!(v3_8_0, [id], co_lnotab);
+CommonLayout!(v3_7_0, [id], co_lnotab);
+CommonLayout!(v3_6_6, [], co_lnotab);
+CommonLayout!(v3_5_5, [], co_lnotab);
+CommonLayout!(v3_3_7, [], co_lnotab);
+#[cfg(feature = "python2")]
+CommonLayout!(v2_7_15, [], co_lnotab);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(version: &str) -> Version {
+        version.parse().unwrap()
+    }
+
+    #[test]
+    fn test_check_offsets() {
+        let offsets: Offsets = toml::from_str(
+            "[PyThreadState]\nsize = 280\ncframe = 64\nthread_id = 184\n\n[PyInterpreterState]\n\"threads.head\" = 80\n",
+        )
+        .unwrap();
+        assert_eq!(offsets["PyThreadState"].size, Some(280));
+        assert_eq!(offsets["PyThreadState"].fields["thread_id"], 184);
+        assert!(check_offsets(&offsets, &version("3.11")).is_ok());
+
+        // 3.10 has 'frame' rather than 'cframe', and no 'threads' struct
+        let err = check_offsets(&offsets, &version("3.10")).unwrap_err();
+        assert!(err.to_string().contains("Unknown field"), "{}", err);
+
+        let offsets: Offsets = toml::from_str("[PyThreadState]\nthread_id = 4096\n").unwrap();
+        let err = check_offsets(&offsets, &version("3.9")).unwrap_err();
+        assert!(err.to_string().contains("past the end"), "{}", err);
+
+        let offsets: Offsets = toml::from_str("[PyGenObject]\ngi_frame = 8\n").unwrap();
+        let err = check_offsets(&offsets, &version("3.9")).unwrap_err();
+        assert!(err.to_string().contains("Unknown struct"), "{}", err);
+    }
+
+    #[test]
+    fn test_remap() {
+        use crate::python_bindings::v3_9_5::PyThreadState;
+        let fields = PyThreadState::fields();
+        let thread_id = fields.iter().find(|f| f.name == "thread_id").unwrap();
+        assert_eq!(thread_id.size, std::mem::size_of::<std::os::raw::c_ulong>());
+
+        // a thread state with an extra pointer before the thread id
+        let size = std::mem::size_of::<PyThreadState>() + 8;
+        let mut remote = vec![0_u8; size];
+        let moved = thread_id.offset + 8;
+        remote[moved..moved + thread_id.size]
+            .copy_from_slice(&1234_u64.to_ne_bytes()[..thread_id.size]);
+        let offsets = StructOffsets {
+            size: Some(size),
+            fields: HashMap::from([("thread_id".to_owned(), moved)]),
+        };
+        let thread: PyThreadState = remap(&remote, &offsets).unwrap();
+        assert_eq!(thread.thread_id(), 1234);
+
+        let offsets = StructOffsets {
+            size: None,
+            fields: HashMap::from([("thread_id".to_owned(), size)]),
+        };
+        assert!(remap::<PyThreadState>(&remote, &offsets).is_err());
+    }
+}
@@ -0,0 +1,172 @@
+use std::net::TcpListener;
+use std::time::Duration;
+
+use anyhow::Error;
+
+/*
+ * This file contains code for running 'py-spy agent' as a systemd service:
+ *
+ *  - socket activation, where systemd listens on the agent's address itself (from a .socket unit)
+ *    and passes the listening socket to py-spy when it's started
+ *  - sd_notify, to tell systemd when the agent is ready to serve requests (for Type=notify
+ *    services), when it's stopping, and that it's still alive (for WatchdogSec=)
+ *
+ * Both of these are done through environment variables that systemd sets, and do nothing when
+ * py-spy isn't started by systemd. They're implemented here rather than with libsystemd so that
+ * py-spy doesn't need to link against it.
+ */
+
+/// The first file descriptor passed by socket activation
+#[cfg(unix)]
+const LISTEN_FDS_START: std::os::unix::io::RawFd = 3;
+
+/// The listening socket passed to this process by systemd socket activation, if any
+#[cfg(unix)]
+pub fn activated_listener() -> Result<Option<TcpListener>, Error> {
+    use std::os::unix::io::FromRawFd;
+
+    let count = activated_fds(
+        std::env::var("LISTEN_PID").ok().as_deref(),
+        std::env::var("LISTEN_FDS").ok().as_deref(),
+        std::process::id(),
+    );
+    // these are only meant for this process, not for the processes it starts
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+
+    match count {
+        0 => Ok(None),
+        count => {
+            if count > 1 {
+                warn!(
+                    "systemd passed {} sockets, only the first one is listened on",
+                    count
+                );
+            }
+            if unsafe { libc::fcntl(LISTEN_FDS_START, libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+            Ok(Some(unsafe { TcpListener::from_raw_fd(LISTEN_FDS_START) }))
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub fn activated_listener() -> Result<Option<TcpListener>, Error> {
+    Ok(None)
+}
+
+/// How many sockets systemd passed to this process, from LISTEN_PID and LISTEN_FDS
+#[cfg_attr(not(unix), allow(dead_code))]
+fn activated_fds(listen_pid: Option<&str>, listen_fds: Option<&str>, pid: u32) -> usize {
+    match listen_pid.and_then(|listen_pid| listen_pid.parse::<u32>().ok()) {
+        Some(listen_pid) if listen_pid == pid => {
+            listen_fds.and_then(|fds| fds.parse().ok()).unwrap_or(0)
+        }
+        _ => 0,
+    }
+}
+
+/// Sends a state (like 'READY=1') to systemd, if this process was started as a notify service
+pub fn notify(state: &str) {
+    let socket = match std::env::var("NOTIFY_SOCKET") {
+        Ok(socket) => socket,
+        Err(_) => return,
+    };
+    if let Err(e) = notify_socket(&socket, state) {
+        warn!("Failed to notify systemd of '{}': {}", state, e);
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn notify_socket(socket: &str, state: &str) -> std::io::Result<()> {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+    let sender = UnixDatagram::unbound()?;
+    // sockets starting with '@' are in the abstract namespace
+    let address = match socket.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(socket)?,
+    };
+    sender.send_to_addr(state.as_bytes(), &address)?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn notify_socket(_socket: &str, _state: &str) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// How often to tell systemd that this process is still alive, if it has a watchdog (which is
+/// half the watchdog timeout, as sd_watchdog_enabled recommends)
+pub fn watchdog_interval() -> Option<Duration> {
+    watchdog_interval_from(
+        std::env::var("WATCHDOG_USEC").ok().as_deref(),
+        std::env::var("WATCHDOG_PID").ok().as_deref(),
+        std::process::id(),
+    )
+}
+
+fn watchdog_interval_from(
+    watchdog_usec: Option<&str>,
+    watchdog_pid: Option<&str>,
+    pid: u32,
+) -> Option<Duration> {
+    // the watchdog is for another process
+    if let Some(watchdog_pid) = watchdog_pid {
+        if watchdog_pid.parse::<u32>().ok() != Some(pid) {
+            return None;
+        }
+    }
+    match watchdog_usec?.parse::<u64>() {
+        Ok(usec) if usec > 0 => Some(Duration::from_micros(usec / 2)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_activated_fds() {
+        assert_eq!(activated_fds(None, None, 10), 0);
+        assert_eq!(activated_fds(Some("10"), Some("1"), 10), 1);
+        assert_eq!(activated_fds(Some("10"), Some("2"), 10), 2);
+        // the sockets were passed to another process, which started this one
+        assert_eq!(activated_fds(Some("9"), Some("1"), 10), 0);
+        assert_eq!(activated_fds(Some("10"), None, 10), 0);
+    }
+
+    #[test]
+    fn test_watchdog_interval() {
+        assert_eq!(watchdog_interval_from(None, None, 10), None);
+        assert_eq!(
+            watchdog_interval_from(Some("30000000"), None, 10),
+            Some(Duration::from_secs(15))
+        );
+        assert_eq!(
+            watchdog_interval_from(Some("30000000"), Some("10"), 10),
+            Some(Duration::from_secs(15))
+        );
+        assert_eq!(
+            watchdog_interval_from(Some("30000000"), Some("9"), 10),
+            None
+        );
+        assert_eq!(watchdog_interval_from(Some("0"), None, 10), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_notify_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notify");
+        let receiver = std::os::unix::net::UnixDatagram::bind(&path).unwrap();
+        notify_socket(path.to_str().unwrap(), "READY=1").unwrap();
+        let mut buf = [0; 64];
+        let len = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+    }
+}
@@ -0,0 +1,20 @@
+use crate::stack_trace::{Frame, StackTrace};
+
+/*
+ * This file contains the stack traces the tests of the output formats (and everything else that
+ * takes samples) are built from. It's compiled into the tests of both the library and the py-spy
+ * binary, since the binary's tests can't see test code in the library.
+ *
+ * Tests that need anything other than the defaults here set fields on the result, since the binary
+ * can't use struct update syntax with the library's (non_exhaustive) StackTrace and Frame.
+ */
+
+/// A call to a function at a line of a file
+pub fn frame(name: &str, filename: &str, line: i32) -> Frame {
+    Frame::new(name, filename, line)
+}
+
+/// A stack trace of an active thread (thread 1 of process 1), with the innermost frame first
+pub fn trace(frames: Vec<Frame>) -> StackTrace {
+    StackTrace::new(1, 1, frames)
+}
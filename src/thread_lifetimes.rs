@@ -0,0 +1,182 @@
+use std::collections::{HashMap, HashSet};
+
+use remoteprocess::Pid;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::config::{Clock, Config};
+use crate::stack_trace::StackTrace;
+
+/*
+ * This file contains code to find when threads start and exit over a recording.
+ *
+ * Threads are only seen when they're sampled, so a thread has started when it's first seen in
+ * a process that has been sampled before, and has exited when it's missing from a sample of its
+ * process. This needs the traces of every thread in each sample (idle threads included), and
+ * processes that failed to be sampled keep their threads until they're next sampled. With --gil
+ * or --clock cpu the samples leave out threads that are still running, so threads aren't tracked.
+ *
+ * Python thread ids are pthread ids, which get reused for new threads as soon as the old thread
+ * has been joined. A thread that changes its OS thread id is treated as a new thread that
+ * replaced the old one.
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ThreadEventKind {
+    Started,
+    Exited,
+}
+
+/// A thread that started or exited during the recording
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ThreadEvent {
+    pub kind: ThreadEventKind,
+    /// The thread as it was last sampled, without its frames
+    pub thread: StackTrace,
+}
+
+#[derive(Default)]
+pub struct ThreadLifetimes {
+    // (pid, thread id) -> the last sample of each running thread, without its frames
+    threads: HashMap<(Pid, u64), StackTrace>,
+    // the processes that have been sampled
+    processes: HashSet<Pid>,
+    started: u64,
+    exited: u64,
+}
+
+impl ThreadLifetimes {
+    pub fn new() -> ThreadLifetimes {
+        Default::default()
+    }
+
+    /// Returns a ThreadLifetimes for a recording, unless its samples won't include every thread
+    pub fn from_config(config: &Config) -> Option<ThreadLifetimes> {
+        if config.gil_only || config.clock == Clock::Cpu {
+            None
+        } else {
+            Some(ThreadLifetimes::new())
+        }
+    }
+
+    /// Updates the running threads from all the traces of a sample, returning the threads that
+    /// have started or exited since the previous sample. The threads of the processes in
+    /// 'failed' couldn't be sampled, and are left as they were
+    pub fn update(&mut self, traces: &[StackTrace], failed: &[Pid]) -> Vec<ThreadEvent> {
+        let mut events = Vec::new();
+        let mut seen = HashSet::new();
+        for trace in traces {
+            let key = (trace.pid, trace.thread_id);
+            if !seen.insert(key) {
+                continue;
+            }
+            let mut thread = trace.clone();
+            thread.frames = Vec::new();
+            thread.exception = None;
+            thread.lock_wait = None;
+            thread.interpreter_id = None;
+            thread.task_name = None;
+            match self.threads.insert(key, thread.clone()) {
+                Some(prev) => {
+                    let reused = match (prev.os_thread_id, thread.os_thread_id) {
+                        (Some(prev_tid), Some(tid)) => prev_tid != tid,
+                        _ => false,
+                    };
+                    if reused {
+                        self.push(&mut events, ThreadEventKind::Exited, prev);
+                        self.push(&mut events, ThreadEventKind::Started, thread);
+                    }
+                }
+                // the threads that were already running when a process is first sampled
+                // weren't started during the recording
+                None if self.processes.contains(&trace.pid) => {
+                    self.push(&mut events, ThreadEventKind::Started, thread);
+                }
+                None => {}
+            }
+        }
+        self.processes.extend(traces.iter().map(|trace| trace.pid));
+
+        let mut exited: Vec<(Pid, u64)> = self
+            .threads
+            .keys()
+            .filter(|key| !seen.contains(key) && !failed.contains(&key.0))
+            .cloned()
+            .collect();
+        exited.sort_unstable();
+        for key in exited {
+            let thread = self.threads.remove(&key).unwrap();
+            self.push(&mut events, ThreadEventKind::Exited, thread);
+        }
+        events
+    }
+
+    fn push(&mut self, events: &mut Vec<ThreadEvent>, kind: ThreadEventKind, thread: StackTrace) {
+        match kind {
+            ThreadEventKind::Started => self.started += 1,
+            ThreadEventKind::Exited => self.exited += 1,
+        }
+        events.push(ThreadEvent { kind, thread });
+    }
+
+    /// The number of threads that have started during the recording
+    pub fn started(&self) -> u64 {
+        self.started
+    }
+
+    /// The number of threads that have exited during the recording
+    pub fn exited(&self) -> u64 {
+        self.exited
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::trace;
+
+    fn thread(pid: Pid, thread_id: u64, os_thread_id: u64) -> StackTrace {
+        let mut stack = trace(vec![]);
+        stack.pid = pid;
+        stack.thread_id = thread_id;
+        stack.os_thread_id = Some(os_thread_id);
+        stack.active = false;
+        stack
+    }
+
+    fn events(events: &[ThreadEvent]) -> Vec<(ThreadEventKind, Pid, u64)> {
+        events
+            .iter()
+            .map(|e| (e.kind, e.thread.pid, e.thread.thread_id))
+            .collect()
+    }
+
+    #[test]
+    fn test_thread_lifetimes() {
+        use ThreadEventKind::*;
+        let mut lifetimes = ThreadLifetimes::new();
+
+        // threads that were running before the recording started aren't counted
+        let updates = lifetimes.update(&[thread(1, 1, 10), thread(1, 2, 20)], &[]);
+        assert!(updates.is_empty());
+
+        // a new thread in the same process has started, and thread 2 has exited
+        let updates = lifetimes.update(&[thread(1, 1, 10), thread(1, 3, 30)], &[]);
+        assert_eq!(events(&updates), vec![(Started, 1, 3), (Exited, 1, 2)]);
+
+        // the threads of processes that failed to be sampled are kept
+        let updates = lifetimes.update(&[thread(2, 1, 40)], &[1]);
+        assert!(updates.is_empty());
+
+        // a thread id that's been reused by a new OS thread is a new thread
+        let updates = lifetimes.update(&[thread(1, 1, 50), thread(1, 3, 30)], &[2]);
+        assert_eq!(events(&updates), vec![(Exited, 1, 1), (Started, 1, 1)]);
+        assert_eq!(updates[1].thread.os_thread_id, Some(50));
+
+        // every thread of a process that has exited has exited too
+        let updates = lifetimes.update(&[thread(2, 1, 40)], &[]);
+        assert_eq!(events(&updates), vec![(Exited, 1, 1), (Exited, 1, 3)]);
+
+        assert_eq!(lifetimes.started(), 2);
+        assert_eq!(lifetimes.exited(), 4);
+    }
+}
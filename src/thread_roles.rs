@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+
+use crate::stack_trace::Frame;
+
+/*
+ * This file contains code for --thread-roles, which labels threads with the role they play when
+ * it can be told from their stacks, like 'Thread-3 [asyncio event loop]'. Libraries start most of
+ * their threads without naming them, so a busy service can have dozens of threads that are only
+ * called 'Thread-N'.
+ *
+ * Each role is recognized by a frame that's on the stack of threads playing it for as long as
+ * they run, like the loop of a ThreadPoolExecutor's workers. Once a thread's role is known, it's
+ * kept for as long as the thread has the same name, so that the label doesn't come and go if the
+ * stack of a thread is only partly read.
+ */
+
+/// A role, with the function and the end of the filename of the frame that recognizes it
+const ROLES: &[(&str, &str, &str)] = &[
+    (
+        "asyncio event loop",
+        "run_forever",
+        "asyncio/base_events.py",
+    ),
+    (
+        "ThreadPoolExecutor worker",
+        "_worker",
+        "concurrent/futures/thread.py",
+    ),
+    ("gRPC poller", "_serve", "grpc/_server.py"),
+    ("gRPC poller", "channel_spin", "grpc/_channel.py"),
+    (
+        "Kafka consumer heartbeat",
+        "_run_once",
+        "kafka/coordinator/base.py",
+    ),
+    (
+        "multiprocessing pool worker",
+        "worker",
+        "multiprocessing/pool.py",
+    ),
+];
+
+/// The role of a thread, from the frames on its stack
+pub fn thread_role(frames: &[Frame]) -> Option<&'static str> {
+    frames.iter().find_map(|frame| {
+        let filename = frame.filename.replace('\\', "/");
+        ROLES
+            .iter()
+            .find(|(_, name, suffix)| frame.name == *name && filename.ends_with(suffix))
+            .map(|(role, _, _)| *role)
+    })
+}
+
+/// Remembers the role of each thread, by its python thread id and name
+#[derive(Default)]
+pub struct ThreadRoles {
+    roles: HashMap<u64, (Option<String>, &'static str)>,
+}
+
+impl ThreadRoles {
+    /// Gets the name of a thread labelled with its role, if it has one
+    pub fn label(
+        &mut self,
+        thread_id: u64,
+        thread_name: Option<String>,
+        frames: &[Frame],
+    ) -> Option<String> {
+        // thread ids are reused once a thread exits, and the new thread usually has a new name
+        let known = match self.roles.get(&thread_id) {
+            Some((name, role)) if *name == thread_name => Some(*role),
+            _ => None,
+        };
+        let role = match known.or_else(|| thread_role(frames)) {
+            Some(role) => role,
+            None => return thread_name,
+        };
+        self.roles.insert(thread_id, (thread_name.clone(), role));
+        Some(match thread_name {
+            Some(name) => format!("{} [{}]", name, role),
+            None => format!("[{}]", role),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::frame;
+
+    #[test]
+    fn test_thread_roles() {
+        let worker = vec![
+            frame("get", "/usr/lib/python3.11/queue.py", 1),
+            frame(
+                "_worker",
+                "/usr/lib/python3.11/concurrent/futures/thread.py",
+                1,
+            ),
+            frame("run", "/usr/lib/python3.11/threading.py", 1),
+        ];
+        assert_eq!(thread_role(&worker), Some("ThreadPoolExecutor worker"));
+        let event_loop = vec![
+            frame(
+                "_run_once",
+                "C:\\Python311\\Lib\\asyncio\\base_events.py",
+                1,
+            ),
+            frame(
+                "run_forever",
+                "C:\\Python311\\Lib\\asyncio\\base_events.py",
+                1,
+            ),
+        ];
+        assert_eq!(thread_role(&event_loop), Some("asyncio event loop"));
+        assert_eq!(thread_role(&[frame("_worker", "app/worker.py", 1)]), None);
+
+        let mut roles = ThreadRoles::default();
+        let name = Some("Thread-3".to_owned());
+        assert_eq!(
+            roles.label(1, name.clone(), &worker).as_deref(),
+            Some("Thread-3 [ThreadPoolExecutor worker]")
+        );
+        // the role is remembered until the thread id is reused by a thread with another name
+        assert_eq!(
+            roles.label(1, name, &[]).as_deref(),
+            Some("Thread-3 [ThreadPoolExecutor worker]")
+        );
+        assert_eq!(
+            roles.label(1, Some("Thread-4".to_owned()), &[]).as_deref(),
+            Some("Thread-4")
+        );
+        assert_eq!(
+            roles.label(2, None, &event_loop).as_deref(),
+            Some("[asyncio event loop]")
+        );
+    }
+}
@@ -6,17 +6,71 @@ use rand_distr::{Distribution, Exp};
 
 /// Timer is an iterator that sleeps an appropriate amount of time between iterations
 /// so that we can sample the process a certain number of times a second.
-/// We're using an irregular sampling strategy to avoid aliasing effects that can happen
-/// if the target process runs code at a similar schedule as the profiler:
+/// By default we're using an irregular sampling strategy to avoid aliasing effects that can
+/// happen if the target process runs code at a similar schedule as the profiler:
 /// https://github.com/benfred/py-spy/issues/94
+/// (with --fixed-interval, samples are evenly spaced instead)
 pub struct Timer {
     start: Instant,
     desired: Duration,
     exp: Exp<f64>,
+    // whether the time between samples is randomized
+    jitter: bool,
+    rate: u64,
+    // when the last sample was started, to time how long taking a sample takes
+    woken: Instant,
+    adaptive: Option<AdaptiveRate>,
+}
+
+// how often --adaptive-rate checks whether the sampling rate needs to change
+const ADAPTIVE_WINDOW: Duration = Duration::from_secs(1);
+
+/// Works out the sampling rate for --adaptive-rate, from how long taking samples takes.
+/// The rate is halved whenever taking a sample takes longer than the time between samples on
+/// average (which happens with large numbers of threads or subprocesses), and is doubled again
+/// once samples would still take less than half the time between them at the doubled rate.
+struct AdaptiveRate {
+    max_rate: u64,
+    window_start: Duration,
+    samples: u32,
+    busy: Duration,
+}
+
+impl AdaptiveRate {
+    fn new(max_rate: u64) -> AdaptiveRate {
+        AdaptiveRate {
+            max_rate,
+            window_start: Duration::from_secs(0),
+            samples: 0,
+            busy: Duration::from_secs(0),
+        }
+    }
+
+    /// Records how long a sample took, returning the new sampling rate if it should change
+    fn update(&mut self, rate: u64, busy: Duration, now: Duration) -> Option<u64> {
+        self.samples += 1;
+        self.busy += busy;
+        if now < self.window_start + ADAPTIVE_WINDOW {
+            return None;
+        }
+        let average = self.busy / self.samples;
+        self.window_start = now;
+        self.samples = 0;
+        self.busy = Duration::from_secs(0);
+
+        let budget = Duration::from_secs(1) / rate as u32;
+        if average > budget && rate > 1 {
+            Some(rate / 2)
+        } else if average * 4 < budget && rate < self.max_rate {
+            Some((rate * 2).min(self.max_rate))
+        } else {
+            None
+        }
+    }
 }
 
 impl Timer {
-    pub fn new(rate: f64) -> Timer {
+    pub fn new(rate: u64) -> Timer {
         // This changes a system-wide setting on Windows so that the OS wakes up every 1ms
         // instead of the default 15.6ms. This is required to have a sleep call
         // take less than 15ms, which we need since we usually profile at more than 64hz.
@@ -32,9 +86,43 @@ impl Timer {
         Timer {
             start,
             desired: Duration::from_secs(0),
-            exp: Exp::new(rate).unwrap(),
+            exp: Exp::new(rate as f64).unwrap(),
+            jitter: true,
+            rate,
+            woken: start,
+            adaptive: None,
         }
     }
+
+    /// Creates a timer with exactly 1/rate seconds between samples
+    pub fn fixed(rate: u64) -> Timer {
+        let mut timer = Timer::new(rate);
+        timer.jitter = false;
+        timer
+    }
+
+    /// Lowers the sampling rate when taking samples keeps overrunning the time between them,
+    /// and raises it back up to the original rate when there's room again
+    pub fn adaptive(mut self) -> Timer {
+        self.adaptive = Some(AdaptiveRate::new(self.rate));
+        self
+    }
+
+    /// How long to wait between the previous and the next sample
+    fn interval(&self) -> Duration {
+        let seconds = if self.jitter {
+            // using an exponential distribution to avoid aliasing
+            self.exp.sample(&mut rand::thread_rng())
+        } else {
+            1.0 / self.rate as f64
+        };
+        Duration::from_nanos((1_000_000_000.0 * seconds) as u64)
+    }
+
+    /// The number of samples currently being taken a second
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
 }
 
 impl Iterator for Timer {
@@ -43,25 +131,65 @@ impl Iterator for Timer {
     fn next(&mut self) -> Option<Self::Item> {
         let elapsed = self.start.elapsed();
 
-        // figure out how many nanoseconds should come between the previous and
-        // the next sample using an exponential distribution to avoid aliasing
-        let nanos = 1_000_000_000.0 * self.exp.sample(&mut rand::thread_rng());
+        if let Some(adaptive) = self.adaptive.as_mut() {
+            if let Some(rate) = adaptive.update(self.rate, self.woken.elapsed(), elapsed) {
+                self.rate = rate;
+                self.exp = Exp::new(rate as f64).unwrap();
+                // don't try to catch up on the samples we fell behind on at the old rate
+                self.desired = self.desired.max(elapsed);
+            }
+        }
 
         // since we want to account for the amount of time the sampling takes
         // we keep track of when we should sleep to (rather than just sleeping
-        // the amount of time from the previous line).
-        self.desired += Duration::from_nanos(nanos as u64);
+        // the amount of time between samples).
+        self.desired += self.interval();
 
         // sleep if appropriate, or warn if we are behind in sampling
         if self.desired > elapsed {
             std::thread::sleep(self.desired - elapsed);
+            self.woken = Instant::now();
             Some(Ok(self.desired - elapsed))
         } else {
+            self.woken = Instant::now();
             Some(Err(elapsed - self.desired))
         }
     }
 }
 
+/// Splits up a recording into windows of --profile-for seconds, that start every --every
+/// seconds. Nothing is sampled between the windows
+pub struct DutyCycle {
+    start: Instant,
+    profile_for: Duration,
+    every: Duration,
+}
+
+impl DutyCycle {
+    pub fn new(profile_for: u64, every: u64) -> DutyCycle {
+        DutyCycle {
+            start: Instant::now(),
+            profile_for: Duration::from_secs(profile_for),
+            every: Duration::from_secs(every),
+        }
+    }
+
+    /// How long sampling is paused for until the next window starts, if the current window
+    /// has finished
+    pub fn paused(&self) -> Option<Duration> {
+        self.until_next_window(self.start.elapsed())
+    }
+
+    fn until_next_window(&self, elapsed: Duration) -> Option<Duration> {
+        let into_window = Duration::from_nanos((elapsed.as_nanos() % self.every.as_nanos()) as u64);
+        if into_window >= self.profile_for {
+            Some(self.every - into_window)
+        } else {
+            None
+        }
+    }
+}
+
 impl Drop for Timer {
     fn drop(&mut self) {
         #[cfg(windows)]
@@ -70,3 +198,54 @@ impl Drop for Timer {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_adaptive_rate() {
+        let ms = Duration::from_millis;
+        let mut adaptive = AdaptiveRate::new(100);
+
+        // the rate only changes once a second
+        assert_eq!(adaptive.update(100, ms(20), ms(500)), None);
+        // samples taking 20ms on average overrun the 10ms between samples at 100Hz
+        assert_eq!(adaptive.update(100, ms(20), ms(1000)), Some(50));
+        // 15ms is still too long at 50Hz, but only just fits in at 25Hz
+        assert_eq!(adaptive.update(50, ms(15), ms(2000)), None);
+        assert_eq!(adaptive.update(50, ms(25), ms(3000)), Some(25));
+        assert_eq!(adaptive.update(25, ms(15), ms(4000)), None);
+
+        // the rate is raised back up once there's room to double it, but no higher than the
+        // original rate
+        assert_eq!(adaptive.update(25, ms(5), ms(5000)), Some(50));
+        assert_eq!(adaptive.update(50, ms(1), ms(6000)), Some(100));
+        assert_eq!(adaptive.update(100, ms(1), ms(7000)), None);
+    }
+
+    #[test]
+    fn test_intervals() {
+        let fixed = Timer::fixed(100);
+        assert!((0..100).all(|_| fixed.interval() == Duration::from_millis(10)));
+
+        // jittered intervals vary, but still average out to the sampling rate
+        let jittered = Timer::new(100);
+        let intervals: Vec<Duration> = (0..10000).map(|_| jittered.interval()).collect();
+        assert!(intervals.iter().any(|i| *i != intervals[0]));
+        let mean = intervals.iter().sum::<Duration>() / intervals.len() as u32;
+        assert!(mean > Duration::from_millis(9) && mean < Duration::from_millis(11));
+    }
+
+    #[test]
+    fn test_duty_cycle() {
+        let secs = Duration::from_secs;
+        let duty_cycle = DutyCycle::new(30, 600);
+        assert_eq!(duty_cycle.until_next_window(secs(0)), None);
+        assert_eq!(duty_cycle.until_next_window(secs(29)), None);
+        assert_eq!(duty_cycle.until_next_window(secs(30)), Some(secs(570)));
+        assert_eq!(duty_cycle.until_next_window(secs(599)), Some(secs(1)));
+        assert_eq!(duty_cycle.until_next_window(secs(600)), None);
+        assert_eq!(duty_cycle.until_next_window(secs(640)), Some(secs(560)));
+    }
+}
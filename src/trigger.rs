@@ -0,0 +1,112 @@
+use std::time::{Duration, Instant};
+
+use remoteprocess::Pid;
+
+use crate::config::Trigger;
+use crate::utils::process_usage;
+
+/*
+ * This file contains code to wait for a process to get busy before starting to record, for
+ * '--trigger'.
+ *
+ * The cpu usage of the process is worked out from the total cpu time it has used, which is read
+ * once a second without attaching to the process. Recording starts once the usage has stayed
+ * above the threshold for the whole window, so that short bursts of cpu don't start a recording.
+ */
+
+// how often the cpu usage of the process is read
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+pub struct CpuTrigger {
+    trigger: Trigger,
+    // (time, total cpu time) of the last reading, in seconds
+    last: Option<(f64, f64)>,
+    // when the cpu usage went over the threshold, in seconds
+    above_since: Option<f64>,
+}
+
+impl CpuTrigger {
+    pub fn new(trigger: Trigger) -> CpuTrigger {
+        CpuTrigger {
+            trigger,
+            last: None,
+            above_since: None,
+        }
+    }
+
+    /// Adds a reading of the total cpu time used by the process at a given time (both in
+    /// seconds), returning whether the trigger has fired
+    pub fn add_reading(&mut self, now: f64, cpu_time: f64) -> bool {
+        let (prev_now, prev_cpu_time) = match self.last.replace((now, cpu_time)) {
+            Some(prev) => prev,
+            None => return false,
+        };
+        let elapsed = now - prev_now;
+        if elapsed <= 0.0 {
+            return false;
+        }
+        let usage = 100.0 * (cpu_time - prev_cpu_time) / elapsed;
+        if usage <= self.trigger.cpu_percent {
+            self.above_since = None;
+            return false;
+        }
+        let since = *self.above_since.get_or_insert(prev_now);
+        now - since >= self.trigger.window as f64
+    }
+}
+
+/// Waits until the cpu usage of a process has been over the threshold of a trigger for its
+/// whole window. Returns false if the process exited first
+pub fn wait_for_trigger(pid: Pid, trigger: Trigger) -> bool {
+    let mut cpu_trigger = CpuTrigger::new(trigger);
+    let start = Instant::now();
+    loop {
+        // a process that has exited but hasn't been reaped yet still has a cpu time
+        if remoteprocess::Process::new(pid)
+            .and_then(|process| process.exe())
+            .is_err()
+        {
+            return false;
+        }
+        let cpu_time = match process_usage(pid) {
+            Some((cpu_time, _)) => cpu_time,
+            None => return false,
+        };
+        if cpu_trigger.add_reading(start.elapsed().as_secs_f64(), cpu_time) {
+            return true;
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cpu_trigger() {
+        let mut trigger = CpuTrigger::new(Trigger {
+            cpu_percent: 80.0,
+            window: 3,
+        });
+        // the first reading is only used to find the usage from the next one
+        assert!(!trigger.add_reading(0.0, 10.0));
+        // 90% for two seconds, then a dip to 50% restarts the window
+        assert!(!trigger.add_reading(1.0, 10.9));
+        assert!(!trigger.add_reading(2.0, 11.8));
+        assert!(!trigger.add_reading(3.0, 12.3));
+        // and it fires once the usage has stayed high for the whole window
+        assert!(!trigger.add_reading(4.0, 13.3));
+        assert!(!trigger.add_reading(5.0, 14.3));
+        assert!(trigger.add_reading(6.0, 15.3));
+
+        // usage can be over 100% for processes using more than one core
+        let mut trigger = CpuTrigger::new(Trigger {
+            cpu_percent: 150.0,
+            window: 1,
+        });
+        assert!(!trigger.add_reading(0.0, 0.0));
+        assert!(!trigger.add_reading(1.0, 1.0));
+        assert!(trigger.add_reading(2.0, 3.0));
+    }
+}
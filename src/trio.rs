@@ -0,0 +1,211 @@
+use std::collections::HashMap;
+
+use anyhow::{Error, Result};
+use remoteprocess::ProcessMemory;
+
+use crate::asyncio::{SuspendedFrame, TaskReader};
+use crate::python_data_access::{copy_string, find_key, object_dict, set_items, slot_offset};
+use crate::python_interpreters::{InterpreterState, ListObject, Object, ThreadState};
+use crate::stack_trace::Frame;
+use crate::version::Version;
+
+/*
+ * This file contains code to read the tree of tasks of a program using trio, for 'py-spy dump'.
+ * Every trio task is started in a nursery, which belongs to the task that opened it, so the tasks
+ * form a tree under the root task that trio.run starts with.
+ *
+ * The tasks of a run are found from the 'runner' local of the trio.run frame on the stack of the
+ * thread running it. Tasks and the runner are slotted classes, so their attributes are read
+ * through the PyMemberDef array of their type (which has the offset of each slot) instead of
+ * their __dict__. The stack of each task is read by following the coroutines it's awaiting, the
+ * same way the stacks of suspended asyncio tasks are.
+ */
+
+// the most tasks read from a single trio run
+const MAX_TASKS: usize = 1 << 16;
+// the deepest the tree of tasks is read to
+const MAX_DEPTH: usize = 256;
+
+/// A trio task, the stack of coroutines it's awaiting, and the tasks started in its nurseries
+#[derive(Debug, Clone)]
+pub struct TrioTask {
+    /// The name of the task, which is the name of the function it was started with by default
+    pub name: String,
+    /// The frames of the coroutines being awaited, innermost first. This is empty when the task
+    /// is running, since its stack is the stack of the thread running it
+    pub frames: Vec<Frame>,
+    /// The tasks started in the nurseries this task opened
+    pub children: Vec<TrioTask>,
+}
+
+/// The tasks of a call to trio.run
+#[derive(Debug, Clone)]
+pub struct TrioRun {
+    /// The python thread id of the thread the run is on
+    pub thread_id: u64,
+    /// The root task of the run, which every other task is a descendant of
+    pub root: TrioTask,
+}
+
+/// Finds the trio Runner in a stack read with its locals, from the frame of trio.run
+pub fn find_runner(frames: &[Frame]) -> Option<usize> {
+    let frame = frames.iter().find(|frame| {
+        frame.name == "run"
+            && (frame.filename.ends_with("trio/_core/_run.py")
+                || frame.filename.ends_with("trio\\_core\\_run.py"))
+    })?;
+    frame
+        .locals
+        .as_ref()?
+        .iter()
+        .find(|local| local.name == "runner")
+        .map(|local| local.addr)
+}
+
+/// Reads the tasks of trio runs, keeping track of where the slots of each type are
+pub struct TrioReader<'a, I: InterpreterState, P: ProcessMemory> {
+    process: &'a P,
+    version: &'a Version,
+    tasks: TaskReader<'a, I, P>,
+    slots: HashMap<(usize, &'static str), Option<usize>>,
+    count: usize,
+}
+
+impl<'a, I, P> TrioReader<'a, I, P>
+where
+    I: InterpreterState,
+    P: ProcessMemory,
+    <I::ThreadState as ThreadState>::FrameObject: SuspendedFrame,
+{
+    pub fn new(
+        process: &'a P,
+        version: &'a Version,
+        tasks: TaskReader<'a, I, P>,
+    ) -> TrioReader<'a, I, P> {
+        TrioReader {
+            process,
+            version,
+            tasks,
+            slots: HashMap::new(),
+            count: 0,
+        }
+    }
+
+    /// Reads the tree of tasks of a trio Runner
+    pub fn task_tree(&mut self, runner: usize) -> Result<TrioTask, Error> {
+        self.count = 0;
+        let root = self
+            .attribute(runner, "init_task")?
+            .ok_or_else(|| format_err!("Failed to find the root task of the trio run"))?;
+        self.task(root, 0)
+    }
+
+    fn task(&mut self, task: usize, depth: usize) -> Result<TrioTask, Error> {
+        self.count += 1;
+        if self.count > MAX_TASKS || depth > MAX_DEPTH {
+            return Err(format_err!("Max trio task recursion depth reached"));
+        }
+
+        let name = match self.attribute(task, "name")? {
+            Some(name) if self.tasks.type_name(name)? == "str" => {
+                copy_string(name as *const I::StringObject, self.process)?
+            }
+            _ => format!("Task at 0x{:x}", task),
+        };
+        let frames = match self.attribute(task, "coro")? {
+            Some(coroutine) => self.tasks.awaiting(coroutine)?.0,
+            None => Vec::new(),
+        };
+
+        let mut children = Vec::new();
+        if let Some(nurseries) = self.attribute(task, "_child_nurseries")? {
+            for nursery in self.list_items(nurseries)? {
+                let nursery_tasks = match self.attribute(nursery, "_children")? {
+                    Some(nursery_tasks) => nursery_tasks,
+                    None => continue,
+                };
+                for child in set_items(self.process, nursery_tasks)? {
+                    if self.tasks.type_name(child)? == "Task" {
+                        children.push(self.task(child, depth + 1)?);
+                    }
+                }
+            }
+        }
+        Ok(TrioTask {
+            name,
+            frames,
+            children,
+        })
+    }
+
+    /// Gets an attribute of an object, from its slots or otherwise its __dict__
+    fn attribute(&mut self, addr: usize, name: &'static str) -> Result<Option<usize>, Error> {
+        let object: I::Object = self.process.copy_struct(addr)?;
+        let offset = self.slot_offset(object.ob_type() as usize, name)?;
+        let value = match offset {
+            Some(offset) => self.process.copy_struct(addr + offset)?,
+            None => {
+                let dict = object_dict::<I, P>(self.process, self.version, addr)?;
+                find_key::<I::StringObject, P>(dict, self.process, name)?.unwrap_or(0)
+            }
+        };
+        Ok((value != 0).then_some(value))
+    }
+
+    /// The offset of the slot with a name in objects of a type, which is cached for each type
+    fn slot_offset(&mut self, type_addr: usize, name: &'static str) -> Result<Option<usize>> {
+        if let Some(offset) = self.slots.get(&(type_addr, name)) {
+            return Ok(*offset);
+        }
+        let offset =
+            slot_offset::<<I::Object as Object>::TypeObject, P>(self.process, type_addr, name)?;
+        self.slots.insert((type_addr, name), offset);
+        Ok(offset)
+    }
+
+    /// Gets the items in a python list
+    fn list_items(&self, addr: usize) -> Result<Vec<usize>, Error> {
+        let list: I::ListObject = self.process.copy_struct(addr)?;
+        if list.size() > MAX_TASKS {
+            return Err(format_err!(
+                "Refusing to read a list of {} items",
+                list.size()
+            ));
+        }
+        Ok(self.process.copy_vec(list.item() as usize, list.size())?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stack_trace::LocalVariable;
+    use crate::testing::frame;
+
+    #[test]
+    fn test_find_runner() {
+        let with_locals = |name: &str, filename: &str, locals| Frame {
+            locals,
+            ..frame(name, filename, 1)
+        };
+        let runner = LocalVariable {
+            name: "runner".to_owned(),
+            addr: 0x1000,
+            arg: false,
+            repr: None,
+            full_repr: None,
+        };
+        let mut frames = vec![
+            with_locals("get_events", "/trio/_core/_io_epoll.py", None),
+            with_locals("run", "/app/main.py", Some(vec![runner.clone()])),
+        ];
+        assert_eq!(find_runner(&frames), None);
+
+        frames.push(with_locals(
+            "run",
+            "/site-packages/trio/_core/_run.py",
+            Some(vec![runner]),
+        ));
+        assert_eq!(find_runner(&frames), Some(0x1000));
+    }
+}
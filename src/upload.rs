@@ -0,0 +1,355 @@
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::Error;
+use chrono::{DateTime, SecondsFormat, Utc};
+
+use crate::config::Config;
+use crate::flamegraph::Flamegraph;
+use crate::otlp::Otlp;
+use crate::pprof::Pprof;
+use crate::stack_trace::StackTrace;
+use crate::utils;
+
+/*
+ * This file contains code to continuously upload profiles to a profiling service, letting
+ * py-spy act as a profiling agent for programs that can't load an in-process profiler.
+ *
+ * Every upload period, the samples collected so far are uploaded to one of:
+ *
+ *  - An endpoint accepting the Datadog agent's profiling intake format (--upload-url). The
+ *    samples are encoded as a pprof profile (with each sample labelled by thread) and POSTed
+ *    as a multipart form, with an 'event' json part describing the profile and an
+ *    'auto.pprof' part with the gzipped profile itself.
+ *  - A Pyroscope server (--pyroscope), which takes collapsed stacks on its /ingest endpoint.
+ *  - An OpenTelemetry collector (--otlp), which takes profiles encoded with the OTLP profiles
+ *    signal over OTLP/HTTP. OTLP/gRPC isn't supported, since that needs an HTTP/2 client.
+ *
+ * Uploads are done on a background thread, so that a slow endpoint doesn't delay sampling.
+ */
+
+const BOUNDARY: &str = "py-spy-profile-boundary";
+
+enum Profile {
+    Intake(Box<Pprof>),
+    // pyroscope application name (including any tags), and the collapsed stacks
+    Pyroscope(String, Box<Flamegraph>),
+    Otlp(Box<Otlp>),
+}
+
+pub struct Uploader {
+    url: String,
+    period: Duration,
+    profile: Profile,
+    window_start: SystemTime,
+    window_ts: Instant,
+    show_linenumbers: bool,
+    sampling_rate: u64,
+    uploads: Vec<JoinHandle<()>>,
+}
+
+impl Uploader {
+    /// Uploads pprof profiles to a Datadog compatible profiling intake
+    pub fn new(config: &Config, url: &str) -> Uploader {
+        Uploader {
+            url: url.to_owned(),
+            period: Duration::from_secs(config.upload_period),
+            profile: Profile::Intake(Box::new(Pprof::new(
+                config.show_line_numbers,
+                config.sampling_rate,
+                true,
+            ))),
+            window_start: SystemTime::now(),
+            window_ts: Instant::now(),
+            show_linenumbers: config.show_line_numbers,
+            sampling_rate: config.sampling_rate,
+            uploads: Vec::new(),
+        }
+    }
+
+    /// Pushes collapsed stacks to a Pyroscope server
+    pub fn pyroscope(config: &Config, server: &str, app_name: &str) -> Uploader {
+        let mut uploader =
+            Uploader::new(config, &format!("{}/ingest", server.trim_end_matches('/')));
+        uploader.profile = Profile::Pyroscope(
+            pyroscope_name(app_name, &config.tags),
+            Box::new(Flamegraph::new(config.show_line_numbers)),
+        );
+        uploader
+    }
+
+    /// Exports profiles to an OpenTelemetry collector's OTLP/HTTP endpoint
+    pub fn otlp(config: &Config, endpoint: &str) -> Uploader {
+        let url = format!("{}/v1development/profiles", endpoint.trim_end_matches('/'));
+        let mut uploader = Uploader::new(config, &url);
+        uploader.profile = Profile::Otlp(Box::new(Otlp::new(
+            config.show_line_numbers,
+            config.sampling_rate,
+            otlp_resource(config),
+        )));
+        uploader
+    }
+
+    fn new_profile(&self) -> Profile {
+        match &self.profile {
+            Profile::Intake(_) => Profile::Intake(Box::new(Pprof::new(
+                self.show_linenumbers,
+                self.sampling_rate,
+                true,
+            ))),
+            Profile::Pyroscope(name, _) => Profile::Pyroscope(
+                name.clone(),
+                Box::new(Flamegraph::new(self.show_linenumbers)),
+            ),
+            Profile::Otlp(otlp) => Profile::Otlp(Box::new(Otlp::new(
+                self.show_linenumbers,
+                self.sampling_rate,
+                otlp.resource().to_vec(),
+            ))),
+        }
+    }
+
+    pub fn increment(&mut self, trace: &StackTrace) -> Result<(), Error> {
+        match &mut self.profile {
+            Profile::Intake(pprof) => pprof.increment(trace)?,
+            Profile::Pyroscope(_, collapsed) => collapsed.increment(trace)?,
+            Profile::Otlp(otlp) => otlp.increment(trace)?,
+        }
+        if self.window_ts.elapsed() >= self.period {
+            self.upload()?;
+        }
+        Ok(())
+    }
+
+    /// Uploads the current profile in the background, and starts collecting a new one
+    pub fn upload(&mut self) -> Result<(), Error> {
+        let next = self.new_profile();
+        let profile = std::mem::replace(&mut self.profile, next);
+        let start = std::mem::replace(&mut self.window_start, SystemTime::now());
+        let end = SystemTime::now();
+        self.window_ts = Instant::now();
+
+        let request = ureq::post(&self.url).timeout(Duration::from_secs(30));
+        let (request, body) = match profile {
+            Profile::Intake(pprof) => {
+                let mut profile = Vec::new();
+                pprof.write(&mut profile)?;
+                let content_type = format!("multipart/form-data; boundary={}", BOUNDARY);
+                (
+                    request.set("Content-Type", &content_type),
+                    multipart_body(start, end, &profile)?,
+                )
+            }
+            Profile::Pyroscope(name, collapsed) => {
+                let mut body = Vec::new();
+                collapsed.write_raw(&mut body)?;
+                let request = request
+                    .set("Content-Type", "text/plain")
+                    .query("name", &name)
+                    .query("from", &unix_seconds(start).to_string())
+                    .query("until", &unix_seconds(end).to_string())
+                    .query("format", "folded")
+                    .query("sampleRate", &self.sampling_rate.to_string())
+                    .query("spyName", "pyspy")
+                    .query("units", "samples")
+                    .query("aggregationType", "sum");
+                (request, body)
+            }
+            Profile::Otlp(otlp) => {
+                let mut body = Vec::new();
+                otlp.write(&mut body)?;
+                (request.set("Content-Type", "application/x-protobuf"), body)
+            }
+        };
+
+        // clean up after any uploads that have already finished
+        self.uploads.retain(|upload| !upload.is_finished());
+
+        let url = self.url.clone();
+        self.uploads.push(std::thread::spawn(move || {
+            if let Err(e) = request.send_bytes(&body) {
+                eprintln!("Failed to upload profile to '{}': {}", url, e);
+            }
+        }));
+        Ok(())
+    }
+
+    /// Uploads any remaining samples, and waits for all uploads to finish
+    pub fn finish(&mut self) -> Result<(), Error> {
+        self.upload()?;
+        for upload in self.uploads.drain(..) {
+            if upload.join().is_err() {
+                return Err(format_err!("Failed to upload profile to '{}'", self.url));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn unix_seconds(t: SystemTime) -> u64 {
+    t.duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Pyroscope takes tags as part of the application name, like 'app{env=prod,host=a}'
+fn pyroscope_name(app_name: &str, tags: &[(String, String)]) -> String {
+    if tags.is_empty() {
+        return app_name.to_owned();
+    }
+    let tags: Vec<String> = tags.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+    format!("{}{{{}}}", app_name, tags.join(","))
+}
+
+/// Resource attributes for OTLP profiles: the service.name comes from --app-name, and --tag
+/// can be used to add other attributes or to override the detected host.name
+fn otlp_resource(config: &Config) -> Vec<(String, String)> {
+    let service_name = config
+        .app_name
+        .clone()
+        .unwrap_or_else(|| String::from("unknown_service:python"));
+    let mut resource = vec![(String::from("service.name"), service_name)];
+    if let Some(host_name) = utils::hostname() {
+        resource.push((String::from("host.name"), host_name));
+    }
+    resource.push((String::from("telemetry.sdk.name"), String::from("py-spy")));
+    for (key, value) in config.tags.iter() {
+        resource.retain(|(k, _)| k != key);
+        resource.push((key.clone(), value.clone()));
+    }
+    resource
+}
+
+fn multipart_body(start: SystemTime, end: SystemTime, pprof: &[u8]) -> Result<Vec<u8>, Error> {
+    let timestamp =
+        |t: SystemTime| DateTime::<Utc>::from(t).to_rfc3339_opts(SecondsFormat::Secs, true);
+    let event = serde_json::json!({
+        "attachments": ["auto.pprof"],
+        "start": timestamp(start),
+        "end": timestamp(end),
+        "family": "python",
+        "version": "4",
+        "tags_profiler": format!("profiler:py-spy,profiler_version:{}", env!("CARGO_PKG_VERSION")),
+    });
+
+    let mut body = Vec::new();
+    body.extend_from_slice(format!("--{}\r\n", BOUNDARY).as_bytes());
+    body.extend_from_slice(
+        b"Content-Disposition: form-data; name=\"event\"; filename=\"event.json\"\r\n",
+    );
+    body.extend_from_slice(b"Content-Type: application/json\r\n\r\n");
+    body.extend_from_slice(serde_json::to_string(&event)?.as_bytes());
+    body.extend_from_slice(format!("\r\n--{}\r\n", BOUNDARY).as_bytes());
+    body.extend_from_slice(
+        b"Content-Disposition: form-data; name=\"auto.pprof\"; filename=\"auto.pprof\"\r\n",
+    );
+    body.extend_from_slice(b"Content-Type: application/octet-stream\r\n\r\n");
+    body.extend_from_slice(pprof);
+    body.extend_from_slice(format!("\r\n--{}--\r\n", BOUNDARY).as_bytes());
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{frame, trace};
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+
+    // minimal http server that reads a single request, returning the request line and body
+    fn serve_one_request() -> (String, JoinHandle<(String, Vec<u8>)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = format!("http://{}", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut content_length = 0;
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                if header == "\r\n" {
+                    break;
+                }
+                if let Some(length) = header.to_lowercase().strip_prefix("content-length: ") {
+                    content_length = length.trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+            (&stream)
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+            (request_line, body)
+        });
+        (address, server)
+    }
+
+    fn thread() -> StackTrace {
+        let mut stack = trace(vec![frame("busy", "test.py", 1)]);
+        stack.thread_name = Some(String::from("MainThread"));
+        stack
+    }
+
+    #[test]
+    fn test_upload_profile() {
+        let (address, server) = serve_one_request();
+        let mut config = Config::default();
+        config.upload_period = 60;
+        let mut uploader = Uploader::new(&config, &format!("{}/profiling/v1/input", address));
+        uploader.increment(&thread()).unwrap();
+        uploader.finish().unwrap();
+
+        let (request_line, body) = server.join().unwrap();
+        assert!(request_line.starts_with("POST /profiling/v1/input"));
+        let body = String::from_utf8_lossy(&body);
+        assert!(body.contains("name=\"event\""));
+        assert!(body.contains("\"attachments\":[\"auto.pprof\"]"));
+        assert!(body.contains("name=\"auto.pprof\""));
+        assert!(body.ends_with(&format!("--{}--\r\n", BOUNDARY)));
+    }
+
+    #[test]
+    fn test_pyroscope_push() {
+        let (address, server) = serve_one_request();
+        let mut config = Config::default();
+        config.upload_period = 10;
+        config.tags = vec![(String::from("env"), String::from("prod"))];
+        let mut uploader = Uploader::pyroscope(&config, &format!("{}/", address), "myservice");
+        uploader.increment(&thread()).unwrap();
+        uploader.increment(&thread()).unwrap();
+        uploader.finish().unwrap();
+
+        let (request_line, body) = server.join().unwrap();
+        assert!(request_line.starts_with("POST /ingest?name=myservice%7Benv%3Dprod%7D&from="));
+        assert!(request_line.contains("&format=folded&sampleRate=100&spyName=pyspy"));
+        assert_eq!(String::from_utf8(body).unwrap(), "busy (test.py) 2\n");
+    }
+
+    #[test]
+    fn test_otlp_export() {
+        let (address, server) = serve_one_request();
+        let mut config = Config::default();
+        config.app_name = Some(String::from("myservice"));
+        config.tags = vec![(String::from("host.name"), String::from("web-1"))];
+        let resource = otlp_resource(&config);
+        assert_eq!(
+            resource[0],
+            (String::from("service.name"), String::from("myservice"))
+        );
+        assert_eq!(
+            resource.last().unwrap(),
+            &(String::from("host.name"), String::from("web-1"))
+        );
+        assert_eq!(resource.iter().filter(|(k, _)| k == "host.name").count(), 1);
+
+        let mut uploader = Uploader::otlp(&config, &address);
+        uploader.increment(&thread()).unwrap();
+        uploader.finish().unwrap();
+
+        let (request_line, body) = server.join().unwrap();
+        assert!(request_line.starts_with("POST /v1development/profiles "));
+        assert!(body.windows(9).any(|w| w == b"myservice"));
+    }
+}
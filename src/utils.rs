@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 #[cfg(unwind)]
 pub fn resolve_filename(filename: &str, modulename: &str) -> Option<String> {
     // check the filename first, if it exists use it
@@ -20,3 +22,143 @@ pub fn resolve_filename(filename: &str, modulename: &str) -> Option<String> {
 
     None
 }
+
+#[cfg(unix)]
+#[allow(dead_code)]
+pub fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) } != 0 {
+        return None;
+    }
+    let len = buf.iter().position(|&c| c == 0).unwrap_or(buf.len());
+    Some(String::from_utf8_lossy(&buf[..len]).into_owned())
+}
+
+#[cfg(windows)]
+#[allow(dead_code)]
+pub fn hostname() -> Option<String> {
+    std::env::var("COMPUTERNAME").ok()
+}
+
+/// Returns the total cpu time used by a process in seconds, and its resident memory in bytes
+#[cfg(target_os = "linux")]
+pub fn process_usage(pid: remoteprocess::Pid) -> Option<(f64, u64)> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // the process name is in parentheses and can contain spaces, so skip past it first
+    let fields: Vec<&str> = stat[stat.rfind(')')? + 1..].split_whitespace().collect();
+    // utime, stime and rss are the 14th, 15th and 24th fields (with the state being the 3rd)
+    let utime: u64 = fields.get(11)?.parse().ok()?;
+    let stime: u64 = fields.get(12)?.parse().ok()?;
+    let rss: u64 = fields.get(21)?.parse().ok()?;
+    let ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as f64;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+    Some(((utime + stime) as f64 / ticks, rss * page_size))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn process_usage(_pid: remoteprocess::Pid) -> Option<(f64, u64)> {
+    None
+}
+
+/// Returns the cpu time used by each thread of a process in nanoseconds, keyed by the OS
+/// thread id
+#[cfg(target_os = "linux")]
+#[allow(dead_code)]
+pub fn thread_cpu_times(pid: remoteprocess::Pid) -> std::io::Result<HashMap<u64, u64>> {
+    let mut times = HashMap::new();
+    for entry in std::fs::read_dir(format!("/proc/{}/task", pid))? {
+        let entry = entry?;
+        let tid = match entry.file_name().to_string_lossy().parse::<u64>() {
+            Ok(tid) => tid,
+            Err(_) => continue,
+        };
+        // the first field of schedstat is the time spent running on the cpu, in nanoseconds.
+        // threads can exit while we're reading them, which just leaves them out
+        let schedstat = match std::fs::read_to_string(entry.path().join("schedstat")) {
+            Ok(schedstat) => schedstat,
+            Err(_) => continue,
+        };
+        if let Some(Ok(time)) = schedstat.split_whitespace().next().map(str::parse) {
+            times.insert(tid, time);
+        }
+    }
+    Ok(times)
+}
+
+#[cfg(not(target_os = "linux"))]
+#[allow(dead_code)]
+pub fn thread_cpu_times(_pid: remoteprocess::Pid) -> std::io::Result<HashMap<u64, u64>> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "reading the cpu time of threads is only supported on linux",
+    ))
+}
+
+/// Returns a time as the number of microseconds since the unix epoch
+#[allow(dead_code)]
+pub fn unix_micros(time: std::time::SystemTime) -> u64 {
+    time.duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or(0)
+}
+
+/// Replaces '{name}' variables in a template with their values, returning an error for
+/// any variables that aren't known
+#[allow(dead_code)]
+pub fn expand_template(template: &str, vars: &[(&str, String)]) -> Result<String, anyhow::Error> {
+    let mut ret = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        ret.push_str(&rest[..start]);
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => return Err(format_err!("Unterminated variable in '{}'", template)),
+        };
+        let name = &rest[start + 1..end];
+        match vars.iter().find(|(var, _)| *var == name) {
+            Some((_, value)) => ret.push_str(value),
+            None => {
+                return Err(format_err!(
+                    "Unknown variable '{{{}}}' in '{}'",
+                    name,
+                    template
+                ))
+            }
+        }
+        rest = &rest[end + 1..];
+    }
+    ret.push_str(rest);
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand_template() {
+        let vars = [
+            ("pid", String::from("1234")),
+            ("exe", String::from("python")),
+        ];
+        assert_eq!(
+            expand_template("/profiles/{exe}-{pid}.svg", &vars).unwrap(),
+            "/profiles/python-1234.svg"
+        );
+        assert_eq!(
+            expand_template("profile.svg", &vars).unwrap(),
+            "profile.svg"
+        );
+        assert!(expand_template("{unknown}.svg", &vars).is_err());
+        assert!(expand_template("{pid.svg", &vars).is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_process_usage() {
+        let (cpu, rss) = process_usage(std::process::id() as remoteprocess::Pid).unwrap();
+        assert!(cpu >= 0.0);
+        assert!(rss > 0);
+        assert!(process_usage(-1).is_none());
+    }
+}
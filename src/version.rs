@@ -60,6 +60,32 @@ impl Version {
     }
 }
 
+/// Parses a version given as 'major.minor' or 'major.minor.patch', like '2.7' or '3.9.18'
+impl std::str::FromStr for Version {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Version, Error> {
+        let parts = value
+            .split('.')
+            .map(|part| part.parse::<u64>())
+            .collect::<Result<Vec<u64>, _>>()
+            .map_err(|_| format_err!("Invalid python version '{}'", value))?;
+        match parts.as_slice() {
+            [major, minor] | [major, minor, _] => Ok(Version {
+                major: *major,
+                minor: *minor,
+                patch: parts.get(2).copied().unwrap_or(0),
+                release_flags: "".to_owned(),
+                build_metadata: None,
+            }),
+            _ => Err(format_err!(
+                "Invalid python version '{}', expected major.minor like '2.7'",
+                value
+            )),
+        }
+    }
+}
+
 impl std::fmt::Display for Version {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
@@ -193,4 +219,17 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn test_parse_version() {
+        let version: Version = "2.7".parse().unwrap();
+        assert_eq!((version.major, version.minor, version.patch), (2, 7, 0));
+        let version: Version = "3.9.18".parse().unwrap();
+        assert_eq!((version.major, version.minor, version.patch), (3, 9, 18));
+        assert_eq!(version.to_string(), "3.9.18");
+
+        assert!("3".parse::<Version>().is_err());
+        assert!("3.x".parse::<Version>().is_err());
+        assert!("2.7.18.1".parse::<Version>().is_err());
+    }
 }
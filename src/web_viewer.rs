@@ -0,0 +1,289 @@
+use std::collections::HashMap;
+use std::io::BufReader;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Error;
+use serde_derive::Serialize;
+
+use crate::config::Config;
+use crate::console_viewer::{update_function_statistics, FunctionStatistics};
+use crate::html::HtmlFlamegraph;
+use crate::http::{
+    accept_websocket, bind, is_websocket_upgrade, read_request, write_websocket_text, Response,
+};
+use crate::stack_trace::StackTrace;
+
+/*
+ * This file contains code for 'top --serve', which shows the samples from 'top' as a live
+ * flamegraph in a browser instead of in the terminal, so that other people can watch a profile
+ * without a screen share.
+ *
+ * The page (src/templates/live.html) connects back over a websocket, and every refresh the
+ * whole profile so far is pushed to it: the flamegraph tree (in the same layout the html
+ * flamegraph uses), and the own/total counts of each function like the ones 'top' shows, which
+ * the page uses for a sandwich view of the callers and callees of a function.
+ */
+
+const TEMPLATE: &str = include_str!("templates/live.html");
+
+/// How long a browser can take to read an update before it's disconnected, so that a slow
+/// client can't hold up sampling
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+pub struct WebViewer {
+    address: SocketAddr,
+    flamegraph: HtmlFlamegraph,
+    function_counts: HashMap<String, FunctionStatistics>,
+    samples: u64,
+    errors: u64,
+    last_error: Option<String>,
+    last_update: Instant,
+    // the most recent update, which is sent to browsers as soon as they connect
+    latest: Arc<Mutex<Option<String>>>,
+    clients: Arc<Mutex<Vec<TcpStream>>>,
+    config: Config,
+}
+
+#[derive(Debug, Serialize)]
+struct Update {
+    samples: u64,
+    errors: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_error: Option<String>,
+    flamegraph: serde_json::Value,
+    functions: Vec<FunctionSummary>,
+}
+
+#[derive(Debug, Serialize)]
+struct FunctionSummary {
+    name: String,
+    own: u64,
+    total: u64,
+    current_own: u64,
+    current_total: u64,
+}
+
+impl WebViewer {
+    /// Starts serving the live view on an address, like '127.0.0.1:8080' or ':8080' for all
+    /// interfaces
+    pub fn new(address: &str, title: &str, config: &Config) -> Result<WebViewer, Error> {
+        let listener = bind(address)?;
+        let latest = Arc::new(Mutex::new(None));
+        let clients = Arc::new(Mutex::new(Vec::new()));
+
+        let page = page(title);
+        let server_latest = latest.clone();
+        let server_clients = clients.clone();
+        let local_address = listener.local_addr()?;
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let page = page.clone();
+                let latest = server_latest.clone();
+                let clients = server_clients.clone();
+                std::thread::spawn(move || {
+                    if let Err(e) = serve(stream, &page, &latest, &clients) {
+                        info!("Failed to serve request: {}", e);
+                    }
+                });
+            }
+        });
+
+        Ok(WebViewer {
+            address: local_address,
+            flamegraph: HtmlFlamegraph::new(false),
+            function_counts: HashMap::new(),
+            samples: 0,
+            errors: 0,
+            last_error: None,
+            last_update: Instant::now(),
+            latest,
+            clients,
+            config: config.clone(),
+        })
+    }
+
+    /// The address the live view is being served on
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    pub fn increment(&mut self, traces: &[StackTrace]) -> Result<(), Error> {
+        for trace in traces {
+            if !(self.config.include_idle || trace.active || trace.task_name.is_some()) {
+                continue;
+            }
+            if self.config.gil_only && !trace.owns_gil {
+                continue;
+            }
+            self.flamegraph.increment(trace)?;
+            update_function_statistics(&mut self.function_counts, trace, function_name);
+        }
+        self.samples += 1;
+        self.maybe_update()
+    }
+
+    pub fn increment_error(&mut self, err: &Error) -> Result<(), Error> {
+        self.errors += 1;
+        self.last_error = Some(format!("{}", err));
+        self.maybe_update()
+    }
+
+    fn maybe_update(&mut self) -> Result<(), Error> {
+        if self.last_update.elapsed().as_secs_f64() < self.config.refresh_seconds {
+            return Ok(());
+        }
+        self.last_update = Instant::now();
+
+        let update = serde_json::to_string(&self.update()?)?;
+        // browsers that have gone away (or can't keep up) are disconnected
+        self.clients
+            .lock()
+            .unwrap()
+            .retain_mut(|client| write_websocket_text(client, &update).is_ok());
+        *self.latest.lock().unwrap() = Some(update);
+
+        for counts in self.function_counts.values_mut() {
+            counts.current_own = 0;
+            counts.current_total = 0;
+        }
+        Ok(())
+    }
+
+    fn update(&self) -> Result<Update, Error> {
+        let mut functions: Vec<FunctionSummary> = self
+            .function_counts
+            .iter()
+            .map(|(name, counts)| FunctionSummary {
+                name: name.clone(),
+                own: counts.overall_own,
+                total: counts.overall_total,
+                current_own: counts.current_own,
+                current_total: counts.current_total,
+            })
+            .collect();
+        functions.sort_by(|a, b| (b.own, b.total, &a.name).cmp(&(a.own, a.total, &b.name)));
+        Ok(Update {
+            samples: self.samples,
+            errors: self.errors,
+            last_error: self.last_error.clone(),
+            flamegraph: self.flamegraph.tree()?,
+            functions,
+        })
+    }
+}
+
+/// Names functions the same way as the frames in the flamegraph, so that the page can find the
+/// frames of a function for the sandwich view
+fn function_name(frame: &crate::stack_trace::Frame) -> String {
+    let filename = match &frame.short_filename {
+        Some(f) => f,
+        None => &frame.filename,
+    };
+    if filename.is_empty() {
+        frame.name.clone()
+    } else {
+        format!("{} ({})", frame.name, filename)
+    }
+}
+
+fn page(title: &str) -> String {
+    let title = title
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;");
+    TEMPLATE.replace("{{TITLE}}", &title).replace(
+        "{{EXPORTER}}",
+        &format!("py-spy@{}", env!("CARGO_PKG_VERSION")),
+    )
+}
+
+fn serve(
+    mut stream: TcpStream,
+    page: &str,
+    latest: &Mutex<Option<String>>,
+    clients: &Mutex<Vec<TcpStream>>,
+) -> Result<(), Error> {
+    stream.set_read_timeout(Some(Duration::from_secs(30)))?;
+    let request = match read_request(&mut BufReader::new(&stream)) {
+        Ok(request) => request,
+        Err(e) => {
+            Response::bad_request(&e).write(&mut stream)?;
+            return Ok(());
+        }
+    };
+    info!("{} {}", request.method, request.path);
+
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/live") if is_websocket_upgrade(&request) => {
+            stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+            accept_websocket(&request, &mut stream)?;
+            if let Some(update) = latest.lock().unwrap().as_ref() {
+                write_websocket_text(&mut stream, update)?;
+            }
+            clients.lock().unwrap().push(stream);
+        }
+        ("GET", "/") => {
+            Response::new(200, "text/html", page.as_bytes().to_vec()).write(&mut stream)?
+        }
+        _ => Response::error(404, "Not found").write(&mut stream)?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stack_trace::Frame;
+    use crate::testing::{frame, trace};
+
+    fn app_frame(name: &str) -> Frame {
+        let mut f = frame(name, "/src/app.py", 1);
+        f.short_filename = Some(String::from("app.py"));
+        f
+    }
+
+    fn thread(names: &[&str], active: bool) -> StackTrace {
+        let mut stack = trace(names.iter().map(|name| app_frame(name)).collect());
+        stack.active = active;
+        stack
+    }
+
+    #[test]
+    fn test_web_viewer() {
+        let mut config = Config::default();
+        config.refresh_seconds = 0.0;
+        let mut viewer = WebViewer::new("127.0.0.1:0", "python app.py", &config).unwrap();
+        viewer
+            .increment(&[
+                thread(&["work", "main"], true),
+                thread(&["sleep", "main"], false),
+            ])
+            .unwrap();
+        viewer
+            .increment(&[thread(&["work", "work", "main"], true)])
+            .unwrap();
+
+        let update = viewer.update().unwrap();
+        assert_eq!(update.samples, 2);
+        let functions: Vec<(&str, u64, u64)> = update
+            .functions
+            .iter()
+            .map(|f| (f.name.as_str(), f.own, f.total))
+            .collect();
+        // the idle thread isn't counted, and the recursive call to work is only counted once
+        assert_eq!(
+            functions,
+            vec![("work (app.py)", 2, 2), ("main (app.py)", 0, 2)]
+        );
+        // the names of the functions match the names of the frames in the flamegraph
+        assert_eq!(update.flamegraph["value"], 2);
+        assert_eq!(update.flamegraph["children"][0]["name"], "main (app.py)");
+        assert!(viewer.latest.lock().unwrap().is_some());
+
+        let page = page("python <app.py>");
+        assert!(page.contains("<title>python &lt;app.py&gt;</title>"));
+        assert!(!page.contains("{{EXPORTER}}"));
+    }
+}
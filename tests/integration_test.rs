@@ -1,5 +1,5 @@
 extern crate py_spy;
-use py_spy::{Config, Pid, PythonSpy};
+use py_spy::{Config, Pid, PythonSpy, StackTrace};
 use std::collections::HashSet;
 
 struct ScriptRunner {
@@ -70,10 +70,8 @@ fn test_thread_reuse() {
     // and this caused errors on native unwind (since the native thread had
     // exited). Test that this works with a simple script that creates
     // a couple short lived threads, and then profiling with native enabled
-    let config = Config {
-        native: true,
-        ..Default::default()
-    };
+    let mut config = Config::default();
+    config.native = true;
     let mut runner = TestRunner::new(config, "./tests/scripts/thread_reuse.py");
 
     let mut errors = 0;
@@ -131,6 +129,67 @@ fn test_long_sleep() {
     assert!(!traces[0].active);
 }
 
+#[cfg(feature = "python2")]
+#[test]
+fn test_python2() {
+    #[cfg(target_os = "macos")]
+    {
+        // We need root permissions here to run this on OSX
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+    }
+
+    // python 2.7 isn't installed on most machines anymore
+    let installed = std::process::Command::new("python2.7")
+        .arg("--version")
+        .output()
+        .map(|output| output.status.success())
+        .unwrap_or(false);
+    if !installed {
+        return;
+    }
+
+    let child = ScriptRunner::new("python2.7", "./tests/scripts/longsleep.py");
+    std::thread::sleep(std::time::Duration::from_millis(400));
+    let mut spy = PythonSpy::retry_new(child.id(), &Config::default(), 20).unwrap();
+    assert_eq!(spy.version.major, 2);
+
+    let traces = spy.get_stack_traces().unwrap();
+    assert_eq!(traces.len(), 1);
+    let frames = &traces[0].frames;
+    assert_eq!(frames[0].name, "longsleep");
+    assert_eq!(frames[0].line, 5);
+    assert_eq!(frames[1].name, "<module>");
+    assert_eq!(frames[1].line, 9);
+}
+
+#[test]
+fn test_qualified_names() {
+    #[cfg(target_os = "macos")]
+    {
+        // We need root permissions here to run this on OSX
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+    }
+    let mut config = Config::default();
+    config.qualified_names = true;
+    let mut runner = TestRunner::new(config, "./tests/scripts/methods.py");
+
+    let traces = runner.spy.get_stack_traces().unwrap();
+    assert_eq!(traces.len(), 1);
+    let names: Vec<&str> = traces[0]
+        .frames
+        .iter()
+        .map(|frame| frame.name.as_str())
+        .collect();
+    assert_eq!(
+        names,
+        vec!["Worker.wait", "Worker.run", "Worker.start", "<module>"]
+    );
+}
+
 #[test]
 fn test_thread_names() {
     #[cfg(target_os = "macos")]
@@ -140,10 +199,8 @@ fn test_thread_names() {
             return;
         }
     }
-    let config = Config {
-        include_idle: true,
-        ..Default::default()
-    };
+    let mut config = Config::default();
+    config.include_idle = true;
     let mut runner = TestRunner::new(config, "./tests/scripts/thread_names.py");
 
     let traces = runner.spy.get_stack_traces().unwrap();
@@ -166,6 +223,134 @@ fn test_thread_names() {
     }
 }
 
+#[test]
+fn test_subinterpreters() {
+    #[cfg(target_os = "macos")]
+    {
+        // We need root permissions here to run this on OSX
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+    }
+    let mut config = Config::default();
+    config.include_idle = true;
+    let mut runner = TestRunner::new(config, "./tests/scripts/subinterpreters.py");
+
+    // subinterpreters can only be created from python code with python 3.8+
+    if runner.spy.version.major != 3 || runner.spy.version.minor < 8 {
+        return;
+    }
+
+    let traces = runner.spy.get_stack_traces().unwrap();
+    let subinterpreter = traces
+        .iter()
+        .find(|trace| {
+            trace
+                .frames
+                .iter()
+                .any(|frame| frame.name == "subinterpreter_sleep")
+        })
+        .unwrap();
+    assert_eq!(subinterpreter.interpreter_id, Some(1));
+
+    // every stack is labelled with its interpreter when there are subinterpreters
+    assert!(traces.iter().all(|trace| trace.interpreter_id.is_some()));
+    assert!(traces
+        .iter()
+        .any(|trace| trace.interpreter_id == Some(0) && trace.thread_name.is_some()));
+}
+
+#[test]
+fn test_asyncio() {
+    #[cfg(target_os = "macos")]
+    {
+        // We need root permissions here to run this on OSX
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+    }
+    let runner = TestRunner::new(Config::default(), "./tests/scripts/asyncio_tasks.py");
+
+    // suspended tasks can only be read for python 3.7 to 3.11
+    let version = &runner.spy.version;
+    if version.major != 3 || version.minor < 7 || version.minor > 11 {
+        return;
+    }
+    let mut config = Config::default();
+    config.asyncio = true;
+    let mut spy = PythonSpy::new(runner.child.id(), &config).unwrap();
+    let traces = spy.get_stack_traces().unwrap();
+
+    // the two tasks sleeping are awaiting 'sleeper' from 'waiter', and the main task is waiting
+    // for them to finish
+    let tasks: Vec<_> = traces
+        .iter()
+        .filter(|trace| trace.task_name.is_some())
+        .collect();
+    assert_eq!(tasks.len(), 3);
+    let sleeping: Vec<_> = tasks
+        .iter()
+        .filter(|trace| trace.frames.len() == 3)
+        .collect();
+    assert_eq!(sleeping.len(), 2);
+    for task in sleeping {
+        assert_eq!(task.frames[0].name, "sleep");
+        assert_eq!(task.frames[1].name, "sleeper");
+        assert_eq!(task.frames[2].name, "waiter");
+        assert!(!task.active);
+    }
+
+    // and each task is on the thread running the event loop
+    let thread = traces
+        .iter()
+        .find(|trace| trace.task_name.is_none())
+        .unwrap();
+    assert!(tasks.iter().all(|task| task.thread_id == thread.thread_id));
+}
+
+#[test]
+fn test_trio() {
+    #[cfg(target_os = "macos")]
+    {
+        // We need root permissions here to run this on OSX
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+    }
+    let mut runner = TestRunner::new(Config::default(), "./tests/scripts/trio_nursery.py");
+
+    // the runs are empty when trio isn't installed, or the python version isn't 3.7 to 3.11
+    let runs = runner.spy.get_trio_runs().unwrap();
+    if runs.is_empty() {
+        return;
+    }
+    assert_eq!(runs.len(), 1);
+    let traces = runner.spy.get_stack_traces().unwrap();
+    assert_eq!(runs[0].thread_id, traces[0].thread_id);
+
+    // the main task is started by trio in the system nursery of the root task
+    let root = &runs[0].root;
+    assert_eq!(root.name, "<init>");
+    let main = root
+        .children
+        .iter()
+        .find(|task| task.name == "__main__.main")
+        .unwrap();
+    assert_eq!(main.frames.last().unwrap().name, "main");
+
+    // and started two workers, which each started a sleeper
+    assert_eq!(main.children.len(), 2);
+    for worker in &main.children {
+        assert_eq!(worker.name, "__main__.worker");
+        assert_eq!(worker.children.len(), 1);
+        let sleeper = &worker.children[0];
+        assert_eq!(sleeper.name, "__main__.sleeper");
+        assert_eq!(sleeper.frames.last().unwrap().name, "sleeper");
+        assert!(sleeper.frames.iter().any(|frame| frame.name == "sleep"));
+        assert!(sleeper.children.is_empty());
+    }
+}
+
 #[test]
 fn test_recursive() {
     #[cfg(target_os = "macos")]
@@ -267,10 +452,8 @@ fn test_local_vars() {
         }
     }
 
-    let config = Config {
-        dump_locals: 1,
-        ..Default::default()
-    };
+    let mut config = Config::default();
+    config.dump_locals = 1;
     let mut runner = TestRunner::new(config, "./tests/scripts/local_vars.py");
 
     let traces = runner.spy.get_stack_traces().unwrap();
@@ -335,6 +518,232 @@ fn test_local_vars() {
     }
 }
 
+#[test]
+fn test_nested_vars() {
+    #[cfg(target_os = "macos")]
+    {
+        // We need root permissions here to run this on OSX
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+    }
+
+    let mut config = Config::default();
+    config.dump_locals = 1;
+    config.locals_depth = Some(2);
+    config.locals_max_items = Some(3);
+    let mut runner = TestRunner::new(config, "./tests/scripts/nested_vars.py");
+
+    // we only support dictionary lookup on python 3.6+ right now
+    let version = &runner.spy.version;
+    if version.major != 3 || version.minor < 6 {
+        return;
+    }
+    let traces = runner.spy.get_stack_traces().unwrap();
+    let frame = &traces[0].frames[0];
+    let payload = &frame.locals.as_ref().unwrap()[0];
+    assert_eq!(payload.name, "payload");
+
+    // the list nested three levels deep isn't shown, and only the first three items of lists are
+    assert_eq!(
+        payload.repr.as_deref(),
+        Some("{\"user\": {\"id\": 1, \"tags\": [...]}, \"items\": [1, 2, 3, ...], \"ids\": {7}}")
+    );
+}
+
+#[test]
+fn test_long_vars() {
+    #[cfg(target_os = "macos")]
+    {
+        // We need root permissions here to run this on OSX
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+    }
+
+    let mut config = Config::default();
+    config.dump_locals = 1;
+    config.args_only = true;
+    config.locals_max_str_len = Some(40);
+    config.locals_output = Some("locals.json".to_owned());
+    let mut runner = TestRunner::new(config, "./tests/scripts/long_vars.py");
+
+    let traces = runner.spy.get_stack_traces().unwrap();
+    let frame = &traces[0].frames[0];
+    let locals = frame.locals.as_ref().unwrap();
+    let query = &locals[0];
+    assert_eq!(query.name, "query");
+    assert_eq!(
+        query.repr.as_deref(),
+        Some("\"SELECT * FROM events WHERE id = 0 OR id ...\"")
+    );
+
+    // the whole query is read for --locals-output, even though it's longer than strings that
+    // are copied from the process otherwise
+    let full_repr = query.full_repr.as_ref().unwrap();
+    assert!(full_repr.len() > 10000);
+    assert!(full_repr.ends_with("OR id = 999\""));
+
+    // bytes are shown in hex on python 3, where they aren't strings
+    if runner.spy.version.major == 3 {
+        let params = &locals[1];
+        assert_eq!(params.name, "params");
+        assert_eq!(
+            params.repr.as_deref(),
+            Some("<10 bytes: 0001feff70792d737079>")
+        );
+    }
+}
+
+#[test]
+fn test_object_vars() {
+    #[cfg(target_os = "macos")]
+    {
+        // We need root permissions here to run this on OSX
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+    }
+
+    let mut config = Config::default();
+    config.dump_locals = 1;
+    config.args_only = true;
+    let mut runner = TestRunner::new(config, "./tests/scripts/object_vars.py");
+
+    // dataclasses are only in python 3.7+
+    let version = &runner.spy.version;
+    if version.major != 3 || version.minor < 7 {
+        return;
+    }
+    let traces = runner.spy.get_stack_traces().unwrap();
+    let frame = &traces[0].frames[0];
+    assert_eq!(frame.name, "object_lookup");
+
+    // only the arguments are included, and not the 'cached' local
+    let locals: Vec<(&str, &str)> = frame
+        .locals
+        .as_ref()
+        .unwrap()
+        .iter()
+        .map(|local| (local.name.as_str(), local.repr.as_deref().unwrap()))
+        .collect();
+    assert_eq!(
+        locals,
+        vec![
+            ("path", "PurePosixPath(\"/tmp/data/input.csv\")"),
+            ("color", "Color.RED"),
+            ("point", "Point(x=1, y=2.5, label=\"origin\")"),
+        ]
+    );
+}
+
+#[test]
+fn test_lock_contention() {
+    #[cfg(target_os = "macos")]
+    {
+        // We need root permissions here to run this on OSX
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+    }
+
+    let mut config = Config::default();
+    config.lock_contention = true;
+    let mut runner = TestRunner::new(config, "./tests/scripts/lock_contention.py");
+
+    // give the threads a chance to start waiting on the locks. The main thread also waits on a
+    // lock while starting the other threads, so check that it's waiting in the script
+    let waiting = |traces: &[StackTrace], name: &str| {
+        traces.iter().any(|trace| {
+            trace.thread_name.as_deref() == Some(name)
+                && trace.lock_wait.is_some()
+                && trace
+                    .frames
+                    .first()
+                    .is_some_and(|frame| frame.name == "wait")
+        })
+    };
+    let mut traces = runner.spy.get_stack_traces().unwrap();
+    for _ in 0..20 {
+        if waiting(&traces, "rlock_waiter") && waiting(&traces, "MainThread") {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        traces = runner.spy.get_stack_traces().unwrap();
+    }
+    let thread = |name: &str| {
+        traces
+            .iter()
+            .find(|trace| trace.thread_name.as_deref() == Some(name))
+            .unwrap()
+    };
+
+    // the owner of an RLock is stored on the lock
+    let rlock_wait = thread("rlock_waiter").lock_wait.as_ref().unwrap();
+    assert_eq!(rlock_wait.kind, "_thread.RLock");
+    assert_eq!(rlock_wait.holder, Some(thread("rlock_holder").thread_id));
+
+    // while plain locks are matched up with the other thread that has it as a local
+    let lock_wait = thread("MainThread").lock_wait.as_ref().unwrap();
+    assert_eq!(lock_wait.kind, "_thread.lock");
+    assert_eq!(lock_wait.holder, Some(thread("lock_holder").thread_id));
+    assert_ne!(lock_wait.lock, rlock_wait.lock);
+
+    // threads holding the locks aren't waiting on them
+    assert!(thread("rlock_holder").lock_wait.is_none());
+    assert!(thread("lock_holder").lock_wait.is_none());
+    // and locals are only kept with --locals
+    assert!(traces[0].frames.iter().all(|frame| frame.locals.is_none()));
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_python_child_process() {
+    // profiling a wrapper that runs python in a child process points at the child
+    let process = ScriptRunner::new("bash", "./tests/scripts/delayed_launch.sh");
+    std::thread::sleep(std::time::Duration::from_millis(1000));
+    let err = match PythonSpy::new(process.id(), &Config::default()) {
+        Ok(_) => panic!("bash shouldn't be profiled as python"),
+        Err(err) => err.to_string(),
+    };
+    assert!(err.contains("hasn't loaded a python library"), "{}", err);
+    assert!(err.contains("child process"), "{}", err);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_memory() {
+    let process = ScriptRunner::new("python", "./tests/scripts/memory_allocations.py");
+    std::thread::sleep(std::time::Duration::from_millis(400));
+
+    // tracemalloc's traces can only be read from python 3.9 to 3.11
+    let spy = PythonSpy::retry_new(process.id(), &Config::default(), 20).unwrap();
+    if spy.version.major != 3 || !(9..=11).contains(&spy.version.minor) {
+        return;
+    }
+    let mut config = Config::default();
+    config.memory = true;
+    let mut spy = PythonSpy::retry_new(process.id(), &config, 20).unwrap();
+    let allocations = spy.get_allocations().unwrap();
+
+    // the bytearrays are all allocated from the same line
+    let allocation = allocations
+        .iter()
+        .find(|allocation| {
+            allocation.frames[0].line == 8
+                && allocation.frames[0]
+                    .filename
+                    .ends_with("memory_allocations.py")
+        })
+        .unwrap();
+    assert!(allocation.count >= 1000);
+    assert!(allocation.size >= 1000 * 1024);
+    assert_eq!(
+        allocation.frames[0].short_filename.as_deref(),
+        Some("memory_allocations.py")
+    );
+}
+
 #[cfg(not(target_os = "freebsd"))]
 #[test]
 fn test_subprocesses() {
@@ -350,10 +759,8 @@ fn test_subprocesses() {
     // was in a zombie state. Verify that this works now
     let process = ScriptRunner::new("python", "./tests/scripts/subprocesses.py");
     std::thread::sleep(std::time::Duration::from_millis(1000));
-    let config = Config {
-        subprocesses: true,
-        ..Default::default()
-    };
+    let mut config = Config::default();
+    config.subprocesses = true;
     let sampler = py_spy::sampler::Sampler::new(process.id(), &config).unwrap();
     std::thread::sleep(std::time::Duration::from_millis(1000));
 
@@ -390,10 +797,8 @@ fn test_subprocesses_zombiechild() {
     // was in a zombie state. Verify that this works now
     let process = ScriptRunner::new("python", "./tests/scripts/subprocesses_zombie_child.py");
     std::thread::sleep(std::time::Duration::from_millis(200));
-    let config = Config {
-        subprocesses: true,
-        ..Default::default()
-    };
+    let mut config = Config::default();
+    config.subprocesses = true;
     let _sampler = py_spy::sampler::Sampler::new(process.id(), &config).unwrap();
 }
 
@@ -438,16 +843,68 @@ fn test_negative_linenumber_increment() {
 #[test]
 fn test_delayed_subprocess() {
     let process = ScriptRunner::new("bash", "./tests/scripts/delayed_launch.sh");
-    let config = Config {
-        subprocesses: true,
-        ..Default::default()
-    };
+    let mut config = Config::default();
+    config.subprocesses = true;
     let sampler = py_spy::sampler::Sampler::new(process.id(), &config).unwrap();
-    for sample in sampler {
+    if let Some(sample) = sampler.into_iter().next() {
         // should have one trace from the subprocess
         let traces = sample.traces;
         assert_eq!(traces.len(), 1);
         assert!(traces[0].pid != process.id());
-        break;
     }
 }
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_delayed_exec_subprocess() {
+    // a wrapper that only execs python after setting up for longer than py-spy waits for python
+    // to start is attached to once it does
+    let process = ScriptRunner::new("bash", "./tests/scripts/delayed_exec.sh");
+    let mut config = Config::default();
+    config.subprocesses = true;
+    let sampler = py_spy::sampler::Sampler::new(process.id(), &config).unwrap();
+    if let Some(sample) = sampler.into_iter().next() {
+        let traces = sample.traces;
+        assert_eq!(traces.len(), 1);
+        assert_eq!(traces[0].pid, process.id());
+    }
+}
+
+#[test]
+fn test_ffi_snapshot() {
+    #[cfg(target_os = "macos")]
+    {
+        // We need root permissions here to run this on OSX
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+    }
+    let process = ScriptRunner::new("python", "./tests/scripts/longsleep.py");
+    let mut buf = vec![0 as std::os::raw::c_char; 1 << 16];
+
+    std::thread::sleep(std::time::Duration::from_millis(400));
+    let mut written = 0;
+    for _ in 0..20 {
+        // python can take a moment to start up
+        written =
+            unsafe { py_spy::ffi::pyspy_snapshot(process.id() as _, buf.as_mut_ptr(), buf.len()) };
+        if written >= 0 {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    assert!(written >= 0 && (written as usize) < buf.len());
+
+    let json = unsafe { std::ffi::CStr::from_ptr(buf.as_ptr()) };
+    assert_eq!(json.to_bytes().len(), written as usize);
+    let traces: Vec<StackTrace> = serde_json::from_slice(json.to_bytes()).unwrap();
+    assert_eq!(traces.len(), 1);
+    assert!(traces[0].frames.iter().any(|f| f.name == "longsleep"));
+
+    // a buffer that's too small isn't written to, and gets back how big it needed to be
+    let mut small = [1 as std::os::raw::c_char; 16];
+    let needed =
+        unsafe { py_spy::ffi::pyspy_snapshot(process.id() as _, small.as_mut_ptr(), small.len()) };
+    assert!(needed >= small.len() as i64);
+    assert_eq!(small, [1; 16]);
+}
@@ -0,0 +1,18 @@
+import asyncio
+
+
+async def sleeper():
+    await asyncio.sleep(100000)
+
+
+async def waiter():
+    await sleeper()
+
+
+async def main():
+    tasks = [asyncio.ensure_future(waiter()) for _ in range(2)]
+    await asyncio.gather(*tasks)
+
+
+if __name__ == "__main__":
+    asyncio.get_event_loop().run_until_complete(main())
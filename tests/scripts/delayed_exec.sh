@@ -0,0 +1,2 @@
+sleep 2
+exec python -c "import time; time.sleep(1000)"
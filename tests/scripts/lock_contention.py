@@ -0,0 +1,21 @@
+import threading
+import time
+
+
+def hold(lock):
+    with lock:
+        time.sleep(100000)
+
+
+def wait(lock):
+    lock.acquire()
+
+
+if __name__ == "__main__":
+    rlock = threading.RLock()
+    lock = threading.Lock()
+    threading.Thread(target=hold, args=(rlock,), name="rlock_holder", daemon=True).start()
+    threading.Thread(target=hold, args=(lock,), name="lock_holder", daemon=True).start()
+    time.sleep(0.1)
+    threading.Thread(target=wait, args=(rlock,), name="rlock_waiter", daemon=True).start()
+    wait(lock)
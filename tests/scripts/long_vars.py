@@ -0,0 +1,10 @@
+import time
+
+
+def run_query(query, params):
+    time.sleep(100000)
+
+
+if __name__ == "__main__":
+    query = "SELECT * FROM events WHERE " + " OR ".join("id = %d" % i for i in range(1000))
+    run_query(query, b"\x00\x01\xfe\xffpy-spy")
@@ -0,0 +1,14 @@
+import time
+import tracemalloc
+
+tracemalloc.start(25)
+
+
+def allocate():
+    return [bytearray(1024) for _ in range(1000)]
+
+
+if __name__ == "__main__":
+    data = allocate()
+    while True:
+        time.sleep(1)
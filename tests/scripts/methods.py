@@ -0,0 +1,17 @@
+import time
+
+
+class Worker(object):
+    def run(self):
+        self.wait()
+
+    def wait(self):
+        time.sleep(100000)
+
+    @classmethod
+    def start(cls):
+        cls().run()
+
+
+if __name__ == "__main__":
+    Worker.start()
@@ -0,0 +1,15 @@
+import time
+
+
+def handle_request(payload):
+    time.sleep(100000)
+
+
+if __name__ == "__main__":
+    handle_request(
+        {
+            "user": {"id": 1, "tags": ["a", "b", "c"]},
+            "items": [1, 2, 3, 4, 5],
+            "ids": {7},
+        }
+    )
@@ -0,0 +1,30 @@
+import sys
+import time
+
+
+def object_lookup(path, color, point):
+    # paths cache their string once it's been asked for
+    cached = path.parent
+    str(cached)
+    time.sleep(100000)
+
+
+if __name__ == "__main__":
+    # dataclasses are only in python 3.7+
+    if sys.version_info < (3, 7):
+        time.sleep(100000)
+
+    import dataclasses
+    import enum
+    import pathlib
+
+    class Color(enum.Enum):
+        RED = 1
+
+    @dataclasses.dataclass
+    class Point:
+        x: int
+        y: float
+        label: str = "origin"
+
+    object_lookup(pathlib.PurePosixPath("/tmp/data/input.csv"), Color.RED, Point(1, 2.5))
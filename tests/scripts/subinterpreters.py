@@ -0,0 +1,26 @@
+import threading
+import time
+
+try:
+    import _xxsubinterpreters as interpreters
+except ImportError:
+    interpreters = None
+
+code = """
+import time
+
+def subinterpreter_sleep():
+    time.sleep(10000)
+
+subinterpreter_sleep()
+"""
+
+
+def run_subinterpreter():
+    interpreters.run_string(interpreters.create(), code)
+
+
+if __name__ == "__main__":
+    if interpreters is not None:
+        threading.Thread(target=run_subinterpreter, daemon=True).start()
+    time.sleep(10000)
@@ -0,0 +1,29 @@
+import time
+
+try:
+    import trio
+except ImportError:
+    # the test is skipped when trio isn't installed
+    trio = None
+
+
+async def sleeper():
+    await trio.sleep(100000)
+
+
+async def worker():
+    async with trio.open_nursery() as nursery:
+        nursery.start_soon(sleeper)
+
+
+async def main():
+    async with trio.open_nursery() as nursery:
+        nursery.start_soon(worker)
+        nursery.start_soon(worker)
+
+
+if __name__ == "__main__":
+    if trio is None:
+        time.sleep(100000)
+    else:
+        trio.run(main)